@@ -0,0 +1,86 @@
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use riviera::cpu::{Cpu, Instruction};
+use riviera::memory::Memory;
+use riviera::rv;
+
+// Address at which the read-write segment starts
+const DATA_START: u64 = 0x20000;
+
+// Small loop that keeps the ALU, the load/store unit and the branches busy:
+//      addi t0, zero, 1000
+// l:   addi t0, t0, -1
+//      add  t1, t1, t0
+//      sd   t1, 0(sp)
+//      ld   t2, 0(sp)
+//      xor  t3, t2, t0
+//      bnez t0, l
+//      ret
+const LOOP_PROGRAM: [Instruction; 8] = [
+    0x3e800293, 0xfff28293, 0x00530333, 0x00613023,
+    0x00013383, 0x0053ce33, 0xfe0296e3, 0x00008067
+];
+
+// One instruction per format, used to measure the decoder alone
+const DECODE_SAMPLES: [(&str, Instruction); 4] = [
+    ("addi", 0x00150513),
+    ("add",  0x00c58533),
+    ("beq",  0x00a50463),
+    ("ld",   0x00813503)
+];
+
+/// Create a CPU with the given program at address 0, ready to run it
+fn setup_cpu(program: &[Instruction]) -> Cpu {
+    let mut cpu: Cpu = Cpu::new(Some(Memory::DRAM_DEFAULT_SIZE));
+    let text: Vec<u8> = program.iter().flat_map(|instr| instr.to_le_bytes()).collect();
+    cpu.set_read_only_segment(0x0);
+    cpu.set_read_write_segment(DATA_START);
+    cpu.store_from_buffer(&text, 0x0).unwrap();
+    cpu.set_pc(0x0);
+    cpu.write_reg(Cpu::RETURN_REGISTER, Cpu::SENTINEL_RETURN_ADDRESS);
+    cpu.set_stack_pointer(DATA_START + 0x100);
+    cpu
+}
+
+fn decode_benchmark(c: &mut Criterion) {
+    let mut group = c.benchmark_group("decode_execute");
+    let mut cpu: Cpu = setup_cpu(&LOOP_PROGRAM);
+    for (name, instr) in DECODE_SAMPLES {
+        group.bench_function(name, |b| b.iter(|| {
+            rv::decode::<false>(black_box(instr), &mut cpu)
+        }));
+    }
+    group.finish();
+}
+
+fn fetch_decode_execute_benchmark(c: &mut Criterion) {
+    let mut group = c.benchmark_group("fetch_decode_execute");
+    let cpu: Cpu = setup_cpu(&LOOP_PROGRAM);
+    // Single step of the first instruction of the loop body
+    group.bench_function("step", |b| b.iter_batched_ref(
+        || { let mut cpu = cpu.clone(); cpu.set_pc(0x4); cpu },
+        |cpu| cpu.cpu_loop_bounded(1),
+        criterion::BatchSize::SmallInput
+    ));
+    group.finish();
+}
+
+fn cpu_loop_benchmark(c: &mut Criterion) {
+    let mut group = c.benchmark_group("cpu_loop");
+    let cpu: Cpu = setup_cpu(&LOOP_PROGRAM);
+    // Fast path used by run()
+    group.bench_function("fast", |b| b.iter_batched_ref(
+        || cpu.clone(),
+        |cpu| cpu.cpu_loop(),
+        criterion::BatchSize::SmallInput
+    ));
+    // Same program with the disassembly being produced, as when debugging
+    group.bench_function("debug", |b| b.iter_batched_ref(
+        || { let mut cpu = cpu.clone(); cpu.set_debug_mode(); cpu },
+        |cpu| cpu.cpu_loop_bounded(u64::MAX),
+        criterion::BatchSize::SmallInput
+    ));
+    group.finish();
+}
+
+criterion_group!(benches, decode_benchmark, fetch_decode_execute_benchmark, cpu_loop_benchmark);
+criterion_main!(benches);
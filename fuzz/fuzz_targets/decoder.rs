@@ -0,0 +1,16 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use riviera::disas;
+use riviera::emulator::Emulator;
+use riviera::harness::RunLimits;
+
+// Disassemble every word and execute the first one
+fuzz_target!(|data: &[u8]| {
+    for word in data.chunks_exact(4) {
+        disas::disassemble(u32::from_le_bytes(word.try_into().unwrap()), Emulator::CODE_ADDRESS);
+    }
+    if data.len() >= 4 {
+        let _ = Emulator::run_bytes(&data[..4], RunLimits { max_instructions: 1, memory_size: 0x1000 });
+    }
+});
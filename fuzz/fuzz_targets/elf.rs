@@ -0,0 +1,10 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use riviera::emulator::Emulator;
+
+// Load the input as an ELF executable: malformed files must be errors
+fuzz_target!(|data: &[u8]| {
+    let mut emu: Emulator = Emulator::new(Some(0x10000));
+    let _ = emu.load_program_bytes(data);
+});
@@ -0,0 +1,10 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use riviera::emulator::Emulator;
+use riviera::harness::RunLimits;
+
+// Execute the input as code
+fuzz_target!(|data: &[u8]| {
+    let _ = Emulator::run_bytes(data, RunLimits { max_instructions: 10_000, memory_size: 0x10000 });
+});
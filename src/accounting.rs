@@ -0,0 +1,115 @@
+use crate::csr::Privilege;
+
+/// Where the emulated CPU time goes. Time is measured in executed
+/// instructions (the CLINT timer also ticks once per instruction) and it
+/// is split by privilege level, between trap handlers and the rest of the
+/// code, while the time spent idling waiting for an interrupt is kept apart
+#[derive(Clone)]
+pub struct TimeAccounting {
+    // Instructions executed at each privilege level (indexed by its
+    // encoding), outside [0] or inside [1] a trap handler
+    counts: [[u64; 2]; 4],
+    idle: u64,
+    privilege: Privilege,
+    // Trap handlers that have been entered and have not returned yet
+    handler_depth: u32
+}
+
+impl Default for TimeAccounting {
+    fn default() -> TimeAccounting {
+        TimeAccounting::new()
+    }
+}
+
+impl TimeAccounting {
+    // jal zero, 0: a jump to itself, the usual way to idle waiting for interrupts
+    const JUMP_TO_SELF: u32 = 0x0000006f;
+    // wfi: stall until an interrupt is pending
+    const WFI: u32 = 0x10500073;
+
+    pub fn new() -> TimeAccounting {
+        TimeAccounting { counts: [[0; 2]; 4], idle: 0, privilege: Privilege::Machine, handler_depth: 0 }
+    }
+
+    /// Account for an instruction that has just been executed
+    #[inline(always)]
+    pub fn record(&mut self, instr: u32) {
+        if instr == TimeAccounting::JUMP_TO_SELF || instr == TimeAccounting::WFI {
+            self.idle += 1;
+        } else {
+            self.counts[self.privilege as usize][(self.handler_depth > 0) as usize] += 1;
+        }
+    }
+
+    /// Account for cycles spent stalled by WFI, when no instruction is executed
+    pub fn record_idle(&mut self, cycles: u64) {
+        self.idle += cycles;
+    }
+
+    /// A trap handler is entered at the given privilege level
+    pub fn enter_trap(&mut self, privilege: Privilege) {
+        self.handler_depth += 1;
+        self.privilege = privilege;
+    }
+
+    /// A trap handler returned (MRET or SRET) to the given privilege level
+    pub fn return_from_trap(&mut self, privilege: Privilege) {
+        self.handler_depth = self.handler_depth.saturating_sub(1);
+        self.privilege = privilege;
+    }
+
+    /// Instructions executed at a privilege level outside of trap handlers
+    pub fn get_normal(&self, privilege: Privilege) -> u64 {
+        self.counts[privilege as usize][0]
+    }
+
+    /// Instructions executed at a privilege level inside trap handlers
+    pub fn get_handler(&self, privilege: Privilege) -> u64 {
+        self.counts[privilege as usize][1]
+    }
+
+    /// Instructions spent idling
+    pub fn get_idle(&self) -> u64 {
+        self.idle
+    }
+
+    /// Total number of instructions that have been accounted for
+    pub fn get_total(&self) -> u64 {
+        self.counts.iter().flatten().sum::<u64>() + self.idle
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::accounting::TimeAccounting;
+    use crate::csr::Privilege;
+
+    #[test]
+    fn accounting_test() {
+        const ADDI: u32 = 0x00150513;
+        let mut accounting: TimeAccounting = TimeAccounting::new();
+        accounting.record(ADDI);
+        accounting.record(TimeAccounting::JUMP_TO_SELF);
+        accounting.record(TimeAccounting::JUMP_TO_SELF);
+        // WFI and the cycles it stalls for
+        accounting.record(TimeAccounting::WFI);
+        accounting.record_idle(5);
+        // A timer interrupt handled in M-mode, then an exception delegated
+        // to S-mode raised while handling it
+        accounting.enter_trap(Privilege::Machine);
+        accounting.record(ADDI);
+        accounting.enter_trap(Privilege::Supervisor);
+        accounting.record(ADDI);
+        accounting.record(ADDI);
+        accounting.return_from_trap(Privilege::Machine);
+        accounting.record(ADDI);
+        accounting.return_from_trap(Privilege::Supervisor);
+        accounting.record(ADDI);
+        assert_eq!(accounting.get_normal(Privilege::Machine), 1);
+        assert_eq!(accounting.get_handler(Privilege::Machine), 2);
+        assert_eq!(accounting.get_handler(Privilege::Supervisor), 2);
+        assert_eq!(accounting.get_normal(Privilege::Supervisor), 1);
+        assert_eq!(accounting.get_idle(), 8);
+        assert_eq!(accounting.get_total(), 14);
+    }
+}
@@ -0,0 +1,85 @@
+use crate::emulator::Emulator;
+use crate::expr::Condition;
+
+/// Outcome of a bisection: the instruction that first made the predicate true
+pub struct BisectResult {
+    // Number of executed instructions after which the predicate holds
+    pub instruction_count: u64,
+    // Address of the instruction that made the predicate true
+    pub pc: u64,
+    // Decoded instruction that made the predicate true
+    pub instruction: String
+}
+
+/// Find the first instruction after which the predicate becomes true.
+/// The program is run forward taking a checkpoint (a copy of the whole
+/// emulator) every `interval` instructions; once the predicate is found to hold
+/// at a checkpoint, a binary search is performed between that checkpoint
+/// and the previous one by re-running from the older checkpoint.
+/// The predicate is assumed to stay true once it becomes true.
+/// It returns None if the program terminates (or the limit of executed
+/// instructions is reached) before the predicate ever holds
+pub fn bisect(start: &Emulator, predicate: &Condition, interval: u64,
+              limit: Option<u64>) -> Option<BisectResult> {
+    let interval: u64 = interval.max(1);
+    let mut checkpoint: Emulator = start.clone();
+    let mut checkpoint_count: u64 = 0;
+
+    if predicate.evaluate(checkpoint.get_cpu()) {
+        return Some(BisectResult {
+            instruction_count: 0,
+            pc: checkpoint.get_cpu().get_pc(),
+            instruction: String::new()
+        });
+    }
+
+    loop {
+        // Do not go past the instruction limit, if any
+        let mut steps: u64 = interval;
+        if let Some(limit) = limit {
+            steps = steps.min(limit.saturating_sub(checkpoint_count));
+            if steps == 0 {
+                return None;
+            }
+        }
+
+        let mut next_checkpoint: Emulator = checkpoint.clone();
+        let executed: u64 = next_checkpoint.step(steps);
+
+        if predicate.evaluate(next_checkpoint.get_cpu()) {
+            // The predicate is false after `low` steps from the checkpoint
+            // and true after `high` steps
+            let mut low: u64 = 0;
+            let mut high: u64 = executed;
+            while high - low > 1 {
+                let middle: u64 = low + (high - low) / 2;
+                let mut probe: Emulator = checkpoint.clone();
+                probe.step(middle);
+                if predicate.evaluate(probe.get_cpu()) {
+                    high = middle;
+                } else {
+                    low = middle;
+                }
+            }
+
+            // Replay the culprit instruction in debug mode to obtain its disassembly
+            let mut culprit: Emulator = checkpoint;
+            culprit.step(low);
+            let pc: u64 = culprit.get_cpu().get_pc();
+            let instruction: String = culprit.step_debug();
+            return Some(BisectResult {
+                instruction_count: checkpoint_count + high,
+                pc,
+                instruction
+            });
+        }
+
+        // The program is over and the predicate never held
+        if executed < steps || next_checkpoint.is_finished() {
+            return None;
+        }
+
+        checkpoint = next_checkpoint;
+        checkpoint_count += executed;
+    }
+}
@@ -0,0 +1,179 @@
+use std::fmt;
+use crate::cpu::Cpu;
+use crate::expr::Condition;
+
+/// A breakpoint of the interactive mode: the program stops before the
+/// instruction at address is executed, if the condition (if any) holds
+/// there and the hits to ignore have been used up
+#[derive(Clone, Debug)]
+pub struct Breakpoint {
+    pub number: usize,
+    pub address: u64,
+    // Symbol the address belongs to, if any (e.g. main+0x8)
+    pub location: Option<String>,
+    pub condition: Option<Condition>,
+    // Number of the next hits that do not stop the program
+    pub ignore_count: u64,
+    // Times the address was reached with the condition holding,
+    // including the ignored hits (as in gdb)
+    pub hits: u64
+}
+
+impl fmt::Display for Breakpoint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: 0x{:0>16x}", self.number, self.address)?;
+        if let Some(location) = self.location.as_ref() {
+            write!(f, " <{}>", location)?;
+        }
+        if let Some(condition) = self.condition.as_ref() {
+            write!(f, " if {}", condition.as_str())?;
+        }
+        match self.hits {
+            0 => (),
+            1 => write!(f, ", hit 1 time")?,
+            hits => write!(f, ", hit {} times", hits)?
+        }
+        if self.ignore_count > 0 {
+            write!(f, ", next {} hits ignored", self.ignore_count)?;
+        }
+        Ok(())
+    }
+}
+
+/// Breakpoints of the interactive mode, numbered from 1 in the order
+/// they were set. Numbers are not reused when breakpoints are deleted
+#[derive(Clone, Debug)]
+pub struct Breakpoints {
+    breakpoints: Vec<Breakpoint>,
+    next_number: usize
+}
+
+impl Default for Breakpoints {
+    fn default() -> Breakpoints {
+        Breakpoints::new()
+    }
+}
+
+impl Breakpoints {
+    pub fn new() -> Breakpoints {
+        Breakpoints { breakpoints: Vec::new(), next_number: 1 }
+    }
+
+    /// Add a breakpoint at address, returning its number
+    pub fn add(&mut self, address: u64, location: Option<String>, condition: Option<Condition>) -> usize {
+        let number: usize = self.next_number;
+        self.next_number += 1;
+        self.breakpoints.push(Breakpoint { number, address, location, condition, ignore_count: 0, hits: 0 });
+        number
+    }
+
+    /// Delete a breakpoint
+    pub fn delete(&mut self, number: usize) -> Result<(), String> {
+        let index: usize = self.index(number)?;
+        self.breakpoints.remove(index);
+        Ok(())
+    }
+
+    /// Delete all the breakpoints
+    pub fn clear(&mut self) {
+        self.breakpoints.clear();
+    }
+
+    /// Ignore the next count hits of a breakpoint
+    pub fn set_ignore_count(&mut self, number: usize, count: u64) -> Result<(), String> {
+        let index: usize = self.index(number)?;
+        self.breakpoints[index].ignore_count = count;
+        Ok(())
+    }
+
+    /// Replace the condition of a breakpoint (None makes it unconditional)
+    pub fn set_condition(&mut self, number: usize, condition: Option<Condition>) -> Result<(), String> {
+        let index: usize = self.index(number)?;
+        self.breakpoints[index].condition = condition;
+        Ok(())
+    }
+
+    fn index(&self, number: usize) -> Result<usize, String> {
+        self.breakpoints.iter().position(|breakpoint| breakpoint.number == number)
+            .ok_or(format!("No breakpoint number {}", number))
+    }
+
+    /// Addresses of the breakpoints, where the CPU has to stop
+    pub fn addresses(&self) -> Vec<u64> {
+        let mut addresses: Vec<u64> = self.breakpoints.iter().map(|breakpoint| breakpoint.address).collect();
+        addresses.sort();
+        addresses.dedup();
+        addresses
+    }
+
+    /// The CPU stopped at a breakpoint address: count the hits of the
+    /// breakpoints there whose condition holds, and return the first one
+    /// that stops the program, if any (the others ignore this hit)
+    pub fn hit(&mut self, cpu: &Cpu) -> Option<&Breakpoint> {
+        let pc: u64 = cpu.get_pc();
+        let mut stop: Option<usize> = None;
+        for (index, breakpoint) in self.breakpoints.iter_mut().enumerate() {
+            if breakpoint.address != pc || !breakpoint.condition.as_ref().is_none_or(|condition| condition.evaluate(cpu)) {
+                continue;
+            }
+            breakpoint.hits += 1;
+            if breakpoint.ignore_count > 0 {
+                breakpoint.ignore_count -= 1;
+            } else if stop.is_none() {
+                stop = Some(index);
+            }
+        }
+        stop.map(|index| &self.breakpoints[index])
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Breakpoint> {
+        self.breakpoints.iter()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.breakpoints.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::breakpoint::Breakpoints;
+    use crate::cpu::Cpu;
+    use crate::expr::Condition;
+    use crate::symbols::SymbolMap;
+
+    #[test]
+    fn breakpoints_test() {
+        let mut cpu: Cpu = Cpu::new(None);
+        let symbols: SymbolMap = SymbolMap::new();
+        let mut breakpoints: Breakpoints = Breakpoints::new();
+        assert_eq!(breakpoints.add(0x100, Some("main".to_string()), Some(Condition::parse("a0 == 5", &symbols).unwrap())), 1);
+        assert_eq!(breakpoints.add(0x200, None, None), 2);
+        breakpoints.set_ignore_count(2, 2).unwrap();
+        assert_eq!(breakpoints.addresses(), vec![0x100, 0x200]);
+
+        // The condition does not hold: no hit
+        cpu.set_pc(0x100);
+        assert!(breakpoints.hit(&cpu).is_none());
+        cpu.write_reg(10, 5);
+        assert_eq!(breakpoints.hit(&cpu).map(|breakpoint| breakpoint.number), Some(1));
+        // Two hits are ignored, the third one stops
+        cpu.set_pc(0x200);
+        assert!(breakpoints.hit(&cpu).is_none());
+        assert!(breakpoints.hit(&cpu).is_none());
+        assert_eq!(breakpoints.hit(&cpu).map(|breakpoint| breakpoint.hits), Some(3));
+        let listed: Vec<String> = breakpoints.iter().map(|breakpoint| breakpoint.to_string()).collect();
+        assert_eq!(listed, ["1: 0x0000000000000100 <main> if a0 == 5, hit 1 time",
+                            "2: 0x0000000000000200, hit 3 times"]);
+
+        breakpoints.set_condition(1, None).unwrap();
+        cpu.set_pc(0x100);
+        cpu.write_reg(10, 0);
+        assert!(breakpoints.hit(&cpu).is_some());
+        breakpoints.delete(1).unwrap();
+        assert!(breakpoints.delete(1).is_err());
+        assert_eq!(breakpoints.add(0x300, None, None), 3);
+        breakpoints.clear();
+        assert!(breakpoints.is_empty());
+    }
+}
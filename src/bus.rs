@@ -1,79 +1,1277 @@
+use std::ops::Range;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use crate::memory;
+use crate::uart::{UartSpec, UART};
+use crate::clint::Clint;
+use crate::rtc::{GoldfishRtc, Rtc};
+use crate::logport::LogPort;
+use crate::plic::Plic;
+use crate::console::SharedConsoleLog;
+use crate::cli::{GuestStdio, SharedHostConsole};
+use crate::machine::MachineConfig;
+use crate::device::Device;
+use crate::cache::CacheSim;
+use crate::memtrace::MemTrace;
+use crate::virtio::{GuestMemory, VirtioBlock};
+use crate::cpu::Xlen;
+use crate::fdt::{DeviceNode, Platform};
+use crate::machine::Region;
+use crate::inputlog::{InputEvent, InputLog};
+use crate::snapshot::{Snapshot, SnapshotReader, SnapshotWriter};
 
 // Bus is an object that contains everything
 // that is connected to the CPU through a bus
 // such as: DRAM, ROM and other peripherals
+#[derive(Clone)]
 pub struct Bus {
-    dram: memory::Memory,
-    dram_offset: u64,
-    rom: memory::Memory,
-    rom_offset: u64
+    // RAM and ROM, the main DRAM is always the first region
+    regions: Vec<MemoryRegion>,
+    // Default layout: the ROM and the DRAM are placed where the ELF wants them
+    relocatable: bool,
+    // Boot ROM image, mapped at bootrom_base and read-only for the program
+    bootrom: memory::Memory,
+    bootrom_base: u64,
+    clint_base: u64,
+    plic_base: u64,
+    uart_base: u64,
+    logport_base: u64,
+    rtc_base: u64,
+    virtio_base: u64,
+    uart: UART,
+    clint: Clint,
+    rtc: Rtc,
+    // Registers of the real-time clock
+    goldfish: GoldfishRtc,
+    logport: LogPort,
+    plic: Plic,
+    // Block device, if a drive has been attached
+    virtio: Option<VirtioBlock>,
+    // Devices attached from outside of riviera (crates and plugins)
+    devices: Vec<MappedDevice>,
+    // Number of times the devices have been cycled (executed instructions)
+    cycles: u64,
+    // Copy of the characters sent through the UART, if enabled
+    console_log: Option<SharedConsoleLog>,
+    // Characters sent to the console and not taken yet, if they are captured
+    console_capture: Option<Vec<u8>>,
+    // Caches simulated on the accesses of the CPU to memory, if enabled
+    cache_sim: Option<CacheSim>,
+    // Inputs from outside of the machine, if they are recorded or replayed
+    input_log: Option<InputLog>,
+    // Standard streams of the program (system calls and semihosting)
+    stdio: GuestStdio,
+    // Log of the loads and stores, if enabled
+    mem_trace: Option<MemTrace>,
+    // The devices wrote to memory (DMA) since the CPU last checked, so
+    // the instructions it decoded may be stale
+    device_writes: bool,
+    // Access rights of the segments of the loaded executables, enforced
+    // on the stores of the program unless protect is false
+    permissions: Vec<(Range<u64>, Permissions)>,
+    protect: bool
+}
+
+// A memory mapped on the bus
+#[derive(Clone)]
+struct MemoryRegion {
+    base: u64,
+    memory: memory::Memory,
+    kind: RegionKind
+}
+
+// A device attached from outside of riviera and where it is mapped
+#[derive(Clone)]
+struct MappedDevice {
+    base: u64,
+    // Size of its window on the bus
+    size: u64,
+    // PLIC source its interrupt is connected to, if any
+    irq: Option<usize>,
+    device: Box<dyn Device>
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum RegionKind {
+    Ram,
+    // Only written when the program is loaded
+    Rom,
+    // ROM of the default layout: it grows to fit the text of the
+    // program and the program itself can write it
+    Text
+}
+
+/// Access rights of a range of memory, from the flags of the ELF segment
+/// loaded there (PF_R, PF_W and PF_X)
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Permissions {
+    pub read: bool,
+    pub write: bool,
+    pub execute: bool
+}
+
+/// Interrupt line that the host can raise (see Bus::raise_irq)
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum InterruptLine {
+    // Machine software interrupt of the CLINT (msip)
+    Software,
+    // Machine timer interrupt of the CLINT (mtimecmp reached)
+    Timer,
+    // Source of the PLIC, 1 to 31
+    External(usize)
+}
+
+impl InterruptLine {
+    /// Parse a line: msip, mtip or the number of a PLIC source
+    pub fn parse(line: &str) -> Result<InterruptLine, String> {
+        match line.trim() {
+            "msip" => Ok(InterruptLine::Software),
+            "mtip" => Ok(InterruptLine::Timer),
+            source => match source.parse::<usize>() {
+                Ok(source) if source != 0 && source < Plic::NUM_SOURCES => Ok(InterruptLine::External(source)),
+                _ => Err(format!("Invalid interrupt line {}: expected msip, mtip or a PLIC source from 1 to {}",
+                                 source, Plic::NUM_SOURCES - 1))
+            }
+        }
+    }
+}
+
+impl std::fmt::Display for InterruptLine {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            InterruptLine::Software => write!(f, "msip"),
+            InterruptLine::Timer => write!(f, "mtip"),
+            InterruptLine::External(source) => write!(f, "PLIC source {}", source)
+        }
+    }
 }
 
 impl Bus {
 
     const TEXT_START_DEFAULT: u64 = 0x00000000;
     const DATA_START_DEFAULT: u64 = 0x00020000;
+    // Position of the regions in the default layout
+    const DRAM_REGION: usize = 0;
+    const TEXT_REGION: usize = 1;
+
+    /// The boot ROM (if any) is mapped at this address by default, where the reset vector is
+    pub const BOOTROM_BASE: u64 = 0x1000;
+    pub const BOOTROM_MAX_SIZE: u64 = 0xf000;
+    /// The CLINT (timer and software interrupts) is mapped at this address by default
+    pub const CLINT_BASE: u64 = 0x02000000;
+    pub const CLINT_SIZE: u64 = 0x10000;
+    /// The PLIC (external interrupts) is mapped at this address by default
+    pub const PLIC_BASE: u64 = 0x0c000000;
+    pub const PLIC_SIZE: u64 = 0x4000000;
+    /// The UART registers are mapped at this address by default (one byte each)
+    pub const UART_BASE: u64 = 0x10000000;
+    pub const UART_SIZE: u64 = 0x100;
+    /// The guest-to-host log port is mapped at this address by default
+    pub const LOGPORT_BASE: u64 = 0x10001000;
+    pub const LOGPORT_SIZE: u64 = 0x100;
+    /// The goldfish RTC (wall clock) is mapped at this address by default
+    pub const RTC_BASE: u64 = 0x10007000;
+    pub const RTC_SIZE: u64 = 0x1000;
+    /// The virtio block device (if any) is mapped at this address by default
+    pub const VIRTIO_BASE: u64 = 0x10008000;
+    pub const VIRTIO_SIZE: u64 = 0x1000;
+    /// PLIC source the UART interrupt is connected to
+    pub const UART_IRQ: usize = 10;
+    /// PLIC source the virtio block device interrupt is connected to
+    pub const VIRTIO_IRQ: usize = 1;
+    /// PLIC source of the RTC alarm interrupt (it is never raised)
+    pub const RTC_IRQ: usize = 2;
 
     // Constructor, initialize DRAM to a certain size
     // while the ROM is only constructed, its size depends
     // on the ELF file that is loaded into it
     pub fn new(memsize: Option<usize>) -> Bus {
+        let regions: Vec<MemoryRegion> = vec![
+            MemoryRegion { base: Bus::DATA_START_DEFAULT, memory: memory::Memory::new(memsize), kind: RegionKind::Ram },
+            MemoryRegion {
+                base: Bus::TEXT_START_DEFAULT,
+                memory: memory::Memory::new(Some(memory::Memory::ROM_DEFAULT_SIZE)),
+                kind: RegionKind::Text
+            }
+        ];
+        Bus::with_regions(regions, true, &MachineConfig::new())
+    }
+
+    /// Build the bus of a machine with a fixed memory map. The machine
+    /// must have at least one RAM region
+    pub fn with_machine(machine: &MachineConfig) -> Bus {
+        let ram = machine.ram.iter().map(|region| (region, RegionKind::Ram));
+        let rom = machine.rom.iter().map(|region| (region, RegionKind::Rom));
+        let regions: Vec<MemoryRegion> = ram.chain(rom).map(|(region, kind)| MemoryRegion {
+            base: region.base,
+            memory: memory::Memory::new(Some(region.size as usize)),
+            kind
+        }).collect();
+        Bus::with_regions(regions, false, machine)
+    }
+
+    fn with_regions(regions: Vec<MemoryRegion>, relocatable: bool, machine: &MachineConfig) -> Bus {
         Self {
-            dram: memory::Memory::new(memsize),
-            dram_offset: Bus::DATA_START_DEFAULT,
-            rom:  memory::Memory::new(Some(memory::Memory::ROM_DEFAULT_SIZE)),
-            rom_offset: Bus::TEXT_START_DEFAULT,
+            regions,
+            relocatable,
+            bootrom: memory::Memory::new(None),
+            bootrom_base: machine.bootrom_base,
+            clint_base: machine.clint_base,
+            plic_base: machine.plic_base,
+            uart_base: machine.uart_base,
+            logport_base: machine.logport_base,
+            rtc_base: machine.rtc_base,
+            virtio_base: machine.virtio_base,
+            uart: UART::new(),
+            clint: Clint::new(),
+            rtc: Rtc::new(),
+            goldfish: GoldfishRtc::new(),
+            logport: LogPort::new(),
+            plic: Plic::new(),
+            virtio: None,
+            devices: Vec::new(),
+            cycles: 0,
+            console_log: None,
+            console_capture: None,
+            cache_sim: None,
+            input_log: None,
+            stdio: GuestStdio::new(),
+            mem_trace: None,
+            device_writes: false,
+            permissions: Vec::new(),
+            protect: true
         }
     }
 
     // Read from any devide through the bus, this function (depending
     // on the memory boundaries) will dispatch the operation to the
-    // appropriate device
-    pub fn read(&self, addr: u64, size: memory::AccessSize) -> u64 {
-        if addr < self.dram_offset  {
-            self.rom.load(addr - self.rom_offset, size)
+    // appropriate device. Misaligned accesses and accesses that do not
+    // hit any device are reported as errors. Reading a device register
+    // can change the state of the device (e.g. the UART receive buffer)
+    pub fn read(&mut self, addr: u64, size: memory::AccessSize) -> Result<u64, memory::MemError> {
+        let result: Result<u64, memory::MemError> = self.read_device(addr, size);
+        if let (Some(trace), Ok(value)) = (self.mem_trace.as_ref(), result.as_ref()) {
+            trace.access(false, addr, size, *value);
+        }
+        result
+    }
+
+    #[inline(always)]
+    fn read_device(&mut self, addr: u64, size: memory::AccessSize) -> Result<u64, memory::MemError> {
+        if self.cache_sim.is_some() {
+            self.simulate_data_access(addr);
+        }
+        if let Some(reg) = self.uart_register(addr) {
+            if !addr.is_multiple_of(size.bytes() as u64) {
+                return Err(memory::MemError::Misaligned(addr));
+            }
+            Ok(self.uart.read(reg) as u64)
+        } else if let Some(offset) = Bus::device_offset(addr, self.plic_base, Bus::PLIC_SIZE) {
+            if !addr.is_multiple_of(size.bytes() as u64) {
+                return Err(memory::MemError::Misaligned(addr));
+            }
+            self.plic.read(offset, size).ok_or(memory::MemError::OutOfBounds(addr))
+        } else if let Some(offset) = Bus::device_offset(addr, self.rtc_base, Bus::RTC_SIZE) {
+            if !addr.is_multiple_of(size.bytes() as u64) {
+                return Err(memory::MemError::Misaligned(addr));
+            }
+            self.read_goldfish(offset).ok_or(memory::MemError::OutOfBounds(addr))
+        } else if let Some((index, offset)) = self.attached_device(addr) {
+            if !addr.is_multiple_of(size.bytes() as u64) {
+                return Err(memory::MemError::Misaligned(addr));
+            }
+            self.devices[index].device.read(offset, size).ok_or(memory::MemError::OutOfBounds(addr))
         } else {
-            self.dram.load(addr - self.dram_offset, size)
+            self.peek(addr, size)
         }
     }
 
+    // Same as read, but device registers are read without side effects.
+    // It is what the debugger uses to look at memory
+    pub fn peek(&self, addr: u64, size: memory::AccessSize) -> Result<u64, memory::MemError> {
+        if !addr.is_multiple_of(size.bytes() as u64) {
+            return Err(memory::MemError::Misaligned(addr));
+        }
+        let result: Result<u64, memory::MemError> = if let Some(reg) = self.uart_register(addr) {
+            Ok(self.uart.peek(reg) as u64)
+        } else if let Some(offset) = Bus::device_offset(addr, self.clint_base, Bus::CLINT_SIZE) {
+            self.clint.read(offset, size).ok_or(memory::MemError::OutOfBounds(addr))
+        } else if let Some(offset) = Bus::device_offset(addr, self.logport_base, Bus::LOGPORT_SIZE) {
+            self.logport.read(offset).ok_or(memory::MemError::OutOfBounds(addr))
+        } else if let Some(offset) = Bus::device_offset(addr, self.rtc_base, Bus::RTC_SIZE) {
+            self.goldfish.peek(offset, self.rtc.now(self.clint.get_mtime())).ok_or(memory::MemError::OutOfBounds(addr))
+        } else if let Some(offset) = Bus::device_offset(addr, self.plic_base, Bus::PLIC_SIZE) {
+            self.plic.peek(offset, size).ok_or(memory::MemError::OutOfBounds(addr))
+        } else if let Some((virtio, offset)) = self.virtio_register(addr) {
+            virtio.read(offset, size).ok_or(memory::MemError::OutOfBounds(addr))
+        } else if let Some(offset) = Bus::device_offset(addr, self.bootrom_base, self.bootrom.get_size() as u64) {
+            self.bootrom.load(offset, size)
+        } else if let Some((index, offset)) = self.attached_device(addr) {
+            self.devices[index].device.peek(offset, size).ok_or(memory::MemError::OutOfBounds(addr))
+        } else if let Some(region) = self.region(addr) {
+            region.memory.load(addr - region.base, size)
+        } else {
+            Err(memory::MemError::OutOfBounds(addr))
+        };
+        // Report the full address rather than the offset inside the device
+        result.map_err(|_| memory::MemError::OutOfBounds(addr))
+    }
+
     // Write to any devide through the bus, this function (depending
     // on the memory boundaries) will dispatch the operation to the
-    // appropriate device
-    pub fn write(&mut self, data: u64, addr: u64, size: memory::AccessSize) {
-        if addr < self.dram_offset {
-            self.rom.store(data, addr - self.rom_offset, size);
+    // appropriate device. Misaligned accesses and accesses that do not
+    // hit any device are reported as errors
+    pub fn write(&mut self, data: u64, addr: u64, size: memory::AccessSize) -> Result<(), memory::MemError> {
+        if !addr.is_multiple_of(size.bytes() as u64) {
+            return Err(memory::MemError::Misaligned(addr));
+        }
+        if self.cache_sim.is_some() {
+            self.simulate_data_access(addr);
+        }
+        let result: Result<(), memory::MemError> = if let Some(reg) = self.uart_register(addr) {
+            // Registers are one byte wide, only the low byte is written
+            self.uart.write(reg, data as u8);
+            Ok(())
+        } else if let Some(offset) = Bus::device_offset(addr, self.clint_base, Bus::CLINT_SIZE) {
+            self.clint.write(offset, data, size).ok_or(memory::MemError::OutOfBounds(addr))
+        } else if let Some(offset) = Bus::device_offset(addr, self.logport_base, Bus::LOGPORT_SIZE) {
+            let mtime: u64 = self.clint.get_mtime();
+            self.logport.write(offset, data, mtime).ok_or(memory::MemError::OutOfBounds(addr))
+        } else if let Some(offset) = Bus::device_offset(addr, self.rtc_base, Bus::RTC_SIZE) {
+            self.write_goldfish(offset, data).ok_or(memory::MemError::OutOfBounds(addr))
+        } else if let Some(offset) = Bus::device_offset(addr, self.plic_base, Bus::PLIC_SIZE) {
+            self.plic.write(offset, data, size).ok_or(memory::MemError::OutOfBounds(addr))
+        } else if let Some(offset) = self.virtio_register(addr).map(|(_, offset)| offset) {
+            self.write_virtio(offset, data, size).ok_or(memory::MemError::OutOfBounds(addr))
+        } else if Bus::device_offset(addr, self.bootrom_base, self.bootrom.get_size() as u64).is_some() {
+            // The boot ROM cannot be written
+            Err(memory::MemError::OutOfBounds(addr))
+        } else if let Some((index, offset)) = self.attached_device(addr) {
+            self.devices[index].device.write(offset, data, size).ok_or(memory::MemError::OutOfBounds(addr))
+        } else if let Some(index) = self.region_index(addr) {
+            let region: &mut MemoryRegion = &mut self.regions[index];
+            match region.kind {
+                RegionKind::Rom => Err(memory::MemError::OutOfBounds(addr)),
+                _ => region.memory.store(data, addr - region.base, size)
+            }
         } else {
-            self.dram.store(data, addr - self.dram_offset, size);
+            Err(memory::MemError::OutOfBounds(addr))
+        };
+        if let (Some(trace), Ok(())) = (self.mem_trace.as_ref(), result.as_ref()) {
+            trace.access(true, addr, size, data);
+        }
+        // Report the full address rather than the offset inside the device
+        result.map_err(|_| memory::MemError::OutOfBounds(addr))
+    }
+
+    // Offset inside the device mapped at [base, base + size) addressed
+    // by addr, if any
+    #[inline(always)]
+    fn device_offset(addr: u64, base: u64, size: u64) -> Option<u64> {
+        match addr.wrapping_sub(base) {
+            offset if offset < size => Some(offset),
+            _ => None
+        }
+    }
+
+    // Offset of the UART register addressed by addr, if any
+    #[inline(always)]
+    fn uart_register(&self, addr: u64) -> Option<u8> {
+        Bus::device_offset(addr, self.uart_base, Bus::UART_SIZE).map(|offset| offset as u8)
+    }
+
+    // The block device and the offset of its register addressed by addr, if any
+    #[inline(always)]
+    fn virtio_register(&self, addr: u64) -> Option<(&VirtioBlock, u64)> {
+        let virtio: &VirtioBlock = self.virtio.as_ref()?;
+        Bus::device_offset(addr, self.virtio_base, Bus::VIRTIO_SIZE).map(|offset| (virtio, offset))
+    }
+
+    // Write a register of the block device. Notified requests are served
+    // right away, the device reads and writes them in memory
+    #[cold]
+    fn write_virtio(&mut self, offset: u64, data: u64, size: memory::AccessSize) -> Option<()> {
+        let mut virtio: VirtioBlock = self.virtio.take()?;
+        let result: Option<()> = virtio.write(offset, data, size);
+        if virtio.is_notified() {
+            virtio.process(self);
+        }
+        self.virtio = Some(virtio);
+        result
+    }
+
+    // Read a register of the RTC. Only reading the time goes through the
+    // input log, the other registers do not depend on it
+    #[cold]
+    fn read_goldfish(&mut self, offset: u64) -> Option<u64> {
+        let now: Duration = match offset {
+            GoldfishRtc::TIME_LOW => self.read_rtc_time(),
+            _ => Duration::ZERO
+        };
+        self.goldfish.read(offset, now)
+    }
+
+    // Write a register of the RTC: setting the time sets the wall clock
+    // of the guest, which from then on follows mtime
+    #[cold]
+    fn write_goldfish(&mut self, offset: u64, data: u64) -> Option<()> {
+        if let Some(time) = self.goldfish.write(offset, data)? {
+            let mtime: u64 = self.clint.get_mtime();
+            self.rtc.set(time, mtime);
         }
+        Some(())
+    }
+
+    // Position of the attached device addressed by addr and offset inside
+    // its window, if any
+    #[inline(always)]
+    fn attached_device(&self, addr: u64) -> Option<(usize, u64)> {
+        self.devices.iter().enumerate().find_map(|(index, mapped)| {
+            Bus::device_offset(addr, mapped.base, mapped.size).map(|offset| (index, offset))
+        })
+    }
+
+    // Position of the memory region that contains addr, if any
+    #[inline(always)]
+    fn region_index(&self, addr: u64) -> Option<usize> {
+        self.regions.iter().position(|region| addr.wrapping_sub(region.base) < region.memory.get_size() as u64)
+    }
+
+    #[inline(always)]
+    fn region(&self, addr: u64) -> Option<&MemoryRegion> {
+        self.region_index(addr).map(|index| &self.regions[index])
     }
 
+    /// Bytes of memory (RAM or ROM) from addr to the end of the region it
+    /// is in, 0 if it is not in one
+    pub fn mapped_bytes(&self, addr: u64) -> u64 {
+        self.region(addr).map_or(0, |region| region.base.wrapping_add(region.memory.get_size() as u64).wrapping_sub(addr))
+    }
+
+    /// Simulate the caches on the accesses of the CPU (see CacheSim)
+    pub fn enable_cache_sim(&mut self, cache_sim: CacheSim) {
+        self.cache_sim = Some(cache_sim);
+    }
+
+    /// Get the simulated caches, if they are enabled
+    pub fn get_cache_sim(&self) -> Option<&CacheSim> {
+        self.cache_sim.as_ref()
+    }
+
+    /// Fetch of an instruction at addr, seen by the simulated I$ if enabled.
+    /// The memory trace attributes the accesses that follow to it
+    #[inline(always)]
+    pub fn simulate_fetch(&mut self, addr: u64) {
+        if let Some(cache_sim) = self.cache_sim.as_mut() {
+            cache_sim.icache.access(addr);
+        }
+        if let Some(trace) = self.mem_trace.as_mut() {
+            trace.set_pc(addr);
+        }
+    }
+
+    /// Log the loads and stores that go through the bus
+    pub fn enable_mem_trace(&mut self, trace: MemTrace) {
+        self.mem_trace = Some(trace);
+    }
+
+    /// Give a range of memory the access rights of the segment loaded there
+    pub fn set_permissions(&mut self, range: Range<u64>, permissions: Permissions) {
+        if !range.is_empty() {
+            self.permissions.push((range, permissions));
+        }
+    }
+
+    /// Choose whether the stores of the program to the segments that are
+    /// not writable fail (the default)
+    pub fn set_protection(&mut self, protect: bool) {
+        self.protect = protect;
+    }
+
+    /// Check if the program may store size bytes at addr: the memory
+    /// outside of the segments of the executables is always writable
+    #[inline(always)]
+    pub fn is_writable(&self, addr: u64, size: memory::AccessSize) -> bool {
+        if !self.protect || self.permissions.is_empty() {
+            return true;
+        }
+        let end: u64 = addr.wrapping_add(size.bytes() as u64);
+        !self.permissions.iter().any(|(range, permissions)| !permissions.write && addr < range.end && end > range.start)
+    }
+
+    /// Check if the devices wrote to memory since the last call (their
+    /// writes do not go through the CPU, which must forget the
+    /// instructions it decoded)
+    pub fn take_device_writes(&mut self) -> bool {
+        std::mem::take(&mut self.device_writes)
+    }
+
+    // Load or store at addr, seen by the simulated D$ unless it addresses
+    // the registers of a device
+    #[inline(never)]
+    fn simulate_data_access(&mut self, addr: u64) {
+        let cacheable: bool = self.region_index(addr).is_some() ||
+            Bus::device_offset(addr, self.bootrom_base, self.bootrom.get_size() as u64).is_some();
+        if let Some(cache_sim) = self.cache_sim.as_mut().filter(|_| cacheable) {
+            cache_sim.dcache.access(addr);
+        }
+    }
+
+    /// Advance the state of the devices by one step. It is called once
+    /// per executed instruction
+    #[inline(always)]
+    pub fn cycle(&mut self) {
+        self.cycles += 1;
+        if self.input_log.is_some() {
+            self.cycle_logged();
+            return;
+        }
+        if let Some(byte) = self.uart.cycle() {
+            self.log_console(byte);
+        }
+        self.clint.tick();
+        self.plic.set_source(Bus::UART_IRQ, self.uart.interrupt());
+        if let Some(virtio) = self.virtio.as_ref() {
+            self.plic.set_source(Bus::VIRTIO_IRQ, virtio.interrupt());
+        }
+        for mapped in self.devices.iter_mut() {
+            mapped.device.cycle();
+            if let Some(irq) = mapped.irq {
+                self.plic.set_source(irq, mapped.device.interrupt());
+            }
+        }
+    }
+
+    // Same as cycle, but the characters received by the UART and the
+    // interrupt lines of the attached devices are recorded in the input
+    // log, or taken from it when it is replayed
+    #[inline(never)]
+    fn cycle_logged(&mut self) {
+        if let Some(byte) = self.uart.transmit() {
+            self.log_console(byte);
+        }
+        let log: &mut InputLog = match self.input_log.as_mut() {
+            Some(log) => log,
+            None => return
+        };
+        if log.is_replaying() {
+            while let Some(InputEvent::Uart(byte)) = log.replay(self.cycles, |event| matches!(event, InputEvent::Uart(_))) {
+                self.uart.receive(byte);
+            }
+        } else if let Some(byte) = self.uart.poll_input() {
+            log.record(self.cycles, InputEvent::Uart(byte));
+        }
+        self.clint.tick();
+        self.plic.set_source(Bus::UART_IRQ, self.uart.interrupt());
+        if let Some(virtio) = self.virtio.as_ref() {
+            self.plic.set_source(Bus::VIRTIO_IRQ, virtio.interrupt());
+        }
+        for (index, mapped) in self.devices.iter_mut().enumerate() {
+            mapped.device.cycle();
+            if let Some(irq) = mapped.irq {
+                self.plic.set_source(irq, log.device_interrupt(self.cycles, index, mapped.device.interrupt()));
+            }
+        }
+    }
+
+    /// Record the inputs that come from outside of the machine in log, or
+    /// replay them from it (see InputLog)
+    pub fn set_input_log(&mut self, log: InputLog) {
+        self.input_log = Some(log);
+    }
+
+    /// Get the input log, if the inputs are being recorded or replayed
+    pub fn get_input_log(&self) -> Option<&InputLog> {
+        self.input_log.as_ref()
+    }
+
+    /// Time of the real-time clock read by the program. When it is the
+    /// time of the host, it goes through the input log (if any)
+    pub fn read_rtc_time(&mut self) -> Duration {
+        if self.rtc.is_host() {
+            self.read_host_time()
+        } else {
+            self.rtc.now(self.clint.get_mtime())
+        }
+    }
+
+    /// Time of the host (since the epoch), through the input log (if any)
+    pub fn read_host_time(&mut self) -> Duration {
+        let now: Duration = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        match self.input_log.as_mut() {
+            Some(log) => Duration::from_micros(log.host_time(self.cycles, now.as_micros() as u64)),
+            None => now
+        }
+    }
+
+    /// Read the standard input of the program, through the input log (if any)
+    pub fn read_stdin(&mut self, data: &mut [u8]) -> std::io::Result<usize> {
+        match self.input_log.as_mut() {
+            Some(log) => log.read_stdin(self.cycles, data, &self.stdio),
+            None => self.stdio.read_stdin(data)
+        }
+    }
+
+    /// Write to the standard output of the program
+    pub fn write_stdout(&self, data: &[u8]) -> std::io::Result<()> {
+        self.stdio.write_stdout(data)
+    }
+
+    /// Write to the standard error of the program
+    pub fn write_stderr(&self, data: &[u8]) -> std::io::Result<()> {
+        self.stdio.write_stderr(data)
+    }
+
+    /// Redirect the standard streams of the program
+    pub fn set_stdio(&mut self, stdio: GuestStdio) {
+        self.stdio = stdio;
+    }
+
+    /// Attach a device at base, with its interrupt connected to a PLIC
+    /// source (if any). It cannot overlap the built-in devices, while it
+    /// hides the memory behind it
+    pub fn add_device(&mut self, base: u64, irq: Option<usize>, device: Box<dyn Device>) -> Result<(), String> {
+        let size: u64 = device.size();
+        self.map_device(base, size, irq, device)
+    }
+
+    /// Attach another UART, talking to console (or to the terminal, but
+    /// without receiving anything from it, since the keyboard goes to the
+    /// UART of the machine)
+    pub fn add_uart(&mut self, spec: UartSpec, console: Option<SharedHostConsole>) -> Result<(), String> {
+        let mut uart: UART = UART::new();
+        if let Some(console) = console {
+            uart.set_console(console);
+        }
+        self.add_device(spec.base, spec.irq, Box::new(uart))
+    }
+
+    /// Attach a device in the window [base, base + size), which can be
+    /// larger than its registers (e.g. a UART given a whole page). Any
+    /// device can be attached this way, including further instances of
+    /// the built-in ones
+    pub fn attach(&mut self, device: Box<dyn Device>, base: u64, size: u64) -> Result<(), String> {
+        self.map_device(base, size, None, device)
+    }
+
+    fn map_device(&mut self, base: u64, size: u64, irq: Option<usize>, device: Box<dyn Device>) -> Result<(), String> {
+        let end: u64 = match base.checked_add(size) {
+            Some(end) if size > 0 => end,
+            _ => return Err(format!("Device {} has an invalid size", device.name()))
+        };
+        if let Some(irq) = irq {
+            if irq == 0 || irq >= Plic::NUM_SOURCES || irq == Bus::UART_IRQ || irq == Bus::RTC_IRQ || (self.virtio.is_some() && irq == Bus::VIRTIO_IRQ) {
+                return Err(format!("Device {} cannot use interrupt source {}", device.name(), irq));
+            }
+        }
+        if let Some((name, other_base)) = self.find_overlap(base, end) {
+            return Err(format!("Device {} overlaps with {} at 0x{:x}", device.name(), name, other_base));
+        }
+        self.devices.push(MappedDevice { base, size, irq, device });
+        Ok(())
+    }
+
+    // Name and base of a device mapped in [base, end), if any
+    fn find_overlap(&self, base: u64, end: u64) -> Option<(&str, u64)> {
+        let builtin = [("the boot ROM", self.bootrom_base, Bus::BOOTROM_MAX_SIZE), ("the CLINT", self.clint_base, Bus::CLINT_SIZE),
+                       ("the PLIC", self.plic_base, Bus::PLIC_SIZE), ("the UART", self.uart_base, Bus::UART_SIZE),
+                       ("the log port", self.logport_base, Bus::LOGPORT_SIZE), ("the RTC", self.rtc_base, Bus::RTC_SIZE)];
+        let virtio = self.virtio.as_ref().map(|_| ("the virtio block device", self.virtio_base, Bus::VIRTIO_SIZE));
+        let attached = self.devices.iter().map(|other| (other.device.name(), other.base, other.size));
+        builtin.into_iter().chain(virtio).chain(attached)
+            .find(|(_, other_base, other_size)| base < other_base.saturating_add(*other_size) && *other_base < end)
+            .map(|(name, other_base, _)| (name, other_base))
+    }
+
+    /// Check that a window handled outside of the bus (e.g. the test
+    /// finisher) does not shadow a device or memory
+    pub fn check_unmapped(&self, name: &str, base: u64, size: u64) -> Result<(), String> {
+        let end: u64 = base.checked_add(size).ok_or(format!("The {} has an invalid size", name))?;
+        if let Some((other, other_base)) = self.find_overlap(base, end) {
+            return Err(format!("The {} overlaps with {} at 0x{:x}", name, other, other_base));
+        }
+        if self.peek(base, memory::AccessSize::BYTE).is_ok() || self.peek(end - 1, memory::AccessSize::BYTE).is_ok() {
+            return Err(format!("The {} overlaps with memory at 0x{:x}", name, base));
+        }
+        Ok(())
+    }
+
+    /// Attach a drive: a virtio block device at the virtio base address,
+    /// with its interrupt connected to PLIC source VIRTIO_IRQ
+    pub fn attach_drive(&mut self, drive: VirtioBlock) -> Result<(), String> {
+        if self.virtio.is_some() {
+            return Err("Only one drive can be attached".to_string());
+        }
+        if let Some((name, other_base)) = self.find_overlap(self.virtio_base, self.virtio_base + Bus::VIRTIO_SIZE) {
+            return Err(format!("The virtio block device overlaps with {} at 0x{:x}", name, other_base));
+        }
+        if let Some(mapped) = self.devices.iter().find(|mapped| mapped.irq == Some(Bus::VIRTIO_IRQ)) {
+            return Err(format!("Device {} already uses interrupt source {}", mapped.device.name(), Bus::VIRTIO_IRQ));
+        }
+        self.virtio = Some(drive);
+        Ok(())
+    }
+
+    /// Describe the machine for the device tree: the RAM regions, the
+    /// interrupt controllers and the devices that have compatible strings
+    pub fn describe(&self, xlen: Xlen) -> Platform {
+        // UARTs are serial nodes, whatever their name
+        let node = |name: &str, compatible: &[&str], base: u64, size: u64, irq: Option<usize>| DeviceNode {
+            name: if compatible.contains(&"ns16550a") { "serial".to_string() } else { name.to_string() },
+            compatible: compatible.iter().map(|compatible| compatible.to_string()).collect(),
+            base,
+            size,
+            irq,
+            properties: if compatible.contains(&"ns16550a") {
+                vec![("clock-frequency".to_string(), UART::CLOCK_FREQUENCY)]
+            } else {
+                Vec::new()
+            }
+        };
+        let mut devices: Vec<DeviceNode> = vec![node("serial", self.uart.compatible(), self.uart_base, Bus::UART_SIZE, Some(Bus::UART_IRQ))];
+        devices.push(node("rtc", &["google,goldfish-rtc"], self.rtc_base, Bus::RTC_SIZE, Some(Bus::RTC_IRQ)));
+        if self.virtio.is_some() {
+            devices.push(node("virtio_mmio", &["virtio,mmio"], self.virtio_base, Bus::VIRTIO_SIZE, Some(Bus::VIRTIO_IRQ)));
+        }
+        devices.extend(self.devices.iter()
+            .filter(|mapped| !mapped.device.compatible().is_empty())
+            .map(|mapped| node(mapped.device.name(), mapped.device.compatible(), mapped.base, mapped.size, mapped.irq)));
+        Platform {
+            xlen,
+            timebase_frequency: Clint::TIMEBASE_FREQUENCY,
+            memory: self.regions.iter()
+                .filter(|region| region.kind == RegionKind::Ram)
+                .map(|region| Region { base: region.base, size: region.memory.get_size() as u64 })
+                .collect(),
+            clint_base: self.clint_base,
+            clint_size: Bus::CLINT_SIZE,
+            plic_base: self.plic_base,
+            plic_size: Bus::PLIC_SIZE,
+            plic_sources: Plic::NUM_SOURCES - 1,
+            devices,
+            console: Some(self.uart_base)
+        }
+    }
+
+    /// Get the attached drive, if any
+    pub fn get_drive(&self) -> Option<&VirtioBlock> {
+        self.virtio.as_ref()
+    }
+
+    pub fn get_drive_mut(&mut self) -> Option<&mut VirtioBlock> {
+        self.virtio.as_mut()
+    }
+
+    /// Address the virtio block device is mapped at
+    pub fn get_virtio_base(&self) -> u64 {
+        self.virtio_base
+    }
+
+    /// Address the UART is mapped at
+    pub fn get_uart_base(&self) -> u64 {
+        self.uart_base
+    }
+
+    #[cold]
+    fn log_console(&mut self, byte: u8) {
+        if let Some(capture) = self.console_capture.as_mut() {
+            capture.push(byte);
+        }
+        if let Some(log) = self.console_log.as_ref() {
+            if let Ok(mut log) = log.lock() {
+                log.record(byte, self.cycles);
+            }
+        }
+    }
+
+    /// Print a character that the program sent to the host console without
+    /// going through the UART (e.g. through HTIF), copying it to the log as well
+    pub fn write_console(&mut self, byte: u8) {
+        self.uart.print(byte);
+        self.log_console(byte);
+    }
+
+    /// Take the character received by the UART, if any, as the console
+    /// drivers of firmware do by polling it
+    pub fn read_console(&mut self) -> Option<u8> {
+        self.uart.take_received()
+    }
+
+    /// Send the host console of the UART (and HTIF) to console
+    pub fn set_host_console(&mut self, console: SharedHostConsole) {
+        self.uart.set_console(console);
+    }
+
+    /// Keep the characters sent to the console (through the UART or HTIF)
+    /// until they are taken with take_console_output
+    pub fn capture_console(&mut self) {
+        self.console_capture.get_or_insert_with(Vec::new);
+    }
+
+    /// Take the characters sent to the console since the last call
+    pub fn take_console_output(&mut self) -> Vec<u8> {
+        self.console_capture.as_mut().map(std::mem::take).unwrap_or_default()
+    }
+
+    /// Copy the characters sent through the UART to a console log
+    pub fn set_console_log(&mut self, log: SharedConsoleLog) {
+        self.console_log = Some(log);
+    }
+
+    /// Interrupt lines driven by the CLINT (MTIP and MSIP) and by the
+    /// PLIC (MEIP and SEIP), as bits of mip
+    #[inline(always)]
+    pub fn interrupt_lines(&self) -> u64 {
+        self.clint.interrupt_lines() | self.plic.interrupt_lines()
+    }
+
+    /// Raise an interrupt line from the host, as a device would. The
+    /// guest acknowledges it the usual way: msip is set until the program
+    /// clears it, mtimecmp is set to mtime until the program moves it, and
+    /// a PLIC source becomes pending once, until it is claimed
+    pub fn raise_irq(&mut self, line: InterruptLine) {
+        match line {
+            InterruptLine::Software => self.clint.set_msip(true),
+            InterruptLine::Timer => {
+                let mtime: u64 = self.clint.get_mtime();
+                self.clint.set_mtimecmp(mtime);
+            },
+            InterruptLine::External(source) => self.plic.trigger(source)
+        }
+    }
+
+    /// Get the CLINT
+    pub fn get_clint(&self) -> &Clint {
+        &self.clint
+    }
+
+    /// Get the CLINT to change the guest time
+    pub fn get_clint_mut(&mut self) -> &mut Clint {
+        &mut self.clint
+    }
+
+    /// Get the real-time clock of the guest
+    pub fn get_rtc(&self) -> &Rtc {
+        &self.rtc
+    }
+
+    pub fn get_rtc_mut(&mut self) -> &mut Rtc {
+        &mut self.rtc
+    }
+
+    /// Move the DRAM. Only the default layout can be changed, a machine
+    /// with a fixed memory map ignores it
     pub fn set_dram_offset(&mut self, offset: u64) {
-        self.dram_offset = offset;
+        if self.relocatable {
+            self.regions[Bus::DRAM_REGION].base = offset;
+        }
+    }
+
+    pub fn get_dram_offset(&self) -> u64 {
+        self.regions[Bus::DRAM_REGION].base
     }
 
+    /// Move the ROM. Only the default layout can be changed, a machine
+    /// with a fixed memory map ignores it
     pub fn set_rom_offset(&mut self, offset: u64) {
-        self.rom_offset = offset;
+        if self.relocatable {
+            self.regions[Bus::TEXT_REGION].base = offset;
+        }
+    }
+
+    /// Make all the memories (RAM, ROM and boot ROM) copy-on-write for
+    /// the clones of the bus (see Memory::share)
+    pub fn share_memory(&mut self) -> Result<(), String> {
+        for region in self.regions.iter_mut() {
+            region.memory.share()?;
+        }
+        self.bootrom.share()
+    }
+
+    /// Fill the RAM according to an initialization mode
+    pub fn init_dram(&mut self, init: memory::MemoryInit) {
+        for region in self.regions.iter_mut().filter(|region| region.kind == RegionKind::Ram) {
+            region.memory.fill(init);
+        }
     }
 
     pub fn get_dram_size(&self) -> usize {
-        self.dram.get_size()
+        self.regions[Bus::DRAM_REGION].memory.get_size()
     }
 
     pub fn get_rom_size(&self) -> usize {
-        self.rom.get_size()
+        self.regions.iter()
+            .filter(|region| region.kind != RegionKind::Ram)
+            .map(|region| region.memory.get_size())
+            .sum()
     }
 
-    pub fn write_from_buf(&mut self, addr: u64, buf: &[u8]) {
-        if addr < self.dram_offset {
-            self.rom.store_n_bytes(buf, addr - self.rom_offset, buf.len());
-        } else {
-            self.dram.store_n_bytes(buf, addr - self.dram_offset, buf.len());
+    /// Address the boot ROM is mapped at
+    pub fn get_bootrom_base(&self) -> u64 {
+        self.bootrom_base
+    }
+
+    /// Map a boot ROM image at the boot ROM base address. It shadows whatever else
+    /// would be found at the same addresses
+    pub fn load_bootrom(&mut self, image: &[u8]) -> Result<(), String> {
+        if image.len() as u64 > Bus::BOOTROM_MAX_SIZE {
+            return Err(format!("Boot ROM image is too large ({} bytes, at most {} are allowed)",
+                               image.len(), Bus::BOOTROM_MAX_SIZE));
         }
+        self.bootrom = memory::Memory::new(Some(image.len()));
+        self.bootrom.store_buffer(image, 0).map_err(|err| format!("Could not load the boot ROM: {}", err))
+    }
+
+    /// Copy a buffer into ROM or RAM (the program is loaded this way, so
+    /// ROM regions can be written too). In the default layout the ROM grows
+    /// to fit the buffer, otherwise a buffer that does not fit in the region
+    /// it starts in is reported as an error
+    pub fn write_from_buf(&mut self, addr: u64, buf: &[u8]) -> Result<(), memory::MemError> {
+        let index: Option<usize> = if self.relocatable && addr < self.get_dram_offset() {
+            let text: &mut MemoryRegion = &mut self.regions[Bus::TEXT_REGION];
+            addr.checked_sub(text.base).map(|paddr| {
+                text.memory.grow((paddr as usize).saturating_add(buf.len()));
+                Bus::TEXT_REGION
+            })
+        } else {
+            self.region_index(addr)
+        };
+        let result: Result<(), memory::MemError> = match index {
+            Some(index) => {
+                let region: &mut MemoryRegion = &mut self.regions[index];
+                region.memory.store_buffer(buf, addr - region.base)
+            },
+            None => Err(memory::MemError::OutOfBounds(addr))
+        };
+        // Report the full address rather than the offset inside the device
+        result.map_err(|_| memory::MemError::OutOfBounds(addr))
     }
 
     pub fn get_device(&self) -> &memory::Memory {
-        &self.dram
+        &self.regions[Bus::DRAM_REGION].memory
+    }
+}
+
+// The devices read and write the RAM and ROM regions directly
+impl GuestMemory for Bus {
+    fn read_bytes(&self, addr: u64, data: &mut [u8]) -> Option<()> {
+        let region: &MemoryRegion = self.region(addr)?;
+        let start: usize = (addr - region.base) as usize;
+        for (offset, byte) in data.iter_mut().enumerate() {
+            *byte = region.memory.load((start + offset) as u64, memory::AccessSize::BYTE).ok()? as u8;
+        }
+        Some(())
+    }
+
+    fn write_bytes(&mut self, addr: u64, data: &[u8]) -> Option<()> {
+        let index: usize = self.region_index(addr)?;
+        let region: &mut MemoryRegion = &mut self.regions[index];
+        self.device_writes = true;
+        region.memory.store_buffer(data, addr - region.base).ok()
+    }
+}
+
+// The console log is not part of the snapshot, it keeps going to the file
+// chosen for the running emulator. Neither are the memory map, the drive
+// and the attached devices: a snapshot can only be restored on the same
+// machine
+impl Snapshot for Bus {
+    fn save(&self, writer: &mut SnapshotWriter) {
+        writer.put_u64(self.regions.len() as u64);
+        for region in &self.regions {
+            writer.put_u64(region.base);
+            region.memory.save(writer);
+        }
+        self.bootrom.save(writer);
+        self.uart.save(writer);
+        self.clint.save(writer);
+        self.rtc.save(writer);
+        self.goldfish.save(writer);
+        self.logport.save(writer);
+        self.plic.save(writer);
+        writer.put_u64(self.cycles);
+    }
+
+    fn restore(&mut self, reader: &mut SnapshotReader) -> Result<(), String> {
+        if reader.get_u64()? != self.regions.len() as u64 {
+            return Err("Snapshot was taken on a machine with a different memory map".to_string());
+        }
+        for region in self.regions.iter_mut() {
+            region.base = reader.get_u64()?;
+            region.memory.restore(reader)?;
+        }
+        self.bootrom.restore(reader)?;
+        self.uart.restore(reader)?;
+        self.clint.restore(reader)?;
+        self.rtc.restore(reader)?;
+        self.goldfish.restore(reader)?;
+        self.logport.restore(reader)?;
+        self.plic.restore(reader)?;
+        self.cycles = reader.get_u64()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+    use crate::bus::{Bus, InterruptLine, Permissions};
+    use crate::cli::BufferConsole;
+    use crate::clint::Clint;
+    use crate::cpu::Xlen;
+    use crate::device::Device;
+    use crate::uart::{UartSpec, UART};
+    use crate::virtio::{GuestMemory, VirtioBlock};
+    use crate::machine::MachineConfig;
+    use crate::memory::AccessSize;
+    use crate::memory::MemError;
+
+    #[test]
+    fn device_mapping_test() {
+        let mut bus: Bus = Bus::new(Some(0x100));
+        // LSR: transmitter empty after reset
+        assert_eq!(bus.read(Bus::UART_BASE + 5, AccessSize::BYTE), Ok(0x60));
+        assert_eq!(bus.write(b'!' as u64, Bus::UART_BASE, AccessSize::BYTE), Ok(()));
+        assert_eq!(bus.peek(Bus::UART_BASE + 5, AccessSize::BYTE), Ok(0x0));
+        bus.cycle();
+        assert_eq!(bus.peek(Bus::UART_BASE + 5, AccessSize::BYTE), Ok(0x60));
+        assert_eq!(bus.read(Bus::UART_BASE + 1, AccessSize::WORD), Err(MemError::Misaligned(Bus::UART_BASE + 1)));
+        // CLINT registers
+        assert_eq!(bus.write(0x10, Bus::CLINT_BASE + 0x4000, AccessSize::DOUBLEWORD), Ok(()));
+        assert_eq!(bus.read(Bus::CLINT_BASE + 0xbff8, AccessSize::DOUBLEWORD), Ok(1));
+        assert_eq!(bus.read(Bus::CLINT_BASE + 0x100, AccessSize::WORD), Err(MemError::OutOfBounds(Bus::CLINT_BASE + 0x100)));
+        // Log port: a record of 0 bytes is emitted right away
+        assert_eq!(bus.write(0, Bus::LOGPORT_BASE, AccessSize::WORD), Ok(()));
+        assert_eq!(bus.read(Bus::LOGPORT_BASE + 8, AccessSize::WORD), Err(MemError::OutOfBounds(Bus::LOGPORT_BASE + 8)));
+        // RTC: the program sets the wall clock (3 s) and reads it back
+        assert_eq!(bus.write(0, Bus::RTC_BASE + 0x4, AccessSize::WORD), Ok(()));
+        assert_eq!(bus.write(3_000_000_000, Bus::RTC_BASE, AccessSize::WORD), Ok(()));
+        assert!(!bus.get_rtc().is_host());
+        assert_eq!(bus.read(Bus::RTC_BASE, AccessSize::WORD), Ok(3_000_000_000));
+        assert_eq!(bus.read(Bus::RTC_BASE + 0x4, AccessSize::WORD), Ok(0));
+        assert_eq!(bus.read(Bus::RTC_BASE + 0x20, AccessSize::WORD), Err(MemError::OutOfBounds(Bus::RTC_BASE + 0x20)));
+        // UART interrupts (THR empty) go through the PLIC
+        assert_eq!(bus.interrupt_lines(), 0);
+        bus.write(1, Bus::PLIC_BASE + 4 * Bus::UART_IRQ as u64, AccessSize::WORD).unwrap();
+        bus.write(1 << Bus::UART_IRQ, Bus::PLIC_BASE + 0x2000, AccessSize::WORD).unwrap();
+        bus.write(0x2, Bus::UART_BASE + 1, AccessSize::BYTE).unwrap();
+        bus.cycle();
+        assert_eq!(bus.interrupt_lines(), 1 << 11);
+        assert_eq!(bus.read(Bus::PLIC_BASE + 0x200004, AccessSize::WORD), Ok(Bus::UART_IRQ as u64));
+        assert_eq!(bus.interrupt_lines(), 0);
+        // DRAM is still reachable
+        assert_eq!(bus.write(0x1234, 0x20010, AccessSize::WORD), Ok(()));
+        assert_eq!(bus.read(0x20010, AccessSize::WORD), Ok(0x1234));
+        // The boot ROM can be read but not written
+        assert!(bus.load_bootrom(&vec![0; Bus::BOOTROM_MAX_SIZE as usize + 1]).is_err());
+        bus.load_bootrom(&[0x97, 0x02, 0x00, 0x00]).unwrap();
+        assert_eq!(bus.read(Bus::BOOTROM_BASE, AccessSize::WORD), Ok(0x00000297));
+        assert_eq!(bus.write(0, Bus::BOOTROM_BASE, AccessSize::WORD), Err(MemError::OutOfBounds(Bus::BOOTROM_BASE)));
+        assert_eq!(bus.read(Bus::BOOTROM_BASE + 4, AccessSize::WORD), Err(MemError::OutOfBounds(Bus::BOOTROM_BASE + 4)));
+    }
+
+    #[test]
+    fn machine_map_test() {
+        let machine: MachineConfig = MachineConfig::parse("
+            [[ram]]
+            base = 0x80000000
+            size = 0x1000
+            [[rom]]
+            base = 0x0
+            size = 0x100
+            [devices]
+            uart = 0x10010000
+        ").unwrap();
+        let mut bus: Bus = Bus::with_machine(&machine);
+        assert_eq!(bus.read(0x10010005, AccessSize::BYTE), Ok(0x60));
+        assert_eq!(bus.read(Bus::UART_BASE + 5, AccessSize::BYTE), Err(MemError::OutOfBounds(Bus::UART_BASE + 5)));
+        // The memory map does not follow the program
+        bus.set_dram_offset(0x20000);
+        assert_eq!(bus.get_dram_offset(), 0x80000000);
+        assert_eq!(bus.write(0x1234, 0x80000ffc, AccessSize::WORD), Ok(()));
+        assert_eq!(bus.read(0x80000ffc, AccessSize::WORD), Ok(0x1234));
+        assert_eq!(bus.write_from_buf(0x80000ffc, &[0; 8]), Err(MemError::OutOfBounds(0x80000ffc)));
+        // ROM is written when loading the program, but not by the program
+        assert_eq!(bus.write_from_buf(0x10, &[0x13, 0x00, 0x00, 0x00]), Ok(()));
+        assert_eq!(bus.read(0x10, AccessSize::WORD), Ok(0x13));
+        assert_eq!(bus.write(0, 0x10, AccessSize::WORD), Err(MemError::OutOfBounds(0x10)));
+        assert_eq!(bus.read(0x100, AccessSize::BYTE), Err(MemError::OutOfBounds(0x100)));
+    }
+
+    // Counter that raises its interrupt when it reaches its limit
+    #[derive(Clone)]
+    struct Counter {
+        count: u64,
+        limit: u64
+    }
+
+    impl Device for Counter {
+        fn name(&self) -> &str {
+            "counter"
+        }
+
+        fn size(&self) -> u64 {
+            0x10
+        }
+
+        fn read(&mut self, offset: u64, _size: AccessSize) -> Option<u64> {
+            match offset {
+                0x0 => Some(self.count),
+                _ => None
+            }
+        }
+
+        fn write(&mut self, offset: u64, data: u64, _size: AccessSize) -> Option<()> {
+            match offset {
+                0x8 => { self.limit = data; Some(()) },
+                _ => None
+            }
+        }
+
+        fn cycle(&mut self) {
+            self.count += 1;
+        }
+
+        fn interrupt(&self) -> bool {
+            self.count >= self.limit
+        }
+
+        fn clone_device(&self) -> Box<dyn Device> {
+            Box::new(self.clone())
+        }
+    }
+
+    #[test]
+    fn attached_device_test() {
+        const BASE: u64 = 0x10002000;
+        let mut bus: Bus = Bus::new(Some(0x100));
+        let counter: Counter = Counter { count: 0, limit: u64::MAX };
+        assert!(bus.add_device(Bus::UART_BASE + 0x80, None, Box::new(counter.clone())).is_err());
+        assert!(bus.add_device(BASE, Some(Bus::UART_IRQ), Box::new(counter.clone())).is_err());
+        bus.add_device(BASE, Some(3), Box::new(counter.clone())).unwrap();
+        assert!(bus.add_device(BASE + 0x8, None, Box::new(counter)).is_err());
+
+        assert_eq!(bus.write(2, BASE + 0x8, AccessSize::DOUBLEWORD), Ok(()));
+        assert_eq!(bus.read(BASE + 0x4, AccessSize::WORD), Err(MemError::OutOfBounds(BASE + 0x4)));
+        assert_eq!(bus.read(BASE + 0x2, AccessSize::WORD), Err(MemError::Misaligned(BASE + 0x2)));
+        // Peeking is not supported by the device
+        assert_eq!(bus.peek(BASE, AccessSize::DOUBLEWORD), Err(MemError::OutOfBounds(BASE)));
+        bus.write(1, Bus::PLIC_BASE + 4 * 3, AccessSize::WORD).unwrap();
+        bus.write(1 << 3, Bus::PLIC_BASE + 0x2000, AccessSize::WORD).unwrap();
+        bus.cycle();
+        assert_eq!(bus.interrupt_lines(), 0);
+        // Clones have their own copy of the device
+        let mut copy: Bus = bus.clone();
+        copy.cycle();
+        assert_eq!(copy.interrupt_lines(), 1 << 11);
+        assert_eq!(bus.read(BASE, AccessSize::DOUBLEWORD), Ok(1));
+    }
+
+    #[test]
+    fn raise_irq_test() {
+        let mut bus: Bus = Bus::new(Some(0x100));
+        bus.raise_irq(InterruptLine::parse("msip").unwrap());
+        assert_eq!(bus.interrupt_lines(), 1 << 3);
+        bus.write(0, Bus::CLINT_BASE, AccessSize::WORD).unwrap();
+        bus.raise_irq(InterruptLine::parse("mtip").unwrap());
+        assert_eq!(bus.interrupt_lines(), 1 << 7);
+        bus.write(u64::MAX, Bus::CLINT_BASE + 0x4000, AccessSize::DOUBLEWORD).unwrap();
+        // A PLIC source is raised once, the UART does not lower it
+        bus.write(1, Bus::PLIC_BASE + 4 * Bus::UART_IRQ as u64, AccessSize::WORD).unwrap();
+        bus.write(1 << Bus::UART_IRQ, Bus::PLIC_BASE + 0x2000, AccessSize::WORD).unwrap();
+        bus.raise_irq(InterruptLine::parse("10").unwrap());
+        bus.cycle();
+        assert_eq!(bus.interrupt_lines(), 1 << 11);
+        assert_eq!(bus.read(Bus::PLIC_BASE + 0x200004, AccessSize::WORD), Ok(Bus::UART_IRQ as u64));
+        bus.write(Bus::UART_IRQ as u64, Bus::PLIC_BASE + 0x200004, AccessSize::WORD).unwrap();
+        assert_eq!(bus.interrupt_lines(), 0);
+        assert!(InterruptLine::parse("0").is_err());
+        assert!(InterruptLine::parse("32").is_err());
+    }
+
+    #[test]
+    fn attach_test() {
+        const UART1_BASE: u64 = 0x10010000;
+        let mut bus: Bus = Bus::new(Some(0x100));
+        assert!(bus.attach(Box::new(UART::new()), UART1_BASE, 0).is_err());
+        assert!(bus.attach(Box::new(UART::new()), Bus::LOGPORT_BASE - 0x800, 0x1000).is_err());
+        // A second UART in a whole page, next to a second CLINT
+        bus.attach(Box::new(UART::new()), UART1_BASE, 0x1000).unwrap();
+        bus.attach(Box::new(Clint::new()), UART1_BASE + 0x1000, Bus::CLINT_SIZE).unwrap();
+        assert_eq!(bus.read(UART1_BASE + 5, AccessSize::BYTE), Ok(0x60));
+        assert_eq!(bus.write(b'a' as u64, UART1_BASE, AccessSize::BYTE), Ok(()));
+        assert_eq!(bus.peek(UART1_BASE + 5, AccessSize::BYTE), Ok(0x0));
+        // The whole window belongs to the UART, unused offsets read as 0
+        assert_eq!(bus.peek(UART1_BASE + 0xfff, AccessSize::BYTE), Ok(0x0));
+        bus.cycle();
+        assert_eq!(bus.peek(UART1_BASE + 5, AccessSize::BYTE), Ok(0x60));
+        assert_eq!(bus.read(UART1_BASE + 0x1000 + 0xbff8, AccessSize::DOUBLEWORD), Ok(1));
+        // The built-in UART is not affected
+        assert_eq!(bus.peek(Bus::UART_BASE + 5, AccessSize::BYTE), Ok(0x60));
+    }
+
+    #[test]
+    fn add_uart_test() {
+        const UART1_BASE: u64 = 0x10002000;
+        let first: Arc<Mutex<BufferConsole>> = Arc::new(Mutex::new(BufferConsole::new()));
+        let second: Arc<Mutex<BufferConsole>> = Arc::new(Mutex::new(BufferConsole::new()));
+        let mut bus: Bus = Bus::new(Some(0x100));
+        bus.set_host_console(first.clone());
+        bus.add_uart(UartSpec { base: UART1_BASE, irq: Some(11) }, Some(second.clone())).unwrap();
+        assert!(bus.add_uart(UartSpec { base: UART1_BASE + 0x100, irq: Some(Bus::UART_IRQ) }, None).is_err());
+        // Each UART talks to its own console
+        bus.write(b'a' as u64, Bus::UART_BASE, AccessSize::BYTE).unwrap();
+        bus.write(b'b' as u64, UART1_BASE, AccessSize::BYTE).unwrap();
+        second.lock().unwrap().send_input(b"c");
+        for _ in 0..256 {
+            bus.cycle();
+        }
+        assert_eq!(first.lock().unwrap().take_output(), b"a");
+        assert_eq!(second.lock().unwrap().take_output(), b"b");
+        assert_eq!(bus.read(Bus::UART_BASE + 5, AccessSize::BYTE), Ok(0x60));
+        assert_eq!(bus.read(UART1_BASE + 5, AccessSize::BYTE), Ok(0x61));
+        assert_eq!(bus.read(UART1_BASE, AccessSize::BYTE), Ok(b'c' as u64));
+        // Both are serial ports in the device tree
+        let serial: Vec<(String, u64, Option<usize>)> = bus.describe(Xlen::Rv64).devices.into_iter()
+            .filter(|node| node.name == "serial")
+            .map(|node| (node.name, node.base, node.irq)).collect();
+        assert_eq!(serial, vec![("serial".to_string(), Bus::UART_BASE, Some(Bus::UART_IRQ)),
+                                ("serial".to_string(), UART1_BASE, Some(11))]);
+    }
+
+    #[test]
+    fn protection_test() {
+        let mut bus: Bus = Bus::new(Some(0x1000));
+        // Nothing is protected before an executable is loaded
+        assert!(bus.is_writable(0x10, AccessSize::WORD));
+        bus.set_permissions(0x0..0x100, Permissions { read: true, write: false, execute: true });
+        bus.set_permissions(0x100..0x180, Permissions { read: true, write: false, execute: false });
+        bus.set_permissions(Bus::DATA_START_DEFAULT..Bus::DATA_START_DEFAULT + 0x100,
+                            Permissions { read: true, write: true, execute: false });
+        assert!(!bus.is_writable(0x10, AccessSize::WORD));
+        assert!(!bus.is_writable(0x17c, AccessSize::DOUBLEWORD));
+        assert!(bus.is_writable(0x180, AccessSize::BYTE));
+        assert!(bus.is_writable(Bus::DATA_START_DEFAULT + 0x8, AccessSize::DOUBLEWORD));
+        bus.set_protection(false);
+        assert!(bus.is_writable(0x10, AccessSize::WORD));
+    }
+
+    #[test]
+    fn drive_test() {
+        let mut bus: Bus = Bus::new(Some(0x100));
+        let drive: VirtioBlock = VirtioBlock::new("disk.img", vec![0; 1024]).unwrap();
+        assert_eq!(bus.read(Bus::VIRTIO_BASE, AccessSize::WORD), Err(MemError::OutOfBounds(Bus::VIRTIO_BASE)));
+        bus.attach_drive(drive.clone()).unwrap();
+        assert!(bus.attach_drive(drive).is_err());
+        assert_eq!(bus.read(Bus::VIRTIO_BASE, AccessSize::WORD), Ok(0x74726976));
+        assert_eq!(bus.read(Bus::VIRTIO_BASE + 0x100, AccessSize::DOUBLEWORD), Ok(2));
+        assert_eq!(bus.write(0, Bus::VIRTIO_BASE + 0x200, AccessSize::WORD), Err(MemError::OutOfBounds(Bus::VIRTIO_BASE + 0x200)));
+        // Writes of the devices to memory are reported once
+        assert!(!bus.take_device_writes());
+        assert_eq!(bus.write_bytes(Bus::DATA_START_DEFAULT, &[0x13, 0, 0, 0]), Some(()));
+        assert!(bus.take_device_writes());
+        assert!(!bus.take_device_writes());
+        // Its window and its interrupt source are taken
+        assert!(bus.attach(Box::new(UART::new()), Bus::VIRTIO_BASE + 0x800, 0x100).is_err());
+        assert!(bus.add_device(0x10020000, Some(Bus::VIRTIO_IRQ), Box::new(UART::new())).is_err());
     }
 }
@@ -0,0 +1,155 @@
+use std::fmt;
+use crate::expr::{parse_number, parse_size};
+
+/// Geometry of a simulated cache: total size, associativity and line
+/// size, all in bytes and powers of two
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct CacheConfig {
+    pub size: u64,
+    pub ways: u64,
+    pub line: u64
+}
+
+impl CacheConfig {
+    /// Parse a cache geometry written as <size>:<ways>:<line>, where the
+    /// size can end with k or m (e.g. "32k:4:64")
+    pub fn parse(spec: &str) -> Result<CacheConfig, String> {
+        let fields: Vec<&str> = spec.trim().split(':').collect();
+        if fields.len() != 3 {
+            return Err(format!("Invalid cache {}: expected <size>:<ways>:<line>, e.g. 32k:4:64", spec));
+        }
+        let config: CacheConfig = CacheConfig { size: parse_size(fields[0])?, ways: parse_number(fields[1])?, line: parse_number(fields[2])? };
+        if [config.size, config.ways, config.line].iter().any(|value| !value.is_power_of_two()) {
+            return Err(format!("Invalid cache {}: size, ways and line size must be powers of two", spec));
+        }
+        if config.ways * config.line > config.size {
+            return Err(format!("Invalid cache {}: {} ways of {}-byte lines do not fit in {} bytes",
+                               spec, config.ways, config.line, config.size));
+        }
+        Ok(config)
+    }
+
+    /// Number of sets
+    pub fn sets(&self) -> u64 {
+        self.size / (self.ways * self.line)
+    }
+}
+
+impl fmt::Display for CacheConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.size {
+            size if size >= 1 << 20 && size.is_multiple_of(1 << 20) => write!(f, "{} MiB", size >> 20)?,
+            size if size >= 1 << 10 && size.is_multiple_of(1 << 10) => write!(f, "{} KiB", size >> 10)?,
+            size => write!(f, "{} B", size)?
+        }
+        write!(f, ", {}-way, {}-byte lines", self.ways, self.line)
+    }
+}
+
+/// Set-associative cache with LRU replacement. Only the tags are kept:
+/// the data always comes from memory, so the cache has no effect on the
+/// execution and it only counts hits and misses. Stores allocate lines
+/// like loads do
+#[derive(Clone)]
+pub struct Cache {
+    config: CacheConfig,
+    // Tag of the line held by each way of each set (set * ways + way)
+    tags: Vec<Option<u64>>,
+    // When each way was last used, the oldest one is replaced
+    last_used: Vec<u64>,
+    accesses: u64,
+    misses: u64
+}
+
+impl Cache {
+    pub fn new(config: CacheConfig) -> Cache {
+        let lines: usize = (config.sets() * config.ways) as usize;
+        Cache { config, tags: vec![None; lines], last_used: vec![0; lines], accesses: 0, misses: 0 }
+    }
+
+    pub fn get_config(&self) -> CacheConfig {
+        self.config
+    }
+
+    /// Access the line containing addr, return true on a hit
+    pub fn access(&mut self, addr: u64) -> bool {
+        self.accesses += 1;
+        let line: u64 = addr / self.config.line;
+        let first: usize = ((line % self.config.sets()) * self.config.ways) as usize;
+        let ways: std::ops::Range<usize> = first..first + self.config.ways as usize;
+        if let Some(way) = ways.clone().find(|way| self.tags[*way] == Some(line)) {
+            self.last_used[way] = self.accesses;
+            return true;
+        }
+        self.misses += 1;
+        let victim: usize = ways.min_by_key(|way| self.last_used[*way]).unwrap_or(first);
+        self.tags[victim] = Some(line);
+        self.last_used[victim] = self.accesses;
+        false
+    }
+
+    pub fn get_accesses(&self) -> u64 {
+        self.accesses
+    }
+
+    pub fn get_misses(&self) -> u64 {
+        self.misses
+    }
+
+    /// Misses per access in percent, 0 if the cache was never accessed
+    pub fn miss_rate(&self) -> f64 {
+        if self.accesses == 0 { 0.0 } else { self.misses as f64 * 100.0 / self.accesses as f64 }
+    }
+}
+
+/// Split first-level caches seen by the CPU: instruction fetches go
+/// through the I$, loads and stores to memory through the D$. Accesses
+/// to the registers of the devices are not cached
+#[derive(Clone)]
+pub struct CacheSim {
+    pub icache: Cache,
+    pub dcache: Cache
+}
+
+impl CacheSim {
+    /// Default geometry of both caches
+    pub const DEFAULT_CONFIG: &'static str = "32k:4:64";
+
+    pub fn new(icache: CacheConfig, dcache: CacheConfig) -> CacheSim {
+        CacheSim { icache: Cache::new(icache), dcache: Cache::new(dcache) }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cache::{Cache, CacheConfig};
+
+    #[test]
+    fn config_test() {
+        let config: CacheConfig = CacheConfig::parse("32k:4:64").unwrap();
+        assert_eq!(config, CacheConfig { size: 32768, ways: 4, line: 64 });
+        assert_eq!(config.sets(), 128);
+        assert_eq!(config.to_string(), "32 KiB, 4-way, 64-byte lines");
+        assert!(CacheConfig::parse("48k:4:64").is_err());
+        assert!(CacheConfig::parse("256:8:64").is_err());
+        assert!(CacheConfig::parse("32k:4").is_err());
+    }
+
+    #[test]
+    fn lru_test() {
+        // 2 sets of 2 ways: lines 0, 2 and 4 all map to set 0
+        let mut cache: Cache = Cache::new(CacheConfig::parse("256:2:64").unwrap());
+        assert!(!cache.access(0x00));
+        assert!(cache.access(0x3f));
+        assert!(!cache.access(0x80));
+        assert!(cache.access(0x00));
+        // Line 2 is the least recently used one and it is replaced
+        assert!(!cache.access(0x100));
+        assert!(cache.access(0x00));
+        assert!(!cache.access(0x80));
+        // Set 1 is not affected
+        assert!(!cache.access(0x40));
+        assert_eq!((cache.get_accesses(), cache.get_misses()), (8, 5));
+        assert_eq!(cache.miss_rate(), 62.5);
+    }
+}
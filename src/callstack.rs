@@ -0,0 +1,82 @@
+/// A function that has been called and has not returned yet
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Frame {
+    // PC of the call instruction and address of the called function
+    pub call_pc: u64,
+    pub function: u64,
+    pub return_address: u64
+}
+
+/// Shadow stack of the calls made by the program (JAL and JALR that
+/// link in ra) and not returned from yet (JALR to ra without linking),
+/// to show how the program got to an instruction
+#[derive(Clone, Default)]
+pub struct CallStack {
+    frames: Vec<Frame>
+}
+
+impl CallStack {
+    // Deepest call chain that is kept: the outermost calls are forgotten
+    // first, so a program that calls without ever returning (e.g. jumps
+    // through jal ra in a loop) does not grow the stack forever
+    const MAX_DEPTH: usize = 4096;
+
+    pub fn new() -> CallStack {
+        CallStack { frames: Vec::new() }
+    }
+
+    /// A call instruction at pc jumps to function, returning to return_address
+    pub fn call(&mut self, pc: u64, function: u64, return_address: u64) {
+        if self.frames.len() == CallStack::MAX_DEPTH {
+            self.frames.remove(0);
+        }
+        self.frames.push(Frame { call_pc: pc, function, return_address });
+    }
+
+    /// A return instruction jumps to target. Frames are popped up to the
+    /// one that returns to target, so that functions left through
+    /// longjmp-like jumps do not stay on the stack. A return that does not
+    /// match any call (made before the tracking started) pops nothing
+    pub fn ret(&mut self, target: u64) {
+        if let Some(position) = self.frames.iter().rposition(|frame| frame.return_address == target) {
+            self.frames.truncate(position);
+        }
+    }
+
+    /// Active calls, from the outermost to the innermost
+    pub fn get_frames(&self) -> &[Frame] {
+        &self.frames
+    }
+
+    pub fn clear(&mut self) {
+        self.frames.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::callstack::{CallStack, Frame};
+
+    #[test]
+    fn call_stack_test() {
+        let mut stack: CallStack = CallStack::new();
+        stack.call(0x100, 0x200, 0x104);
+        stack.call(0x210, 0x300, 0x214);
+        stack.call(0x310, 0x400, 0x314);
+        assert_eq!(stack.get_frames().len(), 3);
+        stack.ret(0x314);
+        assert_eq!(stack.get_frames().last(), Some(&Frame { call_pc: 0x210, function: 0x300, return_address: 0x214 }));
+        // A return that matches no call is ignored
+        stack.ret(0x999);
+        assert_eq!(stack.get_frames().len(), 2);
+        // longjmp-like return to an outer function
+        stack.call(0x320, 0x500, 0x324);
+        stack.ret(0x104);
+        assert!(stack.get_frames().is_empty());
+        for i in 0..5000 {
+            stack.call(i, 0x200, i + 4);
+        }
+        assert_eq!(stack.get_frames().len(), 4096);
+        assert_eq!(stack.get_frames()[0].call_pc, 5000 - 4096);
+    }
+}
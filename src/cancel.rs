@@ -0,0 +1,67 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+#[cfg(unix)]
+use std::sync::atomic::AtomicUsize;
+
+// Set by the SIGINT handler, checked by the long running commands
+static REQUESTED: AtomicBool = AtomicBool::new(false);
+// Handler of SIGINT before the guard caught it, which a second Ctrl-C
+// goes to (e.g. the one of the keyboard, which restores the terminal)
+#[cfg(unix)]
+static PREVIOUS: AtomicUsize = AtomicUsize::new(libc::SIG_DFL);
+
+/// Catch Ctrl-C while a long command runs, so that it can be stopped and
+/// the user gets back to the prompt instead of losing the whole session.
+/// If the command does not stop, a second Ctrl-C terminates riviera as
+/// usual. The previous behaviour is restored when the guard is dropped.
+/// On other platforms than Unix Ctrl-C is not caught
+pub struct CtrlCGuard {
+    #[cfg(unix)]
+    previous: libc::sighandler_t,
+    #[cfg(not(unix))]
+    _private: ()
+}
+
+impl CtrlCGuard {
+    pub fn catch() -> CtrlCGuard {
+        REQUESTED.store(false, Ordering::Relaxed);
+        #[cfg(unix)]
+        unsafe {
+            let handler: libc::sighandler_t = on_sigint as *const () as libc::sighandler_t;
+            let previous: libc::sighandler_t = libc::signal(libc::SIGINT, handler);
+            // A guard inside another one keeps the handler of the outer one
+            if previous != handler {
+                PREVIOUS.store(previous, Ordering::Relaxed);
+            }
+            CtrlCGuard { previous }
+        }
+        #[cfg(not(unix))]
+        CtrlCGuard { _private: () }
+    }
+
+    /// Check if Ctrl-C has been pressed since the guard was created
+    pub fn is_requested(&self) -> bool {
+        REQUESTED.load(Ordering::Relaxed)
+    }
+}
+
+impl Drop for CtrlCGuard {
+    fn drop(&mut self) {
+        #[cfg(unix)]
+        unsafe {
+            libc::signal(libc::SIGINT, self.previous);
+        }
+    }
+}
+
+#[cfg(unix)]
+extern "C" fn on_sigint(signal: libc::c_int) {
+    if !REQUESTED.swap(true, Ordering::Relaxed) {
+        return;
+    }
+    // Ctrl-C again: the command did not stop, give up
+    unsafe {
+        let previous: libc::sighandler_t = PREVIOUS.load(Ordering::Relaxed);
+        libc::signal(signal, if previous == libc::SIG_IGN { libc::SIG_DFL } else { previous });
+        libc::raise(signal);
+    }
+}
@@ -1,29 +1,241 @@
-use std::io::Write;
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::sync::{Arc, Mutex};
+use crate::keyboard::Keyboard;
 
+/// Console of the host that the guest talks to: where the characters it
+/// sends are shown and where the characters it receives are typed. The
+/// terminal of the emulator by default, it can be replaced when riviera
+/// is embedded (e.g. by a web page when built for WebAssembly)
+pub trait HostConsole: Send {
+    /// Show characters sent by the guest
+    fn write(&mut self, bytes: &[u8]);
+
+    /// Read a line typed for the guest into line, return its length
+    /// (0 if there is nothing to read)
+    fn read_line(&mut self, line: &mut String) -> std::io::Result<usize> {
+        let _ = line;
+        Ok(0)
+    }
+
+    /// Take a character typed for the guest, if there is one, without
+    /// blocking
+    fn read_byte(&mut self) -> Option<u8> {
+        None
+    }
+}
+
+pub type SharedHostConsole = Arc<Mutex<dyn HostConsole>>;
+
+/// Standard streams of the guest: the ones of the emulator, unless they
+/// are redirected to files, so that what the program prints is not mixed
+/// with the messages of the emulator. They are shared (and not reopened)
+/// when the emulator is cloned
+#[derive(Clone, Default)]
+pub struct GuestStdio {
+    stdin: Option<Arc<File>>,
+    stdout: Option<Arc<File>>,
+    stderr: Option<Arc<File>>
+}
+
+impl GuestStdio {
+    pub fn new() -> GuestStdio {
+        GuestStdio::default()
+    }
+
+    /// Redirect the streams that are given a file: stdin is read from it,
+    /// stdout and stderr are created (or truncated). They share the file
+    /// if they are redirected to the same one
+    pub fn open(stdin: Option<&str>, stdout: Option<&str>, stderr: Option<&str>) -> Result<GuestStdio, String> {
+        let create = |filename: &str| File::create(filename).map(Arc::new)
+            .map_err(|why| format!("Could not create {}: {}", filename, why));
+        let stdin_file: Option<Arc<File>> = stdin.map(|filename| File::open(filename).map(Arc::new)
+            .map_err(|why| format!("Could not open {}: {}", filename, why))).transpose()?;
+        let stdout_file: Option<Arc<File>> = stdout.map(create).transpose()?;
+        let same_file = |a: &str, b: &str| std::fs::canonicalize(a).ok()
+            .is_some_and(|a| std::fs::canonicalize(b).ok() == Some(a));
+        let stderr_file: Option<Arc<File>> = match (stderr, stdout) {
+            (Some(stderr), Some(stdout)) if same_file(stderr, stdout) => stdout_file.clone(),
+            (stderr, _) => stderr.map(create).transpose()?
+        };
+        Ok(GuestStdio { stdin: stdin_file, stdout: stdout_file, stderr: stderr_file })
+    }
+
+    /// Whether the standard input of the guest is not the one of the emulator
+    pub fn is_stdin_redirected(&self) -> bool {
+        self.stdin.is_some()
+    }
+
+    /// Read from the standard input of the guest
+    pub fn read_stdin(&self, data: &mut [u8]) -> std::io::Result<usize> {
+        match self.stdin.as_ref() {
+            Some(file) => (&**file).read(data),
+            None => std::io::stdin().read(data)
+        }
+    }
+
+    /// Write to the standard output of the guest
+    pub fn write_stdout(&self, data: &[u8]) -> std::io::Result<()> {
+        match self.stdout.as_ref() {
+            Some(file) => (&**file).write_all(data),
+            None => {
+                let mut stdout = std::io::stdout();
+                stdout.write_all(data).and_then(|_| stdout.flush())
+            }
+        }
+    }
+
+    /// Write to the standard error of the guest
+    pub fn write_stderr(&self, data: &[u8]) -> std::io::Result<()> {
+        match self.stderr.as_ref() {
+            Some(file) => (&**file).write_all(data),
+            None => std::io::stderr().write_all(data)
+        }
+    }
+}
+
+/// Standard input and output of the emulator, or of the guest if they
+/// are redirected. The characters typed for the guest come from the
+/// keyboard, if it has been opened
+#[derive(Default)]
+pub struct Terminal {
+    keyboard: Option<Keyboard>,
+    stdio: GuestStdio
+}
+
+impl Terminal {
+    pub fn new() -> Terminal {
+        Terminal::default()
+    }
+
+    /// Terminal whose keyboard is read in the background, so that the
+    /// guest receives the keys as they are pressed
+    pub fn with_keyboard() -> Terminal {
+        Terminal { keyboard: Some(Keyboard::open()), stdio: GuestStdio::new() }
+    }
+
+    /// Show the output and take the input of the guest from its
+    /// standard streams, which can be redirected
+    pub fn set_stdio(&mut self, stdio: GuestStdio) {
+        self.stdio = stdio;
+    }
+}
+
+impl HostConsole for Terminal {
+    fn write(&mut self, bytes: &[u8]) {
+        let _ = self.stdio.write_stdout(bytes);
+    }
+
+    fn read_line(&mut self, line: &mut String) -> std::io::Result<usize> {
+        if !self.stdio.is_stdin_redirected() {
+            return std::io::stdin().read_line(line);
+        }
+        let mut bytes: Vec<u8> = Vec::new();
+        let mut byte: [u8; 1] = [0];
+        while bytes.last() != Some(&b'\n') && self.stdio.read_stdin(&mut byte)? == 1 {
+            bytes.push(byte[0]);
+        }
+        line.push_str(&String::from_utf8_lossy(&bytes));
+        Ok(bytes.len())
+    }
+
+    // A redirected standard input is given to the guest as fast as it
+    // takes it
+    fn read_byte(&mut self) -> Option<u8> {
+        if self.stdio.is_stdin_redirected() {
+            let mut byte: [u8; 1] = [0];
+            return matches!(self.stdio.read_stdin(&mut byte), Ok(1)).then_some(byte[0]);
+        }
+        self.keyboard.as_mut()?.read_byte()
+    }
+}
+
+/// Console whose output is kept in memory until it is taken, for hosts
+/// that cannot be called from the emulator directly. They pass the
+/// characters typed for the guest with send_input
+#[derive(Default)]
+pub struct BufferConsole {
+    output: Vec<u8>,
+    input: VecDeque<u8>
+}
+
+impl BufferConsole {
+    pub fn new() -> BufferConsole {
+        BufferConsole::default()
+    }
+
+    /// Take the characters written so far
+    pub fn take_output(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.output)
+    }
+
+    /// Queue characters for the guest
+    pub fn send_input(&mut self, bytes: &[u8]) {
+        self.input.extend(bytes);
+    }
+}
+
+impl HostConsole for BufferConsole {
+    fn write(&mut self, bytes: &[u8]) {
+        self.output.extend_from_slice(bytes);
+    }
+
+    fn read_byte(&mut self) -> Option<u8> {
+        self.input.pop_front()
+    }
+}
+
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Clone)]
 pub struct CLI {
     output_buffer: String,
     input_buffer: String,
+    console: SharedHostConsole,
+    // Calls to has_input since the console was last polled
+    polls: u32
+}
+
+impl Default for CLI {
+    fn default() -> CLI {
+        CLI::new()
+    }
 }
 
 #[allow(dead_code)]
 impl CLI {
+    // The console is locked to be polled, so it is only done once every
+    // POLL_INTERVAL calls to has_input
+    const POLL_INTERVAL: u32 = 256;
+
     pub fn new() -> CLI {
         CLI {
             output_buffer: String::new(),
-            input_buffer: String::new()
+            input_buffer: String::new(),
+            console: Arc::new(Mutex::new(Terminal::new())),
+            polls: 0
         }
     }
 
+    /// Talk to console instead of the terminal
+    pub fn set_console(&mut self, console: SharedHostConsole) {
+        self.console = console;
+    }
+
     pub fn show_output(&mut self) {
-        print!("{}", self.output_buffer);
-        let _ = std::io::stdout().flush();
+        if let Ok(mut console) = self.console.lock() {
+            console.write(self.output_buffer.as_bytes());
+        }
         self.output_buffer.clear();
     }
 
     pub fn get_input(&mut self) {
-        match std::io::stdin().read_line(&mut self.input_buffer) {
-            Ok(_a) => (),
-            Err(err) => panic!("Could not get input: {}", err),
+        let read = match self.console.lock() {
+            Ok(mut console) => console.read_line(&mut self.input_buffer),
+            Err(_) => Ok(0)
+        };
+        if let Err(err) = read {
+            panic!("Could not get input: {}", err);
         }
     }
 
@@ -31,11 +243,74 @@ impl CLI {
         self.output_buffer.push(value as char);
     }
 
+    /// Check if a character has been typed for the guest
+    pub fn has_input(&mut self) -> bool {
+        if self.input_buffer.is_empty() {
+            self.polls += 1;
+            if self.polls >= CLI::POLL_INTERVAL {
+                self.polls = 0;
+                self.poll_console();
+            }
+        }
+        !self.input_buffer.is_empty()
+    }
+
+    fn poll_console(&mut self) {
+        let byte: Option<u8> = match self.console.lock() {
+            Ok(mut console) => console.read_byte(),
+            Err(_) => None
+        };
+        if let Some(byte) = byte {
+            self.input_buffer.push(byte as char);
+        }
+    }
+
     pub fn read_byte(&mut self) -> u8 {
-        if self.input_buffer.len() > 0 {
+        if !self.input_buffer.is_empty() {
             self.input_buffer.remove(0).try_into().unwrap()
         } else {
             0
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+    use crate::cli::{BufferConsole, GuestStdio, HostConsole, Terminal, CLI};
+
+    #[test]
+    fn console_test() {
+        let console: Arc<Mutex<BufferConsole>> = Arc::new(Mutex::new(BufferConsole::new()));
+        let mut cli: CLI = CLI::new();
+        cli.set_console(console.clone());
+        for byte in b"hi\n" {
+            cli.write_byte(*byte);
+        }
+        cli.show_output();
+        assert_eq!(console.lock().unwrap().take_output(), b"hi\n");
+        assert!(console.lock().unwrap().take_output().is_empty());
+    }
+
+    #[test]
+    fn stdio_test() {
+        let base: std::path::PathBuf = std::env::temp_dir().join(format!("riviera-stdio-{}", std::process::id()));
+        std::fs::create_dir_all(&base).unwrap();
+        let path = |name: &str| base.join(name).to_str().unwrap().to_string();
+        std::fs::write(path("in"), "ls\nx").unwrap();
+        assert!(GuestStdio::open(Some(&path("missing")), None, None).is_err());
+        // stdout and stderr to the same file share it
+        let stdio: GuestStdio = GuestStdio::open(Some(&path("in")), Some(&path("out")), Some(&path("out"))).unwrap();
+        stdio.write_stdout(b"out ").unwrap();
+        stdio.write_stderr(b"err").unwrap();
+        assert_eq!(std::fs::read(path("out")).unwrap(), b"out err");
+        let mut terminal: Terminal = Terminal::new();
+        terminal.set_stdio(stdio);
+        let mut line: String = String::new();
+        assert_eq!(terminal.read_line(&mut line).unwrap(), 3);
+        assert_eq!(line, "ls\n");
+        assert_eq!(terminal.read_byte(), Some(b'x'));
+        assert_eq!(terminal.read_byte(), None);
+        std::fs::remove_dir_all(&base).unwrap();
+    }
+}
@@ -0,0 +1,283 @@
+use crate::bus::Bus;
+use crate::device::Device;
+use crate::memory::AccessSize;
+use crate::snapshot::{Snapshot, SnapshotReader, SnapshotWriter};
+
+/// Core-local interruptor for a single hart, with the same register layout
+/// as the SiFive CLINT: msip (software interrupt), mtimecmp and mtime.
+/// Time is measured in executed instructions: mtime is incremented
+/// every time the CLINT is cycled, unless the rate of the time has been
+/// changed (e.g. frozen to test timeouts deterministically)
+#[derive(Clone)]
+pub struct Clint {
+    msip: u32,
+    mtimecmp: u64,
+    mtime: u64,
+    // Ticks of mtime per cycle and fractional part of mtime (32.32 fixed point)
+    rate: u64,
+    fraction: u64
+}
+
+impl Default for Clint {
+    fn default() -> Clint {
+        Clint::new()
+    }
+}
+
+impl Clint {
+    const MSIP_ADDR:     u64 = 0x0000;
+    const MTIMECMP_ADDR: u64 = 0x4000;
+    const MTIME_ADDR:    u64 = 0xbff8;
+    /// Nominal frequency of mtime, used to turn it into seconds
+    pub const TIMEBASE_FREQUENCY: u64 = 10_000_000;
+    // One tick per cycle, in 32.32 fixed point
+    const RATE_ONE: u64 = 1 << 32;
+    /// Largest rate that can be set
+    pub const MAX_RATE: f64 = 1e6;
+
+    pub fn new() -> Clint {
+        // mtimecmp starts at the maximum value so that no timer
+        // interrupt is pending until the program programs it
+        Clint { msip: 0, mtimecmp: u64::MAX, mtime: 0, rate: Clint::RATE_ONE, fraction: 0 }
+    }
+
+    /// Advance time by one cycle, that is by rate ticks
+    #[inline(always)]
+    pub fn tick(&mut self) {
+        let fixed: u64 = self.fraction + self.rate;
+        self.mtime = self.mtime.wrapping_add(fixed >> 32);
+        self.fraction = fixed & 0xffffffff;
+    }
+
+    /// Set the number of ticks per cycle: 1 is the normal rate, 0 freezes
+    /// the time and fractions slow it down
+    pub fn set_rate(&mut self, rate: f64) -> Result<(), String> {
+        if !(0.0..=Clint::MAX_RATE).contains(&rate) {
+            return Err(format!("Invalid time scale {} (it must be between 0 and {})", rate, Clint::MAX_RATE));
+        }
+        self.rate = (rate * Clint::RATE_ONE as f64).round() as u64;
+        Ok(())
+    }
+
+    /// Ticks per cycle
+    pub fn get_rate(&self) -> f64 {
+        self.rate as f64 / Clint::RATE_ONE as f64
+    }
+
+    pub fn set_mtime(&mut self, mtime: u64) {
+        self.mtime = mtime;
+        self.fraction = 0;
+    }
+
+    /// Machine timer interrupt line (MTIP)
+    #[inline(always)]
+    pub fn timer_interrupt(&self) -> bool {
+        self.mtime >= self.mtimecmp
+    }
+
+    /// Machine software interrupt line (MSIP)
+    #[inline(always)]
+    pub fn software_interrupt(&self) -> bool {
+        self.msip & 0x1 == 0x1
+    }
+
+    /// State of both interrupt lines as MTIP and MSIP bits of mip
+    #[inline(always)]
+    pub fn interrupt_lines(&self) -> u64 {
+        ((self.timer_interrupt() as u64) << 7) | (((self.msip & 0x1) as u64) << 3)
+    }
+
+    /// Set or clear the software interrupt, as a write to msip does
+    pub fn set_msip(&mut self, level: bool) {
+        self.msip = level as u32;
+    }
+
+    pub fn set_mtimecmp(&mut self, mtimecmp: u64) {
+        self.mtimecmp = mtimecmp;
+    }
+
+    pub fn get_mtime(&self) -> u64 {
+        self.mtime
+    }
+
+    /// Number of cycles before the timer interrupt line rises, None if it
+    /// already has or if the time is frozen
+    pub fn cycles_until_timer(&self) -> Option<u64> {
+        if self.timer_interrupt() || self.rate == 0 {
+            return None;
+        }
+        let remaining: u128 = (((self.mtimecmp - self.mtime) as u128) << 32) - self.fraction as u128;
+        Some(remaining.div_ceil(self.rate as u128).min(u64::MAX as u128) as u64)
+    }
+
+    /// Read a register (or a part of it) at an offset from the beginning
+    /// of the CLINT. Accesses that do not fall inside a register fail
+    pub fn read(&self, offset: u64, size: AccessSize) -> Option<u64> {
+        let (base, value, width) = self.register(offset)?;
+        let shift: u64 = (offset - base) * 8;
+        if offset - base + size.bytes() as u64 > width {
+            return None;
+        }
+        Some((value >> shift) & Clint::mask(size))
+    }
+
+    /// Write a register (or a part of it) at an offset from the beginning
+    /// of the CLINT. Only bit 0 of msip is writable
+    pub fn write(&mut self, offset: u64, data: u64, size: AccessSize) -> Option<()> {
+        let (base, value, width) = self.register(offset)?;
+        let shift: u64 = (offset - base) * 8;
+        if offset - base + size.bytes() as u64 > width {
+            return None;
+        }
+        let mask: u64 = Clint::mask(size) << shift;
+        let value: u64 = (value & !mask) | ((data << shift) & mask);
+        match base {
+            Clint::MSIP_ADDR => self.msip = (value & 0x1) as u32,
+            Clint::MTIMECMP_ADDR => self.mtimecmp = value,
+            _ => self.mtime = value
+        }
+        Some(())
+    }
+
+    // Base address, current value and width in bytes of the register
+    // that contains the offset
+    fn register(&self, offset: u64) -> Option<(u64, u64, u64)> {
+        match offset {
+            Clint::MSIP_ADDR..=0x3 => Some((Clint::MSIP_ADDR, self.msip as u64, 4)),
+            Clint::MTIMECMP_ADDR..=0x4007 => Some((Clint::MTIMECMP_ADDR, self.mtimecmp, 8)),
+            Clint::MTIME_ADDR..=0xbfff => Some((Clint::MTIME_ADDR, self.mtime, 8)),
+            _ => None
+        }
+    }
+
+    fn mask(size: AccessSize) -> u64 {
+        match size {
+            AccessSize::DOUBLEWORD => u64::MAX,
+            size => (1 << (size.bytes() * 8)) - 1
+        }
+    }
+}
+
+// The CLINT can also be used as a standalone device, e.g. in the device
+// test harness. Its interrupt line is the machine timer interrupt
+impl Device for Clint {
+    fn name(&self) -> &str {
+        "clint"
+    }
+
+    fn size(&self) -> u64 {
+        Bus::CLINT_SIZE
+    }
+
+    fn read(&mut self, offset: u64, size: AccessSize) -> Option<u64> {
+        Clint::read(self, offset, size)
+    }
+
+    fn peek(&self, offset: u64, size: AccessSize) -> Option<u64> {
+        Clint::read(self, offset, size)
+    }
+
+    fn write(&mut self, offset: u64, data: u64, size: AccessSize) -> Option<()> {
+        Clint::write(self, offset, data, size)
+    }
+
+    fn cycle(&mut self) {
+        self.tick();
+    }
+
+    fn interrupt(&self) -> bool {
+        self.timer_interrupt()
+    }
+
+    fn compatible(&self) -> &[&str] {
+        &["sifive,clint0", "riscv,clint0"]
+    }
+
+    fn clone_device(&self) -> Box<dyn Device> {
+        Box::new(self.clone())
+    }
+}
+
+impl Snapshot for Clint {
+    fn save(&self, writer: &mut SnapshotWriter) {
+        writer.put_u32(self.msip);
+        writer.put_u64(self.mtimecmp);
+        writer.put_u64(self.mtime);
+        writer.put_u64(self.rate);
+        writer.put_u64(self.fraction);
+    }
+
+    fn restore(&mut self, reader: &mut SnapshotReader) -> Result<(), String> {
+        self.msip = reader.get_u32()?;
+        self.mtimecmp = reader.get_u64()?;
+        self.mtime = reader.get_u64()?;
+        self.rate = reader.get_u64()?;
+        self.fraction = reader.get_u64()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::clint::Clint;
+    use crate::memory::AccessSize;
+
+    #[test]
+    fn timer_test() {
+        let mut clint: Clint = Clint::new();
+        assert!(!clint.timer_interrupt());
+        assert_eq!(clint.write(0x4000, 3, AccessSize::DOUBLEWORD), Some(()));
+        clint.tick();
+        clint.tick();
+        assert!(!clint.timer_interrupt());
+        clint.tick();
+        assert!(clint.timer_interrupt());
+        assert_eq!(clint.read(0xbff8, AccessSize::DOUBLEWORD), Some(3));
+        // 32-bit halves of mtimecmp
+        assert_eq!(clint.write(0x4004, 0x1, AccessSize::WORD), Some(()));
+        assert_eq!(clint.read(0x4000, AccessSize::DOUBLEWORD), Some(0x1_0000_0003));
+        assert!(!clint.timer_interrupt());
+        // Software interrupt and accesses outside of the registers
+        assert_eq!(clint.write(0x0, 0xff, AccessSize::WORD), Some(()));
+        assert!(clint.software_interrupt());
+        assert_eq!(clint.read(0x0, AccessSize::WORD), Some(0x1));
+        assert_eq!(clint.read(0x2, AccessSize::WORD), None);
+        assert_eq!(clint.read(0x100, AccessSize::WORD), None);
+    }
+
+    #[test]
+    fn rate_test() {
+        let mut clint: Clint = Clint::new();
+        clint.set_rate(0.0).unwrap();
+        clint.tick();
+        assert_eq!(clint.get_mtime(), 0);
+        clint.set_rate(0.25).unwrap();
+        for _ in 0..10 {
+            clint.tick();
+        }
+        assert_eq!(clint.get_mtime(), 2);
+        clint.set_rate(1000.0).unwrap();
+        clint.tick();
+        assert_eq!(clint.get_mtime(), 1002);
+        assert!(clint.set_rate(-1.0).is_err());
+        assert!(clint.set_rate(f64::NAN).is_err());
+    }
+
+    #[test]
+    fn cycles_until_timer_test() {
+        let mut clint: Clint = Clint::new();
+        clint.set_mtimecmp(10);
+        assert_eq!(clint.cycles_until_timer(), Some(10));
+        clint.set_rate(0.25).unwrap();
+        clint.tick();
+        assert_eq!(clint.cycles_until_timer(), Some(39));
+        for _ in 0..39 {
+            clint.tick();
+        }
+        assert!(clint.timer_interrupt());
+        assert_eq!(clint.cycles_until_timer(), None);
+        clint.set_mtimecmp(20);
+        clint.set_rate(0.0).unwrap();
+        assert_eq!(clint.cycles_until_timer(), None);
+    }
+}
@@ -0,0 +1,84 @@
+use std::ops::Range;
+use crate::memory::AccessSize;
+
+/// A store that hit an executable region
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct CodeWrite {
+    // PC of the store and address it wrote
+    pub pc: u64,
+    pub address: u64,
+    pub size: AccessSize
+}
+
+/// Watch for stores into the executable regions (the text of the program
+/// and the boot ROM). Programs that do not generate code never write
+/// there, so such a store usually comes from a bad pointer and is reported
+/// before the corrupted instructions get executed
+#[derive(Clone, Default)]
+pub struct CodeWatch {
+    regions: Vec<Range<u64>>,
+    writes: Vec<CodeWrite>,
+    // Total number of writes, only the first ones are kept
+    count: u64
+}
+
+impl CodeWatch {
+    // Maximum number of writes that are kept
+    const MAX_WRITES: usize = 64;
+
+    pub fn new() -> CodeWatch {
+        CodeWatch { regions: Vec::new(), writes: Vec::new(), count: 0 }
+    }
+
+    /// Add an executable region to watch
+    pub fn add_region(&mut self, region: Range<u64>) {
+        if !region.is_empty() {
+            self.regions.push(region);
+        }
+    }
+
+    /// Check a store executed at pc: any byte written inside an
+    /// executable region is reported
+    #[inline(always)]
+    pub fn check_store(&mut self, pc: u64, address: u64, size: AccessSize) {
+        let end: u64 = address.wrapping_add(size.bytes() as u64);
+        if self.regions.iter().any(|region| address < region.end && end > region.start) {
+            self.count += 1;
+            if self.writes.len() < CodeWatch::MAX_WRITES {
+                self.writes.push(CodeWrite { pc, address, size });
+            }
+        }
+    }
+
+    /// Writes caught so far (only the first ones are kept)
+    pub fn get_writes(&self) -> &[CodeWrite] {
+        &self.writes
+    }
+
+    /// Total number of writes caught so far
+    pub fn get_count(&self) -> u64 {
+        self.count
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::codewatch::{CodeWatch, CodeWrite};
+    use crate::memory::AccessSize;
+
+    #[test]
+    fn code_write_test() {
+        let mut watch: CodeWatch = CodeWatch::new();
+        watch.add_region(0x10000..0x10100);
+        watch.add_region(0x1000..0x1000);
+        watch.check_store(0x10010, 0x20000, AccessSize::DOUBLEWORD);
+        watch.check_store(0x10010, 0x0ff8, AccessSize::DOUBLEWORD);
+        assert_eq!(watch.get_count(), 0);
+        // A store that crosses the beginning of the text is caught as well
+        watch.check_store(0x10014, 0xfffc, AccessSize::DOUBLEWORD);
+        watch.check_store(0x10018, 0x100ff, AccessSize::BYTE);
+        watch.check_store(0x1001c, 0x10100, AccessSize::BYTE);
+        assert_eq!(watch.get_count(), 2);
+        assert_eq!(watch.get_writes()[0], CodeWrite { pc: 0x10014, address: 0xfffc, size: AccessSize::DOUBLEWORD });
+    }
+}
@@ -0,0 +1,180 @@
+use std::fs::{File, OpenOptions};
+use std::io::{BufWriter, ErrorKind, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use crate::error;
+
+/// Copy of the guest console (the bytes sent through the UART) written to
+/// a file. Each line starts with the number of instructions executed and
+/// the time the emulator had been running when its first byte was sent,
+/// so that console messages can be matched with traces and statistics.
+/// The file is only created when the guest sends its first byte, so runs
+/// that print nothing leave no file behind
+pub struct ConsoleLog {
+    writer: Option<Box<dyn Write + Send>>,
+    // File still to be created, if the log is not open yet
+    file: Option<LogFile>,
+    // Name of the file once it is created
+    filename: Option<String>,
+    start: Instant,
+    // The next byte starts a new line
+    line_start: bool
+}
+
+// Where a console log is created on the first byte
+enum LogFile {
+    // A file named by the user, replaced if it exists
+    Given(String),
+    // The first of <prefix>.console.log, <prefix>.console.1.log, ... that
+    // does not exist yet, so that no file is ever replaced
+    Unused(String)
+}
+
+pub type SharedConsoleLog = Arc<Mutex<ConsoleLog>>;
+
+impl ConsoleLog {
+    // Numbered names tried for a log before giving up
+    const MAX_NAMES: u32 = 1000;
+
+    pub fn new(writer: Box<dyn Write + Send>) -> ConsoleLog {
+        ConsoleLog { writer: Some(writer), file: None, filename: None, start: Instant::now(), line_start: true }
+    }
+
+    /// Log to the given file, which replaces any file with the same name
+    /// once the guest sends its first byte
+    pub fn create(filename: &str) -> ConsoleLog {
+        ConsoleLog { writer: None, file: Some(LogFile::Given(filename.to_string())), filename: None, start: Instant::now(), line_start: true }
+    }
+
+    /// Log to a new file named after the program (e.g. hello.console.log
+    /// for hello.elf) in the current directory. An existing file is never
+    /// replaced: a number is added to the name instead
+    pub fn create_for(program: &str) -> ConsoleLog {
+        let stem: String = Path::new(program).file_stem()
+            .map_or("riviera".to_string(), |stem| stem.to_string_lossy().into_owned());
+        ConsoleLog::create_unused(stem)
+    }
+
+    // Log to the first unused name starting with prefix (a path)
+    fn create_unused(prefix: String) -> ConsoleLog {
+        ConsoleLog { writer: None, file: Some(LogFile::Unused(prefix)), filename: None, start: Instant::now(), line_start: true }
+    }
+
+    // Create the file of the log, reporting the name it got
+    fn open(file: LogFile) -> Result<(File, String), String> {
+        match file {
+            LogFile::Given(filename) => match File::create(&filename) {
+                Ok(file) => Ok((file, filename)),
+                Err(why) => Err(format!("Could not create console log {}: {}", filename, why))
+            },
+            LogFile::Unused(prefix) => {
+                for number in 0..ConsoleLog::MAX_NAMES {
+                    let filename: String = match number {
+                        0 => format!("{}.console.log", prefix),
+                        _ => format!("{}.console.{}.log", prefix, number)
+                    };
+                    match OpenOptions::new().write(true).create_new(true).open(&filename) {
+                        Ok(file) => return Ok((file, filename)),
+                        Err(why) if why.kind() == ErrorKind::AlreadyExists => continue,
+                        Err(why) => return Err(format!("Could not create console log {}: {}", filename, why))
+                    }
+                }
+                Err(format!("Could not create console log {}.console.log: all the names are taken", prefix))
+            }
+        }
+    }
+
+    /// Log a byte sent by the guest after the given number of instructions
+    pub fn record(&mut self, byte: u8, instructions: u64) {
+        // The file is created on the first byte, and only tried once
+        if let Some(file) = self.file.take() {
+            match ConsoleLog::open(file) {
+                Ok((file, filename)) => {
+                    self.writer = Some(Box::new(BufWriter::new(file)));
+                    self.filename = Some(filename);
+                },
+                Err(why) => error!("{}", why)
+            }
+        }
+        let Some(writer) = self.writer.as_mut() else {
+            return;
+        };
+        if self.line_start {
+            let _ = write!(writer, "[{:>12}] [{:>12.6}s] ", instructions, self.start.elapsed().as_secs_f64());
+        }
+        let _ = writer.write_all(&[byte]);
+        self.line_start = byte == b'\n';
+    }
+
+    /// Name of the file of the log, None if the guest has not printed
+    /// anything yet (or if it could not be created)
+    pub fn get_filename(&self) -> Option<&str> {
+        self.filename.as_deref()
+    }
+
+    pub fn flush(&mut self) {
+        if let Some(writer) = self.writer.as_mut() {
+            let _ = writer.flush();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+    use std::sync::{Arc, Mutex};
+    use crate::console::ConsoleLog;
+
+    // Writer whose content can be inspected after being moved into the log
+    #[derive(Clone)]
+    struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn console_log_test() {
+        let buffer: SharedBuffer = SharedBuffer(Arc::new(Mutex::new(Vec::new())));
+        let mut log: ConsoleLog = ConsoleLog::new(Box::new(buffer.clone()));
+        for (i, byte) in b"hi\nthere\n".iter().enumerate() {
+            log.record(*byte, 10 + i as u64);
+        }
+        let text: String = String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap();
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(lines.len(), 2);
+        // Lines are stamped with the instruction count of their first byte
+        assert!(lines[0].starts_with("[          10] ["));
+        assert!(lines[0].ends_with("s] hi"));
+        assert!(lines[1].starts_with("[          13] ["));
+        assert!(lines[1].ends_with("s] there"));
+    }
+
+    #[test]
+    fn lazy_file_test() {
+        let prefix: std::path::PathBuf = std::env::temp_dir().join(format!("riviera-console-{}", std::process::id()));
+        let first: String = format!("{}.console.log", prefix.display());
+        let second: String = format!("{}.console.1.log", prefix.display());
+        std::fs::write(&first, "not a log").unwrap();
+        // Nothing is created until the guest prints
+        let mut log: ConsoleLog = ConsoleLog::create_unused(prefix.display().to_string());
+        log.flush();
+        assert_eq!(log.get_filename(), None);
+        assert!(!std::path::Path::new(&second).exists());
+        // The existing file is left alone, the log gets the next name
+        log.record(b'x', 1);
+        log.flush();
+        assert_eq!(log.get_filename(), Some(second.as_str()));
+        assert_eq!(std::fs::read_to_string(&first).unwrap(), "not a log");
+        assert!(std::fs::read_to_string(&second).unwrap().ends_with("s] x"));
+        std::fs::remove_file(&first).unwrap();
+        std::fs::remove_file(&second).unwrap();
+    }
+}
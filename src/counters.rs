@@ -0,0 +1,220 @@
+use crate::cpu::Instruction;
+use crate::snapshot::{Snapshot, SnapshotReader, SnapshotWriter};
+
+/// Number of programmable counters (mhpmcounter3 to mhpmcounter31)
+pub const HPM_COUNTERS: usize = 29;
+
+/// Events that the programmable counters can count. A counter counts
+/// the event whose code is written to its mhpmevent register, 0 means
+/// that it counts nothing
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum HpmEvent {
+    BranchTaken = 1,
+    Load = 2,
+    Store = 3
+}
+
+impl HpmEvent {
+    pub fn from_code(code: u64) -> Option<HpmEvent> {
+        match code {
+            1 => Some(HpmEvent::BranchTaken),
+            2 => Some(HpmEvent::Load),
+            3 => Some(HpmEvent::Store),
+            _ => None
+        }
+    }
+}
+
+/// Hardware performance counters: cycles, retired instructions and the
+/// programmable counters. Counters are identified by their index, which
+/// is the low 5 bits of their CSR address (0 is cycle, 2 is instret, 3 to
+/// 31 are the programmable ones), and the same index selects their bit in
+/// mcountinhibit. Every instruction takes one cycle
+#[derive(Clone)]
+pub struct Counters {
+    cycle: u64,
+    instret: u64,
+    hpm: [u64; HPM_COUNTERS],
+    events: [u64; HPM_COUNTERS],
+    inhibit: u64,
+    // Some programmable counter is counting an event
+    counting_events: bool
+}
+
+impl Default for Counters {
+    fn default() -> Counters {
+        Counters::new()
+    }
+}
+
+impl Counters {
+    pub const CYCLE: usize = 0;
+    // Index 1 is the time, which is not a counter of the hart
+    pub const INSTRET: usize = 2;
+    const FIRST_HPM: usize = 3;
+    // The time cannot be inhibited
+    const INHIBIT_MASK: u64 = 0xffff_fffd;
+
+    pub fn new() -> Counters {
+        Counters {
+            cycle: 0,
+            instret: 0,
+            hpm: [0; HPM_COUNTERS],
+            events: [0; HPM_COUNTERS],
+            inhibit: 0,
+            counting_events: false
+        }
+    }
+
+    fn is_inhibited(&self, index: usize) -> bool {
+        self.inhibit & (1 << index) != 0
+    }
+
+    /// Count an instruction that retired, taken tells if it changed the flow of execution
+    #[inline(always)]
+    pub fn retire(&mut self, instr: Instruction, taken: bool) {
+        if self.inhibit == 0 {
+            self.cycle = self.cycle.wrapping_add(1);
+            self.instret = self.instret.wrapping_add(1);
+        } else {
+            self.count_inhibited(Counters::CYCLE);
+            self.count_inhibited(Counters::INSTRET);
+        }
+        if self.counting_events {
+            self.count_events(instr, taken);
+        }
+    }
+
+    /// Count an instruction that raised an exception: it takes a cycle but it does not retire
+    pub fn trap(&mut self) {
+        self.count_inhibited(Counters::CYCLE);
+    }
+
+    fn count_inhibited(&mut self, index: usize) {
+        if !self.is_inhibited(index) {
+            let counter: &mut u64 = if index == Counters::CYCLE { &mut self.cycle } else { &mut self.instret };
+            *counter = counter.wrapping_add(1);
+        }
+    }
+
+    #[inline(never)]
+    fn count_events(&mut self, instr: Instruction, taken: bool) {
+        let event: HpmEvent = match (instr & 0x7f, taken) {
+            (0x63, true) => HpmEvent::BranchTaken,
+            (0x03, _) => HpmEvent::Load,
+            (0x23, _) => HpmEvent::Store,
+            _ => return
+        };
+        for i in 0..HPM_COUNTERS {
+            if self.events[i] == event as u64 && !self.is_inhibited(i + Counters::FIRST_HPM) {
+                self.hpm[i] = self.hpm[i].wrapping_add(1);
+            }
+        }
+    }
+
+    /// Read a counter, None for the time
+    pub fn read_counter(&self, index: usize) -> Option<u64> {
+        match index {
+            Counters::CYCLE => Some(self.cycle),
+            Counters::INSTRET => Some(self.instret),
+            index if (Counters::FIRST_HPM..Counters::FIRST_HPM + HPM_COUNTERS).contains(&index) =>
+                Some(self.hpm[index - Counters::FIRST_HPM]),
+            _ => None
+        }
+    }
+
+    /// Write a counter, None for the time
+    pub fn write_counter(&mut self, index: usize, value: u64) -> Option<()> {
+        match index {
+            Counters::CYCLE => self.cycle = value,
+            Counters::INSTRET => self.instret = value,
+            index if (Counters::FIRST_HPM..Counters::FIRST_HPM + HPM_COUNTERS).contains(&index) =>
+                self.hpm[index - Counters::FIRST_HPM] = value,
+            _ => return None
+        }
+        Some(())
+    }
+
+    /// Event counted by a programmable counter (index 3 to 31)
+    pub fn read_event(&self, index: usize) -> Option<u64> {
+        self.events.get(index.checked_sub(Counters::FIRST_HPM)?).copied()
+    }
+
+    /// Select the event counted by a programmable counter. Unknown events
+    /// are WARL: they are replaced by 0 and the counter stops counting
+    pub fn write_event(&mut self, index: usize, code: u64) -> Option<()> {
+        let event: &mut u64 = self.events.get_mut(index.checked_sub(Counters::FIRST_HPM)?)?;
+        *event = HpmEvent::from_code(code).map_or(0, |event| event as u64);
+        self.counting_events = self.events.iter().any(|event| *event != 0);
+        Some(())
+    }
+
+    pub fn read_inhibit(&self) -> u64 {
+        self.inhibit
+    }
+
+    pub fn write_inhibit(&mut self, value: u64) {
+        self.inhibit = value & Counters::INHIBIT_MASK;
+    }
+}
+
+impl Snapshot for Counters {
+    fn save(&self, writer: &mut SnapshotWriter) {
+        writer.put_u64(self.cycle);
+        writer.put_u64(self.instret);
+        for i in 0..HPM_COUNTERS {
+            writer.put_u64(self.hpm[i]);
+            writer.put_u64(self.events[i]);
+        }
+        writer.put_u64(self.inhibit);
+    }
+
+    fn restore(&mut self, reader: &mut SnapshotReader) -> Result<(), String> {
+        self.cycle = reader.get_u64()?;
+        self.instret = reader.get_u64()?;
+        for i in 0..HPM_COUNTERS {
+            self.hpm[i] = reader.get_u64()?;
+            self.events[i] = reader.get_u64()?;
+        }
+        self.inhibit = reader.get_u64()?;
+        self.counting_events = self.events.iter().any(|event| *event != 0);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::counters::{Counters, HpmEvent};
+
+    #[test]
+    fn counters_test() {
+        // beq zero, zero, 8; lw a0, 0(sp); sw a0, 0(sp)
+        const BEQ: u32 = 0x00000463;
+        const LW: u32 = 0x00012503;
+        const SW: u32 = 0x00a12023;
+        let mut counters: Counters = Counters::new();
+        counters.write_event(3, HpmEvent::BranchTaken as u64).unwrap();
+        counters.write_event(4, HpmEvent::Store as u64).unwrap();
+        // Unknown events count nothing
+        counters.write_event(5, 0x42).unwrap();
+        assert_eq!(counters.read_event(5), Some(0));
+        counters.retire(BEQ, true);
+        counters.retire(BEQ, false);
+        counters.retire(LW, false);
+        counters.retire(SW, false);
+        counters.trap();
+        assert_eq!(counters.read_counter(Counters::CYCLE), Some(5));
+        assert_eq!(counters.read_counter(Counters::INSTRET), Some(4));
+        assert_eq!(counters.read_counter(3), Some(1));
+        assert_eq!(counters.read_counter(4), Some(1));
+        assert_eq!(counters.read_counter(1), None);
+
+        // Inhibit instret and the counter of the stores
+        counters.write_inhibit(0b10110);
+        counters.retire(SW, false);
+        assert_eq!(counters.read_counter(Counters::CYCLE), Some(6));
+        assert_eq!(counters.read_counter(Counters::INSTRET), Some(4));
+        assert_eq!(counters.read_counter(4), Some(1));
+        assert_eq!(counters.read_inhibit(), 0b10100);
+    }
+}
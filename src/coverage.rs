@@ -0,0 +1,205 @@
+use std::collections::{BTreeMap, HashMap};
+use gimli::{Dwarf, EndianSlice, LittleEndian, SectionId};
+use crate::elf::Elf;
+
+/// How the coverage is saved: an lcov tracefile, with the source lines
+/// found in the line number information of the ELF (built with -g), or
+/// the executed addresses with their counts, one per line, whose first
+/// column can be given to addr2line
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum CoverageFormat {
+    Lcov,
+    Addresses
+}
+
+impl CoverageFormat {
+    /// Parse a coverage format: "lcov" or "addr"
+    pub fn parse(format: &str) -> Result<CoverageFormat, String> {
+        match format.trim() {
+            "lcov" => Ok(CoverageFormat::Lcov),
+            "addr" => Ok(CoverageFormat::Addresses),
+            format => Err(format!("Invalid coverage format {}: expected lcov or addr", format))
+        }
+    }
+}
+
+// Instructions from start to end (excluded) that come from a source line
+#[derive(Clone, PartialEq, Eq, Debug)]
+struct LineRange {
+    start: u64,
+    end: u64,
+    file: usize,
+    line: u64
+}
+
+/// Source lines of the instructions of a program, read from the
+/// .debug_line section of its ELF
+#[derive(Clone, Default, Debug)]
+pub struct LineTable {
+    files: Vec<String>,
+    ranges: Vec<LineRange>
+}
+
+impl LineTable {
+    /// Read the line number information of an ELF executable
+    pub fn from_elf(buf: &[u8]) -> Result<LineTable, String> {
+        let mut elf: Elf = Elf::new();
+        elf.read_header(buf)?;
+        elf.read_sectionheaders(buf);
+        if elf.get_section_data(buf, ".debug_line").is_none() {
+            return Err("The executable has no line number information (.debug_line): build it with -g".to_string());
+        }
+        let load = |id: SectionId| -> Result<EndianSlice<LittleEndian>, gimli::Error> {
+            Ok(EndianSlice::new(elf.get_section_data(buf, id.name()).unwrap_or(&[]), LittleEndian))
+        };
+        LineTable::read_dwarf(&Dwarf::load(load).map_err(|err| err.to_string())?)
+            .map_err(|err| format!("Invalid line number information: {}", err))
+    }
+
+    fn read_dwarf(dwarf: &Dwarf<EndianSlice<LittleEndian>>) -> Result<LineTable, gimli::Error> {
+        let mut table: LineTable = LineTable::default();
+        let mut file_indexes: HashMap<String, usize> = HashMap::new();
+        let mut units = dwarf.units();
+        while let Some(header) = units.next()? {
+            let unit = dwarf.unit(header)?;
+            let program = match unit.line_program.clone() {
+                Some(program) => program,
+                None => continue
+            };
+            let comp_dir: String = unit.comp_dir.map(|dir| dir.to_string_lossy().into_owned()).unwrap_or_default();
+            // Rows give the line of the instructions from their address up
+            // to the address of the next row of the sequence
+            let mut previous: Option<(u64, usize, u64)> = None;
+            let mut rows = program.rows();
+            while let Some((header, row)) = rows.next_row()? {
+                if let Some((start, file, line)) = previous.take() {
+                    if row.address() > start {
+                        table.ranges.push(LineRange { start, end: row.address(), file, line });
+                    }
+                }
+                if row.end_sequence() {
+                    continue;
+                }
+                let (file, line) = match (row.file(header), row.line()) {
+                    (Some(file), Some(line)) => (file, line.get()),
+                    _ => continue
+                };
+                let mut path: String = dwarf.attr_string(&unit, file.path_name())?.to_string_lossy().into_owned();
+                if !path.starts_with('/') {
+                    if let Some(directory) = file.directory(header) {
+                        let directory: String = dwarf.attr_string(&unit, directory)?.to_string_lossy().into_owned();
+                        path = format!("{}/{}", directory, path);
+                    }
+                }
+                if !path.starts_with('/') && !comp_dir.is_empty() {
+                    path = format!("{}/{}", comp_dir, path);
+                }
+                let next: usize = table.files.len();
+                let file: usize = *file_indexes.entry(path.clone()).or_insert(next);
+                if file == next {
+                    table.files.push(path);
+                }
+                previous = Some((row.address(), file, line));
+            }
+        }
+        table.ranges.sort_by_key(|range| range.start);
+        Ok(table)
+    }
+}
+
+/// Executed instructions of a program, with the number of times each
+/// of them was executed, to measure the coverage of its tests
+#[derive(Clone, Default)]
+pub struct Coverage {
+    counts: HashMap<u64, u64>
+}
+
+impl Coverage {
+    pub fn new() -> Coverage {
+        Coverage::default()
+    }
+
+    /// Account for the execution of the instruction at pc
+    #[inline(always)]
+    pub fn record(&mut self, pc: u64) {
+        *self.counts.entry(pc).or_insert(0) += 1;
+    }
+
+    /// Executed addresses with their counts, in increasing order of address
+    pub fn executed(&self) -> Vec<(u64, u64)> {
+        let mut executed: Vec<(u64, u64)> = self.counts.iter().map(|(pc, count)| (*pc, *count)).collect();
+        executed.sort();
+        executed
+    }
+
+    /// The executed addresses, one per line followed by their count
+    pub fn to_addresses(&self) -> String {
+        self.executed().iter().map(|(pc, count)| format!("0x{:x} {}\n", pc, count)).collect()
+    }
+
+    /// lcov tracefile of the source lines: every line that has code is
+    /// listed, with the number of executions of its most executed instruction
+    pub fn to_lcov(&self, lines: &LineTable) -> String {
+        let executed: BTreeMap<u64, u64> = self.counts.iter().map(|(pc, count)| (*pc, *count)).collect();
+        let mut files: Vec<BTreeMap<u64, u64>> = vec![BTreeMap::new(); lines.files.len()];
+        for range in &lines.ranges {
+            let count: u64 = executed.range(range.start..range.end).map(|(_, count)| *count).max().unwrap_or(0);
+            let line: &mut u64 = files[range.file].entry(range.line).or_insert(0);
+            *line = (*line).max(count);
+        }
+        let mut lcov: String = String::from("TN:\n");
+        let mut order: Vec<usize> = (0..lines.files.len()).collect();
+        order.sort_by(|a, b| lines.files[*a].cmp(&lines.files[*b]));
+        for file in order {
+            lcov += &format!("SF:{}\n", lines.files[file]);
+            for (line, count) in &files[file] {
+                lcov += &format!("DA:{},{}\n", line, count);
+            }
+            let hit: usize = files[file].values().filter(|count| **count > 0).count();
+            lcov += &format!("LF:{}\nLH:{}\nend_of_record\n", files[file].len(), hit);
+        }
+        lcov
+    }
+
+    /// Save the coverage to a file in the given format. The lcov format
+    /// needs the ELF executable the program was loaded from
+    pub fn write_to_file(&self, filename: &str, format: CoverageFormat, program: &[u8]) -> Result<(), String> {
+        let contents: String = match format {
+            CoverageFormat::Addresses => self.to_addresses(),
+            CoverageFormat::Lcov if Elf::is_elf(program) => self.to_lcov(&LineTable::from_elf(program)?),
+            CoverageFormat::Lcov => return Err("The lcov coverage needs an ELF executable: use --coverage-format addr".to_string())
+        };
+        std::fs::write(filename, contents)
+            .map_err(|why| format!("Could not write {}: {}", filename, why))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::coverage::{Coverage, CoverageFormat, LineRange, LineTable};
+
+    #[test]
+    fn coverage_test() {
+        let lines: LineTable = LineTable {
+            files: vec!["/src/main.c".to_string(), "/src/lib.c".to_string()],
+            ranges: vec![
+                LineRange { start: 0x100, end: 0x108, file: 0, line: 3 },
+                LineRange { start: 0x108, end: 0x110, file: 0, line: 4 },
+                LineRange { start: 0x110, end: 0x114, file: 0, line: 3 },
+                LineRange { start: 0x200, end: 0x204, file: 1, line: 10 }
+            ]
+        };
+        let mut coverage: Coverage = Coverage::new();
+        for pc in [0x100, 0x104, 0x110, 0x110, 0x110, 0x104] {
+            coverage.record(pc);
+        }
+        assert_eq!(coverage.executed(), vec![(0x100, 1), (0x104, 2), (0x110, 3)]);
+        assert_eq!(coverage.to_addresses(), "0x100 1\n0x104 2\n0x110 3\n");
+        assert_eq!(coverage.to_lcov(&lines), "TN:\n\
+                                              SF:/src/lib.c\nDA:10,0\nLF:1\nLH:0\nend_of_record\n\
+                                              SF:/src/main.c\nDA:3,3\nDA:4,0\nLF:2\nLH:1\nend_of_record\n");
+        assert_eq!(CoverageFormat::parse("addr"), Ok(CoverageFormat::Addresses));
+        assert!(CoverageFormat::parse("gcov").is_err());
+        assert!(coverage.write_to_file("/nonexistent", CoverageFormat::Lcov, &[0x13; 8]).is_err());
+    }
+}
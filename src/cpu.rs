@@ -1,11 +1,49 @@
 use crate::bus;
 use crate::rv;
+use crate::disas;
 use crate::memory;
-use crate::memory::AccessSize;
+use crate::memory::{AccessSize, MemError};
+use crate::machine::MachineConfig;
+use crate::device::Device;
+use crate::symbols::SymbolMap;
+use crate::trap::{Interrupt, Trap, TrapStatistics};
+use crate::syscall::{SyscallEmulator, SyscallResult};
+use crate::semihosting::Semihosting;
+use crate::sbi::{Sbi, SbiResult};
+use crate::timebase::{TimeSource, Timebase};
+use crate::pmp::{Pmp, PmpAccess, PMP_ENTRIES};
+use crate::htif::Htif;
+use crate::finisher::TestFinisher;
+use crate::clint::Clint;
+use crate::uart::UartSpec;
+use crate::fpu::{FloatMode, Fpu};
+use crate::softfloat::Format;
+use crate::step::{CsrWrite, MemoryWrite, RegisterWrite, StepReport};
+use crate::csr::{CsrFile, Privilege};
+use crate::stackguard::{StackGuard, StackOverflow};
+use crate::callstack::CallStack;
+use crate::codewatch::CodeWatch;
+use crate::icache::{DecodeCache, DecodedInstruction};
+use crate::cache::CacheSim;
+use crate::virtio::VirtioBlock;
+use crate::fdt::{DeviceNode, Platform};
+use crate::inputlog::InputLog;
+use crate::events::{HostEvent, HostEvents};
+use crate::accounting::TimeAccounting;
+use crate::profile::InstructionProfile;
+use crate::coverage::Coverage;
+use crate::taint::TaintTracker;
+use crate::memtrace::MemTrace;
+use crate::profiling::{BranchPredictor, BranchProfile};
+use crate::console::SharedConsoleLog;
+use crate::cli::{GuestStdio, SharedHostConsole};
+use crate::snapshot::{Snapshot, SnapshotReader, SnapshotWriter};
+use crate::trace::{TraceKind, Tracer};
 use colored::Colorize;
+use std::fmt;
+use std::ops::Range;
 
-const REG_FILE_SIZE: usize = 32;
-const CS_REG_FILE_SIZE: usize = 4096;
+pub const REG_FILE_SIZE: usize = 32;
 const PC_INITIAL_VALUE: u64 = 0x0;
 
 pub const REG_FILE_NAMES: [&str; REG_FILE_SIZE] = [
@@ -19,10 +57,124 @@ pub type Instruction = u32;
 pub type RegIndex    = u8;
 pub type CSRegIndex  = u16;
 
+/// Width of the integer registers. In RV32 mode registers keep the
+/// 32 bit value sign-extended to 64 bits, so that most instructions
+/// can be executed the same way in both modes, while the PC, addresses
+/// and CSRs only keep the low 32 bits
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Xlen {
+    Rv32,
+    Rv64
+}
+
+impl Xlen {
+    /// Width in bits
+    pub fn bits(self) -> u32 {
+        match self {
+            Xlen::Rv32 => 32,
+            Xlen::Rv64 => 64
+        }
+    }
+
+    /// Mask of the bits that make up an address or a CSR value
+    #[inline(always)]
+    pub fn mask(self) -> u64 {
+        match self {
+            Xlen::Rv32 => 0xffff_ffff,
+            Xlen::Rv64 => u64::MAX
+        }
+    }
+
+    /// Mask of the bits of a shift amount
+    #[inline(always)]
+    pub fn shamt_mask(self) -> u64 {
+        self.bits() as u64 - 1
+    }
+}
+
+/// What to do when the PC leaves the mapped memory, i.e. when an
+/// instruction cannot be fetched (usually a jump through a corrupted
+/// pointer or return address)
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum FetchFaultAction {
+    // Deliver an instruction access fault to the trap handler of the program
+    Trap,
+    // Stop the execution, even if the program has a trap handler
+    Stop,
+    // Stop the execution and open the interactive debugger
+    Debug
+}
+
+impl FetchFaultAction {
+    /// Parse an action: "trap", "stop" or "debug"
+    pub fn parse(action: &str) -> Result<FetchFaultAction, String> {
+        match action.trim() {
+            "trap" => Ok(FetchFaultAction::Trap),
+            "stop" => Ok(FetchFaultAction::Stop),
+            "debug" => Ok(FetchFaultAction::Debug),
+            action => Err(format!("Invalid fetch fault action {}: expected trap, stop or debug", action))
+        }
+    }
+}
+
+/// Why the execution of the program stopped
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ExitReason {
+    /// The program returned to the sentinel address (e.g. main returned),
+    /// with this value in a0
+    Returned(u64),
+    /// The program asked to exit with this code: exit system call,
+    /// semihosting, SBI shutdown, HTIF or the test finisher
+    Exited(u64),
+    /// EBREAK at this PC, without a trap handler to take it
+    Breakpoint(u64),
+    /// Trap that the program could not handle
+    Trap(Trap),
+    /// The program was stopped before it was over, after the number of
+    /// instructions it was allowed (or by the user in interactive mode,
+    /// or by a HostEvent::Stop)
+    InstructionLimit,
+    /// The program was stopped before it was over, after the host time it
+    /// was allowed
+    Timeout
+}
+
+impl ExitReason {
+    /// Exit status of riviera when a run limit (instructions or time) stops
+    /// the program, the one of timeout(1)
+    pub const LIMIT_EXIT_STATUS: i32 = 124;
+
+    /// Exit status that riviera passes on to the host: the exit code or
+    /// the returned value of the program, 1 if it was stopped by a trap.
+    /// There is none if the program was not over, unless it timed out
+    pub fn exit_status(&self) -> Option<i32> {
+        match *self {
+            ExitReason::Returned(value) | ExitReason::Exited(value) => Some(value as i32),
+            ExitReason::Breakpoint(_) | ExitReason::Trap(_) => Some(1),
+            ExitReason::Timeout => Some(ExitReason::LIMIT_EXIT_STATUS),
+            ExitReason::InstructionLimit => None
+        }
+    }
+}
+
+impl fmt::Display for ExitReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            ExitReason::Returned(value) => write!(f, "Program returned {}", value as i64),
+            ExitReason::Exited(code) => write!(f, "Program exited with code {}", code as i64),
+            ExitReason::Breakpoint(pc) => write!(f, "Stopped by a breakpoint at pc 0x{:0>16x}", pc),
+            ExitReason::Trap(trap) => write!(f, "Stopped by unhandled trap: {}", trap),
+            ExitReason::InstructionLimit => write!(f, "Stopped before the end of the program"),
+            ExitReason::Timeout => write!(f, "Stopped by the timeout before the end of the program")
+        }
+    }
+}
+
 // CPU structure: it represents a RISC-V processing element
 // Attributes:
 // regs         -> array of 64 bits elements representing the reg. file
-// last_upd_reg -> last register that was written
+// fpu          -> floating-point registers, and how their arithmetic is computed
+// last_upd_reg -> last register that was written (only tracked in debug mode)
 // pc           -> program counter
 // next_pc      -> value of the next PC that will be assigned to PC at
 //                 the end of the current cycle
@@ -31,15 +183,78 @@ pub type CSRegIndex  = u16;
 // debug_string -> string containing info about the instruction being executed
 // debug_mode   -> if true, the functions that implement the instructions
 //                 update the debug string
+// unhandled_trap -> trap that stopped the execution because the program
+//                 did not install a trap handler
+// trace_csr    -> if true, every CSR access is logged
+// trap_statistics -> count of the traps raised, by cause and PC
+// accounting   -> executed instructions by privilege level, in and out of trap handlers
+// profile      -> if present, executions by mnemonic and PC, branches, loads and stores
+// coverage     -> if present, executions of each instruction address
+// branch_profile -> if present, the conditional branches are predicted by a model
+// syscalls     -> if present, ECALLs are served by the host instead of trapping
+// sbi          -> if present, ECALLs from S-mode are served by the emulated SBI firmware
+// semihosting  -> if present, the EBREAKs of the semihosting sequence are served by the host
+// exit_code    -> exit code of the program, if it terminated through the exit syscall
+// memory_writes -> memory locations written by the last instruction (debug mode only)
+// stack_guard  -> if present, calls, returns and stores are checked for stack overruns
+// stack_guard_region -> if present, the accesses to it stop the program (stack overflow)
+// stack_overflow -> access of the program to the stack guard region, if any
+// call_stack   -> calls that have not returned yet (debug mode only)
+// code_watch   -> if present, stores into the executable regions are reported
+// taint        -> if present, the data from the taint sources is followed through registers and memory
+// breakpoints  -> addresses where cpu_loop_bounded stops before executing the instruction
+// tracer       -> sinks that receive the trace records (CSR accesses, traps, instructions)
+// trace_exec   -> if true, every instruction executed in debug mode is traced
+// fetch_fault  -> what to do when an instruction cannot be fetched
+// timebase     -> where the time CSR takes its value from, and at which frequency
+// htif         -> if present, the stores to tohost are commands for the host (HTIF of spike)
+// test_finisher -> if present, the stores to it end the run (sifive_test of QEMU)
+// xlen         -> width of the registers (RV32 or RV64)
+#[derive(Clone)]
 pub struct Cpu {
     regs: [u64; REG_FILE_SIZE],
+    fpu: Fpu,
     last_updated_register: RegIndex,
-    csregs: [u64; CS_REG_FILE_SIZE],
+    csrs: CsrFile,
     pc: u64,
     next_pc: u64,
     bus: bus::Bus,
     debug_mode: bool,
-    debug_string: String
+    debug_string: String,
+    unhandled_trap: Option<Trap>,
+    trace_csr: bool,
+    trap_statistics: TrapStatistics,
+    accounting: TimeAccounting,
+    profile: Option<InstructionProfile>,
+    coverage: Option<Coverage>,
+    branch_profile: Option<BranchProfile>,
+    syscalls: Option<SyscallEmulator>,
+    sbi: Option<Sbi>,
+    semihosting: Option<Semihosting>,
+    exit_code: Option<u64>,
+    memory_writes: Vec<MemoryWrite>,
+    stack_guard: Option<StackGuard>,
+    stack_guard_region: Option<Range<u64>>,
+    stack_overflow: Option<StackOverflow>,
+    call_stack: CallStack,
+    code_watch: Option<CodeWatch>,
+    taint: Option<TaintTracker>,
+    breakpoints: Vec<u64>,
+    // Events sent by the host while the CPU runs, and whether one of
+    // them asked to stop the run
+    events: HostEvents,
+    stop_requested: bool,
+    // Instructions already decoded, used when not in debug mode
+    decode_cache: DecodeCache,
+    tracer: Tracer,
+    trace_exec: bool,
+    fetch_fault: FetchFaultAction,
+    timebase: Timebase,
+    htif: Option<Htif>,
+    test_finisher: Option<TestFinisher>,
+    // Sleep the host thread while WFI stalls the hart
+    idle_sleep: bool,
+    xlen: Xlen
 }
 
 // Cpu struct methods implementation
@@ -50,6 +265,8 @@ impl Cpu {
     pub const STACK_POINTER: RegIndex = 0x2;
     pub const GLOBAL_POINTER: RegIndex = 0x3;
     //pub const THREAD_POINTER: RegIndex = 0x4;
+    pub const ARGUMENT_REGISTER: RegIndex = 0xa;
+    pub const SECOND_ARGUMENT_REGISTER: RegIndex = 0xb;
 
     // Return address loaded automatically in RA register at startup.
     // In this way, if a program executes a 'ret' as a last instruction
@@ -57,25 +274,92 @@ impl Cpu {
     // instruction will know when to stop the loop
     pub const SENTINEL_RETURN_ADDRESS: u64 = 0xfffffffffffffffe;
 
+    // Cycles that WFI stalls for at most before it is executed again:
+    // 10 ms of nominal time
+    const WFI_SLICE: u64 = Clint::TIMEBASE_FREQUENCY / 100;
+    // Instructions between two looks at the events sent by the host (a
+    // power of two)
+    const EVENT_INTERVAL: u64 = 4096;
+
     /// Cpu constructor given a memory size for its DRAM
     pub fn new(memsize: Option<usize>) -> Cpu {
+        Cpu::with_bus(bus::Bus::new(memsize))
+    }
+
+    /// Cpu connected to the bus of a machine with a fixed memory map
+    pub fn with_machine(machine: &MachineConfig) -> Cpu {
+        Cpu::with_bus(bus::Bus::with_machine(machine))
+    }
+
+    fn with_bus(bus: bus::Bus) -> Cpu {
         Cpu {
             regs: [0; REG_FILE_SIZE],
+            fpu: Fpu::new(),
             last_updated_register: 0,
-            csregs: [0; CS_REG_FILE_SIZE],
+            csrs: CsrFile::new(),
             pc: PC_INITIAL_VALUE,
             next_pc: PC_INITIAL_VALUE,
-            bus: bus::Bus::new(memsize),
+            bus,
             debug_string: String::new(),
             debug_mode: false,
+            unhandled_trap: None,
+            trace_csr: false,
+            trap_statistics: TrapStatistics::new(),
+            accounting: TimeAccounting::new(),
+            profile: None,
+            coverage: None,
+            branch_profile: None,
+            syscalls: None,
+            sbi: None,
+            semihosting: None,
+            exit_code: None,
+            memory_writes: Vec::new(),
+            stack_guard: None,
+            stack_guard_region: None,
+            stack_overflow: None,
+            call_stack: CallStack::new(),
+            code_watch: None,
+            taint: None,
+            breakpoints: Vec::new(),
+            events: HostEvents::new(),
+            stop_requested: false,
+            decode_cache: DecodeCache::new(),
+            tracer: Tracer::new(),
+            trace_exec: false,
+            fetch_fault: FetchFaultAction::Trap,
+            timebase: Timebase::default(),
+            htif: None,
+            test_finisher: None,
+            idle_sleep: true,
+            xlen: Xlen::Rv64
         }
     }
 
-    /// Function that writes to a Cpu register
+    /// Function that writes to a Cpu register. In RV32 mode only the low
+    /// 32 bits are kept, sign-extended. Writes to x0 are discarded, as it
+    /// is hardwired to 0
     #[inline(always)]
     pub fn write_reg(&mut self, regi: RegIndex, data: u64) {
-        self.regs[regi as usize] = data;
-        self.last_updated_register = regi;
+        if regi == Cpu::ZERO_REGISTER {
+            return;
+        }
+        self.regs[regi as usize] = match self.xlen {
+            Xlen::Rv32 => data as i32 as i64 as u64,
+            Xlen::Rv64 => data
+        };
+    }
+
+    /// Set the width of the registers, before the program is started
+    pub fn set_xlen(&mut self, xlen: Xlen) {
+        self.xlen = xlen;
+        self.csrs.set_xlen(xlen);
+        self.decode_cache.flush();
+    }
+
+    /// Get the width of the registers
+    #[inline(always)]
+    pub fn get_xlen(&self) -> Xlen {
+        self.xlen
     }
 
     /// Function that reads data from a Cpu register
@@ -84,22 +368,187 @@ impl Cpu {
         self.regs[regi as usize]
     }
 
-    /// Function that writes data to a Cpu CS register
+    /// Function that writes data to a Cpu CS register.
+    /// Writing a CSR that does not exist, that is read-only or that is
+    /// not accessible from the current privilege level is an illegal instruction
     #[inline(always)]
-    pub fn write_csreg(&mut self, csregi: CSRegIndex, data: u64) {
-        match self.csregs.get_mut(csregi as usize) {
-            Some(val) => *val = data,
-            None => panic!("Invalid CSR address")
+    pub fn write_csreg(&mut self, csregi: CSRegIndex, data: u64) -> Result<(), Trap> {
+        if !self.csrs.is_accessible(csregi) {
+            return Err(Trap::IllegalInstruction(0));
+        }
+        let old_data: Option<u64> = if self.trace_csr { self.csrs.read(csregi) } else { None };
+        match self.csrs.write(csregi, data & self.xlen.mask()) {
+            Some(()) => {
+                if let Some(old_data) = old_data {
+                    self.trace_csr_write(csregi, old_data, "write");
+                }
+                Ok(())
+            },
+            None => Err(Trap::IllegalInstruction(0))
         }
     }
 
-    /// Function that reads data from a Cpu CS register
+    /// Function that reads data from a Cpu CS register.
+    /// Reading a CSR that does not exist or that is not accessible
+    /// from the current privilege level is an illegal instruction
     #[inline(always)]
-    pub fn read_csreg(&self, csregi: CSRegIndex) -> u64 {
-        match self.csregs.get(csregi as usize) {
-            Some(val) => *val,
-            None => panic!("Invalid CSR address")
+    pub fn read_csreg(&mut self, csregi: CSRegIndex) -> Result<u64, Trap> {
+        if !self.csrs.is_accessible(csregi) {
+            return Err(Trap::IllegalInstruction(0));
+        }
+        if csregi == CsrFile::TIME && self.timebase.get_source() == TimeSource::Host {
+            self.read_host_timebase();
         }
+        match self.csrs.read(csregi).map(|data| data & self.xlen.mask()) {
+            Some(data) => {
+                if self.trace_csr {
+                    self.tracer.emit(TraceKind::Csr, self.pc, format!("read  {} = 0x{:x}", CsrFile::name(csregi), data));
+                }
+                Ok(data)
+            },
+            None => Err(Trap::IllegalInstruction(0))
+        }
+    }
+
+    /// Return from a trap handler of the given privilege level (MRET or SRET):
+    /// the CSRs are restored and the execution continues from the address
+    /// in mepc/sepc. Returning from a more privileged level than the
+    /// current one is an illegal instruction
+    pub fn return_from_trap(&mut self, level: Privilege) -> Result<(), Trap> {
+        if self.csrs.get_privilege() < level {
+            return Err(Trap::IllegalInstruction(0));
+        }
+        let old_mstatus: Option<u64> = self.csrs.read(CsrFile::MSTATUS);
+        self.next_pc = self.csrs.return_from_trap(level);
+        self.accounting.return_from_trap(self.csrs.get_privilege());
+        if let (true, Some(old_mstatus)) = (self.trace_csr, old_mstatus) {
+            let origin: &str = match level {
+                Privilege::Machine => "mret",
+                _ => "sret"
+            };
+            self.trace_csr_write(CsrFile::MSTATUS, old_mstatus, origin);
+        }
+        Ok(())
+    }
+
+    /// Get the CSRs
+    pub fn get_csrs(&self) -> &CsrFile {
+        &self.csrs
+    }
+
+    /// Get the CSRs to change them from outside of the program (e.g. by
+    /// the emulated firmware)
+    pub fn get_csrs_mut(&mut self) -> &mut CsrFile {
+        &mut self.csrs
+    }
+
+    /// Read a floating-point register as a value of the given format
+    #[inline(always)]
+    pub fn read_freg(&self, regi: RegIndex, fmt: Format) -> u64 {
+        self.fpu.read(regi, fmt)
+    }
+
+    /// Write a floating-point register, which makes the floating-point
+    /// state Dirty in mstatus
+    #[inline(always)]
+    pub fn write_freg(&mut self, regi: RegIndex, fmt: Format, data: u64) {
+        self.fpu.write(regi, fmt, data);
+        self.csrs.set_float_dirty();
+    }
+
+    /// Get the floating-point registers
+    pub fn get_fpu(&self) -> &Fpu {
+        &self.fpu
+    }
+
+    /// Choose how the floating-point arithmetic is computed
+    pub fn set_float_mode(&mut self, mode: FloatMode) {
+        self.fpu.set_mode(mode);
+    }
+
+    /// Choose whether the host thread sleeps while WFI stalls the hart,
+    /// or the idle time is skipped as fast as possible
+    pub fn set_idle_sleep(&mut self, sleep: bool) {
+        self.idle_sleep = sleep;
+    }
+
+    /// Get the privilege level the CPU is executing in
+    pub fn get_privilege(&self) -> Privilege {
+        self.csrs.get_privilege()
+    }
+
+    #[inline(always)]
+    /// Enable or disable the tracing of CSR accesses
+    pub fn set_trace_csr(&mut self, enable: bool) {
+        self.trace_csr = enable;
+    }
+
+    /// Enable or disable the tracing of every instruction executed in
+    /// debug mode, with its disassembly
+    pub fn set_trace_exec(&mut self, enable: bool) {
+        self.trace_exec = enable;
+    }
+
+    /// Check if executed instructions are traced
+    pub fn is_tracing_exec(&self) -> bool {
+        self.trace_exec
+    }
+
+    /// Choose what happens when the PC leaves the mapped memory
+    pub fn set_fetch_fault_action(&mut self, action: FetchFaultAction) {
+        self.fetch_fault = action;
+    }
+
+    /// Get what happens when the PC leaves the mapped memory
+    pub fn get_fetch_fault_action(&self) -> FetchFaultAction {
+        self.fetch_fault
+    }
+
+    /// Check if the execution was stopped because the PC left the mapped memory
+    pub fn left_mapped_memory(&self) -> bool {
+        matches!(self.unhandled_trap, Some(Trap::InstructionAccessFault(_)))
+    }
+
+    /// Copy the characters the program sends through the UART to a console log
+    pub fn set_console_log(&mut self, log: SharedConsoleLog) {
+        self.bus.set_console_log(log);
+    }
+
+    /// Send the console of the program (UART and HTIF) to another host console
+    pub fn set_host_console(&mut self, console: SharedHostConsole) {
+        self.bus.set_host_console(console);
+    }
+
+    /// Keep the characters sent to the console (see Bus::capture_console)
+    pub fn capture_console(&mut self) {
+        self.bus.capture_console();
+    }
+
+    /// Take the characters sent to the console since the last call
+    pub fn take_console_output(&mut self) -> Vec<u8> {
+        self.bus.take_console_output()
+    }
+
+    /// Get the tracer, to add sinks to it
+    pub fn get_tracer(&mut self) -> &mut Tracer {
+        &mut self.tracer
+    }
+
+    // Trace the instruction that has just been executed at pc in debug mode
+    fn trace_instruction(&self, pc: u64) {
+        if self.trace_exec && !self.debug_string.is_empty() {
+            self.tracer.emit(TraceKind::Instruction, pc, self.debug_string.clone());
+        }
+    }
+
+    /// Log the change of a CSR from its old value to the current one,
+    /// decoding the fields that were modified
+    fn trace_csr_write(&self, csregi: CSRegIndex, old_data: u64, origin: &str) {
+        let new_data: u64 = self.csrs.read(csregi).unwrap_or(0);
+        let changes: String = CsrFile::describe_change(csregi, old_data, new_data);
+        self.tracer.emit(TraceKind::Csr, self.pc, format!("{:5} {} 0x{:x} -> 0x{:x}{}",
+                         origin, CsrFile::name(csregi), old_data, new_data,
+                         if changes.is_empty() { String::new() } else { format!(" ({})", changes) }));
     }
 
     /// Returns true if the register index passes as a parameter
@@ -129,11 +578,11 @@ impl Cpu {
             }
             i += 1;
             // Every 2 dumped registers print a new line
-            if i % 2 == 0 {
-                println!("");
+            if i.is_multiple_of(2) {
+                println!();
             }
         }
-        println!("");
+        println!();
     }
 
     #[inline(always)]
@@ -146,6 +595,8 @@ impl Cpu {
     /// Clear the debug mode of the CPU
     pub fn clear_debug_mode(&mut self) {
         self.debug_mode = false;
+        // Calls are no longer tracked
+        self.call_stack.clear();
     }
 
     #[inline(always)]
@@ -154,10 +605,9 @@ impl Cpu {
         self.debug_mode
     }
 
-    #[inline(always)]
-    /// Set the debug string (string containing the decoded instruction)
-    pub fn set_debug_string(&mut self, dec_instruction: String) {
-        self.debug_string = dec_instruction
+    /// Get the debug string of the last executed instruction
+    pub fn get_debug_string(&self) -> &str {
+        &self.debug_string
     }
 
     /// Get the current Program Counter
@@ -181,13 +631,27 @@ impl Cpu {
     /// Set the next PC = PC + signed constant
     #[inline(always)]
     pub fn set_next_pc_rel(&mut self, value: i64) {
-        self.next_pc = (self.pc as i64 + value) as u64;
+        self.next_pc = (self.pc as i64).wrapping_add(value) as u64 & self.xlen.mask();
     }
 
-    /// Set the next PC = unsigned constant
+    /// Resolve a conditional branch: jump offset bytes away if it is
+    /// taken, and let the branch predictor (if any) see the outcome
+    #[inline(always)]
+    pub fn resolve_branch(&mut self, taken: bool, offset: i64) {
+        if let Some(branches) = self.branch_profile.as_mut() {
+            branches.resolve(self.pc, offset, taken);
+        }
+        if taken {
+            self.set_next_pc_rel(offset);
+        }
+    }
+
+    /// Set the next PC = unsigned constant. The sentinel return address
+    /// is kept as it is in RV32 mode too, so that returning from the
+    /// entry point still ends the program
     #[inline(always)]
     pub fn set_next_pc_abs(&mut self, value: u64) {
-        self.next_pc = value;
+        self.next_pc = if value == Cpu::SENTINEL_RETURN_ADDRESS { value } else { value & self.xlen.mask() };
     }
 
     #[inline(always)]
@@ -200,33 +664,326 @@ impl Cpu {
     /// Since I/O is memory mapped it could be a load from DRAM, ROM or
     /// any peripheral
     #[inline(always)]
-    pub fn load(&self, addr: u64, size: AccessSize) -> u64 {
-        self.bus.read(addr, size)
+    pub fn load(&mut self, addr: u64, size: AccessSize) -> Result<u64, Trap> {
+        let addr: u64 = addr & self.xlen.mask();
+        if !self.csrs.pmp_allows(addr, size.bytes() as u64, PmpAccess::Read) {
+            return Err(Trap::LoadAccessFault(addr));
+        }
+        if self.hits_stack_guard_region(addr, size) {
+            return Err(Trap::LoadAccessFault(addr));
+        }
+        self.bus.read(addr, size).map_err(MemError::load_trap)
+    }
+
+    /// Same as load, but without side effects on memory-mapped
+    /// peripherals. Used to inspect memory from outside the program
+    pub fn peek(&self, addr: u64, size: AccessSize) -> Result<u64, Trap> {
+        self.bus.peek(addr & self.xlen.mask(), size).map_err(MemError::load_trap)
+    }
+
+    /// Bytes of memory from addr to the end of the region it is in (see
+    /// Bus::mapped_bytes)
+    pub fn mapped_bytes(&self, addr: u64) -> u64 {
+        self.bus.mapped_bytes(addr & self.xlen.mask())
     }
 
     /// Cpu store at address (control is given to the Bus)
     #[inline(always)]
-    pub fn store(&mut self, data: u64, addr: u64, size: AccessSize) {
-        self.bus.write(data, addr, size);
+    pub fn store(&mut self, data: u64, addr: u64, size: AccessSize) -> Result<(), Trap> {
+        let addr: u64 = addr & self.xlen.mask();
+        if !self.csrs.pmp_allows(addr, size.bytes() as u64, PmpAccess::Write) || !self.bus.is_writable(addr, size) {
+            return Err(Trap::StoreAccessFault(addr));
+        }
+        if self.hits_stack_guard_region(addr, size) {
+            return Err(Trap::StoreAccessFault(addr));
+        }
+        if let Some(guard) = self.stack_guard.as_mut() {
+            guard.check_store(self.pc, addr, size, data);
+        }
+        if let Some(watch) = self.code_watch.as_mut() {
+            watch.check_store(self.pc, addr, size);
+        }
+        if self.test_finisher.as_ref().is_some_and(|finisher| finisher.contains(addr)) {
+            self.write_test_finisher(addr, data);
+            return Ok(());
+        }
+        self.decode_cache.invalidate(addr, size);
+        self.bus.write(data, addr, size).map_err(MemError::store_trap)?;
+        // A store to a device register can start a DMA transfer
+        if self.bus.take_device_writes() {
+            self.decode_cache.flush();
+        }
+        if self.htif.as_ref().is_some_and(|htif| htif.is_tohost(addr)) {
+            self.write_tohost(addr);
+        }
+        Ok(())
+    }
+
+    /// Same as store, but not seen by the debugging aids (stack guard, code
+    /// watch) and by HTIF. Used to change memory from outside the program
+    pub fn poke(&mut self, data: u64, addr: u64, size: AccessSize) -> Result<(), Trap> {
+        let addr: u64 = addr & self.xlen.mask();
+        self.decode_cache.invalidate(addr, size);
+        self.bus.write(data, addr, size).map_err(MemError::store_trap)?;
+        if self.bus.take_device_writes() {
+            self.decode_cache.flush();
+        }
+        Ok(())
+    }
+
+    /// Keep track of a memory location that is about to be written by the
+    /// current instruction. It is only called by the instructions in debug mode
+    pub fn log_memory_write(&mut self, addr: u64, size: AccessSize, data: u64) {
+        let old: Option<u64> = self.peek(addr, size).ok();
+        self.memory_writes.push(MemoryWrite { address: addr, size, old, new: data });
     }
 
     /// Store an entire buffer into CPU memory (either ROM or DRAM,
     /// depending on the address)
-    pub fn store_from_buffer(&mut self, data: &[u8], addr: u64) {
+    pub fn store_from_buffer(&mut self, data: &[u8], addr: u64) -> Result<(), MemError> {
+        self.decode_cache.flush();
         self.bus.write_from_buf(addr, data)
     }
 
+    /// Map a boot ROM image at the reset vector (see get_boot_rom_base)
+    pub fn load_boot_rom(&mut self, image: &[u8]) -> Result<(), String> {
+        self.decode_cache.flush();
+        self.bus.load_bootrom(image)
+    }
+
+    /// Attach a device to the bus (see Bus::add_device)
+    pub fn add_device(&mut self, base: u64, irq: Option<usize>, device: Box<dyn Device>) -> Result<(), String> {
+        self.bus.add_device(base, irq, device)
+    }
+
+    /// Attach another UART (see Bus::add_uart)
+    pub fn add_uart(&mut self, spec: UartSpec, console: Option<SharedHostConsole>) -> Result<(), String> {
+        self.bus.add_uart(spec, console)
+    }
+
+    /// Describe the machine for the device tree (see Bus::describe)
+    pub fn describe_platform(&self) -> Platform {
+        let mut platform: Platform = self.bus.describe(self.xlen);
+        platform.timebase_frequency = self.timebase.get_frequency();
+        if let Some(finisher) = self.test_finisher.as_ref() {
+            platform.devices.push(DeviceNode {
+                name: "test".to_string(),
+                compatible: ["sifive,test1", "sifive,test0", "syscon"].iter().map(|compatible| compatible.to_string()).collect(),
+                base: finisher.get_base(),
+                size: TestFinisher::SIZE,
+                irq: None,
+                properties: Vec::new()
+            });
+        }
+        platform
+    }
+
+    /// Attach a drive to the bus (see Bus::attach_drive)
+    pub fn attach_drive(&mut self, drive: VirtioBlock) -> Result<(), String> {
+        self.bus.attach_drive(drive)
+    }
+
+    pub fn get_drive(&self) -> Option<&VirtioBlock> {
+        self.bus.get_drive()
+    }
+
+    pub fn get_drive_mut(&mut self) -> Option<&mut VirtioBlock> {
+        self.bus.get_drive_mut()
+    }
+
+    pub fn get_virtio_base(&self) -> u64 {
+        self.bus.get_virtio_base()
+    }
+
+    /// Attach a device to the bus in a window of size bytes (see Bus::attach)
+    pub fn attach_device(&mut self, device: Box<dyn Device>, base: u64, size: u64) -> Result<(), String> {
+        self.bus.attach(device, base, size)
+    }
+
+    /// Address the boot ROM is mapped at
+    pub fn get_boot_rom_base(&self) -> u64 {
+        self.bus.get_bootrom_base()
+    }
+
+    /// Guest time (mtime)
+    pub fn get_mtime(&self) -> u64 {
+        self.bus.get_clint().get_mtime()
+    }
+
+    /// Raise an interrupt line from the host (see Bus::raise_irq). The
+    /// interrupt is taken before the next instruction if it is enabled
+    pub fn raise_irq(&mut self, line: bus::InterruptLine) {
+        self.bus.raise_irq(line);
+    }
+
+    /// Queue of the events of the host (see HostEvents): the clones it
+    /// returns can be sent to other threads, and the CPU handles their
+    /// events while it runs
+    pub fn host_events(&self) -> HostEvents {
+        self.events.clone()
+    }
+
+    /// Check if the host asked to stop the run (HostEvent::Stop), and
+    /// forget the request
+    pub fn take_stop_request(&mut self) -> bool {
+        std::mem::take(&mut self.stop_requested)
+    }
+
+    // Handle the events sent by the host, if any. An interrupt they raise
+    // is taken right away
+    #[inline(always)]
+    fn pump_events(&mut self) {
+        if !self.events.is_pending() {
+            return;
+        }
+        for event in self.events.take() {
+            match event {
+                HostEvent::RaiseIrq(line) => self.bus.raise_irq(line),
+                HostEvent::Stop => self.stop_requested = true
+            }
+        }
+        self.update_interrupt_lines();
+        if let Some(interrupt) = self.csrs.pending_interrupt() {
+            self.take_interrupt(interrupt);
+        }
+    }
+
+    /// Move the guest time, the real-time clock moves with it when it has been set
+    pub fn set_mtime(&mut self, mtime: u64) {
+        self.bus.get_clint_mut().set_mtime(mtime);
+    }
+
+    /// Ticks of mtime per executed instruction, 0 freezes the guest time
+    pub fn set_time_scale(&mut self, scale: f64) -> Result<(), String> {
+        self.bus.get_clint_mut().set_rate(scale)
+    }
+
+    pub fn get_time_scale(&self) -> f64 {
+        self.bus.get_clint().get_rate()
+    }
+
+    /// Set the real-time clock of the guest (time since the epoch), from
+    /// now on it follows mtime instead of the host clock
+    pub fn set_rtc_time(&mut self, time: std::time::Duration) {
+        let mtime: u64 = self.get_mtime();
+        self.bus.get_rtc_mut().set(time, mtime);
+    }
+
+    /// Current time of the real-time clock of the guest (since the epoch)
+    pub fn get_rtc_time(&self) -> std::time::Duration {
+        self.bus.get_rtc().now(self.get_mtime())
+    }
+
+    /// Time of the real-time clock read by the program: unlike
+    /// get_rtc_time, the reads of the host time are recorded or replayed
+    /// with the other inputs
+    pub fn read_rtc_time(&mut self) -> std::time::Duration {
+        self.bus.read_rtc_time()
+    }
+
+    /// Set the next timer event of the CLINT (mtimecmp), given as a value
+    /// of the time CSR
+    pub fn set_timer(&mut self, time: u64) {
+        let mtimecmp: u64 = self.timebase.to_mtime(time);
+        self.bus.get_clint_mut().set_mtimecmp(mtimecmp);
+    }
+
+    /// Take the time CSR from timebase
+    pub fn set_timebase(&mut self, timebase: Timebase) {
+        self.timebase = timebase;
+    }
+
+    pub fn get_timebase(&self) -> &Timebase {
+        &self.timebase
+    }
+
+    // The time CSR follows the host clock: it is only updated when the
+    // program reads it. The host time is recorded or replayed with the
+    // other inputs
+    #[cold]
+    fn read_host_timebase(&mut self) {
+        let now: std::time::Duration = self.bus.read_host_time();
+        self.csrs.set_time(self.timebase.from_host(now));
+    }
+
+    /// Print a character on the console without going through the UART
+    pub fn write_console(&mut self, byte: u8) {
+        self.bus.write_console(byte);
+    }
+
+    /// Take the character received by the UART, if any
+    pub fn read_console(&mut self) -> Option<u8> {
+        self.bus.read_console()
+    }
+
+    /// Read the standard input of the program (recorded or replayed with
+    /// the other inputs)
+    pub fn read_stdin(&mut self, data: &mut [u8]) -> std::io::Result<usize> {
+        self.bus.read_stdin(data)
+    }
+
+    pub fn write_stdout(&self, data: &[u8]) -> std::io::Result<()> {
+        self.bus.write_stdout(data)
+    }
+
+    pub fn write_stderr(&self, data: &[u8]) -> std::io::Result<()> {
+        self.bus.write_stderr(data)
+    }
+
+    /// Redirect the standard streams of the program (system calls and
+    /// semihosting) to files
+    pub fn set_stdio(&mut self, stdio: GuestStdio) {
+        self.bus.set_stdio(stdio);
+    }
+
+    /// Record the inputs that come from outside of the machine, or replay
+    /// them from a recording
+    pub fn set_input_log(&mut self, log: InputLog) {
+        self.bus.set_input_log(log);
+    }
+
+    /// Get the input log, if the inputs are being recorded or replayed
+    pub fn get_input_log(&self) -> Option<&InputLog> {
+        self.bus.get_input_log()
+    }
+
+    /// Whether the real-time clock of the guest is the one of the host
+    pub fn rtc_follows_host(&self) -> bool {
+        self.bus.get_rtc().is_host()
+    }
+
     #[allow(dead_code)]
     /// Get size of the read-only memory (ROM) [unused for now]
     pub fn get_read_only_memsize(&self) -> usize {
         self.bus.get_rom_size()
     }
 
+    /// Make the memory copy-on-write for the clones of the CPU
+    pub fn share_memory(&mut self) -> Result<(), String> {
+        self.bus.share_memory()
+    }
+
+    /// Set the initial content of the read-write memory (DRAM)
+    pub fn init_memory(&mut self, init: memory::MemoryInit) {
+        self.bus.init_dram(init);
+    }
+
     /// Get size of the read-write memory (DRAM)
     pub fn get_read_write_memsize(&self) -> usize {
         self.bus.get_dram_size()
     }
 
+    /// Give a range of memory the access rights of the segment loaded there
+    pub fn set_permissions(&mut self, range: Range<u64>, permissions: bus::Permissions) {
+        self.bus.set_permissions(range, permissions);
+    }
+
+    /// Choose whether the stores to the segments that are not writable
+    /// raise a store access fault
+    pub fn set_protection(&mut self, protect: bool) {
+        self.bus.set_protection(protect);
+    }
+
     /// Set the beginning of the read-only segment
     pub fn set_read_only_segment(&mut self, offset: u64) {
         self.bus.set_rom_offset(offset);
@@ -237,70 +994,778 @@ impl Cpu {
         self.bus.set_dram_offset(offset)
     }
 
+    /// Get the beginning of the read-write segment
+    pub fn get_read_write_segment(&self) -> u64 {
+        self.bus.get_dram_offset()
+    }
+
     /// Get pointer to device memory
     pub fn get_memory(&self) -> &memory::Memory {
         self.bus.get_device()
     }
 
-    /// Good ol' Fetch, Decode and Execute loop
-    pub fn cpu_loop(&mut self) -> u64 {
+    /// Good ol' Fetch, Decode and Execute loop. It returns the number of
+    /// executed instructions and why the program stopped
+    pub fn cpu_loop(&mut self) -> (u64, ExitReason) {
         let mut count_instructions: u64 = 0;
-        loop {
-            if self.pc == Cpu::SENTINEL_RETURN_ADDRESS {
-                break count_instructions;
+        'run: loop {
+            // The events of the host are handled between runs of
+            // EVENT_INTERVAL instructions, not checked for every one
+            for _ in 0..Cpu::EVENT_INTERVAL {
+                if self.pc == Cpu::SENTINEL_RETURN_ADDRESS {
+                    break 'run;
+                }
+                match self.execute_instruction::<false>() {
+                    Ok(()) => count_instructions += 1,
+                    // Stop if the trap cannot be handled by the program
+                    Err(trap) => if !self.take_trap(trap) {
+                        break 'run;
+                    }
+                }
+            }
+            self.pump_events();
+            if self.take_stop_request() {
+                break;
             }
-            // Fetch and instruction
-            let fetched_instruction: Instruction = self.fetch();
-            // Set the next PC assuming we continue the flow of execution
-            self.next_pc = self.pc + 4;
-            // Decode the instruction and call the function that implements
-            // that instruction
-            self.decode_and_execute(fetched_instruction);
-
-            // The executed instruction might have changed the next PC
-            // from the PC + 4 value, now assign next PC to PC
-            self.pc = self.next_pc;
-            count_instructions += 1;
         }
+        (count_instructions, self.get_exit_reason().unwrap_or(ExitReason::InstructionLimit))
     }
 
     /// Run the CPU loop in interactive mode. The reason it is a separate function
     /// is that if you want to run in non-interactive mode (pure performance) there is
-    /// no overhead due to checking if we need to print the executed instructions
-    pub fn cpu_loop_interactive(&mut self, num_steps: u64) -> u64 {
+    /// no overhead due to checking if we need to print the executed instructions.
+    /// The symbol map is used to show which function each instruction belongs to
+    pub fn cpu_loop_interactive(&mut self, num_steps: u64, symbols: &SymbolMap) -> u64 {
         let mut count_instructions: u64 = 0;
         for _i in 0..num_steps {
-            if self.pc == Cpu::SENTINEL_RETURN_ADDRESS {
+            if self.pc == Cpu::SENTINEL_RETURN_ADDRESS || self.unhandled_trap.is_some() {
                 break;
             }
-            // Fetch and instruction
-            let fetched_instruction: Instruction = self.fetch();
-            // Set the next PC assuming we continue the flow of execution
-            self.next_pc = self.pc + 4;
-            // Decode the instruction and call the function that implements
-            // that instruction
-            self.decode_and_execute(fetched_instruction);
+            // Find the symbol the instruction belongs to before the PC is updated
+            let location: Option<String> = symbols.symbolize(self.pc);
+            self.debug_string.clear();
+            let pc: u64 = self.pc;
+            let result: Result<(), Trap> = self.execute_instruction::<true>();
+            self.trace_instruction(pc);
 
-            println!("{}", self.debug_string);
+            // The debug string is empty if the instruction could not be fetched
+            if !self.debug_string.is_empty() {
+                match location {
+                    Some(location) => println!("{} {}", format!("<{}>", location).yellow(), disas::highlight(&self.debug_string)),
+                    None => println!("{}", disas::highlight(&self.debug_string))
+                }
+            }
 
-            count_instructions += 1;
+            match result {
+                Ok(()) => count_instructions += 1,
+                Err(trap) => {
+                    println!("{} {}", "trap:".red(), symbols.describe_trap(&trap));
+                    if !self.take_trap(trap) {
+                        break;
+                    }
+                }
+            }
+        }
+        count_instructions
+    }
 
-            // The executed instruction might have changed the next PC
-            // from the PC + 4 value, now assign next PC to PC
-            self.pc = self.next_pc;
+    /// Run at most num_steps instructions without printing anything, stopping
+    /// earlier if the program is over or at a breakpoint. It returns the
+    /// number of executed instructions. An instruction that traps counts as
+    /// executed, so that a handler that traps itself cannot run forever
+    pub fn cpu_loop_bounded(&mut self, num_steps: u64) -> u64 {
+        let mut count_instructions: u64 = 0;
+        while count_instructions < num_steps {
+            if self.pc == Cpu::SENTINEL_RETURN_ADDRESS || self.unhandled_trap.is_some() {
+                break;
+            }
+            // A stop is left to the caller, which runs in chunks
+            if count_instructions & (Cpu::EVENT_INTERVAL - 1) == 0 {
+                self.pump_events();
+            }
+            // The check is done once per instruction here rather than
+            // inside every instruction as in the interactive loop
+            let result: Result<(), Trap> = if self.debug_mode {
+                let pc: u64 = self.pc;
+                self.debug_string.clear();
+                let result: Result<(), Trap> = self.execute_instruction::<true>();
+                self.trace_instruction(pc);
+                result
+            } else {
+                self.execute_instruction::<false>()
+            };
+            match result {
+                Ok(()) => count_instructions += 1,
+                Err(trap) => if self.take_trap(trap) {
+                    count_instructions += 1;
+                } else {
+                    break;
+                }
+            }
+            if !self.breakpoints.is_empty() && self.is_at_breakpoint() {
+                break;
+            }
         }
         count_instructions
     }
 
+    /// Stop cpu_loop_bounded when the pc reaches one of the addresses,
+    /// before the instruction there is executed. The first instruction of
+    /// a run is always executed, so that a run can leave a breakpoint
+    pub fn set_breakpoints(&mut self, addresses: Vec<u64>) {
+        self.breakpoints = addresses;
+    }
+
+    /// Check if the pc is at one of the breakpoints
+    pub fn is_at_breakpoint(&self) -> bool {
+        self.breakpoints.contains(&self.pc)
+    }
+
+    /// Execute a single instruction and report everything it did: its
+    /// disassembly, the registers, memory locations and CSRs it wrote and
+    /// the trap it raised, if any. The trap is taken as in the other loops.
+    /// If the program is already over nothing is executed and the
+    /// report has no instruction
+    pub fn step_with_state(&mut self) -> StepReport {
+        let mut report: StepReport = StepReport::new(self.pc);
+        if self.is_finished() {
+            return report;
+        }
+
+        let regs_before: [u64; REG_FILE_SIZE] = self.regs;
+        let csrs_before: Vec<(CSRegIndex, u64)> = CsrFile::implemented()
+            .filter_map(|csr| self.csrs.read(csr).map(|data| (csr, data)))
+            .collect();
+        report.instruction = self.fetch().ok();
+        // Registers written with the value they already had are still reported
+        let destination: Option<RegIndex> = report.instruction.and_then(rv::destination_register);
+
+        self.debug_string.clear();
+        let result: Result<(), Trap> = self.execute_instruction::<true>();
+        report.disassembly = self.debug_string.clone();
+
+        match result {
+            Ok(()) => {
+                report.register_writes = (1..REG_FILE_SIZE)
+                    .filter(|i| regs_before[*i] != self.regs[*i] || destination == Some(*i as RegIndex))
+                    .map(|i| RegisterWrite { register: i as RegIndex, old: regs_before[i], new: self.regs[i] })
+                    .collect();
+                report.memory_writes = std::mem::take(&mut self.memory_writes);
+            },
+            Err(trap) => {
+                report.trap = Some(trap);
+                self.take_trap(trap);
+            }
+        }
+
+        report.csr_writes = csrs_before.into_iter()
+            .filter_map(|(csr, old)| match self.csrs.read(csr) {
+                Some(new) if new != old => Some(CsrWrite { csr, old, new }),
+                _ => None
+            })
+            .collect();
+        report.next_pc = self.pc;
+        report
+    }
+
+    /// Check if the program has returned to the sentinel address or
+    /// it was stopped by a trap that it could not handle
+    pub fn is_finished(&self) -> bool {
+        self.pc == Cpu::SENTINEL_RETURN_ADDRESS || self.unhandled_trap.is_some()
+    }
+
+    /// Get why the program stopped, if it is over
+    pub fn get_exit_reason(&self) -> Option<ExitReason> {
+        match (self.exit_code, self.unhandled_trap) {
+            (Some(code), _) => Some(ExitReason::Exited(code)),
+            (None, Some(Trap::Breakpoint(pc))) => Some(ExitReason::Breakpoint(pc)),
+            (None, Some(trap)) => Some(ExitReason::Trap(trap)),
+            (None, None) if self.pc == Cpu::SENTINEL_RETURN_ADDRESS =>
+                Some(ExitReason::Returned(self.read_reg(Cpu::ARGUMENT_REGISTER))),
+            (None, None) => None
+        }
+    }
+
+    /// Get the trap that stopped the execution, if any
+    pub fn get_unhandled_trap(&self) -> Option<Trap> {
+        self.unhandled_trap
+    }
+
+    /// Serve system calls made with ECALL on the host (user-mode emulation)
+    pub fn set_syscall_emulation(&mut self, syscalls: SyscallEmulator) {
+        self.syscalls = Some(syscalls);
+    }
+
+    pub fn get_syscall_emulation_mut(&mut self) -> Option<&mut SyscallEmulator> {
+        self.syscalls.as_mut()
+    }
+
+    /// Serve the semihosting calls (EBREAK between the semihosting
+    /// markers) on the host
+    pub fn set_semihosting(&mut self, semihosting: Semihosting) {
+        self.semihosting = Some(semihosting);
+    }
+
+    /// Serve the SBI calls made with ECALL from S-mode, as the M-mode
+    /// firmware does, and pass the machine timer interrupt on to S-mode
+    pub fn set_sbi_emulation(&mut self, sbi: Sbi) {
+        self.sbi = Some(sbi);
+    }
+
+    /// Start the program in S-mode, as the firmware does before jumping to
+    /// a kernel: the interrupts and the exceptions that the kernel can
+    /// handle are delegated to it
+    pub fn enter_supervisor(&mut self) {
+        let _ = self.csrs.write(CsrFile::MIDELEG, Sbi::DELEGATED_INTERRUPTS);
+        let _ = self.csrs.write(CsrFile::MEDELEG, Sbi::DELEGATED_EXCEPTIONS);
+        // The kernel can access the whole memory through the last PMP
+        // entry, as with OpenSBI
+        let xlen: Xlen = self.xlen;
+        self.csrs.get_pmp_mut().set_entry(PMP_ENTRIES - 1, Pmp::ALLOW_ALL, u64::MAX, xlen);
+        self.csrs.set_privilege(Privilege::Supervisor);
+        // As if the firmware had executed MRET
+        self.accounting.return_from_trap(Privilege::Supervisor);
+    }
+
+    /// Stop the program as if it had exited with code, from outside of it
+    pub fn exit(&mut self, code: u64) {
+        self.exit_code = Some(code);
+        self.pc = Cpu::SENTINEL_RETURN_ADDRESS;
+        self.next_pc = Cpu::SENTINEL_RETURN_ADDRESS;
+    }
+
+    /// Get the exit code passed to the exit system call (or to the SBI
+    /// shutdown), if the program terminated that way
+    pub fn get_exit_code(&self) -> Option<u64> {
+        self.exit_code
+    }
+
+    /// Get the statistics of the traps raised so far
+    pub fn get_trap_statistics(&self) -> &TrapStatistics {
+        &self.trap_statistics
+    }
+
+    /// Get how the executed instructions are split between privilege
+    /// levels, trap handlers and idle loops
+    pub fn get_time_accounting(&self) -> &TimeAccounting {
+        &self.accounting
+    }
+
+    /// Count the executed instructions by mnemonic and PC
+    pub fn enable_profile(&mut self) {
+        self.profile = Some(InstructionProfile::new());
+    }
+
+    /// Get the profile of the executed instructions, if it is enabled
+    pub fn get_profile(&self) -> Option<&InstructionProfile> {
+        self.profile.as_ref()
+    }
+
+    /// Count the executions of each instruction address
+    pub fn enable_coverage(&mut self) {
+        self.coverage = Some(Coverage::new());
+    }
+
+    /// Get the coverage of the executed instructions, if it is enabled
+    pub fn get_coverage(&self) -> Option<&Coverage> {
+        self.coverage.as_ref()
+    }
+
+    /// Predict the conditional branches with a model and record how
+    /// well it does on each of them
+    pub fn enable_branch_predictor(&mut self, predictor: Box<dyn BranchPredictor>) {
+        self.branch_profile = Some(BranchProfile::new(predictor));
+    }
+
+    /// Get the predictions on the branches, if a predictor is enabled
+    pub fn get_branch_profile(&self) -> Option<&BranchProfile> {
+        self.branch_profile.as_ref()
+    }
+
+    /// Simulate the caches on the instruction fetches and on the loads
+    /// and stores to memory
+    pub fn enable_cache_sim(&mut self, cache_sim: CacheSim) {
+        self.bus.enable_cache_sim(cache_sim);
+    }
+
+    /// Get the simulated caches, if they are enabled
+    pub fn get_cache_sim(&self) -> Option<&CacheSim> {
+        self.bus.get_cache_sim()
+    }
+
+    /// Serve the commands that the program writes to tohost (HTIF)
+    pub fn enable_htif(&mut self, htif: Htif) {
+        self.htif = Some(htif);
+    }
+
+    /// Get the HTIF of the program, if it is enabled
+    pub fn get_htif(&self) -> Option<&Htif> {
+        self.htif.as_ref()
+    }
+
+    /// End the run when the program writes a command to the test finisher.
+    /// Its window must not shadow memory or a device
+    pub fn enable_test_finisher(&mut self, finisher: TestFinisher) -> Result<(), String> {
+        self.bus.check_unmapped("test finisher", finisher.get_base(), TestFinisher::SIZE)?;
+        self.test_finisher = Some(finisher);
+        Ok(())
+    }
+
+    /// Check calls, returns and stores for stack buffer overruns
+    pub fn enable_stack_guard(&mut self) {
+        self.stack_guard = Some(StackGuard::new());
+    }
+
+    /// Get the stack guard, if it is enabled
+    pub fn get_stack_guard(&self) -> Option<&StackGuard> {
+        self.stack_guard.as_ref()
+    }
+
+    /// Stop the program with an access fault when it accesses the guard
+    /// region below its stack, i.e. when the stack overflows
+    pub fn set_stack_guard_region(&mut self, region: Range<u64>) {
+        self.stack_guard_region = Some(region);
+    }
+
+    /// Get the access that stopped the program in the stack guard region, if any
+    pub fn get_stack_overflow(&self) -> Option<StackOverflow> {
+        self.stack_overflow
+    }
+
+    // Check if an access hits the stack guard region, and remember the
+    // first one that does
+    #[inline(always)]
+    fn hits_stack_guard_region(&mut self, addr: u64, size: AccessSize) -> bool {
+        let hit: bool = self.stack_guard_region.as_ref()
+            .is_some_and(|region| addr < region.end && addr.wrapping_add(size.bytes() as u64) > region.start);
+        if hit && self.stack_overflow.is_none() {
+            self.stack_overflow = Some(StackOverflow { pc: self.pc, sp: self.read_reg(Cpu::STACK_POINTER), address: addr });
+        }
+        hit
+    }
+
+    /// Get the calls that have not returned yet. They are only tracked in
+    /// debug mode or with a stack guard region, calls made outside of
+    /// them are missing
+    pub fn get_call_stack(&self) -> &CallStack {
+        &self.call_stack
+    }
+
+    /// Report the stores into the regions watched by watch
+    pub fn enable_code_watch(&mut self, watch: CodeWatch) {
+        self.code_watch = Some(watch);
+    }
+
+    /// Get the watch on the executable regions, if it is enabled
+    pub fn get_code_watch(&self) -> Option<&CodeWatch> {
+        self.code_watch.as_ref()
+    }
+
+    /// Follow the data of the taint sources through the execution
+    pub fn enable_taint(&mut self, taint: TaintTracker) {
+        self.taint = Some(taint);
+    }
+
+    /// Get the taint tracker, if it is enabled
+    pub fn get_taint(&self) -> Option<&TaintTracker> {
+        self.taint.as_ref()
+    }
+
+    /// Log the loads and stores that go through the bus
+    pub fn enable_mem_trace(&mut self, trace: MemTrace) {
+        self.bus.enable_mem_trace(trace);
+    }
+
+    /// Address the UART is mapped at
+    pub fn get_uart_base(&self) -> u64 {
+        self.bus.get_uart_base()
+    }
+
+    /// Fetch, decode and execute a single instruction. If the instruction
+    /// raises a trap, the PC is left pointing to the faulting instruction.
+    /// DEBUG is a compile-time flag: when it is false, all the code that
+    /// produces the disassembly and tracks the last updated register is
+    /// removed from the instruction functions
+    #[inline(always)]
+    fn execute_instruction<const DEBUG: bool>(&mut self) -> Result<(), Trap> {
+        // Fetch and decode the instruction, unless it has already been decoded
+        let decoded: DecodedInstruction = self.fetch_decoded::<DEBUG>()?;
+        self.bus.simulate_fetch(self.pc);
+        let fetched_instruction: Instruction = decoded.instr;
+        // Set the next PC assuming we continue the flow of execution
+        self.next_pc = self.pc + 4;
+        if DEBUG {
+            self.memory_writes.clear();
+            self.debug_string = disas::disassemble(fetched_instruction, self.pc);
+        }
+        // The taint follows the operands, which the instruction might overwrite
+        let regs_before: Option<[u64; REG_FILE_SIZE]> = self.taint.as_ref().map(|_| self.regs);
+        // Call the function that implements the instruction. Illegal
+        // instruction traps carry the instruction itself
+        if let Err(trap) = (decoded.handler)(self, &decoded.operands) {
+            match trap {
+                Trap::IllegalInstruction(_) => return Err(Trap::IllegalInstruction(fetched_instruction)),
+                Trap::EnvironmentCallFromSMode if self.sbi.is_some() => self.emulate_sbi_call(),
+                Trap::EnvironmentCallFromUMode | Trap::EnvironmentCallFromSMode | Trap::EnvironmentCallFromMMode
+                    if self.syscalls.is_some() => self.emulate_syscall(),
+                Trap::Breakpoint(pc) if self.semihosting.is_some() && Semihosting::is_call(self, pc) => self.emulate_semihosting(),
+                trap => return Err(trap)
+            }
+        }
+
+        if DEBUG || self.stack_guard.is_some() || self.stack_guard_region.is_some() || self.profile.is_some() {
+            self.track_stack_frames::<DEBUG>(fetched_instruction);
+        }
+        if let (Some(taint), Some(regs)) = (self.taint.as_mut(), regs_before.as_ref()) {
+            taint.step(self.pc, fetched_instruction, regs);
+        }
+        let taken: bool = self.next_pc != self.pc.wrapping_add(4);
+        self.accounting.record(fetched_instruction);
+        if let Some(profile) = self.profile.as_mut() {
+            profile.record(self.pc, fetched_instruction, taken);
+        }
+        if let Some(coverage) = self.coverage.as_mut() {
+            coverage.record(self.pc);
+        }
+        self.csrs.retire(fetched_instruction, taken);
+
+        if DEBUG {
+            if let Some(rd) = rv::destination_register(fetched_instruction) {
+                self.last_updated_register = rd;
+            }
+        }
+
+        // The executed instruction might have changed the next PC
+        // from the PC + 4 value, now assign next PC to PC
+        self.pc = self.next_pc;
+
+        // Let the peripherals make progress (e.g. the UART prints the
+        // character the program has just written, the CLINT timer ticks,
+        // the PLIC updates the external interrupt lines)
+        // and take an interrupt, if any, before the next instruction
+        self.bus.cycle();
+        self.update_interrupt_lines();
+        if let Some(interrupt) = self.csrs.pending_interrupt() {
+            self.take_interrupt(interrupt);
+        }
+        Ok(())
+    }
+
+    // Bring the time CSR and mip up to date with the bus after it has cycled
+    #[inline(always)]
+    fn update_interrupt_lines(&mut self) {
+        if self.timebase.get_source() == TimeSource::Mtime {
+            self.csrs.set_time(self.timebase.from_mtime(self.bus.get_clint().get_mtime()));
+        }
+        match self.sbi {
+            None => self.csrs.set_interrupt_pending(CsrFile::DEVICE_INTERRUPTS, self.bus.interrupt_lines()),
+            Some(_) => self.csrs.set_interrupt_pending(Sbi::DEVICE_INTERRUPTS, Sbi::route_interrupts(self.bus.interrupt_lines()))
+        }
+    }
+
+    /// Stall the hart (WFI) until an interrupt is pending and enabled in
+    /// mie, even if it is globally disabled. Instead of executing
+    /// instructions the bus is cycled up to the next timer event, at most
+    /// for a slice of WFI_SLICE cycles, and the host thread sleeps for the
+    /// nominal time of the cycles that have passed. Returns false if the
+    /// slice ended without an interrupt: WFI is then executed again, so
+    /// that the loops can still stop a hart that nothing wakes up
+    pub fn wait_for_interrupt(&mut self) -> bool {
+        if self.csrs.interrupt_waiting() {
+            return true;
+        }
+        let slice: u64 = self.bus.get_clint().cycles_until_timer().map_or(Cpu::WFI_SLICE, |cycles| cycles.min(Cpu::WFI_SLICE));
+        let mut waited: u64 = 0;
+        let mut woken: bool = false;
+        while waited < slice && !woken {
+            self.bus.cycle();
+            self.update_interrupt_lines();
+            waited += 1;
+            woken = self.csrs.interrupt_waiting();
+        }
+        self.accounting.record_idle(waited);
+        // The host thread cannot sleep in a web page
+        if self.idle_sleep && cfg!(not(target_arch = "wasm32")) {
+            let nanos: u64 = waited * 1_000_000_000 / Clint::TIMEBASE_FREQUENCY;
+            std::thread::sleep(std::time::Duration::from_nanos(nanos));
+        }
+        woken
+    }
+
+    /// Tell the stack guard, the profile (and the call stack in debug mode)
+    /// about calls (JAL and JALR that link in ra) and returns (JALR to ra
+    /// without linking). The next PC is the target
+    #[inline(never)]
+    fn track_stack_frames<const DEBUG: bool>(&mut self, instr: Instruction) {
+        let opcode: Instruction = instr & 0x7f;
+        let rd: RegIndex = ((instr >> 7) & 0x1f) as RegIndex;
+        let rs1: RegIndex = ((instr >> 15) & 0x1f) as RegIndex;
+        let sp: u64 = self.regs[Cpu::STACK_POINTER as usize];
+        match (opcode, rd, rs1) {
+            (0x6f, Cpu::RETURN_REGISTER, _) |
+            (0x67, Cpu::RETURN_REGISTER, _) => {
+                if let Some(guard) = self.stack_guard.as_mut() {
+                    guard.call(self.pc, self.next_pc, self.pc + 4, sp);
+                }
+                if let Some(profile) = self.profile.as_mut() {
+                    profile.record_call(self.pc, self.next_pc, self.pc + 4);
+                }
+                if DEBUG || self.stack_guard_region.is_some() {
+                    self.call_stack.call(self.pc, self.next_pc, self.pc + 4);
+                }
+            },
+            (0x67, Cpu::ZERO_REGISTER, Cpu::RETURN_REGISTER) => {
+                if let Some(guard) = self.stack_guard.as_mut() {
+                    guard.ret(self.pc, self.next_pc);
+                }
+                if let Some(profile) = self.profile.as_mut() {
+                    profile.record_return(self.next_pc);
+                }
+                if DEBUG || self.stack_guard_region.is_some() {
+                    self.call_stack.ret(self.next_pc);
+                }
+            },
+            _ => ()
+        }
+    }
+
+    /// Serve the system call requested by an ECALL. When the program exits,
+    /// the next PC is set to the sentinel address so that the CPU loops
+    /// stop without checking anything else on every instruction
+    #[cold]
+    fn emulate_syscall(&mut self) {
+        // The emulator is taken out of the CPU so that it can modify the CPU state
+        let mut syscalls: SyscallEmulator = match self.syscalls.take() {
+            Some(syscalls) => syscalls,
+            None => return
+        };
+        match syscalls.handle(self) {
+            SyscallResult::Return(value) => self.write_reg(10, value),
+            SyscallResult::Exit(code) => {
+                self.exit_code = Some(code);
+                self.next_pc = Cpu::SENTINEL_RETURN_ADDRESS;
+            }
+        }
+        self.syscalls = Some(syscalls);
+    }
+
+    /// Serve the semihosting call requested by an EBREAK. As for the
+    /// system calls, an exit stops the CPU loops through the sentinel address
+    #[cold]
+    fn emulate_semihosting(&mut self) {
+        let mut semihosting: Semihosting = match self.semihosting.take() {
+            Some(semihosting) => semihosting,
+            None => return
+        };
+        match semihosting.handle(self) {
+            SyscallResult::Return(value) => self.write_reg(10, value),
+            SyscallResult::Exit(code) => {
+                self.exit_code = Some(code);
+                self.next_pc = Cpu::SENTINEL_RETURN_ADDRESS;
+            }
+        }
+        self.semihosting = Some(semihosting);
+    }
+
+    /// Serve the SBI call requested by an ECALL from S-mode. A shutdown
+    /// stops the CPU loops like the exit system call
+    #[cold]
+    fn emulate_sbi_call(&mut self) {
+        let mut sbi: Sbi = match self.sbi.take() {
+            Some(sbi) => sbi,
+            None => return
+        };
+        match sbi.handle(self) {
+            SbiResult::Return { error, value } => {
+                self.write_reg(Cpu::ARGUMENT_REGISTER, error as u64);
+                self.write_reg(Cpu::SECOND_ARGUMENT_REGISTER, value);
+            },
+            SbiResult::Legacy(value) => self.write_reg(Cpu::ARGUMENT_REGISTER, value),
+            SbiResult::Shutdown(code) => {
+                self.exit_code = Some(code);
+                self.next_pc = Cpu::SENTINEL_RETURN_ADDRESS;
+            }
+        }
+        self.sbi = Some(sbi);
+    }
+
+    /// The program wrote tohost: serve its command. When the program exits
+    /// (e.g. a test that is over), the next PC is set to the sentinel address
+    /// to stop the CPU loops
+    #[cold]
+    fn write_tohost(&mut self, addr: u64) {
+        let exit_code: Option<u64> = match self.htif.as_mut() {
+            Some(htif) => htif.store(addr, self.xlen, &mut self.bus),
+            None => None
+        };
+        if exit_code.is_some() {
+            self.exit_code = exit_code;
+            self.next_pc = Cpu::SENTINEL_RETURN_ADDRESS;
+        }
+    }
+
+    /// The program wrote the test finisher: end the run if it asked to,
+    /// as the exit through tohost does
+    #[cold]
+    fn write_test_finisher(&mut self, addr: u64, data: u64) {
+        let exit_code: Option<u64> = self.test_finisher.as_ref().and_then(|finisher| finisher.store(addr, data));
+        if exit_code.is_some() {
+            self.exit_code = exit_code;
+            self.next_pc = Cpu::SENTINEL_RETURN_ADDRESS;
+        }
+    }
+
+    /// Take a trap: save the faulting PC in xepc, the cause in xcause and
+    /// the trap value in xtval, disable interrupts and jump to the trap vector
+    /// in xtvec, where x is M or S depending on the trap delegation.
+    /// Since there is no firmware behind the emulator, a program
+    /// that did not install a trap handler (xtvec = 0) is stopped instead:
+    /// in that case false is returned and the trap is recorded as the
+    /// reason of the stop. Instruction fetches outside of the mapped
+    /// memory also stop the program unless the fetch fault action is Trap,
+    /// and so do the accesses to the stack guard region
+    fn take_trap(&mut self, trap: Trap) -> bool {
+        self.trap_statistics.record(&trap, self.pc);
+        self.csrs.count_trap();
+        if self.tracer.is_enabled() {
+            self.tracer.emit(TraceKind::Trap, self.pc, self.tracer.get_symbols().describe_trap(&trap));
+        }
+        let target: Privilege = self.csrs.trap_target(trap.cause(), false);
+        let stop_on_fetch: bool = self.fetch_fault != FetchFaultAction::Trap
+            && matches!(trap, Trap::InstructionAccessFault(_));
+        if self.csrs.get_trap_vector(target) == 0 || stop_on_fetch || self.stack_overflow.is_some() {
+            self.unhandled_trap = Some(trap);
+            return false;
+        }
+        self.pc = self.enter_trap(trap.cause(), trap.tval(), false);
+        true
+    }
+
+    /// Take an interrupt before executing the instruction at the current PC,
+    /// which is saved in xepc. Interrupts are ignored if the program did
+    /// not install a handler or if it is already over
+    #[cold]
+    fn take_interrupt(&mut self, interrupt: Interrupt) {
+        let target: Privilege = self.csrs.trap_target(interrupt.cause(), true);
+        if self.csrs.get_trap_vector(target) == 0 || self.pc == Cpu::SENTINEL_RETURN_ADDRESS {
+            return;
+        }
+        self.trap_statistics.record_interrupt(&interrupt, self.pc);
+        if self.tracer.is_enabled() {
+            self.tracer.emit(TraceKind::Trap, self.pc, interrupt.to_string());
+        }
+        self.pc = self.enter_trap(interrupt.cause(), 0, true);
+    }
+
+    // Update the CSRs to enter a trap handler and return its address.
+    // The CSRs changed by the trap are traced if CSR tracing is enabled
+    fn enter_trap(&mut self, cause: u64, tval: u64, interrupt: bool) -> u64 {
+        // Keep the old values around to log what the trap changed
+        let traced_csrs: [CSRegIndex; 7] = [CsrFile::MSTATUS, CsrFile::MEPC, CsrFile::MCAUSE, CsrFile::MTVAL,
+                                            CsrFile::SEPC, CsrFile::SCAUSE, CsrFile::STVAL];
+        let old_values: Vec<u64> = if self.trace_csr {
+            traced_csrs.iter().map(|csr| self.csrs.read(*csr).unwrap_or(0)).collect()
+        } else {
+            Vec::new()
+        };
+
+        let trap_vector: u64 = self.csrs.enter_trap(self.pc, cause, tval, interrupt);
+        self.accounting.enter_trap(self.csrs.get_privilege());
+
+        for (csr, old_data) in traced_csrs.iter().zip(old_values) {
+            if self.csrs.read(*csr) != Some(old_data) {
+                self.trace_csr_write(*csr, old_data, if interrupt { "irq" } else { "trap" });
+            }
+        }
+        trap_vector
+    }
+
     // Fetch function to read the next instruction to be executed
-    fn fetch(&self) -> Instruction {
-        self.bus.read(self.pc, AccessSize::WORD) as Instruction
+    fn fetch(&self) -> Result<Instruction, Trap> {
+        if self.pc & 0x3 != 0 {
+            return Err(Trap::InstructionAddressMisaligned(self.pc));
+        }
+        match self.bus.peek(self.pc, AccessSize::WORD) {
+            Ok(instr) => Ok(instr as Instruction),
+            Err(_) => Err(Trap::InstructionAccessFault(self.pc))
+        }
+    }
+
+    // Fetch the instruction at PC and find the function that executes it.
+    // Outside of debug mode decoded instructions are kept in the decode
+    // cache (debug mode needs the functions that keep track of the writes)
+    #[inline(always)]
+    fn fetch_decoded<const DEBUG: bool>(&mut self) -> Result<DecodedInstruction, Trap> {
+        // Checked before the decode cache, which ignores the PMP
+        if !self.csrs.pmp_allows(self.pc, 4, PmpAccess::Execute) {
+            return Err(Trap::InstructionAccessFault(self.pc));
+        }
+        if !DEBUG {
+            if let Some(decoded) = self.decode_cache.lookup(self.pc) {
+                return Ok(*decoded);
+            }
+        }
+        let instr: Instruction = self.fetch()?;
+        let decoded: DecodedInstruction = DecodedInstruction {
+            instr,
+            handler: rv::predecode::<DEBUG>(instr, self.xlen).unwrap_or(|_, _| Err(Trap::IllegalInstruction(0))),
+            operands: rv::Operands::new(instr)
+        };
+        if !DEBUG {
+            self.decode_cache.insert(self.pc, decoded);
+        }
+        Ok(decoded)
+    }
+
+    /// Forget the decoded instructions (FENCE.I)
+    pub fn flush_decode_cache(&mut self) {
+        self.decode_cache.flush();
     }
 
-    // Call the decoder to decode the instruction. The decoder will call
-    // the function that handles the execution of the decoded instruction
-    fn decode_and_execute(&mut self, instr: Instruction) {
-        rv::decode(instr, self);
+}
+// The state of the debugging aids (statistics, stack guard, traces) is not
+// part of the snapshot, only the state of the machine is
+impl Snapshot for Cpu {
+    fn save(&self, writer: &mut SnapshotWriter) {
+        for reg in self.regs.iter() {
+            writer.put_u64(*reg);
+        }
+        writer.put_u64(self.pc);
+        self.csrs.save(writer);
+        self.fpu.save(writer);
+        self.bus.save(writer);
+        match self.syscalls.as_ref() {
+            Some(syscalls) => { writer.put_u8(1); syscalls.save(writer) },
+            None => writer.put_u8(0)
+        }
+        match self.exit_code {
+            Some(exit_code) => { writer.put_u8(1); writer.put_u64(exit_code) },
+            None => writer.put_u8(0)
+        }
     }
 
+    fn restore(&mut self, reader: &mut SnapshotReader) -> Result<(), String> {
+        for reg in self.regs.iter_mut() {
+            *reg = reader.get_u64()?;
+        }
+        self.pc = reader.get_u64()?;
+        self.csrs.restore(reader)?;
+        self.fpu.restore(reader)?;
+        self.bus.restore(reader)?;
+        self.decode_cache.flush();
+        if reader.get_u8()? != 0 {
+            match self.syscalls.as_mut() {
+                Some(syscalls) => syscalls.restore(reader)?,
+                None => return Err("The snapshot was taken with system call emulation enabled".to_string())
+            }
+        }
+        self.exit_code = match reader.get_u8()? {
+            0 => None,
+            _ => Some(reader.get_u64()?)
+        };
+        self.unhandled_trap = None;
+        self.stack_overflow = None;
+        Ok(())
+    }
 }
+
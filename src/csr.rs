@@ -0,0 +1,913 @@
+use std::fmt;
+use crate::cpu::{CSRegIndex, Instruction, Xlen};
+use crate::counters::Counters;
+use crate::pmp::{Pmp, PmpAccess};
+use crate::trap::Interrupt;
+use crate::snapshot::{Snapshot, SnapshotReader, SnapshotWriter};
+
+/// Privilege levels the hart can execute in. The value of each level
+/// is its encoding in mstatus.MPP and in bits [9:8] of CSR addresses
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub enum Privilege {
+    User = 0,
+    Supervisor = 1,
+    Machine = 3
+}
+
+impl fmt::Display for Privilege {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Privilege::User       => write!(f, "U"),
+            Privilege::Supervisor => write!(f, "S"),
+            Privilege::Machine    => write!(f, "M")
+        }
+    }
+}
+
+/// Machine, Supervisor and User Control and Status Registers.
+/// Every CSR is stored in its own field so that reads and writes can apply
+/// the behavior mandated by the privileged specification (read-only fields,
+/// write masks, hardwired bits) instead of acting as plain memory.
+/// sstatus, sie and sip are restricted views of mstatus, mie and mip.
+/// The current privilege level is kept here too, since it only changes
+/// when a trap is taken or a trap handler returns
+#[derive(Clone)]
+pub struct CsrFile {
+    privilege: Privilege,
+    mstatus: u64,
+    medeleg: u64,
+    mideleg: u64,
+    mie: u64,
+    mip: u64,
+    mtvec: u64,
+    mscratch: u64,
+    mepc: u64,
+    mcause: u64,
+    mtval: u64,
+    stvec: u64,
+    sscratch: u64,
+    sepc: u64,
+    scause: u64,
+    stval: u64,
+    satp: u64,
+    mcounteren: u64,
+    scounteren: u64,
+    // Accrued exception flags and dynamic rounding mode of the F and D extensions
+    fflags: u64,
+    frm: u64,
+    // Value of mtime, read through the time CSR
+    time: u64,
+    counters: Counters,
+    pmp: Pmp,
+    // Register width, reported in misa
+    xlen: Xlen
+}
+
+impl Default for CsrFile {
+    fn default() -> CsrFile {
+        CsrFile::new()
+    }
+}
+
+impl CsrFile {
+    // Floating-point CSRs: fcsr holds frm in bits [7:5] and fflags in [4:0]
+    pub const FFLAGS:   CSRegIndex = 0x001;
+    pub const FRM:      CSRegIndex = 0x002;
+    pub const FCSR:     CSRegIndex = 0x003;
+    // Supervisor trap setup
+    pub const SSTATUS:  CSRegIndex = 0x100;
+    pub const SIE:      CSRegIndex = 0x104;
+    pub const STVEC:    CSRegIndex = 0x105;
+    pub const SCOUNTEREN: CSRegIndex = 0x106;
+    // Supervisor trap handling
+    pub const SSCRATCH: CSRegIndex = 0x140;
+    pub const SEPC:     CSRegIndex = 0x141;
+    pub const SCAUSE:   CSRegIndex = 0x142;
+    pub const STVAL:    CSRegIndex = 0x143;
+    pub const SIP:      CSRegIndex = 0x144;
+    // Supervisor protection and translation
+    pub const SATP:     CSRegIndex = 0x180;
+    // Machine information registers
+    pub const MVENDORID: CSRegIndex = 0xf11;
+    pub const MARCHID:  CSRegIndex = 0xf12;
+    pub const MIMPID:   CSRegIndex = 0xf13;
+    pub const MHARTID:  CSRegIndex = 0xf14;
+    // Machine trap setup
+    pub const MSTATUS:  CSRegIndex = 0x300;
+    pub const MISA:     CSRegIndex = 0x301;
+    pub const MEDELEG:  CSRegIndex = 0x302;
+    pub const MIDELEG:  CSRegIndex = 0x303;
+    pub const MIE:      CSRegIndex = 0x304;
+    pub const MTVEC:    CSRegIndex = 0x305;
+    pub const MCOUNTEREN: CSRegIndex = 0x306;
+    // Machine counter setup
+    pub const MCOUNTINHIBIT: CSRegIndex = 0x320;
+    pub const MHPMEVENT3: CSRegIndex = 0x323;
+    pub const MHPMEVENT31: CSRegIndex = 0x33f;
+    // Machine trap handling
+    pub const PMPCFG0:  CSRegIndex = 0x3a0;
+    pub const PMPCFG3:  CSRegIndex = 0x3a3;
+    pub const PMPADDR0: CSRegIndex = 0x3b0;
+    pub const PMPADDR15: CSRegIndex = 0x3bf;
+
+    pub const MSCRATCH: CSRegIndex = 0x340;
+    pub const MEPC:     CSRegIndex = 0x341;
+    pub const MCAUSE:   CSRegIndex = 0x342;
+    pub const MTVAL:    CSRegIndex = 0x343;
+    pub const MIP:      CSRegIndex = 0x344;
+    // Machine counters
+    pub const MCYCLE:   CSRegIndex = 0xb00;
+    pub const MINSTRET: CSRegIndex = 0xb02;
+    pub const MHPMCOUNTER31: CSRegIndex = 0xb1f;
+    // Unprivileged counters, read-only views of the machine counters and of mtime
+    pub const CYCLE:    CSRegIndex = 0xc00;
+    pub const TIME:     CSRegIndex = 0xc01;
+    pub const INSTRET:  CSRegIndex = 0xc02;
+    pub const HPMCOUNTER31: CSRegIndex = 0xc1f;
+
+    // mstatus fields
+    pub const MSTATUS_SIE:  u64 = 1 << 1;
+    pub const MSTATUS_MIE:  u64 = 1 << 3;
+    pub const MSTATUS_SPIE: u64 = 1 << 5;
+    pub const MSTATUS_MPIE: u64 = 1 << 7;
+    pub const MSTATUS_SPP:  u64 = 1 << 8;
+    const MSTATUS_MPP_SHIFT: u32 = 11;
+    const MSTATUS_MPP: u64 = 0b11 << CsrFile::MSTATUS_MPP_SHIFT;
+    // State of the floating-point unit (FS): Off (the F and D instructions
+    // are illegal), Initial, Clean or Dirty. Writing a floating-point
+    // register or CSR makes it Dirty, which sets the read-only SD bit
+    const MSTATUS_FS: u64 = 0b11 << 13;
+    const MSTATUS_FS_INITIAL: u64 = 0b01 << 13;
+    // MPP is written separately, as it only accepts U, S and M
+    const MSTATUS_WRITE_MASK: u64 = CsrFile::MSTATUS_SIE | CsrFile::MSTATUS_MIE | CsrFile::MSTATUS_SPIE |
+                                    CsrFile::MSTATUS_MPIE | CsrFile::MSTATUS_SPP | CsrFile::MSTATUS_FS;
+    // Fields of mstatus that are visible through sstatus
+    const SSTATUS_MASK: u64 = CsrFile::MSTATUS_SIE | CsrFile::MSTATUS_SPIE | CsrFile::MSTATUS_SPP |
+                              CsrFile::MSTATUS_FS;
+
+    // Supervisor-level interrupt bits (software, timer and external) in mie/mip
+    pub const SSIP: u64 = 1 << 1;
+    pub const STIP: u64 = 1 << 5;
+    pub const SEIP: u64 = 1 << 9;
+    // Machine-level interrupt bits (software, timer and external) in mie/mip
+    pub const MSIP: u64 = 1 << 3;
+    pub const MTIP: u64 = 1 << 7;
+    pub const MEIP: u64 = 1 << 11;
+    const S_INTERRUPTS: u64 = CsrFile::SSIP | CsrFile::STIP | CsrFile::SEIP;
+    /// Interrupt pending bits driven by devices rather than by software.
+    /// SEIP is driven by the PLIC, so writes to it from software are lost
+    pub const DEVICE_INTERRUPTS: u64 = CsrFile::MSIP | CsrFile::MTIP | CsrFile::MEIP | CsrFile::SEIP;
+    const MIE_WRITE_MASK: u64 = CsrFile::S_INTERRUPTS | CsrFile::MSIP | CsrFile::MTIP | CsrFile::MEIP;
+    // Only the supervisor-level pending bits can be written by software
+    const MIP_WRITE_MASK: u64 = CsrFile::S_INTERRUPTS;
+    // Exceptions that can be delegated to S-mode: all the implementable
+    // ones except for environment calls from M-mode (11)
+    const MEDELEG_WRITE_MASK: u64 = 0xb3ff;
+
+    // satp MODE field, only Bare (no translation) is supported
+    const SATP_MODE_SHIFT: u32 = 60;
+
+    // misa extensions: the I extension, B (Zba, Zbb and Zbs), F and D,
+    // supervisor and user modes. MXL is added in the top two bits (1 for
+    // 32 bits, 2 for 64 bits)
+    const MISA_EXTENSIONS: u64 = (1 << 20) | (1 << 18) | (1 << 8) | (1 << 5) | (1 << 3) | (1 << 1);
+
+    /// Create the CSR file with its reset values, executing in M-mode
+    pub fn new() -> CsrFile {
+        CsrFile {
+            privilege: Privilege::Machine,
+            mstatus: CsrFile::MSTATUS_MPP | CsrFile::MSTATUS_SPP | CsrFile::MSTATUS_FS_INITIAL,
+            medeleg: 0,
+            mideleg: 0,
+            mie: 0,
+            mip: 0,
+            mtvec: 0,
+            mscratch: 0,
+            mepc: 0,
+            mcause: 0,
+            mtval: 0,
+            stvec: 0,
+            sscratch: 0,
+            sepc: 0,
+            scause: 0,
+            stval: 0,
+            satp: 0,
+            mcounteren: 0,
+            scounteren: 0,
+            fflags: 0,
+            frm: 0,
+            time: 0,
+            counters: Counters::new(),
+            pmp: Pmp::new(),
+            xlen: Xlen::Rv64
+        }
+    }
+
+    /// Set the register width reported in misa
+    pub fn set_xlen(&mut self, xlen: Xlen) {
+        self.xlen = xlen;
+    }
+
+    fn misa(&self) -> u64 {
+        match self.xlen {
+            Xlen::Rv32 => (0b01 << 30) | CsrFile::MISA_EXTENSIONS,
+            Xlen::Rv64 => (0b10 << 62) | CsrFile::MISA_EXTENSIONS
+        }
+    }
+
+    // mstatus as it is read: SD (the top bit) summarizes a Dirty FS
+    fn status(&self) -> u64 {
+        if self.mstatus & CsrFile::MSTATUS_FS != CsrFile::MSTATUS_FS {
+            return self.mstatus;
+        }
+        match self.xlen {
+            Xlen::Rv32 => self.mstatus | (1 << 31),
+            Xlen::Rv64 => self.mstatus | (1 << 63)
+        }
+    }
+
+    /// Check if the F and D instructions are enabled (mstatus.FS is not Off)
+    #[inline(always)]
+    pub fn float_enabled(&self) -> bool {
+        self.mstatus & CsrFile::MSTATUS_FS != 0
+    }
+
+    /// Mark the floating-point state as modified (mstatus.FS Dirty)
+    #[inline(always)]
+    pub fn set_float_dirty(&mut self) {
+        self.mstatus |= CsrFile::MSTATUS_FS;
+    }
+
+    /// Dynamic rounding mode (frm)
+    #[inline(always)]
+    pub fn get_frm(&self) -> u64 {
+        self.frm
+    }
+
+    /// Accrue the exception flags raised by a floating-point instruction
+    #[inline(always)]
+    pub fn accrue_fflags(&mut self, flags: u8) {
+        if flags != 0 {
+            self.fflags |= flags as u64;
+            self.set_float_dirty();
+        }
+    }
+
+    /// Current privilege level of the hart
+    pub fn get_privilege(&self) -> Privilege {
+        self.privilege
+    }
+
+    /// Change the privilege level outside of the traps (e.g. to start a
+    /// kernel in S-mode when the firmware is emulated)
+    pub fn set_privilege(&mut self, privilege: Privilege) {
+        self.privilege = privilege;
+    }
+
+    /// Check if a CSR can be accessed at the current privilege level:
+    /// bits [9:8] of the CSR address encode the lowest privilege level
+    /// that is allowed to access it. Below M-mode, the unprivileged
+    /// counters can only be read if they are enabled in mcounteren, and
+    /// in U-mode in scounteren as well.
+    /// The floating-point CSRs are not accessible when mstatus.FS is Off
+    pub fn is_accessible(&self, csr: CSRegIndex) -> bool {
+        if (CsrFile::FFLAGS..=CsrFile::FCSR).contains(&csr) {
+            return self.float_enabled();
+        }
+        if (CsrFile::CYCLE..=CsrFile::HPMCOUNTER31).contains(&csr) && self.privilege < Privilege::Machine {
+            let enabled: u64 = match self.privilege {
+                Privilege::User => self.mcounteren & self.scounteren,
+                _ => self.mcounteren
+            };
+            return enabled & (1 << (csr & 0x1f)) != 0;
+        }
+        (self.privilege as u16) >= ((csr >> 8) & 0x3)
+    }
+
+    /// Check if a CSR is a counter (cycle, time, instret, hpmcounters and
+    /// their machine versions), whose value changes by itself at every instruction
+    pub fn is_counter(csr: CSRegIndex) -> bool {
+        (CsrFile::MCYCLE..=CsrFile::MHPMCOUNTER31).contains(&csr) || (CsrFile::CYCLE..=CsrFile::HPMCOUNTER31).contains(&csr)
+    }
+
+    /// Check if a CSR is read-only: bits [11:10] of the CSR address are
+    /// both set. Writing it is an illegal instruction
+    pub fn is_read_only(csr: CSRegIndex) -> bool {
+        (csr >> 10) & 0x3 == 0x3
+    }
+
+    /// Read a CSR. It returns None if the CSR does not exist.
+    /// Privilege checks are left to the caller (see is_accessible)
+    pub fn read(&self, csr: CSRegIndex) -> Option<u64> {
+        match csr {
+            CsrFile::FFLAGS   => Some(self.fflags),
+            CsrFile::FRM      => Some(self.frm),
+            CsrFile::FCSR     => Some((self.frm << 5) | self.fflags),
+            CsrFile::SSTATUS  => {
+                let sd: u64 = self.status() & !self.mstatus;
+                Some((self.mstatus & CsrFile::SSTATUS_MASK) | sd)
+            },
+            CsrFile::SIE      => Some(self.mie & self.mideleg),
+            CsrFile::STVEC    => Some(self.stvec),
+            CsrFile::SSCRATCH => Some(self.sscratch),
+            CsrFile::SEPC     => Some(self.sepc),
+            CsrFile::SCAUSE   => Some(self.scause),
+            CsrFile::STVAL    => Some(self.stval),
+            CsrFile::SIP      => Some(self.mip & self.mideleg),
+            CsrFile::SATP     => Some(self.satp),
+            // Not implemented information registers read as zero
+            CsrFile::MVENDORID | CsrFile::MARCHID | CsrFile::MIMPID => Some(0),
+            CsrFile::MHARTID  => Some(0),
+            CsrFile::MSTATUS  => Some(self.status()),
+            CsrFile::MISA     => Some(self.misa()),
+            CsrFile::MEDELEG  => Some(self.medeleg),
+            CsrFile::MIDELEG  => Some(self.mideleg),
+            CsrFile::MIE      => Some(self.mie),
+            CsrFile::MTVEC    => Some(self.mtvec),
+            CsrFile::MSCRATCH => Some(self.mscratch),
+            CsrFile::MEPC     => Some(self.mepc),
+            CsrFile::MCAUSE   => Some(self.mcause),
+            CsrFile::MTVAL    => Some(self.mtval),
+            CsrFile::MIP      => Some(self.mip),
+            CsrFile::MCOUNTEREN => Some(self.mcounteren),
+            CsrFile::SCOUNTEREN => Some(self.scounteren),
+            CsrFile::MCOUNTINHIBIT => Some(self.counters.read_inhibit()),
+            CsrFile::MHPMEVENT3..=CsrFile::MHPMEVENT31 => self.counters.read_event((csr & 0x1f) as usize),
+            CsrFile::PMPCFG0..=CsrFile::PMPCFG3 => self.pmp.read_cfg((csr - CsrFile::PMPCFG0) as usize, self.xlen),
+            CsrFile::PMPADDR0..=CsrFile::PMPADDR15 => Some(self.pmp.read_addr((csr - CsrFile::PMPADDR0) as usize)),
+            CsrFile::TIME     => Some(self.time),
+            CsrFile::MCYCLE..=CsrFile::MHPMCOUNTER31 |
+            CsrFile::CYCLE..=CsrFile::HPMCOUNTER31 => self.counters.read_counter((csr & 0x1f) as usize),
+            _ => None
+        }
+    }
+
+    /// Write a CSR, only the writable bits are affected (WARL fields keep
+    /// a legal value). It returns None if the CSR does not exist or it is
+    /// read-only. Privilege checks are left to the caller (see is_accessible)
+    pub fn write(&mut self, csr: CSRegIndex, value: u64) -> Option<()> {
+        if CsrFile::is_read_only(csr) {
+            return None;
+        }
+        match csr {
+            CsrFile::FFLAGS   => {
+                self.fflags = value & 0x1f;
+                self.set_float_dirty();
+            },
+            CsrFile::FRM      => {
+                self.frm = value & 0x7;
+                self.set_float_dirty();
+            },
+            CsrFile::FCSR     => {
+                self.fflags = value & 0x1f;
+                self.frm = (value >> 5) & 0x7;
+                self.set_float_dirty();
+            },
+            CsrFile::SSTATUS  => {
+                let writable: u64 = CsrFile::SSTATUS_MASK & CsrFile::MSTATUS_WRITE_MASK;
+                self.mstatus = (self.mstatus & !writable) | (value & writable);
+            },
+            CsrFile::SIE      => {
+                let writable: u64 = self.mideleg & CsrFile::S_INTERRUPTS;
+                self.mie = (self.mie & !writable) | (value & writable);
+            },
+            CsrFile::STVEC    => self.stvec = CsrFile::legalize_tvec(self.stvec, value),
+            CsrFile::SSCRATCH => self.sscratch = value,
+            CsrFile::SEPC     => self.sepc = value & !0x3,
+            CsrFile::SCAUSE   => self.scause = value,
+            CsrFile::STVAL    => self.stval = value,
+            // Only the software interrupt can be made pending from S-mode
+            CsrFile::SIP      => {
+                let writable: u64 = self.mideleg & CsrFile::SSIP;
+                self.mip = (self.mip & !writable) | (value & writable);
+            },
+            // Writes selecting an unsupported translation mode have no effect
+            CsrFile::SATP     => if value >> CsrFile::SATP_MODE_SHIFT == 0 {
+                self.satp = value;
+            },
+            // Only one combination of extensions is supported: writes are ignored
+            CsrFile::MISA     => (),
+            CsrFile::MSTATUS  => {
+                // MPP keeps its value if the reserved level 2 is written
+                let mpp: u64 = match (value & CsrFile::MSTATUS_MPP) >> CsrFile::MSTATUS_MPP_SHIFT {
+                    2 => self.mstatus & CsrFile::MSTATUS_MPP,
+                    _ => value & CsrFile::MSTATUS_MPP
+                };
+                self.mstatus = (value & CsrFile::MSTATUS_WRITE_MASK) | mpp;
+            },
+            CsrFile::MEDELEG  => self.medeleg = value & CsrFile::MEDELEG_WRITE_MASK,
+            CsrFile::MIDELEG  => self.mideleg = value & CsrFile::S_INTERRUPTS,
+            CsrFile::MIE      => self.mie = value & CsrFile::MIE_WRITE_MASK,
+            CsrFile::MTVEC    => self.mtvec = CsrFile::legalize_tvec(self.mtvec, value),
+            CsrFile::MSCRATCH => self.mscratch = value,
+            // Instructions are always 4-byte aligned
+            CsrFile::MEPC     => self.mepc = value & !0x3,
+            CsrFile::MCAUSE   => self.mcause = value,
+            CsrFile::MTVAL    => self.mtval = value,
+            // Machine-level pending bits are set by the hardware only
+            CsrFile::MIP      => self.mip = (self.mip & !CsrFile::MIP_WRITE_MASK) | (value & CsrFile::MIP_WRITE_MASK),
+            // One enable bit for each of the 32 counters
+            CsrFile::MCOUNTEREN => self.mcounteren = value & 0xffff_ffff,
+            CsrFile::SCOUNTEREN => self.scounteren = value & 0xffff_ffff,
+            CsrFile::MCOUNTINHIBIT => self.counters.write_inhibit(value),
+            CsrFile::PMPCFG0..=CsrFile::PMPCFG3 => return self.pmp.write_cfg((csr - CsrFile::PMPCFG0) as usize, value, self.xlen),
+            CsrFile::PMPADDR0..=CsrFile::PMPADDR15 => self.pmp.write_addr((csr - CsrFile::PMPADDR0) as usize, value, self.xlen),
+            CsrFile::MHPMEVENT3..=CsrFile::MHPMEVENT31 => return self.counters.write_event((csr & 0x1f) as usize, value),
+            CsrFile::MCYCLE..=CsrFile::MHPMCOUNTER31 => return self.counters.write_counter((csr & 0x1f) as usize, value),
+            _ => return None
+        };
+        Some(())
+    }
+
+    /// Check an access to memory at the current privilege level against
+    /// the PMP
+    #[inline(always)]
+    pub fn pmp_allows(&self, addr: u64, size: u64, access: PmpAccess) -> bool {
+        self.pmp.allows(addr, size, access, self.privilege)
+    }
+
+    /// Get the PMP to configure it from outside of the program (e.g. by
+    /// the emulated firmware)
+    pub fn get_pmp_mut(&mut self) -> &mut Pmp {
+        &mut self.pmp
+    }
+
+    /// Update the value read through the time CSR
+    #[inline(always)]
+    pub fn set_time(&mut self, mtime: u64) {
+        self.time = mtime;
+    }
+
+    /// Count an instruction that retired (see Counters::retire)
+    #[inline(always)]
+    pub fn retire(&mut self, instr: Instruction, taken: bool) {
+        self.counters.retire(instr, taken);
+    }
+
+    /// Count an instruction that raised an exception
+    pub fn count_trap(&mut self) {
+        self.counters.trap();
+    }
+
+    /// Set the interrupt pending bits selected by mask to the values in
+    /// lines. It is used by devices that drive interrupt lines (e.g. MTIP
+    /// by the CLINT) and unlike write, it can change the bits of mip
+    /// that are read-only for software
+    #[inline(always)]
+    pub fn set_interrupt_pending(&mut self, mask: u64, lines: u64) {
+        self.mip = (self.mip & !mask) | (lines & mask);
+    }
+
+    /// Check if an interrupt is pending and enabled in mie, whether it is
+    /// globally enabled or not: it resumes a hart stalled by WFI
+    #[inline(always)]
+    pub fn interrupt_waiting(&self) -> bool {
+        self.mip & self.mie != 0
+    }
+
+    /// Highest priority interrupt that is pending, enabled in mie and
+    /// globally enabled for the privilege level that handles it.
+    /// Interrupts handled by a more privileged level are always enabled,
+    /// the ones handled by a less privileged level never are
+    #[inline(always)]
+    pub fn pending_interrupt(&self) -> Option<Interrupt> {
+        let pending: u64 = self.mip & self.mie;
+        if pending == 0 {
+            return None;
+        }
+        let m_enabled: bool = self.privilege < Privilege::Machine || self.mstatus & CsrFile::MSTATUS_MIE != 0;
+        let s_enabled: bool = self.privilege < Privilege::Supervisor ||
+            (self.privilege == Privilege::Supervisor && self.mstatus & CsrFile::MSTATUS_SIE != 0);
+        let mut enabled: u64 = 0;
+        if m_enabled {
+            enabled |= pending & !self.mideleg;
+        }
+        if s_enabled {
+            enabled |= pending & self.mideleg;
+        }
+        Interrupt::PRIORITY.into_iter().find(|interrupt| (enabled >> interrupt.cause()) & 0x1 == 0x1)
+    }
+
+    /// Privilege level that handles a trap: traps raised below M-mode go
+    /// to S-mode if they are delegated in medeleg (exceptions) or
+    /// mideleg (interrupts)
+    pub fn trap_target(&self, cause: u64, interrupt: bool) -> Privilege {
+        let delegation: u64 = if interrupt { self.mideleg } else { self.medeleg };
+        if self.privilege < Privilege::Machine && (delegation >> cause) & 0x1 == 0x1 {
+            Privilege::Supervisor
+        } else {
+            Privilege::Machine
+        }
+    }
+
+    /// Update the CSRs when a trap is taken and return the address of
+    /// the trap handler. Interrupts in vectored mode jump to BASE + 4 * cause
+    pub fn enter_trap(&mut self, pc: u64, cause: u64, tval: u64, interrupt: bool) -> u64 {
+        let target: Privilege = self.trap_target(cause, interrupt);
+        let xcause: u64 = if interrupt { (1 << 63) | cause } else { cause };
+        let tvec: u64 = match target {
+            Privilege::Supervisor => {
+                self.sepc = pc;
+                self.scause = xcause;
+                self.stval = tval;
+                // Save the interrupt enable bit in SPIE, disable interrupts
+                // and remember in SPP if the trap came from U or S
+                let sie: u64 = (self.mstatus & CsrFile::MSTATUS_SIE) << 4;
+                let spp: u64 = if self.privilege == Privilege::User { 0 } else { CsrFile::MSTATUS_SPP };
+                self.mstatus = (self.mstatus & !(CsrFile::MSTATUS_SIE | CsrFile::MSTATUS_SPIE | CsrFile::MSTATUS_SPP)) | sie | spp;
+                self.stvec
+            },
+            // Traps are never taken to U-mode
+            _ => {
+                self.mepc = pc;
+                self.mcause = xcause;
+                self.mtval = tval;
+                // Save the interrupt enable bit in MPIE, disable interrupts
+                // and remember the privilege level the trap came from in MPP
+                let mie: u64 = (self.mstatus & CsrFile::MSTATUS_MIE) << 4;
+                self.mstatus = (self.mstatus & !(CsrFile::MSTATUS_MIE | CsrFile::MSTATUS_MPIE | CsrFile::MSTATUS_MPP)) | mie;
+                self.mstatus |= (self.privilege as u64) << CsrFile::MSTATUS_MPP_SHIFT;
+                self.mtvec
+            }
+        };
+        self.privilege = target;
+
+        let base: u64 = tvec & !0x3;
+        if interrupt && tvec & 0x3 == 1 {
+            base + 4 * cause
+        } else {
+            base
+        }
+    }
+
+    /// Update the CSRs when returning from a trap handler of the given
+    /// privilege level (MRET or SRET) and return the address at which
+    /// the execution continues
+    pub fn return_from_trap(&mut self, level: Privilege) -> u64 {
+        match level {
+            Privilege::Machine => {
+                // Restore the interrupt enable bit from MPIE and set MPIE,
+                // then go back to the privilege level saved in MPP
+                let mpie: u64 = (self.mstatus & CsrFile::MSTATUS_MPIE) >> 4;
+                self.privilege = match (self.mstatus & CsrFile::MSTATUS_MPP) >> CsrFile::MSTATUS_MPP_SHIFT {
+                    0 => Privilege::User,
+                    1 => Privilege::Supervisor,
+                    _ => Privilege::Machine
+                };
+                // MPP is set to U, the least privileged mode
+                self.mstatus = (self.mstatus & !(CsrFile::MSTATUS_MIE | CsrFile::MSTATUS_MPP)) | mpie | CsrFile::MSTATUS_MPIE;
+                self.mepc
+            },
+            _ => {
+                // The same with SPIE, and SPP that is set to U
+                let spie: u64 = (self.mstatus & CsrFile::MSTATUS_SPIE) >> 4;
+                self.privilege = if self.mstatus & CsrFile::MSTATUS_SPP != 0 { Privilege::Supervisor } else { Privilege::User };
+                self.mstatus = (self.mstatus & !(CsrFile::MSTATUS_SIE | CsrFile::MSTATUS_SPP)) | spie | CsrFile::MSTATUS_SPIE;
+                self.sepc
+            }
+        }
+    }
+
+    /// Base address of the trap vector of a privilege level
+    pub fn get_trap_vector(&self, level: Privilege) -> u64 {
+        match level {
+            Privilege::Supervisor => self.stvec & !0x3,
+            _ => self.mtvec & !0x3
+        }
+    }
+
+    // Only direct (0) and vectored (1) modes exist, other
+    // values leave the mode unchanged
+    fn legalize_tvec(old: u64, value: u64) -> u64 {
+        let mode: u64 = if value & 0x3 < 2 { value & 0x3 } else { old & 0x3 };
+        (value & !0x3) | mode
+    }
+
+    /// Addresses of the implemented CSRs, except for the counters and
+    /// the events they count (see is_counter)
+    pub fn implemented() -> impl Iterator<Item = CSRegIndex> {
+        CsrFile::NAMES.iter().map(|(address, _)| *address)
+            .chain(CsrFile::PMPCFG0..=CsrFile::PMPCFG3)
+            .chain(CsrFile::PMPADDR0..=CsrFile::PMPADDR15)
+    }
+
+    /// Get the name of a CSR, or its address if the CSR is unknown
+    pub fn name(csr: CSRegIndex) -> String {
+        let index: CSRegIndex = csr & 0x1f;
+        match CsrFile::NAMES.iter().find(|(address, _)| *address == csr) {
+            Some((_, name)) => name.to_string(),
+            None => match csr {
+                CsrFile::MCYCLE   => "mcycle".to_string(),
+                CsrFile::MINSTRET => "minstret".to_string(),
+                CsrFile::CYCLE    => "cycle".to_string(),
+                CsrFile::TIME     => "time".to_string(),
+                CsrFile::INSTRET  => "instret".to_string(),
+                CsrFile::MHPMEVENT3..=CsrFile::MHPMEVENT31 => format!("mhpmevent{}", index),
+                CsrFile::PMPCFG0..=CsrFile::PMPCFG3 => format!("pmpcfg{}", csr - CsrFile::PMPCFG0),
+                CsrFile::PMPADDR0..=CsrFile::PMPADDR15 => format!("pmpaddr{}", csr - CsrFile::PMPADDR0),
+                CsrFile::MCYCLE..=CsrFile::MHPMCOUNTER31 if index >= 3 => format!("mhpmcounter{}", index),
+                CsrFile::CYCLE..=CsrFile::HPMCOUNTER31 if index >= 3 => format!("hpmcounter{}", index),
+                _ => format!("csr 0x{:03x}", csr)
+            }
+        }
+    }
+
+    /// Describe which fields of a CSR changed between two values,
+    /// e.g. "mstatus.MIE 0x0->0x1, mstatus.MPIE 0x1->0x0". The description
+    /// is empty for registers that are not split into fields
+    pub fn describe_change(csr: CSRegIndex, old: u64, new: u64) -> String {
+        let mut changes: Vec<String> = Vec::new();
+        let name: String = CsrFile::name(csr);
+        for (address, field, lsb, width) in CsrFile::FIELDS {
+            if address != csr {
+                continue;
+            }
+            let mask: u64 = if width == 64 { u64::MAX } else { (1 << width) - 1 };
+            let old_field: u64 = (old >> lsb) & mask;
+            let new_field: u64 = (new >> lsb) & mask;
+            if old_field != new_field {
+                changes.push(format!("{}.{} {:#x}->{:#x}", name, field, old_field, new_field));
+            }
+        }
+        changes.join(", ")
+    }
+
+    const NAMES: [(CSRegIndex, &'static str); 30] = [
+        (CsrFile::FFLAGS,   "fflags"),
+        (CsrFile::FRM,      "frm"),
+        (CsrFile::FCSR,     "fcsr"),
+        (CsrFile::SSTATUS,  "sstatus"),
+        (CsrFile::SIE,      "sie"),
+        (CsrFile::STVEC,    "stvec"),
+        (CsrFile::SCOUNTEREN, "scounteren"),
+        (CsrFile::SSCRATCH, "sscratch"),
+        (CsrFile::SEPC,     "sepc"),
+        (CsrFile::SCAUSE,   "scause"),
+        (CsrFile::STVAL,    "stval"),
+        (CsrFile::SIP,      "sip"),
+        (CsrFile::SATP,     "satp"),
+        (CsrFile::MVENDORID, "mvendorid"),
+        (CsrFile::MARCHID,  "marchid"),
+        (CsrFile::MIMPID,   "mimpid"),
+        (CsrFile::MHARTID,  "mhartid"),
+        (CsrFile::MSTATUS,  "mstatus"),
+        (CsrFile::MISA,     "misa"),
+        (CsrFile::MEDELEG,  "medeleg"),
+        (CsrFile::MIDELEG,  "mideleg"),
+        (CsrFile::MIE,      "mie"),
+        (CsrFile::MTVEC,    "mtvec"),
+        (CsrFile::MCOUNTEREN, "mcounteren"),
+        (CsrFile::MCOUNTINHIBIT, "mcountinhibit"),
+        (CsrFile::MSCRATCH, "mscratch"),
+        (CsrFile::MEPC,     "mepc"),
+        (CsrFile::MCAUSE,   "mcause"),
+        (CsrFile::MTVAL,    "mtval"),
+        (CsrFile::MIP,      "mip")
+    ];
+
+    // Fields of the CSRs: (CSR, field name, least significant bit, width)
+    const FIELDS: [(CSRegIndex, &'static str, u32, u32); 45] = [
+        (CsrFile::FCSR,    "fflags",    0,  5),
+        (CsrFile::FCSR,    "frm",       5,  3),
+        (CsrFile::SSTATUS, "SIE",       1,  1),
+        (CsrFile::SSTATUS, "SPIE",      5,  1),
+        (CsrFile::SSTATUS, "SPP",       8,  1),
+        (CsrFile::SSTATUS, "FS",        13, 2),
+        (CsrFile::SIE,     "SSIE",      1,  1),
+        (CsrFile::SIE,     "STIE",      5,  1),
+        (CsrFile::SIE,     "SEIE",      9,  1),
+        (CsrFile::SIP,     "SSIP",      1,  1),
+        (CsrFile::SIP,     "STIP",      5,  1),
+        (CsrFile::SIP,     "SEIP",      9,  1),
+        (CsrFile::STVEC,   "MODE",      0,  2),
+        (CsrFile::STVEC,   "BASE",      2,  62),
+        (CsrFile::SCAUSE,  "Interrupt", 63, 1),
+        (CsrFile::SCAUSE,  "Code",      0,  63),
+        (CsrFile::SATP,    "MODE",      60, 4),
+        (CsrFile::SATP,    "ASID",      44, 16),
+        (CsrFile::SATP,    "PPN",       0,  44),
+        (CsrFile::MSTATUS, "SIE",       1,  1),
+        (CsrFile::MSTATUS, "MIE",       3,  1),
+        (CsrFile::MSTATUS, "SPIE",      5,  1),
+        (CsrFile::MSTATUS, "MPIE",      7,  1),
+        (CsrFile::MSTATUS, "SPP",       8,  1),
+        (CsrFile::MSTATUS, "MPP",       11, 2),
+        (CsrFile::MSTATUS, "FS",        13, 2),
+        (CsrFile::MIE,     "SSIE",      1,  1),
+        (CsrFile::MIE,     "MSIE",      3,  1),
+        (CsrFile::MIE,     "STIE",      5,  1),
+        (CsrFile::MIE,     "MTIE",      7,  1),
+        (CsrFile::MIE,     "SEIE",      9,  1),
+        (CsrFile::MIE,     "MEIE",      11, 1),
+        (CsrFile::MIP,     "SSIP",      1,  1),
+        (CsrFile::MIP,     "MSIP",      3,  1),
+        (CsrFile::MIP,     "STIP",      5,  1),
+        (CsrFile::MIP,     "MTIP",      7,  1),
+        (CsrFile::MIP,     "SEIP",      9,  1),
+        (CsrFile::MIP,     "MEIP",      11, 1),
+        (CsrFile::MIDELEG, "SSI",       1,  1),
+        (CsrFile::MIDELEG, "STI",       5,  1),
+        (CsrFile::MIDELEG, "SEI",       9,  1),
+        (CsrFile::MTVEC,   "MODE",      0,  2),
+        (CsrFile::MTVEC,   "BASE",      2,  62),
+        (CsrFile::MCAUSE,  "Interrupt", 63, 1),
+        (CsrFile::MCAUSE,  "Code",      0,  63)
+    ];
+}
+
+impl Snapshot for CsrFile {
+    fn save(&self, writer: &mut SnapshotWriter) {
+        writer.put_u8(self.privilege as u8);
+        for csr in [self.mstatus, self.medeleg, self.mideleg, self.mie, self.mip, self.mtvec,
+                    self.mscratch, self.mepc, self.mcause, self.mtval, self.stvec, self.sscratch,
+                    self.sepc, self.scause, self.stval, self.satp, self.mcounteren, self.scounteren, self.fflags,
+                    self.frm, self.time] {
+            writer.put_u64(csr);
+        }
+        self.counters.save(writer);
+        self.pmp.save(writer);
+    }
+
+    fn restore(&mut self, reader: &mut SnapshotReader) -> Result<(), String> {
+        self.privilege = match reader.get_u8()? {
+            0 => Privilege::User,
+            1 => Privilege::Supervisor,
+            3 => Privilege::Machine,
+            privilege => return Err(format!("Invalid privilege level {} in snapshot", privilege))
+        };
+        for csr in [&mut self.mstatus, &mut self.medeleg, &mut self.mideleg, &mut self.mie, &mut self.mip,
+                    &mut self.mtvec, &mut self.mscratch, &mut self.mepc, &mut self.mcause, &mut self.mtval,
+                    &mut self.stvec, &mut self.sscratch, &mut self.sepc, &mut self.scause, &mut self.stval,
+                    &mut self.satp, &mut self.mcounteren, &mut self.scounteren, &mut self.fflags,
+                    &mut self.frm, &mut self.time] {
+            *csr = reader.get_u64()?;
+        }
+        self.counters.restore(reader)?;
+        self.pmp.restore(reader)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::csr::{CsrFile, Privilege};
+    use crate::trap::Interrupt;
+
+    #[test]
+    fn trap_and_return_test() {
+        let mut csrs: CsrFile = CsrFile::new();
+        csrs.write(CsrFile::MTVEC, 0x100).unwrap();
+        csrs.write(CsrFile::MSTATUS, CsrFile::MSTATUS_MIE).unwrap();
+        // Entering the trap saves MIE into MPIE and clears MIE
+        assert_eq!(csrs.enter_trap(0x2000, 11, 0, false), 0x100);
+        let mstatus: u64 = csrs.read(CsrFile::MSTATUS).unwrap();
+        assert_eq!(mstatus & CsrFile::MSTATUS_MIE, 0);
+        assert_eq!(mstatus & CsrFile::MSTATUS_MPIE, CsrFile::MSTATUS_MPIE);
+        assert_eq!(csrs.read(CsrFile::MCAUSE), Some(11));
+        // Returning restores MIE and continues from mepc
+        assert_eq!(csrs.return_from_trap(Privilege::Machine), 0x2000);
+        let mstatus: u64 = csrs.read(CsrFile::MSTATUS).unwrap();
+        assert_eq!(mstatus & CsrFile::MSTATUS_MIE, CsrFile::MSTATUS_MIE);
+    }
+
+    #[test]
+    fn delegation_test() {
+        let mut csrs: CsrFile = CsrFile::new();
+        csrs.write(CsrFile::MTVEC, 0x100).unwrap();
+        csrs.write(CsrFile::STVEC, 0x200).unwrap();
+        // Delegate illegal instructions (2) to S-mode, M-mode ecalls (11) cannot be delegated
+        csrs.write(CsrFile::MEDELEG, (1 << 2) | (1 << 11)).unwrap();
+        assert_eq!(csrs.read(CsrFile::MEDELEG), Some(1 << 2));
+        // Traps raised in M-mode are never delegated
+        assert_eq!(csrs.trap_target(2, false), Privilege::Machine);
+
+        // Drop to S-mode through MRET
+        csrs.write(CsrFile::MSTATUS, 0b01 << 11).unwrap();
+        csrs.return_from_trap(Privilege::Machine);
+        assert_eq!(csrs.get_privilege(), Privilege::Supervisor);
+        assert!(!csrs.is_accessible(CsrFile::MSTATUS));
+        assert!(csrs.is_accessible(CsrFile::SSTATUS));
+
+        assert_eq!(csrs.enter_trap(0x3000, 2, 0xffffffff, false), 0x200);
+        assert_eq!(csrs.get_privilege(), Privilege::Supervisor);
+        assert_eq!(csrs.read(CsrFile::SCAUSE), Some(2));
+        assert_eq!(csrs.return_from_trap(Privilege::Supervisor), 0x3000);
+        // Non-delegated traps go to M-mode and save S in MPP
+        assert_eq!(csrs.enter_trap(0x3004, 9, 0, false), 0x100);
+        assert_eq!(csrs.get_privilege(), Privilege::Machine);
+        assert_eq!((csrs.read(CsrFile::MSTATUS).unwrap() >> 11) & 0x3, 0b01);
+    }
+
+    #[test]
+    fn pending_interrupt_test() {
+        let mut csrs: CsrFile = CsrFile::new();
+        // MTIP can only be set by the CLINT
+        csrs.write(CsrFile::MIP, CsrFile::MTIP).unwrap();
+        assert_eq!(csrs.read(CsrFile::MIP), Some(0));
+        csrs.set_interrupt_pending(CsrFile::MTIP | CsrFile::MSIP, CsrFile::MTIP | CsrFile::MSIP);
+        assert_eq!(csrs.pending_interrupt(), None);
+        csrs.write(CsrFile::MIE, CsrFile::MTIP | CsrFile::MSIP).unwrap();
+        assert_eq!(csrs.pending_interrupt(), None);
+        // Globally disabled interrupts still wake up WFI
+        assert!(csrs.interrupt_waiting());
+        csrs.write(CsrFile::MSTATUS, CsrFile::MSTATUS_MIE).unwrap();
+        // Software interrupts have priority over timer interrupts
+        assert_eq!(csrs.pending_interrupt(), Some(Interrupt::MachineSoftware));
+        csrs.set_interrupt_pending(CsrFile::MSIP, 0);
+        assert_eq!(csrs.pending_interrupt(), Some(Interrupt::MachineTimer));
+
+        // Delegated interrupts are not taken in M-mode, even if SIE is set
+        csrs.set_interrupt_pending(CsrFile::MTIP, 0);
+        csrs.write(CsrFile::MIDELEG, CsrFile::STIP).unwrap();
+        csrs.write(CsrFile::MIE, CsrFile::STIP).unwrap();
+        csrs.write(CsrFile::MIP, CsrFile::STIP).unwrap();
+        csrs.write(CsrFile::MSTATUS, CsrFile::MSTATUS_SIE | (0b01 << 11)).unwrap();
+        assert_eq!(csrs.pending_interrupt(), None);
+        // In S-mode they are taken when SIE is set
+        csrs.return_from_trap(Privilege::Machine);
+        assert_eq!(csrs.get_privilege(), Privilege::Supervisor);
+        assert_eq!(csrs.pending_interrupt(), Some(Interrupt::SupervisorTimer));
+    }
+
+    #[test]
+    fn write_masks_test() {
+        let mut csrs: CsrFile = CsrFile::new();
+        let misa: Option<u64> = csrs.read(CsrFile::MISA);
+        csrs.write(CsrFile::MISA, 0).unwrap();
+        assert_eq!(csrs.read(CsrFile::MISA), misa);
+        // Read-only registers cannot be written, not even with their own value
+        assert_eq!(csrs.write(CsrFile::MHARTID, 0), None);
+        assert_eq!(csrs.read(CsrFile::MVENDORID), Some(0));
+        assert!(CsrFile::is_read_only(0xc00));
+        assert!(!CsrFile::is_read_only(CsrFile::MSTATUS));
+        // MPP only holds the implemented privilege levels
+        csrs.write(CsrFile::MSTATUS, 0b10 << 11).unwrap();
+        assert_eq!(csrs.read(CsrFile::MSTATUS).unwrap() & (0b11 << 11), 0b11 << 11);
+        csrs.write(CsrFile::MEPC, 0x1003).unwrap();
+        assert_eq!(csrs.read(CsrFile::MEPC), Some(0x1000));
+        // Vectored mode interrupts jump to BASE + 4 * cause
+        csrs.write(CsrFile::MTVEC, 0x101).unwrap();
+        assert_eq!(csrs.enter_trap(0x0, 7, 0, true), 0x11c);
+        assert_eq!(csrs.read(0x7ff), None);
+    }
+
+    #[test]
+    fn user_mode_test() {
+        let mut csrs: CsrFile = CsrFile::new();
+        // MRET with MPP = U drops to user mode and sets MPP to U
+        csrs.write(CsrFile::MEPC, 0x2000).unwrap();
+        csrs.write(CsrFile::MSTATUS, 0).unwrap();
+        assert_eq!(csrs.return_from_trap(Privilege::Machine), 0x2000);
+        assert_eq!(csrs.get_privilege(), Privilege::User);
+        assert_eq!((csrs.read(CsrFile::MSTATUS).unwrap() >> 11) & 0x3, 0);
+        // Only the unprivileged CSRs are accessible, the counters if both
+        // mcounteren and scounteren enable them
+        assert!(!csrs.is_accessible(CsrFile::SSTATUS));
+        assert!(!csrs.is_accessible(CsrFile::MSTATUS));
+        csrs.write(CsrFile::MCOUNTEREN, 0x1).unwrap();
+        assert!(!csrs.is_accessible(CsrFile::CYCLE));
+        csrs.write(CsrFile::SCOUNTEREN, 0x1).unwrap();
+        assert!(csrs.is_accessible(CsrFile::CYCLE));
+        // An ECALL from U (8) goes to M, which records U in MPP
+        csrs.write(CsrFile::MTVEC, 0x100).unwrap();
+        assert_eq!(csrs.enter_trap(0x2000, 8, 0, false), 0x100);
+        assert_eq!(csrs.get_privilege(), Privilege::Machine);
+        assert_eq!((csrs.read(CsrFile::MSTATUS).unwrap() >> 11) & 0x3, 0);
+        // Delegated to S, U is recorded in SPP and SRET goes back to U
+        csrs.set_privilege(Privilege::User);
+        csrs.write(CsrFile::MEDELEG, 1 << 8).unwrap();
+        csrs.write(CsrFile::STVEC, 0x200).unwrap();
+        csrs.write(CsrFile::SEPC, 0x3000).unwrap();
+        assert_eq!(csrs.enter_trap(0x3000, 8, 0, false), 0x200);
+        assert_eq!(csrs.get_privilege(), Privilege::Supervisor);
+        assert_eq!(csrs.read(CsrFile::SSTATUS).unwrap() & CsrFile::MSTATUS_SPP, 0);
+        assert_eq!(csrs.return_from_trap(Privilege::Supervisor), 0x3000);
+        assert_eq!(csrs.get_privilege(), Privilege::User);
+    }
+
+    #[test]
+    fn float_state_test() {
+        let mut csrs: CsrFile = CsrFile::new();
+        assert!(csrs.float_enabled());
+        assert_eq!(csrs.read(CsrFile::MSTATUS).unwrap() >> 63, 0);
+        // fcsr is frm and fflags, and writing it makes FS Dirty (and SD set)
+        csrs.write(CsrFile::FCSR, 0xff).unwrap();
+        assert_eq!(csrs.read(CsrFile::FRM), Some(0x7));
+        assert_eq!(csrs.read(CsrFile::FFLAGS), Some(0x1f));
+        assert_eq!(csrs.read(CsrFile::MSTATUS).unwrap() >> 63, 1);
+        assert_eq!(csrs.read(CsrFile::SSTATUS).unwrap() >> 13, (1 << 50) | 0b11);
+        // With FS Off the floating-point CSRs cannot be accessed
+        csrs.write(CsrFile::MSTATUS, 0).unwrap();
+        assert!(!csrs.float_enabled());
+        assert!(!csrs.is_accessible(CsrFile::FCSR));
+        assert_eq!(csrs.read(CsrFile::MSTATUS).unwrap() >> 63, 0);
+    }
+
+    #[test]
+    fn describe_change_test() {
+        assert_eq!(CsrFile::describe_change(CsrFile::MSTATUS, 0x1800, 0x1808), "mstatus.MIE 0x0->0x1");
+        assert_eq!(CsrFile::describe_change(CsrFile::MSCRATCH, 0x0, 0x10), "");
+        assert_eq!(CsrFile::describe_change(CsrFile::MIE, 0x8, 0x8), "");
+        assert_eq!(CsrFile::name(0x7c0), "csr 0x7c0");
+    }
+}
@@ -0,0 +1,49 @@
+use crate::memory::AccessSize;
+
+/// A memory-mapped peripheral attached to the bus (see Bus::attach and
+/// Bus::add_device): it can come from a crate that embeds the emulator or
+/// from a plugin library (see plugin.rs), and the built-in UART, CLINT and
+/// PLIC implement it as well. The device gets the offset of each access
+/// inside its register window, the bus takes care of the base address
+pub trait Device: Send {
+    /// Name of the device, used in messages
+    fn name(&self) -> &str;
+
+    /// Size of the register window in bytes
+    fn size(&self) -> u64;
+
+    /// Read a register. None is reported to the program as an access fault
+    fn read(&mut self, offset: u64, size: AccessSize) -> Option<u64>;
+
+    /// Same as read, but without side effects. It is what the debugger
+    /// uses, devices that cannot be read this way return None
+    fn peek(&self, _offset: u64, _size: AccessSize) -> Option<u64> {
+        None
+    }
+
+    /// Write a register. None is reported to the program as an access fault
+    fn write(&mut self, offset: u64, data: u64, size: AccessSize) -> Option<()>;
+
+    /// Advance the state of the device by one step (once per executed instruction)
+    fn cycle(&mut self) {}
+
+    /// Level of the interrupt line of the device, if it is connected to the PLIC
+    fn interrupt(&self) -> bool {
+        false
+    }
+
+    /// Compatible strings of the device in the device tree given to the
+    /// guest, the most specific first. Devices without any are left out
+    fn compatible(&self) -> &[&str] {
+        &[]
+    }
+
+    /// Copy of the device, the emulator is cloned to take checkpoints
+    fn clone_device(&self) -> Box<dyn Device>;
+}
+
+impl Clone for Box<dyn Device> {
+    fn clone(&self) -> Box<dyn Device> {
+        self.clone_device()
+    }
+}
@@ -0,0 +1,287 @@
+use colored::Colorize;
+use crate::cpu::{CSRegIndex, Instruction, REG_FILE_NAMES};
+use crate::csr::CsrFile;
+use crate::rv::{self, OpCodes};
+
+/// Convert an instruction into assembly text with the ABI register names,
+/// e.g. "ld ra, 40(sp)". Branch and jump targets are shown as absolute
+/// addresses, computed from the address of the instruction (pc).
+/// Words that are not RV64I (or F, D, Zba, Zbb, Zbs, Zbc and Zicond)
+/// instructions are shown as ".word 0x..."
+pub fn disassemble(instr: Instruction, pc: u64) -> String {
+    let opcode = (instr & 0x7f) as u8;
+    let f3 = ((instr >> 12) & 0x7) as u8;
+    let f7 = ((instr >> 25) & 0x7f) as u8;
+    let rd:  &str = REG_FILE_NAMES[((instr >>  7) & 0x1f) as usize];
+    let rs1: &str = REG_FILE_NAMES[((instr >> 15) & 0x1f) as usize];
+    let rs2: &str = REG_FILE_NAMES[((instr >> 20) & 0x1f) as usize];
+    let imm5:  u32 = (instr >> 7) & 0x1f;
+    let imm12: u32 = (instr as i32 >> 20) as u32;
+    let imm20: u32 = (instr as i32 >> 12) as u32;
+    // Sign-extended immediate of the I-Type instructions
+    let imm: i64 = imm12 as i32 as i64;
+
+    let text: Option<String> = match opcode {
+        OpCodes::LUI => Some(format!("lui {}, 0x{:x}", rd, instr >> 12)),
+        OpCodes::AUIPC => Some(format!("auipc {}, 0x{:x}", rd, instr >> 12)),
+        OpCodes::JAL => Some(format!("jal {}, 0x{:x}", rd,
+                                     pc.wrapping_add(rv::decode_immediate_jtype(imm20) as u64))),
+        OpCodes::JALR if f3 == 0b000 => Some(format!("jalr {}, {}({})", rd, imm, rs1)),
+        OpCodes::BTYPE => ["beq", "bne", "", "", "blt", "bge", "bltu", "bgeu"]
+            .get(f3 as usize)
+            .filter(|mnemonic| !mnemonic.is_empty())
+            .map(|mnemonic| format!("{} {}, {}, 0x{:x}", mnemonic, rs1, rs2,
+                                    pc.wrapping_add(rv::decode_immediate_btype(imm5, imm12) as u64))),
+        OpCodes::LOAD => ["lb", "lh", "lw", "ld", "lbu", "lhu", "lwu", ""]
+            .get(f3 as usize)
+            .filter(|mnemonic| !mnemonic.is_empty())
+            .map(|mnemonic| format!("{} {}, {}({})", mnemonic, rd, imm, rs1)),
+        OpCodes::STYPE => ["sb", "sh", "sw", "sd"]
+            .get(f3 as usize)
+            .map(|mnemonic| format!("{} {}, {}({})", mnemonic, rs2,
+                                    rv::decode_immediate_stype(imm5, imm12), rs1)),
+        OpCodes::ITYPE => match (f3, f7 >> 1) {
+            // Zbb and Zbs instructions are told apart by imm[11:6]
+            (0b001, 0b011000) => ["clz", "ctz", "cpop", "", "sext.b", "sext.h"]
+                .get((imm12 & 0x1f) as usize)
+                .filter(|mnemonic| !mnemonic.is_empty() && imm12 & 0x20 == 0)
+                .map(|mnemonic| format!("{} {}, {}", mnemonic, rd, rs1)),
+            (0b001, 0b001010) => Some(format!("bseti {}, {}, {}", rd, rs1, imm12 & 0x3f)),
+            (0b001, 0b010010) => Some(format!("bclri {}, {}, {}", rd, rs1, imm12 & 0x3f)),
+            (0b001, 0b011010) => Some(format!("binvi {}, {}, {}", rd, rs1, imm12 & 0x3f)),
+            (0b001, _) => Some(format!("slli {}, {}, {}", rd, rs1, imm12 & 0x3f)),
+            (0b101, 0b011000) => Some(format!("rori {}, {}, {}", rd, rs1, imm12 & 0x3f)),
+            (0b101, 0b010010) => Some(format!("bexti {}, {}, {}", rd, rs1, imm12 & 0x3f)),
+            (0b101, 0b001010) if imm12 & 0xfff == 0x287 => Some(format!("orc.b {}, {}", rd, rs1)),
+            // rev8 is encoded as 0x698 in RV32 and 0x6b8 in RV64
+            (0b101, 0b011010) if imm12 & 0xfdf == 0x698 => Some(format!("rev8 {}, {}", rd, rs1)),
+            (0b101, _) => {
+                let mnemonic: &str = if (instr >> 30) & 0x1 == 0x1 { "srai" } else { "srli" };
+                Some(format!("{} {}, {}, {}", mnemonic, rd, rs1, imm12 & 0x3f))
+            },
+            (f3, _) => ["addi", "", "slti", "sltiu", "xori", "", "ori", "andi"]
+                .get(f3 as usize)
+                .map(|mnemonic| format!("{} {}, {}, {}", mnemonic, rd, rs1, imm))
+        },
+        OpCodes::ITYPE64 => match (f3, f7) {
+            (0b000, _) => Some(format!("addiw {}, {}, {}", rd, rs1, imm)),
+            (0b001, 0b0000000) => Some(format!("slliw {}, {}, {}", rd, rs1, imm12 & 0x1f)),
+            (0b001, 0b0000100 | 0b0000101) => Some(format!("slli.uw {}, {}, {}", rd, rs1, imm12 & 0x3f)),
+            (0b001, 0b0110000) => ["clzw", "ctzw", "cpopw"]
+                .get((imm12 & 0x1f) as usize)
+                .map(|mnemonic| format!("{} {}, {}", mnemonic, rd, rs1)),
+            (0b101, 0b0110000) => Some(format!("roriw {}, {}, {}", rd, rs1, imm12 & 0x1f)),
+            (0b101, _) => {
+                let mnemonic: &str = if (instr >> 30) & 0x1 == 0x1 { "sraiw" } else { "srliw" };
+                Some(format!("{} {}, {}, {}", mnemonic, rd, rs1, imm12 & 0x1f))
+            },
+            _ => None
+        },
+        OpCodes::RTYPE => match (f3, f7) {
+            (0b000, 0b0000000) => Some("add"),
+            (0b000, 0b0100000) => Some("sub"),
+            (0b001, 0b0000000) => Some("sll"),
+            (0b010, 0b0000000) => Some("slt"),
+            (0b011, 0b0000000) => Some("sltu"),
+            (0b100, 0b0000000) => Some("xor"),
+            (0b101, 0b0000000) => Some("srl"),
+            (0b101, 0b0100000) => Some("sra"),
+            (0b110, 0b0000000) => Some("or"),
+            (0b111, 0b0000000) => Some("and"),
+            (0b010, 0b0010000) => Some("sh1add"),
+            (0b100, 0b0010000) => Some("sh2add"),
+            (0b110, 0b0010000) => Some("sh3add"),
+            (0b111, 0b0100000) => Some("andn"),
+            (0b110, 0b0100000) => Some("orn"),
+            (0b100, 0b0100000) => Some("xnor"),
+            (0b100, 0b0000101) => Some("min"),
+            (0b101, 0b0000101) => Some("minu"),
+            (0b110, 0b0000101) => Some("max"),
+            (0b111, 0b0000101) => Some("maxu"),
+            (0b001, 0b0110000) => Some("rol"),
+            (0b101, 0b0110000) => Some("ror"),
+            (0b001, 0b0100100) => Some("bclr"),
+            (0b101, 0b0100100) => Some("bext"),
+            (0b001, 0b0110100) => Some("binv"),
+            (0b001, 0b0010100) => Some("bset"),
+            (0b101, 0b0000111) => Some("czero.eqz"),
+            (0b111, 0b0000111) => Some("czero.nez"),
+            (0b001, 0b0000101) => Some("clmul"),
+            (0b010, 0b0000101) => Some("clmulr"),
+            (0b011, 0b0000101) => Some("clmulh"),
+            _ => None
+        }.map(|mnemonic| format!("{} {}, {}, {}", mnemonic, rd, rs1, rs2))
+            // zext.h has a single source register (RV32 encoding)
+            .or_else(|| (f3 == 0b100 && f7 == 0b0000100 && rs2 == "zero").then(|| format!("zext.h {}, {}", rd, rs1))),
+        OpCodes::RTYPE64 => match (f3, f7) {
+            (0b000, 0b0000000) => Some("addw"),
+            (0b000, 0b0100000) => Some("subw"),
+            (0b001, 0b0000000) => Some("sllw"),
+            (0b101, 0b0000000) => Some("srlw"),
+            (0b101, 0b0100000) => Some("sraw"),
+            (0b000, 0b0000100) => Some("add.uw"),
+            (0b010, 0b0010000) => Some("sh1add.uw"),
+            (0b100, 0b0010000) => Some("sh2add.uw"),
+            (0b110, 0b0010000) => Some("sh3add.uw"),
+            (0b001, 0b0110000) => Some("rolw"),
+            (0b101, 0b0110000) => Some("rorw"),
+            _ => None
+        }.map(|mnemonic| format!("{} {}, {}, {}", mnemonic, rd, rs1, rs2))
+            .or_else(|| (f3 == 0b100 && f7 == 0b0000100 && rs2 == "zero").then(|| format!("zext.h {}, {}", rd, rs1))),
+        OpCodes::LOAD_FP => ["", "", "flw", "fld"]
+            .get(f3 as usize)
+            .filter(|mnemonic| !mnemonic.is_empty())
+            .map(|mnemonic| format!("{} {}, {}({})", mnemonic, freg(instr >> 7), imm, rs1)),
+        OpCodes::STORE_FP => ["", "", "fsw", "fsd"]
+            .get(f3 as usize)
+            .filter(|mnemonic| !mnemonic.is_empty())
+            .map(|mnemonic| format!("{} {}, {}({})", mnemonic, freg(instr >> 20),
+                                    rv::decode_immediate_stype(imm5, imm12), rs1)),
+        OpCodes::MADD | OpCodes::MSUB | OpCodes::NMSUB | OpCodes::NMADD if f7 & 0x3 < 2 => {
+            let mnemonic: &str = match opcode {
+                OpCodes::MADD => "fmadd",
+                OpCodes::MSUB => "fmsub",
+                OpCodes::NMSUB => "fnmsub",
+                _ => "fnmadd"
+            };
+            Some(format!("{}.{} {}, {}, {}, {}{}", mnemonic, precision(f7), freg(instr >> 7), freg(instr >> 15),
+                         freg(instr >> 20), freg(instr >> 27), rounding_mode(f3)))
+        },
+        OpCodes::OP_FP if f7 & 0x3 < 2 => disassemble_float(instr, rd, rs1),
+        OpCodes::FENCE => match f3 {
+            0b000 => Some("fence".to_string()),
+            0b001 => Some("fence.i".to_string()),
+            _ => None
+        },
+        OpCodes::EXCEP => {
+            let csr: CSRegIndex = (imm12 & 0xfff) as CSRegIndex;
+            match (f3, f7) {
+                (0b000, 0b0000000) if imm12 & 0x1 == 0x1 => Some("ebreak".to_string()),
+                (0b000, 0b0000000) => Some("ecall".to_string()),
+                (0b000, 0b0011000) if csr == 0x302 => Some("mret".to_string()),
+                (0b000, 0b0001000) if csr == 0x102 => Some("sret".to_string()),
+                (0b000, 0b0001000) if csr == 0x105 => Some("wfi".to_string()),
+                (0b001..=0b011, _) => Some(format!("{} {}, {}, {}",
+                                                   ["csrrw", "csrrs", "csrrc"][f3 as usize - 1], rd, csr_name(csr), rs1)),
+                (0b101..=0b111, _) => Some(format!("{} {}, {}, {}",
+                                                   ["csrrwi", "csrrsi", "csrrci"][f3 as usize - 5], rd, csr_name(csr),
+                                                   (instr >> 15) & 0x1f)),
+                _ => None
+            }
+        },
+        _ => None
+    };
+    text.unwrap_or_else(|| format!(".word 0x{:0>8x}", instr))
+}
+
+/// Color the mnemonic of a disassembled instruction for the terminal
+pub fn highlight(text: &str) -> String {
+    match text.split_once(' ') {
+        Some((mnemonic, operands)) => format!("{} {}", mnemonic.blue(), operands),
+        None => text.blue().to_string()
+    }
+}
+
+// ABI name of the floating-point register in the low 5 bits of field
+fn freg(field: u32) -> &'static str {
+    const NAMES: [&str; 32] = [
+        "ft0", "ft1", "ft2", "ft3", "ft4", "ft5", "ft6", "ft7",
+        "fs0", "fs1", "fa0", "fa1", "fa2", "fa3", "fa4", "fa5",
+        "fa6", "fa7", "fs2", "fs3", "fs4", "fs5", "fs6", "fs7",
+        "fs8", "fs9", "fs10", "fs11", "ft8", "ft9", "ft10", "ft11"
+    ];
+    NAMES[(field & 0x1f) as usize]
+}
+
+// Suffix of the format (fmt, the low bits of f7): single or double
+fn precision(f7: u8) -> &'static str {
+    if f7 & 0x1 == 0 { "s" } else { "d" }
+}
+
+// Rounding mode operand, omitted when it is dynamic as in the assembler
+fn rounding_mode(rm: u8) -> String {
+    match rm {
+        0b111 => String::new(),
+        rm => format!(", {}", ["rne", "rtz", "rdn", "rup", "rmm", "0x5", "0x6"][rm as usize])
+    }
+}
+
+// Disassemble an OP-FP instruction of the F and D extensions, whose
+// integer registers are rd and rs1
+fn disassemble_float(instr: Instruction, rd: &str, rs1: &str) -> Option<String> {
+    let f3 = ((instr >> 12) & 0x7) as u8;
+    let f7 = ((instr >> 25) & 0x7f) as u8;
+    let rs2: u32 = (instr >> 20) & 0x1f;
+    let fmt: &str = precision(f7);
+    let (frd, frs1, frs2) = (freg(instr >> 7), freg(instr >> 15), freg(instr >> 20));
+    // Integer widths of the conversions, selected by rs2
+    let width: Option<&str> = ["w", "wu", "l", "lu"].get(rs2 as usize).copied();
+    match (f7 >> 2, f3) {
+        (0b00000..=0b00011, _) => Some(format!("{}.{} {}, {}, {}{}", ["fadd", "fsub", "fmul", "fdiv"][(f7 >> 2) as usize],
+                                               fmt, frd, frs1, frs2, rounding_mode(f3))),
+        (0b01011, _) if rs2 == 0 => Some(format!("fsqrt.{} {}, {}{}", fmt, frd, frs1, rounding_mode(f3))),
+        (0b00100, 0b000..=0b010) => Some(format!("{}.{} {}, {}, {}", ["fsgnj", "fsgnjn", "fsgnjx"][f3 as usize],
+                                                 fmt, frd, frs1, frs2)),
+        (0b00101, 0b000..=0b001) => Some(format!("{}.{} {}, {}, {}", ["fmin", "fmax"][f3 as usize], fmt, frd, frs1, frs2)),
+        (0b01000, _) if rs2 == 1 - (f7 & 0x1) as u32 => Some(format!("fcvt.{}.{} {}, {}{}", fmt, precision(rs2 as u8),
+                                                                     frd, frs1, rounding_mode(f3))),
+        (0b10100, 0b000..=0b010) => Some(format!("{}.{} {}, {}, {}", ["fle", "flt", "feq"][f3 as usize], fmt, rd, frs1, frs2)),
+        (0b11000, _) => width.map(|width| format!("fcvt.{}.{} {}, {}{}", width, fmt, rd, frs1, rounding_mode(f3))),
+        (0b11010, _) => width.map(|width| format!("fcvt.{}.{} {}, {}{}", fmt, width, frd, rs1, rounding_mode(f3))),
+        (0b11100, 0b000) if rs2 == 0 => Some(format!("fmv.x.{} {}, {}", if f7 & 0x1 == 0 { "w" } else { "d" }, rd, frs1)),
+        (0b11100, 0b001) if rs2 == 0 => Some(format!("fclass.{} {}, {}", fmt, rd, frs1)),
+        (0b11110, 0b000) if rs2 == 0 => Some(format!("fmv.{}.x {}, {}", if f7 & 0x1 == 0 { "w" } else { "d" }, frd, rs1)),
+        _ => None
+    }
+}
+
+// Name of the CSR if it is implemented, its address otherwise
+fn csr_name(csr: CSRegIndex) -> String {
+    if CsrFile::implemented().any(|address| address == csr) {
+        CsrFile::name(csr)
+    } else {
+        format!("0x{:03x}", csr)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::disas::disassemble;
+
+    #[test]
+    fn disassemble_test() {
+        assert_eq!(disassemble(0x02813083, 0), "ld ra, 40(sp)");
+        assert_eq!(disassemble(0x00113423, 0), "sd ra, 8(sp)");
+        assert_eq!(disassemble(0xfff50513, 0), "addi a0, a0, -1");
+        assert_eq!(disassemble(0x40355513, 0), "srai a0, a0, 3");
+        assert_eq!(disassemble(0x40b50533, 0), "sub a0, a0, a1");
+        assert_eq!(disassemble(0x000122b7, 0), "lui t0, 0x12");
+        // Targets are relative to the address of the instruction
+        assert_eq!(disassemble(0xfeb50ce3, 0x10020), "beq a0, a1, 0x10018");
+        assert_eq!(disassemble(0x010000ef, 0x10000), "jal ra, 0x10010");
+        assert_eq!(disassemble(0x00008067, 0), "jalr zero, 0(ra)");
+        assert_eq!(disassemble(0x30029073, 0), "csrrw zero, mstatus, t0");
+        assert_eq!(disassemble(0x7c02d073, 0), "csrrwi zero, 0x7c0, 5");
+        assert_eq!(disassemble(0x00000073, 0), "ecall");
+        assert_eq!(disassemble(0x30200073, 0), "mret");
+        assert_eq!(disassemble(0x10500073, 0), "wfi");
+        assert_eq!(disassemble(0x0000100f, 0), "fence.i");
+        assert_eq!(disassemble(0x20b54533, 0), "sh2add a0, a0, a1");
+        assert_eq!(disassemble(0x0805053b, 0), "add.uw a0, a0, zero");
+        assert_eq!(disassemble(0x60051513, 0), "clz a0, a0");
+        assert_eq!(disassemble(0x6b855513, 0), "rev8 a0, a0");
+        assert_eq!(disassemble(0x0805453b, 0), "zext.h a0, a0");
+        assert_eq!(disassemble(0x28351513, 0), "bseti a0, a0, 3");
+        assert_eq!(disassemble(0x0eb55533, 0), "czero.eqz a0, a0, a1");
+        assert_eq!(disassemble(0x00853087, 0), "fld ft1, 8(a0)");
+        assert_eq!(disassemble(0x00a12627, 0), "fsw fa0, 12(sp)");
+        assert_eq!(disassemble(0x02b57553, 0), "fadd.d fa0, fa0, fa1");
+        assert_eq!(disassemble(0x00b51553, 0), "fadd.s fa0, fa0, fa1, rtz");
+        assert_eq!(disassemble(0x60b5f543, 0), "fmadd.s fa0, fa1, fa1, fa2");
+        assert_eq!(disassemble(0xc2059553, 0), "fcvt.w.d a0, fa1, rtz");
+        assert_eq!(disassemble(0x4015f553, 0), "fcvt.s.d fa0, fa1");
+        assert_eq!(disassemble(0xa2b52553, 0), "feq.d a0, fa0, fa1");
+        assert_eq!(disassemble(0xe2050553, 0), "fmv.x.d a0, fa0");
+        assert_eq!(disassemble(0xffffffff, 0), ".word 0xffffffff");
+    }
+}
@@ -1,23 +1,57 @@
+use crate::cpu::Xlen;
+
+/// A loadable segment of the executable
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Segment {
+    pub address: u64,
+    // Offset of the segment in the file
+    pub offset: usize,
+    pub file_size: usize,
+    // Size of the segment in memory, the bytes beyond file_size
+    // (e.g. .bss) are not in the file and must be zeroed
+    pub mem_size: usize,
+    pub readable: bool,
+    pub executable: bool,
+    pub writable: bool
+}
+
+impl Segment {
+    /// Address right after the end of the segment in memory
+    pub fn end(&self) -> u64 {
+        self.address + self.mem_size as u64
+    }
+}
+
 pub struct AddressSpace {
-    pub read_execute_segment: usize,
-    pub read_execute_size: usize,
-    pub read_execute_offset: usize,
-    pub read_write_segment: usize,
-    pub read_write_size: usize,
-    pub read_write_offset: usize
+    // The ROM starts at the lowest read-only segment
+    pub text_start: u64,
+    // The DRAM starts at the lowest writable segment
+    pub data_start: u64,
+    pub segments: Vec<Segment>,
+    // Address of the program headers in memory, if a segment loads them,
+    // with the size and number of entries (for the auxiliary vector)
+    pub phdr: Option<u64>,
+    pub phentsize: u64,
+    pub phnum: u64
+}
+
+impl Default for AddressSpace {
+    fn default() -> AddressSpace {
+        AddressSpace::new()
+    }
 }
 
 impl AddressSpace {
-    const TEXT_START_DEFAULT: usize = 0x00000000;
-    const DATA_START_DEFAULT: usize = 0x00020000;
+    pub const TEXT_START_DEFAULT: u64 = 0x00000000;
+    const DATA_START_DEFAULT: u64 = 0x00020000;
     pub fn new() -> AddressSpace {
         AddressSpace {
-            read_execute_segment: AddressSpace::TEXT_START_DEFAULT,
-            read_execute_size: 0,
-            read_execute_offset: 0,
-            read_write_segment: AddressSpace::DATA_START_DEFAULT,
-            read_write_size: 0,
-            read_write_offset: 0
+            text_start: AddressSpace::TEXT_START_DEFAULT,
+            data_start: AddressSpace::DATA_START_DEFAULT,
+            segments: Vec::new(),
+            phdr: None,
+            phentsize: 0,
+            phnum: 0
         }
     }
 }
@@ -74,9 +108,26 @@ impl ElfHeader {
     const ESHNUM_OFF:     usize = 0x3C;
     // e_shstrndx: section header table index of the table with section name table
     const ESHSTRNDX_OFF:  usize = 0x3E;
+    // Size of the whole header
+    const SIZE:           usize = 0x40;
+
+    // In ELF32 files e_entry, e_phoff and e_shoff are 4 bytes long,
+    // so the fields after them are found at different offsets
+    const EENTRY_OFF32:     usize = 0x18;
+    const EPHOFF_OFF32:     usize = 0x1C;
+    const ESHOFF_OFF32:     usize = 0x20;
+    const EFLAGS_OFF32:     usize = 0x24;
+    const EEHSIZE_OFF32:    usize = 0x28;
+    const EPHENTSIZE_OFF32: usize = 0x2A;
+    const EPHNUM_OFF32:     usize = 0x2C;
+    const ESHENTSIZE_OFF32: usize = 0x2E;
+    const ESHNUM_OFF32:     usize = 0x30;
+    const ESHSTRNDX_OFF32:  usize = 0x32;
+    const SIZE32:           usize = 0x34;
 
     const EIDENT_MAGIC: [u8; 4] = [0x7f, 0x45, 0x4C, 0x46];
 
+    const ELFCLASS32: u8        = 0x01;
     const ELFCLASS64: u8        = 0x02;
     const EICLASS_POS: usize    = 0x04;
 
@@ -104,22 +155,46 @@ impl ElfHeader {
         }
     }
 
+    /// Check if the file is an ELF64 file, from the class in e_ident
+    fn is_class64(&self) -> bool {
+        self.e_ident[ElfHeader::EICLASS_POS] != ElfHeader::ELFCLASS32
+    }
+
+    /// Size of the header of an ELF file of the class found in e_ident
+    fn size_of_class(e_ident: &[u8]) -> usize {
+        if e_ident.get(ElfHeader::EICLASS_POS) == Some(&ElfHeader::ELFCLASS32) { ElfHeader::SIZE32 } else { ElfHeader::SIZE }
+    }
+
     /// Fill ELF header from byte buffer
+    #[allow(clippy::wrong_self_convention)]
     fn from_buffer(&mut self, buf: &[u8]) {
         self.e_ident.clone_from_slice(&buf[ElfHeader::EIDENT_OFF..ElfHeader::EIDENT_OFF + ElfHeader::EI_NIDENT]);
-        self.e_type =      u16::from_le_bytes(buf[ElfHeader::ETYPE_OFF..ElfHeader::ETYPE_OFF + 2].try_into().unwrap());
-        self.e_machine =   u16::from_le_bytes(buf[ElfHeader::EMACHINE_OFF..ElfHeader::EMACHINE_OFF + 2].try_into().unwrap());
-        self.e_version =   u32::from_le_bytes(buf[ElfHeader::EVERSION_OFF..ElfHeader::EVERSION_OFF + 4].try_into().unwrap());
-        self.e_entry =     u64::from_le_bytes(buf[ElfHeader::EENTRY_OFF..ElfHeader::EENTRY_OFF + 8].try_into().unwrap());
-        self.e_phoff =     u64::from_le_bytes(buf[ElfHeader::EPHOFF_OFF..ElfHeader::EPHOFF_OFF + 8].try_into().unwrap());
-        self.e_shoff =     u64::from_le_bytes(buf[ElfHeader::ESHOFF_OFF..ElfHeader::ESHOFF_OFF + 8].try_into().unwrap());
-        self.e_flags =     u32::from_le_bytes(buf[ElfHeader::EFLAGS_OFF..ElfHeader::EFLAGS_OFF + 4].try_into().unwrap());
-        self.e_ehsize =    u16::from_le_bytes(buf[ElfHeader::EEHSIZE_OFF..ElfHeader::EEHSIZE_OFF + 2].try_into().unwrap());
-        self.e_phentsize = u16::from_le_bytes(buf[ElfHeader::EPHENTSIZE_OFF..ElfHeader::EPHENTSIZE_OFF + 2].try_into().unwrap());
-        self.e_phnum =     u16::from_le_bytes(buf[ElfHeader::EPHNUM_OFF..ElfHeader::EPHNUM_OFF + 2].try_into().unwrap());
-        self.e_shentsize = u16::from_le_bytes(buf[ElfHeader::ESHENTSIZE_OFF..ElfHeader::ESHENTSIZE_OFF + 2].try_into().unwrap());
-        self.e_shnum =     u16::from_le_bytes(buf[ElfHeader::ESHNUM_OFF..ElfHeader::ESHNUM_OFF + 2].try_into().unwrap());
-        self.e_shstrndx =  u16::from_le_bytes(buf[ElfHeader::ESHSTRNDX_OFF..ElfHeader::ESHSTRNDX_OFF + 2].try_into().unwrap());
+        self.e_type =      read_u16(buf, ElfHeader::ETYPE_OFF);
+        self.e_machine =   read_u16(buf, ElfHeader::EMACHINE_OFF);
+        self.e_version =   read_u32(buf, ElfHeader::EVERSION_OFF);
+        if self.is_class64() {
+            self.e_entry =     read_u64(buf, ElfHeader::EENTRY_OFF);
+            self.e_phoff =     read_u64(buf, ElfHeader::EPHOFF_OFF);
+            self.e_shoff =     read_u64(buf, ElfHeader::ESHOFF_OFF);
+            self.e_flags =     read_u32(buf, ElfHeader::EFLAGS_OFF);
+            self.e_ehsize =    read_u16(buf, ElfHeader::EEHSIZE_OFF);
+            self.e_phentsize = read_u16(buf, ElfHeader::EPHENTSIZE_OFF);
+            self.e_phnum =     read_u16(buf, ElfHeader::EPHNUM_OFF);
+            self.e_shentsize = read_u16(buf, ElfHeader::ESHENTSIZE_OFF);
+            self.e_shnum =     read_u16(buf, ElfHeader::ESHNUM_OFF);
+            self.e_shstrndx =  read_u16(buf, ElfHeader::ESHSTRNDX_OFF);
+        } else {
+            self.e_entry =     read_u32(buf, ElfHeader::EENTRY_OFF32) as u64;
+            self.e_phoff =     read_u32(buf, ElfHeader::EPHOFF_OFF32) as u64;
+            self.e_shoff =     read_u32(buf, ElfHeader::ESHOFF_OFF32) as u64;
+            self.e_flags =     read_u32(buf, ElfHeader::EFLAGS_OFF32);
+            self.e_ehsize =    read_u16(buf, ElfHeader::EEHSIZE_OFF32);
+            self.e_phentsize = read_u16(buf, ElfHeader::EPHENTSIZE_OFF32);
+            self.e_phnum =     read_u16(buf, ElfHeader::EPHNUM_OFF32);
+            self.e_shentsize = read_u16(buf, ElfHeader::ESHENTSIZE_OFF32);
+            self.e_shnum =     read_u16(buf, ElfHeader::ESHNUM_OFF32);
+            self.e_shstrndx =  read_u16(buf, ElfHeader::ESHSTRNDX_OFF32);
+        }
     }
 
     fn check_header(&self) -> Result<String, String> {
@@ -127,32 +202,34 @@ impl ElfHeader {
             return Err("File is not a valid ELF file: bad magic number".to_string());
         }
 
-        if self.e_ident[ElfHeader::EICLASS_POS] != ElfHeader::ELFCLASS64 {
-            return Err("File is not compiled for 64 bit architectures".to_string());
+        if ![ElfHeader::ELFCLASS32, ElfHeader::ELFCLASS64].contains(&self.e_ident[ElfHeader::EICLASS_POS]) {
+            return Err(format!("File is not compiled for 32 or 64 bit architectures (ELF class {})",
+                               self.e_ident[ElfHeader::EICLASS_POS]));
         }
 
         if self.e_ident[ElfHeader::EIDATA_POS] != ElfHeader::ELFDATA2LSB {
-            return Err("File is not compiled for little endian architectures".to_string());
+            return Err(format!("File is not compiled for little endian architectures (data encoding {})",
+                               self.e_ident[ElfHeader::EIDATA_POS]));
         }
 
         if self.e_ident[ElfHeader::EIVERSION_POS] != ElfHeader::EIVERSION {
-            return Err("Invalid ELF version".to_string());
+            return Err(format!("Invalid ELF version {}", self.e_ident[ElfHeader::EIVERSION_POS]));
         }
 
         if self.e_ident[ElfHeader::EIOSABI_POS] != ElfHeader::ELFOSABI_SYSV {
-            return Err("Target ABI is not System V".to_string());
+            return Err(format!("Target ABI is not System V (OS ABI {})", self.e_ident[ElfHeader::EIOSABI_POS]));
         }
 
         if self.e_type != ElfHeader::ET_EXEC {
-            return Err("Not an executable ELF file".to_string());
+            return Err(format!("Not an executable ELF file (type {})", self.e_type));
         }
 
         if self.e_machine != ElfHeader::EM_RISCV {
-            return Err("File was not compiled for a RISC-V machine".to_string());
+            return Err(format!("File was not compiled for a RISC-V machine (machine 0x{:x})", self.e_machine));
         }
 
         if self.e_version!= ElfHeader::EV_CURRENT {
-            return Err("Not an executable ELF file".to_string());
+            return Err(format!("Invalid ELF version {}", self.e_version));
         }
 
         Ok("ELF loaded correctly".to_string())
@@ -179,6 +256,19 @@ impl ProgHeader {
     const PFILESZ_OFF: usize = 0x20;
     const PMEMSZ_OFF:  usize = 0x28;
     const PALIGN_OFF:  usize = 0x30;
+    // Size of a program header
+    const SIZE:        usize = 0x38;
+
+    // ELF32 program headers have shorter fields and p_flags comes later
+    const PTYPE_OFF32:   usize = 0x00;
+    const POFFSET_OFF32: usize = 0x04;
+    const PVADDR_OFF32:  usize = 0x08;
+    const PPADDR_OFF32:  usize = 0x0C;
+    const PFILESZ_OFF32: usize = 0x10;
+    const PMEMSZ_OFF32:  usize = 0x14;
+    const PFLAGS_OFF32:  usize = 0x18;
+    const PALIGN_OFF32:  usize = 0x1C;
+    const SIZE32:        usize = 0x20;
 
     const PTYPE_LOAD:   u32 = 0x1;
     const PFLAGS_READ:  u32 = 0x4;
@@ -194,37 +284,195 @@ impl ProgHeader {
     }
 
     /// Fill program header from byte buffer
-    fn from_buffer(&mut self, buf: &[u8]) {
-        self.p_type =   u32::from_le_bytes(buf[ProgHeader::PTYPE_OFF..ProgHeader::PTYPE_OFF + 4].try_into().unwrap());
-        self.p_flags =  u32::from_le_bytes(buf[ProgHeader::PFLAGS_OFF..ProgHeader::PFLAGS_OFF + 4].try_into().unwrap());
-        self.p_offset = u64::from_le_bytes(buf[ProgHeader::POFFSET_OFF..ProgHeader::POFFSET_OFF + 8].try_into().unwrap());
-        self.p_vaddr =  u64::from_le_bytes(buf[ProgHeader::PVADDR_OFF..ProgHeader::PVADDR_OFF + 8].try_into().unwrap());
-        self.p_paddr =  u64::from_le_bytes(buf[ProgHeader::PPADDR_OFF..ProgHeader::PPADDR_OFF + 8].try_into().unwrap());
-        self.p_filesz = u64::from_le_bytes(buf[ProgHeader::PFILESZ_OFF..ProgHeader::PFILESZ_OFF + 8].try_into().unwrap());
-        self.p_memsz =  u64::from_le_bytes(buf[ProgHeader::PMEMSZ_OFF..ProgHeader::PMEMSZ_OFF + 8].try_into().unwrap());
-        self.p_align =  u64::from_le_bytes(buf[ProgHeader::PALIGN_OFF..ProgHeader::PALIGN_OFF + 8].try_into().unwrap());
+    #[allow(clippy::wrong_self_convention)]
+    fn from_buffer(&mut self, buf: &[u8], class64: bool) {
+        if class64 {
+            self.p_type =   read_u32(buf, ProgHeader::PTYPE_OFF);
+            self.p_flags =  read_u32(buf, ProgHeader::PFLAGS_OFF);
+            self.p_offset = read_u64(buf, ProgHeader::POFFSET_OFF);
+            self.p_vaddr =  read_u64(buf, ProgHeader::PVADDR_OFF);
+            self.p_paddr =  read_u64(buf, ProgHeader::PPADDR_OFF);
+            self.p_filesz = read_u64(buf, ProgHeader::PFILESZ_OFF);
+            self.p_memsz =  read_u64(buf, ProgHeader::PMEMSZ_OFF);
+            self.p_align =  read_u64(buf, ProgHeader::PALIGN_OFF);
+        } else {
+            self.p_type =   read_u32(buf, ProgHeader::PTYPE_OFF32);
+            self.p_flags =  read_u32(buf, ProgHeader::PFLAGS_OFF32);
+            self.p_offset = read_u32(buf, ProgHeader::POFFSET_OFF32) as u64;
+            self.p_vaddr =  read_u32(buf, ProgHeader::PVADDR_OFF32) as u64;
+            self.p_paddr =  read_u32(buf, ProgHeader::PPADDR_OFF32) as u64;
+            self.p_filesz = read_u32(buf, ProgHeader::PFILESZ_OFF32) as u64;
+            self.p_memsz =  read_u32(buf, ProgHeader::PMEMSZ_OFF32) as u64;
+            self.p_align =  read_u32(buf, ProgHeader::PALIGN_OFF32) as u64;
+        }
     }
+
+    /// Size of a program header of an ELF32 or ELF64 file
+    fn size(class64: bool) -> usize {
+        if class64 { ProgHeader::SIZE } else { ProgHeader::SIZE32 }
+    }
+}
+
+struct SectionHeader {
+    sh_name:   u32,
+    sh_type:   u32,
+    sh_flags:  u64,
+    sh_addr:   u64,
+    sh_offset: u64,
+    sh_size:   u64,
+    sh_link:   u32
+}
+
+impl SectionHeader {
+    const SHNAME_OFF:   usize = 0x00;
+    const SHTYPE_OFF:   usize = 0x04;
+    const SHFLAGS_OFF:  usize = 0x08;
+    const SHADDR_OFF:   usize = 0x10;
+    const SHOFFSET_OFF: usize = 0x18;
+    const SHSIZE_OFF:   usize = 0x20;
+    const SHLINK_OFF:   usize = 0x28;
+    // Size of a section header, the fields above are all we need
+    const SIZE: usize = 0x40;
+
+    // ELF32 section headers
+    const SHNAME_OFF32:   usize = 0x00;
+    const SHTYPE_OFF32:   usize = 0x04;
+    const SHFLAGS_OFF32:  usize = 0x08;
+    const SHADDR_OFF32:   usize = 0x0C;
+    const SHOFFSET_OFF32: usize = 0x10;
+    const SHSIZE_OFF32:   usize = 0x14;
+    const SHLINK_OFF32:   usize = 0x18;
+    const SIZE32: usize = 0x28;
+
+    const SHTYPE_SYMTAB: u32 = 0x2;
+    const SHFLAGS_ALLOC: u64 = 0x2;
+
+    /// Read a section header from byte buffer
+    fn from_buffer(buf: &[u8], class64: bool) -> SectionHeader {
+        if class64 {
+            SectionHeader {
+                sh_name:   read_u32(buf, SectionHeader::SHNAME_OFF),
+                sh_type:   read_u32(buf, SectionHeader::SHTYPE_OFF),
+                sh_flags:  read_u64(buf, SectionHeader::SHFLAGS_OFF),
+                sh_addr:   read_u64(buf, SectionHeader::SHADDR_OFF),
+                sh_offset: read_u64(buf, SectionHeader::SHOFFSET_OFF),
+                sh_size:   read_u64(buf, SectionHeader::SHSIZE_OFF),
+                sh_link:   read_u32(buf, SectionHeader::SHLINK_OFF)
+            }
+        } else {
+            SectionHeader {
+                sh_name:   read_u32(buf, SectionHeader::SHNAME_OFF32),
+                sh_type:   read_u32(buf, SectionHeader::SHTYPE_OFF32),
+                sh_flags:  read_u32(buf, SectionHeader::SHFLAGS_OFF32) as u64,
+                sh_addr:   read_u32(buf, SectionHeader::SHADDR_OFF32) as u64,
+                sh_offset: read_u32(buf, SectionHeader::SHOFFSET_OFF32) as u64,
+                sh_size:   read_u32(buf, SectionHeader::SHSIZE_OFF32) as u64,
+                sh_link:   read_u32(buf, SectionHeader::SHLINK_OFF32)
+            }
+        }
+    }
+
+    /// Size of a section header of an ELF32 or ELF64 file
+    fn size(class64: bool) -> usize {
+        if class64 { SectionHeader::SIZE } else { SectionHeader::SIZE32 }
+    }
+
+    /// Content of the section in the file, None if it is not inside the file
+    fn content<'a>(&self, buf: &'a [u8]) -> Option<&'a [u8]> {
+        let start: usize = usize::try_from(self.sh_offset).ok()?;
+        buf.get(start..start.checked_add(usize::try_from(self.sh_size).ok()?)?)
+    }
+}
+
+// Entry of the symbol table
+struct SymbolEntry;
+
+impl SymbolEntry {
+    const STNAME_OFF:  usize = 0x00;
+    const STINFO_OFF:  usize = 0x04;
+    const STSHNDX_OFF: usize = 0x06;
+    const STVALUE_OFF: usize = 0x08;
+    const SIZE: usize = 0x18;
+
+    // ELF32 symbols have st_value before st_info
+    const STNAME_OFF32:  usize = 0x00;
+    const STVALUE_OFF32: usize = 0x04;
+    const STINFO_OFF32:  usize = 0x0C;
+    const STSHNDX_OFF32: usize = 0x0E;
+    const SIZE32: usize = 0x10;
+
+    // Symbol types (low 4 bits of st_info) that name an address
+    const STT_NOTYPE: u8 = 0x0;
+    const STT_OBJECT: u8 = 0x1;
+    const STT_FUNC:   u8 = 0x2;
+    // Undefined symbols are not in any section
+    const SHN_UNDEF: u16 = 0x0;
+}
+
+/// A section of the executable that takes up memory when the program runs
+/// (e.g. .text, .data, .bss)
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Section {
+    pub name: String,
+    pub address: u64,
+    pub size: u64
+}
+
+// Read little endian fields, the caller makes sure they are inside buf
+fn read_u16(buf: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes(buf[offset..offset + 2].try_into().unwrap())
+}
+
+fn read_u32(buf: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(buf[offset..offset + 4].try_into().unwrap())
+}
+
+fn read_u64(buf: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(buf[offset..offset + 8].try_into().unwrap())
+}
+
+// Read a NUL-terminated string starting at offset in a string table
+fn read_string(table: &[u8], offset: usize) -> Option<&str> {
+    let bytes: &[u8] = table.get(offset..)?;
+    let end: usize = bytes.iter().position(|byte| *byte == 0)?;
+    std::str::from_utf8(&bytes[..end]).ok()
 }
 
 pub struct Elf {
     elf_header: ElfHeader,
-    program_headers: Vec<ProgHeader>
+    program_headers: Vec<ProgHeader>,
+    section_headers: Vec<SectionHeader>
+}
+
+impl Default for Elf {
+    fn default() -> Elf {
+        Elf::new()
+    }
 }
 
 impl Elf {
     /// Create new ELF, made of one ELF header and
-    /// a vector of program headers
+    /// the vectors of program and section headers
     pub fn new() -> Elf {
         Elf {
             elf_header: ElfHeader::new(),
-            program_headers: Vec::new()
+            program_headers: Vec::new(),
+            section_headers: Vec::new()
         }
     }
 
+    /// Check if the file buffer starts with the ELF magic number
+    pub fn is_elf(buf: &[u8]) -> bool {
+        buf.starts_with(&ElfHeader::EIDENT_MAGIC)
+    }
+
     /// Read ELF header from file buffer
     /// buf: the file buffer
     /// returns the entry point of the executable
     pub fn read_header(&mut self, buf: &[u8]) -> Result<u64, String> {
+        if buf.len() < ElfHeader::EI_NIDENT || buf.len() < ElfHeader::size_of_class(buf) {
+            return Err(format!("File is not a valid ELF file: it is too short ({} bytes)", buf.len()));
+        }
         self.elf_header.from_buffer(buf);
         match self.elf_header.check_header() {
             Ok(_string) => Ok(self.elf_header.e_entry),
@@ -232,42 +480,256 @@ impl Elf {
         }
     }
 
+    /// Width of the registers the executable was compiled for:
+    /// ELF32 files run in RV32 mode, ELF64 files in RV64 mode
+    pub fn get_xlen(&self) -> Xlen {
+        if self.elf_header.is_class64() { Xlen::Rv64 } else { Xlen::Rv32 }
+    }
+
     /// Cycle through all the program headers in the executables
-    /// and saves them in the program header vector
-    pub fn read_progheaders(&mut self, buf: &[u8]) {
+    /// and saves them in the program header vector. The loadable
+    /// segments must be inside the file
+    pub fn read_progheaders(&mut self, buf: &[u8]) -> Result<(), String> {
+        let class64: bool = self.elf_header.is_class64();
+        let hdr_size_bytes: usize = self.elf_header.e_phentsize as usize;
+        if self.elf_header.e_phnum > 0 && hdr_size_bytes < ProgHeader::size(class64) {
+            return Err(format!("Invalid size of the program headers ({} bytes)", hdr_size_bytes));
+        }
         for i in 0..self.elf_header.e_phnum as usize {
             let mut program_header_i = ProgHeader::new();
-            let hdr_offset_byte: usize = self.elf_header.e_phoff as usize;
-            let hdr_size_bytes: usize = self.elf_header.e_phentsize as usize;
-            let hdr_start_byte: usize = hdr_offset_byte + hdr_size_bytes*i;
+            let hdr: &[u8] = (self.elf_header.e_phoff as usize).checked_add(hdr_size_bytes * i)
+                .and_then(|hdr_start_byte| buf.get(hdr_start_byte..)?.get(..hdr_size_bytes))
+                .ok_or(format!("Program header {} is outside of the file", i))?;
 
-            program_header_i.from_buffer(&buf[hdr_start_byte..hdr_start_byte + hdr_size_bytes]);
+            program_header_i.from_buffer(hdr, class64);
             if program_header_i.p_type == ProgHeader::PTYPE_LOAD {
+                let segment_end: Option<u64> = program_header_i.p_offset.checked_add(program_header_i.p_filesz);
+                if segment_end.is_none_or(|end| end > buf.len() as u64) {
+                    return Err(format!("Segment {} (offset 0x{:x}, 0x{:x} bytes) is outside of the file",
+                                       i, program_header_i.p_offset, program_header_i.p_filesz));
+                }
+                if program_header_i.p_filesz > program_header_i.p_memsz {
+                    return Err(format!("Segment {} is larger in the file (0x{:x} bytes) than in memory (0x{:x} bytes)",
+                                       i, program_header_i.p_filesz, program_header_i.p_memsz));
+                }
+                if program_header_i.p_paddr.checked_add(program_header_i.p_memsz).is_none() {
+                    return Err(format!("Segment {} (0x{:x} bytes at 0x{:x}) goes past the end of the address space",
+                                       i, program_header_i.p_memsz, program_header_i.p_paddr));
+                }
                 self.program_headers.push(program_header_i);
             }
         }
+        Ok(())
     }
 
-    /// This function fills the AddressSpace data structure with the
-    /// virtual address of the executable segment and of the read/write segment
-    /// Moreover it saves the offset of those segments in the executable and their size
-    pub fn get_addrspace(&self) -> AddressSpace {
-        let mut addr_space: AddressSpace = AddressSpace::new();
-        for hdr in &self.program_headers {
-            let segment_start: usize = hdr.p_offset as usize;
-            let segment_size: usize = hdr.p_filesz as usize;
-            if hdr.p_flags == (ProgHeader::PFLAGS_READ | ProgHeader::PFLAGS_EXEC) {
-                addr_space.read_execute_segment = hdr.p_paddr as usize;
-                addr_space.read_execute_offset = segment_start;
-                addr_space.read_execute_size = segment_size;
+    /// Read all the section headers. Section headers are optional: the
+    /// ones that are missing or do not fit in the file are left out
+    pub fn read_sectionheaders(&mut self, buf: &[u8]) {
+        let class64: bool = self.elf_header.is_class64();
+        let entry_size: usize = self.elf_header.e_shentsize as usize;
+        if entry_size < SectionHeader::size(class64) {
+            return;
+        }
+        for i in 0..self.elf_header.e_shnum as usize {
+            let hdr_start_byte: usize = match (self.elf_header.e_shoff as usize).checked_add(entry_size * i) {
+                Some(start) => start,
+                None => break
+            };
+            match buf.get(hdr_start_byte..).and_then(|rest| rest.get(..entry_size)) {
+                Some(hdr) => self.section_headers.push(SectionHeader::from_buffer(hdr, class64)),
+                None => break
             }
-            if hdr.p_flags == (ProgHeader::PFLAGS_READ | ProgHeader::PFLAGS_WRITE) {
-                addr_space.read_write_segment = hdr.p_paddr as usize;
-                addr_space.read_write_offset = segment_start;
-                addr_space.read_write_size = segment_size;
+        }
+    }
+
+    /// Sections that are loaded in memory, with their names
+    pub fn get_sections(&self, buf: &[u8]) -> Vec<Section> {
+        let names: &[u8] = self.section_headers.get(self.elf_header.e_shstrndx as usize)
+            .and_then(|hdr| hdr.content(buf))
+            .unwrap_or(&[]);
+        self.section_headers.iter()
+            .filter(|hdr| hdr.sh_flags & SectionHeader::SHFLAGS_ALLOC != 0 && hdr.sh_size > 0)
+            .map(|hdr| Section {
+                name: read_string(names, hdr.sh_name as usize).unwrap_or("?").to_string(),
+                address: hdr.sh_addr,
+                size: hdr.sh_size
+            })
+            .collect()
+    }
+
+    /// Content of the section with the given name (e.g. .debug_line), if
+    /// the executable has it
+    pub fn get_section_data<'a>(&self, buf: &'a [u8], name: &str) -> Option<&'a [u8]> {
+        let names: &[u8] = self.section_headers.get(self.elf_header.e_shstrndx as usize)?.content(buf)?;
+        self.section_headers.iter()
+            .find(|hdr| read_string(names, hdr.sh_name as usize) == Some(name))?
+            .content(buf)
+    }
+
+    /// Functions and variables in the symbol table, if the executable
+    /// was not stripped, as (address, name) pairs
+    pub fn get_symbols(&self, buf: &[u8]) -> Vec<(u64, String)> {
+        let class64: bool = self.elf_header.is_class64();
+        let (entry_size, name_off, value_off, info_off, shndx_off): (usize, usize, usize, usize, usize) = if class64 {
+            (SymbolEntry::SIZE, SymbolEntry::STNAME_OFF, SymbolEntry::STVALUE_OFF, SymbolEntry::STINFO_OFF, SymbolEntry::STSHNDX_OFF)
+        } else {
+            (SymbolEntry::SIZE32, SymbolEntry::STNAME_OFF32, SymbolEntry::STVALUE_OFF32, SymbolEntry::STINFO_OFF32, SymbolEntry::STSHNDX_OFF32)
+        };
+        let mut symbols: Vec<(u64, String)> = Vec::new();
+        for symtab in self.section_headers.iter().filter(|hdr| hdr.sh_type == SectionHeader::SHTYPE_SYMTAB) {
+            let entries: &[u8] = symtab.content(buf).unwrap_or(&[]);
+            let names: &[u8] = self.section_headers.get(symtab.sh_link as usize)
+                .and_then(|hdr| hdr.content(buf))
+                .unwrap_or(&[]);
+            for entry in entries.chunks_exact(entry_size) {
+                let kind: u8 = entry[info_off] & 0xf;
+                let section: u16 = read_u16(entry, shndx_off);
+                if ![SymbolEntry::STT_NOTYPE, SymbolEntry::STT_OBJECT, SymbolEntry::STT_FUNC].contains(&kind) ||
+                   section == SymbolEntry::SHN_UNDEF {
+                    continue;
+                }
+                let name_offset: u32 = read_u32(entry, name_off);
+                let address: u64 = if class64 { read_u64(entry, value_off) } else { read_u32(entry, value_off) as u64 };
+                match read_string(names, name_offset as usize) {
+                    // Local labels (e.g. .L0) are not worth showing
+                    Some(name) if !name.is_empty() && !name.starts_with(".L") => symbols.push((address, name.to_string())),
+                    _ => ()
+                }
             }
         }
+        symbols
+    }
+
+    /// This function fills the AddressSpace data structure with all the
+    /// loadable segments, and places the ROM at the lowest read-only
+    /// segment and the DRAM at the lowest writable one
+    pub fn get_addrspace(&self) -> AddressSpace {
+        let mut addr_space: AddressSpace = AddressSpace::new();
+        addr_space.segments = self.program_headers.iter().map(|hdr| Segment {
+            address: hdr.p_paddr,
+            offset: hdr.p_offset as usize,
+            file_size: hdr.p_filesz as usize,
+            mem_size: hdr.p_memsz as usize,
+            readable: hdr.p_flags & ProgHeader::PFLAGS_READ != 0,
+            executable: hdr.p_flags & ProgHeader::PFLAGS_EXEC != 0,
+            writable: hdr.p_flags & ProgHeader::PFLAGS_WRITE != 0
+        }).collect();
+        if let Some(text_start) = addr_space.segments.iter().filter(|seg| !seg.writable).map(|seg| seg.address).min() {
+            addr_space.text_start = text_start;
+        }
+        if let Some(data_start) = addr_space.segments.iter().filter(|seg| seg.writable).map(|seg| seg.address).min() {
+            addr_space.data_start = data_start;
+        }
+        let phoff: u64 = self.elf_header.e_phoff;
+        addr_space.phdr = addr_space.segments.iter()
+            .find(|seg| seg.offset as u64 <= phoff && phoff < (seg.offset + seg.file_size) as u64)
+            .map(|seg| seg.address + phoff - seg.offset as u64);
+        addr_space.phentsize = self.elf_header.e_phentsize as u64;
+        addr_space.phnum = self.elf_header.e_phnum as u64;
         addr_space
     }
 
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use crate::cpu::Xlen;
+    use crate::elf::{AddressSpace, Elf, ElfHeader, ProgHeader, Segment};
+
+    // ELF header of a RISC-V executable with a single loadable segment
+    // of 4 bytes, right after the header and the program header
+    fn executable() -> Vec<u8> {
+        let mut buf: Vec<u8> = vec![0; ElfHeader::SIZE + ProgHeader::SIZE + 4];
+        buf[0..8].copy_from_slice(&[0x7f, b'E', b'L', b'F', 2, 1, 1, 0]);
+        buf[ElfHeader::ETYPE_OFF] = 2;
+        buf[ElfHeader::EMACHINE_OFF] = 0xf3;
+        buf[ElfHeader::EVERSION_OFF] = 1;
+        buf[ElfHeader::EENTRY_OFF + 1] = 0x10;
+        buf[ElfHeader::EPHOFF_OFF] = ElfHeader::SIZE as u8;
+        buf[ElfHeader::EPHENTSIZE_OFF] = ProgHeader::SIZE as u8;
+        buf[ElfHeader::EPHNUM_OFF] = 1;
+        let phdr: usize = ElfHeader::SIZE;
+        buf[phdr + ProgHeader::PTYPE_OFF] = 1;
+        buf[phdr + ProgHeader::PFLAGS_OFF] = 5;
+        buf[phdr + ProgHeader::POFFSET_OFF] = (ElfHeader::SIZE + ProgHeader::SIZE) as u8;
+        buf[phdr + ProgHeader::PFILESZ_OFF] = 4;
+        buf[phdr + ProgHeader::PMEMSZ_OFF] = 4;
+        buf
+    }
+
+    #[test]
+    fn header_check_test() {
+        let buf: Vec<u8> = executable();
+        let mut elf: Elf = Elf::new();
+        assert_eq!(elf.read_header(&buf), Ok(0x1000));
+        assert_eq!(elf.read_progheaders(&buf), Ok(()));
+
+        assert!(Elf::new().read_header(&buf[..0x20]).unwrap_err().contains("too short"));
+        let mut not_elf: Vec<u8> = buf.clone();
+        not_elf[1] = b'X';
+        assert!(Elf::new().read_header(&not_elf).unwrap_err().contains("magic"));
+        let mut bad_class: Vec<u8> = buf.clone();
+        bad_class[4] = 3;
+        assert!(Elf::new().read_header(&bad_class).unwrap_err().contains("ELF class 3"));
+        let mut big_endian: Vec<u8> = buf.clone();
+        big_endian[5] = 2;
+        assert!(Elf::new().read_header(&big_endian).unwrap_err().contains("little endian"));
+        let mut x86: Vec<u8> = buf.clone();
+        x86[ElfHeader::EMACHINE_OFF] = 0x3e;
+        assert_eq!(Elf::new().read_header(&x86),
+                   Err("File was not compiled for a RISC-V machine (machine 0x3e)".to_string()));
+
+        // The segment does not fit in a truncated file
+        let truncated: &[u8] = &buf[..buf.len() - 1];
+        let mut elf: Elf = Elf::new();
+        elf.read_header(truncated).unwrap();
+        assert!(elf.read_progheaders(truncated).unwrap_err().contains("outside of the file"));
+
+        // .bss can only make a segment larger in memory
+        let mut shrunk: Vec<u8> = buf.clone();
+        shrunk[ElfHeader::SIZE + ProgHeader::PMEMSZ_OFF] = 2;
+        let mut elf: Elf = Elf::new();
+        elf.read_header(&shrunk).unwrap();
+        assert!(elf.read_progheaders(&shrunk).unwrap_err().contains("larger in the file"));
+
+        // A segment cannot wrap around the address space
+        let mut wrapping: Vec<u8> = buf.clone();
+        wrapping[ElfHeader::SIZE + ProgHeader::PPADDR_OFF + 1] = 0x10;
+        wrapping[ElfHeader::SIZE + ProgHeader::PMEMSZ_OFF..ElfHeader::SIZE + ProgHeader::PMEMSZ_OFF + 8].copy_from_slice(&u64::MAX.to_le_bytes());
+        let mut elf: Elf = Elf::new();
+        elf.read_header(&wrapping).unwrap();
+        assert!(elf.read_progheaders(&wrapping).unwrap_err().contains("end of the address space"));
+    }
+
+    #[test]
+    fn elf32_test() {
+        let mut buf: Vec<u8> = vec![0; ElfHeader::SIZE32 + ProgHeader::SIZE32 + 4];
+        buf[0..8].copy_from_slice(&[0x7f, b'E', b'L', b'F', 1, 1, 1, 0]);
+        buf[ElfHeader::ETYPE_OFF] = 2;
+        buf[ElfHeader::EMACHINE_OFF] = 0xf3;
+        buf[ElfHeader::EVERSION_OFF] = 1;
+        buf[ElfHeader::EENTRY_OFF32 + 2] = 0x01;
+        buf[ElfHeader::EPHOFF_OFF32] = ElfHeader::SIZE32 as u8;
+        buf[ElfHeader::EPHENTSIZE_OFF32] = ProgHeader::SIZE32 as u8;
+        buf[ElfHeader::EPHNUM_OFF32] = 1;
+        let phdr: usize = ElfHeader::SIZE32;
+        buf[phdr + ProgHeader::PTYPE_OFF32] = 1;
+        buf[phdr + ProgHeader::PFLAGS_OFF32] = 5;
+        buf[phdr + ProgHeader::POFFSET_OFF32] = (ElfHeader::SIZE32 + ProgHeader::SIZE32) as u8;
+        buf[phdr + ProgHeader::PPADDR_OFF32 + 2] = 0x01;
+        buf[phdr + ProgHeader::PFILESZ_OFF32] = 4;
+        buf[phdr + ProgHeader::PMEMSZ_OFF32] = 4;
+
+        let mut elf: Elf = Elf::new();
+        assert_eq!(elf.read_header(&buf), Ok(0x10000));
+        assert_eq!(elf.get_xlen(), Xlen::Rv32);
+        assert_eq!(elf.read_progheaders(&buf), Ok(()));
+        let addr_space: AddressSpace = elf.get_addrspace();
+        assert_eq!(addr_space.text_start, 0x10000);
+        assert_eq!(addr_space.segments, [Segment {
+            address: 0x10000, offset: ElfHeader::SIZE32 + ProgHeader::SIZE32, file_size: 4, mem_size: 4,
+            readable: true, executable: true, writable: false
+        }]);
+        // The segment does not load the program headers
+        assert_eq!((addr_space.phdr, addr_space.phnum), (None, 1));
+    }
+}
@@ -1,23 +1,176 @@
 use std::time::Duration;
 use colored::Colorize;
-use crate::cpu::Cpu;
-use crate::elf::{Elf, AddressSpace};
+use crate::cache::{CacheConfig, CacheSim};
+use crate::bus::{InterruptLine, Permissions};
+use crate::cancel::CtrlCGuard;
+use crate::codewatch::CodeWatch;
+use crate::coverage::{Coverage, CoverageFormat};
+use crate::taint::{TaintSource, TaintTracker};
+use crate::{error, info};
+use crate::cpu::{Cpu, ExitReason, FetchFaultAction, Instruction, RegIndex, Xlen};
+use crate::fpu::FloatMode;
+use crate::device::Device;
+use crate::disas;
+use crate::elf::{Elf, AddressSpace, Section};
+use crate::expr::{parse_number, parse_register, Condition, Value};
+use crate::image::{Image, ImageFormat, LoadSpec};
+use crate::inputlog::InputLog;
+use crate::machine::MachineConfig;
+use crate::memory::{AccessSize, DumpFormat, MemoryInit};
+use crate::plugin::{PluginDevice, PluginSpec};
+use crate::profiling::PredictorKind;
+use crate::replay::{self, ReplayOutcome};
+use crate::signature::Signature;
+use crate::stackguard::{StackLimit, StackOverflow};
+use crate::htif::Htif;
+use crate::finisher::TestFinisher;
+use crate::history::History;
+use crate::syscall::SyscallEmulator;
+use crate::initstack::InitialStack;
+use crate::semihosting::Semihosting;
+#[cfg(not(target_arch = "wasm32"))]
+use crate::script::Script;
+use crate::sbi::Sbi;
+use crate::step::StepReport;
+use crate::symbols::SymbolMap;
+use crate::prompt::{Completion, Prompt};
+use crate::breakpoint::Breakpoints;
+use crate::timebase::{TimeSource, Timebase};
+use crate::pacing::{Pacer, Pacing};
+use crate::events::HostEvents;
+use crate::trace::{SharedSink, Tracer};
+use crate::memtrace::MemTrace;
+use crate::virtio::VirtioBlock;
+use crate::console::SharedConsoleLog;
+use crate::cli::{BufferConsole, GuestStdio, SharedHostConsole};
+use crate::harness::{RunLimits, RunOutcome};
+use crate::uart::UartSpec;
+use crate::snapshot::{Snapshot, SnapshotReader, SnapshotWriter};
 use std::fs::File;
-use std::io::{Read, Write};
+use std::io::Read;
+use std::ops::Range;
 use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+/// How the examine command of the interactive mode shows the values
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ExamineFormat {
+    // Hexadecimal, followed by the unsigned decimal value
+    Default,
+    Hex,
+    // Signed decimal
+    Decimal,
+    // The bytes as ASCII characters, in memory order
+    Char
+}
+
+impl ExamineFormat {
+    /// Show a value read from memory with an access of the given size
+    pub fn show(self, value: u64, size: AccessSize) -> String {
+        let bits: u32 = 8 * size.bytes() as u32;
+        match self {
+            ExamineFormat::Default => format!("0x{:0>width$x} ({})", value, value, width = 2 * size.bytes()),
+            ExamineFormat::Hex => format!("0x{:0>width$x}", value, width = 2 * size.bytes()),
+            // Sign-extend the value from its size
+            ExamineFormat::Decimal => format!("{}", ((value << (64 - bits)) as i64) >> (64 - bits)),
+            ExamineFormat::Char => {
+                let chars: String = value.to_le_bytes()[..size.bytes()].iter()
+                    .flat_map(|byte| std::ascii::escape_default(*byte))
+                    .map(char::from)
+                    .collect();
+                format!("'{}'", chars)
+            }
+        }
+    }
+}
 
 /// Emulator is just a wrapper for a CPU
 /// It might contain a cluster of CPU in the future?
+#[derive(Clone)]
 pub struct Emulator {
     cpu: Cpu,
+    symbols: SymbolMap,
+    // First address after the data of the loaded program
+    program_end: u64,
+    // Entries of the auxiliary vector that describe the loaded program,
+    // for the initial stack of user-space programs
+    auxv: Vec<(u64, u64)>,
+    // Regions that hold code: the text of the program and the boot ROM
+    executable_regions: Vec<Range<u64>>,
+    // Symbols of the compliance test being run, if any
+    signature: Option<Signature>,
+    // Checkpoints taken in interactive mode, to go back with rstep and rcontinue
+    history: Option<History<Cpu>>,
+    // Expressions printed after every step of the interactive mode, with their text
+    displays: Vec<(String, Value)>,
+    // Breakpoints of the interactive mode
+    breakpoints: Breakpoints,
+    // Speed limit of the runs, if any
+    pacer: Option<Pacer>,
+    // Ctrl-C stops the runs, and the last one was stopped by it
+    break_on_ctrl_c: bool,
+    interrupted: bool,
+    // The last run was stopped by its timeout
+    timed_out: bool
 }
 
 impl Emulator {
+    /// Address the code given to run_bytes is loaded and started at
+    pub const CODE_ADDRESS: u64 = AddressSpace::TEXT_START_DEFAULT;
+    // Longest step that prints every executed instruction
+    const STEP_PRINT_LIMIT: u64 = 1000;
+    // Longer steps are split into chunks of this size, Ctrl-C is checked
+    // between them (and so is the timeout of a run)
+    const STEP_CHUNK: u64 = 10000;
+    // Page size given to user-space programs, the heap starts on a page boundary
+    const PAGE_SIZE: u64 = 0x1000;
+    // Largest buffer of zeros written at once for the .bss of a segment
+    const ZERO_CHUNK_SIZE: usize = 0x10000;
+    // Time between two progress reports of a long step
+    const STEP_PROGRESS_PERIOD: Duration = Duration::from_secs(1);
+    // Instructions between two checkpoints of the interactive mode (at first)
+    const CHECKPOINT_INTERVAL: u64 = 100000;
+    // Commands of the interactive mode, completed with Tab
+    const COMMANDS: [&'static str; 21] = ["s", "c", "b", "delete", "ignore", "condition", "rstep", "rcontinue", "r", "bt",
+                                          "d", "disas", "x", "x/", "set", "time", "irq", "display", "undisplay", "q", "h"];
+    // Commands of the interactive mode that an empty line repeats, as in gdb
+    const REPEATED_COMMANDS: [&'static str; 6] = ["s", "rstep", "r", "bt", "disas", "x/"];
 
     /// Create a new emulator with a certain memory size (DRAM)
     pub fn new(memsize: Option<usize>) -> Emulator {
         Emulator {
-            cpu: Cpu::new(memsize)
+            cpu: Cpu::new(memsize),
+            symbols: SymbolMap::new(),
+            program_end: 0,
+            auxv: Vec::new(),
+            executable_regions: Vec::new(),
+            signature: None,
+            history: None,
+            displays: Vec::new(),
+            breakpoints: Breakpoints::new(),
+            pacer: None,
+            break_on_ctrl_c: false,
+            interrupted: false,
+            timed_out: false
+        }
+    }
+
+    /// Create a new emulator for a machine with a fixed memory map
+    pub fn with_machine(machine: &MachineConfig) -> Emulator {
+        Emulator {
+            cpu: Cpu::with_machine(machine),
+            symbols: SymbolMap::new(),
+            program_end: 0,
+            auxv: Vec::new(),
+            executable_regions: Vec::new(),
+            signature: None,
+            history: None,
+            displays: Vec::new(),
+            breakpoints: Breakpoints::new(),
+            pacer: None,
+            break_on_ctrl_c: false,
+            interrupted: false,
+            timed_out: false
         }
     }
 
@@ -27,48 +180,224 @@ impl Emulator {
         let filepath: &Path = Path::new(filename);
         let display = filepath.display();
         let mut filebuffer: Vec<u8> = Vec::new();
-        let mut elf_file = Elf::new();
 
         // Try to open the file
-        let mut file = match File::open(&filepath) {
-            Err(why) => panic!("Could not open {}: {}", display, why),
+        let mut file = match File::open(filepath) {
+            Err(why) => return Err(format!("Could not open {}: {}", display, why)),
             Ok(file) => file,
         };
 
         // Try to read the file to the end and copy it into a heap-allocated buffer
-        match file.read_to_end(&mut filebuffer) {
-            Err(why) => panic!("Could not read {}: {}", display, why),
-            Ok(_) => ()
+        if let Err(why) = file.read_to_end(&mut filebuffer) {
+            return Err(format!("Could not read {}: {}", display, why));
         }
+        self.load_program_bytes(&filebuffer)
+    }
+
+    /// Load an ELF executable that is already in memory (e.g. when there
+    /// is no file system) and setup the CPU for its execution
+    pub fn load_program_bytes(&mut self, filebuffer: &[u8]) -> Result<(), String> {
+        let mut elf_file = Elf::new();
 
         // Read ELF header and obtain entry point
-        let entry_point: u64;
-        match elf_file.read_header(&filebuffer) {
-            Ok(entry) => entry_point = entry,
-            Err(err_string) => return Err(err_string),
-        }
+        let entry_point: u64 = elf_file.read_header(filebuffer)?;
+        // ELF32 executables run in RV32 mode
+        self.cpu.set_xlen(elf_file.get_xlen());
 
         // Read all the program headers to set the address space
-        elf_file.read_progheaders(&filebuffer);
+        elf_file.read_progheaders(filebuffer)?;
+        // Sections and symbols are only used to describe addresses
+        elf_file.read_sectionheaders(filebuffer);
         // Get the address space
         let addr_space: AddressSpace = elf_file.get_addrspace();
 
-        // Set the read-only memory offset (address at which the read only memory starts)
-        self.cpu.set_read_only_segment(addr_space.read_execute_segment as u64);
-        // Set the read-write memory offset
-        self.cpu.set_read_write_segment(addr_space.read_write_segment as u64);
-        // Copy the read-execute segment in the file into the read only memory of the CPU
-        self.cpu.store_from_buffer(&filebuffer[addr_space.read_execute_offset..
-                                                    addr_space.read_execute_offset
-                                                    + addr_space.read_execute_size],
-                                   addr_space.read_execute_segment as u64);
-
-        // Copy the read-write segment from the file into the DRAM of the CPU
-        self.cpu.store_from_buffer(&filebuffer[addr_space.read_write_offset..
-                                                    addr_space.read_write_offset
-                                                    + addr_space.read_write_size],
-                              addr_space.read_write_segment as u64);
+        // Place the ROM at the lowest read-only segment and the DRAM at the lowest writable one
+        self.cpu.set_read_only_segment(addr_space.text_start);
+        self.cpu.set_read_write_segment(addr_space.data_start);
+        self.store_segments(filebuffer, &addr_space)?;
+        // The free memory above the program is left to the stack
+        self.program_end = addr_space.segments.iter()
+            .filter(|segment| segment.address >= addr_space.data_start)
+            .map(|segment| segment.end())
+            .fold(addr_space.data_start, u64::max);
+
+        self.setup_registers(entry_point);
+        if let Some(phdr) = addr_space.phdr {
+            self.auxv.extend([(InitialStack::AT_PHDR, phdr), (InitialStack::AT_PHENT, addr_space.phentsize),
+                              (InitialStack::AT_PHNUM, addr_space.phnum)]);
+        }
+
+        // Name the regions of memory for diagnostics: the sections if the
+        // executable has them, otherwise its segments, and the free memory
+        // above the program, where the stack grows
+        let sections: Vec<Section> = elf_file.get_sections(filebuffer);
+        if sections.is_empty() {
+            for segment in &addr_space.segments {
+                let name: &str = match (segment.executable, segment.writable) {
+                    (true, _) => "text segment",
+                    (false, true) => "data segment",
+                    (false, false) => "read-only segment"
+                };
+                self.symbols.add_region(segment.address, segment.mem_size as u64, name);
+            }
+        }
+        for section in &sections {
+            self.symbols.add_region(section.address, section.size, &section.name);
+        }
+        self.add_stack_region();
+        for (address, name) in elf_file.get_symbols(filebuffer) {
+            self.symbols.insert(address, &name);
+        }
+        // The linker defines where gp points for gp-relative accesses
+        if let Some(global_pointer) = self.symbols.address_of("__global_pointer$") {
+            self.cpu.write_reg(Cpu::GLOBAL_POINTER, global_pointer);
+        }
+        // Programs built for spike talk to the host through HTIF
+        if let Some(tohost) = self.symbols.address_of("tohost") {
+            self.cpu.enable_htif(Htif::new(tohost, self.symbols.address_of("fromhost")));
+        }
+        self.cpu.get_tracer().set_symbols(self.symbols.clone());
+        Ok(())
+
+    }
+
+    // Copy the segments of an ELF from the file into memory (the ROM grows
+    // to fit the segments below the DRAM)
+    fn store_segments(&mut self, filebuffer: &[u8], addr_space: &AddressSpace) -> Result<(), String> {
+        for segment in &addr_space.segments {
+            self.cpu.store_from_buffer(&filebuffer[segment.offset..segment.offset + segment.file_size], segment.address)
+                .map_err(|err| format!("Program does not fit in memory: {}", err))?;
+            // Zero the part of the segment that is not stored in the file
+            // (.bss), as the DRAM might have been initialized with something
+            // else. It goes a chunk at a time, so that a bogus size fails at
+            // the end of the memory instead of allocating all of it
+            let zeros: Vec<u8> = vec![0; Emulator::ZERO_CHUNK_SIZE.min(segment.mem_size - segment.file_size)];
+            let mut address: u64 = segment.address + segment.file_size as u64;
+            while address < segment.end() {
+                let len: usize = zeros.len().min((segment.end() - address) as usize);
+                self.cpu.store_from_buffer(&zeros[..len], address)
+                    .map_err(|err| format!("Program does not fit in memory: {}", err))?;
+                address += len as u64;
+            }
+            self.cpu.set_permissions(segment.address..segment.end(),
+                                     Permissions { read: segment.readable, write: segment.writable, execute: segment.executable });
+            if segment.executable {
+                self.executable_regions.push(segment.address..segment.end());
+            }
+        }
+        Ok(())
+    }
+
+    /// Place another file into memory next to the program (e.g. a kernel,
+    /// an initrd or a device tree for a boot loader), as given by spec:
+    /// its bytes at the address, or the segments of an ELF executable
+    /// (whose symbols are added to the ones of the program). It has to be
+    /// called after loading the program, which still decides where the
+    /// execution starts. It returns the range of addresses it takes
+    pub fn load_extra_image(&mut self, spec: &LoadSpec) -> Result<Range<u64>, String> {
+        let filename: &str = &spec.filename;
+        let contents: Vec<u8> = std::fs::read(filename).map_err(|why| format!("Could not read {}: {}", filename, why))?;
+        let range: Range<u64> = match spec.address {
+            Some(address) => {
+                let end: u64 = address.checked_add(contents.len() as u64)
+                    .ok_or(format!("{} does not fit in memory at 0x{:x}", filename, address))?;
+                self.cpu.store_from_buffer(&contents, address)
+                    .map_err(|err| format!("{} does not fit in memory: {}", filename, err))?;
+                let name: &str = Path::new(filename).file_name().and_then(|name| name.to_str()).unwrap_or(filename);
+                self.symbols.add_region(address, end - address, name);
+                address..end
+            },
+            None if Elf::is_elf(&contents) => {
+                let mut elf_file = Elf::new();
+                elf_file.read_header(&contents).map_err(|err| format!("{}: {}", filename, err))?;
+                elf_file.read_progheaders(&contents).map_err(|err| format!("{}: {}", filename, err))?;
+                elf_file.read_sectionheaders(&contents);
+                let addr_space: AddressSpace = elf_file.get_addrspace();
+                self.store_segments(&contents, &addr_space)
+                    .map_err(|err| format!("{}: {}", filename, err))?;
+                for section in elf_file.get_sections(&contents) {
+                    self.symbols.add_region(section.address, section.size, &section.name);
+                }
+                for (address, name) in elf_file.get_symbols(&contents) {
+                    self.symbols.insert(address, &name);
+                }
+                let start: u64 = addr_space.segments.iter().map(|segment| segment.address).min().unwrap_or(0);
+                let end: u64 = addr_space.segments.iter().map(|segment| segment.end()).max().unwrap_or(0);
+                start..end
+            },
+            None => return Err(format!("{} is not an ELF executable: give the address to load it at (<file>@<address>)", filename))
+        };
+        // The stack starts above anything loaded in the DRAM
+        let dram: Range<u64> = self.cpu.get_read_write_segment()..self.cpu.get_read_write_segment() + self.cpu.get_read_write_memsize() as u64;
+        if dram.contains(&range.start) && range.end > self.program_end {
+            self.program_end = range.end;
+            self.symbols.remove_region("stack");
+            self.add_stack_region();
+        }
+        self.cpu.get_tracer().set_symbols(self.symbols.clone());
+        Ok(range)
+    }
+
+    /// Load a program in any of the supported formats. Firmware images
+    /// (raw binaries, Intel HEX and S-records) have no segments: the ROM
+    /// starts at their lowest address and the DRAM right after them.
+    /// A raw binary is loaded at load_address, the other formats carry
+    /// their own addresses
+    pub fn load_image(&mut self, filename: &str, format: ImageFormat, load_address: u64) -> Result<(), String> {
+        let contents: Vec<u8> = match format {
+            ImageFormat::Elf => return self.load_program(filename),
+            _ => std::fs::read(filename).map_err(|why| format!("Could not read {}: {}", filename, why))?
+        };
+        let image: Image = Emulator::parse_image(contents, format, load_address)
+            .map_err(|err| format!("Invalid {} file {}: {}", format, filename, err))?;
+        self.place_image(&image)
+    }
+
+    /// Load a program in any of the supported formats from its contents,
+    /// as load_image does with a file
+    pub fn load_image_bytes(&mut self, contents: Vec<u8>, format: ImageFormat, load_address: u64) -> Result<(), String> {
+        if format == ImageFormat::Elf {
+            return self.load_program_bytes(&contents);
+        }
+        let image: Image = Emulator::parse_image(contents, format, load_address)
+            .map_err(|err| format!("Invalid {} image: {}", format, err))?;
+        self.place_image(&image)
+    }
+
+    // Decode the contents of a firmware image (not an ELF)
+    fn parse_image(contents: Vec<u8>, format: ImageFormat, load_address: u64) -> Result<Image, String> {
+        let text = |contents: &[u8]| std::str::from_utf8(contents).map(str::to_string).map_err(|_| "not a text file".to_string());
+        match format {
+            ImageFormat::Bin => Ok(Image::from_binary(contents, load_address)),
+            ImageFormat::Hex => text(&contents).and_then(|text| Image::from_ihex(&text)),
+            _ => text(&contents).and_then(|text| Image::from_srec(&text))
+        }
+    }
+
+    // Copy a firmware image into memory and setup the CPU to run it
+    fn place_image(&mut self, image: &Image) -> Result<(), String> {
+        let (start, end): (u64, u64) = (image.start(), image.end());
+
+        // Keep the stack aligned, the DRAM top is where it starts
+        self.cpu.set_read_only_segment(start);
+        self.cpu.set_read_write_segment((end + 0xf) & !0xf);
+        for chunk in &image.chunks {
+            self.cpu.store_from_buffer(&chunk.data, chunk.address)
+                .map_err(|err| format!("Program does not fit in memory: {}", err))?;
+        }
+        // Code and data are not told apart in an image
+        self.executable_regions.push(start..end);
+        self.program_end = end.max(self.cpu.get_read_write_segment());
+
+        self.setup_registers(image.entry.unwrap_or(start));
+        self.symbols.add_region(start, end - start, "image");
+        self.add_stack_region();
+        self.cpu.get_tracer().set_symbols(self.symbols.clone());
+        Ok(())
+    }
 
+    // Start from the entry point with the stack at the top of the DRAM
+    fn setup_registers(&mut self, entry_point: u64) {
         // Set initial value of the PC
         self.cpu.set_pc(entry_point);
 
@@ -76,29 +405,694 @@ impl Emulator {
         // nowhere else to return but this value then the emulator will stop executing instructions
         self.cpu.write_reg(Cpu::RETURN_REGISTER, Cpu::SENTINEL_RETURN_ADDRESS);
 
-        // Set SP to the last address in the DRAM
-        self.cpu.set_stack_pointer(addr_space.read_write_segment as u64 + self.cpu.get_read_write_memsize() as u64);
+        // Set SP to the last address in the DRAM (with a fixed memory map
+        // the DRAM does not move to the data segment)
+        let dram_start: u64 = self.cpu.get_read_write_segment();
+        self.cpu.set_stack_pointer(dram_start + self.cpu.get_read_write_memsize() as u64);
+
+        // GP is set from __global_pointer$ if the executable has it,
+        // otherwise the startup code of the program has to set it
+        self.cpu.write_reg(Cpu::GLOBAL_POINTER, 0);
+        self.auxv = vec![(InitialStack::AT_PAGESZ, Emulator::PAGE_SIZE), (InitialStack::AT_ENTRY, entry_point)];
+    }
+
+    /// Where the heap of the program starts: the first page after its
+    /// data, as the program break of Linux
+    pub fn get_heap_start(&self) -> u64 {
+        (self.program_end + Emulator::PAGE_SIZE - 1) & !(Emulator::PAGE_SIZE - 1)
+    }
+
+    // Name the free memory above the program, where the stack grows
+    fn add_stack_region(&mut self) {
+        let stack_top: u64 = self.cpu.get_read_write_segment() + self.cpu.get_read_write_memsize() as u64;
+        self.add_stack_region_below(stack_top);
+    }
+
+    fn add_stack_region_below(&mut self, stack_top: u64) {
+        if self.program_end < stack_top {
+            self.symbols.add_region(self.program_end, stack_top - self.program_end, "stack");
+        }
+    }
+
+    /// Load a boot ROM image from a raw binary file and start the execution
+    /// from it, as a mask ROM would at reset. The ROM gets the entry point
+    /// of the program in a0, so it has to be called after loading the
+    /// program. It returns the size of the image
+    pub fn load_rom(&mut self, filename: &str) -> Result<usize, String> {
+        let image: Vec<u8> = match std::fs::read(filename) {
+            Ok(image) => image,
+            Err(why) => return Err(format!("Could not read {}: {}", filename, why))
+        };
+        self.cpu.load_boot_rom(&image)?;
+        let base: u64 = self.cpu.get_boot_rom_base();
+        self.start_from_reset_vector(base);
+        self.executable_regions.push(base..base + image.len() as u64);
+        Ok(image.len())
+    }
+
+    /// Start the program from entry instead of the entry point of the
+    /// executable. It has to be called after loading the program
+    pub fn set_entry_point(&mut self, entry: u64) {
+        self.cpu.set_pc(entry);
+    }
 
-        // Set GP to the middle address in the DRAM
-        // TODO: check if this is correct? Seems like it is, but not 100% sure
-        self.cpu.write_reg(Cpu::GLOBAL_POINTER,
-                     addr_space.read_write_segment as u64 + (self.cpu.get_read_write_memsize() as u64)/2);
+    /// Start the execution from the reset vector, as a SoC whose boot code
+    /// jumps to the program in flash: the code there has been preloaded
+    /// with the program (e.g. in a ROM region of the machine) and it gets
+    /// the entry point in a0, as a boot ROM does. It has to be called
+    /// after loading the program
+    pub fn set_reset_vector(&mut self, reset_vector: u64) -> Result<(), String> {
+        if self.cpu.peek(reset_vector, AccessSize::HALFWORD).is_err() {
+            return Err(format!("There is no memory at the reset vector 0x{:x}", reset_vector));
+        }
+        self.start_from_reset_vector(reset_vector);
         Ok(())
+    }
+
+    // Pass the entry point of the program in a0 and start from the reset vector
+    fn start_from_reset_vector(&mut self, reset_vector: u64) {
+        let entry_point: u64 = self.cpu.get_pc();
+        self.cpu.write_reg(Cpu::ARGUMENT_REGISTER, entry_point);
+        self.cpu.set_pc(reset_vector);
+    }
+
+    /// Flattened device tree of the machine: the RAM, the interrupt
+    /// controllers and the devices (see fdt.rs)
+    pub fn device_tree(&self) -> Vec<u8> {
+        self.cpu.describe_platform().to_fdt()
+    }
+
+    /// Pass the device tree to the program as boot loaders do: it is placed
+    /// at the top of the DRAM, with the stack starting below it, and the
+    /// program gets the hart ID in a0 and its address in a1. It has to be
+    /// called after loading the program and attaching the devices, and
+    /// before loading a boot ROM (which gets the entry point in a0).
+    /// It returns the address and the size of the tree
+    pub fn install_device_tree(&mut self) -> Result<(u64, usize), String> {
+        let blob: Vec<u8> = self.device_tree();
+        let dram_end: u64 = self.cpu.get_read_write_segment() + self.cpu.get_read_write_memsize() as u64;
+        let address: u64 = dram_end.checked_sub(blob.len() as u64)
+            .map(|address| address & !0x7)
+            .filter(|address| *address >= self.program_end)
+            .ok_or_else(|| format!("The device tree ({} bytes) does not fit in memory above the program", blob.len()))?;
+        self.cpu.store_from_buffer(&blob, address)
+            .map_err(|err| format!("Could not store the device tree: {}", err))?;
+        self.cpu.write_reg(Cpu::ARGUMENT_REGISTER, 0);
+        self.cpu.write_reg(Cpu::SECOND_ARGUMENT_REGISTER, address);
+        let stack_top: u64 = address & !0xf;
+        self.cpu.set_stack_pointer(stack_top);
+        self.symbols.remove_region("stack");
+        self.add_stack_region_below(stack_top);
+        self.symbols.add_region(address, blob.len() as u64, "device tree");
+        self.cpu.get_tracer().set_symbols(self.symbols.clone());
+        Ok((address, blob.len()))
+    }
+
+    /// Boot the program as an S-mode kernel (e.g. Linux or xv6) without a
+    /// firmware: the SBI calls are served by the emulator (see sbi.rs), the
+    /// device tree is passed in a1 and the execution starts in S-mode from
+    /// the entry point. It returns the address and the size of the tree
+    pub fn boot_kernel(&mut self) -> Result<(u64, usize), String> {
+        let device_tree: (u64, usize) = self.install_device_tree()?;
+        self.cpu.set_sbi_emulation(Sbi::new());
+        self.cpu.enter_supervisor();
+        Ok(device_tree)
+    }
+
+    /// Attach a device to the bus at base, with its interrupt connected
+    /// to a PLIC source (if any)
+    pub fn add_device(&mut self, base: u64, irq: Option<usize>, device: Box<dyn Device>) -> Result<(), String> {
+        self.cpu.add_device(base, irq, device)
+    }
+
+    /// Attach another UART, talking to console if given, e.g. a data port
+    /// next to the console of the machine
+    pub fn add_uart(&mut self, spec: UartSpec, console: Option<SharedHostConsole>) -> Result<(), String> {
+        self.cpu.add_uart(spec, console)
+    }
+
+    /// Attach a device to the bus in the window [base, base + size), e.g.
+    /// another instance of a built-in device
+    pub fn attach_device(&mut self, device: Box<dyn Device>, base: u64, size: u64) -> Result<(), String> {
+        self.cpu.attach_device(device, base, size)
+    }
+
+    /// Attach a disk image file as a virtio block device. It returns
+    /// the address of the device and the size of the disk in sectors
+    pub fn attach_drive(&mut self, filename: &str) -> Result<(u64, u64), String> {
+        let drive: VirtioBlock = VirtioBlock::open(filename)?;
+        let capacity: u64 = drive.capacity();
+        self.cpu.attach_drive(drive)?;
+        Ok((self.cpu.get_virtio_base(), capacity))
+    }
+
+    /// Write the disk of the drive back to its image file, if the program
+    /// changed it
+    pub fn save_drive(&mut self) -> Result<String, String> {
+        let drive: &mut VirtioBlock = self.cpu.get_drive_mut().ok_or("No drive is attached")?;
+        if !drive.is_dirty() {
+            return Ok(format!("Disk image {} was not changed", drive.get_filename()));
+        }
+        drive.write_back()?;
+        Ok(format!("Saved the changes to disk image {}", drive.get_filename()))
+    }
+
+    /// Load a device plugin library and map its device as described by
+    /// the spec. It returns the name of the device
+    pub fn load_device_plugin(&mut self, spec: &PluginSpec) -> Result<String, String> {
+        let device: PluginDevice = PluginDevice::load(&spec.path)?;
+        let name: String = device.name().to_string();
+        self.add_device(spec.base, spec.irq, Box::new(device))?;
+        Ok(name)
+    }
+
+    /// Set the initial content of the DRAM. This has to be done
+    /// before loading the program, otherwise it would be overwritten
+    pub fn init_memory(&mut self, init: MemoryInit) {
+        self.cpu.init_memory(init);
+    }
+
+    /// Copy of the emulator that goes on from the current state on its own,
+    /// e.g. for a fuzzing harness that boots the program once and then
+    /// runs every input from there. The memory is shared copy-on-write
+    /// (on Unix): the first fork freezes it, and the next ones cost the
+    /// same whatever its size, until this emulator runs again. Each fork
+    /// only copies the pages it writes
+    pub fn fork(&mut self) -> Result<Emulator, String> {
+        self.cpu.share_memory()?;
+        Ok(self.clone())
+    }
 
+    /// Serve the system calls of the program on the host (user-mode emulation),
+    /// so that programs linked against newlib can print, read files and exit.
+    /// It has to be called after loading the program: the heap (moved by
+    /// brk) starts at the first page after the program data, and the stack
+    /// gets the arguments (args[0] is the name of the program), the
+    /// environment (NAME=VALUE strings) and the auxiliary vector, as the
+    /// psABI and crt0 expect (see InitialStack)
+    pub fn enable_syscall_emulation(&mut self, args: &[String], env: &[String]) {
+        let mut stack: InitialStack = InitialStack::new(args, env);
+        for (key, value) in &self.auxv {
+            stack.push_aux(*key, *value);
+        }
+        let top: u64 = self.cpu.read_reg(Cpu::STACK_POINTER);
+        let stack_pointer: u64 = match stack.build(top, self.cpu.get_xlen()) {
+            Some((stack_pointer, bytes)) => {
+                // A failure here means that the DRAM is too small even for the
+                // initial stack, the program will fault as soon as it uses it
+                let _ = self.cpu.store_from_buffer(&bytes, stack_pointer);
+                stack_pointer
+            },
+            None => top
+        };
+        self.cpu.set_stack_pointer(stack_pointer);
+        self.cpu.set_syscall_emulation(SyscallEmulator::new(self.get_heap_start(), stack_pointer));
+    }
+
+    /// Confine the files the program opens with system calls to a host
+    /// directory, which becomes its root. It has to be called after
+    /// enable_syscall_emulation
+    pub fn set_fs_root(&mut self, root: &str) -> Result<(), String> {
+        match self.cpu.get_syscall_emulation_mut() {
+            Some(syscalls) => syscalls.set_root(root),
+            None => Err("The file system root needs the system call emulation".to_string())
+        }
+    }
+
+    /// Serve the semihosting calls of the program on the host, passing it
+    /// the given command line. Its heap goes from the end of the program
+    /// to the stack. It has to be called after loading the program
+    pub fn enable_semihosting(&mut self, cmdline: &str) {
+        let stack_pointer: u64 = self.cpu.read_reg(Cpu::STACK_POINTER);
+        self.cpu.set_semihosting(Semihosting::new(cmdline, self.get_heap_start(), stack_pointer));
+    }
+
+    /// Map the test finisher at base: a store of PASS or FAIL to it ends
+    /// the run with the corresponding exit code
+    pub fn enable_test_finisher(&mut self, base: u64) -> Result<(), String> {
+        self.cpu.enable_test_finisher(TestFinisher::new(base))
+    }
+
+    /// Load an external symbol file (nm output or GNU ld map) so that
+    /// debug output can be symbolized even for stripped executables.
+    /// Its symbols replace the ones found in the executable.
+    /// It returns the number of loaded symbols
+    pub fn load_symbols(&mut self, filename: &str) -> Result<usize, String> {
+        self.symbols.replace_symbols(SymbolMap::from_file(filename)?);
+        self.cpu.get_tracer().set_symbols(self.symbols.clone());
+        Ok(self.symbols.len())
+    }
+
+    /// Run the program as a compliance test: the execution stops when it
+    /// exits through tohost and its signature can be written at the end with
+    /// write_signature. It has to be called after loading the program,
+    /// whose symbol table must have tohost, begin_signature and end_signature
+    pub fn enable_signature(&mut self) -> Result<Signature, String> {
+        let signature: Signature = Signature::from_symbols(&self.symbols)?;
+        self.cpu.enable_htif(Htif::new(signature.tohost, self.symbols.address_of("fromhost")));
+        self.signature = Some(signature);
+        Ok(signature)
+    }
+
+    /// Write the signature of the compliance test to a file, in the format
+    /// of the reference signatures of RISCOF
+    pub fn write_signature(&self, filename: &str) -> Result<String, String> {
+        let signature: &Signature = self.signature.as_ref().ok_or("The signature mode is not enabled")?;
+        let data: Vec<u8> = self.read_memory(signature.begin, signature.len())
+            .map_err(|err| format!("Could not read the signature: {}", err))?;
+        match std::fs::write(filename, Signature::encode(&data)) {
+            Err(why) => Err(format!("Could not write the signature to {}: {}", filename, why)),
+            Ok(()) => Ok(format!("Saved the signature ({} bytes) to {}", signature.len(), filename))
+        }
+    }
+
+    /// Profile the executed instructions (mnemonics, branches, loads and
+    /// stores, hottest PCs), to be printed after the run
+    pub fn enable_profile(&mut self) {
+        self.cpu.enable_profile();
+    }
+
+    /// Record the instruction addresses executed by the program and how
+    /// many times, to be saved with write_coverage
+    pub fn enable_coverage(&mut self) {
+        self.cpu.enable_coverage();
+    }
+
+    /// Save the coverage of the run to a file. The lcov format takes the
+    /// source lines from the ELF executable the program was loaded from
+    pub fn write_coverage(&self, filename: &str, format: CoverageFormat, program: &str) -> Result<String, String> {
+        let coverage: &Coverage = self.cpu.get_coverage().ok_or("The coverage is not enabled")?;
+        let contents: Vec<u8> = std::fs::read(program).map_err(|why| format!("Could not read {}: {}", program, why))?;
+        coverage.write_to_file(filename, format, &contents)?;
+        Ok(format!("Saved the coverage of {} instruction addresses to {}", coverage.executed().len(), filename))
+    }
+
+    /// Run a branch predictor model on the conditional branches of the
+    /// program and record its accuracy on each of them
+    pub fn enable_branch_predictor(&mut self, kind: PredictorKind) {
+        self.cpu.enable_branch_predictor(kind.create());
+    }
+
+    /// Simulate an I$ and a D$ with the given geometries and count their
+    /// hits and misses. The execution is not affected
+    pub fn enable_cache_sim(&mut self, icache: CacheConfig, dcache: CacheConfig) {
+        self.cpu.enable_cache_sim(CacheSim::new(icache, dcache));
+    }
+
+    /// Report stack buffer overruns with the instruction that caused them
+    pub fn enable_stack_guard(&mut self) {
+        self.cpu.enable_stack_guard();
+    }
+
+    /// Place a guard region below the stack, which starts at the current
+    /// stack pointer and can take limit.size bytes: the program is stopped
+    /// when it accesses the region. It has to be called after loading the
+    /// program, and returns the range of the guard region
+    pub fn set_stack_limit(&mut self, limit: StackLimit) -> Result<Range<u64>, String> {
+        let sp: u64 = self.cpu.read_reg(Cpu::STACK_POINTER);
+        let region: Range<u64> = limit.guard_region(sp)
+            .ok_or(format!("A stack of 0x{:x} bytes and its guard region do not fit below sp = 0x{:x}", limit.size, sp))?;
+        if region.start < self.program_end && region.end > self.cpu.get_read_write_segment() {
+            return Err(format!("The stack guard region 0x{:x}-0x{:x} overlaps the program, which ends at 0x{:x}",
+                               region.start, region.end, self.program_end));
+        }
+        self.cpu.set_stack_guard_region(region.clone());
+        self.symbols.add_region(region.start, region.end - region.start, "stack guard");
+        self.cpu.get_tracer().set_symbols(self.symbols.clone());
+        Ok(region)
+    }
+
+    /// If the execution was stopped because the stack grew into its guard
+    /// region, describe the access that got there
+    pub fn describe_stack_overflow(&self) -> Option<String> {
+        let overflow: StackOverflow = self.cpu.get_stack_overflow()?;
+        Some(format!("Stack overflow: the instruction at 0x{:0>16x}{} accessed 0x{:0>16x} in the guard region, sp = 0x{:0>16x}",
+                     overflow.pc, self.symbols.annotate(overflow.pc), overflow.address, overflow.sp))
+    }
+
+    /// Report the stores into the code of the program and of the boot ROM.
+    /// It has to be called after loading them
+    pub fn enable_code_watch(&mut self) {
+        let mut watch: CodeWatch = CodeWatch::new();
+        for region in self.executable_regions.iter() {
+            watch.add_region(region.clone());
+        }
+        self.cpu.enable_code_watch(watch);
+    }
+
+    /// Follow the data that comes from the sources (the data register of
+    /// the UART or ranges of memory) through registers and memory, and
+    /// report when it is used as a jump target or stored into the sinks
+    pub fn enable_taint(&mut self, sources: &[TaintSource], sinks: &[Range<u64>]) {
+        let mut taint: TaintTracker = TaintTracker::new();
+        for source in sources {
+            match source {
+                TaintSource::Uart => taint.add_source(self.cpu.get_uart_base()..self.cpu.get_uart_base() + 1),
+                TaintSource::Memory(range) => taint.add_source(range.clone())
+            }
+        }
+        for sink in sinks {
+            taint.add_sink(sink.clone());
+        }
+        self.cpu.enable_taint(taint);
+    }
+
+    /// Copy the console output of the program to a log, with timestamps
+    pub fn set_console_log(&mut self, log: SharedConsoleLog) {
+        self.cpu.set_console_log(log);
+    }
+
+    /// Show the console output of the program on another host console
+    /// than the terminal, and take its input from there
+    pub fn set_host_console(&mut self, console: SharedHostConsole) {
+        self.cpu.set_host_console(console);
+    }
+
+    /// Redirect the standard streams of the program (system calls and
+    /// semihosting) to files. The console of the UART is not affected:
+    /// it is redirected with set_host_console
+    pub fn set_stdio(&mut self, stdio: GuestStdio) {
+        self.cpu.set_stdio(stdio);
+    }
+
+    /// Send the trace records to another sink, in addition to the ones
+    /// that were already added
+    pub fn add_trace_sink(&mut self, sink: SharedSink) {
+        self.cpu.get_tracer().add_sink(sink);
+    }
+
+    /// Trace the loads and stores that go through the bus, only the ones
+    /// that touch filter if given. It has to be called after adding the sinks
+    pub fn enable_mem_trace(&mut self, filter: Option<Range<u64>>) {
+        let tracer: Tracer = self.cpu.get_tracer().clone();
+        self.cpu.enable_mem_trace(MemTrace::new(filter, tracer));
+    }
+
+    /// Trace every executed instruction with its disassembly
+    pub fn set_trace_exec(&mut self, enable: bool) {
+        self.cpu.set_trace_exec(enable);
+    }
+
+    /// Log every access to the CSRs, decoding the modified fields
+    pub fn set_trace_csr(&mut self, enable: bool) {
+        self.cpu.set_trace_csr(enable);
+    }
+
+    /// Choose how the floating-point arithmetic is computed: with the
+    /// host FPU or bit-exact in software
+    pub fn set_float_mode(&mut self, mode: FloatMode) {
+        self.cpu.set_float_mode(mode);
+    }
+
+    /// Choose whether the host sleeps while the program waits for an
+    /// interrupt (WFI), or the idle time is skipped
+    pub fn set_idle_sleep(&mut self, sleep: bool) {
+        self.cpu.set_idle_sleep(sleep);
+    }
+
+    /// Choose whether the stores of the program to the segments of the
+    /// executable that are not writable (e.g. the text) raise a store
+    /// access fault, the default, or change the memory
+    pub fn set_protection(&mut self, protect: bool) {
+        self.cpu.set_protection(protect);
+    }
+
+    /// Choose what happens when the PC leaves the mapped memory
+    pub fn set_fetch_fault_action(&mut self, action: FetchFaultAction) {
+        self.cpu.set_fetch_fault_action(action);
+    }
+
+    /// If the execution was stopped because the PC left the mapped memory,
+    /// describe where it went and where it probably came from: after a
+    /// call through a bad pointer ra is just past the call, after a return
+    /// through a corrupted return address ra is the address itself
+    pub fn describe_fetch_fault(&self) -> Option<String> {
+        if !self.cpu.left_mapped_memory() {
+            return None;
+        }
+        let pc: u64 = self.cpu.get_pc();
+        let ra: u64 = self.cpu.read_reg(Cpu::RETURN_REGISTER);
+        let sp: u64 = self.cpu.read_reg(Cpu::STACK_POINTER);
+        Some(format!("The PC left the mapped memory at 0x{:0>16x}, ra = 0x{:0>16x}{}, sp = 0x{:0>16x}{}",
+                     pc, ra, self.symbols.annotate(ra), sp, self.symbols.annotate(sp)))
+    }
+
+    /// Get the symbol map used to symbolize addresses
+    pub fn get_symbols(&self) -> &SymbolMap {
+        &self.symbols
+    }
+
+    /// Get a reference to the CPU, e.g. to inspect its state
+    pub fn get_cpu(&self) -> &Cpu {
+        &self.cpu
+    }
+
+    /// Ticks of mtime per executed instruction: 1 is the normal rate,
+    /// 0 freezes the guest time
+    pub fn set_time_scale(&mut self, scale: f64) -> Result<(), String> {
+        self.cpu.set_time_scale(scale)
+    }
+
+    /// Take the time CSR from timebase. It has to be called before the
+    /// device tree is generated, which gives its frequency
+    pub fn set_timebase(&mut self, timebase: Timebase) {
+        self.cpu.set_timebase(timebase);
+    }
+
+    /// Set the real-time clock of the guest to seconds since the epoch
+    pub fn set_rtc(&mut self, seconds: u64) {
+        self.cpu.set_rtc_time(Duration::from_secs(seconds));
+    }
+
+    /// Queue of the events sent by the host to the running program, e.g.
+    /// from another thread (see HostEvents)
+    pub fn host_events(&self) -> HostEvents {
+        self.cpu.host_events()
+    }
+
+    /// Let Ctrl-C stop run and run_limited where the program is, instead of
+    /// terminating riviera (a second Ctrl-C still does, if the run does
+    /// not stop), e.g. to open the interactive mode on a hang
+    pub fn set_break_on_ctrl_c(&mut self, enabled: bool) {
+        self.break_on_ctrl_c = enabled;
+    }
+
+    /// Check if the last run was stopped by Ctrl-C
+    pub fn was_interrupted(&self) -> bool {
+        self.interrupted
+    }
+
+    /// Limit the speed of run and run_limited (see Pacing)
+    pub fn set_pacing(&mut self, pacing: Pacing) {
+        self.pacer = Some(Pacer::new(pacing));
+    }
+
+    /// Freeze the guest time. The real-time clock of the guest stops too:
+    /// if it was following the host, it is set to the current host time
+    pub fn freeze_time(&mut self) {
+        if self.cpu.rtc_follows_host() {
+            let now: Duration = self.cpu.get_rtc_time();
+            self.cpu.set_rtc_time(now);
+        }
+        self.cpu.set_time_scale(0.0).expect("0 is a valid time scale");
+    }
+
+    /// Describe the guest time: mtime, its rate and the real-time clock
+    pub fn describe_time(&self) -> String {
+        let rtc: Duration = self.cpu.get_rtc_time();
+        let source: &str = if self.cpu.rtc_follows_host() { "host" } else { "guest" };
+        let timebase: &str = match self.cpu.get_timebase().get_source() {
+            TimeSource::Mtime => "mtime",
+            TimeSource::Host => "host clock"
+        };
+        format!("mtime = {} (x{} per instruction), rtc = {}.{:0>6} ({} clock), time CSR from {} at {} Hz", self.cpu.get_mtime(),
+                self.cpu.get_time_scale(), rtc.as_secs(), rtc.subsec_micros(), source, timebase, self.cpu.get_timebase().get_frequency())
+    }
+
+    /// Raise an interrupt line from the host, e.g. to test the interrupt
+    /// handler of a driver at a chosen instruction. It is taken before
+    /// the next instruction if the program has enabled it
+    pub fn raise_irq(&mut self, line: InterruptLine) {
+        self.cpu.raise_irq(line);
+    }
+
+    // time command of the interactive mode: show or change the guest time
+    fn time_command(&mut self, subcommand: Option<&str>, value: Option<&str>) -> Result<(), String> {
+        match (subcommand, value) {
+            (None, _) => (),
+            (Some("freeze"), _) => self.freeze_time(),
+            (Some("scale"), Some(scale)) => {
+                let scale: f64 = scale.parse().map_err(|_| format!("Invalid time scale {}", scale))?;
+                self.set_time_scale(scale)?;
+            },
+            (Some("mtime"), Some(mtime)) => self.cpu.set_mtime(parse_number(mtime)?),
+            (Some("rtc"), Some(seconds)) => self.set_rtc(parse_number(seconds)?),
+            (Some(subcommand), _) => return Err(format!("Invalid time command {}: expected freeze, scale <f>, mtime <n> or rtc <seconds>", subcommand))
+        }
+        println!("{}", self.describe_time());
+        Ok(())
+    }
+
+    /// Silently execute at most num_steps instructions.
+    /// It returns the number of executed instructions
+    pub fn step(&mut self, num_steps: u64) -> u64 {
+        self.cpu.cpu_loop_bounded(num_steps)
+    }
+
+    /// Execute a single instruction in debug mode and return its disassembly
+    pub fn step_debug(&mut self) -> String {
+        self.cpu.set_debug_mode();
+        self.cpu.cpu_loop_bounded(1);
+        self.cpu.clear_debug_mode();
+        self.cpu.get_debug_string().to_string()
+    }
+
+    /// Execute a single instruction and return a full report of its effects
+    /// (disassembly, register, memory and CSR writes, trap)
+    pub fn step_with_state(&mut self) -> StepReport {
+        self.cpu.step_with_state()
+    }
+
+    /// Check if the program running on the emulator is over
+    pub fn is_finished(&self) -> bool {
+        self.cpu.is_finished()
+    }
+
+    /// Get why the program stopped. A program that is not over was
+    /// stopped by the timeout of the last run, or else by an instruction
+    /// limit or by the user
+    pub fn get_exit_reason(&self) -> ExitReason {
+        match self.cpu.get_exit_reason() {
+            Some(exit_reason) => exit_reason,
+            None if self.timed_out => ExitReason::Timeout,
+            None => ExitReason::InstructionLimit
+        }
     }
 
     // Let the emulator run the CPU and execute all instructions
     // It returns the duration of the exectuion and the number of exectued instructions
     pub fn run(&mut self) -> (Duration, u64) {
+        // Tracing every instruction needs the disassembly, so the debug
+        // loop is used, and a paced run or one that Ctrl-C stops goes in chunks
+        if self.cpu.is_tracing_exec() || self.pacer.is_some() || self.break_on_ctrl_c {
+            let result: (Duration, u64) = self.run_limited(None, None);
+            self.history = None;
+            return result;
+        }
         // Start the execution time counter
         let now = std::time::Instant::now();
-        let instruction_count: u64;
+        // Run CPU loop, this will return the number of executed instructions.
+        let instruction_count: u64 = self.cpu.cpu_loop().0;
+        self.cpu.get_tracer().flush();
+        self.history = None;
+        (now.elapsed(), instruction_count)
+    }
+
+    /// Run a buffer of code without any file, for fuzzers and other
+    /// harnesses: the bytes are loaded as a raw image at CODE_ADDRESS,
+    /// where the execution starts, with limits.memory_size bytes of DRAM
+    /// right after them, and the run stops after limits.max_instructions
+    /// instructions at most (traps included). The UART writes to a buffer and the idle
+    /// loops do not sleep. Bad inputs are errors, never panics
+    pub fn run_bytes(code: &[u8], limits: RunLimits) -> Result<RunOutcome, String> {
+        if code.is_empty() || limits.memory_size == 0 {
+            return Err("A run needs some code and some memory".to_string());
+        }
+        let mut emu: Emulator = Emulator::new(Some(limits.memory_size));
+        emu.load_image_bytes(code.to_vec(), ImageFormat::Bin, Emulator::CODE_ADDRESS)?;
+        let console: Arc<Mutex<BufferConsole>> = Arc::new(Mutex::new(BufferConsole::new()));
+        emu.set_host_console(console.clone());
+        emu.set_idle_sleep(false);
+        let (_, instructions) = emu.run_limited(Some(limits.max_instructions), None);
+        let output: Vec<u8> = console.lock().map(|mut console| console.take_output()).unwrap_or_default();
+        Ok(RunOutcome {
+            exit_reason: emu.get_exit_reason(),
+            instructions,
+            pc: emu.cpu.get_pc(),
+            registers: std::array::from_fn(|index| emu.cpu.read_reg(index as RegIndex)),
+            output
+        })
+    }
 
-        // Run CPU loop, this will return the number of executed instructions
-        instruction_count = self.cpu.cpu_loop();
+    // Let the emulator run the CPU for at most max_instructions instructions
+    // (e.g. to stop at a checkpoint) and for at most timeout of host time,
+    // whichever comes first, or until the host (or Ctrl-C) stops it. It
+    // returns the duration of the execution and the number of executed
+    // instructions
+    pub fn run_limited(&mut self, max_instructions: Option<u64>, timeout: Option<Duration>) -> (Duration, u64) {
+        let now = std::time::Instant::now();
+        if self.cpu.is_tracing_exec() {
+            self.cpu.set_debug_mode();
+        }
+        let max_instructions: u64 = max_instructions.unwrap_or(u64::MAX);
+        let mut instruction_count: u64 = 0;
+        self.timed_out = false;
+        self.interrupted = false;
+        let ctrl_c: Option<CtrlCGuard> = self.break_on_ctrl_c.then(CtrlCGuard::catch);
+        let chunk: u64 = self.pacer.as_ref().map_or(Emulator::STEP_CHUNK, |pacer| pacer.chunk().min(Emulator::STEP_CHUNK));
+        if let Some(pacer) = self.pacer.as_mut() {
+            pacer.start(Emulator::pace_progress(&self.cpu, pacer.get_pacing(), 0));
+        }
+        while instruction_count < max_instructions && !self.cpu.is_finished() {
+            if timeout.is_some_and(|timeout| now.elapsed() >= timeout) {
+                self.timed_out = true;
+                break;
+            }
+            instruction_count += self.cpu.cpu_loop_bounded((max_instructions - instruction_count).min(chunk));
+            if let Some(pacer) = self.pacer.as_mut() {
+                pacer.pace(Emulator::pace_progress(&self.cpu, pacer.get_pacing(), instruction_count));
+            }
+            // The host asked to stop (see HostEvents)
+            if self.cpu.take_stop_request() {
+                break;
+            }
+            if ctrl_c.as_ref().is_some_and(CtrlCGuard::is_requested) {
+                self.interrupted = true;
+                break;
+            }
+        }
+        self.cpu.clear_debug_mode();
+        self.cpu.get_tracer().flush();
         (now.elapsed(), instruction_count)
     }
 
+    // Progress of a run for its pacing: the executed instructions or mtime
+    fn pace_progress(cpu: &Cpu, pacing: Pacing, instruction_count: u64) -> u64 {
+        match pacing {
+            Pacing::Instructions(_) => instruction_count,
+            Pacing::RealTime => cpu.get_mtime()
+        }
+    }
+
+    /// Run the program recording what every instruction does (see replay.rs),
+    /// for at most max_instructions instructions if given. It returns the
+    /// duration of the execution and the number of executed instructions
+    pub fn run_recording(&mut self, filename: &str, max_instructions: Option<u64>) -> Result<(Duration, u64), String> {
+        let now = std::time::Instant::now();
+        let instruction_count: u64 = replay::record(&mut self.cpu, filename, max_instructions)?;
+        self.cpu.get_tracer().flush();
+        Ok((now.elapsed(), instruction_count))
+    }
+
+    /// Run the program along with a script (see Script), for at most
+    /// max_instructions instructions if given. It returns the duration of
+    /// the execution and the number of executed instructions
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn run_script(&mut self, script: &mut Script, max_instructions: Option<u64>) -> Result<(Duration, u64), String> {
+        let now = std::time::Instant::now();
+        let result: Result<u64, String> = script.run(&mut self.cpu, max_instructions);
+        self.cpu.get_tracer().flush();
+        Ok((now.elapsed(), result?))
+    }
+
+    /// Run the program checking every instruction against a recording,
+    /// up to the end of the recording or to the first difference
+    pub fn run_replay(&mut self, filename: &str) -> Result<ReplayOutcome, String> {
+        let outcome: Result<ReplayOutcome, String> = replay::replay(&mut self.cpu, filename);
+        self.cpu.get_tracer().flush();
+        outcome
+    }
+
     // Let the emulator run in interactive mode: the user is asked
     // to move forward the program by stepping through the instructions
     // It returns the duration of the execution and the number of executed instructions
@@ -109,13 +1103,11 @@ impl Emulator {
         let now: std::time::Instant = std::time::Instant::now();
         // Set the debug mode of the CPU
         self.cpu.set_debug_mode();
-        loop {
-            let mut command_string: String = String::new();
-            // Write command prompt
-            print!("> ");
-            let _ = std::io::stdout().flush();
-            // Ask for user command
-            std::io::stdin().read_line(&mut command_string).expect("could not read from stdin");
+        self.history = Some(History::new(self.cpu.clone(), Emulator::CHECKPOINT_INTERVAL));
+        let symbols: Vec<&str> = self.symbols.names().collect();
+        let mut prompt: Prompt = Prompt::new(Completion::new(&Emulator::COMMANDS, &symbols), &Emulator::REPEATED_COMMANDS);
+        // Ask for user command, the end of the input quits
+        while let Some(command_string) = prompt.read_command() {
             // Split the command into tokens by using a whitespace as a delimiter
             command_tokens = command_string.split(" ");
             // Get the first item from the iterator returned by the split() method
@@ -133,31 +1125,183 @@ impl Emulator {
                         {
                             // Remove trailing whitespaces and try to parse the string into a u64
                             match num_steps.trim().parse() {
-                                Ok(num_steps) => instruction_count += self.cpu.cpu_loop_interactive(num_steps),
+                                Ok(num_steps) => instruction_count += self.interactive_step(num_steps),
                                 Err(err) => println!("Error: {}", err)
                             }
 
                         },
                         // If there is not second element, just step by 1 instruction
-                        None => instruction_count += self.cpu.cpu_loop_interactive(1)
+                        None => instruction_count += self.interactive_step(1)
                     }
+                    self.print_displays();
                 },
                 // r: dump register content
                 "r" => self.cpu.dump_regs(),
-                // c: disable debug mode and run CPU loop until the end is reached
-                "c" => { self.cpu.clear_debug_mode(); instruction_count += self.cpu.cpu_loop()},
-                // d: dump the content of the DRAM into a binary file
+                // bt: print the call chain that led to the current instruction
+                "bt" => self.print_backtrace(usize::MAX),
+                // c: disable debug mode and run CPU loop until the end or a breakpoint is reached
+                "c" => instruction_count += self.continue_command(),
+                // b: set a breakpoint, possibly with a condition, or list them
+                "b" =>
+                {
+                    let arguments: String = command_tokens.by_ref().collect::<Vec<&str>>().join(" ").trim().to_string();
+                    if let Err(err) = self.breakpoint_command(&arguments) {
+                        println!("Error: {}", err);
+                    }
+                }
+                // delete: delete a breakpoint (all of them if none is given)
+                "delete" =>
+                {
+                    match command_tokens.next().map(str::trim).filter(|token| !token.is_empty()).map(str::parse::<usize>) {
+                        Some(Ok(number)) => if let Err(err) = self.breakpoints.delete(number) {
+                            println!("Error: {}", err);
+                        },
+                        Some(Err(err)) => println!("Error: {}", err),
+                        None => self.breakpoints.clear()
+                    }
+                    self.cpu.set_breakpoints(self.breakpoints.addresses());
+                }
+                // ignore: do not stop at the next hits of a breakpoint
+                "ignore" =>
+                {
+                    let number: Option<&str> = command_tokens.next().map(str::trim);
+                    let count: Option<&str> = command_tokens.next().map(str::trim);
+                    match (number.map(str::parse::<usize>), count.map(str::parse::<u64>)) {
+                        (Some(Ok(number)), Some(Ok(count))) => match self.breakpoints.set_ignore_count(number, count) {
+                            Ok(()) => println!("Will ignore next {} crossings of breakpoint {}", count, number),
+                            Err(err) => println!("Error: {}", err)
+                        },
+                        _ => println!("Expected a breakpoint number and a count")
+                    }
+                }
+                // condition: change the condition of a breakpoint (remove it if none is given)
+                "condition" =>
+                {
+                    let number: Option<Result<usize, _>> = command_tokens.next().map(str::trim).filter(|token| !token.is_empty()).map(str::parse::<usize>);
+                    let text: String = command_tokens.by_ref().collect::<Vec<&str>>().join(" ").trim().to_string();
+                    let condition: Result<Option<Condition>, String> = match text.is_empty() {
+                        true => Ok(None),
+                        false => Condition::parse(&text, &self.symbols).map(Some)
+                    };
+                    match (number, condition) {
+                        (Some(Ok(number)), Ok(condition)) => if let Err(err) = self.breakpoints.set_condition(number, condition) {
+                            println!("Error: {}", err);
+                        },
+                        (Some(Ok(_)), Err(err)) => println!("Error: {}", err),
+                        _ => println!("Expected a breakpoint number")
+                    }
+                }
+                // rstep: go back by N steps (1 if omitted)
+                "rstep" =>
+                {
+                    match command_tokens.next().map(str::trim).filter(|token| !token.is_empty()).map(str::parse::<u64>) {
+                        Some(Err(err)) => println!("Error: {}", err),
+                        Some(Ok(num_steps)) => self.reverse_step(num_steps),
+                        None => self.reverse_step(1)
+                    }
+                    self.print_displays();
+                },
+                // rcontinue: go back to where the interactive mode started
+                "rcontinue" => { self.reverse_step(u64::MAX); self.print_displays() },
+                // d: dump the content of the DRAM into a file, raw or in another format
                 "d" =>
                 {
-                    let second_arg: Option<&str> = command_tokens.next();
-                    match second_arg {
-                        Some(filename) => {
-                            match self.dump_memory_to_file(filename.trim()) {
-                                Ok(res_string) => println!("{}", res_string),
-                                Err(res_string) => println!("{}", res_string)
+                    let arguments: Vec<&str> = command_tokens.by_ref().map(str::trim).filter(|token| !token.is_empty()).collect();
+                    match self.dump_command(&arguments) {
+                        Ok(res_string) => println!("{}", res_string),
+                        Err(res_string) => println!("{}", res_string)
+                    }
+                }
+                // disas: disassemble n instructions starting from an address
+                "disas" =>
+                {
+                    let address: Option<&str> = command_tokens.next();
+                    let count: Option<&str> = command_tokens.next();
+                    match (address, count) {
+                        (Some(address), Some(count)) => {
+                            match (self.symbols.resolve(address), count.trim().parse::<u64>()) {
+                                (Ok(address), Ok(count)) => self.print_disassembly(address, count),
+                                (Err(err), _) => println!("Error: {}", err),
+                                (_, Err(err)) => println!("Error: {}", err)
                             }
                         }
-                        None => println!("Expected file name")
+                        _ => println!("Expected address and number of instructions")
+                    }
+                }
+                // x: examine memory at an address or a symbol
+                "x" =>
+                {
+                    let location: Option<&str> = command_tokens.next().map(str::trim).filter(|token| !token.is_empty());
+                    let count: Option<&str> = command_tokens.next().map(str::trim);
+                    let size: Option<&str> = command_tokens.next().map(str::trim);
+                    if let Err(err) = self.examine_command(location, count, size) {
+                        println!("Error: {}", err);
+                    }
+                }
+                // x/<n><format><size>: examine memory with a format, as in gdb
+                command if command.starts_with("x/") =>
+                {
+                    let location: Option<&str> = command_tokens.next().map(str::trim).filter(|token| !token.is_empty());
+                    if let Err(err) = self.examine_format_command(&command[2..], location) {
+                        println!("Error: {}", err);
+                    }
+                }
+                // set: change a memory location or a register
+                "set" =>
+                {
+                    let arguments: Vec<&str> = command_tokens.by_ref().map(str::trim).filter(|token| !token.is_empty()).collect();
+                    match self.set_command(&arguments) {
+                        Ok(()) => self.checkpoint_now(),
+                        Err(err) => println!("Error: {}", err)
+                    }
+                }
+                // time: show, freeze, scale or set the guest time
+                "time" =>
+                {
+                    let subcommand: Option<&str> = command_tokens.next().map(str::trim).filter(|token| !token.is_empty());
+                    let value: Option<&str> = command_tokens.next().map(str::trim);
+                    match self.time_command(subcommand, value) {
+                        Ok(()) => self.checkpoint_now(),
+                        Err(err) => println!("Error: {}", err)
+                    }
+                }
+                // irq: raise an interrupt line
+                "irq" =>
+                {
+                    match command_tokens.next().map(str::trim).filter(|token| !token.is_empty()).map(InterruptLine::parse) {
+                        Some(Ok(line)) => {
+                            self.raise_irq(line);
+                            self.checkpoint_now();
+                            println!("Raised {}", line);
+                        },
+                        Some(Err(err)) => println!("Error: {}", err),
+                        None => println!("Expected msip, mtip or a PLIC source")
+                    }
+                }
+                // display: print an expression after every step (all of them if none is given)
+                "display" =>
+                {
+                    let text: String = command_tokens.by_ref().collect::<Vec<&str>>().join(" ").trim().to_string();
+                    if text.is_empty() {
+                        self.print_displays();
+                    } else {
+                        match Value::parse(&text, &self.symbols) {
+                            Ok(value) => {
+                                self.displays.push((text, value));
+                                self.print_display(self.displays.len() - 1);
+                            },
+                            Err(err) => println!("Error: {}", err)
+                        }
+                    }
+                }
+                // undisplay: stop printing an expression
+                "undisplay" =>
+                {
+                    match command_tokens.next().map(str::trim).filter(|token| !token.is_empty()).map(str::parse::<usize>) {
+                        Some(Ok(number)) if (1..=self.displays.len()).contains(&number) => { self.displays.remove(number - 1); },
+                        Some(Ok(number)) => println!("Error: no display number {}", number),
+                        Some(Err(err)) => println!("Error: {}", err),
+                        None => self.displays.clear()
                     }
                 }
                 // q: quit interactive mode
@@ -168,22 +1312,471 @@ impl Emulator {
                 _   => println!("Command not recognized: type h for help"),
             }
         }
+        self.cpu.get_tracer().flush();
         (now.elapsed(), instruction_count)
 
     }
 
+    /// Step by num_steps instructions in interactive mode. Short steps print
+    /// every instruction, longer ones run in chunks and only report their
+    /// progress, and they can be stopped with Ctrl-C. It returns the number
+    /// of executed instructions
+    fn interactive_step(&mut self, num_steps: u64) -> u64 {
+        if num_steps <= Emulator::STEP_PRINT_LIMIT {
+            self.take_due_checkpoint();
+            let executed: u64 = self.cpu.cpu_loop_interactive(num_steps, &self.symbols);
+            if let Some(history) = self.history.as_mut() {
+                history.advance(executed);
+            }
+            return executed;
+        }
+        info!("Stepping by {} instructions, press Ctrl-C to stop", num_steps);
+        let ctrl_c: CtrlCGuard = CtrlCGuard::catch();
+        // Nothing is printed, the disassembly is only needed to trace the instructions
+        if !self.cpu.is_tracing_exec() {
+            self.cpu.clear_debug_mode();
+        }
+        let mut last_report: std::time::Instant = std::time::Instant::now();
+        let mut executed: u64 = 0;
+        while executed < num_steps && !self.cpu.is_finished() {
+            if ctrl_c.is_requested() {
+                info!("Stopped by Ctrl-C");
+                break;
+            }
+            executed += self.run_checkpointed((num_steps - executed).min(Emulator::STEP_CHUNK));
+            if self.stopped_at_breakpoint() {
+                break;
+            }
+            if last_report.elapsed() >= Emulator::STEP_PROGRESS_PERIOD {
+                info!("{}/{} instructions ({:.1}%)", executed, num_steps,
+                      100.0 * executed as f64 / num_steps as f64);
+                last_report = std::time::Instant::now();
+            }
+        }
+        self.cpu.set_debug_mode();
+        let pc: u64 = self.cpu.get_pc();
+        let location: String = self.symbols.annotate(pc);
+        info!("Executed {} instructions, pc = 0x{:0>16x}{}", executed, pc, location.yellow());
+        if let Some(trap) = self.cpu.get_unhandled_trap() {
+            println!("{} {}", "trap:".red(), self.symbols.describe_trap(&trap));
+        }
+        executed
+    }
+
+    // Run up to num_steps instructions without printing them, taking the
+    // checkpoints of the interactive mode on the way. It returns the number
+    // of executed instructions
+    fn run_checkpointed(&mut self, num_steps: u64) -> u64 {
+        let mut executed: u64 = 0;
+        while executed < num_steps && !self.cpu.is_finished() {
+            self.take_due_checkpoint();
+            let chunk: u64 = match self.history.as_ref() {
+                Some(history) => (num_steps - executed).min(history.until_checkpoint()),
+                None => num_steps - executed
+            };
+            let count: u64 = self.cpu.cpu_loop_bounded(chunk);
+            if let Some(history) = self.history.as_mut() {
+                history.advance(count);
+            }
+            executed += count;
+            // The program stopped before the end of the chunk, or at a breakpoint
+            if count < chunk || self.cpu.is_at_breakpoint() {
+                break;
+            }
+        }
+        executed
+    }
+
+    // Continue until the end of the program or until a breakpoint stops
+    // it. It returns the number of executed instructions
+    fn continue_command(&mut self) -> u64 {
+        self.cpu.clear_debug_mode();
+        let mut executed: u64 = 0;
+        loop {
+            executed += self.run_checkpointed(u64::MAX);
+            // The breakpoints whose condition does not hold are passed
+            if self.cpu.is_finished() || !self.cpu.is_at_breakpoint() || self.stopped_at_breakpoint() {
+                break;
+            }
+        }
+        self.cpu.set_debug_mode();
+        executed
+    }
+
+    // Check if the CPU stopped at a breakpoint that stops the program (its
+    // condition holds and it is not ignored), and show where
+    fn stopped_at_breakpoint(&mut self) -> bool {
+        if self.cpu.is_finished() || !self.cpu.is_at_breakpoint() {
+            return false;
+        }
+        let number: usize = match self.breakpoints.hit(&self.cpu) {
+            Some(breakpoint) => breakpoint.number,
+            None => return false
+        };
+        let pc: u64 = self.cpu.get_pc();
+        info!("Breakpoint {}, pc = 0x{:0>16x}{}", number, pc, self.symbols.annotate(pc).yellow());
+        self.print_disassembly(pc, 1);
+        self.print_displays();
+        true
+    }
+
+    // Set a breakpoint: b <location> [if <condition>], where the location
+    // is an address or a symbol. Without arguments, list the breakpoints
+    fn breakpoint_command(&mut self, arguments: &str) -> Result<(), String> {
+        if arguments.is_empty() {
+            if self.breakpoints.is_empty() {
+                println!("No breakpoints");
+            }
+            for breakpoint in self.breakpoints.iter() {
+                println!("{}", breakpoint);
+            }
+            return Ok(());
+        }
+        let (location, condition): (&str, Option<Condition>) = match arguments.split_once(" if ") {
+            Some((location, condition)) => (location, Some(Condition::parse(condition, &self.symbols)?)),
+            None => (arguments, None)
+        };
+        let address: u64 = self.symbols.resolve(location)?;
+        let number: usize = self.breakpoints.add(address, self.symbols.symbolize(address), condition);
+        self.cpu.set_breakpoints(self.breakpoints.addresses());
+        println!("Breakpoint {} at 0x{:0>16x}{}", number, address, self.symbols.annotate(address).yellow());
+        Ok(())
+    }
+
+    // Take a checkpoint if one is due
+    fn take_due_checkpoint(&mut self) {
+        if self.history.as_ref().is_some_and(|history| history.until_checkpoint() == 0) {
+            self.checkpoint_now();
+        }
+    }
+
+    // Take a checkpoint at the current instruction (e.g. after the state
+    // has been changed by hand, so that going back does not lose it)
+    fn checkpoint_now(&mut self) {
+        if let Some(history) = self.history.as_mut() {
+            history.checkpoint(&self.cpu);
+        }
+    }
+
+    // Go back by num_steps instructions (at most to where the interactive
+    // mode started): restore the nearest earlier checkpoint and execute
+    // again from there, without printing the instructions
+    fn reverse_step(&mut self, num_steps: u64) {
+        let history: &mut History<Cpu> = match self.history.as_mut() {
+            Some(history) => history,
+            None => return
+        };
+        let target: u64 = history.get_position().saturating_sub(num_steps);
+        let (checkpoint, distance) = history.rewind(target);
+        self.cpu = checkpoint;
+        self.cpu.clear_debug_mode();
+        // The breakpoints do not stop the execution again
+        self.cpu.set_breakpoints(Vec::new());
+        let executed: u64 = self.cpu.cpu_loop_bounded(distance);
+        self.cpu.set_breakpoints(self.breakpoints.addresses());
+        self.cpu.set_debug_mode();
+        if executed < distance {
+            error!("The program did not run the same way again, stopped {} instructions earlier", distance - executed);
+        }
+        let pc: u64 = self.cpu.get_pc();
+        info!("Back to instruction {} of the interactive mode, pc = 0x{:0>16x}{}", target, pc, self.symbols.annotate(pc).yellow());
+        self.print_disassembly(pc, 1);
+    }
+
     /// This function shows the usage of the interactive mode
     fn interactive_usage(&self) {
         println!("Commands:");
-        println!("{}: step by <n> instructions (if omitted, execute next instruction)", "s [<n>]".bold());
-        println!("{}: continue until all code is executed", "c".bold());
+        println!("{}: step by <n> instructions (if omitted, execute next instruction); above {} instructions \
+                  only the progress is shown and Ctrl-C stops the execution", "s [<n>]".bold(), Emulator::STEP_PRINT_LIMIT);
+        println!("{}: continue until all code is executed or a breakpoint stops the program", "c".bold());
+        println!("{}: stop before the instruction at <addr> (an address or a symbol) is executed, only when <cond> \
+                  holds if given (e.g. b main if a0 == 5, b loop if x[sp+8]); without arguments, list the breakpoints \
+                  with their hit counts", "b [<addr> [if <cond>]]".bold());
+        println!("{}: delete breakpoint <n> (all of them if omitted)", "delete [<n>]".bold());
+        println!("{}: do not stop at the next <count> hits of breakpoint <n>", "ignore <n> <count>".bold());
+        println!("{}: change the condition of breakpoint <n> (remove it if omitted)", "condition <n> [<cond>]".bold());
+        println!("{}: step back by <n> instructions (if omitted, go back to the previous instruction): the nearest \
+                  checkpoint is restored and the program runs again up to there, printing its output again", "rstep [<n>]".bold());
+        println!("{}: go back to where the interactive mode started", "rcontinue".bold());
         println!("{}: dump registers", "r".bold());
-        println!("{}: dump memory content to binary file", "d <filename>".bold());
-        println!("{}: quit interactive mode", "q".bold());
+        println!("{}: show the calls that led to the current instruction, innermost first (only the calls made \
+                  in interactive mode are known)", "bt".bold());
+        println!("{}: dump the DRAM, or <len> bytes from <start> (an address or a symbol), to file: raw (bin), \
+                  Intel HEX (hex), hexdump (dump) or word32/word64 with :le/:be byte order",
+                 "d <filename> [<start> <len>] [--fmt <format>]".bold());
+        println!("{}: disassemble <n> instructions starting from <addr> (an address or a symbol, e.g. main+0x8)", "disas <addr> <n>".bold());
+        println!("{}: examine <n> values (default 1) of size b, h, w or d (default the register width) at \
+                  <addr> (an address or a symbol, e.g. counter)", "x <addr> [<n>] [<size>]".bold());
+        println!("{}: examine <n> values (default 1) as in gdb, in hexadecimal (x), signed decimal (d) or characters (c), \
+                  of size b, h, w or g (default the register width)", "x/<n><format><size> <addr>".bold());
+        println!("{}: write <value> (a number, possibly negative, or a symbol) of size b, h, w or d \
+                  (default the register width) at <addr>", "set mem <addr> <value> [<size>]".bold());
+        println!("{}: write <value> to a register (ABI name, x0-x31 or pc)", "set reg <register> <value>".bold());
+        println!("{}: show the guest time, freeze it, change its rate (ticks of mtime per instruction) or set mtime \
+                  or the real-time clock (seconds since the epoch)", "time [freeze|scale <f>|mtime <n>|rtc <seconds>]".bold());
+        println!("{}: raise an interrupt line as a device would: the software (msip) or timer (mtip) interrupt \
+                  of the CLINT, or a PLIC source that becomes pending once", "irq <msip|mtip|n>".bold());
+        println!("{}: print <expr> after every step, or all the expressions if none is given. It can use registers, \
+                  the pc, CSRs, symbols, numbers, +, -, * and memory reads of the register width (x[a0+8]) or of \
+                  a size (mem8/mem16/mem32/mem64[sp])", "display [<expr>]".bold());
+        println!("{}: stop printing the expression number <n> (all of them if omitted)", "undisplay [<n>]".bold());
+        println!("{}: quit interactive mode (also Ctrl-D)", "q".bold());
+        println!("The up and down arrows recall the previous commands, Ctrl-R searches them and Tab completes the \
+                  commands and the symbols. An empty line repeats s, rstep, r, bt, disas and x/");
+    }
+
+    // Print the expression number index (from 0) of the display command
+    fn print_display(&self, index: usize) {
+        let (text, value) = &self.displays[index];
+        match value.evaluate(&self.cpu) {
+            Some(result) => println!("{}: {} = {}", index + 1, text.bold(), ExamineFormat::Default.show(result, AccessSize::DOUBLEWORD)),
+            None => println!("{}: {} = {}", index + 1, text.bold(), "<unreadable>".red())
+        }
+    }
+
+    // Print all the expressions of the display command
+    fn print_displays(&self) {
+        for index in 0..self.displays.len() {
+            self.print_display(index);
+        }
+    }
+
+    // Dump memory in interactive mode: d <filename> [<start> <len>] [--fmt <format>].
+    // The format can also be given without --fmt, as in d <filename> <format>
+    fn dump_command(&self, arguments: &[&str]) -> Result<String, String> {
+        let (filename, mut arguments) = match arguments.split_first() {
+            Some((filename, arguments)) => (*filename, arguments.iter()),
+            None => return Err("Expected file name".to_string())
+        };
+        let mut format: DumpFormat = DumpFormat::Raw;
+        let mut range: Vec<u64> = Vec::new();
+        while let Some(argument) = arguments.next() {
+            if *argument == "--fmt" {
+                format = DumpFormat::parse(arguments.next().ok_or("Expected a format after --fmt")?)?;
+            } else if let Ok(parsed) = DumpFormat::parse(argument) {
+                format = parsed;
+            } else if range.is_empty() {
+                range.push(self.symbols.resolve(argument)?);
+            } else {
+                range.push(parse_number(argument)?);
+            }
+        }
+        match range.as_slice() {
+            [] => self.dump_memory_to_file(filename, format),
+            [start, len] => self.dump_memory_range_to_file(filename, format, *start, *len),
+            [_] => Err("Expected the length of the range after its start".to_string()),
+            _ => Err("Too many arguments".to_string())
+        }
+    }
+
+    // Examine memory in interactive mode: x <location> [<count>] [<size>]
+    fn examine_command(&self, location: Option<&str>, count: Option<&str>, size: Option<&str>) -> Result<(), String> {
+        let address: u64 = self.symbols.resolve(location.ok_or("Expected an address or a symbol")?)?;
+        let count: u64 = match count.filter(|token| !token.is_empty()) {
+            Some(count) => parse_number(count)?,
+            None => 1
+        };
+        let size: AccessSize = match size.filter(|token| !token.is_empty()) {
+            Some("b") => AccessSize::BYTE,
+            Some("h") => AccessSize::HALFWORD,
+            Some("w") => AccessSize::WORD,
+            Some("d") => AccessSize::DOUBLEWORD,
+            Some(size) => return Err(format!("Invalid size {}: expected b, h, w or d", size)),
+            None if self.cpu.get_xlen() == Xlen::Rv32 => AccessSize::WORD,
+            None => AccessSize::DOUBLEWORD
+        };
+        self.print_memory(address, count, size, ExamineFormat::Default);
+        Ok(())
+    }
+
+    // Examine memory with a format in interactive mode: x/<count><format><size> <location>,
+    // where the format is x (hex), d (decimal) or c (characters) and the size
+    // b, h, w or g (giant, 8 bytes) as in gdb. Everything but the location is optional
+    fn examine_format_command(&self, spec: &str, location: Option<&str>) -> Result<(), String> {
+        let address: u64 = self.symbols.resolve(location.ok_or("Expected an address or a symbol")?)?;
+        let letters: usize = spec.find(|c: char| !c.is_ascii_digit()).unwrap_or(spec.len());
+        let count: u64 = match &spec[..letters] {
+            "" => 1,
+            count => parse_number(count)?
+        };
+        let mut format: ExamineFormat = ExamineFormat::Hex;
+        let mut size: AccessSize = if self.cpu.get_xlen() == Xlen::Rv32 { AccessSize::WORD } else { AccessSize::DOUBLEWORD };
+        for letter in spec[letters..].chars() {
+            match letter {
+                'x' => format = ExamineFormat::Hex,
+                'd' => format = ExamineFormat::Decimal,
+                'c' => format = ExamineFormat::Char,
+                'b' => size = AccessSize::BYTE,
+                'h' => size = AccessSize::HALFWORD,
+                'w' => size = AccessSize::WORD,
+                'g' => size = AccessSize::DOUBLEWORD,
+                letter => return Err(format!("Invalid format or size {}: expected x, d, c, b, h, w or g", letter))
+            }
+        }
+        self.print_memory(address, count, size, format);
+        Ok(())
+    }
+
+    // Change the machine state in interactive mode: set mem <location> <value> [<size>]
+    // or set reg <register> <value>. Values can be negative or symbols
+    fn set_command(&mut self, arguments: &[&str]) -> Result<(), String> {
+        let value = |token: &str| -> Result<u64, String> {
+            match token.strip_prefix('-') {
+                Some(token) => self.symbols.resolve(token).map(u64::wrapping_neg),
+                None => self.symbols.resolve(token)
+            }
+        };
+        match arguments {
+            ["mem", location, data, size @ ..] => {
+                let address: u64 = self.symbols.resolve(location)?;
+                let data: u64 = value(data)?;
+                let size: AccessSize = match size {
+                    [] if self.cpu.get_xlen() == Xlen::Rv32 => AccessSize::WORD,
+                    [] => AccessSize::DOUBLEWORD,
+                    ["b"] => AccessSize::BYTE,
+                    ["h"] => AccessSize::HALFWORD,
+                    ["w"] => AccessSize::WORD,
+                    ["d"] => AccessSize::DOUBLEWORD,
+                    _ => return Err("Invalid size: expected b, h, w or d".to_string())
+                };
+                self.cpu.poke(data, address, size).map_err(|trap| self.symbols.describe_trap(&trap))?;
+                self.print_memory(address, 1, size, ExamineFormat::Default);
+            },
+            ["reg", register, data] => {
+                let data: u64 = value(data)?;
+                match (*register, parse_register(register)) {
+                    ("pc", _) => self.cpu.set_pc(data),
+                    (_, Some(Cpu::ZERO_REGISTER)) => return Err("zero is hardwired to 0".to_string()),
+                    (_, Some(regi)) => self.cpu.write_reg(regi, data),
+                    _ => return Err(format!("Unknown register {}", register))
+                }
+            },
+            _ => return Err("Expected set mem <location> <value> [<size>] or set reg <register> <value>".to_string())
+        }
+        Ok(())
+    }
+
+    /// Print count values of the given size in memory starting from address,
+    /// stopping at the first address that cannot be read
+    pub fn print_memory(&self, address: u64, count: u64, size: AccessSize, format: ExamineFormat) {
+        let bytes: u64 = size.bytes() as u64;
+        for i in 0..count {
+            let address: u64 = address.wrapping_add(i * bytes);
+            let location: String = self.symbols.annotate(address);
+            match self.cpu.peek(address, size) {
+                Ok(value) => println!("0x{:0>16x}{}: {}", address, location.yellow(), format.show(value, size)),
+                Err(trap) => {
+                    println!("{} {}", "trap:".red(), self.symbols.describe_trap(&trap));
+                    break;
+                }
+            }
+        }
+    }
+
+    /// Print the call chain that led to the current instruction: the
+    /// current PC first, then the call instructions of the functions that
+    /// have not returned yet, from the innermost, up to max_frames lines
+    pub fn print_backtrace(&self, max_frames: usize) {
+        let frames = self.cpu.get_call_stack().get_frames().iter().rev().map(|frame| frame.call_pc);
+        for (i, pc) in std::iter::once(self.cpu.get_pc()).chain(frames).enumerate().take(max_frames) {
+            println!("#{:<3} 0x{:0>16x}{}", i, pc, self.symbols.annotate(pc).yellow());
+        }
+        let depth: usize = self.cpu.get_call_stack().get_frames().len() + 1;
+        if depth > max_frames {
+            println!("... {} more frames", depth - max_frames);
+        }
+    }
+
+    /// Print the disassembly of count instructions in memory starting
+    /// from address, stopping at the first address that cannot be read
+    pub fn print_disassembly(&self, address: u64, count: u64) {
+        for i in 0..count {
+            let pc: u64 = address.wrapping_add(i * 4);
+            let location: String = self.symbols.annotate(pc);
+            match self.cpu.peek(pc, AccessSize::WORD) {
+                Ok(word) => println!("0x{:0>16x}{} {:0>8x}  {}", pc, location.yellow(), word,
+                                     disas::highlight(&disas::disassemble(word as Instruction, pc))),
+                Err(trap) => {
+                    println!("{} {}", "trap:".red(), self.symbols.describe_trap(&trap));
+                    break;
+                }
+            }
+        }
+    }
+
+    /// Record the inputs that come from outside of the machine (characters
+    /// received by the UART, standard input, host time, interrupts of the
+    /// attached devices), to save them with save_input_log
+    pub fn record_inputs(&mut self) {
+        self.cpu.set_input_log(InputLog::recording());
+    }
+
+    /// Take the inputs from a log saved by a run with record_inputs, at the
+    /// same instants, to reproduce that run. It returns the number of inputs
+    pub fn replay_inputs(&mut self, filename: &str) -> Result<usize, String> {
+        let recorded: InputLog = InputLog::read_from_file(filename)?;
+        self.cpu.set_input_log(InputLog::replaying(&recorded));
+        Ok(recorded.len())
+    }
+
+    /// Save the inputs recorded so far
+    pub fn save_input_log(&self, filename: &str) -> Result<String, String> {
+        let log: &InputLog = self.cpu.get_input_log().ok_or("The inputs are not being recorded")?;
+        log.write_to_file(filename)?;
+        Ok(format!("Recorded {} inputs to {}", log.len(), filename))
+    }
+
+    /// Check that all the inputs of the log have been replayed: if some
+    /// are left, the run did not go the way it was recorded
+    pub fn check_replayed_inputs(&self) -> Result<String, String> {
+        let log: &InputLog = self.cpu.get_input_log().ok_or("The inputs are not being replayed")?;
+        match log.remaining().first() {
+            None => Ok(format!("Replayed all the {} recorded inputs", log.len())),
+            Some((instant, event)) => Err(format!("The run diverged from the recording: {} of {} inputs were not replayed, \
+                                                   the first one ({}) at instruction {}", log.remaining().len(), log.len(), event, instant))
+        }
+    }
+
+    /// Save the state of the machine (registers, CSRs, PC, memories and
+    /// devices) to a file, to resume the execution later on
+    pub fn save_snapshot(&self, filename: &str) -> Result<(), String> {
+        let mut writer: SnapshotWriter = SnapshotWriter::new();
+        self.cpu.save(&mut writer);
+        writer.write_to_file(filename)
+    }
+
+    /// Restore the state of the machine from a snapshot file. The program
+    /// has to be loaded first, for its symbols and code regions
+    pub fn load_snapshot(&mut self, filename: &str) -> Result<(), String> {
+        let mut reader: SnapshotReader = SnapshotReader::read_from_file(filename)?;
+        // Restore a copy, so that a broken snapshot leaves the CPU untouched
+        let mut cpu: Cpu = self.cpu.clone();
+        cpu.restore(&mut reader)?;
+        reader.finish()?;
+        self.cpu = cpu;
+        Ok(())
+    }
+
+    /// Dump the memory associated to the CPU to a file specified as a string,
+    /// either raw or as words with their addresses
+    pub fn dump_memory_to_file(&self, filename: &str, format: DumpFormat) -> Result<String, String> {
+        self.cpu.get_memory().dump_to_file_as(filename, format, self.cpu.get_read_write_segment())
+    }
+
+    /// Dump len bytes of memory starting at start to a file. The range can
+    /// be anywhere in the address space (e.g. in the ROM), but all of it
+    /// has to be readable
+    pub fn dump_memory_range_to_file(&self, filename: &str, format: DumpFormat, start: u64, len: u64) -> Result<String, String> {
+        let data: Vec<u8> = self.read_memory(start, len)
+            .map_err(|err| format!("Could not read the range to dump: {}", err))?;
+        format.write_to_file(filename, &data, start)
     }
 
-    /// Dump the memory associated to the CPU to a file specified as a string
-    pub fn dump_memory_to_file(&self, filename: &str) -> Result<String, String> {
-        self.cpu.get_memory().dump_to_file(filename)
+    // Read len bytes starting at start, without side effects on the devices
+    fn read_memory(&self, start: u64, len: u64) -> Result<Vec<u8>, String> {
+        (0..len)
+            .map(|offset| self.cpu.peek(start.wrapping_add(offset), AccessSize::BYTE).map(|byte| byte as u8))
+            .collect::<Result<Vec<u8>, _>>()
+            .map_err(|trap| self.symbols.describe_trap(&trap))
     }
 }
\ No newline at end of file
@@ -0,0 +1,93 @@
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use crate::bus::InterruptLine;
+
+/// Something the host asks of a running CPU
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum HostEvent {
+    /// Raise an interrupt line, as Cpu::raise_irq does
+    RaiseIrq(InterruptLine),
+    /// Stop the run before the program is over
+    Stop
+}
+
+/// Queue of the events sent to a CPU from other host threads (e.g. a
+/// user interface, a network server or a test harness) while it runs.
+/// The CPU loops do not look at it after every instruction but every
+/// few thousands, so that it costs nothing when it is empty. Clones
+/// share the same queue
+#[derive(Clone, Debug, Default)]
+pub struct HostEvents {
+    queue: Arc<Mutex<Vec<HostEvent>>>,
+    // Set when the queue is not empty, so that checking it takes no lock
+    pending: Arc<AtomicBool>
+}
+
+impl HostEvents {
+    pub fn new() -> HostEvents {
+        HostEvents::default()
+    }
+
+    /// Send an event to the CPU, it is handled within a few thousands
+    /// instructions
+    pub fn send(&self, event: HostEvent) {
+        if let Ok(mut queue) = self.queue.lock() {
+            queue.push(event);
+            self.pending.store(true, Ordering::Release);
+        }
+    }
+
+    /// Check if events have been sent since the last call to take
+    #[inline(always)]
+    pub fn is_pending(&self) -> bool {
+        self.pending.load(Ordering::Acquire)
+    }
+
+    /// Take the events sent so far, in the order they were sent
+    pub fn take(&self) -> Vec<HostEvent> {
+        match self.queue.lock() {
+            Ok(mut queue) => {
+                self.pending.store(false, Ordering::Release);
+                std::mem::take(&mut *queue)
+            },
+            Err(_) => Vec::new()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::bus::InterruptLine;
+    use crate::cpu::{Cpu, ExitReason};
+    use crate::memory::AccessSize;
+    use crate::events::{HostEvent, HostEvents};
+
+    #[test]
+    fn host_events_test() {
+        let events: HostEvents = HostEvents::new();
+        let sender: HostEvents = events.clone();
+        assert!(!events.is_pending());
+        std::thread::spawn(move || {
+            sender.send(HostEvent::RaiseIrq(InterruptLine::Software));
+            sender.send(HostEvent::Stop);
+        }).join().unwrap();
+        assert!(events.is_pending());
+        assert_eq!(events.take(), vec![HostEvent::RaiseIrq(InterruptLine::Software), HostEvent::Stop]);
+        assert!(!events.is_pending());
+
+        // A stop sent from another thread ends an endless loop (j .)
+        let mut cpu: Cpu = Cpu::new(Some(crate::memory::Memory::DRAM_DEFAULT_SIZE));
+        cpu.set_read_write_segment(0x0);
+        cpu.store(0x0000006f, 0x0, AccessSize::WORD).unwrap();
+        let events: HostEvents = cpu.host_events();
+        let stopper = std::thread::spawn(move || {
+            std::thread::sleep(std::time::Duration::from_millis(10));
+            events.send(HostEvent::Stop);
+        });
+        let (count, reason) = cpu.cpu_loop();
+        stopper.join().unwrap();
+        assert!(count > 0);
+        assert_eq!(reason, ExitReason::InstructionLimit);
+        assert_eq!(cpu.get_pc(), 0x0);
+    }
+}
@@ -0,0 +1,364 @@
+use std::ops::Range;
+use crate::cpu::{CSRegIndex, Cpu, RegIndex, Xlen, REG_FILE_NAMES};
+use crate::csr::CsrFile;
+use crate::memory::AccessSize;
+use crate::symbols::SymbolMap;
+
+#[derive(Clone, Copy, Debug)]
+pub enum Comparison {
+    Equal,
+    NotEqual,
+    Less,
+    LessEqual,
+    Greater,
+    GreaterEqual
+}
+
+impl Comparison {
+    // Operators are ordered so that the two-character ones are tried first
+    const OPERATORS: [(&'static str, Comparison); 6] = [
+        ("==", Comparison::Equal),
+        ("!=", Comparison::NotEqual),
+        ("<=", Comparison::LessEqual),
+        (">=", Comparison::GreaterEqual),
+        ("<",  Comparison::Less),
+        (">",  Comparison::Greater)
+    ];
+
+    fn apply(self, lhs: u64, rhs: u64) -> bool {
+        match self {
+            Comparison::Equal => lhs == rhs,
+            Comparison::NotEqual => lhs != rhs,
+            Comparison::Less => lhs < rhs,
+            Comparison::LessEqual => lhs <= rhs,
+            Comparison::Greater => lhs > rhs,
+            Comparison::GreaterEqual => lhs >= rhs
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Arithmetic {
+    Add,
+    Sub,
+    Mul
+}
+
+/// An arithmetic expression over the machine state, e.g. "sp", "a0+8",
+/// "x[a0+8]" (the value of the register width at a0+8), "mem8[buf+2*a1]"
+/// or "mstatus". Registers, the pc, CSRs, symbols (their address) and
+/// numbers are combined with +, - and * (wrapping, on unsigned 64 bit
+/// values) and parentheses
+#[derive(Clone, Debug)]
+pub enum Value {
+    Register(RegIndex),
+    Pc,
+    Csr(CSRegIndex),
+    Constant(u64),
+    // Read of the given size (None for the register width) at an address
+    Memory(Box<Value>, Option<AccessSize>),
+    Binary(Box<Value>, Arithmetic, Box<Value>)
+}
+
+// Reads a Value from the text of an expression, one character at a time
+struct ValueParser<'a> {
+    text: &'a str,
+    position: usize,
+    symbols: &'a SymbolMap
+}
+
+impl ValueParser<'_> {
+    fn skip_spaces(&mut self) {
+        while self.text[self.position..].starts_with(' ') {
+            self.position += 1;
+        }
+    }
+
+    // Consume c if it is the next character
+    fn accept(&mut self, c: char) -> bool {
+        self.skip_spaces();
+        if self.text[self.position..].starts_with(c) {
+            self.position += 1;
+            return true;
+        }
+        false
+    }
+
+    // sum := product (('+' | '-') product)*
+    fn sum(&mut self) -> Result<Value, String> {
+        let mut value: Value = self.product()?;
+        loop {
+            let operator: Arithmetic = if self.accept('+') {
+                Arithmetic::Add
+            } else if self.accept('-') {
+                Arithmetic::Sub
+            } else {
+                return Ok(value);
+            };
+            value = Value::Binary(Box::new(value), operator, Box::new(self.product()?));
+        }
+    }
+
+    // product := term ('*' term)*
+    fn product(&mut self) -> Result<Value, String> {
+        let mut value: Value = self.term()?;
+        while self.accept('*') {
+            value = Value::Binary(Box::new(value), Arithmetic::Mul, Box::new(self.term()?));
+        }
+        Ok(value)
+    }
+
+    // term := '(' sum ')' | name '[' sum ']' | name | number
+    fn term(&mut self) -> Result<Value, String> {
+        if self.accept('(') {
+            let value: Value = self.sum()?;
+            return match self.accept(')') {
+                true => Ok(value),
+                false => Err(format!("Expected ) in {}", self.text))
+            };
+        }
+        self.skip_spaces();
+        let rest: &str = &self.text[self.position..];
+        let length: usize = rest.find(|c: char| !(c.is_ascii_alphanumeric() || c == '_' || c == '.' || c == '$'))
+            .unwrap_or(rest.len());
+        let word: &str = &rest[..length];
+        if word.is_empty() {
+            return Err(format!("Expected a register, a CSR, a symbol or a number at position {} of {}", self.position, self.text));
+        }
+        self.position += length;
+
+        if self.accept('[') {
+            let size: Option<AccessSize> = match word {
+                "x" => None,
+                "mem8" => Some(AccessSize::BYTE),
+                "mem16" => Some(AccessSize::HALFWORD),
+                "mem32" => Some(AccessSize::WORD),
+                "mem64" => Some(AccessSize::DOUBLEWORD),
+                _ => return Err(format!("Invalid memory access {}[: expected x, mem8, mem16, mem32 or mem64", word))
+            };
+            let address: Value = self.sum()?;
+            if !self.accept(']') {
+                return Err(format!("Expected ] in {}", self.text));
+            }
+            return Ok(Value::Memory(Box::new(address), size));
+        }
+        if word.starts_with(|c: char| c.is_ascii_digit()) {
+            return Ok(Value::Constant(parse_number(word)?));
+        }
+        if word == "pc" {
+            return Ok(Value::Pc);
+        }
+        if let Some(regi) = parse_register(word) {
+            return Ok(Value::Register(regi));
+        }
+        if let Some(csr) = (0..=0xfff).find(|csr| CsrFile::name(*csr) == word) {
+            return Ok(Value::Csr(csr));
+        }
+        match self.symbols.address_of(word) {
+            Some(address) => Ok(Value::Constant(address)),
+            None => Err(format!("Unknown register, CSR or symbol {}", word))
+        }
+    }
+}
+
+impl Value {
+    /// Parse an expression, the symbols are replaced by their address
+    pub fn parse(text: &str, symbols: &SymbolMap) -> Result<Value, String> {
+        let mut parser: ValueParser = ValueParser { text: text.trim(), position: 0, symbols };
+        let value: Value = parser.sum()?;
+        parser.skip_spaces();
+        if parser.position < parser.text.len() {
+            return Err(format!("Unexpected {} in {}", &parser.text[parser.position..], parser.text));
+        }
+        Ok(value)
+    }
+
+    /// Evaluate the expression on the current state of the CPU. It
+    /// returns None if it reads memory that cannot be read or a CSR that
+    /// does not exist
+    pub fn evaluate(&self, cpu: &Cpu) -> Option<u64> {
+        match self {
+            Value::Register(regi) => Some(cpu.read_reg(*regi)),
+            Value::Pc => Some(cpu.get_pc()),
+            Value::Csr(csr) => cpu.get_csrs().read(*csr),
+            Value::Constant(value) => Some(*value),
+            Value::Memory(address, size) => {
+                let size: AccessSize = size.unwrap_or(match cpu.get_xlen() {
+                    Xlen::Rv32 => AccessSize::WORD,
+                    Xlen::Rv64 => AccessSize::DOUBLEWORD
+                });
+                cpu.peek(address.evaluate(cpu)?, size).ok()
+            },
+            Value::Binary(lhs, operator, rhs) => {
+                let (lhs, rhs): (u64, u64) = (lhs.evaluate(cpu)?, rhs.evaluate(cpu)?);
+                Some(match operator {
+                    Arithmetic::Add => lhs.wrapping_add(rhs),
+                    Arithmetic::Sub => lhs.wrapping_sub(rhs),
+                    Arithmetic::Mul => lhs.wrapping_mul(rhs)
+                })
+            }
+        }
+    }
+}
+
+/// Parse a register name: an ABI name (or fp) or x0-x31
+pub fn parse_register(token: &str) -> Option<RegIndex> {
+    if token == "fp" {
+        return Some(8);
+    }
+    if let Some(position) = REG_FILE_NAMES.iter().position(|name| *name == token) {
+        return Some(position as RegIndex);
+    }
+    match token.strip_prefix('x').map(|index| index.parse::<RegIndex>()) {
+        Some(Ok(regi)) if (regi as usize) < REG_FILE_NAMES.len() => Some(regi),
+        _ => None
+    }
+}
+
+/// Parse a decimal or hexadecimal (0x prefixed) number
+pub fn parse_number(token: &str) -> Result<u64, String> {
+    let token: &str = token.trim();
+    let result = match token.strip_prefix("0x") {
+        Some(hex_digits) => u64::from_str_radix(hex_digits, 16),
+        None => token.parse::<u64>()
+    };
+    result.map_err(|err| format!("Invalid number {}: {}", token, err))
+}
+
+/// Parse a size in bytes, which can end with k or m (e.g. "64k")
+pub fn parse_size(token: &str) -> Result<u64, String> {
+    let token: &str = token.trim();
+    let (number, shift): (&str, u32) = match token.strip_suffix(['k', 'K']) {
+        Some(kib) => (kib, 10),
+        None => match token.strip_suffix(['m', 'M']) {
+            Some(mib) => (mib, 20),
+            None => (token, 0)
+        }
+    };
+    parse_number(number)?.checked_mul(1 << shift).ok_or(format!("Invalid size {}: it is too large", token))
+}
+
+/// Parse a range of addresses given as "<start>:<len>"
+pub fn parse_range(range: &str) -> Result<Range<u64>, String> {
+    let (start, len) = range.trim().split_once(':')
+        .ok_or(format!("Invalid range {}: expected <start>:<len>", range))?;
+    let start: u64 = parse_number(start)?;
+    let end: u64 = start.checked_add(parse_number(len)?).ok_or(format!("Invalid range {}: it ends past the address space", range))?;
+    Ok(start..end)
+}
+
+/// A condition over the machine state, as in gdb: two expressions (see
+/// Value) compared with ==, !=, <, <=, > or >=, or a single expression
+/// that holds when it is not 0, e.g. "a0 == 5", "x[sp+8] != counter" or
+/// "mem8[buffer]". Comparisons are done between unsigned 64 bit values
+#[derive(Clone, Debug)]
+pub struct Condition {
+    lhs: Value,
+    rhs: Option<(Comparison, Value)>,
+    text: String
+}
+
+impl Condition {
+    /// Parse a condition, the symbols are replaced by their address
+    pub fn parse(text: &str, symbols: &SymbolMap) -> Result<Condition, String> {
+        let text: &str = text.trim();
+        for (operator, comparison) in Comparison::OPERATORS {
+            if let Some((lhs, rhs)) = text.split_once(operator) {
+                return Ok(Condition {
+                    lhs: Value::parse(lhs, symbols)?,
+                    rhs: Some((comparison, Value::parse(rhs, symbols)?)),
+                    text: text.to_string()
+                });
+            }
+        }
+        Ok(Condition { lhs: Value::parse(text, symbols)?, rhs: None, text: text.to_string() })
+    }
+
+    /// Evaluate the condition on the current state of the CPU. A
+    /// condition that reads unreadable memory is always false
+    pub fn evaluate(&self, cpu: &Cpu) -> bool {
+        match (self.lhs.evaluate(cpu), self.rhs.as_ref()) {
+            (Some(lhs), Some((comparison, rhs))) => rhs.evaluate(cpu).is_some_and(|rhs| comparison.apply(lhs, rhs)),
+            (Some(lhs), None) => lhs != 0,
+            (None, _) => false
+        }
+    }
+
+    /// Get the condition as it was written by the user
+    pub fn as_str(&self) -> &str {
+        &self.text
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cpu::Cpu;
+    use crate::csr::CsrFile;
+    use crate::expr::{Condition, Value};
+    use crate::memory::{AccessSize, Memory};
+    use crate::symbols::SymbolMap;
+
+    #[test]
+    fn register_test() {
+        let mut cpu: Cpu = Cpu::new(None);
+        cpu.write_reg(10, 5);
+        let symbols: SymbolMap = SymbolMap::new();
+        assert!(Condition::parse("a0 == 5", &symbols).unwrap().evaluate(&cpu));
+        assert!(Condition::parse("x10 >= 0x5", &symbols).unwrap().evaluate(&cpu));
+        assert!(!Condition::parse("a0 != 5", &symbols).unwrap().evaluate(&cpu));
+        assert!(Condition::parse("a0 equals 5", &symbols).is_err());
+    }
+
+    #[test]
+    fn memory_test() {
+        let mut cpu: Cpu = Cpu::new(Some(Memory::DRAM_DEFAULT_SIZE));
+        cpu.set_read_write_segment(0x0);
+        cpu.store(0xdead, 0x10, AccessSize::HALFWORD).unwrap();
+        let symbols: SymbolMap = SymbolMap::new();
+        assert!(Condition::parse("mem16[0x10] == 0xdead", &symbols).unwrap().evaluate(&cpu));
+        assert!(Condition::parse("mem8[16] < 0xae", &symbols).unwrap().evaluate(&cpu));
+        assert!(Condition::parse("mem12[0x10] == 0", &symbols).is_err());
+    }
+
+    #[test]
+    fn value_test() {
+        let mut cpu: Cpu = Cpu::new(Some(Memory::DRAM_DEFAULT_SIZE));
+        cpu.set_read_write_segment(0x0);
+        cpu.store(0x1122334455667788, 0x18, AccessSize::DOUBLEWORD).unwrap();
+        cpu.write_reg(10, 0x10);
+        cpu.write_reg(11, 2);
+        cpu.get_csrs_mut().write(CsrFile::MSCRATCH, 0x42).unwrap();
+        let symbols: SymbolMap = SymbolMap::parse("0000000000000008 D buffer\n");
+        let evaluate = |text: &str| Value::parse(text, &symbols).unwrap().evaluate(&cpu);
+        assert_eq!(evaluate("x[a0+8]"), Some(0x1122334455667788));
+        assert_eq!(evaluate("mem8[buffer + 8*a1]"), Some(0x88));
+        assert_eq!(evaluate("mem16[(a0 - 6) * 2 + 8]"), Some(0x3344));
+        assert_eq!(evaluate("mscratch + x11"), Some(0x44));
+        assert_eq!(evaluate("a1 - 3"), Some(u64::MAX));
+        assert_eq!(evaluate("x[0x100000000]"), None);
+        assert!(Value::parse("a0 +", &symbols).is_err());
+        assert!(Value::parse("x[a0", &symbols).is_err());
+        assert!(Value::parse("nosuch", &symbols).is_err());
+        assert!(Value::parse("a0 a1", &symbols).is_err());
+    }
+
+    #[test]
+    fn condition_test() {
+        let mut cpu: Cpu = Cpu::new(Some(Memory::DRAM_DEFAULT_SIZE));
+        cpu.set_read_write_segment(0x0);
+        cpu.store(7, 0x8, AccessSize::BYTE).unwrap();
+        cpu.write_reg(10, 5);
+        let symbols: SymbolMap = SymbolMap::parse("0000000000000008 D counter\n");
+        let evaluate = |text: &str| Condition::parse(text, &symbols).unwrap().evaluate(&cpu);
+        assert!(evaluate("a0==5"));
+        assert!(evaluate("mem8[counter] > a0 + 1"));
+        assert!(!evaluate("mem8[counter] <= 6"));
+        assert!(evaluate("a0 - 5 != counter"));
+        // A single expression holds when it is not 0
+        assert!(evaluate("mem8[counter]"));
+        assert!(!evaluate("zero"));
+        assert!(!evaluate("x[0x100000000] == 0"));
+        assert_eq!(Condition::parse(" a0 == 5 ", &symbols).unwrap().as_str(), "a0 == 5");
+        assert!(Condition::parse("a0 == nosuch", &symbols).is_err());
+    }
+}
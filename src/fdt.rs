@@ -0,0 +1,312 @@
+use std::collections::HashMap;
+use crate::cpu::Xlen;
+use crate::machine::Region;
+
+/// Writer of a flattened device tree (the DTB format of the devicetree
+/// specification, version 17): nodes are opened and closed in order and
+/// properties are added to the node that is open
+pub struct FdtBuilder {
+    structure: Vec<u8>,
+    strings: Vec<u8>,
+    // Offset of each property name in the strings block
+    names: HashMap<String, u32>
+}
+
+impl Default for FdtBuilder {
+    fn default() -> FdtBuilder {
+        FdtBuilder::new()
+    }
+}
+
+impl FdtBuilder {
+    pub const MAGIC: u32 = 0xd00dfeed;
+    const VERSION: u32 = 17;
+    const LAST_COMPATIBLE_VERSION: u32 = 16;
+    const HEADER_SIZE: usize = 40;
+    // The memory reservation block only has its terminating entry
+    const RESERVATION_SIZE: usize = 16;
+
+    const BEGIN_NODE: u32 = 1;
+    const END_NODE: u32 = 2;
+    const PROP: u32 = 3;
+    const END: u32 = 9;
+
+    pub fn new() -> FdtBuilder {
+        FdtBuilder { structure: Vec::new(), strings: Vec::new(), names: HashMap::new() }
+    }
+
+    fn put_u32(&mut self, value: u32) {
+        self.structure.extend_from_slice(&value.to_be_bytes());
+    }
+
+    // Bytes followed by padding up to a multiple of 4
+    fn put_padded(&mut self, bytes: &[u8]) {
+        self.structure.extend_from_slice(bytes);
+        self.structure.resize(self.structure.len().next_multiple_of(4), 0);
+    }
+
+    /// Open a node (the root node has an empty name)
+    pub fn begin_node(&mut self, name: &str) {
+        self.put_u32(FdtBuilder::BEGIN_NODE);
+        self.put_padded(&[name.as_bytes(), &[0]].concat());
+    }
+
+    /// Close the last node that was opened
+    pub fn end_node(&mut self) {
+        self.put_u32(FdtBuilder::END_NODE);
+    }
+
+    /// Add a property with a raw value
+    pub fn property(&mut self, name: &str, value: &[u8]) {
+        let offset: u32 = match self.names.get(name) {
+            Some(offset) => *offset,
+            None => {
+                let offset: u32 = self.strings.len() as u32;
+                self.strings.extend_from_slice(name.as_bytes());
+                self.strings.push(0);
+                self.names.insert(name.to_string(), offset);
+                offset
+            }
+        };
+        self.put_u32(FdtBuilder::PROP);
+        self.put_u32(value.len() as u32);
+        self.put_u32(offset);
+        self.put_padded(value);
+    }
+
+    /// Add a property made of 32-bit cells
+    pub fn property_cells(&mut self, name: &str, cells: &[u32]) {
+        let value: Vec<u8> = cells.iter().flat_map(|cell| cell.to_be_bytes()).collect();
+        self.property(name, &value);
+    }
+
+    pub fn property_u32(&mut self, name: &str, value: u32) {
+        self.property_cells(name, &[value]);
+    }
+
+    /// Add a list of (address, size) pairs, both made of two cells
+    pub fn property_reg(&mut self, name: &str, ranges: &[(u64, u64)]) {
+        let cells: Vec<u32> = ranges.iter()
+            .flat_map(|(address, size)| [(address >> 32) as u32, *address as u32, (size >> 32) as u32, *size as u32])
+            .collect();
+        self.property_cells(name, &cells);
+    }
+
+    pub fn property_string(&mut self, name: &str, value: &str) {
+        self.property_strings(name, &[value]);
+    }
+
+    /// Add a list of strings (e.g. compatible)
+    pub fn property_strings<S: AsRef<str>>(&mut self, name: &str, values: &[S]) {
+        let value: Vec<u8> = values.iter().flat_map(|value| [value.as_ref().as_bytes(), &[0]].concat()).collect();
+        self.property(name, &value);
+    }
+
+    /// Add a property without value (e.g. interrupt-controller)
+    pub fn property_empty(&mut self, name: &str) {
+        self.property(name, &[]);
+    }
+
+    /// Put the blob together: header, memory reservations, structure and strings
+    pub fn finish(mut self) -> Vec<u8> {
+        self.put_u32(FdtBuilder::END);
+        let structure_offset: usize = FdtBuilder::HEADER_SIZE + FdtBuilder::RESERVATION_SIZE;
+        let strings_offset: usize = structure_offset + self.structure.len();
+        let total_size: usize = strings_offset + self.strings.len();
+        let header: [u32; 10] = [
+            FdtBuilder::MAGIC, total_size as u32, structure_offset as u32, strings_offset as u32,
+            FdtBuilder::HEADER_SIZE as u32, FdtBuilder::VERSION, FdtBuilder::LAST_COMPATIBLE_VERSION,
+            // Boot CPU, sizes of the strings and of the structure
+            0, self.strings.len() as u32, self.structure.len() as u32
+        ];
+        let mut blob: Vec<u8> = header.iter().flat_map(|word| word.to_be_bytes()).collect();
+        blob.resize(structure_offset, 0);
+        blob.extend_from_slice(&self.structure);
+        blob.extend_from_slice(&self.strings);
+        blob
+    }
+}
+
+/// A memory-mapped device, as it is described to the guest
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct DeviceNode {
+    // Generic name of the node, e.g. serial
+    pub name: String,
+    pub compatible: Vec<String>,
+    pub base: u64,
+    pub size: u64,
+    // PLIC source its interrupt is connected to, if any
+    pub irq: Option<usize>,
+    // Other properties of one cell, e.g. clock-frequency
+    pub properties: Vec<(String, u32)>
+}
+
+/// What the device tree tells the guest about the machine: the hart, the
+/// RAM, the interrupt controllers and the other devices
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Platform {
+    pub xlen: Xlen,
+    pub timebase_frequency: u64,
+    pub memory: Vec<Region>,
+    pub clint_base: u64,
+    pub clint_size: u64,
+    pub plic_base: u64,
+    pub plic_size: u64,
+    // Number of PLIC sources, source 0 excluded
+    pub plic_sources: usize,
+    pub devices: Vec<DeviceNode>,
+    // Device the console goes to (chosen stdout-path), if any
+    pub console: Option<u64>
+}
+
+impl Platform {
+    // phandles of the interrupt controllers
+    const CPU_INTC_PHANDLE: u32 = 1;
+    const PLIC_PHANDLE: u32 = 2;
+    // Interrupt numbers (mip bits) taken by the controllers
+    const IRQ_M_SOFT: u32 = 3;
+    const IRQ_M_TIMER: u32 = 7;
+    const IRQ_S_EXT: u32 = 9;
+    const IRQ_M_EXT: u32 = 11;
+
+    /// Build the flattened device tree of the platform
+    pub fn to_fdt(&self) -> Vec<u8> {
+        let mut fdt: FdtBuilder = FdtBuilder::new();
+        fdt.begin_node("");
+        fdt.property_u32("#address-cells", 2);
+        fdt.property_u32("#size-cells", 2);
+        fdt.property_string("compatible", "riviera");
+        fdt.property_string("model", "riviera");
+
+        fdt.begin_node("chosen");
+        if let Some(console) = self.devices.iter().find(|device| Some(device.base) == self.console) {
+            fdt.property_string("stdout-path", &format!("/soc/{}@{:x}", console.name, console.base));
+        }
+        fdt.end_node();
+
+        fdt.begin_node("cpus");
+        fdt.property_u32("#address-cells", 1);
+        fdt.property_u32("#size-cells", 0);
+        fdt.property_u32("timebase-frequency", self.timebase_frequency as u32);
+        fdt.begin_node("cpu@0");
+        fdt.property_string("device_type", "cpu");
+        fdt.property_u32("reg", 0);
+        fdt.property_string("status", "okay");
+        fdt.property_string("compatible", "riscv");
+        fdt.property_string("riscv,isa", match self.xlen { Xlen::Rv32 => "rv32ifd_zicond_zba_zbb_zbc_zbs", Xlen::Rv64 => "rv64ifd_zicond_zba_zbb_zbc_zbs" });
+        fdt.begin_node("interrupt-controller");
+        fdt.property_u32("#interrupt-cells", 1);
+        fdt.property_empty("interrupt-controller");
+        fdt.property_string("compatible", "riscv,cpu-intc");
+        fdt.property_u32("phandle", Platform::CPU_INTC_PHANDLE);
+        fdt.end_node();
+        fdt.end_node();
+        fdt.end_node();
+
+        for region in &self.memory {
+            fdt.begin_node(&format!("memory@{:x}", region.base));
+            fdt.property_string("device_type", "memory");
+            fdt.property_reg("reg", &[(region.base, region.size)]);
+            fdt.end_node();
+        }
+
+        fdt.begin_node("soc");
+        fdt.property_u32("#address-cells", 2);
+        fdt.property_u32("#size-cells", 2);
+        fdt.property_string("compatible", "simple-bus");
+        fdt.property_empty("ranges");
+
+        fdt.begin_node(&format!("clint@{:x}", self.clint_base));
+        fdt.property_strings("compatible", &["sifive,clint0", "riscv,clint0"]);
+        fdt.property_reg("reg", &[(self.clint_base, self.clint_size)]);
+        fdt.property_cells("interrupts-extended", &[Platform::CPU_INTC_PHANDLE, Platform::IRQ_M_SOFT,
+                                                    Platform::CPU_INTC_PHANDLE, Platform::IRQ_M_TIMER]);
+        fdt.end_node();
+
+        fdt.begin_node(&format!("plic@{:x}", self.plic_base));
+        fdt.property_strings("compatible", &["sifive,plic-1.0.0", "riscv,plic0"]);
+        fdt.property_reg("reg", &[(self.plic_base, self.plic_size)]);
+        fdt.property_u32("#address-cells", 0);
+        fdt.property_u32("#interrupt-cells", 1);
+        fdt.property_empty("interrupt-controller");
+        fdt.property_cells("interrupts-extended", &[Platform::CPU_INTC_PHANDLE, Platform::IRQ_M_EXT,
+                                                    Platform::CPU_INTC_PHANDLE, Platform::IRQ_S_EXT]);
+        fdt.property_u32("riscv,ndev", self.plic_sources as u32);
+        fdt.property_u32("phandle", Platform::PLIC_PHANDLE);
+        fdt.end_node();
+
+        for device in &self.devices {
+            fdt.begin_node(&format!("{}@{:x}", device.name, device.base));
+            fdt.property_strings("compatible", &device.compatible);
+            fdt.property_reg("reg", &[(device.base, device.size)]);
+            if let Some(irq) = device.irq {
+                fdt.property_u32("interrupt-parent", Platform::PLIC_PHANDLE);
+                fdt.property_u32("interrupts", irq as u32);
+            }
+            for (name, value) in &device.properties {
+                fdt.property_u32(name, *value);
+            }
+            fdt.end_node();
+        }
+        fdt.end_node();
+
+        fdt.end_node();
+        fdt.finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cpu::Xlen;
+    use crate::fdt::{DeviceNode, FdtBuilder, Platform};
+    use crate::machine::Region;
+
+    fn word(blob: &[u8], offset: usize) -> u32 {
+        u32::from_be_bytes(blob[offset..offset + 4].try_into().unwrap())
+    }
+
+    #[test]
+    fn builder_test() {
+        let mut fdt: FdtBuilder = FdtBuilder::new();
+        fdt.begin_node("");
+        fdt.property_u32("#size-cells", 2);
+        fdt.begin_node("cpus");
+        fdt.property_u32("#size-cells", 0);
+        fdt.end_node();
+        fdt.end_node();
+        let blob: Vec<u8> = fdt.finish();
+        assert_eq!(word(&blob, 0), FdtBuilder::MAGIC);
+        assert_eq!(word(&blob, 4) as usize, blob.len());
+        assert_eq!(word(&blob, 8), 56);
+        // The property name is stored once
+        assert_eq!(&blob[word(&blob, 12) as usize..], b"#size-cells\0");
+        let structure: Vec<u32> = (56..word(&blob, 12) as usize).step_by(4).map(|offset| word(&blob, offset)).collect();
+        assert_eq!(structure, [1, 0, 3, 4, 0, 2, 1, u32::from_be_bytes(*b"cpus"), 0, 3, 4, 0, 0, 2, 2, 9]);
+    }
+
+    #[test]
+    fn platform_test() {
+        let platform: Platform = Platform {
+            xlen: Xlen::Rv64,
+            timebase_frequency: 10_000_000,
+            memory: vec![Region { base: 0x80000000, size: 0x100000 }],
+            clint_base: 0x2000000,
+            clint_size: 0x10000,
+            plic_base: 0xc000000,
+            plic_size: 0x4000000,
+            plic_sources: 31,
+            devices: vec![DeviceNode { name: "serial".to_string(), compatible: vec!["ns16550a".to_string()],
+                                       base: 0x10000000, size: 0x100, irq: Some(10),
+                                       properties: vec![("clock-frequency".to_string(), 3_686_400)] }],
+            console: Some(0x10000000)
+        };
+        let blob: Vec<u8> = platform.to_fdt();
+        assert_eq!(word(&blob, 0), FdtBuilder::MAGIC);
+        let contains = |needle: &[u8]| blob.windows(needle.len()).any(|window| window == needle);
+        assert!(contains(b"memory@80000000\0"));
+        assert!(contains(b"/soc/serial@10000000\0"));
+        assert!(contains(b"rv64ifd_zicond_zba_zbb_zbc_zbs\0"));
+        assert!(contains(b"clock-frequency\0"));
+        assert!(contains(&[0, 0, 0, 0, 0x80, 0, 0, 0, 0, 0, 0, 0, 0, 0x10, 0, 0]));
+    }
+}
@@ -0,0 +1,66 @@
+/// Test finisher of the virt machine of QEMU (sifive_test): a bare-metal
+/// test ends the run by storing a word to it, without any runtime. The low
+/// 16 bits of the word are the command:
+/// - 0x5555 (PASS): exit with code 0
+/// - 0x3333 (FAIL): exit with the code in the upper 16 bits
+/// - 0x7777 (RESET): the machine cannot be reset, the run ends with code 0
+///   as after an SBI reboot
+///
+/// Other values are ignored. The device cannot be read
+#[derive(Clone)]
+pub struct TestFinisher {
+    base: u64
+}
+
+impl TestFinisher {
+    /// Address of the test finisher on the virt machine of QEMU
+    pub const BASE: u64 = 0x100000;
+    pub const SIZE: u64 = 0x1000;
+
+    const FINISHER_FAIL: u64 = 0x3333;
+    const FINISHER_PASS: u64 = 0x5555;
+    const FINISHER_RESET: u64 = 0x7777;
+
+    pub fn new(base: u64) -> TestFinisher {
+        TestFinisher { base }
+    }
+
+    pub fn get_base(&self) -> u64 {
+        self.base
+    }
+
+    /// Check if a store at addr goes to the test finisher
+    #[inline(always)]
+    pub fn contains(&self, addr: u64) -> bool {
+        addr.wrapping_sub(self.base) < TestFinisher::SIZE
+    }
+
+    /// Handle a store of data at addr. It returns the exit code if the
+    /// program asked to end the run
+    pub fn store(&self, addr: u64, data: u64) -> Option<u64> {
+        if addr != self.base {
+            return None;
+        }
+        match data & 0xffff {
+            TestFinisher::FINISHER_PASS | TestFinisher::FINISHER_RESET => Some(0),
+            TestFinisher::FINISHER_FAIL => Some((data >> 16) & 0xffff),
+            _ => None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::finisher::TestFinisher;
+
+    #[test]
+    fn store_test() {
+        let finisher: TestFinisher = TestFinisher::new(TestFinisher::BASE);
+        assert!(finisher.contains(0x100004) && !finisher.contains(0x101000));
+        assert_eq!(finisher.store(0x100000, 0x5555), Some(0));
+        assert_eq!(finisher.store(0x100000, (3 << 16) | 0x3333), Some(3));
+        assert_eq!(finisher.store(0x100000, 0x7777), Some(0));
+        assert_eq!(finisher.store(0x100000, 0x1234), None);
+        assert_eq!(finisher.store(0x100004, 0x5555), None);
+    }
+}
@@ -0,0 +1,231 @@
+use crate::cpu::RegIndex;
+use crate::snapshot::{Snapshot, SnapshotReader, SnapshotWriter};
+use crate::softfloat::{Format, RoundingMode, FLAG_DIVIDE_BY_ZERO, FLAG_INEXACT, FLAG_INVALID, FLAG_OVERFLOW};
+
+/// How the floating-point arithmetic (add, sub, mul, div, sqrt, fma) is computed
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum FloatMode {
+    // The host FPU computes the operations that round to nearest even,
+    // the other rounding modes fall back to the software implementation.
+    // NaNs are canonicalized and the invalid, divide by zero and overflow
+    // flags are raised, but inexact and underflow are not tracked
+    Host,
+    // Every operation is computed in software, bit-exact with the
+    // specification, flags included
+    Soft
+}
+
+impl FloatMode {
+    /// Parse a mode: "host" or "soft"
+    pub fn parse(mode: &str) -> Result<FloatMode, String> {
+        match mode.trim() {
+            "host" => Ok(FloatMode::Host),
+            "soft" => Ok(FloatMode::Soft),
+            mode => Err(format!("Invalid floating-point mode {}: expected host or soft", mode))
+        }
+    }
+}
+
+/// Floating-point registers of the F and D extensions, 64 bits wide.
+/// Single-precision values are NaN-boxed: they are written with the upper
+/// 32 bits set, and reading a register whose upper bits are not all ones
+/// as a single-precision value gives the canonical NaN
+#[derive(Clone)]
+pub struct Fpu {
+    regs: [u64; 32],
+    mode: FloatMode
+}
+
+impl Default for Fpu {
+    fn default() -> Fpu {
+        Fpu::new()
+    }
+}
+
+impl Fpu {
+    const NAN_BOX: u64 = 0xffff_ffff_0000_0000;
+
+    pub fn new() -> Fpu {
+        Fpu { regs: [0; 32], mode: FloatMode::Host }
+    }
+
+    pub fn get_mode(&self) -> FloatMode {
+        self.mode
+    }
+
+    pub fn set_mode(&mut self, mode: FloatMode) {
+        self.mode = mode;
+    }
+
+    /// Read a register as a value of the given format
+    #[inline(always)]
+    pub fn read(&self, reg: RegIndex, fmt: Format) -> u64 {
+        let bits: u64 = self.regs[reg as usize];
+        if fmt == Format::F64 {
+            bits
+        } else if bits & Fpu::NAN_BOX == Fpu::NAN_BOX {
+            bits & 0xffff_ffff
+        } else {
+            Format::F32.canonical_nan()
+        }
+    }
+
+    /// Write a value of the given format into a register
+    #[inline(always)]
+    pub fn write(&mut self, reg: RegIndex, fmt: Format, value: u64) {
+        self.regs[reg as usize] = if fmt == Format::F64 { value } else { value | Fpu::NAN_BOX };
+    }
+
+    /// Read the 64 bits of a register, as they are stored by FSD
+    #[inline(always)]
+    pub fn read_raw(&self, reg: RegIndex) -> u64 {
+        self.regs[reg as usize]
+    }
+
+    // The host FPU is only used to round to nearest even, which is its
+    // default rounding mode
+    fn use_host(&self, rm: RoundingMode) -> bool {
+        self.mode == FloatMode::Host && rm == RoundingMode::NearestEven
+    }
+
+    pub fn add(&self, fmt: Format, a: u64, b: u64, rm: RoundingMode, flags: &mut u8) -> u64 {
+        if !self.use_host(rm) {
+            return fmt.add(a, b, rm, flags);
+        }
+        let result: u64 = host_compute(fmt, &[a, b], |x, y, _| x + y, |x, y, _| x + y);
+        host_flags(fmt, &[a, b], result, false, flags)
+    }
+
+    pub fn sub(&self, fmt: Format, a: u64, b: u64, rm: RoundingMode, flags: &mut u8) -> u64 {
+        if !self.use_host(rm) {
+            return fmt.sub(a, b, rm, flags);
+        }
+        let result: u64 = host_compute(fmt, &[a, b], |x, y, _| x - y, |x, y, _| x - y);
+        host_flags(fmt, &[a, b], result, false, flags)
+    }
+
+    pub fn mul(&self, fmt: Format, a: u64, b: u64, rm: RoundingMode, flags: &mut u8) -> u64 {
+        if !self.use_host(rm) {
+            return fmt.mul(a, b, rm, flags);
+        }
+        let result: u64 = host_compute(fmt, &[a, b], |x, y, _| x * y, |x, y, _| x * y);
+        host_flags(fmt, &[a, b], result, false, flags)
+    }
+
+    pub fn div(&self, fmt: Format, a: u64, b: u64, rm: RoundingMode, flags: &mut u8) -> u64 {
+        if !self.use_host(rm) {
+            return fmt.div(a, b, rm, flags);
+        }
+        let result: u64 = host_compute(fmt, &[a, b], |x, y, _| x / y, |x, y, _| x / y);
+        host_flags(fmt, &[a, b], result, fmt.is_zero(b), flags)
+    }
+
+    pub fn sqrt(&self, fmt: Format, a: u64, rm: RoundingMode, flags: &mut u8) -> u64 {
+        if !self.use_host(rm) {
+            return fmt.sqrt(a, rm, flags);
+        }
+        let result: u64 = host_compute(fmt, &[a], |x, _, _| x.sqrt(), |x, _, _| x.sqrt());
+        host_flags(fmt, &[a], result, false, flags)
+    }
+
+    /// a * b + c with a single rounding
+    pub fn fma(&self, fmt: Format, a: u64, b: u64, c: u64, rm: RoundingMode, flags: &mut u8) -> u64 {
+        if !self.use_host(rm) {
+            return fmt.fma(a, b, c, rm, flags);
+        }
+        let result: u64 = host_compute(fmt, &[a, b, c], |x, y, z| x.mul_add(y, z), |x, y, z| x.mul_add(y, z));
+        host_flags(fmt, &[a, b, c], result, false, flags)
+    }
+}
+
+// Compute an operation of up to three operands with the host FPU
+fn host_compute(fmt: Format, operands: &[u64], single: fn(f32, f32, f32) -> f32,
+                double: fn(f64, f64, f64) -> f64) -> u64 {
+    let operand = |index: usize| operands.get(index).copied().unwrap_or(0);
+    if fmt == Format::F32 {
+        let [x, y, z] = [0, 1, 2].map(|index| f32::from_bits(operand(index) as u32));
+        single(x, y, z).to_bits() as u64
+    } else {
+        let [x, y, z] = [0, 1, 2].map(|index| f64::from_bits(operand(index)));
+        double(x, y, z).to_bits()
+    }
+}
+
+// Flags of a result computed by the host FPU, as far as they can be told
+// from the operands and the result: a NaN is invalid if an operand is a
+// signaling NaN or if no operand is a NaN, and an infinity out of finite
+// operands is an overflow, or a division by zero
+fn host_flags(fmt: Format, operands: &[u64], result: u64, by_zero: bool, flags: &mut u8) -> u64 {
+    if fmt.is_nan(result) {
+        if operands.iter().any(|a| fmt.is_signaling(*a)) || !operands.iter().any(|a| fmt.is_nan(*a)) {
+            *flags |= FLAG_INVALID;
+        }
+        return fmt.canonical_nan();
+    }
+    if fmt.is_infinity(result) && !operands.iter().any(|a| fmt.is_infinity(*a)) {
+        *flags |= if by_zero { FLAG_DIVIDE_BY_ZERO } else { FLAG_OVERFLOW | FLAG_INEXACT };
+    }
+    result
+}
+
+impl Snapshot for Fpu {
+    fn save(&self, writer: &mut SnapshotWriter) {
+        for reg in self.regs.iter() {
+            writer.put_u64(*reg);
+        }
+    }
+
+    fn restore(&mut self, reader: &mut SnapshotReader) -> Result<(), String> {
+        for reg in self.regs.iter_mut() {
+            *reg = reader.get_u64()?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::fpu::{FloatMode, Fpu};
+    use crate::softfloat::{Format, RoundingMode, FLAG_DIVIDE_BY_ZERO, FLAG_INVALID, FLAG_OVERFLOW};
+
+    #[test]
+    fn host_mode_test() {
+        let mut fpu: Fpu = Fpu::new();
+        let one: u64 = 1.0f64.to_bits();
+        let third: u64 = (1.0f64 / 3.0).to_bits();
+        for mode in [FloatMode::Host, FloatMode::Soft] {
+            fpu.set_mode(mode);
+            let mut flags: u8 = 0;
+            assert_eq!(fpu.div(Format::F64, one, 3.0f64.to_bits(), RoundingMode::NearestEven, &mut flags), third);
+            // Rounding up is computed in software in both modes
+            assert_eq!(fpu.div(Format::F64, one, 3.0f64.to_bits(), RoundingMode::Up, &mut flags), third + 1);
+
+            let mut flags: u8 = 0;
+            assert_eq!(fpu.div(Format::F64, one, 0, RoundingMode::NearestEven, &mut flags), f64::INFINITY.to_bits());
+            assert_eq!(flags & FLAG_DIVIDE_BY_ZERO, FLAG_DIVIDE_BY_ZERO);
+
+            let mut flags: u8 = 0;
+            let max: u64 = f32::MAX.to_bits() as u64;
+            assert_eq!(fpu.mul(Format::F32, max, max, RoundingMode::NearestEven, &mut flags), f32::INFINITY.to_bits() as u64);
+            assert_eq!(flags & FLAG_OVERFLOW, FLAG_OVERFLOW);
+
+            // A NaN out of numbers is invalid and canonical
+            let mut flags: u8 = 0;
+            let infinity: u64 = f64::INFINITY.to_bits();
+            assert_eq!(fpu.sub(Format::F64, infinity, infinity, RoundingMode::NearestEven, &mut flags),
+                       Format::F64.canonical_nan());
+            assert_eq!(flags, FLAG_INVALID);
+        }
+    }
+
+    #[test]
+    fn nan_boxing_test() {
+        let mut fpu: Fpu = Fpu::new();
+        fpu.write(1, Format::F32, 1.5f32.to_bits() as u64);
+        assert_eq!(fpu.read_raw(1), 0xffff_ffff_3fc0_0000);
+        assert_eq!(fpu.read(1, Format::F32), 0x3fc0_0000);
+        // A double read as a single is not properly boxed
+        fpu.write(2, Format::F64, 1.5f64.to_bits());
+        assert_eq!(fpu.read(2, Format::F32), Format::F32.canonical_nan());
+    }
+}
@@ -0,0 +1,56 @@
+use crate::cpu::ExitReason;
+
+/// Limits of a run started by Emulator::run_bytes. Every run stops: the
+/// instructions it executes and the memory it gets are both bounded
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct RunLimits {
+    pub max_instructions: u64,
+    // Size of the DRAM placed right after the code
+    pub memory_size: usize
+}
+
+impl Default for RunLimits {
+    fn default() -> RunLimits {
+        RunLimits { max_instructions: 100_000, memory_size: 0x10000 }
+    }
+}
+
+/// What a run started by Emulator::run_bytes did
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct RunOutcome {
+    pub exit_reason: ExitReason,
+    pub instructions: u64,
+    pub pc: u64,
+    pub registers: [u64; 32],
+    // Characters written to the UART
+    pub output: Vec<u8>
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cpu::ExitReason;
+    use crate::emulator::Emulator;
+    use crate::harness::{RunLimits, RunOutcome};
+    use crate::trap::Trap;
+
+    #[test]
+    fn run_bytes_test() {
+        let limits: RunLimits = RunLimits { max_instructions: 100, memory_size: 0x1000 };
+        // li a0, 5; ret
+        let outcome: RunOutcome = Emulator::run_bytes(&[0x13, 0x05, 0x50, 0x00, 0x67, 0x80, 0x00, 0x00], limits).unwrap();
+        assert_eq!((outcome.exit_reason, outcome.instructions, outcome.registers[10]), (ExitReason::Returned(5), 2, 5));
+        // j . never ends
+        let outcome: RunOutcome = Emulator::run_bytes(&[0x6f, 0x00, 0x00, 0x00], limits).unwrap();
+        assert_eq!((outcome.exit_reason, outcome.instructions), (ExitReason::InstructionLimit, 100));
+        // auipc t0, 0; addi t0, t0, 12; csrw mtvec, t0; .word 0: the handler
+        // traps to itself forever, and the traps count against the limit
+        let storm: [u8; 16] = [0x97, 0x02, 0x00, 0x00, 0x93, 0x82, 0xc2, 0x00, 0x73, 0x90, 0x52, 0x30, 0x00, 0x00, 0x00, 0x00];
+        let outcome: RunOutcome = Emulator::run_bytes(&storm, limits).unwrap();
+        assert_eq!((outcome.exit_reason, outcome.instructions), (ExitReason::InstructionLimit, 100));
+        assert_eq!(outcome.pc, Emulator::CODE_ADDRESS + 12);
+        let outcome: RunOutcome = Emulator::run_bytes(&[0xff, 0xff, 0xff, 0xff], limits).unwrap();
+        assert_eq!(outcome.exit_reason, ExitReason::Trap(Trap::IllegalInstruction(0xffffffff)));
+        assert!(Emulator::run_bytes(&[], limits).is_err());
+        assert!(Emulator::run_bytes(&[0x13; 8], RunLimits { max_instructions: 1, memory_size: 0 }).is_err());
+    }
+}
@@ -0,0 +1,103 @@
+/// Checkpoints of a machine taken while it runs in interactive mode, to go
+/// back in time: the nearest checkpoint before the wanted point is restored
+/// and the machine runs again from there (the execution is deterministic),
+/// so that the effects of every single instruction need not be stored.
+/// Positions count the instructions executed since the history started.
+/// A checkpoint is taken every interval instructions; when there are too
+/// many, every other one is dropped and the interval doubles
+#[derive(Clone)]
+pub struct History<T: Clone> {
+    checkpoints: Vec<(u64, T)>,
+    interval: u64,
+    position: u64
+}
+
+impl<T: Clone> History<T> {
+    /// Most checkpoints kept at the same time
+    pub const MAX_CHECKPOINTS: usize = 64;
+
+    /// Start the history from the state start
+    pub fn new(start: T, interval: u64) -> History<T> {
+        History { checkpoints: vec![(0, start)], interval, position: 0 }
+    }
+
+    /// Instructions executed since the history started
+    pub fn get_position(&self) -> u64 {
+        self.position
+    }
+
+    /// The machine executed count instructions
+    pub fn advance(&mut self, count: u64) {
+        self.position += count;
+    }
+
+    /// Instructions that can be executed before the next checkpoint is due
+    pub fn until_checkpoint(&self) -> u64 {
+        let last: u64 = self.checkpoints.last().map_or(0, |(position, _)| *position);
+        (last + self.interval).saturating_sub(self.position)
+    }
+
+    /// Take a checkpoint of state at the current position, replacing the
+    /// one already taken there (e.g. when the state has been changed by hand)
+    pub fn checkpoint(&mut self, state: &T) {
+        if self.checkpoints.last().is_some_and(|(position, _)| *position == self.position) {
+            self.checkpoints.pop();
+        }
+        self.checkpoints.push((self.position, state.clone()));
+        if self.checkpoints.len() > History::<T>::MAX_CHECKPOINTS {
+            // The first checkpoint (the start) is always kept
+            let mut index: usize = 0;
+            self.checkpoints.retain(|_| { index += 1; index % 2 == 1 });
+            self.interval *= 2;
+        }
+    }
+
+    /// Go back to position target (the start at most): return the state of
+    /// the nearest checkpoint before it, and the instructions to execute
+    /// from there to reach it. The later checkpoints are dropped
+    pub fn rewind(&mut self, target: u64) -> (T, u64) {
+        let target: u64 = target.min(self.position);
+        self.checkpoints.retain(|(position, _)| *position <= target);
+        let (position, state) = self.checkpoints.last().cloned().expect("the start is always kept");
+        self.position = target;
+        (state, target - position)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::history::History;
+
+    #[test]
+    fn rewind_test() {
+        // The state is the position itself
+        let mut history: History<u64> = History::new(0, 100);
+        history.advance(100);
+        assert_eq!(history.until_checkpoint(), 0);
+        history.checkpoint(&100);
+        history.advance(150);
+        assert_eq!(history.until_checkpoint(), 0);
+        history.checkpoint(&250);
+        history.advance(30);
+        assert_eq!(history.until_checkpoint(), 70);
+
+        assert_eq!(history.rewind(279), (250, 29));
+        assert_eq!(history.rewind(200), (100, 100));
+        assert_eq!(history.get_position(), 200);
+        assert_eq!(history.rewind(300), (100, 100));
+        assert_eq!(history.rewind(0), (0, 0));
+    }
+
+    #[test]
+    fn thin_out_test() {
+        let mut history: History<u64> = History::new(0, 10);
+        for _ in 0..History::<u64>::MAX_CHECKPOINTS {
+            history.advance(10);
+            history.checkpoint(&history.get_position());
+        }
+        assert_eq!(history.checkpoints.len(), History::<u64>::MAX_CHECKPOINTS / 2 + 1);
+        assert_eq!(history.checkpoints[1].0, 20);
+        assert_eq!(history.until_checkpoint(), 20);
+        assert_eq!(history.rewind(635), (620, 15));
+    }
+}
@@ -0,0 +1,147 @@
+use crate::bus::Bus;
+use crate::cpu::Xlen;
+use crate::memory::AccessSize;
+
+/// Host-target interface of spike: the program writes a command to the
+/// 64-bit tohost variable and the host answers in fromhost. A command is
+/// made of a device (bits 63:56), a command for the device (bits 55:48)
+/// and a payload (bits 47:0). The devices are:
+/// - 0, the system call proxy: an odd payload is (exit code << 1) | 1,
+///   an even one the address of 8 doublewords with the number of the
+///   system call and its arguments, where the result is written back
+/// - 1, the console: command 1 prints the low byte of the payload
+///
+/// Other commands are acknowledged without doing anything
+#[derive(Clone)]
+pub struct Htif {
+    tohost: u64,
+    fromhost: Option<u64>,
+    // An RV32 program has written the low word of tohost, the command is
+    // taken when it writes the high one (or the low one again)
+    pending_low: bool
+}
+
+impl Htif {
+    // System calls served by the proxy (Linux numbers)
+    const SYS_WRITE: u64 = 64;
+    const SYS_EXIT: u64 = 93;
+    const ENOSYS: i64 = 38;
+
+    /// Talk to a program through its tohost and (if any) fromhost variables
+    pub fn new(tohost: u64, fromhost: Option<u64>) -> Htif {
+        Htif { tohost, fromhost, pending_low: false }
+    }
+
+    pub fn get_tohost(&self) -> u64 {
+        self.tohost
+    }
+
+    /// Check if a store at addr writes tohost
+    #[inline(always)]
+    pub fn is_tohost(&self, addr: u64) -> bool {
+        addr.wrapping_sub(self.tohost) < 8
+    }
+
+    /// Handle a store to tohost at addr, once the memory has been written.
+    /// RV64 programs write the whole command at once, RV32 programs the low
+    /// word and then the high one, except for the exit loops of compliance
+    /// tests, which keep writing the low word only. It returns the exit
+    /// code if the program asked to exit
+    pub fn store(&mut self, addr: u64, xlen: Xlen, bus: &mut Bus) -> Option<u64> {
+        let complete: bool = match xlen {
+            Xlen::Rv64 => addr == self.tohost,
+            Xlen::Rv32 => addr == self.tohost + 4 || (addr == self.tohost && self.pending_low)
+        };
+        if !complete {
+            self.pending_low = addr == self.tohost;
+            return None;
+        }
+        self.pending_low = false;
+        let command: u64 = bus.peek(self.tohost, AccessSize::DOUBLEWORD).ok().filter(|command| *command != 0)?;
+        let (device, cmd, payload): (u64, u64, u64) = (command >> 56, (command >> 48) & 0xff, command & 0xffff_ffff_ffff);
+        let mut exit_code: Option<u64> = None;
+        let response: u64 = match (device, cmd) {
+            (0, 0) if payload & 1 != 0 => return Some(payload >> 1),
+            (0, 0) => self.syscall(payload, bus, &mut exit_code),
+            (1, 1) => { bus.write_console(payload as u8); 0 },
+            _ => 0
+        };
+        // The command has been taken, a program waiting for the answer
+        // polls fromhost before sending the next one
+        let _ = bus.write(0, self.tohost, AccessSize::DOUBLEWORD);
+        if let Some(fromhost) = self.fromhost {
+            let _ = bus.write((device << 56) | (cmd << 48) | response, fromhost, AccessSize::DOUBLEWORD);
+        }
+        exit_code
+    }
+
+    // Serve a system call described at magic_mem: the number and the
+    // arguments, the first of which is replaced by the result. Only the
+    // writes to stdout and stderr and exit are supported
+    fn syscall(&self, magic_mem: u64, bus: &mut Bus, exit_code: &mut Option<u64>) -> u64 {
+        let args: Vec<u64> = (0..4)
+            .map(|i| bus.peek(magic_mem + 8 * i, AccessSize::DOUBLEWORD).unwrap_or(0))
+            .collect();
+        let result: u64 = match (args[0], args[1]) {
+            (Htif::SYS_WRITE, 1 | 2) => {
+                for address in args[2]..args[2].wrapping_add(args[3]) {
+                    match bus.peek(address, AccessSize::BYTE) {
+                        Ok(byte) => bus.write_console(byte as u8),
+                        Err(_) => break
+                    }
+                }
+                args[3]
+            },
+            (Htif::SYS_EXIT, code) => { *exit_code = Some(code); 0 },
+            _ => -Htif::ENOSYS as u64
+        };
+        let _ = bus.write(result, magic_mem, AccessSize::DOUBLEWORD);
+        1
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::bus::Bus;
+    use crate::cpu::Xlen;
+    use crate::htif::Htif;
+    use crate::memory::{AccessSize, Memory};
+
+    #[test]
+    fn exit_test() {
+        let mut bus: Bus = Bus::new(Some(Memory::DRAM_DEFAULT_SIZE));
+        bus.set_dram_offset(0x0);
+        let mut htif: Htif = Htif::new(0x40, Some(0x48));
+        bus.write(7, 0x40, AccessSize::DOUBLEWORD).unwrap();
+        assert_eq!(htif.store(0x40, Xlen::Rv64, &mut bus), Some(3));
+
+        // RV32: the command is taken when the high word is written...
+        bus.write(0x41, 0x40, AccessSize::WORD).unwrap();
+        assert_eq!(htif.store(0x40, Xlen::Rv32, &mut bus), None);
+        bus.write(0x0101_0000, 0x44, AccessSize::WORD).unwrap();
+        assert_eq!(htif.store(0x44, Xlen::Rv32, &mut bus), None);
+        assert_eq!(bus.peek(0x40, AccessSize::DOUBLEWORD), Ok(0));
+        assert_eq!(bus.peek(0x48, AccessSize::DOUBLEWORD), Ok(0x0101_0000_0000_0000));
+        // ...or when the low word is written twice
+        bus.write(1, 0x40, AccessSize::WORD).unwrap();
+        assert_eq!(htif.store(0x40, Xlen::Rv32, &mut bus), None);
+        assert_eq!(htif.store(0x40, Xlen::Rv32, &mut bus), Some(0));
+    }
+
+    #[test]
+    fn syscall_test() {
+        let mut bus: Bus = Bus::new(Some(Memory::DRAM_DEFAULT_SIZE));
+        bus.set_dram_offset(0x0);
+        let mut htif: Htif = Htif::new(0x40, None);
+        // exit(5) through the system call proxy
+        bus.write(93, 0x100, AccessSize::DOUBLEWORD).unwrap();
+        bus.write(5, 0x108, AccessSize::DOUBLEWORD).unwrap();
+        bus.write(0x100, 0x40, AccessSize::DOUBLEWORD).unwrap();
+        assert_eq!(htif.store(0x40, Xlen::Rv64, &mut bus), Some(5));
+        // Unknown system calls fail with ENOSYS
+        bus.write(1000, 0x100, AccessSize::DOUBLEWORD).unwrap();
+        bus.write(0x100, 0x40, AccessSize::DOUBLEWORD).unwrap();
+        assert_eq!(htif.store(0x40, Xlen::Rv64, &mut bus), None);
+        assert_eq!(bus.peek(0x100, AccessSize::DOUBLEWORD), Ok(-38i64 as u64));
+    }
+}
@@ -0,0 +1,154 @@
+use crate::cpu::Instruction;
+use crate::memory::AccessSize;
+use crate::rv::{Handler, Operands};
+use crate::trap::Trap;
+
+/// An instruction that has already been fetched and decoded
+#[derive(Clone, Copy)]
+pub struct DecodedInstruction {
+    pub instr: Instruction,
+    pub handler: Handler,
+    pub operands: Operands
+}
+
+#[derive(Clone, Copy)]
+struct CacheEntry {
+    // Address of the instruction, INVALID_PC if the entry is empty
+    pc: u64,
+    decoded: DecodedInstruction
+}
+
+/// Cache of decoded instructions indexed by their address (direct mapped),
+/// so that the body of a loop is fetched and decoded only the first time
+/// it is executed. It must be told about every write to memory that could
+/// hit the code (invalidate) and about every change of the whole memory
+/// (flush), e.g. when a program or a snapshot is loaded or a device
+/// writes to memory. Code written by the program is therefore executed
+/// from the next fetch on, even without the FENCE.I that the ISA
+/// requires (which flushes the whole cache)
+#[derive(Clone)]
+pub struct DecodeCache {
+    entries: Vec<CacheEntry>
+}
+
+impl Default for DecodeCache {
+    fn default() -> DecodeCache {
+        DecodeCache::new()
+    }
+}
+
+impl DecodeCache {
+    // Number of entries, a power of two
+    const SIZE: usize = 4096;
+    // The lowest bit of the PC is always clear, so no entry can match this address
+    const INVALID_PC: u64 = 1;
+
+    pub fn new() -> DecodeCache {
+        let empty: CacheEntry = CacheEntry {
+            pc: DecodeCache::INVALID_PC,
+            decoded: DecodedInstruction { instr: 0, handler: |_, _| Err(Trap::IllegalInstruction(0)), operands: Operands::default() }
+        };
+        DecodeCache { entries: vec![empty; DecodeCache::SIZE] }
+    }
+
+    #[inline(always)]
+    fn index(pc: u64) -> usize {
+        (pc >> 2) as usize & (DecodeCache::SIZE - 1)
+    }
+
+    /// Get the instruction at pc, if it has been decoded
+    #[inline(always)]
+    pub fn lookup(&self, pc: u64) -> Option<&DecodedInstruction> {
+        let entry: &CacheEntry = &self.entries[DecodeCache::index(pc)];
+        if entry.pc == pc {
+            Some(&entry.decoded)
+        } else {
+            None
+        }
+    }
+
+    #[inline(always)]
+    pub fn insert(&mut self, pc: u64, decoded: DecodedInstruction) {
+        self.entries[DecodeCache::index(pc)] = CacheEntry { pc, decoded };
+    }
+
+    /// Forget the instructions overwritten by a store. Stores are aligned,
+    /// so they cover at most two instructions
+    #[inline(always)]
+    pub fn invalidate(&mut self, addr: u64, size: AccessSize) {
+        let first: u64 = addr & !0x3;
+        let last: u64 = addr.wrapping_add(size.bytes() as u64 - 1) & !0x3;
+        for pc in [first, last] {
+            let entry: &mut CacheEntry = &mut self.entries[DecodeCache::index(pc)];
+            if entry.pc == pc {
+                entry.pc = DecodeCache::INVALID_PC;
+            }
+        }
+    }
+
+    /// Forget all instructions
+    pub fn flush(&mut self) {
+        for entry in self.entries.iter_mut() {
+            entry.pc = DecodeCache::INVALID_PC;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cpu::{Cpu, Xlen};
+    use crate::icache::{DecodeCache, DecodedInstruction};
+    use crate::memory::{AccessSize, Memory};
+    use crate::rv::{self, Operands};
+
+    #[test]
+    fn decode_cache_test() {
+        // addi a0, a0, 1
+        const ADDI: u32 = 0x00150513;
+        let decoded: DecodedInstruction = DecodedInstruction {
+            instr: ADDI,
+            handler: rv::predecode::<false>(ADDI, Xlen::Rv64).unwrap(),
+            operands: Operands::new(ADDI)
+        };
+        let mut cache: DecodeCache = DecodeCache::new();
+        assert!(cache.lookup(0x10000).is_none());
+        cache.insert(0x10000, decoded);
+        cache.insert(0x10004, decoded);
+        assert_eq!(cache.lookup(0x10000).map(|decoded| decoded.instr), Some(ADDI));
+        // Same entry, different address
+        assert!(cache.lookup(0x10000 + 4 * DecodeCache::SIZE as u64).is_none());
+
+        // A doubleword store overwrites both of them
+        cache.invalidate(0x10000, AccessSize::DOUBLEWORD);
+        assert!(cache.lookup(0x10000).is_none());
+        assert!(cache.lookup(0x10004).is_none());
+        cache.insert(0x10004, decoded);
+        cache.invalidate(0x10008, AccessSize::BYTE);
+        assert!(cache.lookup(0x10004).is_some());
+        cache.flush();
+        assert!(cache.lookup(0x10004).is_none());
+    }
+
+    #[test]
+    fn self_modifying_code_test() {
+        let mut cpu: Cpu = Cpu::new(Some(Memory::DRAM_DEFAULT_SIZE));
+        // li t2, 2; loop: addi a0, a0, 1; sw t1, 4(zero); addi t2, t2, -1; bnez t2, loop
+        let program: [u32; 5] = [0x00200393, 0x00150513, 0x00602223, 0xfff38393, 0xfe039ae3];
+        let text: Vec<u8> = program.iter().flat_map(|instr| instr.to_le_bytes()).collect();
+        cpu.set_read_only_segment(0x0);
+        cpu.set_read_write_segment(0x1000);
+        cpu.store_from_buffer(&text, 0x0).unwrap();
+        // The first iteration replaces its first instruction with addi a0, a0, 100
+        cpu.write_reg(6, 0x06450513);
+        assert_eq!(cpu.cpu_loop_bounded(9), 9);
+        assert_eq!(cpu.read_reg(10), 101);
+
+        // sw t1, 0x20(zero); fence.i; nop becomes addi a0, a0, 100
+        let program: [u32; 3] = [0x02602023, 0x0000100f, 0x00000013];
+        let text: Vec<u8> = program.iter().flat_map(|instr| instr.to_le_bytes()).collect();
+        cpu.store_from_buffer(&text, 0x18).unwrap();
+        cpu.set_pc(0x18);
+        assert_eq!(cpu.cpu_loop_bounded(3), 3);
+        assert_eq!(cpu.read_reg(10), 201);
+    }
+}
@@ -0,0 +1,265 @@
+use std::fmt;
+use crate::expr;
+
+/// Format of the file holding the program: an ELF executable, or a
+/// firmware image without headers as produced by objcopy
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ImageFormat {
+    Elf,
+    // Raw bytes (objcopy -O binary), loaded at a given address
+    Bin,
+    // Intel HEX records
+    Hex,
+    // Motorola S-records
+    Srec
+}
+
+impl ImageFormat {
+    /// Parse a program format: "elf", "bin", "hex" or "srec"
+    pub fn parse(format: &str) -> Result<ImageFormat, String> {
+        match format.trim() {
+            "elf" => Ok(ImageFormat::Elf),
+            "bin" => Ok(ImageFormat::Bin),
+            "hex" => Ok(ImageFormat::Hex),
+            "srec" => Ok(ImageFormat::Srec),
+            format => Err(format!("Invalid program format {}: expected elf, bin, hex or srec", format))
+        }
+    }
+}
+
+impl fmt::Display for ImageFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ImageFormat::Elf => write!(f, "ELF"),
+            ImageFormat::Bin => write!(f, "raw binary"),
+            ImageFormat::Hex => write!(f, "Intel HEX"),
+            ImageFormat::Srec => write!(f, "S-record")
+        }
+    }
+}
+
+/// Additional file placed into memory before the program starts (e.g. a
+/// kernel, an initrd or a device tree next to a boot loader): with an
+/// address its bytes are loaded there, otherwise it must be an ELF
+/// executable, whose segments go where they belong
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct LoadSpec {
+    pub filename: String,
+    pub address: Option<u64>
+}
+
+impl LoadSpec {
+    /// Parse <file>[@<address>]
+    pub fn parse(spec: &str) -> Result<LoadSpec, String> {
+        match spec.rsplit_once('@') {
+            Some((filename, address)) if !filename.is_empty() =>
+                Ok(LoadSpec { filename: filename.to_string(), address: Some(expr::parse_number(address)?) }),
+            Some(_) => Err(format!("Invalid image {}: expected <file>[@<address>]", spec)),
+            None => Ok(LoadSpec { filename: spec.to_string(), address: None })
+        }
+    }
+}
+
+/// Contiguous bytes of an image and the address they are loaded at
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Chunk {
+    pub address: u64,
+    pub data: Vec<u8>
+}
+
+impl Chunk {
+    /// Address right after the last byte of the chunk
+    pub fn end(&self) -> u64 {
+        self.address + self.data.len() as u64
+    }
+}
+
+/// Firmware image: the bytes to load, in chunks, and the entry point
+/// if the file gives one
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Image {
+    pub chunks: Vec<Chunk>,
+    pub entry: Option<u64>
+}
+
+impl Default for Image {
+    fn default() -> Image {
+        Image::new()
+    }
+}
+
+impl Image {
+    /// Create an empty image
+    pub fn new() -> Image {
+        Image { chunks: Vec::new(), entry: None }
+    }
+
+    /// A raw binary is a single chunk, executed from its first byte
+    pub fn from_binary(data: Vec<u8>, address: u64) -> Image {
+        Image { chunks: vec![Chunk { address, data }], entry: Some(address) }
+    }
+
+    /// Parse the records of an Intel HEX file. Extended segment (02) and
+    /// extended linear (04) address records move the following data, the
+    /// start address records (03 and 05) give the entry point
+    pub fn from_ihex(contents: &str) -> Result<Image, String> {
+        let mut image: Image = Image::new();
+        // Added to the 16-bit address of the data records
+        let mut base: u64 = 0;
+        for (number, line) in contents.lines().enumerate().map(|(i, line)| (i + 1, line.trim())) {
+            if line.is_empty() {
+                continue;
+            }
+            let record: Vec<u8> = match line.strip_prefix(':') {
+                Some(digits) => Image::decode_record(digits, number)?,
+                None => return Err(format!("Line {}: Intel HEX records start with ':'", number))
+            };
+            if record.len() < 5 || record.len() != record[0] as usize + 5 {
+                return Err(format!("Line {}: record length does not match its byte count", number));
+            }
+            if record.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte)) != 0 {
+                return Err(format!("Line {}: wrong checksum", number));
+            }
+            let address: u64 = u16::from_be_bytes([record[1], record[2]]) as u64;
+            let data: &[u8] = &record[4..record.len() - 1];
+            let value = || data.iter().fold(0u64, |value, byte| (value << 8) | *byte as u64);
+            match (record[3], data.len()) {
+                (0x00, _) => image.push(base + address, data),
+                (0x01, _) => return Ok(image),
+                (0x02, 2) => base = value() << 4,
+                (0x03, 4) => image.entry = Some(((value() >> 16) << 4) + (value() & 0xffff)),
+                (0x04, 2) => base = value() << 16,
+                (0x05, 4) => image.entry = Some(value()),
+                (kind, _) => return Err(format!("Line {}: invalid record of type {:02x}", number, kind))
+            }
+        }
+        Err("Missing end of file record".to_string())
+    }
+
+    /// Parse the records of a Motorola S-record file. Data records have
+    /// 16 (S1), 24 (S2) or 32-bit (S3) addresses, the termination records
+    /// (S7, S8, S9) give the entry point. Headers and counts are skipped
+    pub fn from_srec(contents: &str) -> Result<Image, String> {
+        let mut image: Image = Image::new();
+        for (number, line) in contents.lines().enumerate().map(|(i, line)| (i + 1, line.trim())) {
+            if line.is_empty() {
+                continue;
+            }
+            let (kind, record): (char, Vec<u8>) = match (line.strip_prefix('S'), line.chars().nth(1), line.get(2..)) {
+                (Some(_), Some(kind), Some(digits)) => (kind, Image::decode_record(digits, number)?),
+                _ => return Err(format!("Line {}: S-records start with 'S' and their type", number))
+            };
+            if record.len() < 2 || record.len() != record[0] as usize + 1 {
+                return Err(format!("Line {}: record length does not match its byte count", number));
+            }
+            if record.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte)) != 0xff {
+                return Err(format!("Line {}: wrong checksum", number));
+            }
+            let address_size: usize = match kind {
+                '0' | '1' | '5' | '9' => 2,
+                '2' | '6' | '8' => 3,
+                '3' | '7' => 4,
+                _ => return Err(format!("Line {}: invalid record of type S{}", number, kind))
+            };
+            if record.len() < address_size + 2 {
+                return Err(format!("Line {}: record is too short for its address", number));
+            }
+            let address: u64 = record[1..=address_size].iter().fold(0u64, |value, byte| (value << 8) | *byte as u64);
+            match kind {
+                '1' | '2' | '3' => image.push(address, &record[address_size + 1..record.len() - 1]),
+                '7' | '8' | '9' => image.entry = Some(address),
+                _ => ()
+            }
+        }
+        if image.chunks.is_empty() {
+            return Err("No data records found".to_string());
+        }
+        Ok(image)
+    }
+
+    // Decode the hexadecimal digits of a record into bytes
+    fn decode_record(digits: &str, number: usize) -> Result<Vec<u8>, String> {
+        if !digits.is_ascii() {
+            return Err(format!("Line {}: invalid characters in the record", number));
+        }
+        if !digits.len().is_multiple_of(2) {
+            return Err(format!("Line {}: odd number of hexadecimal digits", number));
+        }
+        (0..digits.len()).step_by(2)
+            .map(|i| u8::from_str_radix(&digits[i..i + 2], 16)
+                .map_err(|_| format!("Line {}: invalid hexadecimal digits {}", number, &digits[i..i + 2])))
+            .collect()
+    }
+
+    // Append data at an address, merging it with the last chunk if it
+    // continues it (records are usually contiguous)
+    fn push(&mut self, address: u64, data: &[u8]) {
+        match self.chunks.last_mut() {
+            Some(last) if last.end() == address => last.data.extend_from_slice(data),
+            _ => self.chunks.push(Chunk { address, data: data.to_vec() })
+        }
+    }
+
+    /// Lowest address of the image
+    pub fn start(&self) -> u64 {
+        self.chunks.iter().map(|chunk| chunk.address).min().unwrap_or(0)
+    }
+
+    /// Address right after the highest byte of the image
+    pub fn end(&self) -> u64 {
+        self.chunks.iter().map(|chunk| chunk.end()).max().unwrap_or(0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::image::{Chunk, Image, ImageFormat, LoadSpec};
+
+    #[test]
+    fn format_test() {
+        assert_eq!(ImageFormat::parse("hex"), Ok(ImageFormat::Hex));
+        assert_eq!(ImageFormat::parse("bin"), Ok(ImageFormat::Bin));
+        assert!(ImageFormat::parse("coff").is_err());
+    }
+
+    #[test]
+    fn load_spec_test() {
+        assert_eq!(LoadSpec::parse("initrd.img@0x84000000"),
+                   Ok(LoadSpec { filename: "initrd.img".to_string(), address: Some(0x84000000) }));
+        assert_eq!(LoadSpec::parse("kernel.elf"), Ok(LoadSpec { filename: "kernel.elf".to_string(), address: None }));
+        assert!(LoadSpec::parse("@0x1000").is_err());
+        assert!(LoadSpec::parse("board.dtb@top").is_err());
+    }
+
+    #[test]
+    fn ihex_test() {
+        let contents: &str = ":0200000480007A\n\
+                              :0800100013050000730010004D\n\
+                              :040018006F00000075\n\
+                              :040000058000001067\n\
+                              :00000001FF\n";
+        let image: Image = Image::from_ihex(contents).unwrap();
+        assert_eq!(image.chunks, [Chunk {
+            address: 0x80000010, data: vec![0x13, 0x05, 0x00, 0x00, 0x73, 0x00, 0x10, 0x00, 0x6f, 0x00, 0x00, 0x00]
+        }]);
+        assert_eq!(image.entry, Some(0x80000010));
+        assert_eq!((image.start(), image.end()), (0x80000010, 0x8000001c));
+
+        assert!(Image::from_ihex(":040018006F00000076\n:00000001FF").unwrap_err().contains("checksum"));
+        assert!(Image::from_ihex(":040018006F00000075").unwrap_err().contains("end of file"));
+    }
+
+    #[test]
+    fn srec_test() {
+        let contents: &str = "S005000066771D\n\
+                              S30980000000130500005E\n\
+                              S3098000000473001000EF\n\
+                              S705800000007A\n";
+        let image: Image = Image::from_srec(contents).unwrap();
+        assert_eq!(image.chunks, [Chunk { address: 0x80000000, data: vec![0x13, 0x05, 0x00, 0x00, 0x73, 0x00, 0x10, 0x00] }]);
+        assert_eq!(image.entry, Some(0x80000000));
+
+        assert!(Image::from_srec("S30980000000130500005F").unwrap_err().contains("checksum"));
+        assert!(Image::from_srec("SA0980000000130500005E").is_err());
+    }
+}
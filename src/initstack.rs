@@ -0,0 +1,109 @@
+use crate::cpu::Xlen;
+
+/// Initial stack of a user-space program, laid out as Linux does for the
+/// RISC-V psABI. From the stack pointer up: argc, the argv pointers and a
+/// null pointer, the envp pointers and a null pointer, the auxiliary
+/// vector (type and value pairs, ending with AT_NULL), then the bytes
+/// they point to: the AT_RANDOM bytes and the strings. The stack pointer
+/// is 16-byte aligned
+#[derive(Clone, Default)]
+pub struct InitialStack {
+    args: Vec<String>,
+    env: Vec<String>,
+    auxv: Vec<(u64, u64)>
+}
+
+impl InitialStack {
+    // Types of the entries of the auxiliary vector
+    pub const AT_NULL:   u64 = 0;
+    pub const AT_PHDR:   u64 = 3;
+    pub const AT_PHENT:  u64 = 4;
+    pub const AT_PHNUM:  u64 = 5;
+    pub const AT_PAGESZ: u64 = 6;
+    pub const AT_ENTRY:  u64 = 9;
+    pub const AT_RANDOM: u64 = 25;
+    // Bytes AT_RANDOM points to (e.g. the seed of the stack protector):
+    // they are always the same, so that runs can be reproduced
+    const RANDOM: [u8; 16] = *b"riviera-seed-16b";
+
+    pub fn new(args: &[String], env: &[String]) -> InitialStack {
+        InitialStack { args: args.to_vec(), env: env.to_vec(), auxv: Vec::new() }
+    }
+
+    /// Add an entry to the auxiliary vector (AT_RANDOM is always there)
+    pub fn push_aux(&mut self, key: u64, value: u64) {
+        self.auxv.push((key, value));
+    }
+
+    /// Lay out the stack right below top for a program with registers of
+    /// the given width. It returns the stack pointer and the bytes to be
+    /// stored there, None if it does not fit below top
+    pub fn build(&self, top: u64, xlen: Xlen) -> Option<(u64, Vec<u8>)> {
+        let word: u64 = xlen.bits() as u64 / 8;
+        let strings: Vec<&String> = self.args.iter().chain(self.env.iter()).collect();
+        let info_size: u64 = InitialStack::RANDOM.len() as u64 + strings.iter().map(|string| string.len() as u64 + 1).sum::<u64>();
+        let info_start: u64 = top.checked_sub(info_size)? & !0xf;
+        // argc, argv and envp with their null pointers, the auxiliary
+        // vector with AT_RANDOM and AT_NULL
+        let words: u64 = 1 + (self.args.len() as u64 + 1) + (self.env.len() as u64 + 1) + 2 * (self.auxv.len() as u64 + 2);
+        let sp: u64 = info_start.checked_sub(words * word)? & !0xf;
+
+        let mut bytes: Vec<u8> = vec![0; (info_start + info_size - sp) as usize];
+        let mut table: Vec<u64> = vec![self.args.len() as u64];
+        let mut address: u64 = info_start + InitialStack::RANDOM.len() as u64;
+        let mut pointers: Vec<u64> = Vec::new();
+        for string in &strings {
+            let offset: usize = (address - sp) as usize;
+            bytes[offset..offset + string.len()].copy_from_slice(string.as_bytes());
+            pointers.push(address);
+            address += string.len() as u64 + 1;
+        }
+        let (argv, envp) = pointers.split_at(self.args.len());
+        table.extend(argv.iter().copied().chain([0]));
+        table.extend(envp.iter().copied().chain([0]));
+        for (key, value) in self.auxv.iter().chain(&[(InitialStack::AT_RANDOM, info_start), (InitialStack::AT_NULL, 0)]) {
+            table.extend([*key, *value]);
+        }
+        for (i, value) in table.iter().enumerate() {
+            let offset: usize = i * word as usize;
+            bytes[offset..offset + word as usize].copy_from_slice(&value.to_le_bytes()[..word as usize]);
+        }
+        let offset: usize = (info_start - sp) as usize;
+        bytes[offset..offset + InitialStack::RANDOM.len()].copy_from_slice(&InitialStack::RANDOM);
+        Some((sp, bytes))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cpu::Xlen;
+    use crate::initstack::InitialStack;
+
+    #[test]
+    fn initial_stack_test() {
+        let word = |bytes: &[u8], i: usize| u64::from_le_bytes(bytes[i * 8..i * 8 + 8].try_into().unwrap());
+        let mut stack: InitialStack = InitialStack::new(&["prog".to_string(), "-v".to_string()], &["HOME=/".to_string()]);
+        stack.push_aux(InitialStack::AT_PAGESZ, 4096);
+        let (sp, bytes) = stack.build(0x10000, Xlen::Rv64).unwrap();
+        assert_eq!(sp % 16, 0);
+        assert!(sp + bytes.len() as u64 <= 0x10000);
+        // argc, argv, envp and the auxiliary vector
+        assert_eq!(word(&bytes, 0), 2);
+        let argv1: u64 = word(&bytes, 2);
+        assert_eq!(&bytes[(argv1 - sp) as usize..(argv1 - sp) as usize + 3], b"-v\0");
+        assert_eq!(word(&bytes, 3), 0);
+        let envp0: u64 = word(&bytes, 4);
+        assert_eq!(&bytes[(envp0 - sp) as usize..(envp0 - sp) as usize + 7], b"HOME=/\0");
+        assert_eq!(word(&bytes, 5), 0);
+        assert_eq!((word(&bytes, 6), word(&bytes, 7)), (InitialStack::AT_PAGESZ, 4096));
+        assert_eq!(word(&bytes, 8), InitialStack::AT_RANDOM);
+        assert_eq!((word(&bytes, 10), word(&bytes, 11)), (InitialStack::AT_NULL, 0));
+
+        // RV32 has 4-byte pointers
+        let (sp, bytes) = InitialStack::new(&[], &[]).build(0x1000, Xlen::Rv32).unwrap();
+        assert_eq!(&bytes[..12], &[0; 12]);
+        assert_eq!(u32::from_le_bytes(bytes[12..16].try_into().unwrap()) as u64, InitialStack::AT_RANDOM);
+        assert!(sp < 0x1000 - 16);
+        assert!(InitialStack::new(&[], &[]).build(0x10, Xlen::Rv64).is_none());
+    }
+}
@@ -0,0 +1,240 @@
+use std::fmt;
+use crate::cli::GuestStdio;
+
+/// Input that comes from outside of the emulated machine, the only thing
+/// that can make two runs of the same program behave differently
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum InputEvent {
+    // Character received by the UART
+    Uart(u8),
+    // Bytes read from the standard input of the host (read system call)
+    Stdin(Vec<u8>),
+    // Time of the host read by the program, in microseconds since the epoch
+    HostTime(u64),
+    // The interrupt line of the attached device at the given position changed
+    DeviceInterrupt(usize, bool)
+}
+
+impl fmt::Display for InputEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            InputEvent::Uart(byte) => write!(f, "uart 0x{:02x}", byte),
+            InputEvent::Stdin(bytes) if bytes.is_empty() => write!(f, "stdin -"),
+            InputEvent::Stdin(bytes) => write!(f, "stdin {}", bytes.iter().map(|byte| format!("{:02x}", byte)).collect::<String>()),
+            InputEvent::HostTime(time) => write!(f, "time {}", time),
+            InputEvent::DeviceInterrupt(device, level) => write!(f, "irq {} {}", device, *level as u8)
+        }
+    }
+}
+
+/// Log of the inputs of a run, each one stamped with the instant (number
+/// of executed instructions) it arrived at. A recorded log can be replayed:
+/// the inputs are then taken from it instead of the host, at the same
+/// instants, so that the run is reproduced exactly. On disk it is a text
+/// file with one input per line:
+///
+/// ```text
+/// 1200 uart 0x61
+/// 1500 stdin 68690a
+/// 1800 time 1700000000123456
+/// 2000 irq 0 1
+/// ```
+#[derive(Clone, Default)]
+pub struct InputLog {
+    replaying: bool,
+    events: Vec<(u64, InputEvent)>,
+    // Next event to be replayed
+    next: usize,
+    // Current level of the interrupt line of each attached device
+    device_lines: Vec<bool>
+}
+
+impl InputLog {
+    const HEADER: &'static str = "# riviera input log";
+
+    /// Start recording the inputs
+    pub fn recording() -> InputLog {
+        InputLog::default()
+    }
+
+    /// Replay the inputs of a recorded log
+    pub fn replaying(recorded: &InputLog) -> InputLog {
+        InputLog { replaying: true, events: recorded.events.clone(), next: 0, device_lines: Vec::new() }
+    }
+
+    pub fn is_replaying(&self) -> bool {
+        self.replaying
+    }
+
+    pub fn len(&self) -> usize {
+        self.events.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.events.is_empty()
+    }
+
+    /// Recorded events that have not been replayed. If there are some at
+    /// the end of the run, the execution went a different way
+    pub fn remaining(&self) -> &[(u64, InputEvent)] {
+        &self.events[self.next..]
+    }
+
+    /// Record an input that arrived at instant
+    pub fn record(&mut self, instant: u64, event: InputEvent) {
+        self.events.push((instant, event));
+    }
+
+    /// Take the next recorded event, if it arrived at instant and it is
+    /// the kind of input wanted
+    pub fn replay(&mut self, instant: u64, wanted: impl Fn(&InputEvent) -> bool) -> Option<InputEvent> {
+        match self.events.get(self.next) {
+            Some((at, event)) if *at == instant && wanted(event) => {
+                self.next += 1;
+                Some(event.clone())
+            },
+            _ => None
+        }
+    }
+
+    /// Time of the host (now, in microseconds) read by the program:
+    /// recorded, or replaced with the recorded one
+    pub fn host_time(&mut self, instant: u64, now: u64) -> u64 {
+        if !self.replaying {
+            self.record(instant, InputEvent::HostTime(now));
+            return now;
+        }
+        match self.replay(instant, |event| matches!(event, InputEvent::HostTime(_))) {
+            Some(InputEvent::HostTime(time)) => time,
+            _ => now
+        }
+    }
+
+    /// Read the standard input of the guest into data, recording what is
+    /// read, or take the recorded bytes instead (none if the recording
+    /// has no read at this instant)
+    pub fn read_stdin(&mut self, instant: u64, data: &mut [u8], stdio: &GuestStdio) -> std::io::Result<usize> {
+        if !self.replaying {
+            let read: usize = stdio.read_stdin(data)?;
+            self.record(instant, InputEvent::Stdin(data[..read].to_vec()));
+            return Ok(read);
+        }
+        match self.replay(instant, |event| matches!(event, InputEvent::Stdin(_))) {
+            Some(InputEvent::Stdin(bytes)) => {
+                let read: usize = bytes.len().min(data.len());
+                data[..read].copy_from_slice(&bytes[..read]);
+                Ok(read)
+            },
+            _ => Ok(0)
+        }
+    }
+
+    /// Level of the interrupt line of the attached device at position
+    /// device, whose line is at level: the changes are recorded, or the
+    /// recorded level is used instead
+    pub fn device_interrupt(&mut self, instant: u64, device: usize, level: bool) -> bool {
+        if self.device_lines.len() <= device {
+            self.device_lines.resize(device + 1, false);
+        }
+        if self.replaying {
+            let wanted = |event: &InputEvent| matches!(event, InputEvent::DeviceInterrupt(index, _) if *index == device);
+            while let Some(InputEvent::DeviceInterrupt(_, recorded)) = self.replay(instant, wanted) {
+                self.device_lines[device] = recorded;
+            }
+        } else if level != self.device_lines[device] {
+            self.device_lines[device] = level;
+            self.record(instant, InputEvent::DeviceInterrupt(device, level));
+        }
+        self.device_lines[device]
+    }
+
+    /// Write the log as text
+    pub fn to_text(&self) -> String {
+        let mut text: String = format!("{}\n", InputLog::HEADER);
+        for (instant, event) in &self.events {
+            text += &format!("{} {}\n", instant, event);
+        }
+        text
+    }
+
+    /// Read a log written by to_text
+    pub fn parse(text: &str) -> Result<InputLog, String> {
+        let mut log: InputLog = InputLog::recording();
+        for (number, line) in text.lines().enumerate().map(|(i, line)| (i + 1, line.trim())) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let invalid = || format!("Line {}: invalid input {}", number, line);
+            let instant: u64 = fields[0].parse().map_err(|_| invalid())?;
+            let event: InputEvent = match fields[1..] {
+                ["uart", byte] => InputEvent::Uart(byte.strip_prefix("0x")
+                    .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+                    .ok_or_else(invalid)?),
+                ["stdin", "-"] => InputEvent::Stdin(Vec::new()),
+                ["stdin", hex] if hex.is_ascii() && hex.len().is_multiple_of(2) => InputEvent::Stdin((0..hex.len()).step_by(2)
+                    .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).map_err(|_| invalid()))
+                    .collect::<Result<Vec<u8>, String>>()?),
+                ["time", time] => InputEvent::HostTime(time.parse().map_err(|_| invalid())?),
+                ["irq", device, "0" | "1"] => InputEvent::DeviceInterrupt(device.parse().map_err(|_| invalid())?, fields[3] == "1"),
+                _ => return Err(invalid())
+            };
+            if log.events.last().is_some_and(|(last, _)| *last > instant) {
+                return Err(format!("Line {}: inputs are not in chronological order", number));
+            }
+            log.record(instant, event);
+        }
+        Ok(log)
+    }
+
+    pub fn read_from_file(filename: &str) -> Result<InputLog, String> {
+        let text: String = std::fs::read_to_string(filename)
+            .map_err(|why| format!("Could not read {}: {}", filename, why))?;
+        InputLog::parse(&text).map_err(|why| format!("Invalid input log {}: {}", filename, why))
+    }
+
+    pub fn write_to_file(&self, filename: &str) -> Result<(), String> {
+        std::fs::write(filename, self.to_text())
+            .map_err(|why| format!("Could not write {}: {}", filename, why))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::inputlog::{InputEvent, InputLog};
+
+    #[test]
+    fn text_test() {
+        let mut log: InputLog = InputLog::recording();
+        log.record(10, InputEvent::Uart(b'a'));
+        log.record(12, InputEvent::Stdin(b"hi\n".to_vec()));
+        log.record(12, InputEvent::Stdin(Vec::new()));
+        log.record(15, InputEvent::HostTime(1_700_000_000_123_456));
+        log.record(20, InputEvent::DeviceInterrupt(1, true));
+        let parsed: InputLog = InputLog::parse(&log.to_text()).unwrap();
+        assert_eq!(parsed.events, log.events);
+        assert!(InputLog::parse("10 uart 97").is_err());
+        assert!(InputLog::parse("10 irq 0 2").is_err());
+        assert!(InputLog::parse("10 time 5\n9 time 6").is_err());
+    }
+
+    #[test]
+    fn replay_test() {
+        let mut recording: InputLog = InputLog::recording();
+        assert_eq!(recording.host_time(5, 1000), 1000);
+        assert!(!recording.device_interrupt(6, 0, false));
+        assert!(recording.device_interrupt(7, 0, true));
+        assert!(recording.device_interrupt(8, 0, true));
+        assert!(!recording.device_interrupt(9, 0, false));
+        assert_eq!(recording.len(), 3);
+
+        let mut replay: InputLog = InputLog::replaying(&recording);
+        // The recorded time is read again, whatever the host says
+        assert_eq!(replay.host_time(5, 2000), 1000);
+        let levels: Vec<bool> = (6..10).map(|instant| replay.device_interrupt(instant, 0, false)).collect();
+        assert_eq!(levels, [false, true, true, false]);
+        assert!(replay.remaining().is_empty());
+        // An input that is not in the recording is the one of the host
+        assert_eq!(replay.host_time(11, 3000), 3000);
+    }
+}
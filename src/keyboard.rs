@@ -0,0 +1,121 @@
+use std::io::Read;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+#[cfg(unix)]
+use std::sync::OnceLock;
+
+// Settings of the terminal before it was switched to raw mode
+#[cfg(unix)]
+static SAVED_TERMINAL: OnceLock<libc::termios> = OnceLock::new();
+// The keys no longer go to the guest (see Keyboard::suspend)
+static SUSPENDED: AtomicBool = AtomicBool::new(false);
+
+/// Keyboard of the host: the standard input is read by a thread of its
+/// own, so that the emulator never blocks waiting for a key. When it is a
+/// terminal it is switched to raw mode (no line buffering, no echo): each
+/// key reaches the guest as soon as it is pressed and the guest echoes it,
+/// as on a serial console. Ctrl-C is still handled by riviera. The terminal is
+/// restored when riviera exits or is stopped by Ctrl-C
+pub struct Keyboard {
+    receiver: Receiver<u8>
+}
+
+impl Keyboard {
+    pub fn open() -> Keyboard {
+        #[cfg(unix)]
+        raw_mode();
+        let (sender, receiver) = mpsc::channel::<u8>();
+        std::thread::spawn(move || read_keys(sender));
+        Keyboard { receiver }
+    }
+
+    /// Take the next key that has been pressed, if any, without blocking
+    pub fn read_byte(&mut self) -> Option<u8> {
+        self.receiver.try_recv().ok()
+    }
+
+    /// Stop reading the keys for the guest and give the terminal back as
+    /// it was, so that the standard input can be read by someone else
+    /// (e.g. the interactive mode opened by Ctrl-C). The keys pressed
+    /// afterwards never reach the guest
+    pub fn suspend() {
+        SUSPENDED.store(true, Ordering::Relaxed);
+        #[cfg(unix)]
+        restore_terminal();
+    }
+}
+
+// Pass the bytes of the standard input one at a time, until it is closed,
+// the keyboard is dropped or suspended
+fn read_keys(sender: Sender<u8>) {
+    let mut byte: [u8; 1] = [0];
+    while !SUSPENDED.load(Ordering::Relaxed) {
+        // Wait for a key without reading it, so that a suspended keyboard
+        // leaves it to the new reader
+        #[cfg(unix)]
+        if !key_pressed() || SUSPENDED.load(Ordering::Relaxed) {
+            continue;
+        }
+        match std::io::stdin().read(&mut byte) {
+            Ok(1) => if sender.send(byte[0]).is_err() {
+                break;
+            },
+            _ => break
+        }
+    }
+}
+
+// Wait a little for the standard input to be readable (or closed)
+#[cfg(unix)]
+fn key_pressed() -> bool {
+    const WAIT_MS: libc::c_int = 100;
+    let mut stdin: libc::pollfd = libc::pollfd { fd: libc::STDIN_FILENO, events: libc::POLLIN, revents: 0 };
+    unsafe { libc::poll(&mut stdin, 1, WAIT_MS) > 0 }
+}
+
+// Turn off line buffering and echo on the terminal, if the standard input
+// is one, and restore it at exit and on Ctrl-C
+#[cfg(unix)]
+fn raw_mode() {
+    unsafe {
+        if libc::isatty(libc::STDIN_FILENO) != 1 || SAVED_TERMINAL.get().is_some() {
+            return;
+        }
+        let mut settings: libc::termios = std::mem::zeroed();
+        if libc::tcgetattr(libc::STDIN_FILENO, &mut settings) != 0 {
+            return;
+        }
+        let _ = SAVED_TERMINAL.set(settings);
+        settings.c_lflag &= !(libc::ICANON | libc::ECHO);
+        settings.c_cc[libc::VMIN] = 1;
+        settings.c_cc[libc::VTIME] = 0;
+        libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &settings);
+        libc::atexit(restore_at_exit);
+        libc::signal(libc::SIGINT, on_sigint as *const () as libc::sighandler_t);
+    }
+}
+
+// Put the terminal back as it was before the keyboard was opened
+#[cfg(unix)]
+fn restore_terminal() {
+    if let Some(settings) = SAVED_TERMINAL.get() {
+        unsafe {
+            libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, settings);
+        }
+    }
+}
+
+#[cfg(unix)]
+extern "C" fn restore_at_exit() {
+    restore_terminal();
+}
+
+// Restore the terminal, then let Ctrl-C terminate riviera as usual
+#[cfg(unix)]
+extern "C" fn on_sigint(_signal: libc::c_int) {
+    restore_terminal();
+    unsafe {
+        libc::signal(libc::SIGINT, libc::SIG_DFL);
+        libc::raise(libc::SIGINT);
+    }
+}
@@ -0,0 +1,78 @@
+//! riviera: a RISC-V emulator written in Rust.
+//! The emulator is available as a library so that it can be embedded,
+//! benchmarked and tested; the command line interface lives in main.rs
+
+pub mod cpu;
+pub mod bus;
+pub mod machine;
+pub mod memory;
+#[cfg(unix)]
+pub mod mmap;
+pub mod rv;
+pub mod selftest;
+pub mod disas;
+pub mod icache;
+pub mod cache;
+pub mod elf;
+pub mod fdt;
+pub mod image;
+pub mod log;
+pub mod emulator;
+pub mod harness;
+pub mod uart;
+pub mod console;
+pub mod clint;
+pub mod rtc;
+pub mod timebase;
+pub mod pacing;
+pub mod events;
+pub mod logport;
+pub mod plic;
+pub mod virtio;
+pub mod device;
+pub mod plugin;
+pub mod testing;
+pub mod cli;
+pub mod keyboard;
+pub mod prompt;
+pub mod serial;
+pub mod cancel;
+pub mod symbols;
+pub mod expr;
+pub mod breakpoint;
+pub mod bisect;
+pub mod trap;
+pub mod accounting;
+pub mod profile;
+pub mod coverage;
+pub mod profiling;
+pub mod csr;
+pub mod counters;
+pub mod pmp;
+pub mod softfloat;
+pub mod fpu;
+pub mod rvfd;
+pub mod semihosting;
+pub mod syscall;
+pub mod initstack;
+pub mod sbi;
+pub mod htif;
+pub mod finisher;
+pub mod step;
+pub mod stackguard;
+pub mod callstack;
+pub mod codewatch;
+pub mod taint;
+pub mod trace;
+pub mod memtrace;
+pub mod snapshot;
+pub mod history;
+pub mod replay;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod script;
+pub mod inputlog;
+pub mod signature;
+pub mod stats;
+pub mod report;
+#[cfg(target_arch = "wasm32")]
+pub mod wasm;
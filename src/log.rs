@@ -0,0 +1,46 @@
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use colored::Colorize;
+
+// Set by --quiet
+static QUIET: AtomicBool = AtomicBool::new(false);
+
+/// Informational messages of riviera (`[*]`, on stdout) and errors (`[x]`,
+/// on stderr), printed with the info! and error! macros. In quiet mode the
+/// informational messages are dropped, so that only the output of the
+/// guest and the errors are left in the logs of scripts
+pub fn set_quiet(quiet: bool) {
+    QUIET.store(quiet, Ordering::Relaxed);
+}
+
+pub fn is_quiet() -> bool {
+    QUIET.load(Ordering::Relaxed)
+}
+
+/// Turn the colors of the output on or off. Without a call the colors are
+/// on, unless the NO_COLOR environment variable is set
+pub fn set_color(color: bool) {
+    colored::control::set_override(color);
+}
+
+pub fn info(message: fmt::Arguments) {
+    if !is_quiet() {
+        println!("{} {}", "[*]".green(), message);
+    }
+}
+
+pub fn error(message: fmt::Arguments) {
+    eprintln!("{} {}", "[x]".red(), message);
+}
+
+/// Print an informational message, unless in quiet mode
+#[macro_export]
+macro_rules! info {
+    ($($arg:tt)*) => { $crate::log::info(format_args!($($arg)*)) };
+}
+
+/// Print an error on stderr
+#[macro_export]
+macro_rules! error {
+    ($($arg:tt)*) => { $crate::log::error(format_args!($($arg)*)) };
+}
@@ -0,0 +1,124 @@
+use std::time::Instant;
+use colored::Colorize;
+use crate::snapshot::{Snapshot, SnapshotReader, SnapshotWriter};
+
+/// Debug channel from the guest to the host, separate from the UART console.
+/// A record is sent by writing its length in bytes to the LENGTH register and
+/// then its bytes, one at a time, to the DATA register. When the last byte
+/// is written the record is printed on stderr together with the time the
+/// emulator has been running and the guest time (mtime)
+#[derive(Clone)]
+pub struct LogPort {
+    record: Vec<u8>,
+    length: usize,
+    start: Instant,
+    // Records are kept instead of being printed (used by the tests)
+    quiet: bool,
+    last_record: Option<String>
+}
+
+impl Default for LogPort {
+    fn default() -> LogPort {
+        LogPort::new()
+    }
+}
+
+impl LogPort {
+    pub const LENGTH_ADDR: u64 = 0x0;
+    pub const DATA_ADDR:   u64 = 0x4;
+
+    // Longest record that is accepted, longer ones are truncated
+    const MAX_LENGTH: usize = 4096;
+
+    pub fn new() -> LogPort {
+        LogPort {
+            record: Vec::new(),
+            length: 0,
+            start: Instant::now(),
+            quiet: false,
+            last_record: None
+        }
+    }
+
+    /// Write a register. The guest time is the value of mtime when the
+    /// register is written
+    pub fn write(&mut self, offset: u64, data: u64, mtime: u64) -> Option<()> {
+        match offset {
+            LogPort::LENGTH_ADDR => {
+                // A new record discards a partial one
+                self.record.clear();
+                self.length = (data as usize).min(LogPort::MAX_LENGTH);
+                if self.length == 0 {
+                    self.emit(mtime);
+                }
+            },
+            LogPort::DATA_ADDR => {
+                if self.record.len() < self.length {
+                    self.record.push(data as u8);
+                    if self.record.len() == self.length {
+                        self.emit(mtime);
+                    }
+                }
+            },
+            _ => return None
+        }
+        Some(())
+    }
+
+    /// Read a register: LENGTH holds the number of bytes still missing
+    /// to complete the current record, DATA always reads 0
+    pub fn read(&self, offset: u64) -> Option<u64> {
+        match offset {
+            LogPort::LENGTH_ADDR => Some((self.length - self.record.len()) as u64),
+            LogPort::DATA_ADDR => Some(0),
+            _ => None
+        }
+    }
+
+    fn emit(&mut self, mtime: u64) {
+        let message: String = String::from_utf8_lossy(&self.record).into_owned();
+        if self.quiet {
+            self.last_record = Some(message);
+        } else {
+            eprintln!("{} {:>12.6}s mtime={} {}", "[log]".cyan(),
+                      self.start.elapsed().as_secs_f64(), mtime, message);
+        }
+        self.record.clear();
+        self.length = 0;
+    }
+}
+
+impl Snapshot for LogPort {
+    fn save(&self, writer: &mut SnapshotWriter) {
+        writer.put_bytes(&self.record);
+        writer.put_u64(self.length as u64);
+    }
+
+    fn restore(&mut self, reader: &mut SnapshotReader) -> Result<(), String> {
+        self.record = reader.get_bytes()?;
+        self.length = (reader.get_u64()? as usize).min(LogPort::MAX_LENGTH);
+        self.record.truncate(self.length);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::logport::LogPort;
+
+    #[test]
+    fn record_test() {
+        let mut port: LogPort = LogPort::new();
+        port.quiet = true;
+        port.write(LogPort::LENGTH_ADDR, 2, 0).unwrap();
+        port.write(LogPort::DATA_ADDR, b'h' as u64, 0).unwrap();
+        assert_eq!(port.read(LogPort::LENGTH_ADDR), Some(1));
+        assert_eq!(port.last_record, None);
+        port.write(LogPort::DATA_ADDR, b'i' as u64, 0).unwrap();
+        assert_eq!(port.last_record.as_deref(), Some("hi"));
+        // Bytes outside of a record are ignored
+        port.write(LogPort::DATA_ADDR, b'x' as u64, 0).unwrap();
+        assert_eq!(port.read(LogPort::LENGTH_ADDR), Some(0));
+        assert_eq!(port.write(0x8, 0, 0), None);
+    }
+}
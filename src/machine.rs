@@ -0,0 +1,197 @@
+use crate::bus::Bus;
+use crate::expr;
+
+/// A range of addresses described by the machine
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Region {
+    pub base: u64,
+    pub size: u64
+}
+
+impl Region {
+    fn end(&self) -> u64 {
+        self.base + self.size
+    }
+}
+
+/// Memory map of the emulated platform: the RAM and ROM regions and the
+/// addresses of the memory-mapped devices. Without RAM regions the default
+/// layout is used, where ROM and DRAM are placed where the ELF wants them.
+/// A machine is described by a file like this one (numbers can be decimal
+/// or hexadecimal, the [devices] entries are optional):
+///
+/// ```toml
+/// [[ram]]
+/// base = 0x80000000
+/// size = 0x100000
+///
+/// [[rom]]
+/// base = 0x20000000
+/// size = 0x10000
+///
+/// [devices]
+/// bootrom = 0x1000
+/// clint = 0x2000000
+/// plic = 0xc000000
+/// uart = 0x10000000
+/// logport = 0x10001000
+/// rtc = 0x10007000
+/// virtio = 0x10008000
+/// ```
+///
+/// The first RAM region is the main memory: the stack starts at its end
+/// and it is the one that gets dumped
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct MachineConfig {
+    pub ram: Vec<Region>,
+    pub rom: Vec<Region>,
+    pub bootrom_base: u64,
+    pub clint_base: u64,
+    pub plic_base: u64,
+    pub uart_base: u64,
+    pub logport_base: u64,
+    pub rtc_base: u64,
+    pub virtio_base: u64
+}
+
+// Section of the description file being parsed
+enum Section {
+    Top,
+    Ram,
+    Rom,
+    Devices
+}
+
+impl Default for MachineConfig {
+    fn default() -> MachineConfig {
+        MachineConfig::new()
+    }
+}
+
+impl MachineConfig {
+    /// Default machine: no fixed memory regions and the devices at their
+    /// usual addresses
+    pub fn new() -> MachineConfig {
+        MachineConfig {
+            ram: Vec::new(),
+            rom: Vec::new(),
+            bootrom_base: Bus::BOOTROM_BASE,
+            clint_base: Bus::CLINT_BASE,
+            plic_base: Bus::PLIC_BASE,
+            uart_base: Bus::UART_BASE,
+            logport_base: Bus::LOGPORT_BASE,
+            rtc_base: Bus::RTC_BASE,
+            virtio_base: Bus::VIRTIO_BASE
+        }
+    }
+
+    pub fn read_from_file(filename: &str) -> Result<MachineConfig, String> {
+        match std::fs::read_to_string(filename) {
+            Ok(text) => MachineConfig::parse(&text).map_err(|err| format!("{}: {}", filename, err)),
+            Err(why) => Err(format!("Could not read machine description {}: {}", filename, why))
+        }
+    }
+
+    /// Parse a machine description (a small subset of TOML)
+    pub fn parse(text: &str) -> Result<MachineConfig, String> {
+        let mut machine: MachineConfig = MachineConfig::new();
+        let mut section: Section = Section::Top;
+        // Regions are filled field by field, a missing field is found when they are checked
+        let mut ram: Vec<(Option<u64>, Option<u64>)> = Vec::new();
+        let mut rom: Vec<(Option<u64>, Option<u64>)> = Vec::new();
+
+        for (index, line) in text.lines().enumerate() {
+            let line: &str = line.split('#').next().unwrap_or("").trim();
+            let error = |message: String| format!("line {}: {}", index + 1, message);
+            match line {
+                "" => continue,
+                "[[ram]]" => { section = Section::Ram; ram.push((None, None)); continue },
+                "[[rom]]" => { section = Section::Rom; rom.push((None, None)); continue },
+                "[devices]" => { section = Section::Devices; continue },
+                _ if line.starts_with('[') => return Err(error(format!("unknown section {}", line))),
+                _ => ()
+            }
+
+            let (key, value) = match line.split_once('=') {
+                Some((key, value)) => (key.trim(), expr::parse_number(value).map_err(error)?),
+                None => return Err(error(format!("expected key = value, found \"{}\"", line)))
+            };
+            let field: &mut Option<u64> = match (&section, key) {
+                (Section::Ram, "base") => &mut ram.last_mut().unwrap().0,
+                (Section::Ram, "size") => &mut ram.last_mut().unwrap().1,
+                (Section::Rom, "base") => &mut rom.last_mut().unwrap().0,
+                (Section::Rom, "size") => &mut rom.last_mut().unwrap().1,
+                (Section::Devices, "bootrom") => { machine.bootrom_base = value; continue },
+                (Section::Devices, "clint") => { machine.clint_base = value; continue },
+                (Section::Devices, "plic") => { machine.plic_base = value; continue },
+                (Section::Devices, "uart") => { machine.uart_base = value; continue },
+                (Section::Devices, "logport") => { machine.logport_base = value; continue },
+                (Section::Devices, "rtc") => { machine.rtc_base = value; continue },
+                (Section::Devices, "virtio") => { machine.virtio_base = value; continue },
+                (Section::Top, _) => return Err(error(format!("{} is outside of any section", key))),
+                _ => return Err(error(format!("unknown key {}", key)))
+            };
+            *field = Some(value);
+        }
+
+        machine.ram = MachineConfig::check_regions(&ram, "RAM")?;
+        machine.rom = MachineConfig::check_regions(&rom, "ROM")?;
+        if machine.ram.is_empty() {
+            return Err("at least one RAM region is needed".to_string());
+        }
+        let regions: Vec<&Region> = machine.ram.iter().chain(machine.rom.iter()).collect();
+        for (i, first) in regions.iter().enumerate() {
+            for second in &regions[i + 1..] {
+                if first.base < second.end() && second.base < first.end() {
+                    return Err(format!("regions at 0x{:x} and 0x{:x} overlap", first.base, second.base));
+                }
+            }
+        }
+        Ok(machine)
+    }
+
+    // Check that every region has a base and a size that make sense
+    fn check_regions(fields: &[(Option<u64>, Option<u64>)], name: &str) -> Result<Vec<Region>, String> {
+        let mut regions: Vec<Region> = Vec::new();
+        for field in fields {
+            match *field {
+                (Some(base), Some(size)) if size > 0 && base.checked_add(size).is_some() =>
+                    regions.push(Region { base, size }),
+                (Some(base), Some(_)) => return Err(format!("{} region at 0x{:x} has an invalid size", name, base)),
+                _ => return Err(format!("{} region without base or size", name))
+            }
+        }
+        Ok(regions)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::bus::Bus;
+    use crate::machine::{MachineConfig, Region};
+
+    #[test]
+    fn parse_test() {
+        let machine: MachineConfig = MachineConfig::parse("
+            # A board with its RAM high in the address space
+            [[ram]]
+            base = 0x80000000
+            size = 0x10000
+            [[rom]]
+            base = 0x20000000
+            size = 4096  # 4 KiB
+
+            [devices]
+            uart = 0x10010000
+        ").unwrap();
+        assert_eq!(machine.ram, vec![Region { base: 0x80000000, size: 0x10000 }]);
+        assert_eq!(machine.rom, vec![Region { base: 0x20000000, size: 0x1000 }]);
+        assert_eq!(machine.uart_base, 0x10010000);
+        assert_eq!(machine.clint_base, Bus::CLINT_BASE);
+
+        assert!(MachineConfig::parse("[devices]\nuart = 0x10000000").is_err());
+        assert!(MachineConfig::parse("[[ram]]\nbase = 0x0").is_err());
+        assert!(MachineConfig::parse("[[ram]]\nbase = 0x0\nsize = 0x100\nspeed = 3").is_err());
+        assert!(MachineConfig::parse("[[ram]]\nbase = 0x0\nsize = 0x100\n[[rom]]\nbase = 0xff\nsize = 0x10").is_err());
+    }
+}
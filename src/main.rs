@@ -1,15 +1,41 @@
 use colored::Colorize;
-use clap::Parser;
-use crate::emulator::Emulator;
-
-mod cpu;
-mod bus;
-mod memory;
-mod rv;
-mod elf;
-mod emulator;
-mod uart;
-mod cli;
+use std::ops::Range;
+use std::sync::{Arc, Mutex};
+use clap::{Parser, Subcommand};
+use riviera::emulator::Emulator;
+use riviera::expr::{parse_number, parse_range, Condition};
+use riviera::elf::AddressSpace;
+use riviera::image::{ImageFormat, LoadSpec};
+use riviera::{bisect, error, info, log, memory};
+use riviera::memory::{DumpFormat, MemoryInit};
+use riviera::machine::MachineConfig;
+use riviera::plugin::PluginSpec;
+use riviera::replay::{Divergence, ReplayEntry, ReplayOutcome};
+use riviera::script::Script;
+use riviera::cpu::{ExitReason, FetchFaultAction, REG_FILE_NAMES};
+use riviera::fpu::FloatMode;
+use riviera::selftest::{SelfTest, SelfTestReport};
+use riviera::trap::TrapStatistics;
+use riviera::accounting::TimeAccounting;
+use riviera::cache::{CacheConfig, CacheSim};
+use riviera::profile::{FunctionCost, InstructionProfile};
+use riviera::coverage::CoverageFormat;
+use riviera::profiling::{BranchProfile, BranchRecord, PredictorKind};
+use riviera::csr::Privilege;
+use riviera::stackguard::{StackGuard, StackLimit, ViolationKind};
+use riviera::codewatch::CodeWatch;
+use riviera::taint::{TaintEvent, TaintSource, TaintTracker};
+use riviera::symbols::SymbolMap;
+use riviera::timebase::Timebase;
+use riviera::pacing::Pacing;
+use riviera::stats::{Change, RunStats};
+use riviera::report::RunReport;
+use riviera::console::{ConsoleLog, SharedConsoleLog};
+use riviera::cli::{GuestStdio, SharedHostConsole, Terminal};
+use riviera::keyboard::Keyboard;
+use riviera::uart::UartSpec;
+use riviera::serial::{SerialSpec, TcpConsole};
+use riviera::trace::{FileSink, RingSink, SharedSink, SinkSpec, StdoutSink, TcpSink};
 
 const BANNER: &str = "
         d8b          d8b
@@ -22,23 +48,467 @@ const BANNER: &str = "
 888     888   Y88P   888   Y8888  888     Y888888";
 
 #[derive(Parser)]
-#[command(author, version, about)]
+#[command(author, version, about, args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
 struct CLIArguments {
+    #[command(subcommand)]
+    command: Option<Command>,
+
     /// Executable to be run on emulator
-    #[arg()]
-    elf: String,
+    #[arg(required_unless_present_any = ["kernel", "selftest"])]
+    elf: Option<String>,
+
+    /// Boot an S-mode kernel (e.g. Linux or xv6) without a firmware: the
+    /// SBI calls are served by the emulator and the kernel gets the hart ID
+    /// in a0 and a device tree in a1
+    #[arg(long, conflicts_with_all = ["elf", "rom", "syscalls", "dtb"])]
+    kernel: Option<String>,
+
+    /// Format of the program: elf, bin (raw binary, e.g. from objcopy -O
+    /// binary), hex (Intel HEX) or srec (S-records)
+    #[arg(long, value_parser = ImageFormat::parse, default_value = "elf")]
+    format: ImageFormat,
+
+    /// Address a raw binary (--format bin) is loaded and started at
+    #[arg(long, value_parser = parse_number)]
+    load_address: Option<u64>,
+
+    /// Place another file into memory before starting: <file>@<address>
+    /// loads its bytes there (e.g. a kernel, an initrd or a device tree),
+    /// <file> alone loads the segments of an ELF (can be repeated)
+    #[arg(long, value_parser = LoadSpec::parse)]
+    load: Vec<LoadSpec>,
+
+    /// Start the program at this address instead of the entry point of
+    /// the executable
+    #[arg(long, value_parser = parse_number, conflicts_with = "restore")]
+    entry: Option<u64>,
+
+    /// Start the execution at this address, where the boot code has been
+    /// preloaded with the program (e.g. in a ROM region of the machine),
+    /// with the entry point of the executable in a0 as with --rom
+    #[arg(long, value_parser = parse_number, conflicts_with_all = ["rom", "kernel", "restore"])]
+    reset_vector: Option<u64>,
 
     /// File for memory dumping
     #[arg(short, long)]
     dump: Option<String>,
 
+    /// Format of the memory dump: raw (or bin), hex (Intel HEX), dump
+    /// (hexdump with characters), word32 or word64 (one word per line
+    /// with its address), optionally with the byte order (e.g. word64:be)
+    #[arg(long, value_parser = DumpFormat::parse, default_value = "raw")]
+    dump_format: DumpFormat,
+
+    /// Dump the memory from this address instead of the whole DRAM
+    #[arg(long, value_parser = parse_number, requires = "dump_len")]
+    dump_start: Option<u64>,
+
+    /// Number of bytes to dump from --dump-start
+    #[arg(long, value_parser = parse_number, requires = "dump_start")]
+    dump_len: Option<u64>,
+
     /// Run in interactive mode
     #[arg(short, long)]
     interactive: bool,
 
     /// RAM size for the emulator
     #[arg(short, long)]
-    memsize: Option<u64>
+    memsize: Option<u64>,
+
+    /// Only print the output of the program and the errors: no banner,
+    /// no information about the run and no statistics after it
+    #[arg(short, long)]
+    quiet: bool,
+
+    /// Print without colors (also when the NO_COLOR environment variable is set)
+    #[arg(long)]
+    no_color: bool,
+
+    /// Machine description file with the memory map of the platform
+    /// (RAM and ROM regions, device addresses)
+    #[arg(long, conflicts_with = "memsize")]
+    machine: Option<String>,
+
+    /// Symbol file (nm output or GNU ld map) used to symbolize debug output
+    #[arg(short, long)]
+    symbols: Option<String>,
+
+    /// Initial RAM content: zero, a byte value (e.g. 0xaa) or random[:seed]
+    #[arg(long, value_parser = MemoryInit::parse, default_value = "zero")]
+    mem_init: MemoryInit,
+
+    /// Log every CSR read and write with the decoded fields that changed
+    #[arg(long)]
+    trace_csr: bool,
+
+    /// Boot ROM image (raw binary) mapped at 0x1000: execution starts
+    /// there, with the entry point of the executable in a0
+    #[arg(long)]
+    rom: Option<String>,
+
+    /// Serve Linux/newlib system calls made with ECALL on the host
+    #[arg(long)]
+    syscalls: bool,
+
+    /// Environment variable of the program (--syscalls): NAME passes the
+    /// one of the host, if it is set, NAME=VALUE sets it. It can be given
+    /// more than once
+    #[arg(long, requires = "syscalls", value_name = "NAME[=VALUE]")]
+    env: Vec<String>,
+
+    /// Host directory that is the root of the file system of the program
+    /// (--syscalls): the files it opens are looked up there, and it cannot
+    /// reach the ones outside of it
+    #[arg(long, requires = "syscalls", value_name = "DIR")]
+    fsroot: Option<String>,
+
+    /// Arguments of the program, after --: with --syscalls they are passed
+    /// in argv (argv[0] is the executable), with --semihosting they make
+    /// its command line
+    #[arg(last = true, value_name = "ARGS")]
+    program_args: Vec<String>,
+
+    /// Serve the semihosting calls (EBREAK between slli zero and srai zero)
+    /// on the host: console and file I/O, clock and exit
+    #[arg(long, conflicts_with = "kernel")]
+    semihosting: bool,
+
+    /// Map a test finisher (sifive_test of QEMU) at the given address,
+    /// 0x100000 by default: a store of PASS (0x5555) or FAIL (code << 16
+    /// | 0x3333) to it ends the run with the corresponding exit status
+    #[arg(long, value_parser = parse_number, num_args = 0..=1, default_missing_value = "0x100000", value_name = "ADDRESS")]
+    test_finisher: Option<u64>,
+
+    /// Report stores that overwrite return addresses saved on the stack
+    #[arg(long)]
+    stack_guard: bool,
+
+    /// Give the stack <size> bytes below the initial stack pointer and
+    /// stop the program when it accesses the guard region below them
+    /// (4k unless given), e.g. 64k or 1m:64k
+    #[arg(long, value_parser = StackLimit::parse, value_name = "SIZE[:GUARD]")]
+    stack_limit: Option<StackLimit>,
+
+    /// File where the console output (UART) is copied, each line stamped
+    /// with the instruction count and the host time. By default it is a new
+    /// <program>.console.log (numbered if it exists), created only if the
+    /// program prints something
+    #[arg(long)]
+    console_log: Option<String>,
+
+    /// Do not write the console log
+    #[arg(long)]
+    no_console_log: bool,
+
+    /// Report stores into the code of the program (or of the boot ROM)
+    #[arg(long)]
+    watch_code: bool,
+
+    /// Taint the data that comes from a source, the characters received
+    /// by the UART (uart) or a range of memory (<start>:<len>), follow it
+    /// through registers and memory and report the jumps to tainted
+    /// addresses (can be repeated)
+    #[arg(long, value_parser = TaintSource::parse)]
+    taint_source: Vec<TaintSource>,
+
+    /// Report the stores of tainted data into a range of memory,
+    /// <start>:<len> (can be repeated)
+    #[arg(long, value_parser = parse_range, requires = "taint_source")]
+    taint_sink: Vec<Range<u64>>,
+
+    /// Save the state of the machine to this file when the execution stops
+    #[arg(long)]
+    snapshot: Option<String>,
+
+    /// Run a compliance test (riscv-tests, RISCOF): stop when it writes
+    /// tohost and save the memory between begin_signature and end_signature
+    /// to this file, in the format expected by RISCOF
+    #[arg(long)]
+    signature: Option<String>,
+
+    /// Save the statistics of the run (instructions, traps) to this JSON
+    /// file, to compare them with another run with `riviera compare`
+    #[arg(long)]
+    stats: Option<String>,
+
+    /// Save a report of the run to this JSON file for scripts: instruction
+    /// count, wall time, MIPS, exit reason and status, final registers and
+    /// the traps raised
+    #[arg(long)]
+    json_report: Option<String>,
+
+    /// Profile the executed instructions: print the most executed
+    /// mnemonics and PCs, the branches taken, the loads and stores and
+    /// the instructions executed by each function (flat profile, with the
+    /// symbols) after the run (also saved with --stats)
+    #[arg(long)]
+    profile: bool,
+
+    /// Save the profile to this file in the callgrind format, to browse
+    /// it by function and call with KCachegrind
+    #[arg(long, requires = "profile")]
+    profile_callgrind: Option<String>,
+
+    /// Save the coverage of the run to this file: the instruction
+    /// addresses that were executed and how many times
+    #[arg(long)]
+    coverage: Option<String>,
+
+    /// Format of the coverage: lcov (the source lines, from the line
+    /// number information of the ELF built with -g) or addr (the executed
+    /// addresses with their counts, for addr2line)
+    #[arg(long, value_parser = CoverageFormat::parse, default_value = "lcov", requires = "coverage")]
+    coverage_format: CoverageFormat,
+
+    /// Predict the conditional branches with a model (static, bimodal or
+    /// gshare) and print its accuracy on each branch after the run
+    #[arg(long, value_parser = PredictorKind::parse)]
+    branch_predictor: Option<PredictorKind>,
+
+    /// Simulate an instruction and a data cache and print their miss
+    /// rates after the run (also saved with --stats)
+    #[arg(long)]
+    cache_sim: bool,
+
+    /// Geometry of the simulated I$: <size>:<ways>:<line>
+    #[arg(long, value_parser = CacheConfig::parse, default_value = CacheSim::DEFAULT_CONFIG, requires = "cache_sim")]
+    icache: CacheConfig,
+
+    /// Geometry of the simulated D$: <size>:<ways>:<line>
+    #[arg(long, value_parser = CacheConfig::parse, default_value = CacheSim::DEFAULT_CONFIG, requires = "cache_sim")]
+    dcache: CacheConfig,
+
+    /// Stop after this number of instructions (to save a snapshot)
+    #[arg(long)]
+    snapshot_at: Option<u64>,
+
+    /// Stop a program that has not finished after this number of
+    /// instructions, exiting with status 124
+    #[arg(long, conflicts_with_all = ["interactive", "replay"])]
+    max_instructions: Option<u64>,
+
+    /// Stop a program that has not finished after this number of seconds
+    /// of host time, exiting with status 124
+    #[arg(long, conflicts_with_all = ["interactive", "replay", "record"])]
+    timeout: Option<f64>,
+
+    /// Resume the execution from a snapshot taken with the same executable
+    #[arg(long)]
+    restore: Option<String>,
+
+    /// Record what every executed instruction does to this file, to check
+    /// later runs against it with --replay (stops at --snapshot-at, if given)
+    #[arg(long, conflicts_with_all = ["interactive", "replay"])]
+    record: Option<String>,
+
+    /// Run the program checking every instruction against a recording made
+    /// with --record, and report the first one that behaves differently
+    #[arg(long, conflicts_with = "interactive")]
+    replay: Option<String>,
+
+    /// Run the program along with a Rhai script (e.g. test.rhai) that
+    /// registers callbacks on events (instruction executed at an address,
+    /// memory written, trap taken, console output) and reads and changes
+    /// the state of the CPU. The run stops at the first error of the script
+    #[arg(long, conflicts_with_all = ["interactive", "replay", "record", "timeout"])]
+    script: Option<String>,
+
+    /// Record the inputs from outside of the machine (UART, standard input,
+    /// host time, interrupts of plugin devices) to this file, to reproduce
+    /// the run with --replay-inputs
+    #[arg(long, conflicts_with = "replay_inputs")]
+    record_inputs: Option<String>,
+
+    /// Reproduce a run recorded with --record-inputs: the inputs are taken
+    /// from the log, at the same instants, instead of the host
+    #[arg(long)]
+    replay_inputs: Option<String>,
+
+    /// Generate a device tree of the machine (RAM, interrupt controllers
+    /// and devices), place it at the top of the DRAM and pass its address
+    /// in a1 (and the hart ID in a0), as boot loaders do for kernels
+    #[arg(long)]
+    dtb: bool,
+
+    /// Attach a disk image file as a virtio block device (virtio-mmio at
+    /// 0x10008000, PLIC source 1). The changes made by the program are
+    /// written back to the file at the end of the run
+    #[arg(long)]
+    drive: Option<String>,
+
+    /// Map the device of a plugin library: <library>@<base>, optionally
+    /// followed by ,irq=<source> to connect it to the PLIC (can be repeated)
+    #[arg(long, value_parser = PluginSpec::parse)]
+    device_plugin: Vec<PluginSpec>,
+
+    /// Ticks of the guest time (mtime) per executed instruction, e.g. 0.5
+    /// to slow it down or 0 to freeze it
+    #[arg(long, conflicts_with = "freeze_time")]
+    time_scale: Option<f64>,
+
+    /// Freeze the guest time: mtime and the guest real-time clock stop
+    #[arg(long)]
+    freeze_time: bool,
+
+    /// Source and frequency of the time CSR (rdtime): mtime (the guest
+    /// time) or host (the wall clock of the host), optionally followed by
+    /// :<frequency> in Hz, e.g. host:1000000. The frequency is the
+    /// timebase-frequency of the device tree
+    #[arg(long, value_parser = Timebase::parse)]
+    timebase: Option<Timebase>,
+
+    /// Limit the speed of the run to this many millions of instructions
+    /// per second, e.g. 1.5
+    #[arg(long, value_name = "MIPS", value_parser = Pacing::parse_mips, conflicts_with = "realtime")]
+    mips_limit: Option<Pacing>,
+
+    /// Run at real-time speed: one second of the guest time (mtime)
+    /// takes one second of host time
+    #[arg(long)]
+    realtime: bool,
+
+    /// Set the guest real-time clock (seconds since the epoch) instead of
+    /// using the time of the host. It then advances with mtime
+    #[arg(long)]
+    rtc: Option<u64>,
+
+    /// Send trace records to a sink: stdout, file:<path>, tcp:<host>:<port>
+    /// or ring:<n> (last n records, printed if the program crashes).
+    /// It can be given more than once
+    #[arg(long, value_parser = SinkSpec::parse)]
+    trace: Vec<SinkSpec>,
+
+    /// Trace every executed instruction
+    #[arg(long)]
+    trace_exec: bool,
+
+    /// Trace the loads and stores of the program (PC, address, size and
+    /// value), only the ones that touch a range <start>:<len> if given
+    #[arg(long, value_parser = parse_range, value_name = "RANGE")]
+    memtrace: Option<Option<Range<u64>>>,
+
+    /// What to do when the PC leaves the mapped memory: trap (deliver an
+    /// instruction access fault to the program), stop, or debug (stop and
+    /// open the interactive mode)
+    #[arg(long, value_parser = FetchFaultAction::parse, default_value = "trap")]
+    on_fetch_fault: FetchFaultAction,
+
+    /// How the F and D instructions compute their results: host (the host
+    /// FPU, fast, without the inexact and underflow flags) or soft
+    /// (bit-exact in software, flags included)
+    #[arg(long, value_parser = FloatMode::parse, default_value = "host")]
+    float: FloatMode,
+
+    /// Do not sleep while the program waits for an interrupt (WFI): the
+    /// idle time is skipped as fast as possible
+    #[arg(long)]
+    no_idle_sleep: bool,
+
+    /// Let the program write the segments of the ELF that are not
+    /// writable (e.g. its text): by default such stores raise a store
+    /// access fault
+    #[arg(long)]
+    no_protect: bool,
+
+    /// Console of a UART: stdio (the terminal, the default), or a TCP
+    /// socket that a client attaches to, tcp:[<host>:]<port> (raw) or
+    /// telnet:[<host>:]<port>, with ,wait to wait for the client before
+    /// starting. The host is 127.0.0.1 if not given. It can be given once
+    /// for the UART of the machine and once for each --uart, in order
+    #[arg(long, value_parser = SerialSpec::parse)]
+    serial: Vec<SerialSpec>,
+
+    /// File read by the program as its standard input (system calls,
+    /// semihosting and the UART on stdio) instead of the terminal
+    #[arg(long, value_name = "FILE")]
+    stdin: Option<String>,
+
+    /// File the standard output of the program (system calls,
+    /// semihosting and the UART on stdio) is written to, so that it is
+    /// not mixed with the messages of the emulator
+    #[arg(long, value_name = "FILE")]
+    stdout: Option<String>,
+
+    /// File the standard error of the program is written to. It can be
+    /// the same as --stdout
+    #[arg(long, value_name = "FILE")]
+    stderr: Option<String>,
+
+    /// Map another UART at <base>, optionally followed by ,irq=<source>
+    /// to connect its interrupt to a PLIC source. It can be given more
+    /// than once
+    #[arg(long, value_parser = UartSpec::parse)]
+    uart: Vec<UartSpec>,
+
+    /// Check the instructions of the emulator against a reference model
+    /// instead of running a program: alu (shifts, additions and
+    /// subtractions on the corner cases of their operands)
+    #[arg(long, value_parser = SelfTest::parse, conflicts_with_all = ["elf", "kernel"])]
+    selftest: Option<SelfTest>
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Find the first instruction after which a predicate becomes true
+    Bisect {
+        /// Executable to be run on emulator
+        #[arg()]
+        elf: String,
+
+        /// Format of the program: elf, bin, hex or srec
+        #[arg(long, value_parser = ImageFormat::parse, default_value = "elf")]
+        format: ImageFormat,
+
+        /// Address a raw binary (--format bin) is loaded and started at
+        #[arg(long, value_parser = parse_number)]
+        load_address: Option<u64>,
+
+        /// Predicate to look for, e.g. "mem8[0x20010] == 0x00" or "a0 != 0"
+        #[arg(short, long)]
+        predicate: String,
+
+        /// Number of instructions between two checkpoints
+        #[arg(short, long, default_value_t = 100000)]
+        interval: u64,
+
+        /// Give up after executing this number of instructions
+        #[arg(short, long)]
+        limit: Option<u64>,
+
+        /// RAM size for the emulator
+        #[arg(short, long)]
+        memsize: Option<u64>,
+
+        /// Machine description file with the memory map of the platform
+        #[arg(long, conflicts_with = "memsize")]
+        machine: Option<String>,
+
+        /// Symbol file (nm output or GNU ld map) used to symbolize the result
+        #[arg(short, long)]
+        symbols: Option<String>,
+
+        /// Initial RAM content: zero, a byte value (e.g. 0xaa) or random[:seed]
+        #[arg(long, value_parser = MemoryInit::parse, default_value = "zero")]
+        mem_init: MemoryInit
+    },
+    /// Compare the statistics of two runs saved with --stats
+    Compare {
+        /// Statistics of the first run
+        #[arg()]
+        before: String,
+
+        /// Statistics of the second run
+        #[arg()]
+        after: String,
+
+        /// Changes of at least this percentage are highlighted
+        #[arg(short, long, default_value_t = 5.0)]
+        threshold: f64,
+
+        /// Only show the highlighted changes
+        #[arg(short, long)]
+        significant: bool
+    }
 }
 
 /// Print welcome banner
@@ -48,52 +518,832 @@ fn welcome() {
     println!("Developed by Vlad George Bancila {}\n",
              "<https://github.com/drvladbancila>".green());
 }
-fn main() {
-    welcome();
+/// Create the emulator, load the executable and (optionally) the symbol file
+fn setup_emulator(elf: &str, format: ImageFormat, load_address: Option<u64>, memsize: Option<u64>, machine: Option<&str>, symbols: Option<&str>, mem_init: MemoryInit) -> Emulator {
+    let mut emu: Emulator;
+
+    // A machine description gives the whole memory map. Otherwise, if a
+    // memory size was specified with the -m flag, allocate a DRAM vector
+    // with that size, or else the default value is taken
+    if let Some(machine_file) = machine {
+        match MachineConfig::read_from_file(machine_file) {
+            Ok(machine) => {
+                info!("Machine description loaded from {}", machine_file);
+                emu = Emulator::with_machine(&machine);
+            },
+            Err(err_string) => { error!("{}", err_string); std::process::exit(1) }
+        }
+    } else if let Some(memsize) = memsize {
+        emu = Emulator::new(Some(memsize as usize));
+    } else {
+        emu = Emulator::new(Some(memory::Memory::DRAM_DEFAULT_SIZE));
+    }
 
+    // Fill the DRAM before the program is loaded into it. The seed is
+    // printed so that a failing run with random content can be reproduced
+    match mem_init {
+        MemoryInit::Zero => (),
+        MemoryInit::Pattern(byte) => info!("RAM initialized to 0x{:02x}", byte),
+        MemoryInit::Random(seed) => info!("RAM initialized with random content (seed {})", seed)
+    }
+    emu.init_memory(mem_init);
+
+    // Load the program into memory. Only raw binaries have no addresses
+    // of their own, they go where the ELF text would by default
+    if load_address.is_some() && format != ImageFormat::Bin {
+        error!("--load-address only applies to raw binaries (--format bin)");
+        std::process::exit(1)
+    }
+    match emu.load_image(elf, format, load_address.unwrap_or(AddressSpace::TEXT_START_DEFAULT)) {
+        Ok(()) => info!("{} loaded correctly", format),
+        Err(err_string) => { error!("{}", err_string); std::process::exit(1) }
+    }
+    if let Some(htif) = emu.get_cpu().get_htif() {
+        info!("HTIF console and exit through tohost at 0x{:x}", htif.get_tohost());
+    }
+
+    // Load the external symbol file, if any. A missing or broken symbol file
+    // is not fatal: execution can go on without symbolized output
+    if let Some(symbols_file) = symbols {
+        match emu.load_symbols(symbols_file) {
+            Ok(count) => info!("Loaded {} symbols from {}", count, symbols_file),
+            Err(err_string) => error!("{}", err_string)
+        }
+    }
+    emu
+}
+
+/// Self-test mode: print the checks that failed and exit with 1 if there
+/// are any, 0 otherwise
+fn run_selftest(selftest: SelfTest) -> ! {
+    let report: SelfTestReport = selftest.run();
+    for failure in &report.failures {
+        error!("{}", failure);
+    }
+    if report.passed() {
+        info!("All {} checks passed", report.checks);
+        std::process::exit(0)
+    }
+    error!("{} of {} checks failed", report.failures.len(), report.checks);
+    std::process::exit(1)
+}
+
+/// Bisect mode: look for the first instruction after which the predicate holds
+fn run_bisect(emu: &Emulator, predicate: &str, interval: u64, limit: Option<u64>) {
+    let predicate: Condition = match Condition::parse(predicate, emu.get_symbols()) {
+        Ok(predicate) => predicate,
+        Err(err_string) => { error!("{}", err_string); return }
+    };
+
+    info!("Bisecting on \"{}\" (checkpoint every {} instructions)", predicate.as_str(), interval);
+    match bisect::bisect(emu, &predicate, interval, limit) {
+        Some(result) if result.instruction_count == 0 =>
+            info!("Predicate already holds before the first instruction"),
+        Some(result) => {
+            let location: String = emu.get_symbols().annotate(result.pc);
+            info!("Predicate first holds after instruction #{}", result.instruction_count);
+            info!("pc = 0x{:0>16x}{}: {}", result.pc, location, result.instruction);
+        },
+        None => error!("Predicate never holds")
+    }
+}
+
+/// Compare mode: print the statistics of two runs side by side,
+/// highlighting the counters that changed significantly
+fn run_compare(before_file: &str, after_file: &str, threshold: f64, only_significant: bool) {
+    let (before, after): (RunStats, RunStats) = match (RunStats::read_from_file(before_file), RunStats::read_from_file(after_file)) {
+        (Ok(before), Ok(after)) => (before, after),
+        (Err(err_string), _) | (_, Err(err_string)) => { error!("{}", err_string); std::process::exit(1) }
+    };
+    let changes: Vec<Change> = before.compare(&after);
+    let significant: usize = changes.iter().filter(|change| change.is_significant(threshold)).count();
+    info!("{} -> {}: {} significant changes (threshold {}%)", before_file, after_file, significant, threshold);
+    let mut section: &str = "";
+    for change in changes.iter().filter(|change| !only_significant || change.is_significant(threshold)) {
+        if change.section != section {
+            section = &change.section;
+            println!("{}", section.bright_cyan());
+        }
+        let value = |value: Option<u64>| value.map_or("-".to_string(), |value| value.to_string());
+        let delta: String = match (change.before, change.after, change.percent()) {
+            (_, _, Some(percent)) => format!("{:+.2}%", percent),
+            (Some(0), Some(0), _) => format!("{:+.2}%", 0.0),
+            (Some(_), Some(_), None) => "from 0".to_string(),
+            (None, _, _) => "added".to_string(),
+            (_, None, _) => "removed".to_string()
+        };
+        let line: String = format!("    {:<32} {:>14} -> {:<14} {:>9}", change.name, value(change.before), value(change.after), delta);
+        if change.is_significant(threshold) {
+            println!("{}", line.yellow());
+        } else {
+            println!("{}", line);
+        }
+    }
+}
+
+/// Print how the executed instructions are split between normal code
+/// and trap handlers of each privilege level and idle loops. The MIPS
+/// without the idle loops show how fast the useful work was emulated
+fn print_time_accounting(emu: &Emulator, execution_time: std::time::Duration) {
+    let accounting: &TimeAccounting = emu.get_cpu().get_time_accounting();
+    let total: u64 = accounting.get_total();
+    if total == 0 {
+        return;
+    }
+    let mut buckets: Vec<(String, u64)> = Vec::new();
+    for privilege in [Privilege::Machine, Privilege::Supervisor, Privilege::User] {
+        buckets.push((format!("{}-mode", privilege), accounting.get_normal(privilege)));
+        buckets.push((format!("{}-mode trap handlers", privilege), accounting.get_handler(privilege)));
+    }
+    buckets.push(("idle".to_string(), accounting.get_idle()));
+    info!("Emulated time (executed instructions):");
+    for (name, count) in buckets.iter().filter(|(_, count)| *count > 0) {
+        println!("    {:<22} {:>12} {:>7.2}%", name, count, *count as f64 * 100.0 / total as f64);
+    }
+    if accounting.get_idle() > 0 {
+        let busy_mips: f64 = ((total - accounting.get_idle()) as f64 / 1e6) / execution_time.as_secs_f64();
+        info!("{:.6?} MIPS without idle loops", busy_mips);
+    }
+}
+
+/// Print how many traps were raised during the execution, grouped
+/// by cause, together with the instructions that raised most of them
+fn print_trap_summary(emu: &Emulator) {
+    // Number of PCs shown for each cause
+    const TOP_PCS: usize = 3;
+
+    let statistics = emu.get_cpu().get_trap_statistics();
+    if statistics.is_empty() {
+        info!("No traps were raised");
+        return;
+    }
+    println!("{} Traps raised: {}", "[*]".yellow(), statistics.total());
+    for (cause, stats) in statistics.causes() {
+        if cause & TrapStatistics::INTERRUPT_BIT != 0 {
+            println!("    {} x {} (interrupt {})", stats.count, stats.description, cause & !TrapStatistics::INTERRUPT_BIT);
+        } else {
+            println!("    {} x {} (cause {})", stats.count, stats.description, cause);
+        }
+        for (pc, count) in stats.top_pcs(TOP_PCS) {
+            let location: String = emu.get_symbols().annotate(pc);
+            println!("        {} at pc 0x{:0>16x}{}", count, pc, location);
+        }
+    }
+}
+
+/// Print the profile of the executed instructions: the most executed
+/// mnemonics, the branches, the loads and stores and the hottest PCs
+fn print_profile(emu: &Emulator) {
+    // Number of mnemonics, PCs and functions shown
+    const TOP_MNEMONICS: usize = 15;
+    const TOP_PCS: usize = 10;
+    const TOP_FUNCTIONS: usize = 15;
+
+    let profile: &InstructionProfile = match emu.get_cpu().get_profile() {
+        Some(profile) => profile,
+        None => return
+    };
+    let total: u64 = profile.total();
+    if total == 0 {
+        return;
+    }
+    let percent = |count: u64| count as f64 * 100.0 / total as f64;
+    let mnemonics: Vec<(String, u64)> = profile.mnemonics();
+    info!("Executed instructions by mnemonic ({} different):", mnemonics.len());
+    for (mnemonic, count) in mnemonics.iter().take(TOP_MNEMONICS) {
+        println!("    {:<10} {:>12} {:>7.2}%", mnemonic, count, percent(*count));
+    }
+    let (taken, not_taken): (u64, u64) = profile.branches();
+    if taken + not_taken > 0 {
+        info!("Branches: {} taken, {} not taken ({:.2}% taken)", taken, not_taken, taken as f64 * 100.0 / (taken + not_taken) as f64);
+    }
+    info!("Loads: {} ({:.2}%), stores: {} ({:.2}%)", profile.loads(), percent(profile.loads()), profile.stores(), percent(profile.stores()));
+    info!("Hottest PCs:");
+    for (pc, count) in profile.top_pcs(TOP_PCS) {
+        println!("    {:>12} {:>7.2}% at pc 0x{:0>16x}{}", count, percent(count), pc, emu.get_symbols().annotate(pc));
+    }
+    if emu.get_symbols().is_empty() {
+        return;
+    }
+    let functions: Vec<FunctionCost> = profile.functions(emu.get_symbols());
+    info!("Flat profile ({} functions):", functions.len());
+    println!("    {:>12} {:>7} {:>12} {:>7} {:>10}  function", "self", "self %", "cumulative", "cum. %", "calls");
+    for function in functions.iter().take(TOP_FUNCTIONS) {
+        println!("    {:>12} {:>6.2}% {:>12} {:>6.2}% {:>10}  {}", function.self_instructions, percent(function.self_instructions),
+                 function.cumulative, percent(function.cumulative), function.calls, function.name);
+    }
+}
+
+/// Print the accuracy of the branch predictor, overall and on the
+/// branches that it mispredicted most
+fn print_branch_prediction(emu: &Emulator) {
+    // Number of branches shown
+    const TOP_BRANCHES: usize = 10;
+
+    let branches: &BranchProfile = match emu.get_cpu().get_branch_profile() {
+        Some(branches) => branches,
+        None => return
+    };
+    let total: BranchRecord = branches.total();
+    info!("Branch predictor {}: {} branches, {} mispredicted ({:.2}% accuracy)",
+          branches.get_predictor_name(), total.executed, total.mispredicted, total.accuracy());
+    for (pc, record) in branches.branches().into_iter().take(TOP_BRANCHES).filter(|(_, record)| record.mispredicted > 0) {
+        println!("    pc 0x{:0>16x}{}: {} executed, {} taken, {} mispredicted ({:.2}% accuracy)",
+                 pc, emu.get_symbols().annotate(pc), record.executed, record.taken, record.mispredicted, record.accuracy());
+    }
+}
+
+/// Print the hits and misses of the simulated caches
+fn print_cache_sim(emu: &Emulator) {
+    let cache_sim: &CacheSim = match emu.get_cpu().get_cache_sim() {
+        Some(cache_sim) => cache_sim,
+        None => return
+    };
+    info!("Simulated caches:");
+    for (name, cache) in [("I$", &cache_sim.icache), ("D$", &cache_sim.dcache)] {
+        println!("    {} ({}): {} accesses, {} misses ({:.2}% miss rate)",
+                 name, cache.get_config(), cache.get_accesses(), cache.get_misses(), cache.miss_rate());
+    }
+}
+
+// Open the console of a UART, reading the keyboard for it if asked to.
+// On stdio it uses the standard streams of the guest, which can be redirected
+fn open_serial(spec: &SerialSpec, name: &str, keyboard: bool, stdio: &GuestStdio) -> Result<SharedHostConsole, String> {
+    match spec {
+        SerialSpec::Stdio => {
+            let mut terminal: Terminal = if keyboard { Terminal::with_keyboard() } else { Terminal::new() };
+            terminal.set_stdio(stdio.clone());
+            Ok(Arc::new(Mutex::new(terminal)))
+        },
+        SerialSpec::Tcp { address, telnet, wait } => {
+            if *wait {
+                info!("Waiting for a client of the console of {} on {}", name, address);
+            }
+            let console: TcpConsole = TcpConsole::listen(address, *telnet, *wait)?;
+            info!("Console of {} on {}", name, console.get_address());
+            Ok(Arc::new(Mutex::new(console)))
+        }
+    }
+}
+
+// Create the trace sinks and add them to the emulator. If some tracing is
+// enabled but no sink was given, the records are printed on stdout.
+// The ring buffer, if any, is returned to be inspected after the run
+fn setup_trace_sinks(emu: &mut Emulator, specs: &[SinkSpec], tracing: bool) -> Option<Arc<Mutex<RingSink>>> {
+    let mut ring: Option<Arc<Mutex<RingSink>>> = None;
+    if specs.is_empty() && tracing {
+        emu.add_trace_sink(Arc::new(Mutex::new(StdoutSink)));
+    }
+    for spec in specs {
+        let sink: Result<SharedSink, String> = match spec {
+            SinkSpec::Stdout => Ok(Arc::new(Mutex::new(StdoutSink))),
+            SinkSpec::File(filename) => FileSink::create(filename).map(|sink| Arc::new(Mutex::new(sink)) as SharedSink),
+            SinkSpec::Tcp(address) => TcpSink::connect(address).map(|sink| Arc::new(Mutex::new(sink)) as SharedSink),
+            SinkSpec::Ring(capacity) => {
+                let sink: Arc<Mutex<RingSink>> = Arc::new(Mutex::new(RingSink::new(*capacity)));
+                ring = Some(sink.clone());
+                Ok(sink as SharedSink)
+            }
+        };
+        match sink {
+            Ok(sink) => emu.add_trace_sink(sink),
+            Err(why) => error!("{}", why)
+        }
+    }
+    ring
+}
+
+// Environment of a program from --env: NAME is taken from the host (and
+// left out if it is not set there), NAME=VALUE is given as it is
+fn guest_environment(specs: &[String]) -> Vec<String> {
+    specs.iter().filter_map(|spec| match spec.contains('=') {
+        true => Some(spec.clone()),
+        false => std::env::var(spec).ok().map(|value| format!("{}={}", spec, value))
+    }).collect()
+}
+
+fn print_trace_ring(ring: &Arc<Mutex<RingSink>>) {
+    if let Ok(ring) = ring.lock() {
+        println!("{} Last trace records before the crash:", "[x]".red());
+        for record in ring.get_records() {
+            println!("    {}", record);
+        }
+    }
+}
+
+fn print_stack_violations(emu: &Emulator) {
+    let guard: &StackGuard = match emu.get_cpu().get_stack_guard() {
+        Some(guard) => guard,
+        None => return
+    };
+    if guard.get_count() == 0 {
+        info!("No stack overruns were detected");
+        return;
+    }
+    let symbols: &SymbolMap = emu.get_symbols();
+    println!("{} Stack overruns detected: {}", "[x]".red(), guard.get_count());
+    for violation in guard.get_violations() {
+        match violation.kind {
+            ViolationKind::Overwrite { address, size } =>
+                println!("    pc 0x{:0>16x}{} wrote {} bytes at 0x{:x}{} over the return address saved by",
+                         violation.pc, symbols.annotate(violation.pc), size.bytes(), address, symbols.annotate(address)),
+            ViolationKind::ReturnMismatch { expected, actual } =>
+                println!("    pc 0x{:0>16x}{} returned to 0x{:x} instead of 0x{:x} from",
+                         violation.pc, symbols.annotate(violation.pc), actual, expected)
+        }
+        println!("        function 0x{:x}{} called at pc 0x{:0>16x}{}",
+                 violation.function, symbols.annotate(violation.function), violation.call_pc, symbols.annotate(violation.call_pc));
+    }
+}
+
+fn print_taint_reports(emu: &Emulator) {
+    let taint: &TaintTracker = match emu.get_cpu().get_taint() {
+        Some(taint) => taint,
+        None => return
+    };
+    if taint.get_count() == 0 {
+        info!("No tainted data reached the PC or the sinks ({} tainted bytes in memory)", taint.tainted_bytes());
+        return;
+    }
+    let symbols: &SymbolMap = emu.get_symbols();
+    println!("{} Tainted data reached the PC or the sinks: {}", "[x]".red(), taint.get_count());
+    for report in taint.get_reports() {
+        match report.event {
+            TaintEvent::Jump { register, target } =>
+                println!("    pc 0x{:0>16x}{} jumped to 0x{:x}{}, a tainted address in {}",
+                         report.pc, symbols.annotate(report.pc), target, symbols.annotate(target), REG_FILE_NAMES[register as usize]),
+            TaintEvent::Sink { address, size } =>
+                println!("    pc 0x{:0>16x}{} stored {} tainted bytes at 0x{:x}{}",
+                         report.pc, symbols.annotate(report.pc), size.bytes(), address, symbols.annotate(address))
+        }
+    }
+}
+
+fn print_code_writes(emu: &Emulator) {
+    let watch: &CodeWatch = match emu.get_cpu().get_code_watch() {
+        Some(watch) => watch,
+        None => return
+    };
+    if watch.get_count() == 0 {
+        info!("No writes to the code were detected");
+        return;
+    }
+    let symbols: &SymbolMap = emu.get_symbols();
+    println!("{} Writes to the code detected: {}", "[x]".red(), watch.get_count());
+    for write in watch.get_writes() {
+        println!("    pc 0x{:0>16x}{} wrote {} bytes at 0x{:x}{}",
+                 write.pc, symbols.annotate(write.pc), write.size.bytes(), write.address, symbols.annotate(write.address));
+    }
+}
+
+fn print_divergence(emu: &Emulator, divergence: &Divergence) {
+    println!("{} Replay diverged after {} matching instructions", "[x]".red(), divergence.instruction_count);
+    let describe = |entry: &Option<ReplayEntry>, ended: &str| match entry {
+        Some(entry) => {
+            let location: String = emu.get_symbols().annotate(entry.pc);
+            format!("{}{}", entry, location)
+        },
+        None => ended.to_string()
+    };
+    println!("    recorded: {}", describe(&divergence.recorded, "the recording is over"));
+    println!("    replayed: {}", describe(&divergence.actual, "the program is over"));
+    println!("{}", "State before the instruction (recorded / replayed)".red());
+    let names = std::iter::once("pc").chain(REG_FILE_NAMES);
+    for (name, (recorded, actual)) in names.zip(divergence.recorded_state.iter().zip(&divergence.actual_state)) {
+        let line: String = format!("{:4}: 0x{:0>16x}  0x{:0>16x}", name, recorded, actual);
+        if recorded != actual {
+            println!("{}", line.red());
+        } else {
+            println!("{}", line);
+        }
+    }
+}
+
+fn main() {
     // Parse arguments thanks to clap crate
     let args: CLIArguments = CLIArguments::parse();
+    log::set_quiet(args.quiet);
+    if args.no_color {
+        log::set_color(false);
+    }
+    if !log::is_quiet() {
+        welcome();
+    }
     // Variable to store execution time for running the executable
     let execution_time: std::time::Duration;
     // Executed instructions counter
-    let instr_count: u64;
-    let mips: f64;
-    let mut emu: Emulator;
+    let mut instr_count: u64;
 
-    // If a memory size was specified with the -m flag, allocate a
-    // DRAM vector with that size, otherwise the default value is taken
-    if let Some(memsize) = args.memsize {
-        emu = Emulator::new(Some(memsize as usize));
+    if let Some(Command::Bisect { elf, format, load_address, predicate, interval, limit, memsize, machine, symbols, mem_init }) = args.command {
+        let emu: Emulator = setup_emulator(&elf, format, load_address, memsize, machine.as_deref(), symbols.as_deref(), mem_init);
+        run_bisect(&emu, &predicate, interval, limit);
+        return;
+    }
+    if let Some(Command::Compare { before, after, threshold, significant }) = args.command {
+        run_compare(&before, &after, threshold, significant);
+        return;
+    }
+    if let Some(selftest) = args.selftest {
+        run_selftest(selftest);
+    }
+
+    // The executable (or the kernel) is required when no subcommand is given
+    let boot_kernel: bool = args.kernel.is_some();
+    let elf: String = args.kernel.or(args.elf).expect("no executable specified");
+    let mut emu: Emulator = setup_emulator(&elf, args.format, args.load_address, args.memsize, args.machine.as_deref(), args.symbols.as_deref(), args.mem_init);
+    // The device tree gives the frequency of the timebase
+    if let Some(timebase) = args.timebase.clone() {
+        emu.set_timebase(timebase);
+    }
+    for spec in &args.device_plugin {
+        match emu.load_device_plugin(spec) {
+            Ok(name) => info!("Device {} mapped at 0x{:x}", name, spec.base),
+            Err(err_string) => { error!("{}", err_string); return }
+        }
+    }
+    let stdio: GuestStdio = match GuestStdio::open(args.stdin.as_deref(), args.stdout.as_deref(), args.stderr.as_deref()) {
+        Ok(stdio) => stdio,
+        Err(err_string) => { error!("{}", err_string); return }
+    };
+    emu.set_stdio(stdio.clone());
+    // The UART of the machine and then the additional ones get the serial
+    // backends in order. The keys pressed on the terminal go to the first
+    // UART on stdio, unless the standard input belongs to the debugger or
+    // to the program (syscalls, semihosting), is redirected to a file, or
+    // the inputs are replayed
+    if args.serial.len() > args.uart.len() + 1 {
+        error!("--serial was given {} times for {} UARTs", args.serial.len(), args.uart.len() + 1);
+        return
+    }
+    let mut keyboard: bool = !args.interactive && !args.syscalls && !args.semihosting && args.replay_inputs.is_none()
+        && args.on_fetch_fault != FetchFaultAction::Debug && args.stdin.is_none();
+    for index in 0..=args.uart.len() {
+        let spec: &SerialSpec = args.serial.get(index).unwrap_or(&SerialSpec::Stdio);
+        let name: String = match index {
+            0 => "the UART".to_string(),
+            _ => format!("the UART at 0x{:x}", args.uart[index - 1].base)
+        };
+        let console: SharedHostConsole = match open_serial(spec, &name, keyboard, &stdio) {
+            Ok(console) => console,
+            Err(err_string) => { error!("{}", err_string); return }
+        };
+        keyboard &= *spec != SerialSpec::Stdio;
+        if index == 0 {
+            emu.set_host_console(console);
+            continue;
+        }
+        let uart: UartSpec = args.uart[index - 1];
+        match emu.add_uart(uart, Some(console)) {
+            Ok(()) => info!("UART mapped at 0x{:x}", uart.base),
+            Err(err_string) => { error!("{}", err_string); return }
+        }
+    }
+    if let Some(drive) = args.drive.as_deref() {
+        match emu.attach_drive(drive) {
+            Ok((base, sectors)) => info!("Drive {} ({} sectors) attached at 0x{:x}", drive, sectors, base),
+            Err(err_string) => { error!("{}", err_string); return }
+        }
+    }
+    for spec in &args.load {
+        match emu.load_extra_image(spec) {
+            Ok(range) => info!("{} loaded at 0x{:x}-0x{:x}", spec.filename, range.start, range.end),
+            Err(err_string) => { error!("{}", err_string); return }
+        }
+    }
+    if let Some(entry) = args.entry {
+        emu.set_entry_point(entry);
+        info!("Entry point moved to 0x{:x}", entry);
+    }
+    // The device tree describes the devices, and the boot ROM gets it
+    if args.dtb {
+        match emu.install_device_tree() {
+            Ok((address, size)) => info!("Device tree ({} bytes) at 0x{:x}", size, address),
+            Err(err_string) => { error!("{}", err_string); return }
+        }
+    }
+    if boot_kernel {
+        match emu.boot_kernel() {
+            Ok((address, size)) => info!("Booting the kernel in S-mode with SBI, device tree ({} bytes) at 0x{:x}", size, address),
+            Err(err_string) => { error!("{}", err_string); return }
+        }
+    }
+    if let Some(rom) = args.rom.as_deref() {
+        match emu.load_rom(rom) {
+            Ok(size) => info!("Boot ROM loaded ({} bytes)", size),
+            Err(err_string) => { error!("{}", err_string); return }
+        }
+    }
+    if let Some(reset_vector) = args.reset_vector {
+        match emu.set_reset_vector(reset_vector) {
+            Ok(()) => info!("Starting from the reset vector at 0x{:x}", reset_vector),
+            Err(err_string) => { error!("{}", err_string); return }
+        }
+    }
+    emu.set_trace_csr(args.trace_csr);
+    emu.set_trace_exec(args.trace_exec);
+    emu.set_fetch_fault_action(args.on_fetch_fault);
+    emu.set_float_mode(args.float);
+    emu.set_idle_sleep(!args.no_idle_sleep);
+    emu.set_protection(!args.no_protect);
+    let ring: Option<Arc<Mutex<RingSink>>> = setup_trace_sinks(&mut emu, &args.trace, args.trace_csr || args.trace_exec || args.memtrace.is_some());
+    if let Some(filter) = args.memtrace.clone() {
+        emu.enable_mem_trace(filter);
+    }
+    let argv: Vec<String> = std::iter::once(elf.clone()).chain(args.program_args.iter().cloned()).collect();
+    if args.syscalls {
+        emu.enable_syscall_emulation(&argv, &guest_environment(&args.env));
+        if let Some(root) = &args.fsroot {
+            if let Err(err_string) = emu.set_fs_root(root) {
+                error!("{}", err_string);
+                return
+            }
+        }
+    } else if !args.program_args.is_empty() && !args.semihosting {
+        error!("The arguments of the program can only be passed with --syscalls or --semihosting");
+        return
+    }
+    if args.semihosting {
+        emu.enable_semihosting(&argv.join(" "));
+    }
+    if let Some(base) = args.test_finisher {
+        match emu.enable_test_finisher(base) {
+            Ok(()) => info!("Test finisher at 0x{:x}", base),
+            Err(err_string) => { error!("{}", err_string); return }
+        }
+    }
+    if args.signature.is_some() {
+        match emu.enable_signature() {
+            Ok(signature) => info!("Compliance test: signature 0x{:x}-0x{:x}, tohost at 0x{:x}",
+                                   signature.begin, signature.end, signature.tohost),
+            Err(err_string) => { error!("{}", err_string); return }
+        }
+    }
+    if args.profile {
+        emu.enable_profile();
+    }
+    if args.coverage.is_some() {
+        emu.enable_coverage();
+    }
+    if let Some(kind) = args.branch_predictor {
+        emu.enable_branch_predictor(kind);
+    }
+    if args.cache_sim {
+        emu.enable_cache_sim(args.icache, args.dcache);
+    }
+    if args.record_inputs.is_some() {
+        emu.record_inputs();
+    }
+    if let Some(input_log) = args.replay_inputs.as_deref() {
+        match emu.replay_inputs(input_log) {
+            Ok(count) => info!("Replaying {} inputs from {}", count, input_log),
+            Err(err_string) => { error!("{}", err_string); return }
+        }
+    }
+    if args.stack_guard {
+        emu.enable_stack_guard();
+    }
+    if let Some(limit) = args.stack_limit {
+        match emu.set_stack_limit(limit) {
+            Ok(region) => info!("Stack guard region at 0x{:x}-0x{:x}", region.start, region.end),
+            Err(err_string) => { error!("{}", err_string); return }
+        }
+    }
+    if args.watch_code {
+        emu.enable_code_watch();
+    }
+    if !args.taint_source.is_empty() {
+        emu.enable_taint(&args.taint_source, &args.taint_sink);
+    }
+    let console_log: Option<SharedConsoleLog> = if args.no_console_log {
+        None
     } else {
-        emu = Emulator::new(Some(memory::Memory::DRAM_DEFAULT_SIZE));
+        let log: ConsoleLog = match args.console_log.as_deref() {
+            Some(filename) => ConsoleLog::create(filename),
+            None => ConsoleLog::create_for(&elf)
+        };
+        Some(Arc::new(Mutex::new(log)))
+    };
+    if let Some(log) = console_log.as_ref() {
+        emu.set_console_log(log.clone());
     }
 
-    // Load ELF file into memory
-    match emu.load_program(args.elf.as_str()) {
-        Ok(()) => println!("{} ELF loaded correctly", "[*]".green()),
-        Err(err_string) => { eprintln!("{} {}", "[x]".red(), err_string); panic!()}
+    if let Some(snapshot) = args.restore.as_deref() {
+        match emu.load_snapshot(snapshot) {
+            Ok(()) => info!("Restored snapshot {} (pc 0x{:0>16x})", snapshot, emu.get_cpu().get_pc()),
+            Err(err_string) => { error!("{}", err_string); return }
+        }
     }
+    if let Some(seconds) = args.rtc {
+        emu.set_rtc(seconds);
+    }
+    if args.freeze_time {
+        emu.freeze_time();
+    } else if let Some(scale) = args.time_scale {
+        if let Err(err_string) = emu.set_time_scale(scale) {
+            error!("{}", err_string);
+            return
+        }
+    }
+    if let Some(pacing) = args.mips_limit.or(args.realtime.then_some(Pacing::RealTime)) {
+        emu.set_pacing(pacing);
+    }
+    if args.rtc.is_some() || args.freeze_time || args.time_scale.is_some() || args.timebase.is_some() {
+        info!("Guest time: {}", emu.describe_time());
+    }
+
+    // The run stops at the snapshot or at the limit, whichever comes first
+    let max_instructions: Option<u64> = args.snapshot_at.into_iter().chain(args.max_instructions).min();
+    let timeout: Option<std::time::Duration> = match args.timeout.map(std::time::Duration::try_from_secs_f64).transpose() {
+        Ok(timeout) => timeout,
+        Err(_) => { error!("Invalid timeout: expected a number of seconds"); return }
+    };
 
+    // The script can refer to the symbols of the program
+    let mut script: Option<Script> = match args.script.as_deref().map(|script| Script::load(script, emu.get_symbols().clone())).transpose() {
+        Ok(script) => script,
+        Err(err_string) => { error!("{}", err_string); return }
+    };
 
+    // Ctrl-C stops a run where it is, to open the interactive mode below
+    emu.set_break_on_ctrl_c(true);
     // Check if interactive mode is on
     if args.interactive {
         (execution_time, instr_count) = emu.interactive_run()
+    } else if let Some(recording) = args.replay.as_deref() {
+        match emu.run_replay(recording) {
+            Ok(ReplayOutcome::Matched(count)) =>
+                info!("Replay matched the recording ({} instructions)", count),
+            Ok(ReplayOutcome::Diverged(divergence)) => print_divergence(&emu, &divergence),
+            Err(err_string) => error!("{}", err_string)
+        }
+        return;
+    } else if let Some(recording) = args.record.as_deref() {
+        match emu.run_recording(recording, max_instructions) {
+            Ok(result) => {
+                (execution_time, instr_count) = result;
+                info!("Recorded {} instructions to {}", instr_count, recording);
+            },
+            Err(err_string) => { error!("{}", err_string); return }
+        }
+    } else if let Some(script) = script.as_mut() {
+        match emu.run_script(script, max_instructions) {
+            Ok(result) => (execution_time, instr_count) = result,
+            Err(err_string) => { error!("{}", err_string); return }
+        }
+    } else if max_instructions.is_some() || timeout.is_some() {
+        (execution_time, instr_count) = emu.run_limited(max_instructions, timeout);
     } else {
         (execution_time, instr_count) = emu.run();
     }
+    // Let the user look at what the program was doing (e.g. why it hangs).
+    // The keys now go to the interactive mode
+    if emu.was_interrupted() {
+        let pc: u64 = emu.get_cpu().get_pc();
+        info!("Stopped by Ctrl-C at pc 0x{:0>16x}{}, opening the interactive mode", pc, emu.get_symbols().annotate(pc));
+        Keyboard::suspend();
+        let (_, count) = emu.interactive_run();
+        instr_count += count;
+    }
+    // Let the user look around where the PC went astray
+    if let (FetchFaultAction::Debug, Some(fault)) = (args.on_fetch_fault, emu.describe_fetch_fault()) {
+        if !args.interactive {
+            error!("{}, opening the interactive mode", fault);
+            let (_, count) = emu.interactive_run();
+            instr_count += count;
+        }
+    }
+
+    if let Some(Ok(mut log)) = console_log.as_ref().map(|log| log.lock()) {
+        log.flush();
+        if let Some(filename) = log.get_filename() {
+            info!("Console output logged to {}", filename);
+        }
+    }
 
     // If execution is over, print the total runtime
-    mips = (instr_count as f64/1e6)/execution_time.as_secs_f64();
-    println!("{} Execution is over", "[*]".green());
-    println!("{} T = {:.2?}, IC = {} ({:.6?} MIPS)",
-             "[*]".green(), execution_time, instr_count, mips);
+    let mips: f64 = (instr_count as f64/1e6)/execution_time.as_secs_f64();
+    info!("Execution is over");
+    let exit_reason: ExitReason = emu.get_exit_reason();
+    let limit_reached: bool = exit_reason == ExitReason::InstructionLimit
+        && args.max_instructions.is_some_and(|limit| instr_count >= limit);
+    // The exit code of the program (or the value returned by it) is the
+    // one of riviera, so that test scripts can tell whether it succeeded
+    // (124 if the program ran past --max-instructions or --timeout)
+    let exit_status: Option<i32> = if limit_reached { Some(ExitReason::LIMIT_EXIT_STATUS) } else { exit_reason.exit_status() };
+    match exit_reason {
+        // Traps are described below, with where they happened
+        ExitReason::Breakpoint(_) | ExitReason::Trap(_) => (),
+        ExitReason::Timeout => error!("{}", exit_reason),
+        _ if limit_reached => error!("Stopped after the maximum number of instructions, before the end of the program"),
+        _ => info!("{}", exit_reason)
+    }
+    // The program might have been stopped by a trap it could not handle
+    if let Some(trap) = emu.get_cpu().get_unhandled_trap() {
+        let pc: u64 = emu.get_cpu().get_pc();
+        error!("Stopped by unhandled trap: {} at pc 0x{:0>16x}{}",
+               emu.get_symbols().describe_trap(&trap), pc, emu.get_symbols().annotate(pc));
+        if let Some(fault) = emu.describe_fetch_fault() {
+            error!("{}", fault);
+        }
+        // Innermost calls shown after a stack overflow, e.g. of a recursion
+        const OVERFLOW_FRAMES: usize = 16;
+        if let Some(overflow) = emu.describe_stack_overflow() {
+            error!("{}", overflow);
+            emu.print_backtrace(OVERFLOW_FRAMES);
+        }
+    }
+    info!("T = {:.2?}, IC = {} ({:.6?} MIPS)", execution_time, instr_count, mips);
+    // The reports can still be saved with --stats and --json-report
+    if !log::is_quiet() {
+        print_time_accounting(&emu, execution_time);
+        print_trap_summary(&emu);
+        print_profile(&emu);
+        print_branch_prediction(&emu);
+        print_cache_sim(&emu);
+    }
+    print_stack_violations(&emu);
+    print_code_writes(&emu);
+    print_taint_reports(&emu);
+    if let (Some(ring), Some(_)) = (ring, emu.get_cpu().get_unhandled_trap()) {
+        print_trace_ring(&ring);
+    }
+
+    if let Some(signature_file) = args.signature.as_deref() {
+        match emu.write_signature(signature_file) {
+            Ok(res_str) => info!("{}", res_str),
+            Err(err_string) => error!("{}", err_string)
+        }
+    }
 
-    // If the -d flag was used, dump all the DRAM in a binary file
+    if let Some(stats_file) = args.stats.as_deref() {
+        match RunStats::collect(emu.get_cpu(), instr_count).write_to_file(stats_file) {
+            Ok(()) => info!("Saved the statistics of the run to {}", stats_file),
+            Err(err_string) => error!("{}", err_string)
+        }
+    }
+
+    if let (Some(callgrind_file), Some(profile)) = (args.profile_callgrind.as_deref(), emu.get_cpu().get_profile()) {
+        match profile.write_callgrind(callgrind_file, emu.get_symbols()) {
+            Ok(()) => info!("Saved the profile in the callgrind format to {}", callgrind_file),
+            Err(err_string) => error!("{}", err_string)
+        }
+    }
+
+    if let Some(coverage_file) = args.coverage.as_deref() {
+        match emu.write_coverage(coverage_file, args.coverage_format, &elf) {
+            Ok(res_str) => info!("{}", res_str),
+            Err(err_string) => error!("{}", err_string)
+        }
+    }
+
+    if let Some(report_file) = args.json_report.as_deref() {
+        match RunReport::collect(emu.get_cpu(), instr_count, execution_time, exit_reason, exit_status).write_to_file(report_file) {
+            Ok(()) => info!("Saved the report of the run to {}", report_file),
+            Err(err_string) => error!("{}", err_string)
+        }
+    }
+
+    if let Some(input_log) = args.record_inputs.as_deref() {
+        match emu.save_input_log(input_log) {
+            Ok(res_str) => info!("{}", res_str),
+            Err(err_string) => error!("{}", err_string)
+        }
+    }
+    if args.replay_inputs.is_some() {
+        match emu.check_replayed_inputs() {
+            Ok(res_str) => info!("{}", res_str),
+            Err(err_string) => error!("{}", err_string)
+        }
+    }
+
+    if args.drive.is_some() {
+        match emu.save_drive() {
+            Ok(res_str) => info!("{}", res_str),
+            Err(err_string) => error!("{}", err_string)
+        }
+    }
+
+    if let Some(snapshot) = args.snapshot.as_deref() {
+        match emu.save_snapshot(snapshot) {
+            Ok(()) => info!("Saved snapshot {} (pc 0x{:0>16x})", snapshot, emu.get_cpu().get_pc()),
+            Err(err_string) => error!("{}", err_string)
+        }
+    }
+
+    // If the -d flag was used, dump all the DRAM (or the given range) in a file
     if let Some(dump_file) = args.dump.as_deref() {
-        match emu.dump_memory_to_file(dump_file) {
-            Err(res_str) => println!("{} {}", "[x]".red(), res_str),
-            Ok(res_str) => println!("{} {}", "[*]".green(), res_str)
+        let result: Result<String, String> = match (args.dump_start, args.dump_len) {
+            (Some(start), Some(len)) => emu.dump_memory_range_to_file(dump_file, args.dump_format, start, len),
+            _ => emu.dump_memory_to_file(dump_file, args.dump_format)
+        };
+        match result {
+            Err(res_str) => error!("{}", res_str),
+            Ok(res_str) => info!("{}", res_str)
         }
 
     }
+
+    if let Some(exit_status) = exit_status {
+        std::process::exit(exit_status);
+    }
 }
@@ -1,6 +1,16 @@
+use std::fmt;
 use std::fs::File;
-use std::io::Write;
-use std::path::Path;
+use std::io::{Seek, SeekFrom, Write};
+use std::ops::{Deref, DerefMut};
+#[cfg(unix)]
+use crate::mmap::{self, MappedBytes, SharedBytes};
+#[cfg(unix)]
+use std::sync::Arc;
+use crate::trap::Trap;
+use crate::snapshot::{Snapshot, SnapshotReader, SnapshotWriter};
+
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
 pub enum AccessSize {
     BYTE,
     HALFWORD,
@@ -8,8 +18,345 @@ pub enum AccessSize {
     DOUBLEWORD
 }
 
+impl AccessSize {
+    /// Number of bytes involved in an access of this size
+    pub fn bytes(&self) -> usize {
+        match self {
+            AccessSize::BYTE => 1,
+            AccessSize::HALFWORD => 2,
+            AccessSize::WORD => 4,
+            AccessSize::DOUBLEWORD => 8
+        }
+    }
+}
+
+/// Failed access to a memory or to a device. The CPU turns it into the
+/// access fault (or misaligned access) trap matching the kind of access
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum MemError {
+    // The address is not a multiple of the access size
+    Misaligned(u64),
+    // Nothing answers at the address, or the device refused the access
+    OutOfBounds(u64)
+}
+
+impl MemError {
+    /// Trap raised by a load that failed with this error
+    pub fn load_trap(self) -> Trap {
+        match self {
+            MemError::Misaligned(addr) => Trap::LoadAddressMisaligned(addr),
+            MemError::OutOfBounds(addr) => Trap::LoadAccessFault(addr)
+        }
+    }
+
+    /// Trap raised by a store that failed with this error
+    pub fn store_trap(self) -> Trap {
+        match self {
+            MemError::Misaligned(addr) => Trap::StoreAddressMisaligned(addr),
+            MemError::OutOfBounds(addr) => Trap::StoreAccessFault(addr)
+        }
+    }
+}
+
+impl fmt::Display for MemError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MemError::Misaligned(addr) => write!(f, "misaligned access at 0x{:x}", addr),
+            MemError::OutOfBounds(addr) => write!(f, "no memory or device at 0x{:x}", addr)
+        }
+    }
+}
+
+/// Initial content of a memory. Guest code that only works when memory
+/// happens to be zero can be caught by filling it with something else
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum MemoryInit {
+    Zero,
+    // Every byte is set to the same value
+    Pattern(u8),
+    // Pseudo-random bytes generated from a seed, so that runs can be reproduced
+    Random(u64)
+}
+
+impl MemoryInit {
+    /// Parse an initialization mode: "zero", a byte value (e.g. "0xaa")
+    /// or "random" with an optional seed ("random:1234"). Without a seed
+    /// one is derived from the current time
+    pub fn parse(mode: &str) -> Result<MemoryInit, String> {
+        match mode.trim() {
+            "zero" => Ok(MemoryInit::Zero),
+            "random" => {
+                let seed: u64 = std::time::SystemTime::now()
+                    .duration_since(std::time::UNIX_EPOCH)
+                    .map(|time| time.as_nanos() as u64)
+                    .unwrap_or(0);
+                Ok(MemoryInit::Random(seed))
+            },
+            mode => match mode.strip_prefix("random:") {
+                Some(seed) => crate::expr::parse_number(seed).map(MemoryInit::Random),
+                None => match crate::expr::parse_number(mode) {
+                    Ok(byte) if byte <= 0xff => Ok(MemoryInit::Pattern(byte as u8)),
+                    _ => Err(format!("Invalid memory initialization {}: expected zero, a byte value or random[:seed]", mode))
+                }
+            }
+        }
+    }
+}
+
+/// Layout of a memory dump: the raw bytes, text with one word per line
+/// preceded by its address, Intel HEX records or a hexdump with the
+/// bytes and their characters
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum DumpFormat {
+    Raw,
+    Words { size: AccessSize, big_endian: bool },
+    Hex,
+    Hexdump
+}
+
+impl DumpFormat {
+    // Data bytes in each line of the Intel HEX and hexdump formats
+    const BYTES_PER_LINE: usize = 16;
+    // Blocks of zeros of this size are holes in a raw dump
+    const SPARSE_BLOCK_SIZE: usize = 4096;
+
+    /// Parse a dump format: "raw" (or "bin"), "hex", "dump", "word32" or
+    /// "word64", the word formats optionally followed by the byte order
+    /// (e.g. "word64:be", default "le")
+    pub fn parse(format: &str) -> Result<DumpFormat, String> {
+        let format: &str = format.trim();
+        match format {
+            "raw" | "bin" => return Ok(DumpFormat::Raw),
+            "hex" => return Ok(DumpFormat::Hex),
+            "dump" => return Ok(DumpFormat::Hexdump),
+            _ => ()
+        }
+        let (width, order) = format.split_once(':').unwrap_or((format, "le"));
+        let size: AccessSize = match width {
+            "word32" => AccessSize::WORD,
+            "word64" => AccessSize::DOUBLEWORD,
+            _ => return Err(format!("Invalid dump format {}: expected raw, hex, dump, word32[:le|:be] or word64[:le|:be]", format))
+        };
+        match order {
+            "le" => Ok(DumpFormat::Words { size, big_endian: false }),
+            "be" => Ok(DumpFormat::Words { size, big_endian: true }),
+            _ => Err(format!("Invalid byte order {}: expected le or be", order))
+        }
+    }
+
+    /// Lay out bytes that are found in memory starting at base
+    pub fn encode(&self, data: &[u8], base: u64) -> Vec<u8> {
+        let (size, big_endian) = match *self {
+            DumpFormat::Raw => return data.to_vec(),
+            DumpFormat::Hex => return DumpFormat::encode_hex(data, base).into_bytes(),
+            DumpFormat::Hexdump => return DumpFormat::encode_hexdump(data, base).into_bytes(),
+            DumpFormat::Words { size, big_endian } => (size, big_endian)
+        };
+        let mut text: String = String::with_capacity(data.len() * 5);
+        for (i, word) in data.chunks(size.bytes()).enumerate() {
+            // The last word is padded with zeros if the memory size is not a multiple
+            let mut bytes: [u8; 8] = [0; 8];
+            bytes[..word.len()].copy_from_slice(word);
+            let bytes: &mut [u8] = &mut bytes[..size.bytes()];
+            if !big_endian {
+                bytes.reverse();
+            }
+            text.push_str(&format!("0x{:0>16x}: 0x", base + (i * size.bytes()) as u64));
+            for byte in bytes.iter() {
+                text.push_str(&format!("{:02x}", byte));
+            }
+            text.push('\n');
+        }
+        text.into_bytes()
+    }
+
+    /// Write bytes found in memory starting at base to a file in this format.
+    /// Raw dumps are sparse files: the blocks of zeros are not written
+    pub fn write_to_file(&self, filename: &str, data: &[u8], base: u64) -> Result<String, String> {
+        let result = File::create(filename).and_then(|mut file| match self {
+            DumpFormat::Raw => DumpFormat::write_sparse(&mut file, data),
+            _ => file.write_all(&self.encode(data, base))
+        });
+        match result {
+            Err(why) => Err(format!("Could not write memory dump to {}: {}", filename, why)),
+            Ok(_) => Ok(format!("Successfully saved memory content to {}", filename))
+        }
+    }
+
+    // Write the blocks that hold data and seek over the others, so that
+    // the dump of a large memory only takes the space of what it uses
+    pub(crate) fn write_sparse(file: &mut File, data: &[u8]) -> std::io::Result<()> {
+        for block in data.chunks(DumpFormat::SPARSE_BLOCK_SIZE) {
+            if block.iter().any(|byte| *byte != 0) {
+                file.write_all(block)?;
+            } else {
+                file.seek(SeekFrom::Current(block.len() as i64))?;
+            }
+        }
+        file.set_len(data.len() as u64)
+    }
+
+    // Intel HEX data records, with an extended linear address record
+    // whenever the upper 16 bits of the address change (addresses are 32 bits)
+    fn encode_hex(data: &[u8], base: u64) -> String {
+        let record = |kind: u8, address: u16, bytes: &[u8]| -> String {
+            let mut record: Vec<u8> = vec![bytes.len() as u8, (address >> 8) as u8, address as u8, kind];
+            record.extend_from_slice(bytes);
+            let checksum: u8 = record.iter().fold(0u8, |sum, byte| sum.wrapping_sub(*byte));
+            record.push(checksum);
+            let digits: String = record.iter().map(|byte| format!("{:02X}", byte)).collect();
+            format!(":{}\n", digits)
+        };
+        let mut text: String = String::new();
+        let mut upper: Option<u64> = None;
+        let mut offset: usize = 0;
+        while offset < data.len() {
+            let address: u64 = base + offset as u64;
+            if upper != Some(address >> 16) {
+                upper = Some(address >> 16);
+                text.push_str(&record(0x04, 0, &((address >> 16) as u16).to_be_bytes()));
+            }
+            // A record does not cross a 64 KiB boundary
+            let len: usize = DumpFormat::BYTES_PER_LINE
+                .min(data.len() - offset)
+                .min(0x10000 - (address & 0xffff) as usize);
+            text.push_str(&record(0x00, address as u16, &data[offset..offset + len]));
+            offset += len;
+        }
+        text.push_str(&record(0x01, 0, &[]));
+        text
+    }
+
+    // Address, bytes in hexadecimal and printable characters, like hexdump -C
+    fn encode_hexdump(data: &[u8], base: u64) -> String {
+        let mut text: String = String::new();
+        for (i, line) in data.chunks(DumpFormat::BYTES_PER_LINE).enumerate() {
+            text.push_str(&format!("{:0>16x} ", base + (i * DumpFormat::BYTES_PER_LINE) as u64));
+            for column in 0..DumpFormat::BYTES_PER_LINE {
+                if column % 8 == 0 {
+                    text.push(' ');
+                }
+                match line.get(column) {
+                    Some(byte) => text.push_str(&format!("{:02x} ", byte)),
+                    None => text.push_str("   ")
+                }
+            }
+            let chars: String = line.iter()
+                .map(|byte| if byte.is_ascii_graphic() || *byte == b' ' { *byte as char } else { '.' })
+                .collect();
+            text.push_str(&format!(" |{}|\n", chars));
+        }
+        text
+    }
+}
+
+// Bytes of a memory: small memories live on the heap, large ones (on
+// Unix) in an anonymous mapping, where the host only allocates the pages
+// that are written. Shared memories are frozen in a file that all their
+// clones map: the first write turns a clone into a private mapping of it
+#[derive(Clone)]
+enum Storage {
+    Heap(Vec<u8>),
+    #[cfg(unix)]
+    Mapped(MappedBytes),
+    #[cfg(unix)]
+    Shared(Arc<SharedBytes>)
+}
+
+impl Storage {
+    // Memories from this size on are mapped
+    #[cfg(unix)]
+    const MAPPED_MIN_SIZE: usize = 16 * 1024 * 1024;
+
+    fn new(size: usize) -> Storage {
+        #[cfg(unix)]
+        if size >= Storage::MAPPED_MIN_SIZE {
+            if let Some(bytes) = MappedBytes::new(size) {
+                return Storage::Mapped(bytes);
+            }
+        }
+        Storage::Heap(vec![0; size])
+    }
+
+    // Same as new, holding a copy of data
+    fn from_bytes(data: Vec<u8>) -> Storage {
+        match Storage::new(data.len()) {
+            #[cfg(unix)]
+            Storage::Mapped(mut bytes) => {
+                mmap::copy_sparse(&mut bytes, &data);
+                Storage::Mapped(bytes)
+            },
+            _ => Storage::Heap(data)
+        }
+    }
+
+    // Set every byte to zero. Mapped pages are given back to the host
+    // instead of being written
+    fn clear(&mut self) {
+        match self {
+            Storage::Heap(bytes) => bytes.fill(0),
+            #[cfg(unix)]
+            _ => *self = Storage::new(self.len())
+        }
+    }
+
+    // Freeze the bytes so that the clones share them
+    #[cfg(unix)]
+    fn share(&mut self) -> Result<(), String> {
+        if !matches!(self, Storage::Shared(_)) && !self.is_empty() {
+            *self = Storage::Shared(Arc::new(SharedBytes::new(self)?));
+        }
+        Ok(())
+    }
+
+    // Take a private copy of shared bytes before they are written
+    #[cfg(unix)]
+    #[cold]
+    fn unshare(&mut self) {
+        if let Storage::Shared(shared) = self {
+            *self = match shared.copy() {
+                Some(bytes) => Storage::Mapped(bytes),
+                None => Storage::from_bytes(shared.to_vec())
+            };
+        }
+    }
+}
+
+impl Deref for Storage {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self {
+            Storage::Heap(bytes) => bytes,
+            #[cfg(unix)]
+            Storage::Mapped(bytes) => bytes,
+            #[cfg(unix)]
+            Storage::Shared(bytes) => bytes
+        }
+    }
+}
+
+impl DerefMut for Storage {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        #[cfg(unix)]
+        if matches!(self, Storage::Shared(_)) {
+            self.unshare();
+        }
+        match self {
+            Storage::Heap(bytes) => bytes,
+            #[cfg(unix)]
+            Storage::Mapped(bytes) => bytes,
+            #[cfg(unix)]
+            Storage::Shared(_) => unreachable!("shared memory is copied before it is written")
+        }
+    }
+}
+
+/// Memory of the machine (RAM or ROM). Large memories are mapped from the
+/// host lazily, so a guest with gigabytes of RAM is cheap until it uses it
+#[derive(Clone)]
 pub struct Memory {
-    memory: Vec<u8>
+    memory: Storage
 }
 
 impl Memory {
@@ -17,86 +364,242 @@ impl Memory {
     pub const ROM_DEFAULT_SIZE:  usize = 0;
 
     pub fn new(size: Option<usize>) -> Memory {
-            match size {
-                Some(size) => Self { memory: vec![0; size]},
-                None => Self { memory: Vec::new() },
+        Self { memory: Storage::new(size.unwrap_or(0)) }
+    }
+
+    /// Make the clones of the memory share its contents until they write
+    /// them (copy-on-write, on Unix): a clone then costs the same for any
+    /// size of the memory, and each one only copies the pages it writes.
+    /// Elsewhere the clones keep copying the whole memory
+    pub fn share(&mut self) -> Result<(), String> {
+        #[cfg(unix)]
+        self.memory.share()?;
+        Ok(())
+    }
+
+    /// Overwrite the whole memory according to the initialization mode
+    pub fn fill(&mut self, init: MemoryInit) {
+        match init {
+            MemoryInit::Zero => self.memory.clear(),
+            MemoryInit::Pattern(byte) => self.memory.fill(byte),
+            MemoryInit::Random(seed) => {
+                // splitmix64: tiny, fast and good enough to shake out
+                // reads of uninitialized memory
+                let mut state: u64 = seed;
+                for chunk in self.memory.chunks_mut(8) {
+                    state = state.wrapping_add(0x9e3779b97f4a7c15);
+                    let mut z: u64 = state;
+                    z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+                    z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+                    z ^= z >> 31;
+                    chunk.copy_from_slice(&z.to_le_bytes()[..chunk.len()]);
+                }
             }
+        }
     }
 
-    pub fn load(&self, paddr: u64, size: AccessSize) -> u64 {
-        match size {
-            AccessSize::BYTE => self.load8(paddr as usize) as u64,
-            AccessSize::HALFWORD => self.load16(paddr as usize) as u64,
-            AccessSize::WORD => self.load32(paddr as usize) as u64,
+    /// Load data from memory. Accesses outside of the memory boundaries
+    /// are errors (the address in the error is relative to the beginning
+    /// of this memory)
+    pub fn load(&self, paddr: u64, size: AccessSize) -> Result<u64, MemError> {
+        let data: Option<u64> = match size {
+            AccessSize::BYTE => self.load8(paddr as usize).map(|data| data as u64),
+            AccessSize::HALFWORD => self.load16(paddr as usize).map(|data| data as u64),
+            AccessSize::WORD => self.load32(paddr as usize).map(|data| data as u64),
             AccessSize::DOUBLEWORD => self.load64(paddr as usize)
-        }
+        };
+        data.ok_or(MemError::OutOfBounds(paddr))
     }
 
     pub fn get_size(&self) -> usize {
         self.memory.len()
     }
 
-    pub fn store(&mut self, data: u64, paddr: u64, size: AccessSize) {
-        match size {
-            AccessSize::BYTE => self.store8(data as u8, paddr as usize),
-            AccessSize::HALFWORD => self.store16(data as u16, paddr as usize),
-            AccessSize::WORD => self.store32(data as u32, paddr as usize),
-            AccessSize::DOUBLEWORD => self.store64(data as u64, paddr as usize)
+    /// Store data to memory. Accesses outside of the memory boundaries
+    /// are errors (the address in the error is relative to the beginning
+    /// of this memory)
+    pub fn store(&mut self, data: u64, paddr: u64, size: AccessSize) -> Result<(), MemError> {
+        let bytes: Option<&mut [u8]> = self.slice_mut(paddr as usize, size.bytes());
+        match (bytes, size) {
+            (Some(bytes), AccessSize::BYTE) => bytes.copy_from_slice(&(data as u8).to_le_bytes()),
+            (Some(bytes), AccessSize::HALFWORD) => bytes.copy_from_slice(&(data as u16).to_le_bytes()),
+            (Some(bytes), AccessSize::WORD) => bytes.copy_from_slice(&(data as u32).to_le_bytes()),
+            (Some(bytes), AccessSize::DOUBLEWORD) => bytes.copy_from_slice(&data.to_le_bytes()),
+            (None, _) => return Err(MemError::OutOfBounds(paddr))
         };
+        Ok(())
     }
 
-    pub fn dump_to_file(&self, filename: &str) -> Result<String, String> {
-        let filepath: &Path = Path::new(filename);
-        let display = filepath.display();
-
-        let mut file = match File::create(&filepath) {
-            Err(why) => return Err(format!("Could not create {}: {}", display, why)),
-            Ok(file) => file,
-        };
+    /// Dump the memory to a file in the given format. Formats with
+    /// addresses show them as if the memory started at base
+    pub fn dump_to_file_as(&self, filename: &str, format: DumpFormat, base: u64) -> Result<String, String> {
+        format.write_to_file(filename, &self.memory, base)
+    }
 
-        match file.write(&self.memory) {
-            Err(why) => return Err(format!("Could not write memory buffer to {}: {}", display, why)),
-            Ok(_) => return Ok(format!("Successfully saved memory content to {}", filename))
+    /// Copy a buffer into memory starting at paddr. The whole buffer has
+    /// to fit in the memory, otherwise nothing is written
+    pub fn store_buffer(&mut self, data: &[u8], paddr: u64) -> Result<(), MemError> {
+        match self.slice_mut(paddr as usize, data.len()) {
+            Some(bytes) => { bytes.copy_from_slice(data); Ok(()) },
+            None => Err(MemError::OutOfBounds(paddr))
         }
     }
 
-    pub fn store_n_bytes(&mut self, data: &[u8], paddr: u64, size: usize) {
-        if (paddr as usize + size)  <= self.memory.len() {
-            self.memory[paddr as usize..paddr as usize+size].clone_from_slice(data);
-        } else {
-            self.memory.extend_from_slice(data).try_into().expect("Could not allocate enough memory")
+    /// Extend the memory with zeros so that it is at least size bytes long
+    pub fn grow(&mut self, size: usize) {
+        if size > self.memory.len() {
+            let mut memory: Storage = Storage::new(size);
+            memory[..self.memory.len()].copy_from_slice(&self.memory);
+            self.memory = memory;
         }
     }
 
-    fn load8(&self, paddr: usize) -> u8 {
-        self.memory[paddr]
+    // Get a slice of len bytes starting at paddr, if it is inside the memory
+    fn slice(&self, paddr: usize, len: usize) -> Option<&[u8]> {
+        self.memory.get(paddr..)?.get(..len)
+    }
+
+    // Get a mutable slice of len bytes starting at paddr, if it is inside the memory
+    fn slice_mut(&mut self, paddr: usize, len: usize) -> Option<&mut [u8]> {
+        self.memory.get_mut(paddr..)?.get_mut(..len)
+    }
+
+    fn load8(&self, paddr: usize) -> Option<u8> {
+        self.memory.get(paddr).copied()
+    }
+
+    fn load16(&self, paddr: usize) -> Option<u16> {
+        Some(u16::from_le_bytes(self.slice(paddr, 2)?.try_into().unwrap()))
     }
 
-    fn load16(&self, paddr: usize) -> u16 {
-        u16::from_le_bytes(self.memory[paddr..paddr + 2].try_into().unwrap())
+    fn load32(&self, paddr: usize) -> Option<u32> {
+        Some(u32::from_le_bytes(self.slice(paddr, 4)?.try_into().unwrap()))
     }
 
-    fn load32(&self, paddr: usize) -> u32 {
-        u32::from_le_bytes(self.memory[paddr..paddr + 4].try_into().unwrap())
+    fn load64(&self, paddr: usize) -> Option<u64> {
+        Some(u64::from_le_bytes(self.slice(paddr, 8)?.try_into().unwrap()))
     }
+}
 
-    fn load64(&self, paddr: usize) -> u64 {
-        u64::from_le_bytes(self.memory[paddr..paddr + 8].try_into().unwrap())
+impl Snapshot for Memory {
+    fn save(&self, writer: &mut SnapshotWriter) {
+        writer.put_bytes(&self.memory);
     }
 
-    fn store8(&mut self, data: u8, paddr: usize) {
-        self.memory[paddr] = data;
+    fn restore(&mut self, reader: &mut SnapshotReader) -> Result<(), String> {
+        self.memory = Storage::from_bytes(reader.get_bytes()?);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::memory::{AccessSize, DumpFormat, MemError, Memory, MemoryInit};
+
+    #[test]
+    fn init_test() {
+        assert_eq!(MemoryInit::parse("zero"), Ok(MemoryInit::Zero));
+        assert_eq!(MemoryInit::parse("0x55"), Ok(MemoryInit::Pattern(0x55)));
+        assert_eq!(MemoryInit::parse("random:7"), Ok(MemoryInit::Random(7)));
+        assert!(MemoryInit::parse("0x100").is_err());
+
+        let mut memory: Memory = Memory::new(Some(16));
+        memory.fill(MemoryInit::Pattern(0xaa));
+        assert_eq!(memory.load(0x8, AccessSize::WORD), Ok(0xaaaaaaaa));
+        // The same seed always gives the same content
+        memory.fill(MemoryInit::Random(7));
+        let first: Result<u64, _> = memory.load(0x8, AccessSize::DOUBLEWORD);
+        memory.fill(MemoryInit::Random(7));
+        assert_eq!(memory.load(0x8, AccessSize::DOUBLEWORD), first);
     }
 
-    fn store16(&mut self, data: u16, paddr: usize) {
-        self.memory[paddr..paddr + 2].copy_from_slice(&data.to_le_bytes());
+    #[test]
+    fn dump_format_test() {
+        assert_eq!(DumpFormat::parse("raw"), Ok(DumpFormat::Raw));
+        assert_eq!(DumpFormat::parse("word64:be"), Ok(DumpFormat::Words { size: AccessSize::DOUBLEWORD, big_endian: true }));
+        assert!(DumpFormat::parse("word16").is_err());
+        assert!(DumpFormat::parse("word32:middle").is_err());
+
+        let mut memory: Memory = Memory::new(Some(12));
+        memory.store(0x1122334455667788, 0x0, AccessSize::DOUBLEWORD).unwrap();
+        memory.store(0xaabbccdd, 0x8, AccessSize::WORD).unwrap();
+        let filename: std::path::PathBuf = std::env::temp_dir().join(format!("riviera-dump-{}.txt", std::process::id()));
+        let filename: &str = filename.to_str().unwrap();
+        memory.dump_to_file_as(filename, DumpFormat::parse("word64").unwrap(), 0x20000).unwrap();
+        let text: String = std::fs::read_to_string(filename).unwrap();
+        assert_eq!(text, "0x0000000000020000: 0x1122334455667788\n0x0000000000020008: 0x00000000aabbccdd\n");
+        memory.dump_to_file_as(filename, DumpFormat::parse("word32:be").unwrap(), 0x0).unwrap();
+        let text: String = std::fs::read_to_string(filename).unwrap();
+        assert_eq!(text.lines().next(), Some("0x0000000000000000: 0x88776655"));
+        let _ = std::fs::remove_file(filename);
     }
 
-    fn store32(&mut self, data: u32, paddr: usize) {
-        self.memory[paddr..paddr + 4].copy_from_slice(&data.to_le_bytes());
+    #[test]
+    fn hex_dump_test() {
+        assert_eq!(DumpFormat::parse("bin"), Ok(DumpFormat::Raw));
+        assert_eq!(DumpFormat::parse("dump"), Ok(DumpFormat::Hexdump));
+
+        let mut data: Vec<u8> = 0x1122334455667788u64.to_le_bytes().to_vec();
+        data.extend_from_slice(&0xaabbccddu32.to_le_bytes());
+        let hex: Vec<u8> = DumpFormat::Hex.encode(&data, 0x20000);
+        assert_eq!(String::from_utf8(hex).unwrap(), ":020000040002F8\n:0C0000008877665544332211DDCCBBAA82\n:00000001FF\n");
+        // A record never crosses a 64 KiB boundary
+        let hex: String = String::from_utf8(DumpFormat::Hex.encode(&data, 0x1fffc)).unwrap();
+        assert_eq!(hex.lines().count(), 5);
+        assert!(hex.lines().nth(2).unwrap().starts_with(":020000040002"));
+
+        let dump: Vec<u8> = DumpFormat::Hexdump.encode(b"riscv\0", 0x10);
+        assert_eq!(String::from_utf8(dump).unwrap(),
+                   "0000000000000010  72 69 73 63 76 00                                 |riscv.|\n");
     }
 
-    fn store64(&mut self, data: u64, paddr: usize) {
-        self.memory[paddr..paddr + 8].copy_from_slice(&data.to_le_bytes());
+    #[test]
+    fn out_of_bounds_test() {
+        let mut memory: Memory = Memory::new(Some(16));
+        assert_eq!(memory.load(0xc, AccessSize::DOUBLEWORD), Err(MemError::OutOfBounds(0xc)));
+        assert_eq!(memory.store(0, 0x10, AccessSize::BYTE), Err(MemError::OutOfBounds(0x10)));
+        assert_eq!(memory.store_buffer(&[1; 8], 0xc), Err(MemError::OutOfBounds(0xc)));
+        // Nothing is written by a buffer that does not fit
+        assert_eq!(memory.load(0xc, AccessSize::WORD), Ok(0));
+        memory.grow(20);
+        assert_eq!(memory.store_buffer(&[1; 8], 0xc), Ok(()));
+    }
+    #[test]
+    fn large_memory_test() {
+        // 64 MiB: mapped on Unix, only the written pages are allocated
+        const SIZE: usize = 64 * 1024 * 1024;
+        let mut memory: Memory = Memory::new(Some(SIZE));
+        memory.fill(MemoryInit::Zero);
+        memory.store(0x1234, (SIZE - 8) as u64, AccessSize::DOUBLEWORD).unwrap();
+        let copy: Memory = memory.clone();
+        memory.fill(MemoryInit::Zero);
+        assert_eq!(memory.load((SIZE - 8) as u64, AccessSize::DOUBLEWORD), Ok(0));
+        assert_eq!(copy.load((SIZE - 8) as u64, AccessSize::DOUBLEWORD), Ok(0x1234));
+        // The raw dump is a sparse file with the same content
+        let filename: std::path::PathBuf = std::env::temp_dir().join(format!("riviera-sparse-{}.bin", std::process::id()));
+        let filename: &str = filename.to_str().unwrap();
+        copy.dump_to_file_as(filename, DumpFormat::Raw, 0x0).unwrap();
+        let data: Vec<u8> = std::fs::read(filename).unwrap();
+        assert_eq!(data.len(), SIZE);
+        assert_eq!(&data[SIZE - 8..], &0x1234u64.to_le_bytes());
+        assert!(data[..SIZE - 8].iter().all(|byte| *byte == 0));
+        let _ = std::fs::remove_file(filename);
+    }
+    #[test]
+    fn share_test() {
+        let mut memory: Memory = Memory::new(Some(0x2000));
+        memory.store(0x1122334455667788, 0x1ff8, AccessSize::DOUBLEWORD).unwrap();
+        memory.share().unwrap();
+        let mut fork: Memory = memory.clone();
+        assert_eq!(fork.load(0x1ff8, AccessSize::DOUBLEWORD), Ok(0x1122334455667788));
+        // Each copy only sees its own writes
+        fork.store(0xaa, 0x0, AccessSize::BYTE).unwrap();
+        memory.store(0xbb, 0x1ff8, AccessSize::BYTE).unwrap();
+        assert_eq!(memory.load(0x0, AccessSize::BYTE), Ok(0x0));
+        assert_eq!(fork.load(0x0, AccessSize::BYTE), Ok(0xaa));
+        assert_eq!(fork.load(0x1ff8, AccessSize::BYTE), Ok(0x88));
+        assert_eq!(memory.clone().load(0x1ff8, AccessSize::BYTE), Ok(0xbb));
+        fork.fill(MemoryInit::Zero);
+        assert_eq!(fork.load(0x1ff8, AccessSize::DOUBLEWORD), Ok(0x0));
     }
 }
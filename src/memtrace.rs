@@ -0,0 +1,72 @@
+use std::ops::Range;
+use crate::memory::AccessSize;
+use crate::trace::{TraceKind, Tracer};
+
+/// Log of the loads and stores that go through the bus, with the PC of
+/// the instruction, the address, the size and the value, optionally only
+/// of the accesses that touch a range of addresses (e.g. a buffer that
+/// gets corrupted). The records go to the trace sinks
+#[derive(Clone)]
+pub struct MemTrace {
+    filter: Option<Range<u64>>,
+    // PC of the instruction being executed
+    pc: u64,
+    tracer: Tracer
+}
+
+impl MemTrace {
+    pub fn new(filter: Option<Range<u64>>, tracer: Tracer) -> MemTrace {
+        MemTrace { filter, pc: 0, tracer }
+    }
+
+    /// Set the PC of the instruction whose accesses follow
+    #[inline(always)]
+    pub fn set_pc(&mut self, pc: u64) {
+        self.pc = pc;
+    }
+
+    /// Log a load (write is false) or a store of value at address
+    pub fn access(&self, write: bool, address: u64, size: AccessSize, value: u64) {
+        let end: u64 = address.wrapping_add(size.bytes() as u64);
+        if self.filter.as_ref().is_some_and(|filter| address >= filter.end || end <= filter.start) {
+            return;
+        }
+        let location: String = self.tracer.get_symbols().annotate(address);
+        self.tracer.emit(TraceKind::Memory, self.pc, format!("{} 0x{:0>16x}{} {} 0x{:0>width$x}",
+                                                             if write { "w" } else { "r" }, address, location,
+                                                             size.bytes(), value, width = 2 * size.bytes()));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+    use crate::bus::Bus;
+    use crate::memory::AccessSize;
+    use crate::memtrace::MemTrace;
+    use crate::trace::{RingSink, Tracer};
+
+    #[test]
+    fn memtrace_test() {
+        // Where the DRAM starts in the default memory map
+        const DRAM: u64 = 0x20000;
+        let ring: Arc<Mutex<RingSink>> = Arc::new(Mutex::new(RingSink::new(8)));
+        let mut tracer: Tracer = Tracer::new();
+        tracer.add_sink(ring.clone());
+        let mut bus: Bus = Bus::new(Some(0x1000));
+        bus.enable_mem_trace(MemTrace::new(Some(DRAM + 0x10..DRAM + 0x18), tracer));
+        bus.simulate_fetch(0x100);
+        bus.write(0xbeef, DRAM + 0x16, AccessSize::HALFWORD).unwrap();
+        // Outside of the range
+        bus.write(0x1, DRAM + 0x18, AccessSize::BYTE).unwrap();
+        bus.simulate_fetch(0x104);
+        bus.read(DRAM + 0x10, AccessSize::DOUBLEWORD).unwrap();
+        // The debugger does not show up
+        bus.peek(DRAM + 0x10, AccessSize::DOUBLEWORD).unwrap();
+        let records: Vec<String> = ring.lock().unwrap().get_records().map(|record| record.to_string()).collect();
+        assert_eq!(records, vec![
+            format!("[mem] pc=0x0000000000000100 w 0x{:0>16x} 2 0xbeef", DRAM + 0x16),
+            format!("[mem] pc=0x0000000000000104 r 0x{:0>16x} 8 0xbeef000000000000", DRAM + 0x10)
+        ]);
+    }
+}
@@ -0,0 +1,137 @@
+use std::fs::{File, OpenOptions};
+use std::ops::{Deref, DerefMut};
+use std::os::unix::io::AsRawFd;
+use std::ptr::NonNull;
+use std::sync::atomic::{AtomicU64, Ordering};
+use crate::memory::DumpFormat;
+
+/// Bytes in a private mapping, anonymous (zero-filled) or of a file (see
+/// SharedBytes). The host only backs the pages that get written, the
+/// others all read from the shared zero page or from the file: a guest
+/// with gigabytes of RAM costs the host the memory it uses, and
+/// allocating it takes no time
+pub struct MappedBytes {
+    ptr: NonNull<u8>,
+    len: usize
+}
+
+// The mapping is owned like the buffer of a Vec
+unsafe impl Send for MappedBytes {}
+unsafe impl Sync for MappedBytes {}
+
+impl MappedBytes {
+    /// Size of the pages that are skipped when they are all zero
+    pub const PAGE_SIZE: usize = 4096;
+
+    /// Map len zero bytes. The swap space is not reserved, so that the
+    /// mapping can be larger than what the host could back. It returns
+    /// None if the host refuses the mapping
+    pub fn new(len: usize) -> Option<MappedBytes> {
+        MappedBytes::map(len, libc::PROT_READ | libc::PROT_WRITE, libc::MAP_ANONYMOUS | libc::MAP_NORESERVE, -1)
+    }
+
+    // Map the first len bytes of a file privately: the writes (if it is
+    // writable) go to copies of the pages that only this mapping sees
+    fn map_file(file: &File, len: usize, writable: bool) -> Option<MappedBytes> {
+        let protection: libc::c_int = if writable { libc::PROT_READ | libc::PROT_WRITE } else { libc::PROT_READ };
+        MappedBytes::map(len, protection, libc::MAP_NORESERVE, file.as_raw_fd())
+    }
+
+    fn map(len: usize, protection: libc::c_int, flags: libc::c_int, fd: libc::c_int) -> Option<MappedBytes> {
+        if len == 0 {
+            return None;
+        }
+        let ptr: *mut libc::c_void = unsafe {
+            libc::mmap(std::ptr::null_mut(), len, protection, libc::MAP_PRIVATE | flags, fd, 0)
+        };
+        if ptr == libc::MAP_FAILED {
+            return None;
+        }
+        NonNull::new(ptr as *mut u8).map(|ptr| MappedBytes { ptr, len })
+    }
+}
+
+/// Contents of a memory frozen in an unlinked temporary file. Its copies
+/// are private mappings of the file: the host shares the pages between
+/// all of them and only copies a page for the one that writes it. Taking
+/// a copy costs a system call, whatever the size of the memory
+pub struct SharedBytes {
+    // Read-only mapping of the whole file
+    bytes: MappedBytes,
+    file: File
+}
+
+// Tells apart the files of the memories frozen by a process
+static SHARED_FILES: AtomicU64 = AtomicU64::new(0);
+
+impl SharedBytes {
+    /// Freeze data. The file is written sparse, so the zero pages take
+    /// no space
+    pub fn new(data: &[u8]) -> Result<SharedBytes, String> {
+        let error = |why: std::io::Error| format!("Could not share the memory: {}", why);
+        let path: std::path::PathBuf = std::env::temp_dir().join(format!("riviera-memory-{}-{}", std::process::id(),
+                                                                         SHARED_FILES.fetch_add(1, Ordering::Relaxed)));
+        let mut file: File = OpenOptions::new().read(true).write(true).create_new(true).open(&path).map_err(error)?;
+        // The file lives as long as it is open
+        let _ = std::fs::remove_file(&path);
+        DumpFormat::write_sparse(&mut file, data).map_err(error)?;
+        let bytes: MappedBytes = MappedBytes::map_file(&file, data.len(), false)
+            .ok_or_else(|| error(std::io::Error::last_os_error()))?;
+        Ok(SharedBytes { bytes, file })
+    }
+
+    /// Writable copy of the contents, None if the host refuses the mapping
+    pub fn copy(&self) -> Option<MappedBytes> {
+        MappedBytes::map_file(&self.file, self.bytes.len, true)
+    }
+}
+
+impl Deref for SharedBytes {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.bytes
+    }
+}
+
+/// Copy src into dst, which is all zeros, skipping the pages of src that
+/// are all zeros: a mapped destination only gets the pages that hold data
+pub fn copy_sparse(dst: &mut [u8], src: &[u8]) {
+    for (dst, src) in dst.chunks_mut(MappedBytes::PAGE_SIZE).zip(src.chunks(MappedBytes::PAGE_SIZE)) {
+        if src.iter().any(|byte| *byte != 0) {
+            dst[..src.len()].copy_from_slice(src);
+        }
+    }
+}
+
+impl Deref for MappedBytes {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        unsafe { std::slice::from_raw_parts(self.ptr.as_ptr(), self.len) }
+    }
+}
+
+impl DerefMut for MappedBytes {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        unsafe { std::slice::from_raw_parts_mut(self.ptr.as_ptr(), self.len) }
+    }
+}
+
+// A copy only backs the pages that hold data. Reading the pages that
+// were never written does not allocate them
+impl Clone for MappedBytes {
+    fn clone(&self) -> MappedBytes {
+        let mut copy: MappedBytes = MappedBytes::new(self.len).expect("Could not map memory for a copy");
+        copy_sparse(&mut copy, self);
+        copy
+    }
+}
+
+impl Drop for MappedBytes {
+    fn drop(&mut self) {
+        unsafe {
+            libc::munmap(self.ptr.as_ptr() as *mut libc::c_void, self.len);
+        }
+    }
+}
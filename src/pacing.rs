@@ -0,0 +1,110 @@
+use std::time::{Duration, Instant};
+use crate::clint::Clint;
+
+/// Speed limit of a run. By default the program runs as fast as the host
+/// allows, which breaks the programs that rely on timing (e.g. a delay
+/// loop or a blinking LED): pacing slows the CPU down to a realistic speed
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Pacing {
+    /// At most this many instructions per second of host time
+    Instructions(f64),
+    /// The guest time (mtime) advances at its frequency in host time,
+    /// e.g. one second of mtime takes one second
+    RealTime
+}
+
+impl Pacing {
+    /// Parse a speed limit in millions of instructions per second, e.g. 2.5
+    pub fn parse_mips(text: &str) -> Result<Pacing, String> {
+        match text.trim().parse::<f64>() {
+            Ok(mips) if mips.is_finite() && mips > 0.0 => Ok(Pacing::Instructions(mips * 1e6)),
+            _ => Err(format!("Invalid MIPS limit {}: expected a positive number", text))
+        }
+    }
+
+    // Units of progress (instructions or ticks of mtime) per second
+    fn rate(&self) -> f64 {
+        match self {
+            Pacing::Instructions(rate) => *rate,
+            Pacing::RealTime => Clint::TIMEBASE_FREQUENCY as f64
+        }
+    }
+}
+
+/// Keeps a run at the speed of its pacing: the CPU runs in chunks of about
+/// a millisecond and after each one the pacer sleeps until the host time
+/// catches up with the progress of the program
+#[derive(Clone, Debug)]
+pub struct Pacer {
+    pacing: Pacing,
+    // Host time and progress the pace is measured from
+    reference: Option<(Instant, u64)>
+}
+
+impl Pacer {
+    // Beyond this, the program is not brought back to the pace by running
+    // faster or by sleeping longer: the pace starts over from there (e.g.
+    // after the host was busy or after mtime was set)
+    const MAX_DRIFT: Duration = Duration::from_millis(100);
+
+    pub fn new(pacing: Pacing) -> Pacer {
+        Pacer { pacing, reference: None }
+    }
+
+    pub fn get_pacing(&self) -> Pacing {
+        self.pacing
+    }
+
+    /// Instructions to run between two calls to pace (with real-time pacing
+    /// mtime advances by about one tick per instruction)
+    pub fn chunk(&self) -> u64 {
+        (self.pacing.rate() / 1000.0).max(1.0) as u64
+    }
+
+    /// Measure the pace from now on, where the progress of the program is
+    /// progress (the executed instructions or mtime, depending on the pacing)
+    pub fn start(&mut self, progress: u64) {
+        self.reference = Some((Instant::now(), progress));
+    }
+
+    /// Sleep until the host time catches up with progress
+    pub fn pace(&mut self, progress: u64) {
+        let Some((start, base)) = self.reference else {
+            return self.start(progress);
+        };
+        let due: Duration = Duration::from_secs_f64(progress.saturating_sub(base) as f64 / self.pacing.rate());
+        let elapsed: Duration = start.elapsed();
+        if progress < base || due.abs_diff(elapsed) > Pacer::MAX_DRIFT {
+            self.start(progress);
+        } else if due > elapsed {
+            std::thread::sleep(due - elapsed);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+    use crate::pacing::{Pacer, Pacing};
+
+    #[test]
+    fn pacing_test() {
+        assert_eq!(Pacing::parse_mips("2.5"), Ok(Pacing::Instructions(2.5e6)));
+        assert!(Pacing::parse_mips("0").is_err());
+        assert!(Pacing::parse_mips("fast").is_err());
+
+        // 1 MIPS: 20000 instructions take 20 ms
+        let mut pacer: Pacer = Pacer::new(Pacing::Instructions(1e6));
+        assert_eq!(pacer.chunk(), 1000);
+        pacer.start(0);
+        let now: Instant = Instant::now();
+        pacer.pace(20_000);
+        assert!(now.elapsed() >= Duration::from_millis(19));
+        // A jump far ahead (e.g. mtime set by hand) does not sleep
+        let mut pacer: Pacer = Pacer::new(Pacing::RealTime);
+        pacer.start(0);
+        let now: Instant = Instant::now();
+        pacer.pace(1 << 40);
+        assert!(now.elapsed() < Duration::from_secs(1));
+    }
+}
@@ -0,0 +1,330 @@
+use crate::bus::Bus;
+use crate::device::Device;
+use crate::memory::AccessSize;
+use crate::snapshot::{Snapshot, SnapshotReader, SnapshotWriter};
+
+/// Platform-level interrupt controller with the register layout of the
+/// SiFive PLIC, for a single hart with two contexts: 0 drives the machine
+/// external interrupt (MEIP), 1 the supervisor external interrupt (SEIP).
+/// Interrupt sources are level-triggered: a source is pending while its
+/// line is high, until it is claimed, and it can become pending again
+/// only after the claim has been completed
+#[derive(Clone)]
+pub struct Plic {
+    priority: [u32; Plic::NUM_SOURCES],
+    // Bit i is set if source i is pending
+    pending: u32,
+    // Sources claimed by a context and not completed yet
+    claimed: u32,
+    // Current level of the line of each source
+    lines: u32,
+    // Sources triggered from the host that have not become pending yet
+    // because they were claimed at the time
+    injected: u32,
+    enable: [u32; Plic::NUM_CONTEXTS],
+    threshold: [u32; Plic::NUM_CONTEXTS],
+    // Interrupt output of each context, as MEIP and SEIP bits of mip
+    output: u64
+}
+
+impl Default for Plic {
+    fn default() -> Plic {
+        Plic::new()
+    }
+}
+
+impl Plic {
+    /// Sources 1 to 31 can be connected to devices, 0 means "no interrupt"
+    pub const NUM_SOURCES: usize = 32;
+    const NUM_CONTEXTS: usize = 2;
+    const MAX_PRIORITY: u32 = 7;
+
+    const PRIORITY_ADDR:  u64 = 0x0;
+    const PENDING_ADDR:   u64 = 0x1000;
+    const ENABLE_ADDR:    u64 = 0x2000;
+    const ENABLE_STRIDE:  u64 = 0x80;
+    const CONTEXT_ADDR:   u64 = 0x200000;
+    const CONTEXT_STRIDE: u64 = 0x1000;
+
+    // mip bit driven by each context
+    const CONTEXT_INTERRUPTS: [u64; Plic::NUM_CONTEXTS] = [1 << 11, 1 << 9];
+
+    pub fn new() -> Plic {
+        Plic {
+            priority: [0; Plic::NUM_SOURCES],
+            pending: 0,
+            claimed: 0,
+            lines: 0,
+            injected: 0,
+            enable: [0; Plic::NUM_CONTEXTS],
+            threshold: [0; Plic::NUM_CONTEXTS],
+            output: 0
+        }
+    }
+
+    /// Set the level of the interrupt line of a source
+    #[inline(always)]
+    pub fn set_source(&mut self, source: usize, level: bool) {
+        let bit: u32 = 1 << source;
+        if (self.lines & bit != 0) != level {
+            self.lines ^= bit;
+            self.update();
+        }
+    }
+
+    /// Make a source pending once, whatever the level of its line, as an
+    /// edge would on an edge-triggered source. If the source is claimed,
+    /// it becomes pending when the claim is completed
+    pub fn trigger(&mut self, source: usize) {
+        self.injected |= 1 << source;
+        self.update();
+    }
+
+    /// Interrupt outputs of the contexts as MEIP and SEIP bits of mip
+    #[inline(always)]
+    pub fn interrupt_lines(&self) -> u64 {
+        self.output
+    }
+
+    /// Read a register at an offset from the beginning of the PLIC.
+    /// Registers are 32 bits wide and only word accesses are allowed.
+    /// Reading a claim register claims the interrupt it returns
+    pub fn read(&mut self, offset: u64, size: AccessSize) -> Option<u64> {
+        if size != AccessSize::WORD {
+            return None;
+        }
+        if let Some(context) = Plic::claim_context(offset) {
+            let source: usize = self.best_source(context).unwrap_or(0);
+            if source != 0 {
+                self.pending &= !(1 << source);
+                self.claimed |= 1 << source;
+                self.update();
+            }
+            return Some(source as u64);
+        }
+        self.peek(offset, size)
+    }
+
+    /// Read a register without side effects: claim registers return the
+    /// interrupt that would be claimed without claiming it
+    pub fn peek(&self, offset: u64, size: AccessSize) -> Option<u64> {
+        if size != AccessSize::WORD {
+            return None;
+        }
+        if let Some(context) = Plic::claim_context(offset) {
+            return Some(self.best_source(context).unwrap_or(0) as u64);
+        }
+        let value: u32 = match offset {
+            offset if offset < Plic::PENDING_ADDR => *self.priority.get(Plic::priority_source(offset))?,
+            Plic::PENDING_ADDR => self.pending,
+            _ => match Plic::enable_context(offset) {
+                Some(context) => self.enable[context],
+                None => self.threshold[Plic::threshold_context(offset)?]
+            }
+        };
+        Some(value as u64)
+    }
+
+    /// Write a register at an offset from the beginning of the PLIC.
+    /// Writing a source to a claim register completes it
+    pub fn write(&mut self, offset: u64, data: u64, size: AccessSize) -> Option<()> {
+        if size != AccessSize::WORD {
+            return None;
+        }
+        let data: u32 = data as u32;
+        if Plic::claim_context(offset).is_some() {
+            if (data as usize) < Plic::NUM_SOURCES {
+                self.claimed &= !(1 << data);
+            }
+        } else if offset < Plic::PENDING_ADDR {
+            let source: usize = Plic::priority_source(offset);
+            // Source 0 does not exist
+            if source != 0 {
+                *self.priority.get_mut(source)? = data.min(Plic::MAX_PRIORITY);
+            }
+        } else if offset == Plic::PENDING_ADDR {
+            // Pending bits are read-only
+        } else if let Some(context) = Plic::enable_context(offset) {
+            self.enable[context] = data & !0x1;
+        } else {
+            self.threshold[Plic::threshold_context(offset)?] = data.min(Plic::MAX_PRIORITY);
+        }
+        self.update();
+        Some(())
+    }
+
+    // Recompute the pending sources and the outputs of the contexts
+    fn update(&mut self) {
+        let raised: u32 = (self.lines | self.injected) & !self.claimed;
+        self.pending |= raised;
+        self.injected &= !raised;
+        self.output = (0..Plic::NUM_CONTEXTS)
+            .filter(|context| self.best_source(*context).is_some())
+            .map(|context| Plic::CONTEXT_INTERRUPTS[context])
+            .fold(0, |output, interrupt| output | interrupt);
+    }
+
+    // Pending source enabled for a context with the highest priority
+    // above the threshold of the context (the lowest ID wins ties)
+    fn best_source(&self, context: usize) -> Option<usize> {
+        let candidates: u32 = self.pending & self.enable[context];
+        (1..Plic::NUM_SOURCES)
+            .filter(|source| (candidates >> source) & 0x1 == 0x1)
+            .filter(|source| self.priority[*source] > self.threshold[context])
+            .max_by(|a, b| self.priority[*a].cmp(&self.priority[*b]).then(b.cmp(a)))
+    }
+
+    fn priority_source(offset: u64) -> usize {
+        ((offset - Plic::PRIORITY_ADDR) / 4) as usize
+    }
+
+    fn enable_context(offset: u64) -> Option<usize> {
+        let context: u64 = offset.checked_sub(Plic::ENABLE_ADDR)? / Plic::ENABLE_STRIDE;
+        let register: u64 = offset - Plic::ENABLE_ADDR - context * Plic::ENABLE_STRIDE;
+        (context < Plic::NUM_CONTEXTS as u64 && register == 0).then_some(context as usize)
+    }
+
+    fn threshold_context(offset: u64) -> Option<usize> {
+        Plic::context_register(offset, 0x0)
+    }
+
+    fn claim_context(offset: u64) -> Option<usize> {
+        Plic::context_register(offset, 0x4)
+    }
+
+    fn context_register(offset: u64, register: u64) -> Option<usize> {
+        let context: u64 = offset.checked_sub(Plic::CONTEXT_ADDR)? / Plic::CONTEXT_STRIDE;
+        let offset: u64 = offset - Plic::CONTEXT_ADDR - context * Plic::CONTEXT_STRIDE;
+        (context < Plic::NUM_CONTEXTS as u64 && offset == register).then_some(context as usize)
+    }
+}
+
+// The PLIC can also be attached as a further device (e.g. a second
+// controller cascaded into a source of the first one). Its interrupt
+// line is the output of the machine context
+impl Device for Plic {
+    fn name(&self) -> &str {
+        "plic"
+    }
+
+    fn size(&self) -> u64 {
+        Bus::PLIC_SIZE
+    }
+
+    fn read(&mut self, offset: u64, size: AccessSize) -> Option<u64> {
+        Plic::read(self, offset, size)
+    }
+
+    fn peek(&self, offset: u64, size: AccessSize) -> Option<u64> {
+        Plic::peek(self, offset, size)
+    }
+
+    fn write(&mut self, offset: u64, data: u64, size: AccessSize) -> Option<()> {
+        Plic::write(self, offset, data, size)
+    }
+
+    fn interrupt(&self) -> bool {
+        self.output & Plic::CONTEXT_INTERRUPTS[0] != 0
+    }
+
+    fn compatible(&self) -> &[&str] {
+        &["sifive,plic-1.0.0", "riscv,plic0"]
+    }
+
+    fn clone_device(&self) -> Box<dyn Device> {
+        Box::new(self.clone())
+    }
+}
+
+impl Snapshot for Plic {
+    fn save(&self, writer: &mut SnapshotWriter) {
+        for priority in self.priority.iter() {
+            writer.put_u32(*priority);
+        }
+        writer.put_u32(self.pending);
+        writer.put_u32(self.claimed);
+        writer.put_u32(self.lines);
+        writer.put_u32(self.injected);
+        for context in 0..Plic::NUM_CONTEXTS {
+            writer.put_u32(self.enable[context]);
+            writer.put_u32(self.threshold[context]);
+        }
+    }
+
+    fn restore(&mut self, reader: &mut SnapshotReader) -> Result<(), String> {
+        for priority in self.priority.iter_mut() {
+            *priority = reader.get_u32()?;
+        }
+        self.pending = reader.get_u32()?;
+        self.claimed = reader.get_u32()?;
+        self.lines = reader.get_u32()?;
+        self.injected = reader.get_u32()?;
+        for context in 0..Plic::NUM_CONTEXTS {
+            self.enable[context] = reader.get_u32()?;
+            self.threshold[context] = reader.get_u32()?;
+        }
+        // The outputs only depend on the rest of the state
+        self.update();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::memory::AccessSize;
+    use crate::plic::Plic;
+
+    #[test]
+    fn claim_complete_test() {
+        const MEIP: u64 = 1 << 11;
+        const SEIP: u64 = 1 << 9;
+        let mut plic: Plic = Plic::new();
+        plic.set_source(10, true);
+        plic.set_source(3, true);
+        // Nothing is signaled until the sources are enabled with a priority
+        assert_eq!(plic.interrupt_lines(), 0);
+        plic.write(10 * 4, 2, AccessSize::WORD).unwrap();
+        plic.write(3 * 4, 1, AccessSize::WORD).unwrap();
+        plic.write(0x2000, (1 << 10) | (1 << 3), AccessSize::WORD).unwrap();
+        assert_eq!(plic.interrupt_lines(), MEIP);
+        assert_eq!(plic.peek(0x1000, AccessSize::WORD), Some((1 << 10) | (1 << 3)));
+
+        // The highest priority source is claimed first
+        assert_eq!(plic.read(0x200004, AccessSize::WORD), Some(10));
+        assert_eq!(plic.read(0x200004, AccessSize::WORD), Some(3));
+        assert_eq!(plic.read(0x200004, AccessSize::WORD), Some(0));
+        assert_eq!(plic.interrupt_lines(), 0);
+        // A source whose line is still high is pending again after completion
+        plic.write(0x200004, 10, AccessSize::WORD).unwrap();
+        assert_eq!(plic.interrupt_lines(), MEIP);
+        plic.set_source(3, false);
+        plic.write(0x200004, 3, AccessSize::WORD).unwrap();
+        assert_eq!(plic.peek(0x1000, AccessSize::WORD), Some(1 << 10));
+
+        // The threshold masks sources with a priority that is not above it
+        plic.write(0x200000, 2, AccessSize::WORD).unwrap();
+        assert_eq!(plic.interrupt_lines(), 0);
+        // The S-mode context has its own enables
+        plic.write(0x2080, 1 << 10, AccessSize::WORD).unwrap();
+        assert_eq!(plic.interrupt_lines(), SEIP);
+        assert_eq!(plic.read(0x201004, AccessSize::BYTE), None);
+        assert_eq!(plic.read(0x201004, AccessSize::WORD), Some(10));
+    }
+
+    #[test]
+    fn trigger_test() {
+        let mut plic: Plic = Plic::new();
+        plic.write(5 * 4, 1, AccessSize::WORD).unwrap();
+        plic.write(0x2000, 1 << 5, AccessSize::WORD).unwrap();
+        // A triggered source is pending once, its line stays low
+        plic.trigger(5);
+        assert_eq!(plic.read(0x200004, AccessSize::WORD), Some(5));
+        // Triggered while claimed: pending after the completion
+        plic.trigger(5);
+        assert_eq!(plic.read(0x200004, AccessSize::WORD), Some(0));
+        plic.write(0x200004, 5, AccessSize::WORD).unwrap();
+        assert_eq!(plic.read(0x200004, AccessSize::WORD), Some(5));
+        plic.write(0x200004, 5, AccessSize::WORD).unwrap();
+        assert_eq!(plic.interrupt_lines(), 0);
+    }
+}
@@ -0,0 +1,204 @@
+use std::ffi::{c_char, c_int, c_void, CStr};
+use crate::device::Device;
+use crate::expr;
+use crate::memory::AccessSize;
+
+/// Version of the plugin interface. Plugins built for another version are refused
+pub const PLUGIN_ABI_VERSION: u32 = 1;
+
+/// Descriptor of a device plugin. A plugin is a shared library exporting
+/// `const struct riviera_device_plugin *riviera_device_plugin(void)`,
+/// where the structure has the same layout as this one:
+///
+/// ```c
+/// struct riviera_device_plugin {
+///     uint32_t abi_version;           // PLUGIN_ABI_VERSION
+///     const char *name;
+///     uint64_t size;                  // size of the register window
+///     void *(*create)(void);
+///     void *(*clone)(const void *state);
+///     void (*destroy)(void *state);
+///     // Accesses return 0 on success, anything else is an access fault.
+///     // size is the width of the access in bytes (1, 2, 4 or 8)
+///     int (*read)(void *state, uint64_t offset, uint32_t size, uint64_t *value);
+///     int (*peek)(const void *state, uint64_t offset, uint32_t size, uint64_t *value);  // optional
+///     int (*write)(void *state, uint64_t offset, uint32_t size, uint64_t value);
+///     void (*cycle)(void *state);                                                       // optional
+///     int (*interrupt)(const void *state);                                              // optional
+/// };
+/// ```
+///
+/// Optional functions can be NULL. The state returned by create is owned
+/// by riviera, which releases it with destroy
+#[repr(C)]
+pub struct PluginDescriptor {
+    pub abi_version: u32,
+    pub name: *const c_char,
+    pub size: u64,
+    pub create: extern "C" fn() -> *mut c_void,
+    pub clone: extern "C" fn(*const c_void) -> *mut c_void,
+    pub destroy: extern "C" fn(*mut c_void),
+    pub read: extern "C" fn(*mut c_void, u64, u32, *mut u64) -> c_int,
+    pub peek: Option<extern "C" fn(*const c_void, u64, u32, *mut u64) -> c_int>,
+    pub write: extern "C" fn(*mut c_void, u64, u32, u64) -> c_int,
+    pub cycle: Option<extern "C" fn(*mut c_void)>,
+    pub interrupt: Option<extern "C" fn(*const c_void) -> c_int>
+}
+
+/// Where a plugin is mapped, as given on the command line:
+/// `<library>@<base>` optionally followed by `,irq=<source>` to connect
+/// the interrupt of the device to a PLIC source
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct PluginSpec {
+    pub path: String,
+    pub base: u64,
+    pub irq: Option<usize>
+}
+
+impl PluginSpec {
+    pub fn parse(spec: &str) -> Result<PluginSpec, String> {
+        let (mapping, irq) = match spec.split_once(",irq=") {
+            Some((mapping, irq)) => match irq.parse::<usize>() {
+                Ok(irq) => (mapping, Some(irq)),
+                Err(_) => return Err(format!("Invalid interrupt source {}", irq))
+            },
+            None => (spec, None)
+        };
+        match mapping.rsplit_once('@') {
+            Some((path, base)) if !path.is_empty() => Ok(PluginSpec { path: path.to_string(), base: expr::parse_number(base)?, irq }),
+            _ => Err(format!("Invalid device plugin {}: expected <library>@<base>[,irq=<source>]", spec))
+        }
+    }
+}
+
+/// A device implemented by a plugin library
+pub struct PluginDevice {
+    descriptor: &'static PluginDescriptor,
+    name: String,
+    state: *mut c_void
+}
+
+// The state of the plugin is only ever used by the thread that owns the device
+unsafe impl Send for PluginDevice {}
+
+impl PluginDevice {
+    /// Load a plugin library and create its device. The library stays
+    /// loaded until riviera exits
+    pub fn load(path: &str) -> Result<PluginDevice, String> {
+        let descriptor: &'static PluginDescriptor = load_descriptor(path)?;
+        if descriptor.abi_version != PLUGIN_ABI_VERSION {
+            return Err(format!("Device plugin {} was built for interface version {} (expected {})",
+                               path, descriptor.abi_version, PLUGIN_ABI_VERSION));
+        }
+        let name: String = if descriptor.name.is_null() {
+            path.to_string()
+        } else {
+            unsafe { CStr::from_ptr(descriptor.name) }.to_string_lossy().into_owned()
+        };
+        let state: *mut c_void = (descriptor.create)();
+        if state.is_null() {
+            return Err(format!("Device plugin {} could not create its device", name));
+        }
+        Ok(PluginDevice { descriptor, name, state })
+    }
+}
+
+impl Device for PluginDevice {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn size(&self) -> u64 {
+        self.descriptor.size
+    }
+
+    fn read(&mut self, offset: u64, size: AccessSize) -> Option<u64> {
+        let mut value: u64 = 0;
+        match (self.descriptor.read)(self.state, offset, size.bytes() as u32, &mut value) {
+            0 => Some(value),
+            _ => None
+        }
+    }
+
+    fn peek(&self, offset: u64, size: AccessSize) -> Option<u64> {
+        let peek = self.descriptor.peek?;
+        let mut value: u64 = 0;
+        match peek(self.state, offset, size.bytes() as u32, &mut value) {
+            0 => Some(value),
+            _ => None
+        }
+    }
+
+    fn write(&mut self, offset: u64, data: u64, size: AccessSize) -> Option<()> {
+        match (self.descriptor.write)(self.state, offset, size.bytes() as u32, data) {
+            0 => Some(()),
+            _ => None
+        }
+    }
+
+    fn cycle(&mut self) {
+        if let Some(cycle) = self.descriptor.cycle {
+            cycle(self.state);
+        }
+    }
+
+    fn interrupt(&self) -> bool {
+        match self.descriptor.interrupt {
+            Some(interrupt) => interrupt(self.state) != 0,
+            None => false
+        }
+    }
+
+    fn clone_device(&self) -> Box<dyn Device> {
+        let state: *mut c_void = (self.descriptor.clone)(self.state);
+        assert!(!state.is_null(), "device plugin {} could not clone its device", self.name);
+        Box::new(PluginDevice { descriptor: self.descriptor, name: self.name.clone(), state })
+    }
+}
+
+impl Drop for PluginDevice {
+    fn drop(&mut self) {
+        (self.descriptor.destroy)(self.state);
+    }
+}
+
+// Open the library and get the descriptor of the plugin
+#[cfg(unix)]
+fn load_descriptor(path: &str) -> Result<&'static PluginDescriptor, String> {
+    const ENTRY_POINT: &CStr = c"riviera_device_plugin";
+    let filename: std::ffi::CString = std::ffi::CString::new(path)
+        .map_err(|_| format!("Invalid device plugin path {}", path))?;
+    unsafe {
+        let handle: *mut c_void = libc::dlopen(filename.as_ptr(), libc::RTLD_NOW | libc::RTLD_LOCAL);
+        if handle.is_null() {
+            let reason: String = CStr::from_ptr(libc::dlerror()).to_string_lossy().into_owned();
+            return Err(format!("Could not load device plugin {}: {}", path, reason));
+        }
+        let entry: *mut c_void = libc::dlsym(handle, ENTRY_POINT.as_ptr());
+        if entry.is_null() {
+            return Err(format!("{} is not a device plugin (riviera_device_plugin is missing)", path));
+        }
+        let entry: extern "C" fn() -> *const PluginDescriptor = std::mem::transmute(entry);
+        entry().as_ref().ok_or(format!("Device plugin {} did not return its descriptor", path))
+    }
+}
+
+#[cfg(not(unix))]
+fn load_descriptor(path: &str) -> Result<&'static PluginDescriptor, String> {
+    Err(format!("Could not load device plugin {}: plugins are only supported on Unix", path))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::plugin::PluginSpec;
+
+    #[test]
+    fn spec_test() {
+        assert_eq!(PluginSpec::parse("./libtimer.so@0x10002000"),
+                   Ok(PluginSpec { path: "./libtimer.so".to_string(), base: 0x10002000, irq: None }));
+        assert_eq!(PluginSpec::parse("dev@lib.so@4096,irq=3"),
+                   Ok(PluginSpec { path: "dev@lib.so".to_string(), base: 0x1000, irq: Some(3) }));
+        assert!(PluginSpec::parse("libtimer.so").is_err());
+        assert!(PluginSpec::parse("libtimer.so@0x1000,irq=uart").is_err());
+    }
+}
@@ -0,0 +1,247 @@
+use crate::cpu::Xlen;
+use crate::csr::Privilege;
+use crate::snapshot::{Snapshot, SnapshotReader, SnapshotWriter};
+
+/// Number of PMP entries (pmpcfg0-pmpcfg3 and pmpaddr0-pmpaddr15)
+pub const PMP_ENTRIES: usize = 16;
+
+/// Kind of memory access checked by the PMP, the value is its permission bit
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum PmpAccess {
+    Read = 0x1,
+    Write = 0x2,
+    Execute = 0x4
+}
+
+/// Physical memory protection: each entry gives the read, write and
+/// execute permissions of a range of addresses, selected by its
+/// configuration byte (in pmpcfg) and its address register (pmpaddr).
+/// An access is checked against the lowest-numbered entry that matches
+/// any of its bytes, and fails if the entry does not contain all of them
+/// or does not grant the permission. M-mode accesses are only checked
+/// against locked entries and succeed if no entry matches, while S-mode
+/// accesses fail if no entry matches. The granularity is 4 bytes
+#[derive(Clone)]
+pub struct Pmp {
+    cfg: [u8; PMP_ENTRIES],
+    addr: [u64; PMP_ENTRIES],
+    // Some entry is locked, so M-mode accesses have to be checked
+    locked: bool
+}
+
+impl Default for Pmp {
+    fn default() -> Pmp {
+        Pmp::new()
+    }
+}
+
+impl Pmp {
+    // Fields of a configuration byte
+    const R: u8 = 0x1;
+    const W: u8 = 0x2;
+    const A_SHIFT: u32 = 3;
+    const L: u8 = 0x80;
+    // Writable bits: R, W, X, A and L
+    const CFG_MASK: u8 = 0x9f;
+    // Address matching modes (A field)
+    const OFF: u8 = 0;
+    const TOR: u8 = 1;
+    const NA4: u8 = 2;
+    const NAPOT: u8 = 3;
+    // pmpaddr holds bits 55:2 of an address on RV64, 33:2 on RV32
+    const ADDR_MASK_RV64: u64 = (1 << 54) - 1;
+    const ADDR_MASK_RV32: u64 = 0xffff_ffff;
+
+    /// Configuration of an entry whose permissions cover the whole
+    /// address space with pmpaddr set to all ones (NAPOT, RWX)
+    pub const ALLOW_ALL: u8 = (Pmp::NAPOT << Pmp::A_SHIFT) | 0x7;
+
+    pub fn new() -> Pmp {
+        Pmp { cfg: [0; PMP_ENTRIES], addr: [0; PMP_ENTRIES], locked: false }
+    }
+
+    // Entries configured by pmpcfg<index>: 4 per register on RV32, 8 on
+    // RV64 where only the even registers exist
+    fn cfg_entries(index: usize, xlen: Xlen) -> Option<std::ops::Range<usize>> {
+        match xlen {
+            Xlen::Rv32 => Some(index * 4..index * 4 + 4),
+            Xlen::Rv64 if index.is_multiple_of(2) => Some(index * 4..index * 4 + 8),
+            Xlen::Rv64 => None
+        }
+    }
+
+    /// Read pmpcfg<index>
+    pub fn read_cfg(&self, index: usize, xlen: Xlen) -> Option<u64> {
+        let entries = Pmp::cfg_entries(index, xlen)?;
+        Some(self.cfg[entries].iter().rev().fold(0, |value, cfg| (value << 8) | *cfg as u64))
+    }
+
+    /// Write pmpcfg<index>. The bytes of the locked entries are not
+    /// changed, and the reserved combination W without R clears W
+    pub fn write_cfg(&mut self, index: usize, value: u64, xlen: Xlen) -> Option<()> {
+        let entries = Pmp::cfg_entries(index, xlen)?;
+        for (shift, entry) in entries.enumerate() {
+            if self.cfg[entry] & Pmp::L != 0 {
+                continue;
+            }
+            let mut cfg: u8 = (value >> (shift * 8)) as u8 & Pmp::CFG_MASK;
+            if cfg & (Pmp::R | Pmp::W) == Pmp::W {
+                cfg &= !Pmp::W;
+            }
+            self.cfg[entry] = cfg;
+        }
+        self.locked = self.cfg.iter().any(|cfg| cfg & Pmp::L != 0);
+        Some(())
+    }
+
+    /// Read pmpaddr<index>
+    pub fn read_addr(&self, index: usize) -> u64 {
+        self.addr[index]
+    }
+
+    /// Write pmpaddr<index>, unless its entry is locked or it is the top
+    /// of the range of the next entry, locked and in TOR mode
+    pub fn write_addr(&mut self, index: usize, value: u64, xlen: Xlen) {
+        let locked_tor: bool = index + 1 < PMP_ENTRIES && self.cfg[index + 1] & Pmp::L != 0
+            && self.mode(index + 1) == Pmp::TOR;
+        if self.cfg[index] & Pmp::L != 0 || locked_tor {
+            return;
+        }
+        self.addr[index] = match xlen {
+            Xlen::Rv32 => value & Pmp::ADDR_MASK_RV32,
+            Xlen::Rv64 => value & Pmp::ADDR_MASK_RV64
+        };
+    }
+
+    /// Configure an entry as the firmware does before starting a kernel
+    pub fn set_entry(&mut self, index: usize, cfg: u8, addr: u64, xlen: Xlen) {
+        self.write_addr(index, addr, xlen);
+        if self.cfg[index] & Pmp::L == 0 {
+            self.cfg[index] = cfg & Pmp::CFG_MASK;
+        }
+        self.locked = self.cfg.iter().any(|cfg| cfg & Pmp::L != 0);
+    }
+
+    fn mode(&self, index: usize) -> u8 {
+        (self.cfg[index] >> Pmp::A_SHIFT) & 0x3
+    }
+
+    // Range of addresses [start, end) of an entry, None if it is off
+    fn range(&self, index: usize) -> Option<(u128, u128)> {
+        let addr: u128 = self.addr[index] as u128;
+        match self.mode(index) {
+            Pmp::OFF => None,
+            Pmp::TOR => {
+                let start: u128 = if index == 0 { 0 } else { (self.addr[index - 1] as u128) << 2 };
+                Some((start, addr << 2))
+            },
+            Pmp::NA4 => Some((addr << 2, (addr << 2) + 4)),
+            _ => {
+                // The number of trailing ones gives the size of the range
+                let ones: u32 = self.addr[index].trailing_ones();
+                let start: u128 = (addr & !((1 << ones) - 1)) << 2;
+                Some((start, start + (1 << (ones + 3))))
+            }
+        }
+    }
+
+    /// Check if an access of size bytes at addr is allowed at a privilege level
+    #[inline(always)]
+    pub fn allows(&self, addr: u64, size: u64, access: PmpAccess, privilege: Privilege) -> bool {
+        if privilege == Privilege::Machine && !self.locked {
+            return true;
+        }
+        self.check(addr, size, access, privilege)
+    }
+
+    fn check(&self, addr: u64, size: u64, access: PmpAccess, privilege: Privilege) -> bool {
+        let first: u128 = addr as u128;
+        let last: u128 = first + size as u128;
+        for index in 0..PMP_ENTRIES {
+            let (start, end) = match self.range(index) {
+                Some(range) => range,
+                None => continue
+            };
+            if first >= end || last <= start {
+                continue;
+            }
+            if first < start || last > end {
+                return false;
+            }
+            let cfg: u8 = self.cfg[index];
+            return (privilege == Privilege::Machine && cfg & Pmp::L == 0) || cfg & access as u8 != 0;
+        }
+        privilege == Privilege::Machine
+    }
+}
+
+impl Snapshot for Pmp {
+    fn save(&self, writer: &mut SnapshotWriter) {
+        for (cfg, addr) in self.cfg.iter().zip(self.addr.iter()) {
+            writer.put_u8(*cfg);
+            writer.put_u64(*addr);
+        }
+    }
+
+    fn restore(&mut self, reader: &mut SnapshotReader) -> Result<(), String> {
+        for index in 0..PMP_ENTRIES {
+            self.cfg[index] = reader.get_u8()?;
+            self.addr[index] = reader.get_u64()?;
+        }
+        self.locked = self.cfg.iter().any(|cfg| cfg & Pmp::L != 0);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cpu::Xlen;
+    use crate::csr::Privilege;
+    use crate::pmp::{Pmp, PmpAccess};
+
+    #[test]
+    fn match_test() {
+        let mut pmp: Pmp = Pmp::new();
+        // No entry: M-mode can access everything, S-mode nothing
+        assert!(pmp.allows(0x8000_0000, 4, PmpAccess::Write, Privilege::Machine));
+        assert!(!pmp.allows(0x8000_0000, 4, PmpAccess::Read, Privilege::Supervisor));
+
+        // Entry 0: read-only NAPOT 0x1000-0x1fff, entry 1: RWX TOR up to 0x4000
+        pmp.write_addr(0, (0x1000 >> 2) | 0x1ff, Xlen::Rv64);
+        pmp.write_addr(1, 0x4000 >> 2, Xlen::Rv64);
+        pmp.write_cfg(0, 0x0f19, Xlen::Rv64).unwrap();
+        assert_eq!(pmp.read_cfg(0, Xlen::Rv64), Some(0x0f19));
+        assert!(pmp.read_cfg(1, Xlen::Rv64).is_none());
+        assert!(pmp.allows(0x1ffc, 4, PmpAccess::Read, Privilege::Supervisor));
+        assert!(!pmp.allows(0x1ffc, 4, PmpAccess::Write, Privilege::Supervisor));
+        assert!(pmp.allows(0x1ffc, 4, PmpAccess::Write, Privilege::Machine));
+        // TOR starts from pmpaddr0 (0x17fc), below there is no entry
+        assert!(pmp.allows(0x2000, 8, PmpAccess::Execute, Privilege::Supervisor));
+        assert!(!pmp.allows(0x0ff8, 8, PmpAccess::Write, Privilege::Supervisor));
+        // Across the end of entry 0, and past the last entry
+        assert!(!pmp.allows(0x1ffc, 8, PmpAccess::Read, Privilege::Supervisor));
+        assert!(!pmp.allows(0x4000, 4, PmpAccess::Read, Privilege::Supervisor));
+
+        // Reserved W without R
+        pmp.write_cfg(2, 0x1a, Xlen::Rv64).unwrap();
+        assert_eq!(pmp.read_cfg(2, Xlen::Rv64), Some(0x18));
+    }
+
+    #[test]
+    fn lock_test() {
+        let mut pmp: Pmp = Pmp::new();
+        // Entry 1 locked, TOR, no permission: M-mode is checked as well
+        pmp.write_addr(0, 0x1000 >> 2, Xlen::Rv32);
+        pmp.write_addr(1, 0x2000 >> 2, Xlen::Rv32);
+        pmp.write_cfg(0, 0x8800, Xlen::Rv32).unwrap();
+        assert!(!pmp.allows(0x1000, 4, PmpAccess::Read, Privilege::Machine));
+        assert!(pmp.allows(0x3000, 4, PmpAccess::Read, Privilege::Machine));
+        // Neither the entry nor the bottom of its range can be changed
+        pmp.write_cfg(0, 0, Xlen::Rv32).unwrap();
+        pmp.write_addr(0, 0, Xlen::Rv32);
+        pmp.write_addr(1, 0, Xlen::Rv32);
+        assert_eq!(pmp.read_cfg(0, Xlen::Rv32), Some(0x8800));
+        assert_eq!(pmp.read_addr(0), 0x1000 >> 2);
+        assert_eq!(pmp.read_addr(1), 0x2000 >> 2);
+    }
+}
@@ -0,0 +1,341 @@
+use std::collections::{BTreeMap, HashMap};
+use crate::cpu::Instruction;
+use crate::disas;
+use crate::rv::OpCodes;
+use crate::symbols::SymbolMap;
+
+// A call that has not returned yet, with the number of instructions that
+// had been executed when it was made
+#[derive(Clone)]
+struct ProfiledCall {
+    call_pc: u64,
+    function: u64,
+    return_address: u64,
+    start: u64,
+    // The function was already running when it was called (recursion)
+    recursive: bool
+}
+
+/// Calls made by a call instruction to a function, and the instructions
+/// executed by them (up to their return)
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug)]
+pub struct CallCost {
+    pub count: u64,
+    pub instructions: u64
+}
+
+/// Instructions executed by a function of the program: by the function
+/// itself and by the function together with everything it called
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct FunctionCost {
+    pub name: String,
+    pub self_instructions: u64,
+    pub cumulative: u64,
+    pub calls: u64
+}
+
+/// What the program spent its instructions on, collected with --profile:
+/// how many times each mnemonic was executed, how often the conditional
+/// branches were taken, the loads and stores, the hottest PCs and the
+/// instructions spent in each function, through its calls and returns
+#[derive(Clone, Default)]
+pub struct InstructionProfile {
+    // Executions of each instruction word, the mnemonics are only
+    // decoded when the profile is read
+    words: HashMap<Instruction, u64>,
+    // Executions of the instruction at each PC
+    pcs: HashMap<u64, u64>,
+    branches_taken: u64,
+    branches_not_taken: u64,
+    instructions: u64,
+    // Calls that have not returned yet, from the outermost
+    calls: Vec<ProfiledCall>,
+    // Number of calls of each function that have not returned yet
+    active: HashMap<u64, u32>,
+    // Cost of the calls from each call instruction to each function
+    call_costs: HashMap<(u64, u64), CallCost>,
+    // Instructions executed by each function and its callees, without
+    // counting twice the calls made while it was already running
+    cumulative: HashMap<u64, u64>
+}
+
+impl InstructionProfile {
+    // Deepest call chain that is followed, as in CallStack
+    const MAX_DEPTH: usize = 4096;
+
+    pub fn new() -> InstructionProfile {
+        InstructionProfile::default()
+    }
+
+    /// Account for the instruction at pc that has just been executed,
+    /// taken tells if it changed the flow of execution
+    pub fn record(&mut self, pc: u64, instr: Instruction, taken: bool) {
+        *self.words.entry(instr).or_insert(0) += 1;
+        *self.pcs.entry(pc).or_insert(0) += 1;
+        self.instructions += 1;
+        if (instr & 0x7f) as u8 == OpCodes::BTYPE {
+            if taken {
+                self.branches_taken += 1;
+            } else {
+                self.branches_not_taken += 1;
+            }
+        }
+    }
+
+    /// Number of instructions that have been profiled
+    pub fn total(&self) -> u64 {
+        self.instructions
+    }
+
+    /// The call instruction at pc (not recorded yet) jumps to function,
+    /// returning to return_address
+    pub fn record_call(&mut self, pc: u64, function: u64, return_address: u64) {
+        if self.calls.len() == InstructionProfile::MAX_DEPTH {
+            let outermost: ProfiledCall = self.calls.remove(0);
+            self.end_call(outermost, self.instructions);
+        }
+        let active: &mut u32 = self.active.entry(function).or_insert(0);
+        *active += 1;
+        self.call_costs.entry((pc, function)).or_default().count += 1;
+        self.calls.push(ProfiledCall {
+            call_pc: pc,
+            function,
+            return_address,
+            start: self.instructions + 1,
+            recursive: *active > 1
+        });
+    }
+
+    /// The return instruction (not recorded yet) jumps to target. The calls
+    /// are ended up to the one that returns to target, as in CallStack
+    pub fn record_return(&mut self, target: u64) {
+        if let Some(position) = self.calls.iter().rposition(|call| call.return_address == target) {
+            // The return instruction belongs to the function that returns
+            let end: u64 = self.instructions + 1;
+            while self.calls.len() > position {
+                let call: ProfiledCall = self.calls.pop().expect("the calls are more than position");
+                self.end_call(call, end);
+            }
+        }
+    }
+
+    fn end_call(&mut self, call: ProfiledCall, end: u64) {
+        let instructions: u64 = end - call.start;
+        self.call_costs.entry((call.call_pc, call.function)).or_default().instructions += instructions;
+        if !call.recursive {
+            *self.cumulative.entry(call.function).or_insert(0) += instructions;
+        }
+        if let Some(active) = self.active.get_mut(&call.function) {
+            *active -= 1;
+        }
+    }
+
+    /// Cost of the calls from each call instruction to each function,
+    /// the calls that have not returned yet cost what they executed so far
+    pub fn call_costs(&self) -> HashMap<(u64, u64), CallCost> {
+        let mut call_costs: HashMap<(u64, u64), CallCost> = self.call_costs.clone();
+        for call in &self.calls {
+            call_costs.entry((call.call_pc, call.function)).or_default().instructions += self.instructions.saturating_sub(call.start);
+        }
+        call_costs
+    }
+
+    /// Flat profile of the functions of the program, found with the
+    /// symbols, in decreasing order of the instructions they executed
+    /// themselves. The cumulative count of a function that was never
+    /// called (e.g. the entry point) is its own plus that of its calls
+    pub fn functions(&self, symbols: &SymbolMap) -> Vec<FunctionCost> {
+        fn function(functions: &mut HashMap<String, FunctionCost>, name: String) -> &mut FunctionCost {
+            functions.entry(name.clone())
+                .or_insert(FunctionCost { name, self_instructions: 0, cumulative: 0, calls: 0 })
+        }
+        let mut functions: HashMap<String, FunctionCost> = HashMap::new();
+        for (pc, count) in &self.pcs {
+            function(&mut functions, function_name(symbols, *pc)).self_instructions += count;
+        }
+        let mut cumulative: HashMap<u64, u64> = self.cumulative.clone();
+        for call in self.calls.iter().filter(|call| !call.recursive) {
+            *cumulative.entry(call.function).or_insert(0) += self.instructions.saturating_sub(call.start);
+        }
+        for (address, instructions) in cumulative {
+            function(&mut functions, function_name(symbols, address)).cumulative += instructions;
+        }
+        let mut outgoing: HashMap<String, u64> = HashMap::new();
+        for ((call_pc, address), cost) in self.call_costs() {
+            let (caller, callee): (String, String) = (function_name(symbols, call_pc), function_name(symbols, address));
+            function(&mut functions, callee.clone()).calls += cost.count;
+            if caller != callee {
+                *outgoing.entry(caller).or_insert(0) += cost.instructions;
+            }
+        }
+        let mut functions: Vec<FunctionCost> = functions.into_values().collect();
+        for function in functions.iter_mut().filter(|function| function.calls == 0) {
+            function.cumulative = function.self_instructions + outgoing.get(&function.name).copied().unwrap_or(0);
+        }
+        functions.sort_by(|a, b| b.self_instructions.cmp(&a.self_instructions).then(a.name.cmp(&b.name)));
+        functions
+    }
+
+    /// The profile in the callgrind format of valgrind, to be opened with
+    /// KCachegrind: the instructions executed at each PC and the calls,
+    /// grouped by function
+    pub fn to_callgrind(&self, symbols: &SymbolMap) -> String {
+        // Costs of each function, ordered by name, and the calls they made
+        // (the call instructions have a cost, so their function is there)
+        let mut functions: BTreeMap<String, Vec<(u64, u64)>> = BTreeMap::new();
+        for (pc, count) in &self.pcs {
+            functions.entry(function_name(symbols, *pc)).or_default().push((*pc, *count));
+        }
+        let mut calls_by_function: HashMap<String, Vec<(u64, u64, CallCost)>> = HashMap::new();
+        for ((call_pc, address), cost) in self.call_costs() {
+            calls_by_function.entry(function_name(symbols, call_pc)).or_default().push((call_pc, address, cost));
+        }
+        let mut callgrind: String = format!("# callgrind format\nversion: 1\ncreator: riviera\npositions: instr\n\
+                                             events: Instructions\nsummary: {}\n", self.instructions);
+        for (name, mut pcs) in functions {
+            let mut calls: Vec<(u64, u64, CallCost)> = calls_by_function.remove(&name).unwrap_or_default();
+            pcs.sort();
+            calls.sort_by_key(|(call_pc, address, _)| (*call_pc, *address));
+            callgrind += &format!("\nfn={}\n", name);
+            for (pc, count) in pcs {
+                callgrind += &format!("0x{:x} {}\n", pc, count);
+            }
+            for (call_pc, address, cost) in calls {
+                callgrind += &format!("cfn={}\ncalls={} 0x{:x}\n0x{:x} {}\n",
+                                      function_name(symbols, address), cost.count, address, call_pc, cost.instructions);
+            }
+        }
+        callgrind
+    }
+
+    /// Save the profile in the callgrind format
+    pub fn write_callgrind(&self, filename: &str, symbols: &SymbolMap) -> Result<(), String> {
+        std::fs::write(filename, self.to_callgrind(symbols))
+            .map_err(|why| format!("Could not write {}: {}", filename, why))
+    }
+
+    /// Executions of each mnemonic, in decreasing order
+    pub fn mnemonics(&self) -> Vec<(String, u64)> {
+        let mut counts: HashMap<String, u64> = HashMap::new();
+        for (instr, count) in &self.words {
+            let text: String = disas::disassemble(*instr, 0);
+            let mnemonic: &str = text.split(' ').next().unwrap_or_default();
+            *counts.entry(mnemonic.to_string()).or_insert(0) += count;
+        }
+        let mut mnemonics: Vec<(String, u64)> = counts.into_iter().collect();
+        mnemonics.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        mnemonics
+    }
+
+    /// Conditional branches that were taken and not taken
+    pub fn branches(&self) -> (u64, u64) {
+        (self.branches_taken, self.branches_not_taken)
+    }
+
+    /// Number of executed loads
+    pub fn loads(&self) -> u64 {
+        self.count_opcode(OpCodes::LOAD)
+    }
+
+    /// Number of executed stores
+    pub fn stores(&self) -> u64 {
+        self.count_opcode(OpCodes::STYPE)
+    }
+
+    fn count_opcode(&self, opcode: u8) -> u64 {
+        self.words.iter()
+            .filter(|(instr, _)| (**instr & 0x7f) as u8 == opcode)
+            .map(|(_, count)| count)
+            .sum()
+    }
+
+    /// The n most executed PCs with their number of executions, in
+    /// decreasing order
+    pub fn top_pcs(&self, n: usize) -> Vec<(u64, u64)> {
+        let mut pcs: Vec<(u64, u64)> = self.pcs.iter().map(|(pc, count)| (*pc, *count)).collect();
+        pcs.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        pcs.truncate(n);
+        pcs
+    }
+}
+
+// Name of the function that contains an address
+fn function_name(symbols: &SymbolMap, address: u64) -> String {
+    match symbols.lookup(address) {
+        Some((name, _)) => name.to_string(),
+        None => "<unknown>".to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::profile::{FunctionCost, InstructionProfile};
+    use crate::symbols::SymbolMap;
+
+    #[test]
+    fn profile_test() {
+        const ADDI: u32 = 0x00150513;
+        const LD: u32 = 0x02813083;
+        const SD: u32 = 0x00113423;
+        const BNE: u32 = 0xfe051ee3;
+        let mut profile: InstructionProfile = InstructionProfile::new();
+        // A loop of three iterations
+        for i in 0..3 {
+            profile.record(0x100, ADDI, false);
+            profile.record(0x104, LD, false);
+            profile.record(0x108, SD, false);
+            profile.record(0x10c, BNE, i < 2);
+        }
+        profile.record(0x110, ADDI, false);
+        assert_eq!(profile.total(), 13);
+        assert_eq!(profile.mnemonics()[0], ("addi".to_string(), 4));
+        assert_eq!(profile.mnemonics().len(), 4);
+        assert_eq!(profile.branches(), (2, 1));
+        assert_eq!((profile.loads(), profile.stores()), (3, 3));
+        assert_eq!(profile.top_pcs(2), vec![(0x100, 3), (0x104, 3)]);
+    }
+
+    #[test]
+    fn function_profile_test() {
+        const ADDI: u32 = 0x00150513;
+        const JAL: u32 = 0x008000ef;
+        const RET: u32 = 0x00008067;
+        let symbols: SymbolMap = SymbolMap::parse("0000000000000100 T main\n0000000000000200 T leaf\n\
+                                                   0000000000000300 T fact\n");
+        let mut profile: InstructionProfile = InstructionProfile::new();
+        let call = |profile: &mut InstructionProfile, pc: u64, function: u64| {
+            profile.record_call(pc, function, pc + 4);
+            profile.record(pc, JAL, true);
+        };
+        let ret = |profile: &mut InstructionProfile, pc: u64, target: u64| {
+            profile.record_return(target);
+            profile.record(pc, RET, true);
+        };
+        // main calls leaf twice, leaf executes 3 instructions
+        for pc in [0x100, 0x108] {
+            call(&mut profile, pc, 0x200);
+            profile.record(0x200, ADDI, false);
+            profile.record(0x204, ADDI, false);
+            ret(&mut profile, 0x208, pc + 4);
+        }
+        // main calls fact, which calls itself once
+        call(&mut profile, 0x110, 0x300);
+        call(&mut profile, 0x300, 0x300);
+        ret(&mut profile, 0x304, 0x304);
+        ret(&mut profile, 0x304, 0x114);
+        // main is still running
+        profile.record(0x114, ADDI, false);
+        let functions: Vec<FunctionCost> = profile.functions(&symbols);
+        let function = |name: &str| functions.iter().find(|function| function.name == name).unwrap().clone();
+        assert_eq!(functions[0].name, "leaf");
+        assert_eq!(function("leaf"), FunctionCost { name: "leaf".to_string(), self_instructions: 6, cumulative: 6, calls: 2 });
+        assert_eq!(function("fact"), FunctionCost { name: "fact".to_string(), self_instructions: 3, cumulative: 3, calls: 2 });
+        assert_eq!(function("main"), FunctionCost { name: "main".to_string(), self_instructions: 4, cumulative: 13, calls: 0 });
+        assert_eq!(profile.total(), 13);
+
+        let callgrind: String = profile.to_callgrind(&symbols);
+        assert!(callgrind.contains("summary: 13\n"));
+        assert!(callgrind.contains("fn=main\n0x100 1\n0x108 1\n0x110 1\n0x114 1\ncfn=leaf\ncalls=1 0x200\n0x100 3\n"));
+        assert!(callgrind.contains("fn=fact\n0x300 1\n0x304 2\ncfn=fact\ncalls=1 0x300\n0x300 1\n"));
+    }
+}
@@ -0,0 +1,266 @@
+use std::collections::HashMap;
+
+/// Model of a branch predictor. It is asked for a prediction before each
+/// conditional branch is resolved and then told the actual outcome
+pub trait BranchPredictor: Send {
+    /// Name of the predictor, used in messages
+    fn name(&self) -> &str;
+
+    /// Predict if the branch at pc, jumping offset bytes away, is taken
+    fn predict(&self, pc: u64, offset: i64) -> bool;
+
+    /// Learn the outcome of the branch at pc
+    fn update(&mut self, pc: u64, taken: bool);
+
+    /// Copy of the predictor, the emulator is cloned to take checkpoints
+    fn clone_predictor(&self) -> Box<dyn BranchPredictor>;
+}
+
+impl Clone for Box<dyn BranchPredictor> {
+    fn clone(&self) -> Box<dyn BranchPredictor> {
+        self.clone_predictor()
+    }
+}
+
+/// Static prediction: backward branches (loops) are taken, forward
+/// branches are not
+#[derive(Clone)]
+pub struct StaticPredictor;
+
+impl BranchPredictor for StaticPredictor {
+    fn name(&self) -> &str {
+        "static"
+    }
+
+    fn predict(&self, _pc: u64, offset: i64) -> bool {
+        offset < 0
+    }
+
+    fn update(&mut self, _pc: u64, _taken: bool) {}
+
+    fn clone_predictor(&self) -> Box<dyn BranchPredictor> {
+        Box::new(self.clone())
+    }
+}
+
+// Table of 2-bit saturating counters: 0 and 1 predict not taken, 2 and 3
+// predict taken. They start weakly not taken
+#[derive(Clone)]
+struct CounterTable {
+    counters: Vec<u8>
+}
+
+impl CounterTable {
+    fn new(bits: u32) -> CounterTable {
+        CounterTable { counters: vec![1; 1 << bits] }
+    }
+
+    fn mask(&self) -> u64 {
+        self.counters.len() as u64 - 1
+    }
+
+    fn predict(&self, index: u64) -> bool {
+        self.counters[(index & self.mask()) as usize] >= 2
+    }
+
+    fn update(&mut self, index: u64, taken: bool) {
+        let slot: usize = (index & self.mask()) as usize;
+        let counter: &mut u8 = &mut self.counters[slot];
+        *counter = if taken { (*counter + 1).min(3) } else { counter.saturating_sub(1) };
+    }
+}
+
+/// Bimodal predictor: a 2-bit counter for each branch, selected by the
+/// low bits of its PC
+#[derive(Clone)]
+pub struct BimodalPredictor {
+    table: CounterTable
+}
+
+impl BimodalPredictor {
+    pub fn new(bits: u32) -> BimodalPredictor {
+        BimodalPredictor { table: CounterTable::new(bits) }
+    }
+}
+
+impl BranchPredictor for BimodalPredictor {
+    fn name(&self) -> &str {
+        "bimodal"
+    }
+
+    fn predict(&self, pc: u64, _offset: i64) -> bool {
+        self.table.predict(pc >> 2)
+    }
+
+    fn update(&mut self, pc: u64, taken: bool) {
+        self.table.update(pc >> 2, taken);
+    }
+
+    fn clone_predictor(&self) -> Box<dyn BranchPredictor> {
+        Box::new(self.clone())
+    }
+}
+
+/// Gshare predictor: the 2-bit counters are selected by the PC xored
+/// with the outcomes of the last branches (global history)
+#[derive(Clone)]
+pub struct GsharePredictor {
+    table: CounterTable,
+    history: u64
+}
+
+impl GsharePredictor {
+    pub fn new(bits: u32) -> GsharePredictor {
+        GsharePredictor { table: CounterTable::new(bits), history: 0 }
+    }
+}
+
+impl BranchPredictor for GsharePredictor {
+    fn name(&self) -> &str {
+        "gshare"
+    }
+
+    fn predict(&self, pc: u64, _offset: i64) -> bool {
+        self.table.predict((pc >> 2) ^ self.history)
+    }
+
+    fn update(&mut self, pc: u64, taken: bool) {
+        self.table.update((pc >> 2) ^ self.history, taken);
+        self.history = ((self.history << 1) | taken as u64) & self.table.mask();
+    }
+
+    fn clone_predictor(&self) -> Box<dyn BranchPredictor> {
+        Box::new(self.clone())
+    }
+}
+
+/// Branch predictors that can be chosen from the command line
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum PredictorKind {
+    Static,
+    Bimodal,
+    Gshare
+}
+
+impl PredictorKind {
+    // Number of index bits of the tables of counters (4096 entries)
+    const TABLE_BITS: u32 = 12;
+
+    /// Parse a branch predictor: "static", "bimodal" or "gshare"
+    pub fn parse(kind: &str) -> Result<PredictorKind, String> {
+        match kind.trim() {
+            "static" => Ok(PredictorKind::Static),
+            "bimodal" => Ok(PredictorKind::Bimodal),
+            "gshare" => Ok(PredictorKind::Gshare),
+            kind => Err(format!("Invalid branch predictor {}: expected static, bimodal or gshare", kind))
+        }
+    }
+
+    /// Create a predictor of this kind
+    pub fn create(&self) -> Box<dyn BranchPredictor> {
+        match self {
+            PredictorKind::Static => Box::new(StaticPredictor),
+            PredictorKind::Bimodal => Box::new(BimodalPredictor::new(PredictorKind::TABLE_BITS)),
+            PredictorKind::Gshare => Box::new(GsharePredictor::new(PredictorKind::TABLE_BITS))
+        }
+    }
+}
+
+/// How a static branch (the branch at a PC) behaved and how well it
+/// was predicted
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub struct BranchRecord {
+    pub executed: u64,
+    pub taken: u64,
+    pub mispredicted: u64
+}
+
+impl BranchRecord {
+    /// Correct predictions in percent, 100 if the branch was never executed
+    pub fn accuracy(&self) -> f64 {
+        if self.executed == 0 {
+            100.0
+        } else {
+            (self.executed - self.mispredicted) as f64 * 100.0 / self.executed as f64
+        }
+    }
+}
+
+/// Predictions of a branch predictor on the conditional branches
+/// executed by the program, recorded per static branch
+#[derive(Clone)]
+pub struct BranchProfile {
+    predictor: Box<dyn BranchPredictor>,
+    branches: HashMap<u64, BranchRecord>
+}
+
+impl BranchProfile {
+    pub fn new(predictor: Box<dyn BranchPredictor>) -> BranchProfile {
+        BranchProfile { predictor, branches: HashMap::new() }
+    }
+
+    pub fn get_predictor_name(&self) -> &str {
+        self.predictor.name()
+    }
+
+    /// The branch at pc, jumping offset bytes away, has been resolved
+    pub fn resolve(&mut self, pc: u64, offset: i64, taken: bool) {
+        let predicted: bool = self.predictor.predict(pc, offset);
+        self.predictor.update(pc, taken);
+        let record: &mut BranchRecord = self.branches.entry(pc).or_default();
+        record.executed += 1;
+        record.taken += taken as u64;
+        record.mispredicted += (predicted != taken) as u64;
+    }
+
+    /// All the executed branches together
+    pub fn total(&self) -> BranchRecord {
+        self.branches.values().fold(BranchRecord::default(), |total, record| BranchRecord {
+            executed: total.executed + record.executed,
+            taken: total.taken + record.taken,
+            mispredicted: total.mispredicted + record.mispredicted
+        })
+    }
+
+    /// The executed branches, the most mispredicted first
+    pub fn branches(&self) -> Vec<(u64, BranchRecord)> {
+        let mut branches: Vec<(u64, BranchRecord)> = self.branches.iter().map(|(pc, record)| (*pc, *record)).collect();
+        branches.sort_by(|a, b| b.1.mispredicted.cmp(&a.1.mispredicted).then(a.0.cmp(&b.0)));
+        branches
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::profiling::{BranchProfile, BranchRecord, PredictorKind};
+
+    // A loop branch taken 9 times and then not taken, run three times,
+    // with a forward branch inside it that alternates
+    fn run(kind: PredictorKind) -> BranchProfile {
+        let mut profile: BranchProfile = BranchProfile::new(kind.create());
+        for _ in 0..3 {
+            for i in 0..10 {
+                profile.resolve(0x100, 0x10, i % 2 == 0);
+                profile.resolve(0x120, -0x20, i < 9);
+            }
+        }
+        profile
+    }
+
+    #[test]
+    fn predictor_test() {
+        assert_eq!(PredictorKind::parse("gshare"), Ok(PredictorKind::Gshare));
+        assert!(PredictorKind::parse("tage").is_err());
+
+        let profile: BranchProfile = run(PredictorKind::Static);
+        assert_eq!(profile.total(), BranchRecord { executed: 60, taken: 42, mispredicted: 18 });
+        assert_eq!(profile.branches()[0], (0x100, BranchRecord { executed: 30, taken: 15, mispredicted: 15 }));
+
+        // The loop branch is learned, the alternating one only with history
+        let bimodal: BranchProfile = run(PredictorKind::Bimodal);
+        assert_eq!(bimodal.branches()[1].0, 0x120);
+        assert!(bimodal.branches()[1].1.accuracy() > 80.0);
+        let gshare: BranchProfile = run(PredictorKind::Gshare);
+        assert!(gshare.total().mispredicted < bimodal.total().mispredicted);
+    }
+}
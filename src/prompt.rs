@@ -0,0 +1,167 @@
+use std::io::Write;
+#[cfg(not(target_arch = "wasm32"))]
+use rustyline::{Context, Editor, Helper};
+#[cfg(not(target_arch = "wasm32"))]
+use rustyline::completion::Completer;
+#[cfg(not(target_arch = "wasm32"))]
+use rustyline::error::ReadlineError;
+#[cfg(not(target_arch = "wasm32"))]
+use rustyline::highlight::Highlighter;
+#[cfg(not(target_arch = "wasm32"))]
+use rustyline::hint::Hinter;
+#[cfg(not(target_arch = "wasm32"))]
+use rustyline::history::DefaultHistory;
+#[cfg(not(target_arch = "wasm32"))]
+use rustyline::validate::Validator;
+
+/// Words the prompt completes with Tab: the commands as the first word
+/// of a line, the symbols of the program after it
+#[derive(Clone, Default)]
+pub struct Completion {
+    commands: Vec<String>,
+    symbols: Vec<String>
+}
+
+impl Completion {
+    pub fn new(commands: &[&str], symbols: &[&str]) -> Completion {
+        let mut symbols: Vec<String> = symbols.iter().map(|name| name.to_string()).collect();
+        symbols.sort();
+        symbols.dedup();
+        Completion { commands: commands.iter().map(|name| name.to_string()).collect(), symbols }
+    }
+
+    /// Start of the word that ends at pos in line, and the words it can
+    /// be completed with
+    pub fn candidates(&self, line: &str, pos: usize) -> (usize, Vec<String>) {
+        let start: usize = line[..pos].rfind(char::is_whitespace).map(|space| space + 1).unwrap_or(0);
+        let prefix: &str = &line[start..pos];
+        let words: &[String] = if line[..start].trim().is_empty() { &self.commands } else { &self.symbols };
+        (start, words.iter().filter(|word| word.starts_with(prefix)).cloned().collect())
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl Completer for Completion {
+    type Candidate = String;
+
+    fn complete(&self, line: &str, pos: usize, _: &Context<'_>) -> rustyline::Result<(usize, Vec<String>)> {
+        Ok(self.candidates(line, pos))
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl Hinter for Completion {
+    type Hint = String;
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl Highlighter for Completion {}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl Validator for Completion {}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl Helper for Completion {}
+
+/// Command line of the interactive mode. On a terminal the line can be
+/// edited, the previous commands are recalled with the arrow keys and
+/// searched with Ctrl-R, and Tab completes the commands and the symbols.
+/// As in gdb, an empty line repeats the previous command if it is one
+/// of the repeated ones (e.g. a step)
+pub struct Prompt {
+    #[cfg(not(target_arch = "wasm32"))]
+    editor: Option<Editor<Completion, DefaultHistory>>,
+    repeated: Vec<String>,
+    last: Option<String>
+}
+
+impl Prompt {
+    const PROMPT: &'static str = "> ";
+
+    /// Prompt that completes with completion and repeats the commands in
+    /// repeated (a command ending with / stands for all the commands that
+    /// start with it, e.g. x/ for x/4xw)
+    pub fn new(completion: Completion, repeated: &[&str]) -> Prompt {
+        // Without a terminal there is nothing to complete
+        #[cfg(target_arch = "wasm32")]
+        let _ = completion;
+        Prompt {
+            #[cfg(not(target_arch = "wasm32"))]
+            editor: Editor::new().ok().map(|mut editor: Editor<Completion, DefaultHistory>| {
+                editor.set_helper(Some(completion));
+                editor
+            }),
+            repeated: repeated.iter().map(|command| command.to_string()).collect(),
+            last: None
+        }
+    }
+
+    /// Read the next command, None at the end of the input (Ctrl-D)
+    pub fn read_command(&mut self) -> Option<String> {
+        let line: String = self.read_line()?;
+        Some(self.repeat(line))
+    }
+
+    // The command to run for a line: the previous one if the line is
+    // empty and it can be repeated
+    fn repeat(&mut self, line: String) -> String {
+        if line.trim().is_empty() {
+            return self.last.clone().unwrap_or(line);
+        }
+        let command: &str = line.split_whitespace().next().unwrap_or("");
+        let repeated: bool = self.repeated.iter().any(|repeated| match repeated.ends_with('/') {
+            true => command.starts_with(repeated.as_str()),
+            false => command == repeated
+        });
+        self.last = repeated.then(|| line.clone());
+        line
+    }
+
+    fn read_line(&mut self) -> Option<String> {
+        #[cfg(not(target_arch = "wasm32"))]
+        if let Some(editor) = self.editor.as_mut() {
+            loop {
+                match editor.readline(Prompt::PROMPT) {
+                    Ok(line) => {
+                        if !line.trim().is_empty() {
+                            let _ = editor.add_history_entry(line.as_str());
+                        }
+                        return Some(line);
+                    },
+                    // Ctrl-C drops the line being typed
+                    Err(ReadlineError::Interrupted) => continue,
+                    Err(_) => return None
+                }
+            }
+        }
+        print!("{}", Prompt::PROMPT);
+        let _ = std::io::stdout().flush();
+        let mut line: String = String::new();
+        match std::io::stdin().read_line(&mut line) {
+            Ok(0) | Err(_) => None,
+            Ok(_) => Some(line)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::prompt::{Completion, Prompt};
+
+    #[test]
+    fn prompt_test() {
+        let completion: Completion = Completion::new(&["disas", "display", "s"], &["main", "memcpy", "main"]);
+        assert_eq!(completion.candidates("dis", 3), (0, vec!["disas".to_string(), "display".to_string()]));
+        assert_eq!(completion.candidates("disas m", 7), (6, vec!["main".to_string(), "memcpy".to_string()]));
+        assert_eq!(completion.candidates("x mem 4", 5), (2, vec!["memcpy".to_string()]));
+
+        let mut prompt: Prompt = Prompt::new(completion, &["s", "x/"]);
+        assert_eq!(prompt.repeat("s 10".to_string()), "s 10");
+        assert_eq!(prompt.repeat("\n".to_string()), "s 10");
+        assert_eq!(prompt.repeat("x/4xw sp".to_string()), "x/4xw sp");
+        assert_eq!(prompt.repeat(String::new()), "x/4xw sp");
+        // Other commands are not repeated
+        assert_eq!(prompt.repeat("display a0".to_string()), "display a0");
+        assert_eq!(prompt.repeat(String::new()), "");
+    }
+}
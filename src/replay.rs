@@ -0,0 +1,273 @@
+use std::fmt;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, ErrorKind, Read, Write};
+use crate::cpu::{CSRegIndex, Cpu, Instruction, RegIndex, REG_FILE_NAMES};
+use crate::csr::CsrFile;
+use crate::disas;
+use crate::step::StepReport;
+
+/// What a single instruction did, as it is stored in a recording: enough
+/// to follow the architectural state (registers, memory, CSRs and PC)
+/// instruction by instruction
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct ReplayEntry {
+    pub pc: u64,
+    pub instruction: Option<Instruction>,
+    pub register_writes: Vec<(RegIndex, u64)>,
+    // Address, size in bytes and value
+    pub memory_writes: Vec<(u64, u8, u64)>,
+    pub csr_writes: Vec<(CSRegIndex, u64)>,
+    // Description of the trap raised by the instruction
+    pub trap: Option<String>,
+    pub next_pc: u64
+}
+
+impl ReplayEntry {
+    pub fn from_report(report: &StepReport) -> ReplayEntry {
+        ReplayEntry {
+            pc: report.pc,
+            instruction: report.instruction,
+            register_writes: report.register_writes.iter().map(|write| (write.register, write.new)).collect(),
+            memory_writes: report.memory_writes.iter().map(|write| (write.address, write.size.bytes() as u8, write.new)).collect(),
+            csr_writes: report.csr_writes.iter().map(|write| (write.csr, write.new)).collect(),
+            trap: report.trap.map(|trap| trap.to_string()),
+            next_pc: report.next_pc
+        }
+    }
+
+    fn write(&self, writer: &mut impl Write) -> std::io::Result<()> {
+        writer.write_all(&self.pc.to_le_bytes())?;
+        writer.write_all(&[self.instruction.is_some() as u8])?;
+        writer.write_all(&self.instruction.unwrap_or(0).to_le_bytes())?;
+        writer.write_all(&[self.register_writes.len() as u8])?;
+        for (register, value) in &self.register_writes {
+            writer.write_all(&[*register])?;
+            writer.write_all(&value.to_le_bytes())?;
+        }
+        writer.write_all(&[self.memory_writes.len() as u8])?;
+        for (address, size, value) in &self.memory_writes {
+            writer.write_all(&address.to_le_bytes())?;
+            writer.write_all(&[*size])?;
+            writer.write_all(&value.to_le_bytes())?;
+        }
+        writer.write_all(&[self.csr_writes.len() as u8])?;
+        for (csr, value) in &self.csr_writes {
+            writer.write_all(&csr.to_le_bytes())?;
+            writer.write_all(&value.to_le_bytes())?;
+        }
+        let trap: &str = self.trap.as_deref().unwrap_or("");
+        writer.write_all(&[self.trap.is_some() as u8])?;
+        writer.write_all(&(trap.len() as u16).to_le_bytes())?;
+        writer.write_all(trap.as_bytes())?;
+        writer.write_all(&self.next_pc.to_le_bytes())
+    }
+
+    // Read the next entry, None at the end of the recording
+    fn read(reader: &mut impl Read) -> std::io::Result<Option<ReplayEntry>> {
+        let pc: u64 = match read_u64(reader) {
+            Ok(pc) => pc,
+            Err(why) if why.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+            Err(why) => return Err(why)
+        };
+        let has_instruction: bool = read_u8(reader)? != 0;
+        let instruction: u32 = u32::from_le_bytes(read_array(reader)?);
+        let mut register_writes: Vec<(RegIndex, u64)> = Vec::new();
+        for _ in 0..read_u8(reader)? {
+            register_writes.push((read_u8(reader)?, read_u64(reader)?));
+        }
+        let mut memory_writes: Vec<(u64, u8, u64)> = Vec::new();
+        for _ in 0..read_u8(reader)? {
+            memory_writes.push((read_u64(reader)?, read_u8(reader)?, read_u64(reader)?));
+        }
+        let mut csr_writes: Vec<(CSRegIndex, u64)> = Vec::new();
+        for _ in 0..read_u8(reader)? {
+            csr_writes.push((u16::from_le_bytes(read_array(reader)?), read_u64(reader)?));
+        }
+        let has_trap: bool = read_u8(reader)? != 0;
+        let mut trap: Vec<u8> = vec![0; u16::from_le_bytes(read_array(reader)?) as usize];
+        reader.read_exact(&mut trap)?;
+        Ok(Some(ReplayEntry {
+            pc,
+            instruction: has_instruction.then_some(instruction),
+            register_writes,
+            memory_writes,
+            csr_writes,
+            trap: has_trap.then(|| String::from_utf8_lossy(&trap).into_owned()),
+            next_pc: read_u64(reader)?
+        }))
+    }
+}
+
+impl fmt::Display for ReplayEntry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "pc=0x{:0>16x}", self.pc)?;
+        if let Some(instruction) = self.instruction {
+            write!(f, " {:0>8x} {}", instruction, disas::disassemble(instruction, self.pc))?;
+        }
+        for (register, value) in &self.register_writes {
+            write!(f, " | {}=0x{:x}", REG_FILE_NAMES[*register as usize], value)?;
+        }
+        for (address, size, value) in &self.memory_writes {
+            write!(f, " | mem{}[0x{:x}]=0x{:x}", *size as u32 * 8, address, value)?;
+        }
+        for (csr, value) in &self.csr_writes {
+            write!(f, " | {}=0x{:x}", CsrFile::name(*csr), value)?;
+        }
+        if let Some(trap) = self.trap.as_deref() {
+            write!(f, " | trap: {}", trap)?;
+        }
+        write!(f, " | next pc=0x{:0>16x}", self.next_pc)
+    }
+}
+
+fn read_array<const N: usize>(reader: &mut impl Read) -> std::io::Result<[u8; N]> {
+    let mut bytes: [u8; N] = [0; N];
+    reader.read_exact(&mut bytes)?;
+    Ok(bytes)
+}
+
+fn read_u8(reader: &mut impl Read) -> std::io::Result<u8> {
+    Ok(read_array::<1>(reader)?[0])
+}
+
+fn read_u64(reader: &mut impl Read) -> std::io::Result<u64> {
+    Ok(u64::from_le_bytes(read_array(reader)?))
+}
+
+// Recordings start with a magic string and a version
+const MAGIC: &[u8; 8] = b"RVRECORD";
+const VERSION: u32 = 1;
+
+/// First instruction whose effects differ from the recording, with the
+/// architectural state of both runs before it was executed
+pub struct Divergence {
+    // Number of instructions that matched the recording
+    pub instruction_count: u64,
+    // None when the recording or the program ended first
+    pub recorded: Option<ReplayEntry>,
+    pub actual: Option<ReplayEntry>,
+    // Registers of the recorded run and of the replay (pc first, then x0-x31)
+    pub recorded_state: Vec<u64>,
+    pub actual_state: Vec<u64>
+}
+
+/// Result of a replay
+pub enum ReplayOutcome {
+    // All the recorded instructions were executed the same way
+    Matched(u64),
+    Diverged(Box<Divergence>)
+}
+
+// pc and registers of the CPU
+fn architectural_state(cpu: &Cpu) -> Vec<u64> {
+    std::iter::once(cpu.get_pc())
+        .chain((0..REG_FILE_NAMES.len()).map(|i| cpu.read_reg(i as RegIndex)))
+        .collect()
+}
+
+/// Execute the program one instruction at a time, writing what every
+/// instruction does to a recording, until the program is over or limit
+/// instructions have been executed. It returns the number of recorded instructions
+pub fn record(cpu: &mut Cpu, filename: &str, limit: Option<u64>) -> Result<u64, String> {
+    let file: File = File::create(filename).map_err(|why| format!("Could not create recording {}: {}", filename, why))?;
+    let mut writer: BufWriter<File> = BufWriter::new(file);
+    let error = |why: std::io::Error| format!("Could not write recording {}: {}", filename, why);
+    writer.write_all(MAGIC).map_err(error)?;
+    writer.write_all(&VERSION.to_le_bytes()).map_err(error)?;
+    let mut count: u64 = 0;
+    while !cpu.is_finished() && limit.is_none_or(|limit| count < limit) {
+        let report: StepReport = cpu.step_with_state();
+        ReplayEntry::from_report(&report).write(&mut writer).map_err(error)?;
+        count += 1;
+    }
+    writer.flush().map_err(error)?;
+    Ok(count)
+}
+
+/// Execute the program again and check every instruction against a
+/// recording taken from the same starting state, stopping at the first
+/// one that does something different
+pub fn replay(cpu: &mut Cpu, filename: &str) -> Result<ReplayOutcome, String> {
+    let file: File = File::open(filename).map_err(|why| format!("Could not open recording {}: {}", filename, why))?;
+    let mut reader: BufReader<File> = BufReader::new(file);
+    let error = |why: std::io::Error| format!("Could not read recording {}: {}", filename, why);
+    let header: [u8; 12] = read_array(&mut reader).map_err(error)?;
+    if &header[0..8] != MAGIC || header[8..12] != VERSION.to_le_bytes() {
+        return Err(format!("{} is not a recording made by this version of riviera", filename));
+    }
+
+    // The recorded state is followed through the writes of the recording
+    let mut recorded_state: Vec<u64> = architectural_state(cpu);
+    let mut count: u64 = 0;
+    loop {
+        let recorded: Option<ReplayEntry> = ReplayEntry::read(&mut reader).map_err(error)?;
+        if recorded.is_none() {
+            return Ok(ReplayOutcome::Matched(count));
+        }
+        let actual_state: Vec<u64> = architectural_state(cpu);
+        let actual: Option<ReplayEntry> = if cpu.is_finished() {
+            None
+        } else {
+            Some(ReplayEntry::from_report(&cpu.step_with_state()))
+        };
+        if actual != recorded {
+            return Ok(ReplayOutcome::Diverged(Box::new(Divergence {
+                instruction_count: count,
+                recorded,
+                actual,
+                recorded_state,
+                actual_state
+            })));
+        }
+        if let Some(entry) = recorded {
+            for (register, value) in entry.register_writes {
+                recorded_state[register as usize + 1] = value;
+            }
+            recorded_state[0] = entry.next_pc;
+        }
+        count += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cpu::Cpu;
+    use crate::memory::Memory;
+    use crate::replay::{self, ReplayOutcome};
+
+    fn cpu(program: &[u32]) -> Cpu {
+        let mut cpu: Cpu = Cpu::new(Some(Memory::DRAM_DEFAULT_SIZE));
+        let text: Vec<u8> = program.iter().flat_map(|instr| instr.to_le_bytes()).collect();
+        cpu.set_read_only_segment(0x0);
+        cpu.set_read_write_segment(0x1000);
+        cpu.store_from_buffer(&text, 0x0).unwrap();
+        cpu
+    }
+
+    #[test]
+    fn replay_test() {
+        let filename: String = std::env::temp_dir().join(format!("riviera-replay-{}.rec", std::process::id()))
+            .to_string_lossy().into_owned();
+        // lui t0, 1; li a0, 5; addi a0, a0, 1; sd a0, 256(t0); addi a0, a0, 1
+        let program: [u32; 5] = [0x000012b7, 0x00500513, 0x00150513, 0x10a2b023, 0x00150513];
+        assert_eq!(replay::record(&mut cpu(&program), &filename, Some(5)), Ok(5));
+        match replay::replay(&mut cpu(&program), &filename) {
+            Ok(ReplayOutcome::Matched(count)) => assert_eq!(count, 5),
+            _ => panic!("the replay should match the recording")
+        }
+
+        // The third instruction adds 2 instead of 1
+        let changed: [u32; 5] = [0x000012b7, 0x00500513, 0x00250513, 0x10a2b023, 0x00150513];
+        match replay::replay(&mut cpu(&changed), &filename) {
+            Ok(ReplayOutcome::Diverged(divergence)) => {
+                assert_eq!(divergence.instruction_count, 2);
+                assert_eq!(divergence.recorded.unwrap().register_writes, vec![(10, 6)]);
+                assert_eq!(divergence.actual.unwrap().register_writes, vec![(10, 7)]);
+                assert_eq!(divergence.recorded_state[11], 5);
+                assert_eq!(divergence.actual_state, divergence.recorded_state);
+            },
+            _ => panic!("the replay should diverge")
+        }
+        let _ = std::fs::remove_file(&filename);
+    }
+}
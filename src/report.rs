@@ -0,0 +1,138 @@
+use std::time::Duration;
+use crate::cpu::{Cpu, ExitReason, REG_FILE_NAMES};
+use crate::stats::quote;
+use crate::trap::{TrapEvent, TrapStatistics};
+
+/// Report of a run for scripts and CI jobs, saved with --json-report: how
+/// many instructions were executed and how fast, why the program stopped
+/// and with which exit status, the registers at the end and the traps
+/// raised (counted by cause, and the last ones in order). Values of
+/// registers and addresses are hexadecimal strings, since JSON numbers
+/// cannot hold every 64 bit value:
+///
+/// ```json
+/// {
+///   "instructions": 1200,
+///   "wall_time": 0.000512,
+///   "mips": 2.34375,
+///   "exit": { "reason": "exited", "code": 0, "status": 0, "description": "Program exited with code 0" },
+///   "pc": "0xfffffffffffffffe",
+///   "registers": { "zero": "0x0", "ra": "0xfffffffffffffffe", ... },
+///   "traps": [ { "cause": 11, "interrupt": false, "description": "environment call from M-mode", "count": 3,
+///                "pcs": [ { "pc": "0x80000010", "count": 3 } ] } ],
+///   "trap_history": [ { "number": 1, "cause": 11, "interrupt": false, "description": "environment call from M-mode",
+///                       "pc": "0x80000010", "tval": "0x0" } ]
+/// }
+/// ```
+pub struct RunReport {
+    json: String
+}
+
+impl RunReport {
+    /// Collect the report of the run of a Cpu, which stopped for exit_reason
+    /// after executing instr_count instructions in execution_time. The
+    /// exit status is the one riviera exits with, if any
+    pub fn collect(cpu: &Cpu, instr_count: u64, execution_time: Duration,
+                   exit_reason: ExitReason, exit_status: Option<i32>) -> RunReport {
+        let mips: f64 = (instr_count as f64 / 1e6) / execution_time.as_secs_f64();
+        let mut json: String = String::from("{\n");
+        json += &format!("  \"instructions\": {},\n", instr_count);
+        json += &format!("  \"wall_time\": {},\n", number(execution_time.as_secs_f64()));
+        json += &format!("  \"mips\": {},\n", number(mips));
+        json += &format!("  \"exit\": {},\n", exit(exit_reason, exit_status));
+        json += &format!("  \"pc\": {},\n", hex(cpu.get_pc()));
+
+        let mask: u64 = cpu.get_xlen().mask();
+        let registers: Vec<String> = REG_FILE_NAMES.iter().enumerate()
+            .map(|(i, name)| format!("{}: {}", quote(name), hex(cpu.read_reg(i as u8) & mask)))
+            .collect();
+        json += &format!("  \"registers\": {{ {} }},\n", registers.join(", "));
+
+        let statistics: &TrapStatistics = cpu.get_trap_statistics();
+        let traps: Vec<String> = statistics.causes().map(|(cause, stats)| {
+            let pcs: Vec<String> = stats.top_pcs(usize::MAX).into_iter()
+                .map(|(pc, count)| format!("{{ \"pc\": {}, \"count\": {} }}", hex(pc), count))
+                .collect();
+            format!("{{ \"cause\": {}, \"interrupt\": {}, \"description\": {}, \"count\": {}, \"pcs\": [{}] }}",
+                    cause & !TrapStatistics::INTERRUPT_BIT, cause & TrapStatistics::INTERRUPT_BIT != 0,
+                    quote(stats.description), stats.count, pcs.join(", "))
+        }).collect();
+        json += &format!("  \"traps\": {},\n", list(&traps));
+
+        let history: Vec<String> = statistics.history().map(event).collect();
+        json += &format!("  \"trap_history\": {}\n", list(&history));
+        json += "}\n";
+        RunReport { json }
+    }
+
+    pub fn to_json(&self) -> &str {
+        &self.json
+    }
+
+    pub fn write_to_file(&self, filename: &str) -> Result<(), String> {
+        std::fs::write(filename, &self.json)
+            .map_err(|why| format!("Could not write {}: {}", filename, why))
+    }
+}
+
+fn exit(exit_reason: ExitReason, exit_status: Option<i32>) -> String {
+    let (reason, code): (&str, Option<u64>) = match exit_reason {
+        ExitReason::Returned(value) => ("returned", Some(value)),
+        ExitReason::Exited(code) => ("exited", Some(code)),
+        ExitReason::Breakpoint(_) => ("breakpoint", None),
+        ExitReason::Trap(_) => ("trap", None),
+        ExitReason::InstructionLimit => ("instruction_limit", None),
+        ExitReason::Timeout => ("timeout", None)
+    };
+    let optional = |value: Option<String>| value.unwrap_or("null".to_string());
+    format!("{{ \"reason\": {}, \"code\": {}, \"status\": {}, \"description\": {} }}",
+            quote(reason), optional(code.map(|code| (code as i64).to_string())),
+            optional(exit_status.map(|status| status.to_string())), quote(&exit_reason.to_string()))
+}
+
+fn event(event: &TrapEvent) -> String {
+    format!("{{ \"number\": {}, \"cause\": {}, \"interrupt\": {}, \"description\": {}, \"pc\": {}, \"tval\": {} }}",
+            event.number, event.cause & !TrapStatistics::INTERRUPT_BIT, event.cause & TrapStatistics::INTERRUPT_BIT != 0,
+            quote(event.description), hex(event.pc), hex(event.tval))
+}
+
+// One element per line
+fn list(elements: &[String]) -> String {
+    if elements.is_empty() {
+        return "[]".to_string();
+    }
+    format!("[\n    {}\n  ]", elements.join(",\n    "))
+}
+
+fn hex(value: u64) -> String {
+    format!("\"0x{:x}\"", value)
+}
+
+// JSON has no infinity or NaN (e.g. the MIPS of a run too short to be timed)
+fn number(value: f64) -> String {
+    if value.is_finite() { value.to_string() } else { "null".to_string() }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use crate::cpu::{Cpu, ExitReason};
+    use crate::report::RunReport;
+
+    #[test]
+    fn report_test() {
+        let mut cpu: Cpu = Cpu::new(None);
+        cpu.write_reg(10, u64::MAX);
+        let report: RunReport = RunReport::collect(&cpu, 2_000_000, Duration::from_secs(1), ExitReason::Exited(3), Some(3));
+        let json: &str = report.to_json();
+        assert!(json.contains("\"instructions\": 2000000,\n"));
+        assert!(json.contains("\"mips\": 2,\n"));
+        assert!(json.contains("\"exit\": { \"reason\": \"exited\", \"code\": 3, \"status\": 3, \"description\": \"Program exited with code 3\" }"));
+        assert!(json.contains("\"a0\": \"0xffffffffffffffff\""));
+        assert!(json.contains("\"traps\": [],\n"));
+
+        let report: RunReport = RunReport::collect(&cpu, 0, Duration::ZERO, ExitReason::Timeout, Some(124));
+        assert!(report.to_json().contains("\"mips\": null,\n"));
+        assert!(report.to_json().contains("\"reason\": \"timeout\", \"code\": null, \"status\": 124"));
+    }
+}
@@ -0,0 +1,190 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use crate::clint::Clint;
+use crate::snapshot::{Snapshot, SnapshotReader, SnapshotWriter};
+
+/// Wall clock of the guest (what gettimeofday returns). By default it is
+/// the time of the host; once it has been set it is derived from mtime,
+/// so it follows the guest time: it stops when the time is frozen and
+/// it can be moved e.g. past the expiry date of a certificate
+#[derive(Clone, Default)]
+pub struct Rtc {
+    // Time (in microseconds since the epoch) at which the clock was set
+    // and value of mtime at that moment
+    base: Option<(u64, u64)>
+}
+
+impl Rtc {
+    pub fn new() -> Rtc {
+        Rtc { base: None }
+    }
+
+    /// Set the clock to time (since the epoch), mtime is the current guest time
+    pub fn set(&mut self, time: Duration, mtime: u64) {
+        self.base = Some((time.as_micros() as u64, mtime));
+    }
+
+    /// Go back to the time of the host
+    pub fn follow_host(&mut self) {
+        self.base = None;
+    }
+
+    /// Whether the clock is the one of the host
+    pub fn is_host(&self) -> bool {
+        self.base.is_none()
+    }
+
+    /// Current time since the epoch, mtime is the current guest time
+    pub fn now(&self, mtime: u64) -> Duration {
+        match self.base {
+            Some((base, base_mtime)) => {
+                let elapsed: u128 = mtime.wrapping_sub(base_mtime) as u128 * 1_000_000 / Clint::TIMEBASE_FREQUENCY as u128;
+                Duration::from_micros(base.wrapping_add(elapsed as u64))
+            },
+            None => SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default()
+        }
+    }
+}
+
+impl Snapshot for Rtc {
+    fn save(&self, writer: &mut SnapshotWriter) {
+        let (set, base, base_mtime) = match self.base {
+            Some((base, base_mtime)) => (1, base, base_mtime),
+            None => (0, 0, 0)
+        };
+        writer.put_u64(set);
+        writer.put_u64(base);
+        writer.put_u64(base_mtime);
+    }
+
+    fn restore(&mut self, reader: &mut SnapshotReader) -> Result<(), String> {
+        let set: u64 = reader.get_u64()?;
+        let base: u64 = reader.get_u64()?;
+        let base_mtime: u64 = reader.get_u64()?;
+        self.base = if set != 0 { Some((base, base_mtime)) } else { None };
+        Ok(())
+    }
+}
+
+/// Registers of the goldfish RTC (the real-time clock of the virt machine
+/// of QEMU, rtc-goldfish in Linux), through which the program reads and
+/// sets the wall clock in nanoseconds since the epoch. Reading TIME_LOW
+/// latches the high half of the time in TIME_HIGH, and the clock is set by
+/// writing TIME_HIGH and then TIME_LOW. Alarms can be programmed but they
+/// never fire
+#[derive(Clone, Default)]
+pub struct GoldfishRtc {
+    // High half of the time latched by the last read of TIME_LOW, or
+    // written by the program before it sets the time
+    time_high: u32,
+    alarm: u64,
+    alarm_running: bool,
+    irq_enabled: bool
+}
+
+impl GoldfishRtc {
+    pub const TIME_LOW:        u64 = 0x00;
+    pub const TIME_HIGH:       u64 = 0x04;
+    pub const ALARM_LOW:       u64 = 0x08;
+    pub const ALARM_HIGH:      u64 = 0x0c;
+    pub const IRQ_ENABLED:     u64 = 0x10;
+    pub const CLEAR_ALARM:     u64 = 0x14;
+    pub const ALARM_STATUS:    u64 = 0x18;
+    pub const CLEAR_INTERRUPT: u64 = 0x1c;
+
+    pub fn new() -> GoldfishRtc {
+        GoldfishRtc::default()
+    }
+
+    /// Read a register, now is the time of the wall clock of the guest
+    pub fn read(&mut self, offset: u64, now: Duration) -> Option<u64> {
+        if offset == GoldfishRtc::TIME_LOW {
+            self.time_high = (now.as_nanos() as u64 >> 32) as u32;
+        }
+        self.peek(offset, now)
+    }
+
+    /// Same as read, but TIME_HIGH is left as it is
+    pub fn peek(&self, offset: u64, now: Duration) -> Option<u64> {
+        match offset {
+            GoldfishRtc::TIME_LOW => Some(now.as_nanos() as u64 & 0xffff_ffff),
+            GoldfishRtc::TIME_HIGH => Some(self.time_high as u64),
+            GoldfishRtc::ALARM_LOW => Some(self.alarm & 0xffff_ffff),
+            GoldfishRtc::ALARM_HIGH => Some(self.alarm >> 32),
+            GoldfishRtc::IRQ_ENABLED => Some(self.irq_enabled as u64),
+            GoldfishRtc::ALARM_STATUS => Some(self.alarm_running as u64),
+            GoldfishRtc::CLEAR_ALARM | GoldfishRtc::CLEAR_INTERRUPT => Some(0),
+            _ => None
+        }
+    }
+
+    /// Write a register. It returns the new time of the wall clock when
+    /// the program sets it
+    pub fn write(&mut self, offset: u64, data: u64) -> Option<Option<Duration>> {
+        let data: u64 = data & 0xffff_ffff;
+        match offset {
+            GoldfishRtc::TIME_LOW => return Some(Some(Duration::from_nanos(((self.time_high as u64) << 32) | data))),
+            GoldfishRtc::TIME_HIGH => self.time_high = data as u32,
+            // Writing the low half arms the alarm
+            GoldfishRtc::ALARM_LOW => {
+                self.alarm = (self.alarm & !0xffff_ffff) | data;
+                self.alarm_running = true;
+            },
+            GoldfishRtc::ALARM_HIGH => self.alarm = (self.alarm & 0xffff_ffff) | (data << 32),
+            GoldfishRtc::IRQ_ENABLED => self.irq_enabled = data & 1 != 0,
+            GoldfishRtc::CLEAR_ALARM => self.alarm_running = false,
+            GoldfishRtc::CLEAR_INTERRUPT => (),
+            _ => return None
+        }
+        Some(None)
+    }
+}
+
+impl Snapshot for GoldfishRtc {
+    fn save(&self, writer: &mut SnapshotWriter) {
+        writer.put_u64(self.time_high as u64);
+        writer.put_u64(self.alarm);
+        writer.put_u64(self.alarm_running as u64);
+        writer.put_u64(self.irq_enabled as u64);
+    }
+
+    fn restore(&mut self, reader: &mut SnapshotReader) -> Result<(), String> {
+        self.time_high = reader.get_u64()? as u32;
+        self.alarm = reader.get_u64()?;
+        self.alarm_running = reader.get_u64()? != 0;
+        self.irq_enabled = reader.get_u64()? != 0;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use crate::clint::Clint;
+    use crate::rtc::{GoldfishRtc, Rtc};
+
+    #[test]
+    fn rtc_test() {
+        let mut rtc: Rtc = Rtc::new();
+        assert!(rtc.is_host());
+        rtc.set(Duration::from_secs(2_000_000_000), 500);
+        assert_eq!(rtc.now(500), Duration::from_secs(2_000_000_000));
+        assert_eq!(rtc.now(500 + 3 * Clint::TIMEBASE_FREQUENCY), Duration::from_secs(2_000_000_003));
+        rtc.follow_host();
+        assert!(rtc.now(0) < Duration::from_secs(2_000_000_000));
+    }
+    #[test]
+    fn goldfish_test() {
+        let mut goldfish: GoldfishRtc = GoldfishRtc::new();
+        let now: Duration = Duration::from_nanos(0x1234_5678_9abc_def0);
+        assert_eq!(goldfish.read(GoldfishRtc::TIME_LOW, now), Some(0x9abc_def0));
+        // The high half is the one of the time read through TIME_LOW
+        assert_eq!(goldfish.read(GoldfishRtc::TIME_HIGH, Duration::ZERO), Some(0x1234_5678));
+        assert_eq!(goldfish.write(GoldfishRtc::TIME_HIGH, 0x1), Some(None));
+        assert_eq!(goldfish.write(GoldfishRtc::TIME_LOW, 0x2), Some(Some(Duration::from_nanos(0x1_0000_0002))));
+        assert_eq!(goldfish.write(GoldfishRtc::ALARM_LOW, 0x10), Some(None));
+        assert_eq!(goldfish.peek(GoldfishRtc::ALARM_STATUS, now), Some(1));
+        assert_eq!(goldfish.write(GoldfishRtc::CLEAR_ALARM, 0x1), Some(None));
+        assert_eq!(goldfish.peek(GoldfishRtc::ALARM_STATUS, now), Some(0));
+        assert_eq!(goldfish.peek(0x20, now), None);
+    }
+}
@@ -1,9 +1,12 @@
 use crate::cpu::Instruction;
 use crate::cpu::RegIndex;
+use crate::cpu::CSRegIndex;
 use crate::cpu::Cpu;
-use crate::cpu::REG_FILE_NAMES;
+use crate::cpu::Xlen;
+use crate::csr::Privilege;
 use crate::memory::AccessSize;
-use colored::Colorize;
+use crate::trap::Trap;
+use crate::rvfd;
 
 #[derive(PartialEq, Eq)]
 pub struct DecInstruction {
@@ -12,231 +15,381 @@ pub struct DecInstruction {
     f7: u8
 }
 
-struct OpCodes;
+pub(crate) struct OpCodes;
 impl OpCodes {
     // RV32I
-    const RTYPE:   u8 = 0b0110011;
-    const ITYPE:   u8 = 0b0010011;
-    const STYPE:   u8 = 0b0100011;
-    const BTYPE:   u8 = 0b1100011;
-    const LOAD:    u8 = 0b0000011;
-    const LUI:     u8 = 0b0110111;
-    const AUIPC:   u8 = 0b0010111;
-    const JAL:     u8 = 0b1101111;
-    const JALR:    u8 = 0b1100111;
-    const FENCE:   u8 = 0b0001111;
-    const EXCEP:   u8 = 0b1110011;
+    pub(crate) const RTYPE:   u8 = 0b0110011;
+    pub(crate) const ITYPE:   u8 = 0b0010011;
+    pub(crate) const STYPE:   u8 = 0b0100011;
+    pub(crate) const BTYPE:   u8 = 0b1100011;
+    pub(crate) const LOAD:    u8 = 0b0000011;
+    pub(crate) const LUI:     u8 = 0b0110111;
+    pub(crate) const AUIPC:   u8 = 0b0010111;
+    pub(crate) const JAL:     u8 = 0b1101111;
+    pub(crate) const JALR:    u8 = 0b1100111;
+    pub(crate) const FENCE:   u8 = 0b0001111;
+    pub(crate) const EXCEP:   u8 = 0b1110011;
     // RV64I
-    const RTYPE64: u8 = 0b0111011;
-    const ITYPE64: u8 = 0b0011011;
+    pub(crate) const RTYPE64: u8 = 0b0111011;
+    pub(crate) const ITYPE64: u8 = 0b0011011;
+    // F and D extensions
+    pub(crate) const LOAD_FP:  u8 = 0b0000111;
+    pub(crate) const STORE_FP: u8 = 0b0100111;
+    pub(crate) const MADD:     u8 = 0b1000011;
+    pub(crate) const MSUB:     u8 = 0b1000111;
+    pub(crate) const NMSUB:    u8 = 0b1001011;
+    pub(crate) const NMADD:    u8 = 0b1001111;
+    pub(crate) const OP_FP:    u8 = 0b1010011;
 }
 
-pub fn decode(instr: Instruction, curcpu: &mut Cpu) {
+/// Fields of an instruction, extracted once so that an instruction that
+/// has already been decoded can be executed again without looking at its
+/// encoding (see icache.rs)
+#[derive(Clone, Copy, Default)]
+pub struct Operands {
+    pub(crate) rd: RegIndex,
+    pub(crate) rs1: RegIndex,
+    pub(crate) rs2: RegIndex,
+    // 5 bits long immediate takes the place of rd instr[11:7]
+    pub(crate) imm5: u32,
+    // 12 bits long immediate is instr[31:20]
+    pub(crate) imm12: u32,
+    // 20 bits long immediate is instr[31:12]
+    pub(crate) imm20: u32
+}
+
+impl Operands {
+    #[inline(always)]
+    pub fn new(instr: Instruction) -> Operands {
+        Operands {
+            // rd = instr[11:7]
+            rd: ((instr >> 7) & 0x1f) as RegIndex,
+            // rs1 = instr[19:15]
+            rs1: ((instr >> 15) & 0x1f) as RegIndex,
+            // rs2 = instr[24:20]
+            rs2: ((instr >> 20) & 0x1f) as RegIndex,
+            imm5: (instr >> 7) & 0x1f,
+            imm12: (instr as i32 >> 20) as u32,
+            // cast to signed integer to do sign extension as we shift right
+            imm20: (instr as i32 >> 12) as u32
+        }
+    }
+}
+
+/// Function that executes an instruction given its operands
+pub type Handler = fn(&mut Cpu, &Operands) -> Result<(), Trap>;
+
+/// Decode an instruction and execute it
+#[inline(always)]
+pub fn decode<const DEBUG: bool>(instr: Instruction, curcpu: &mut Cpu) -> Result<(), Trap> {
+    match predecode::<DEBUG>(instr, curcpu.get_xlen()) {
+        Some(handler) => handler(curcpu, &Operands::new(instr)),
+        None => Err(Trap::IllegalInstruction(instr))
+    }
+}
+
+/// Find the function that executes an instruction, None if the
+/// instruction is illegal (RV64 instructions are illegal in RV32 mode)
+pub fn predecode<const DEBUG: bool>(instr: Instruction, xlen: Xlen) -> Option<Handler> {
     // opcode = instr[6:0]
     let opcode = (instr & 0x7f) as u8;
     // f3 = instr[14:12]
     let f3 = ((instr >> 12) & 0x7) as u8;
     // f7 = instr[31:25]
     let f7 = ((instr >> 25) & 0x7f) as u8;
+    // MRET and SRET are told apart by instr[31:20]
+    let imm12: u32 = instr >> 20;
 
-    // rd = instr[11:7]
-    let rd:  RegIndex = ((instr >>  7) & 0x1f) as RegIndex;
-    // rs1 = instr[19:15]
-    let rs1: RegIndex = ((instr >> 15) & 0x1f) as RegIndex;
-    // rs2 = instr[24:20]
-    let rs2: RegIndex = ((instr >> 20) & 0x1f) as RegIndex;
-    // 5 bits long immediate takes the place of rd instr[11:7]
-    let imm5:  u32 = ((instr >>  7) & 0x1f) as u32;
-    // 12 bits long immediate is instr[31:20]
-    let imm12: u32 = (instr as i32 >> 20) as u32;
-    // 20 bits long immediate is instr[31:12]
-    // cast to signed integer to do sign extension as we shift right
-    let imm20: u32 = (instr as i32 >> 12) as u32;
+    // The floating-point instructions are decoded by the F and D extensions
+    if matches!(opcode, OpCodes::LOAD_FP | OpCodes::STORE_FP | OpCodes::MADD | OpCodes::MSUB |
+                        OpCodes::NMSUB | OpCodes::NMADD | OpCodes::OP_FP) {
+        return rvfd::predecode::<DEBUG>(instr, xlen);
+    }
 
     // Create a DecInstruction given f3, f7 and the opcode
     let dec_instr: DecInstruction = DecInstruction { opcode, f3, f7 };
 
-    match dec_instr {
+    // RV32 has no doubleword loads and stores, no word instructions and
+    // shift amounts (also bit indexes of BSETI, BCLRI, BINVI and BEXTI)
+    // only go up to 31
+    if xlen == Xlen::Rv32 {
+        let rv64_only: bool = match opcode {
+            OpCodes::ITYPE64 | OpCodes::RTYPE64 => true,
+            OpCodes::LOAD => f3 == 0b011 || f3 == 0b110,
+            OpCodes::STYPE => f3 == 0b011,
+            OpCodes::ITYPE => (f3 == 0b001 || f3 == 0b101) && f7 & 0x1 != 0,
+            _ => false
+        };
+        if rv64_only {
+            return None;
+        }
+    }
+
+    let handler: Handler = match dec_instr {
         // RV32I Base Instruction Set
         // LUI
-        DecInstruction { opcode: OpCodes::LUI,   f3: _,     f7: _         } => lui(curcpu, rd, imm20),
+        DecInstruction { opcode: OpCodes::LUI,   f3: _,     f7: _         } => |cpu, op| { lui::<DEBUG>(cpu, op.rd, op.imm20); Ok(()) },
         // AUIPC
-        DecInstruction { opcode: OpCodes::AUIPC, f3: _,     f7: _         } => auipc(curcpu, rd, imm20),
+        DecInstruction { opcode: OpCodes::AUIPC, f3: _,     f7: _         } => |cpu, op| { auipc::<DEBUG>(cpu, op.rd, op.imm20); Ok(()) },
         // JAL
-        DecInstruction { opcode: OpCodes::JAL,   f3: _,     f7: _         } => jal(curcpu, rd, imm20),
+        DecInstruction { opcode: OpCodes::JAL,   f3: _,     f7: _         } => |cpu, op| { jal::<DEBUG>(cpu, op.rd, op.imm20); Ok(()) },
         // JALR
-        DecInstruction { opcode: OpCodes::JALR,  f3: 0b000, f7: _         } => jalr(curcpu, rs1, rd, imm12),
+        DecInstruction { opcode: OpCodes::JALR,  f3: 0b000, f7: _         } => |cpu, op| { jalr::<DEBUG>(cpu, op.rs1, op.rd, op.imm12); Ok(()) },
         // BEQ
-        DecInstruction { opcode: OpCodes::BTYPE, f3: 0b000, f7: _         } => beq(curcpu, rs1, rs2, imm5, imm12),
+        DecInstruction { opcode: OpCodes::BTYPE, f3: 0b000, f7: _         } => |cpu, op| { beq::<DEBUG>(cpu, op.rs1, op.rs2, op.imm5, op.imm12); Ok(()) },
         // BNE
-        DecInstruction { opcode: OpCodes::BTYPE, f3: 0b001, f7: _         } => bne(curcpu, rs1, rs2, imm5, imm12),
+        DecInstruction { opcode: OpCodes::BTYPE, f3: 0b001, f7: _         } => |cpu, op| { bne::<DEBUG>(cpu, op.rs1, op.rs2, op.imm5, op.imm12); Ok(()) },
         // BLT
-        DecInstruction { opcode: OpCodes::BTYPE, f3: 0b100, f7: _         } => blt(curcpu, rs1, rs2, imm5, imm12),
+        DecInstruction { opcode: OpCodes::BTYPE, f3: 0b100, f7: _         } => |cpu, op| { blt::<DEBUG>(cpu, op.rs1, op.rs2, op.imm5, op.imm12); Ok(()) },
         // BGE
-        DecInstruction { opcode: OpCodes::BTYPE, f3: 0b101, f7: _         } => bge(curcpu, rs1, rs2, imm5, imm12),
+        DecInstruction { opcode: OpCodes::BTYPE, f3: 0b101, f7: _         } => |cpu, op| { bge::<DEBUG>(cpu, op.rs1, op.rs2, op.imm5, op.imm12); Ok(()) },
         // BLTU
-        DecInstruction { opcode: OpCodes::BTYPE, f3: 0b110, f7: _         } => bltu(curcpu, rs1, rs2, imm5, imm12),
+        DecInstruction { opcode: OpCodes::BTYPE, f3: 0b110, f7: _         } => |cpu, op| { bltu::<DEBUG>(cpu, op.rs1, op.rs2, op.imm5, op.imm12); Ok(()) },
         // BGEU
-        DecInstruction { opcode: OpCodes::BTYPE, f3: 0b111, f7: _         } => bgeu(curcpu, rs1, rs2, imm5, imm12),
+        DecInstruction { opcode: OpCodes::BTYPE, f3: 0b111, f7: _         } => |cpu, op| { bgeu::<DEBUG>(cpu, op.rs1, op.rs2, op.imm5, op.imm12); Ok(()) },
         // LB
-        DecInstruction { opcode: OpCodes::LOAD,  f3: 0b000, f7: _         } => lb(curcpu, rs1, rd, imm12),
+        DecInstruction { opcode: OpCodes::LOAD,  f3: 0b000, f7: _         } => |cpu, op| lb::<DEBUG>(cpu, op.rs1, op.rd, op.imm12),
         // LH
-        DecInstruction { opcode: OpCodes::LOAD,  f3: 0b001, f7: _         } => lh(curcpu, rs1, rd, imm12),
+        DecInstruction { opcode: OpCodes::LOAD,  f3: 0b001, f7: _         } => |cpu, op| lh::<DEBUG>(cpu, op.rs1, op.rd, op.imm12),
         // LW
-        DecInstruction { opcode: OpCodes::LOAD,  f3: 0b010, f7: _         } => lw(curcpu, rs1, rd, imm12),
+        DecInstruction { opcode: OpCodes::LOAD,  f3: 0b010, f7: _         } => |cpu, op| lw::<DEBUG>(cpu, op.rs1, op.rd, op.imm12),
         // LBU
-        DecInstruction { opcode: OpCodes::LOAD,  f3: 0b100, f7: _         } => lbu(curcpu, rs1, rd, imm12),
+        DecInstruction { opcode: OpCodes::LOAD,  f3: 0b100, f7: _         } => |cpu, op| lbu::<DEBUG>(cpu, op.rs1, op.rd, op.imm12),
         // LHU
-        DecInstruction { opcode: OpCodes::LOAD,  f3: 0b101, f7: _         } => lhu(curcpu, rs1, rd, imm12),
+        DecInstruction { opcode: OpCodes::LOAD,  f3: 0b101, f7: _         } => |cpu, op| lhu::<DEBUG>(cpu, op.rs1, op.rd, op.imm12),
         // SB
-        DecInstruction { opcode: OpCodes::STYPE, f3: 0b000, f7: _         } => sb(curcpu, rs1, imm12, imm5),
+        DecInstruction { opcode: OpCodes::STYPE, f3: 0b000, f7: _         } => |cpu, op| sb::<DEBUG>(cpu, op.rs1, op.imm12, op.imm5),
         // SH
-        DecInstruction { opcode: OpCodes::STYPE, f3: 0b001, f7: _         } => sh(curcpu, rs1, imm12, imm5),
+        DecInstruction { opcode: OpCodes::STYPE, f3: 0b001, f7: _         } => |cpu, op| sh::<DEBUG>(cpu, op.rs1, op.imm12, op.imm5),
         // SW
-        DecInstruction { opcode: OpCodes::STYPE, f3: 0b010, f7: _         } => sw(curcpu, rs1, imm12, imm5),
+        DecInstruction { opcode: OpCodes::STYPE, f3: 0b010, f7: _         } => |cpu, op| sw::<DEBUG>(cpu, op.rs1, op.imm12, op.imm5),
         // ADDI
-        DecInstruction { opcode: OpCodes::ITYPE, f3: 0b000, f7: _         } => addi(curcpu, rs1, rd, imm12),
+        DecInstruction { opcode: OpCodes::ITYPE, f3: 0b000, f7: _         } => |cpu, op| { addi::<DEBUG>(cpu, op.rs1, op.rd, op.imm12); Ok(()) },
         // SLTI
-        DecInstruction { opcode: OpCodes::ITYPE, f3: 0b010, f7: _         } => slti(curcpu, rs1, rd, imm12),
+        DecInstruction { opcode: OpCodes::ITYPE, f3: 0b010, f7: _         } => |cpu, op| { slti::<DEBUG>(cpu, op.rs1, op.rd, op.imm12); Ok(()) },
         // SLTIU
-        DecInstruction { opcode: OpCodes::ITYPE, f3: 0b011, f7: _         } => sltiu(curcpu, rs1, rd, imm12),
+        DecInstruction { opcode: OpCodes::ITYPE, f3: 0b011, f7: _         } => |cpu, op| { sltiu::<DEBUG>(cpu, op.rs1, op.rd, op.imm12); Ok(()) },
         // XORI
-        DecInstruction { opcode: OpCodes::ITYPE, f3: 0b100, f7: _         } => xori(curcpu, rs1, rd, imm12),
+        DecInstruction { opcode: OpCodes::ITYPE, f3: 0b100, f7: _         } => |cpu, op| { xori::<DEBUG>(cpu, op.rs1, op.rd, op.imm12); Ok(()) },
         // ORI
-        DecInstruction { opcode: OpCodes::ITYPE, f3: 0b110, f7: _         } => ori(curcpu, rs1, rd, imm12),
+        DecInstruction { opcode: OpCodes::ITYPE, f3: 0b110, f7: _         } => |cpu, op| { ori::<DEBUG>(cpu, op.rs1, op.rd, op.imm12); Ok(()) },
         // ANDI
-        DecInstruction { opcode: OpCodes::ITYPE, f3: 0b111, f7: _         } => andi(curcpu, rs1, rd, imm12),
+        DecInstruction { opcode: OpCodes::ITYPE, f3: 0b111, f7: _         } => |cpu, op| { andi::<DEBUG>(cpu, op.rs1, op.rd, op.imm12); Ok(()) },
+        // Zbb: CLZ, CTZ, CPOP, SEXT.B and SEXT.H (rs2 selects the operation)
+        DecInstruction { opcode: OpCodes::ITYPE, f3: 0b001, f7: 0b0110000 } if imm12 & 0xfff == 0x600 => |cpu, op| { clz::<DEBUG>(cpu, op.rs1, op.rd); Ok(()) },
+        DecInstruction { opcode: OpCodes::ITYPE, f3: 0b001, f7: 0b0110000 } if imm12 & 0xfff == 0x601 => |cpu, op| { ctz::<DEBUG>(cpu, op.rs1, op.rd); Ok(()) },
+        DecInstruction { opcode: OpCodes::ITYPE, f3: 0b001, f7: 0b0110000 } if imm12 & 0xfff == 0x602 => |cpu, op| { cpop::<DEBUG>(cpu, op.rs1, op.rd); Ok(()) },
+        DecInstruction { opcode: OpCodes::ITYPE, f3: 0b001, f7: 0b0110000 } if imm12 & 0xfff == 0x604 => |cpu, op| { sext_b::<DEBUG>(cpu, op.rs1, op.rd); Ok(()) },
+        DecInstruction { opcode: OpCodes::ITYPE, f3: 0b001, f7: 0b0110000 } if imm12 & 0xfff == 0x605 => |cpu, op| { sext_h::<DEBUG>(cpu, op.rs1, op.rd); Ok(()) },
+        // Zbs: BSETI, BCLRI and BINVI (f7[0] is shamt[5])
+        DecInstruction { opcode: OpCodes::ITYPE, f3: 0b001, f7: 0b0010100 | 0b0010101 } => |cpu, op| { bseti::<DEBUG>(cpu, op.rs1, op.rd, op.imm12); Ok(()) },
+        DecInstruction { opcode: OpCodes::ITYPE, f3: 0b001, f7: 0b0100100 | 0b0100101 } => |cpu, op| { bclri::<DEBUG>(cpu, op.rs1, op.rd, op.imm12); Ok(()) },
+        DecInstruction { opcode: OpCodes::ITYPE, f3: 0b001, f7: 0b0110100 | 0b0110101 } => |cpu, op| { binvi::<DEBUG>(cpu, op.rs1, op.rd, op.imm12); Ok(()) },
+        // Zbb: RORI, ORC.B and REV8 (its encoding depends on XLEN)
+        DecInstruction { opcode: OpCodes::ITYPE, f3: 0b101, f7: 0b0110000 | 0b0110001 } => |cpu, op| { rori::<DEBUG>(cpu, op.rs1, op.rd, op.imm12); Ok(()) },
+        DecInstruction { opcode: OpCodes::ITYPE, f3: 0b101, f7: 0b0010100 } if imm12 & 0xfff == 0x287 => |cpu, op| { orc_b::<DEBUG>(cpu, op.rs1, op.rd); Ok(()) },
+        DecInstruction { opcode: OpCodes::ITYPE, f3: 0b101, f7: 0b0110100 } if imm12 & 0xfff == 0x698 && xlen == Xlen::Rv32 => |cpu, op| { rev8::<DEBUG>(cpu, op.rs1, op.rd); Ok(()) },
+        DecInstruction { opcode: OpCodes::ITYPE, f3: 0b101, f7: 0b0110101 } if imm12 & 0xfff == 0x6b8 => |cpu, op| { rev8::<DEBUG>(cpu, op.rs1, op.rd); Ok(()) },
+        // Zbs: BEXTI
+        DecInstruction { opcode: OpCodes::ITYPE, f3: 0b101, f7: 0b0100100 | 0b0100101 } => |cpu, op| { bexti::<DEBUG>(cpu, op.rs1, op.rd, op.imm12); Ok(()) },
         // SLLI
-        DecInstruction { opcode: OpCodes::ITYPE, f3: 0b001, f7: _         } => slli(curcpu, rs1, rd, imm12),
+        DecInstruction { opcode: OpCodes::ITYPE, f3: 0b001, f7: _         } => |cpu, op| { slli::<DEBUG>(cpu, op.rs1, op.rd, op.imm12); Ok(()) },
         // SRLI and SRAI
-        DecInstruction { opcode: OpCodes::ITYPE, f3: 0b101, f7: _         } => srli_srai(curcpu, rs1, rd, imm12),
+        DecInstruction { opcode: OpCodes::ITYPE, f3: 0b101, f7: _         } => |cpu, op| { srli_srai::<DEBUG>(cpu, op.rs1, op.rd, op.imm12); Ok(()) },
         // ADD
-        DecInstruction { opcode: OpCodes::RTYPE, f3: 0b000, f7: 0b0000000 } => add(curcpu, rs1, rs2, rd),
+        DecInstruction { opcode: OpCodes::RTYPE, f3: 0b000, f7: 0b0000000 } => |cpu, op| { add::<DEBUG>(cpu, op.rs1, op.rs2, op.rd); Ok(()) },
         // SUB
-        DecInstruction { opcode: OpCodes::RTYPE, f3: 0b000, f7: 0b0100000 } => sub(curcpu, rs1, rs2, rd),
+        DecInstruction { opcode: OpCodes::RTYPE, f3: 0b000, f7: 0b0100000 } => |cpu, op| { sub::<DEBUG>(cpu, op.rs1, op.rs2, op.rd); Ok(()) },
         // SLL
-        DecInstruction { opcode: OpCodes::RTYPE, f3: 0b001, f7: 0b0000000 } => sll(curcpu, rs1, rs2, rd),
+        DecInstruction { opcode: OpCodes::RTYPE, f3: 0b001, f7: 0b0000000 } => |cpu, op| { sll::<DEBUG>(cpu, op.rs1, op.rs2, op.rd); Ok(()) },
         // SLT
-        DecInstruction { opcode: OpCodes::RTYPE, f3: 0b010, f7: 0b0000000 } => slt(curcpu, rs1, rs2, rd),
+        DecInstruction { opcode: OpCodes::RTYPE, f3: 0b010, f7: 0b0000000 } => |cpu, op| { slt::<DEBUG>(cpu, op.rs1, op.rs2, op.rd); Ok(()) },
         // SLTU
-        DecInstruction { opcode: OpCodes::RTYPE, f3: 0b011, f7: 0b0000000 } => sltu(curcpu, rs1, rs2, rd),
+        DecInstruction { opcode: OpCodes::RTYPE, f3: 0b011, f7: 0b0000000 } => |cpu, op| { sltu::<DEBUG>(cpu, op.rs1, op.rs2, op.rd); Ok(()) },
         // XOR
-        DecInstruction { opcode: OpCodes::RTYPE, f3: 0b100, f7: 0b0000000 } => xor(curcpu, rs1, rs2, rd),
+        DecInstruction { opcode: OpCodes::RTYPE, f3: 0b100, f7: 0b0000000 } => |cpu, op| { xor::<DEBUG>(cpu, op.rs1, op.rs2, op.rd); Ok(()) },
         // SRL
-        DecInstruction { opcode: OpCodes::RTYPE, f3: 0b101, f7: 0b0000000 } => srl(curcpu, rs1, rs2, rd),
+        DecInstruction { opcode: OpCodes::RTYPE, f3: 0b101, f7: 0b0000000 } => |cpu, op| { srl::<DEBUG>(cpu, op.rs1, op.rs2, op.rd); Ok(()) },
         // SRA
-        DecInstruction { opcode: OpCodes::RTYPE, f3: 0b101, f7: 0b0100000 } => sra(curcpu, rs1, rs2, rd),
+        DecInstruction { opcode: OpCodes::RTYPE, f3: 0b101, f7: 0b0100000 } => |cpu, op| { sra::<DEBUG>(cpu, op.rs1, op.rs2, op.rd); Ok(()) },
         // OR
-        DecInstruction { opcode: OpCodes::RTYPE, f3: 0b110, f7: 0b0000000 } => or(curcpu, rs1, rs2, rd),
+        DecInstruction { opcode: OpCodes::RTYPE, f3: 0b110, f7: 0b0000000 } => |cpu, op| { or::<DEBUG>(cpu, op.rs1, op.rs2, op.rd); Ok(()) },
         // AND
-        DecInstruction { opcode: OpCodes::RTYPE, f3: 0b111, f7: 0b0000000 } => and(curcpu, rs1, rs2, rd),
+        DecInstruction { opcode: OpCodes::RTYPE, f3: 0b111, f7: 0b0000000 } => |cpu, op| { and::<DEBUG>(cpu, op.rs1, op.rs2, op.rd); Ok(()) },
+        // Zba: SH1ADD, SH2ADD and SH3ADD
+        DecInstruction { opcode: OpCodes::RTYPE, f3: 0b010, f7: 0b0010000 } => |cpu, op| { shadd::<DEBUG>(cpu, op.rs1, op.rs2, op.rd, 1); Ok(()) },
+        DecInstruction { opcode: OpCodes::RTYPE, f3: 0b100, f7: 0b0010000 } => |cpu, op| { shadd::<DEBUG>(cpu, op.rs1, op.rs2, op.rd, 2); Ok(()) },
+        DecInstruction { opcode: OpCodes::RTYPE, f3: 0b110, f7: 0b0010000 } => |cpu, op| { shadd::<DEBUG>(cpu, op.rs1, op.rs2, op.rd, 3); Ok(()) },
+        // Zbb: ANDN, ORN and XNOR
+        DecInstruction { opcode: OpCodes::RTYPE, f3: 0b111, f7: 0b0100000 } => |cpu, op| { andn::<DEBUG>(cpu, op.rs1, op.rs2, op.rd); Ok(()) },
+        DecInstruction { opcode: OpCodes::RTYPE, f3: 0b110, f7: 0b0100000 } => |cpu, op| { orn::<DEBUG>(cpu, op.rs1, op.rs2, op.rd); Ok(()) },
+        DecInstruction { opcode: OpCodes::RTYPE, f3: 0b100, f7: 0b0100000 } => |cpu, op| { xnor::<DEBUG>(cpu, op.rs1, op.rs2, op.rd); Ok(()) },
+        // Zbb: MIN, MINU, MAX and MAXU
+        DecInstruction { opcode: OpCodes::RTYPE, f3: 0b100, f7: 0b0000101 } => |cpu, op| { min::<DEBUG>(cpu, op.rs1, op.rs2, op.rd); Ok(()) },
+        DecInstruction { opcode: OpCodes::RTYPE, f3: 0b101, f7: 0b0000101 } => |cpu, op| { minu::<DEBUG>(cpu, op.rs1, op.rs2, op.rd); Ok(()) },
+        DecInstruction { opcode: OpCodes::RTYPE, f3: 0b110, f7: 0b0000101 } => |cpu, op| { max::<DEBUG>(cpu, op.rs1, op.rs2, op.rd); Ok(()) },
+        DecInstruction { opcode: OpCodes::RTYPE, f3: 0b111, f7: 0b0000101 } => |cpu, op| { maxu::<DEBUG>(cpu, op.rs1, op.rs2, op.rd); Ok(()) },
+        // Zbb: ROL and ROR
+        DecInstruction { opcode: OpCodes::RTYPE, f3: 0b001, f7: 0b0110000 } => |cpu, op| { rol::<DEBUG>(cpu, op.rs1, op.rs2, op.rd); Ok(()) },
+        DecInstruction { opcode: OpCodes::RTYPE, f3: 0b101, f7: 0b0110000 } => |cpu, op| { ror::<DEBUG>(cpu, op.rs1, op.rs2, op.rd); Ok(()) },
+        // Zbb: ZEXT.H (RV32 encoding, RV64 has it among the word instructions)
+        DecInstruction { opcode: OpCodes::RTYPE, f3: 0b100, f7: 0b0000100 } if imm12 & 0x1f == 0 && xlen == Xlen::Rv32 => |cpu, op| { zext_h::<DEBUG>(cpu, op.rs1, op.rd); Ok(()) },
+        // Zbs: BCLR, BEXT, BINV and BSET
+        DecInstruction { opcode: OpCodes::RTYPE, f3: 0b001, f7: 0b0100100 } => |cpu, op| { bclr::<DEBUG>(cpu, op.rs1, op.rs2, op.rd); Ok(()) },
+        DecInstruction { opcode: OpCodes::RTYPE, f3: 0b101, f7: 0b0100100 } => |cpu, op| { bext::<DEBUG>(cpu, op.rs1, op.rs2, op.rd); Ok(()) },
+        DecInstruction { opcode: OpCodes::RTYPE, f3: 0b001, f7: 0b0110100 } => |cpu, op| { binv::<DEBUG>(cpu, op.rs1, op.rs2, op.rd); Ok(()) },
+        DecInstruction { opcode: OpCodes::RTYPE, f3: 0b001, f7: 0b0010100 } => |cpu, op| { bset::<DEBUG>(cpu, op.rs1, op.rs2, op.rd); Ok(()) },
+        // Zicond: CZERO.EQZ and CZERO.NEZ
+        DecInstruction { opcode: OpCodes::RTYPE, f3: 0b101, f7: 0b0000111 } => |cpu, op| { czero_eqz::<DEBUG>(cpu, op.rs1, op.rs2, op.rd); Ok(()) },
+        DecInstruction { opcode: OpCodes::RTYPE, f3: 0b111, f7: 0b0000111 } => |cpu, op| { czero_nez::<DEBUG>(cpu, op.rs1, op.rs2, op.rd); Ok(()) },
+        // Zbc: CLMUL, CLMULR and CLMULH
+        DecInstruction { opcode: OpCodes::RTYPE, f3: 0b001, f7: 0b0000101 } => |cpu, op| { clmul::<DEBUG>(cpu, op.rs1, op.rs2, op.rd); Ok(()) },
+        DecInstruction { opcode: OpCodes::RTYPE, f3: 0b010, f7: 0b0000101 } => |cpu, op| { clmulr::<DEBUG>(cpu, op.rs1, op.rs2, op.rd); Ok(()) },
+        DecInstruction { opcode: OpCodes::RTYPE, f3: 0b011, f7: 0b0000101 } => |cpu, op| { clmulh::<DEBUG>(cpu, op.rs1, op.rs2, op.rd); Ok(()) },
         // FENCE
-        DecInstruction { opcode: OpCodes::FENCE, f3: 0b000, f7: _         } => fence(curcpu),
+        DecInstruction { opcode: OpCodes::FENCE, f3: 0b000, f7: _         } => |cpu, _| { fence::<DEBUG>(cpu); Ok(()) },
         // FENCEI
-        DecInstruction { opcode: OpCodes::FENCE, f3: 0b001, f7: _         } => fencei(curcpu),
-        // ECALL
-        DecInstruction { opcode: OpCodes::EXCEP, f3: 0b000, f7: 0b0000000 } => ecall_ebreak(curcpu, imm12),
+        DecInstruction { opcode: OpCodes::FENCE, f3: 0b001, f7: _         } => |cpu, _| { fencei::<DEBUG>(cpu); Ok(()) },
+        // ECALL and EBREAK
+        DecInstruction { opcode: OpCodes::EXCEP, f3: 0b000, f7: 0b0000000 } => |cpu, op| ecall_ebreak::<DEBUG>(cpu, op.imm12),
+        // MRET
+        DecInstruction { opcode: OpCodes::EXCEP, f3: 0b000, f7: 0b0011000 } if imm12 & 0xfff == 0x302 => |cpu, _| mret::<DEBUG>(cpu),
+        // SRET
+        DecInstruction { opcode: OpCodes::EXCEP, f3: 0b000, f7: 0b0001000 } if imm12 & 0xfff == 0x102 => |cpu, _| sret::<DEBUG>(cpu),
+        // WFI
+        DecInstruction { opcode: OpCodes::EXCEP, f3: 0b000, f7: 0b0001000 } if imm12 & 0xfff == 0x105 => |cpu, _| wfi::<DEBUG>(cpu),
         // CSRRW
-        DecInstruction { opcode: OpCodes::EXCEP, f3: 0b001, f7: _         } => csrrw(curcpu, rs1, rd, imm12),
+        DecInstruction { opcode: OpCodes::EXCEP, f3: 0b001, f7: _         } => |cpu, op| csrrw::<DEBUG>(cpu, op.rs1, op.rd, op.imm12),
         // CSRRS
-        DecInstruction { opcode: OpCodes::EXCEP, f3: 0b010, f7: _         } => csrrs(curcpu, rs1, rd, imm12),
+        DecInstruction { opcode: OpCodes::EXCEP, f3: 0b010, f7: _         } => |cpu, op| csrrs::<DEBUG>(cpu, op.rs1, op.rd, op.imm12),
         // CSRRC
-        DecInstruction { opcode: OpCodes::EXCEP, f3: 0b011, f7: _         } => csrrc(curcpu, rs1, rd, imm12),
+        DecInstruction { opcode: OpCodes::EXCEP, f3: 0b011, f7: _         } => |cpu, op| csrrc::<DEBUG>(cpu, op.rs1, op.rd, op.imm12),
         // CSRRWI
-        DecInstruction { opcode: OpCodes::EXCEP, f3: 0b101, f7: _         } => csrrwi(curcpu, rs1, rd, imm12),
+        DecInstruction { opcode: OpCodes::EXCEP, f3: 0b101, f7: _         } => |cpu, op| csrrwi::<DEBUG>(cpu, op.rs1, op.rd, op.imm12),
         // CSRRS
-        DecInstruction { opcode: OpCodes::EXCEP, f3: 0b110, f7: _         } => csrrsi(curcpu, rs1, rd, imm12),
+        DecInstruction { opcode: OpCodes::EXCEP, f3: 0b110, f7: _         } => |cpu, op| csrrsi::<DEBUG>(cpu, op.rs1, op.rd, op.imm12),
         // CSRRCI
-        DecInstruction { opcode: OpCodes::EXCEP, f3: 0b111, f7: _         } => csrrci(curcpu, rs1, rd, imm12),
+        DecInstruction { opcode: OpCodes::EXCEP, f3: 0b111, f7: _         } => |cpu, op| csrrci::<DEBUG>(cpu, op.rs1, op.rd, op.imm12),
 
         // RV64I Base Instruction Set
         // LWU
-        DecInstruction { opcode: OpCodes::LOAD,    f3: 0b110, f7: _         } => lwu(curcpu, rs1, rd, imm12),
+        DecInstruction { opcode: OpCodes::LOAD,    f3: 0b110, f7: _         } => |cpu, op| lwu::<DEBUG>(cpu, op.rs1, op.rd, op.imm12),
         // LD
-        DecInstruction { opcode: OpCodes::LOAD,    f3: 0b011, f7: _         } => ld(curcpu, rs1, rd, imm12),
+        DecInstruction { opcode: OpCodes::LOAD,    f3: 0b011, f7: _         } => |cpu, op| ld::<DEBUG>(cpu, op.rs1, op.rd, op.imm12),
         // SD
-        DecInstruction { opcode: OpCodes::STYPE,   f3: 0b011, f7: _         } => sd(curcpu, rs1, imm12, imm5),
+        DecInstruction { opcode: OpCodes::STYPE,   f3: 0b011, f7: _         } => |cpu, op| sd::<DEBUG>(cpu, op.rs1, op.imm12, op.imm5),
         // ADDIW
-        DecInstruction { opcode: OpCodes::ITYPE64, f3: 0b000, f7: _         } => addiw(curcpu, rs1, rd, imm12),
+        DecInstruction { opcode: OpCodes::ITYPE64, f3: 0b000, f7: _         } => |cpu, op| { addiw::<DEBUG>(cpu, op.rs1, op.rd, op.imm12); Ok(()) },
         // SLLIW
-        DecInstruction { opcode: OpCodes::ITYPE64, f3: 0b001, f7: 0b0000000 } => slliw(curcpu, rs1, rd, imm12),
+        DecInstruction { opcode: OpCodes::ITYPE64, f3: 0b001, f7: 0b0000000 } => |cpu, op| { slliw::<DEBUG>(cpu, op.rs1, op.rd, op.imm12); Ok(()) },
+        // Zba: SLLI.UW
+        DecInstruction { opcode: OpCodes::ITYPE64, f3: 0b001, f7: 0b0000100 | 0b0000101 } => |cpu, op| { slli_uw::<DEBUG>(cpu, op.rs1, op.rd, op.imm12); Ok(()) },
+        // Zbb: CLZW, CTZW and CPOPW
+        DecInstruction { opcode: OpCodes::ITYPE64, f3: 0b001, f7: 0b0110000 } if imm12 & 0xfff == 0x600 => |cpu, op| { clzw::<DEBUG>(cpu, op.rs1, op.rd); Ok(()) },
+        DecInstruction { opcode: OpCodes::ITYPE64, f3: 0b001, f7: 0b0110000 } if imm12 & 0xfff == 0x601 => |cpu, op| { ctzw::<DEBUG>(cpu, op.rs1, op.rd); Ok(()) },
+        DecInstruction { opcode: OpCodes::ITYPE64, f3: 0b001, f7: 0b0110000 } if imm12 & 0xfff == 0x602 => |cpu, op| { cpopw::<DEBUG>(cpu, op.rs1, op.rd); Ok(()) },
+        // Zbb: RORIW
+        DecInstruction { opcode: OpCodes::ITYPE64, f3: 0b101, f7: 0b0110000 } => |cpu, op| { roriw::<DEBUG>(cpu, op.rs1, op.rd, op.imm12); Ok(()) },
         // SRLIW and SRAIW
-        DecInstruction { opcode: OpCodes::ITYPE64, f3: 0b101, f7: _         } => srliw_sraiw(curcpu, rs1, rd, imm12),
+        DecInstruction { opcode: OpCodes::ITYPE64, f3: 0b101, f7: _         } => |cpu, op| { srliw_sraiw::<DEBUG>(cpu, op.rs1, op.rd, op.imm12); Ok(()) },
         // ADDW
-        DecInstruction { opcode: OpCodes::RTYPE64, f3: 0b000, f7: 0b0000000 } => addw(curcpu, rs1, rs2, rd),
+        DecInstruction { opcode: OpCodes::RTYPE64, f3: 0b000, f7: 0b0000000 } => |cpu, op| { addw::<DEBUG>(cpu, op.rs1, op.rs2, op.rd); Ok(()) },
         // SUBW
-        DecInstruction { opcode: OpCodes::RTYPE64, f3: 0b000, f7: 0b0100000 } => subw(curcpu, rs1, rs2, rd),
+        DecInstruction { opcode: OpCodes::RTYPE64, f3: 0b000, f7: 0b0100000 } => |cpu, op| { subw::<DEBUG>(cpu, op.rs1, op.rs2, op.rd); Ok(()) },
         // SLLW
-        DecInstruction { opcode: OpCodes::RTYPE64, f3: 0b001, f7: 0b0000000 } => sllw(curcpu, rs1, rs2, rd),
+        DecInstruction { opcode: OpCodes::RTYPE64, f3: 0b001, f7: 0b0000000 } => |cpu, op| { sllw::<DEBUG>(cpu, op.rs1, op.rs2, op.rd); Ok(()) },
         // SRLW
-        DecInstruction { opcode: OpCodes::RTYPE64, f3: 0b101, f7: 0b0000000 } => srlw(curcpu, rs1, rs2, rd),
+        DecInstruction { opcode: OpCodes::RTYPE64, f3: 0b101, f7: 0b0000000 } => |cpu, op| { srlw::<DEBUG>(cpu, op.rs1, op.rs2, op.rd); Ok(()) },
         // SRAW
-        DecInstruction { opcode: OpCodes::RTYPE64, f3: 0b101, f7: 0b0100000 } => sraw(curcpu, rs1, rs2, rd),
-        _ => panic!("Instruction {:x} was not recognized", instr)
+        DecInstruction { opcode: OpCodes::RTYPE64, f3: 0b101, f7: 0b0100000 } => |cpu, op| { sraw::<DEBUG>(cpu, op.rs1, op.rs2, op.rd); Ok(()) },
+        // Zba: ADD.UW, SH1ADD.UW, SH2ADD.UW and SH3ADD.UW
+        DecInstruction { opcode: OpCodes::RTYPE64, f3: 0b000, f7: 0b0000100 } => |cpu, op| { shadd_uw::<DEBUG>(cpu, op.rs1, op.rs2, op.rd, 0); Ok(()) },
+        DecInstruction { opcode: OpCodes::RTYPE64, f3: 0b010, f7: 0b0010000 } => |cpu, op| { shadd_uw::<DEBUG>(cpu, op.rs1, op.rs2, op.rd, 1); Ok(()) },
+        DecInstruction { opcode: OpCodes::RTYPE64, f3: 0b100, f7: 0b0010000 } => |cpu, op| { shadd_uw::<DEBUG>(cpu, op.rs1, op.rs2, op.rd, 2); Ok(()) },
+        DecInstruction { opcode: OpCodes::RTYPE64, f3: 0b110, f7: 0b0010000 } => |cpu, op| { shadd_uw::<DEBUG>(cpu, op.rs1, op.rs2, op.rd, 3); Ok(()) },
+        // Zbb: ZEXT.H
+        DecInstruction { opcode: OpCodes::RTYPE64, f3: 0b100, f7: 0b0000100 } if imm12 & 0x1f == 0 => |cpu, op| { zext_h::<DEBUG>(cpu, op.rs1, op.rd); Ok(()) },
+        // Zbb: ROLW and RORW
+        DecInstruction { opcode: OpCodes::RTYPE64, f3: 0b001, f7: 0b0110000 } => |cpu, op| { rolw::<DEBUG>(cpu, op.rs1, op.rs2, op.rd); Ok(()) },
+        DecInstruction { opcode: OpCodes::RTYPE64, f3: 0b101, f7: 0b0110000 } => |cpu, op| { rorw::<DEBUG>(cpu, op.rs1, op.rs2, op.rd); Ok(()) },
+        _ => return None
     };
+    Some(handler)
+}
+
+
+/// Register written by an instruction, if any. It is only needed by the
+/// debugger to highlight the last updated register, so the execution of
+/// the instructions does not have to keep track of it
+pub fn destination_register(instr: Instruction) -> Option<RegIndex> {
+    let opcode = (instr & 0x7f) as u8;
+    let f3 = ((instr >> 12) & 0x7) as u8;
+    let rd: RegIndex = ((instr >> 7) & 0x1f) as RegIndex;
+    match opcode {
+        OpCodes::LUI | OpCodes::AUIPC | OpCodes::JAL | OpCodes::JALR |
+        OpCodes::LOAD | OpCodes::ITYPE | OpCodes::RTYPE |
+        OpCodes::ITYPE64 | OpCodes::RTYPE64 => Some(rd),
+        // Only the CSR instructions write rd
+        OpCodes::EXCEP if f3 != 0b000 => Some(rd),
+        // Floating-point comparisons, conversions to integers, FMV.X and FCLASS
+        OpCodes::OP_FP => rvfd::integer_destination(instr).then_some(rd),
+        _ => None
+    }
 }
 
 // Decode J-Type Immediates
 #[inline(always)]
-fn decode_immediate_jtype(imm20: u32) -> i64 {
-    let imm_32_20: u32 = imm20 & 0xfff80000;
-    let imm_19_12: u32 = (imm20 & 0xff) << 11;
-    let imm_11:    u32 = (imm20 & 0x100) << 2;
-    let imm_10_0:  u32 = (imm20 & 0x7fe00) >> 8;
+pub(crate) fn decode_immediate_jtype(imm20: u32) -> i64 {
+    let imm_32_20: u32 = (imm20 & 0xfff80000) << 1;
+    let imm_19_12: u32 = (imm20 & 0xff) << 12;
+    let imm_11:    u32 = (imm20 & 0x100) << 3;
+    let imm_10_1:  u32 = (imm20 & 0x7fe00) >> 8;
 
-    ((imm_32_20 | imm_19_12 | imm_11 | imm_10_0)) as i32 as i64
+    (imm_32_20 | imm_19_12 | imm_11 | imm_10_1) as i32 as i64
 }
 
 // Decode B-Type Immediates
 #[inline(always)]
-fn decode_immediate_btype(imm5: u32, imm12: u32) -> i64 {
-    let imm_32_12: u32 = imm12 & 0xfffff800;
-    let imm_11:    u32 = (imm5 & 0x1) << 10;
+pub(crate) fn decode_immediate_btype(imm5: u32, imm12: u32) -> i64 {
+    let imm_32_12: u32 = (imm12 & 0xfffff800) << 1;
+    let imm_11:    u32 = (imm5 & 0x1) << 11;
     let imm_10_5:  u32 = imm12 & 0x7e0;
-    let imm_4_0:   u32 = imm5 & !0x3;
+    let imm_4_1:   u32 = imm5 & 0x1e;
 
-    (imm_32_12 | imm_11 | imm_10_5 | imm_4_0) as i32 as i64
+    (imm_32_12 | imm_11 | imm_10_5 | imm_4_1) as i32 as i64
 }
 
 // Decode S-Type Immediates
 #[inline(always)]
-fn decode_immediate_stype(imm5: u32, imm12: u32) -> i64 {
+pub(crate) fn decode_immediate_stype(imm5: u32, imm12: u32) -> i64 {
     ((imm12 & 0xffffffe0) | imm5) as i32 as i64
 }
 
 // LUI instruction
 // rd <- signed'imm[32:12] << 12
 #[inline(always)]
-fn lui(curcpu: &mut Cpu, rd: RegIndex, imm: u32) {
+fn lui<const DEBUG: bool>(curcpu: &mut Cpu, rd: RegIndex, imm: u32) {
     let imm64: i64 = (imm << 12) as i32 as i64;
     curcpu.write_reg(rd, imm64 as u64);
-    if curcpu.is_debug_mode() {
-        curcpu.set_debug_string(format!("{} {}, {}",
-        "lui".blue(), REG_FILE_NAMES[rd as usize].red(), imm64));
-    }
 }
 
 // AUIPC instruction
 // rd <- pc + (signed'imm[32:12] << 12)
 #[inline(always)]
-fn auipc(curcpu: &mut Cpu, rd: RegIndex, imm: u32) {
+fn auipc<const DEBUG: bool>(curcpu: &mut Cpu, rd: RegIndex, imm: u32) {
     // AUIPC adds an immediate to the current PC (the one that points to
     // this instruction)
     let first_operand: i64 = (curcpu.get_pc()) as i64;
     // immediate is sign-extended to 64 bits and shifted left
     let second_operand: i64 = (imm as i32 as i64) << 12;
     curcpu.write_reg(rd, first_operand.wrapping_add(second_operand) as u64);
-    if curcpu.is_debug_mode() {
-        curcpu.set_debug_string(format!("{} {}, {}",
-        "auipc".blue(), REG_FILE_NAMES[rd as usize].red(), imm));
-    }
 }
 
 // JAL instruction
 // rd <- pc + 4
 // pc <- pc + signed'immediate
 #[inline(always)]
-fn jal(curcpu: &mut Cpu, rd: RegIndex, imm: u32) {
+fn jal<const DEBUG: bool>(curcpu: &mut Cpu, rd: RegIndex, imm: u32) {
     // Next PC needs to be saved in rd
     if rd != Cpu::ZERO_REGISTER {
         curcpu.write_reg(rd, curcpu.get_next_pc());
@@ -244,296 +397,220 @@ fn jal(curcpu: &mut Cpu, rd: RegIndex, imm: u32) {
     // The immediate - instead - needs to be added to this PC
     let imm64: i64 = decode_immediate_jtype(imm);
     curcpu.set_next_pc_rel(imm64);
-    if curcpu.is_debug_mode() {
-        curcpu.set_debug_string(format!("{} {}, {}",
-        "jal".blue(), REG_FILE_NAMES[rd as usize].red(), imm64));
-    }
 }
 
 // JALR instruction
 // rd <- pc + 4
 // pc <- (rs1 + signed'immediate) & !0x1
 #[inline(always)]
-fn jalr(curcpu: &mut Cpu, rs1: RegIndex, rd: RegIndex, imm: u32) {
+fn jalr<const DEBUG: bool>(curcpu: &mut Cpu, rs1: RegIndex, rd: RegIndex, imm: u32) {
     if rd != Cpu::ZERO_REGISTER {
         curcpu.write_reg(rd, curcpu.get_next_pc());
     }
     let first_operand: i64 = curcpu.read_reg(rs1) as i64;
     let second_operand: i64 = imm as i32 as i64;
     // Mask the resulting PC with 0xfff...ffe so that it is always an even number
-    curcpu.set_next_pc_abs(((first_operand + second_operand) & !0x1) as u64);
-    if curcpu.is_debug_mode() {
-        curcpu.set_debug_string(format!("{} {}, {}, {}",
-        "jalr".blue(), REG_FILE_NAMES[rd as usize].red(), REG_FILE_NAMES[rs1 as usize].red(),
-        second_operand));
-    }
+    curcpu.set_next_pc_abs((first_operand.wrapping_add(second_operand) & !0x1) as u64);
 }
 
 // BEQ instruction
 // if (rs1 == rs2) { pc = pc + signed'immediate }
 #[inline(always)]
-fn beq(curcpu: &mut Cpu, rs1: RegIndex, rs2: RegIndex, imm5: u32, imm12: u32) {
+fn beq<const DEBUG: bool>(curcpu: &mut Cpu, rs1: RegIndex, rs2: RegIndex, imm5: u32, imm12: u32) {
     let imm64: i64 = decode_immediate_btype(imm5, imm12);
-
-    if curcpu.read_reg(rs1) == curcpu.read_reg(rs2) {
-        curcpu.set_next_pc_rel(imm64);
-    }
-    if curcpu.is_debug_mode() {
-        curcpu.set_debug_string(format!("{} {}, {}, {}",
-        "beq".blue(), REG_FILE_NAMES[rs1 as usize].red(), REG_FILE_NAMES[rs2 as usize].red(),
-        imm64));
-    }
+    let taken: bool = curcpu.read_reg(rs1) == curcpu.read_reg(rs2);
+    curcpu.resolve_branch(taken, imm64);
 }
 
 // BNE instruction
 // if (rs1 != rs2) { pc = pc + signed'immediate }
 #[inline(always)]
-fn bne(curcpu: &mut Cpu, rs1: RegIndex, rs2: RegIndex, imm5: u32, imm12: u32) {
+fn bne<const DEBUG: bool>(curcpu: &mut Cpu, rs1: RegIndex, rs2: RegIndex, imm5: u32, imm12: u32) {
     let imm64: i64 = decode_immediate_btype(imm5, imm12);
-
-    if curcpu.read_reg(rs1) != curcpu.read_reg(rs2) {
-        curcpu.set_next_pc_rel(imm64);
-    }
-    if curcpu.is_debug_mode() {
-        curcpu.set_debug_string(format!("{} {}, {}, {}",
-        "bne".blue(), REG_FILE_NAMES[rs1 as usize].red(), REG_FILE_NAMES[rs2 as usize].red(),
-        imm64));
-    }
+    let taken: bool = curcpu.read_reg(rs1) != curcpu.read_reg(rs2);
+    curcpu.resolve_branch(taken, imm64);
 }
 
 // BLT instruction
 // if (singed'rs1 < signed'rs2) { pc = pc + signed'immediate }
 #[inline(always)]
-fn blt(curcpu: &mut Cpu, rs1: RegIndex, rs2: RegIndex, imm5: u32, imm12: u32) {
+fn blt<const DEBUG: bool>(curcpu: &mut Cpu, rs1: RegIndex, rs2: RegIndex, imm5: u32, imm12: u32) {
     let imm64: i64 = decode_immediate_btype(imm5, imm12);
-
-    if (curcpu.read_reg(rs1) as i64) < curcpu.read_reg(rs2) as i64 {
-        curcpu.set_next_pc_rel(imm64);
-    }
-    if curcpu.is_debug_mode() {
-        curcpu.set_debug_string(format!("{} {}, {}, {}",
-        "blt".blue(), REG_FILE_NAMES[rs1 as usize].red(), REG_FILE_NAMES[rs2 as usize].red(),
-        imm64));
-    }
+    let taken: bool = (curcpu.read_reg(rs1) as i64) < curcpu.read_reg(rs2) as i64;
+    curcpu.resolve_branch(taken, imm64);
 }
 
 // BGE instruction
 // if (signed'rs1 >= signed'rs2) { pc = pc + signed'immediate }
 #[inline(always)]
-fn bge(curcpu: &mut Cpu, rs1: RegIndex, rs2: RegIndex, imm5: u32, imm12: u32) {
+fn bge<const DEBUG: bool>(curcpu: &mut Cpu, rs1: RegIndex, rs2: RegIndex, imm5: u32, imm12: u32) {
     let imm64: i64 = decode_immediate_btype(imm5, imm12);
-
-    if curcpu.read_reg(rs1) as i64 >= curcpu.read_reg(rs2) as i64 {
-        curcpu.set_next_pc_rel(imm64);
-    }
-    if curcpu.is_debug_mode() {
-        curcpu.set_debug_string(format!("{} {}, {}, {}",
-        "bge".blue(), REG_FILE_NAMES[rs1 as usize].red(), REG_FILE_NAMES[rs2 as usize].red(),
-        imm64));
-    }
+    let taken: bool = curcpu.read_reg(rs1) as i64 >= curcpu.read_reg(rs2) as i64;
+    curcpu.resolve_branch(taken, imm64);
 }
 
 // BLTU instruction
 // if (unsigned'rs1 < unsigned'rs2) { pc = pc + signed'immediate }
 #[inline(always)]
-fn bltu(curcpu: &mut Cpu, rs1: RegIndex, rs2: RegIndex, imm5: u32, imm12: u32) {
+fn bltu<const DEBUG: bool>(curcpu: &mut Cpu, rs1: RegIndex, rs2: RegIndex, imm5: u32, imm12: u32) {
     let imm64: i64 = decode_immediate_btype(imm5, imm12);
-
-    if curcpu.read_reg(rs1) < curcpu.read_reg(rs2) {
-        curcpu.set_next_pc_rel(imm64);
-    }
-    if curcpu.is_debug_mode() {
-        curcpu.set_debug_string(format!("{} {}, {}, {}",
-        "bltu".blue(), REG_FILE_NAMES[rs1 as usize].red(), REG_FILE_NAMES[rs2 as usize].red(),
-        imm64));
-    }
+    let taken: bool = curcpu.read_reg(rs1) < curcpu.read_reg(rs2);
+    curcpu.resolve_branch(taken, imm64);
 }
 
 // BGEU instruction
 // if (unsigned'rs1 >= unsigned'rs2) { pc = pc + signed'immediate }
 #[inline(always)]
-fn bgeu(curcpu: &mut Cpu, rs1: RegIndex, rs2: RegIndex, imm5: u32, imm12: u32) {
+fn bgeu<const DEBUG: bool>(curcpu: &mut Cpu, rs1: RegIndex, rs2: RegIndex, imm5: u32, imm12: u32) {
     let imm64: i64 = decode_immediate_btype(imm5, imm12);
-
-    if curcpu.read_reg(rs1) >= curcpu.read_reg(rs2) {
-        curcpu.set_next_pc_rel(imm64);
-    }
-    if curcpu.is_debug_mode() {
-        curcpu.set_debug_string(format!("{} {}, {}, {}",
-        "bgeu".blue(), REG_FILE_NAMES[rs1 as usize].red(), REG_FILE_NAMES[rs2 as usize].red(),
-        imm64));
-    }
+    let taken: bool = curcpu.read_reg(rs1) >= curcpu.read_reg(rs2);
+    curcpu.resolve_branch(taken, imm64);
 }
 
 // LB instruction
 // rd <- memory[signed'rs1 + signed'imm][7:0]
 #[inline(always)]
-fn lb(curcpu: &mut Cpu, rs1: RegIndex, rd: RegIndex, imm12: u32) {
+fn lb<const DEBUG: bool>(curcpu: &mut Cpu, rs1: RegIndex, rd: RegIndex, imm12: u32) -> Result<(), Trap> {
     let addr: u64 = (curcpu.read_reg(rs1) as i64 + imm12 as i32 as i64) as u64;
-    let data: i64 = curcpu.load(addr, AccessSize::BYTE) as i8 as i64;
+    let data: i64 = curcpu.load(addr, AccessSize::BYTE)? as i8 as i64;
     curcpu.write_reg(rd, data as u64);
-    if curcpu.is_debug_mode() {
-        curcpu.set_debug_string(format!("{} {}, {}({})",
-        "lb".blue(), REG_FILE_NAMES[rd as usize].red(), imm12 as i32, REG_FILE_NAMES[rs1 as usize].red()));
-    }
+    Ok(())
 }
 
 // LH instruction
 // rd <- memory[signed'rs1 + signed'imm][15:0]
 #[inline(always)]
-fn lh(curcpu: &mut Cpu, rs1: RegIndex, rd: RegIndex, imm12: u32) {
+fn lh<const DEBUG: bool>(curcpu: &mut Cpu, rs1: RegIndex, rd: RegIndex, imm12: u32) -> Result<(), Trap> {
     let addr: u64 = (curcpu.read_reg(rs1) as i64 + imm12 as i32 as i64) as u64;
-    let data: i64 = curcpu.load(addr, AccessSize::HALFWORD) as i16 as i64;
+    let data: i64 = curcpu.load(addr, AccessSize::HALFWORD)? as i16 as i64;
     curcpu.write_reg(rd, data as u64);
-    if curcpu.is_debug_mode() {
-        curcpu.set_debug_string(format!("{} {}, {}({})",
-        "lh".blue(), REG_FILE_NAMES[rd as usize].red(), imm12 as i32, REG_FILE_NAMES[rs1 as usize].red()));
-    }
+    Ok(())
 }
 
 // LW instruction
 // rd <- memory[signed'rs1 + signed'imm][31:0]
 #[inline(always)]
-fn lw(curcpu: &mut Cpu, rs1: RegIndex, rd: RegIndex, imm12: u32) {
+fn lw<const DEBUG: bool>(curcpu: &mut Cpu, rs1: RegIndex, rd: RegIndex, imm12: u32) -> Result<(), Trap> {
     let addr: u64 = (curcpu.read_reg(rs1) as i64 + imm12 as i32 as i64) as u64;
-    let data: i64 = curcpu.load(addr, AccessSize::WORD) as i32 as i64;
+    let data: i64 = curcpu.load(addr, AccessSize::WORD)? as i32 as i64;
     curcpu.write_reg(rd, data as u64);
-    if curcpu.is_debug_mode() {
-        curcpu.set_debug_string(format!("{} {}, {}({})",
-        "lw".blue(), REG_FILE_NAMES[rd as usize].red(), imm12 as i32, REG_FILE_NAMES[rs1 as usize].red()));
-    }
+    Ok(())
 }
 
 // LD instruction
 // rd <- memory[signed'rs1 + signed'imm][63:0]
 #[inline(always)]
-fn ld(curcpu: &mut Cpu, rs1: RegIndex, rd: RegIndex, imm12: u32) {
+fn ld<const DEBUG: bool>(curcpu: &mut Cpu, rs1: RegIndex, rd: RegIndex, imm12: u32) -> Result<(), Trap> {
     let addr: u64 = (curcpu.read_reg(rs1) as i64 + imm12 as i32 as i64) as u64;
-    let data: u64 = curcpu.load(addr, AccessSize::DOUBLEWORD);
+    let data: u64 = curcpu.load(addr, AccessSize::DOUBLEWORD)?;
     curcpu.write_reg(rd, data);
-    if curcpu.is_debug_mode() {
-        curcpu.set_debug_string(format!("{} {}, {}({})",
-        "ld".blue(), REG_FILE_NAMES[rd as usize].red(), imm12 as i32, REG_FILE_NAMES[rs1 as usize].red()));
-    }
+    Ok(())
 }
 
 // LBU instruction
 // rd <- memory[rs1 + unsigned'(signed'imm)][7:0]
 #[inline(always)]
-fn lbu(curcpu: &mut Cpu, rs1: RegIndex, rd: RegIndex, imm12: u32) {
+fn lbu<const DEBUG: bool>(curcpu: &mut Cpu, rs1: RegIndex, rd: RegIndex, imm12: u32) -> Result<(), Trap> {
     let addr: u64 = (curcpu.read_reg(rs1) as i64 + imm12 as i32 as i64) as u64;
-    let data: u64 = curcpu.load(addr, AccessSize::BYTE);
+    let data: u64 = curcpu.load(addr, AccessSize::BYTE)?;
     curcpu.write_reg(rd, data);
-    if curcpu.is_debug_mode() {
-        curcpu.set_debug_string(format!("{} {}, {}({})",
-        "lbu".blue(), REG_FILE_NAMES[rd as usize].red(), imm12 as i32, REG_FILE_NAMES[rs1 as usize].red()));
-    }
+    Ok(())
 }
 
 // LHU instruction
 // rd <- memory[rs1 + unsigned'(signed'imm)][15:0]
 #[inline(always)]
-fn lhu(curcpu: &mut Cpu, rs1: RegIndex, rd: RegIndex, imm12: u32) {
+fn lhu<const DEBUG: bool>(curcpu: &mut Cpu, rs1: RegIndex, rd: RegIndex, imm12: u32) -> Result<(), Trap> {
     let addr: u64 = (curcpu.read_reg(rs1) as i64 + imm12 as i32 as i64) as u64;
-    let data: u64 = curcpu.load(addr, AccessSize::HALFWORD);
+    let data: u64 = curcpu.load(addr, AccessSize::HALFWORD)?;
     curcpu.write_reg(rd, data);
-    if curcpu.is_debug_mode() {
-        curcpu.set_debug_string(format!("{} {}, {}({})",
-        "lhu".blue(), REG_FILE_NAMES[rd as usize].red(), imm12 as i32, REG_FILE_NAMES[rs1 as usize].red()));
-    }
+    Ok(())
 }
 
 // LWU instruction
 // rd <- memory[signed'rs1 + signed'imm][63:0]
 #[inline(always)]
-fn lwu(curcpu: &mut Cpu, rs1: RegIndex, rd: RegIndex, imm12: u32) {
+fn lwu<const DEBUG: bool>(curcpu: &mut Cpu, rs1: RegIndex, rd: RegIndex, imm12: u32) -> Result<(), Trap> {
     let addr: u64 = (curcpu.read_reg(rs1) as i64 + imm12 as i32 as i64) as u64;
-    let data: u64 = curcpu.load(addr, AccessSize::WORD);
+    let data: u64 = curcpu.load(addr, AccessSize::WORD)?;
     curcpu.write_reg(rd, data);
-    if curcpu.is_debug_mode() {
-        curcpu.set_debug_string(format!("{} {}, {}({})",
-        "lwu".blue(), REG_FILE_NAMES[rd as usize].red(), imm12 as i32, REG_FILE_NAMES[rs1 as usize].red()));
-    }
+    Ok(())
 }
 
 // SB instruction
 // memory[signed'rs1 + imm] = rs2[7:0]
 #[inline(always)]
-fn sb(curcpu: &mut Cpu, rs1: RegIndex, imm12: u32, imm5: u32) {
+fn sb<const DEBUG: bool>(curcpu: &mut Cpu, rs1: RegIndex, imm12: u32, imm5: u32) -> Result<(), Trap> {
     let rs2: RegIndex = (imm12 & 0x1f) as RegIndex;
     let data: u64 = curcpu.read_reg(rs2);
     let imm: i64 = decode_immediate_stype(imm5, imm12);
     let addr: u64 = (curcpu.read_reg(rs1) as i64 + imm) as u64;
-    curcpu.store(data, addr, AccessSize::BYTE);
-    if curcpu.is_debug_mode() {
-        curcpu.set_debug_string(format!("{} {}, {}({})",
-        "sb".blue(), REG_FILE_NAMES[rs1 as usize].red(), imm, REG_FILE_NAMES[rs2 as usize].red()));
+    if DEBUG {
+        curcpu.log_memory_write(addr, AccessSize::BYTE, data);
     }
+    curcpu.store(data, addr, AccessSize::BYTE)?;
+    Ok(())
 }
 
 // SH instruction
 // memory[signed'rs1 + imm] = rs2[15:0]
 #[inline(always)]
-fn sh(curcpu: &mut Cpu, rs1: RegIndex, imm12: u32, imm5: u32) {
+fn sh<const DEBUG: bool>(curcpu: &mut Cpu, rs1: RegIndex, imm12: u32, imm5: u32) -> Result<(), Trap> {
     let rs2: RegIndex = (imm12 & 0x1f) as RegIndex;
     let data: u64 = curcpu.read_reg(rs2);
     let imm: i64 = decode_immediate_stype(imm5, imm12);
     let addr: u64 = (curcpu.read_reg(rs1) as i64 + imm) as u64;
-    curcpu.store(data, addr, AccessSize::HALFWORD);
-    if curcpu.is_debug_mode() {
-        curcpu.set_debug_string(format!("{} {}, {}({})",
-        "sh".blue(), REG_FILE_NAMES[rs1 as usize].red(), imm, REG_FILE_NAMES[rs2 as usize].red()));
+    if DEBUG {
+        curcpu.log_memory_write(addr, AccessSize::HALFWORD, data);
     }
+    curcpu.store(data, addr, AccessSize::HALFWORD)?;
+    Ok(())
 }
 
 // SW instruction
 // memory[signed'rs1 + imm] = rs2[31:0]
 #[inline(always)]
-fn sw(curcpu: &mut Cpu, rs1: RegIndex, imm12: u32, imm5: u32) {
+fn sw<const DEBUG: bool>(curcpu: &mut Cpu, rs1: RegIndex, imm12: u32, imm5: u32) -> Result<(), Trap> {
     let rs2: RegIndex = (imm12 & 0x1f) as RegIndex;
     let data: u64 = curcpu.read_reg(rs2);
     let imm: i64 = decode_immediate_stype(imm5, imm12);
     let addr: u64 = (curcpu.read_reg(rs1) as i64 + imm) as u64;
-    curcpu.store(data, addr, AccessSize::WORD);
-    if curcpu.is_debug_mode() {
-        curcpu.set_debug_string(format!("{} {}, {}({})",
-        "sw".blue(), REG_FILE_NAMES[rs1 as usize].red(), imm, REG_FILE_NAMES[rs2 as usize].red()));
+    if DEBUG {
+        curcpu.log_memory_write(addr, AccessSize::WORD, data);
     }
+    curcpu.store(data, addr, AccessSize::WORD)?;
+    Ok(())
 }
 
 // SD instruction
 // memory[signed'rs1 + imm] = rs2[63:0]
 #[inline(always)]
-fn sd(curcpu: &mut Cpu, rs1: RegIndex, imm12: u32, imm5: u32) {
+fn sd<const DEBUG: bool>(curcpu: &mut Cpu, rs1: RegIndex, imm12: u32, imm5: u32) -> Result<(), Trap> {
     let rs2: RegIndex = (imm12 & 0x1f) as RegIndex;
     let data: u64 = curcpu.read_reg(rs2);
     let imm: i64 = decode_immediate_stype(imm5, imm12);
     let addr: u64 = (curcpu.read_reg(rs1) as i64 + imm) as u64;
-    curcpu.store(data, addr, AccessSize::DOUBLEWORD);
-    if curcpu.is_debug_mode() {
-        curcpu.set_debug_string(format!("{} {}, {}({})",
-        "sd".blue(), REG_FILE_NAMES[rs1 as usize].red(), imm, REG_FILE_NAMES[rs2 as usize].red()));
+    if DEBUG {
+        curcpu.log_memory_write(addr, AccessSize::DOUBLEWORD, data);
     }
+    curcpu.store(data, addr, AccessSize::DOUBLEWORD)?;
+    Ok(())
 }
 
 // ADDI instruction
 // rd <- rs1 + imm
 #[inline(always)]
-fn addi(curcpu: &mut Cpu, rs1: RegIndex, rd: RegIndex, imm12: u32) {
+fn addi<const DEBUG: bool>(curcpu: &mut Cpu, rs1: RegIndex, rd: RegIndex, imm12: u32) {
     let first_operand: i64 = curcpu.read_reg(rs1) as i64;
     let second_operand: i64 = imm12 as i32 as i64;
-    curcpu.write_reg(rd, (first_operand + second_operand) as u64);
+    curcpu.write_reg(rd, first_operand.wrapping_add(second_operand) as u64);
 
-    if curcpu.is_debug_mode() {
-        curcpu.set_debug_string(format!("{} {}, {}, {}",
-        "addi".blue(), REG_FILE_NAMES[rd as usize].red(), REG_FILE_NAMES[rs1 as usize].red(), second_operand));
-    }
 }
 
 // SLTI instruction
 // rd <- (rs1 < imm) ? 1 : 0
 #[inline(always)]
-fn slti(curcpu: &mut Cpu, rs1: RegIndex, rd: RegIndex, imm12: u32) {
+fn slti<const DEBUG: bool>(curcpu: &mut Cpu, rs1: RegIndex, rd: RegIndex, imm12: u32) {
     let first_operand: i64 = curcpu.read_reg(rs1) as i64;
     let second_operand: i64 = imm12 as i32 as i64;
     if first_operand < second_operand {
@@ -542,16 +619,12 @@ fn slti(curcpu: &mut Cpu, rs1: RegIndex, rd: RegIndex, imm12: u32) {
         curcpu.write_reg(rd, 0x0);
     }
 
-    if curcpu.is_debug_mode() {
-        curcpu.set_debug_string(format!("{} {}, {}, {}",
-        "slti".blue(), REG_FILE_NAMES[rd as usize].red(), REG_FILE_NAMES[rs1 as usize].red(), second_operand));
-    }
 }
 
 // SLTIU instruction
 // rd <- (unsigned'rs1 < unsigned'imm) ? 1 : 0
 #[inline(always)]
-fn sltiu(curcpu: &mut Cpu, rs1: RegIndex, rd: RegIndex, imm12: u32) {
+fn sltiu<const DEBUG: bool>(curcpu: &mut Cpu, rs1: RegIndex, rd: RegIndex, imm12: u32) {
     let first_operand: u64 = curcpu.read_reg(rs1);
     let second_operand: u64 = imm12 as i32 as i64 as u64;
     if first_operand < second_operand {
@@ -560,306 +633,215 @@ fn sltiu(curcpu: &mut Cpu, rs1: RegIndex, rd: RegIndex, imm12: u32) {
         curcpu.write_reg(rd, 0x0);
     }
 
-    if curcpu.is_debug_mode() {
-        curcpu.set_debug_string(format!("{} {}, {}, {}",
-        "sltiu".blue(), REG_FILE_NAMES[rd as usize].red(), REG_FILE_NAMES[rs1 as usize].red(), second_operand));
-    }
 }
 
 // XORI instruction
 // rd <- rs1 ^ imm
 #[inline(always)]
-fn xori(curcpu: &mut Cpu, rs1: RegIndex, rd: RegIndex, imm12: u32) {
+fn xori<const DEBUG: bool>(curcpu: &mut Cpu, rs1: RegIndex, rd: RegIndex, imm12: u32) {
     let first_operand: i64 = curcpu.read_reg(rs1) as i64;
     let second_operand: i64 = imm12 as i32 as i64;
     curcpu.write_reg(rd, (first_operand ^ second_operand) as u64);
-    if curcpu.is_debug_mode() {
-        curcpu.set_debug_string(format!("{} {}, {}, {}",
-        "xori".blue(), REG_FILE_NAMES[rd as usize].red(), REG_FILE_NAMES[rs1 as usize].red(), second_operand));
-    }
 }
 
 // ORI instruction
 // rd <- rs1 | imm
 #[inline(always)]
-fn ori(curcpu: &mut Cpu, rs1: RegIndex, rd: RegIndex, imm12: u32) {
+fn ori<const DEBUG: bool>(curcpu: &mut Cpu, rs1: RegIndex, rd: RegIndex, imm12: u32) {
     let first_operand: i64 = curcpu.read_reg(rs1) as i64;
     let second_operand: i64 = imm12 as i32 as i64;
     curcpu.write_reg(rd, (first_operand | second_operand) as u64);
-    if curcpu.is_debug_mode() {
-        curcpu.set_debug_string(format!("{} {}, {}, {}",
-        "ori".blue(), REG_FILE_NAMES[rd as usize].red(), REG_FILE_NAMES[rs1 as usize].red(), second_operand));
-    }
 }
 
 // SLLI instruction
 // rd <- unsigned'rs1 << imm
 #[inline(always)]
-fn slli(curcpu: &mut Cpu, rs1: RegIndex, rd: RegIndex, imm12: u32) {
+fn slli<const DEBUG: bool>(curcpu: &mut Cpu, rs1: RegIndex, rd: RegIndex, imm12: u32) {
     let first_operand: u64 = curcpu.read_reg(rs1);
-    let second_operand: u8 = (imm12 & 0x3f) as u8;
+    let second_operand: u8 = (imm12 as u64 & curcpu.get_xlen().shamt_mask()) as u8;
     curcpu.write_reg(rd, first_operand << second_operand);
-    if curcpu.is_debug_mode() {
-        curcpu.set_debug_string(format!("{} {}, {}, {}",
-        "slli".blue(), REG_FILE_NAMES[rd as usize].red(), REG_FILE_NAMES[rs1 as usize].red(), second_operand));
-    }
 }
 
 // SLLIW instruction
-// rd <- unsigned'rs1 << imm
+// rd <- signed'(rs1[31:0] << imm)
 #[inline(always)]
-fn slliw(curcpu: &mut Cpu, rs1: RegIndex, rd: RegIndex, imm12: u32) {
-    let first_operand: u64 = curcpu.read_reg(rs1);
+fn slliw<const DEBUG: bool>(curcpu: &mut Cpu, rs1: RegIndex, rd: RegIndex, imm12: u32) {
+    let first_operand: u32 = curcpu.read_reg(rs1) as u32;
     let second_operand: u8 = (imm12 & 0x1f) as u8;
-    curcpu.write_reg(rd, first_operand << second_operand);
-    if curcpu.is_debug_mode() {
-        curcpu.set_debug_string(format!("{} {}, {}, {}",
-        "slliw".blue(), REG_FILE_NAMES[rd as usize].red(), REG_FILE_NAMES[rs1 as usize].red(), second_operand));
-    }
+    curcpu.write_reg(rd, (first_operand << second_operand) as i32 as i64 as u64);
 }
 
 // SRLI and SRAI instruction
 // rd <- unsigned'rs1 >> imm (SRLI)
-// rd <- signed'rs1 | imm    (SRAI)
+// rd <- signed'rs1 >> imm   (SRAI)
 #[inline(always)]
-fn srli_srai(curcpu: &mut Cpu, rs1: RegIndex, rd: RegIndex, imm12: u32) {
-    let first_operand: u64 = curcpu.read_reg(rs1);
-    let second_operand: u8 = (imm12 & 0x3f) as u8;
+fn srli_srai<const DEBUG: bool>(curcpu: &mut Cpu, rs1: RegIndex, rd: RegIndex, imm12: u32) {
+    let xlen: Xlen = curcpu.get_xlen();
+    let second_operand: u8 = (imm12 as u64 & xlen.shamt_mask()) as u8;
     // if the 11th bit of the immediate is 0b1 -> SRAI, otherwise SRLI
     if imm12 >> 10 == 0b1 {
+        // In RV32 mode the upper bits of rs1 are already a sign extension,
+        // so the 64 bits shift fills the low word with the sign bit too
+        let first_operand: i64 = curcpu.read_reg(rs1) as i64;
         curcpu.write_reg(rd, (first_operand >> second_operand) as u64);
-        if curcpu.is_debug_mode() {
-            curcpu.set_debug_string(format!("{} {}, {}, {}",
-            "srai".blue(), REG_FILE_NAMES[rd as usize].red(), REG_FILE_NAMES[rs1 as usize].red(), second_operand));
-        }
     } else {
+        // In RV32 mode the upper bits of rs1 are a sign extension, not shifted in
+        let first_operand: u64 = curcpu.read_reg(rs1) & xlen.mask();
         curcpu.write_reg(rd, first_operand >> second_operand);
-        if curcpu.is_debug_mode() {
-            curcpu.set_debug_string(format!("{} {}, {}, {}",
-            "srli".blue(), REG_FILE_NAMES[rd as usize].red(), REG_FILE_NAMES[rs1 as usize].red(), second_operand));
-        }
     }
 }
 
 // SRLIW and SRAIW instruction
-// rd <- unsigned'rs1 >> imm (SRLI)
-// rd <- signed'rs1 | imm    (SRAI)
+// rd <- signed'(unsigned'rs1[31:0] >> imm) (SRLIW)
+// rd <- signed'rs1[31:0] >> imm            (SRAIW)
 #[inline(always)]
-fn srliw_sraiw(curcpu: &mut Cpu, rs1: RegIndex, rd: RegIndex, imm12: u32) {
-    let first_operand: u64 = curcpu.read_reg(rs1);
+fn srliw_sraiw<const DEBUG: bool>(curcpu: &mut Cpu, rs1: RegIndex, rd: RegIndex, imm12: u32) {
     let second_operand: u8 = (imm12 & 0x1f) as u8;
     // if the 11th bit of the immediate is 0b1 -> SRAIW, otherwise SRLIW
     if imm12 >> 10 == 0b1 {
-        curcpu.write_reg(rd, (first_operand >> second_operand) as u64);
-        if curcpu.is_debug_mode() {
-            curcpu.set_debug_string(format!("{} {}, {}, {}",
-            "sraiw".blue(), REG_FILE_NAMES[rd as usize].red(), REG_FILE_NAMES[rs1 as usize].red(), second_operand));
-        }
+        let first_operand: i32 = curcpu.read_reg(rs1) as i32;
+        curcpu.write_reg(rd, (first_operand >> second_operand) as i64 as u64);
     } else {
-        curcpu.write_reg(rd, first_operand >> second_operand);
-        if curcpu.is_debug_mode() {
-            curcpu.set_debug_string(format!("{} {}, {}, {}",
-            "srliw".blue(), REG_FILE_NAMES[rd as usize].red(),REG_FILE_NAMES[rs1 as usize].red(), second_operand));
-        }
+        let first_operand: u32 = curcpu.read_reg(rs1) as u32;
+        curcpu.write_reg(rd, (first_operand >> second_operand) as i32 as i64 as u64);
     }
 }
 
 // ANDI instruction
 // rd <- rs1 | imm
 #[inline(always)]
-fn andi(curcpu: &mut Cpu, rs1: RegIndex, rd: RegIndex, imm12: u32) {
+fn andi<const DEBUG: bool>(curcpu: &mut Cpu, rs1: RegIndex, rd: RegIndex, imm12: u32) {
     let first_operand: i64 = curcpu.read_reg(rs1) as i64;
     let second_operand: i64 = imm12 as i32 as i64;
     curcpu.write_reg(rd, (first_operand & second_operand) as u64);
-    if curcpu.is_debug_mode() {
-        curcpu.set_debug_string(format!("{} {}, {}, {}",
-        "andi".blue(), REG_FILE_NAMES[rd as usize].red(), REG_FILE_NAMES[rs1 as usize].red(), second_operand));
-    }
 }
 
 // ADD instruction
 // rd <- rs1 + rs2
 #[inline(always)]
-fn add(curcpu: &mut Cpu, rs1: RegIndex, rs2: RegIndex, rd: RegIndex) {
+fn add<const DEBUG: bool>(curcpu: &mut Cpu, rs1: RegIndex, rs2: RegIndex, rd: RegIndex) {
     let first_operand: i64 = curcpu.read_reg(rs1) as i64;
     let second_operand: i64 = curcpu.read_reg(rs2) as i64;
-    curcpu.write_reg(rd, (first_operand + second_operand) as u64);
-    if curcpu.is_debug_mode() {
-        curcpu.set_debug_string(format!("{} {}, {}, {}",
-        "add".blue(), REG_FILE_NAMES[rd as usize].red(), REG_FILE_NAMES[rs1 as usize].red(),
-        REG_FILE_NAMES[rs2 as usize].red()));
-    }
+    curcpu.write_reg(rd, first_operand.wrapping_add(second_operand) as u64);
 }
 
 // ADDW instruction
 // rd <- signed'(rs1[31:0] + rs2[31:0])
 #[inline(always)]
-fn addw(curcpu: &mut Cpu, rs1: RegIndex, rs2: RegIndex, rd: RegIndex) {
+fn addw<const DEBUG: bool>(curcpu: &mut Cpu, rs1: RegIndex, rs2: RegIndex, rd: RegIndex) {
     let first_operand: i32 = curcpu.read_reg(rs1) as i32;
     let second_operand: i32 = curcpu.read_reg(rs2) as i32;
-    curcpu.write_reg(rd, (first_operand + second_operand) as i64 as u64);
-    if curcpu.is_debug_mode() {
-        curcpu.set_debug_string(format!("{} {}, {}, {}",
-        "addw".blue(), REG_FILE_NAMES[rd as usize].red(), REG_FILE_NAMES[rs1 as usize].red(),
-        REG_FILE_NAMES[rs2 as usize].red()));
-    }
+    curcpu.write_reg(rd, first_operand.wrapping_add(second_operand) as i64 as u64);
 }
 
 // SUB instruction
 // rd <- rs1 - rs2
 #[inline(always)]
-fn sub(curcpu: &mut Cpu, rs1: RegIndex, rs2: RegIndex, rd: RegIndex) {
+fn sub<const DEBUG: bool>(curcpu: &mut Cpu, rs1: RegIndex, rs2: RegIndex, rd: RegIndex) {
     let first_operand: i64 = curcpu.read_reg(rs1) as i64;
     let second_operand: i64 = curcpu.read_reg(rs2) as i64;
-    curcpu.write_reg(rd, (first_operand - second_operand) as u64);
-    if curcpu.is_debug_mode() {
-        curcpu.set_debug_string(format!("{} {}, {}, {}",
-        "sub".blue(), REG_FILE_NAMES[rd as usize].red(), REG_FILE_NAMES[rs1 as usize].red(),
-        REG_FILE_NAMES[rs2 as usize].red()));
-    }
+    curcpu.write_reg(rd, first_operand.wrapping_sub(second_operand) as u64);
 }
 
 // SUBW instruction
 // rd <- signed'(rs1[31:0] - rs2[31:0])
 #[inline(always)]
-fn subw(curcpu: &mut Cpu, rs1: RegIndex, rs2: RegIndex, rd: RegIndex) {
+fn subw<const DEBUG: bool>(curcpu: &mut Cpu, rs1: RegIndex, rs2: RegIndex, rd: RegIndex) {
     let first_operand: i32 = curcpu.read_reg(rs1) as i32;
     let second_operand: i32 = curcpu.read_reg(rs2) as i32;
-    curcpu.write_reg(rd, (first_operand - second_operand) as i64 as u64);
-    if curcpu.is_debug_mode() {
-        curcpu.set_debug_string(format!("{} {}, {}, {}",
-        "subw".blue(), REG_FILE_NAMES[rd as usize].red(), REG_FILE_NAMES[rs1 as usize].red(),
-        REG_FILE_NAMES[rs2 as usize].red()));
-    }
+    curcpu.write_reg(rd, first_operand.wrapping_sub(second_operand) as i64 as u64);
 }
 
 // SLL instruction
 // rd <- rs1 << rs2[4:0]
 #[inline(always)]
-fn sll(curcpu: &mut Cpu, rs1: RegIndex, rs2: RegIndex, rd: RegIndex) {
-    curcpu.write_reg(rd, curcpu.read_reg(rs1) << (curcpu.read_reg(rs2) & 0x3f));
-    if curcpu.is_debug_mode() {
-        curcpu.set_debug_string(format!("{} {}, {}, {}",
-        "sll".blue(), REG_FILE_NAMES[rd as usize].red(), REG_FILE_NAMES[rs1 as usize].red(),
-        REG_FILE_NAMES[rs2 as usize].red()));
-    }
+fn sll<const DEBUG: bool>(curcpu: &mut Cpu, rs1: RegIndex, rs2: RegIndex, rd: RegIndex) {
+    curcpu.write_reg(rd, curcpu.read_reg(rs1) << (curcpu.read_reg(rs2) & curcpu.get_xlen().shamt_mask()));
 }
 
 // SLLW instruction
-// rd <- rs1 << rs2[4:0]
+// rd <- signed'(rs1[31:0] << rs2[4:0])
 #[inline(always)]
-fn sllw(curcpu: &mut Cpu, rs1: RegIndex, rs2: RegIndex, rd: RegIndex) {
+fn sllw<const DEBUG: bool>(curcpu: &mut Cpu, rs1: RegIndex, rs2: RegIndex, rd: RegIndex) {
     let first_operand: u32 = curcpu.read_reg(rs1) as u32;
     let second_operand: u64= curcpu.read_reg(rs2) & 0x1f;
-    curcpu.write_reg(rd, (first_operand << second_operand) as u64);
-    if curcpu.is_debug_mode() {
-        curcpu.set_debug_string(format!("{} {}, {}, {}",
-        "sllw".blue(), REG_FILE_NAMES[rd as usize].red(), REG_FILE_NAMES[rs1 as usize].red(),
-        REG_FILE_NAMES[rs2 as usize].red()));
-    }
+    curcpu.write_reg(rd, (first_operand << second_operand) as i32 as i64 as u64);
 }
 
 // SLT instruction
 // rd <- (rs1 < rs2) ? 1 : 0
 #[inline(always)]
-fn slt(curcpu: &mut Cpu, rs1: RegIndex, rs2: RegIndex, rd: RegIndex) {
+fn slt<const DEBUG: bool>(curcpu: &mut Cpu, rs1: RegIndex, rs2: RegIndex, rd: RegIndex) {
     if (curcpu.read_reg(rs1) as i64) < (curcpu.read_reg(rs2) as i64) {
         curcpu.write_reg(rd, 0b1);
     } else {
         curcpu.write_reg(rd, 0b0);
     }
-    if curcpu.is_debug_mode() {
-        curcpu.set_debug_string(format!("{} {}, {}, {}",
-        "slt".blue(), REG_FILE_NAMES[rd as usize].red(), REG_FILE_NAMES[rs1 as usize].red(),
-        REG_FILE_NAMES[rs2 as usize].red()));
-    }
 }
 
 // SLTU instruction
 // rd <- (rs1 < rs2) ? 1 : 0
 #[inline(always)]
-fn sltu(curcpu: &mut Cpu, rs1: RegIndex, rs2: RegIndex, rd: RegIndex) {
+fn sltu<const DEBUG: bool>(curcpu: &mut Cpu, rs1: RegIndex, rs2: RegIndex, rd: RegIndex) {
     if curcpu.read_reg(rs1) < curcpu.read_reg(rs2) {
         curcpu.write_reg(rd, 0b1);
     } else {
         curcpu.write_reg(rd, 0b0);
     }
-    if curcpu.is_debug_mode() {
-        curcpu.set_debug_string(format!("{} {}, {}, {}",
-        "sltu".blue(), REG_FILE_NAMES[rd as usize].red(), REG_FILE_NAMES[rs1 as usize].red(),
-        REG_FILE_NAMES[rs2 as usize].red()));
-    }
 }
 
 // XOR instruction
 // rd <- rs1 xor rs2
 #[inline(always)]
-fn xor(curcpu: &mut Cpu, rs1: RegIndex, rs2: RegIndex, rd: RegIndex) {
+fn xor<const DEBUG: bool>(curcpu: &mut Cpu, rs1: RegIndex, rs2: RegIndex, rd: RegIndex) {
     curcpu.write_reg(rd, curcpu.read_reg(rs1) ^ (curcpu.read_reg(rs2)));
-    if curcpu.is_debug_mode() {
-        curcpu.set_debug_string(format!("{} {}, {}, {}",
-        "xor".blue(), REG_FILE_NAMES[rd as usize].red(), REG_FILE_NAMES[rs1 as usize].red(),
-        REG_FILE_NAMES[rs2 as usize].red()));
-    }
 }
 
 // OR instruction
 // rd <- rs1 xor rs2
 #[inline(always)]
-fn or(curcpu: &mut Cpu, rs1: RegIndex, rs2: RegIndex, rd: RegIndex) {
+fn or<const DEBUG: bool>(curcpu: &mut Cpu, rs1: RegIndex, rs2: RegIndex, rd: RegIndex) {
     curcpu.write_reg(rd, curcpu.read_reg(rs1) | (curcpu.read_reg(rs2)));
-    if curcpu.is_debug_mode() {
-        curcpu.set_debug_string(format!("{} {}, {}, {}",
-        "or".blue(), REG_FILE_NAMES[rd as usize].red(), REG_FILE_NAMES[rs1 as usize].red(),
-        REG_FILE_NAMES[rs2 as usize].red()));
-    }
 }
 
 // AND instruction
 // rd <- rs1 xor rs2
 #[inline(always)]
-fn and(curcpu: &mut Cpu, rs1: RegIndex, rs2: RegIndex, rd: RegIndex) {
+fn and<const DEBUG: bool>(curcpu: &mut Cpu, rs1: RegIndex, rs2: RegIndex, rd: RegIndex) {
     curcpu.write_reg(rd, curcpu.read_reg(rs1) & (curcpu.read_reg(rs2)));
-    if curcpu.is_debug_mode() {
-        curcpu.set_debug_string(format!("{} {}, {}, {}",
-        "and".blue(), REG_FILE_NAMES[rd as usize].red(), REG_FILE_NAMES[rs1 as usize].red(),
-        REG_FILE_NAMES[rs2 as usize].red()));
-    }
 }
 
 // FENCE instruction
 // Does not do anything because the CPU executes memory accesses in the program order anyway
 #[inline(always)]
-fn fence(curcpu: &mut Cpu) {
+fn fence<const DEBUG: bool>(_curcpu: &mut Cpu) {
     // Placeholder, just in case I have the crazy idea to support OoO execution
-    if curcpu.is_debug_mode() {
-        curcpu.set_debug_string(format!("{}", "fence".blue()));
-    }
 }
 
 // FENCEI instruction
-// Does not do anything because the CPU executes memory accesses in the program order anyway
+// Forgets all decoded instructions, so that the following fetches see
+// every write made before it, also the ones of the devices
 #[inline(always)]
-fn fencei(curcpu: &mut Cpu) {
-    // Placeholder, just in case I have the crazy idea to support OoO execution
-    if curcpu.is_debug_mode() {
-        curcpu.set_debug_string(format!("{}", "fencei".blue()));
-    }
+fn fencei<const DEBUG: bool>(curcpu: &mut Cpu) {
+    // Stores already keep the decoded instructions up to date, but
+    // FENCE.I is what programs that write code are required to execute
+    curcpu.flush_decode_cache();
 }
 
 // ECALL and EBREAK instruction
-// Not implemented yet
+// Both of them raise an exception: a breakpoint for EBREAK and an
+// environment call for ECALL
 #[inline(always)]
-fn ecall_ebreak(curcpu: &mut Cpu, imm12: u32) {
+fn ecall_ebreak<const DEBUG: bool>(curcpu: &mut Cpu, imm12: u32) -> Result<(), Trap> {
     if imm12 & 0x1 == 0x1 {
         // EBREAK
-        if curcpu.is_debug_mode() {
-            curcpu.set_debug_string(format!("{}", "ebreak".blue()));
-        }
+        Err(Trap::Breakpoint(curcpu.get_pc()))
     } else {
         // ECALL
-        if curcpu.is_debug_mode() {
-            curcpu.set_debug_string(format!("{}", "ecall".blue()));
+        match curcpu.get_privilege() {
+            Privilege::User => Err(Trap::EnvironmentCallFromUMode),
+            Privilege::Supervisor => Err(Trap::EnvironmentCallFromSMode),
+            Privilege::Machine => Err(Trap::EnvironmentCallFromMMode)
         }
     }
 }
@@ -867,169 +849,528 @@ fn ecall_ebreak(curcpu: &mut Cpu, imm12: u32) {
 // CSRRW instruction
 // rd <- csr[imm]
 // csr[imm] <- rs1
+// If rd is x0 the CSR is not read at all
 #[inline(always)]
-fn csrrw(curcpu: &mut Cpu, rs1: RegIndex, rd: RegIndex, imm12: u32) {
+fn csrrw<const DEBUG: bool>(curcpu: &mut Cpu, rs1: RegIndex, rd: RegIndex, imm12: u32) -> Result<(), Trap> {
+    let csr: CSRegIndex = (imm12 & 0xfff) as CSRegIndex;
+    // rs1 has to be read before rd is written, since they might be the same register
+    let rs1_data: u64 = curcpu.read_reg(rs1);
     if rd != Cpu::ZERO_REGISTER {
-        curcpu.write_reg(rd, curcpu.read_csreg(imm12 as u16));
-    }
-    curcpu.write_csreg(imm12 as u16, curcpu.read_reg(rs1));
-    if curcpu.is_debug_mode() {
-        curcpu.set_debug_string(format!("{} {}, {}, {}",
-        "csrrw".blue(), REG_FILE_NAMES[rd as usize].red(), REG_FILE_NAMES[rs1 as usize].red(), imm12));
+        let csr_data: u64 = curcpu.read_csreg(csr)?;
+        curcpu.write_reg(rd, csr_data);
     }
+    curcpu.write_csreg(csr, rs1_data)
 }
 
 // CSRRS instruction
 // rd <- csr[imm]
 // csr[imm] <- csr[imm] | rs1
+// If rs1 is x0 the CSR is not written at all
 #[inline(always)]
-fn csrrs(curcpu: &mut Cpu, rs1: RegIndex, rd: RegIndex, imm12: u32) {
-    let csr_data: u64 = curcpu.read_csreg(imm12 as u16);
+fn csrrs<const DEBUG: bool>(curcpu: &mut Cpu, rs1: RegIndex, rd: RegIndex, imm12: u32) -> Result<(), Trap> {
+    let csr: CSRegIndex = (imm12 & 0xfff) as CSRegIndex;
+    let rs1_data: u64 = curcpu.read_reg(rs1);
+    let csr_data: u64 = curcpu.read_csreg(csr)?;
     if rd != Cpu::ZERO_REGISTER {
         curcpu.write_reg(rd, csr_data);
     }
-    curcpu.write_csreg(imm12 as u16, curcpu.read_reg(rs1) | csr_data);
-    if curcpu.is_debug_mode() {
-        curcpu.set_debug_string(format!("{} {}, {}, {}",
-        "csrrs".blue(), REG_FILE_NAMES[rd as usize].red(), REG_FILE_NAMES[rs1 as usize].red(), imm12));
+    if rs1 != Cpu::ZERO_REGISTER {
+        curcpu.write_csreg(csr, rs1_data | csr_data)?;
     }
+    Ok(())
 }
 
 // CSRRC instruction
 // rd <- csr[imm]
 // csr[imm] <- !csr[imm] & rs1 (clear bits in CSR where rs1 = 1)
+// If rs1 is x0 the CSR is not written at all
 #[inline(always)]
-fn csrrc(curcpu: &mut Cpu, rs1: RegIndex, rd: RegIndex, imm12: u32) {
-    let csr_data: u64 = curcpu.read_csreg(imm12 as u16);
+fn csrrc<const DEBUG: bool>(curcpu: &mut Cpu, rs1: RegIndex, rd: RegIndex, imm12: u32) -> Result<(), Trap> {
+    let csr: CSRegIndex = (imm12 & 0xfff) as CSRegIndex;
+    let rs1_data: u64 = curcpu.read_reg(rs1);
+    let csr_data: u64 = curcpu.read_csreg(csr)?;
     if rd != Cpu::ZERO_REGISTER {
         curcpu.write_reg(rd, csr_data);
     }
-    curcpu.write_csreg(imm12 as u16, !curcpu.read_reg(rs1) & csr_data);
-    if curcpu.is_debug_mode() {
-        curcpu.set_debug_string(format!("{} {}, {}, {}",
-        "csrrc".blue(), REG_FILE_NAMES[rd as usize].red(), REG_FILE_NAMES[rs1 as usize].red(), imm12));
+    if rs1 != Cpu::ZERO_REGISTER {
+        curcpu.write_csreg(csr, !rs1_data & csr_data)?;
     }
+    Ok(())
 }
 
 // CSRRWI instruction
 // rd <- csr[imm]
 // csr[imm] <- unsigned'rs1[4:0]
+// If rd is x0 the CSR is not read at all
 #[inline(always)]
-fn csrrwi(curcpu: &mut Cpu, rs1: u8, rd: RegIndex, imm12: u32) {
+fn csrrwi<const DEBUG: bool>(curcpu: &mut Cpu, rs1: u8, rd: RegIndex, imm12: u32) -> Result<(), Trap> {
+    let csr: CSRegIndex = (imm12 & 0xfff) as CSRegIndex;
     if rd != Cpu::ZERO_REGISTER {
-        curcpu.write_reg(rd, curcpu.read_csreg(imm12 as u16));
-    }
-    curcpu.write_csreg(imm12 as u16, (rs1 & 0x1f) as u64);
-    if curcpu.is_debug_mode() {
-        curcpu.set_debug_string(format!("{} {}, {}, {}",
-        "csrrwi".blue(), REG_FILE_NAMES[rd as usize].red(), rs1 & 0x1f, imm12));
+        let csr_data: u64 = curcpu.read_csreg(csr)?;
+        curcpu.write_reg(rd, csr_data);
     }
+    curcpu.write_csreg(csr, (rs1 & 0x1f) as u64)
 }
 
 // CSRRSI instruction
 // rd <- csr[imm]
 // csr[imm] <- csr[imm] | unsigned'rs1[4:0]
+// If the immediate is zero the CSR is not written at all
 #[inline(always)]
-fn csrrsi(curcpu: &mut Cpu, rs1: u8, rd: RegIndex, imm12: u32) {
-    let csr_data: u64 = curcpu.read_csreg(imm12 as u16);
+fn csrrsi<const DEBUG: bool>(curcpu: &mut Cpu, rs1: u8, rd: RegIndex, imm12: u32) -> Result<(), Trap> {
+    let csr: CSRegIndex = (imm12 & 0xfff) as CSRegIndex;
+    let csr_data: u64 = curcpu.read_csreg(csr)?;
     if rd != Cpu::ZERO_REGISTER {
         curcpu.write_reg(rd, csr_data);
     }
-    curcpu.write_csreg(imm12 as u16, (rs1 & 0x1f) as u64 | csr_data);
-    if curcpu.is_debug_mode() {
-        curcpu.set_debug_string(format!("{} {}, {}, {}",
-        "csrrsi".blue(), REG_FILE_NAMES[rd as usize].red(), rs1 & 0x1f, imm12));
+    if rs1 & 0x1f != 0 {
+        curcpu.write_csreg(csr, (rs1 & 0x1f) as u64 | csr_data)?;
     }
+    Ok(())
 }
 
 // CSRRCI instruction
 // rd <- csr[imm]
 // csr[imm] <- !csr[imm] & unsigned'rs1[4:0] (clear bits in CSR where rs1 = 1)
+// If the immediate is zero the CSR is not written at all
 #[inline(always)]
-fn csrrci(curcpu: &mut Cpu, rs1: u8, rd: RegIndex, imm12: u32) {
-    let csr_data: u64 = curcpu.read_csreg(imm12 as u16);
+fn csrrci<const DEBUG: bool>(curcpu: &mut Cpu, rs1: u8, rd: RegIndex, imm12: u32) -> Result<(), Trap> {
+    let csr: CSRegIndex = (imm12 & 0xfff) as CSRegIndex;
+    let csr_data: u64 = curcpu.read_csreg(csr)?;
     if rd != Cpu::ZERO_REGISTER {
         curcpu.write_reg(rd, csr_data);
     }
-    curcpu.write_csreg(imm12 as u16, !((rs1 & 0x1f) as u64) & csr_data);
-    if curcpu.is_debug_mode() {
-        curcpu.set_debug_string(format!("{} {}, {}, {}",
-        "csrrci".blue(), REG_FILE_NAMES[rd as usize].red(), rs1 & 0x1f, imm12));
+    if rs1 & 0x1f != 0 {
+        curcpu.write_csreg(csr, !((rs1 & 0x1f) as u64) & csr_data)?;
     }
+    Ok(())
+}
+
+// MRET instruction
+// Return from a machine-mode trap handler: pc <- mepc
+#[inline(always)]
+fn mret<const DEBUG: bool>(curcpu: &mut Cpu) -> Result<(), Trap> {
+    curcpu.return_from_trap(Privilege::Machine)
+}
+
+// SRET instruction
+// Return from a supervisor-mode trap handler: pc <- sepc
+#[inline(always)]
+fn sret<const DEBUG: bool>(curcpu: &mut Cpu) -> Result<(), Trap> {
+    curcpu.return_from_trap(Privilege::Supervisor)
+}
+
+// WFI instruction
+// Stall until an interrupt is pending, then continue with the next
+// instruction (or the handler, if the interrupt is globally enabled).
+// It is illegal in U-mode
+#[inline(always)]
+fn wfi<const DEBUG: bool>(curcpu: &mut Cpu) -> Result<(), Trap> {
+    if curcpu.get_privilege() == Privilege::User {
+        return Err(Trap::IllegalInstruction(0));
+    }
+    if !curcpu.wait_for_interrupt() {
+        // Not woken up yet: stay on WFI
+        curcpu.set_next_pc_rel(0);
+    }
+    Ok(())
 }
 
 // SRL instruction
 // rd <- rs1 >> rs2[5:0]
 #[inline(always)]
-fn srl(curcpu: &mut Cpu, rs1: RegIndex, rs2: RegIndex, rd: RegIndex) {
-    curcpu.write_reg(rd, curcpu.read_reg(rs1) >> (curcpu.read_reg(rs2) & 0x3f));
-    if curcpu.is_debug_mode() {
-        curcpu.set_debug_string(format!("{} {}, {}, {}",
-        "srl".blue(), REG_FILE_NAMES[rd as usize].red(), REG_FILE_NAMES[rs1 as usize].red(),
-        REG_FILE_NAMES[rs2 as usize].red()));
-    }
+fn srl<const DEBUG: bool>(curcpu: &mut Cpu, rs1: RegIndex, rs2: RegIndex, rd: RegIndex) {
+    let xlen: Xlen = curcpu.get_xlen();
+    curcpu.write_reg(rd, (curcpu.read_reg(rs1) & xlen.mask()) >> (curcpu.read_reg(rs2) & xlen.shamt_mask()));
 }
 
 // SRLW instruction
-// rd <- rs1 >> rs2[4:0]
+// rd <- signed'(unsigned'rs1[31:0] >> rs2[4:0])
 #[inline(always)]
-fn srlw(curcpu: &mut Cpu, rs1: RegIndex, rs2: RegIndex, rd: RegIndex) {
+fn srlw<const DEBUG: bool>(curcpu: &mut Cpu, rs1: RegIndex, rs2: RegIndex, rd: RegIndex) {
     let first_operand: u32 = curcpu.read_reg(rs1) as u32;
     let second_operand: u64= curcpu.read_reg(rs2) & 0x1f;
-    curcpu.write_reg(rd, (first_operand >> second_operand) as u64);
-    if curcpu.is_debug_mode() {
-        curcpu.set_debug_string(format!("{} {}, {}, {}",
-        "srlw".blue(), REG_FILE_NAMES[rd as usize].red(), REG_FILE_NAMES[rs1 as usize].red(),
-        REG_FILE_NAMES[rs2 as usize].red()));
-    }
+    curcpu.write_reg(rd, (first_operand >> second_operand) as i32 as i64 as u64);
 }
 
 // SRA instruction
 // rd <- rs1 >> rs2[4:0]
 #[inline(always)]
-fn sra(curcpu: &mut Cpu, rs1: RegIndex, rs2: RegIndex, rd: RegIndex) {
+fn sra<const DEBUG: bool>(curcpu: &mut Cpu, rs1: RegIndex, rs2: RegIndex, rd: RegIndex) {
     let first_operand: i64 = curcpu.read_reg(rs1) as i64;
-    let second_operand: u64= curcpu.read_reg(rs2) & 0x3f;
+    let second_operand: u64= curcpu.read_reg(rs2) & curcpu.get_xlen().shamt_mask();
     curcpu.write_reg(rd, (first_operand >> second_operand) as u64);
-    if curcpu.is_debug_mode() {
-        curcpu.set_debug_string(format!("{} {}, {}, {}",
-        "sra".blue(), REG_FILE_NAMES[rd as usize].red(), REG_FILE_NAMES[rs1 as usize].red(),
-        REG_FILE_NAMES[rs2 as usize].red()));
-    }
 }
 
 // SRAW instruction
 // rd <- rs1 >> rs2[4:0]
 #[inline(always)]
-fn sraw(curcpu: &mut Cpu, rs1: RegIndex, rs2: RegIndex, rd: RegIndex) {
+fn sraw<const DEBUG: bool>(curcpu: &mut Cpu, rs1: RegIndex, rs2: RegIndex, rd: RegIndex) {
     let first_operand: i32 = curcpu.read_reg(rs1) as i32;
     let second_operand: u64= curcpu.read_reg(rs2) & 0x1f;
-    curcpu.write_reg(rd, (first_operand >> second_operand) as u64);
-    if curcpu.is_debug_mode() {
-        curcpu.set_debug_string(format!("{} {}, {}, {}",
-        "sraw".blue(), REG_FILE_NAMES[rd as usize].red(), REG_FILE_NAMES[rs1 as usize].red(),
-        REG_FILE_NAMES[rs2 as usize].red()));
-    }
+    curcpu.write_reg(rd, (first_operand >> second_operand) as i64 as u64);
 }
 
-// ADDI instruction
+// ADDIW instruction
 // rd <- rs1 + imm
 #[inline(always)]
-fn addiw(curcpu: &mut Cpu, rs1: RegIndex, rd: RegIndex, imm12: u32) {
+fn addiw<const DEBUG: bool>(curcpu: &mut Cpu, rs1: RegIndex, rd: RegIndex, imm12: u32) {
     let first_operand: i32 = (curcpu.read_reg(rs1) & 0xffffffff) as i32;
     let second_operand: i32 = imm12 as i32;
-    curcpu.write_reg(rd, (first_operand + second_operand) as i64 as u64);
-    if curcpu.is_debug_mode() {
-        curcpu.set_debug_string(format!("{} {}, {}, {}",
-        "addiw".blue(), REG_FILE_NAMES[rd as usize].red(), REG_FILE_NAMES[rs1 as usize].red(),
-        imm12));
+    curcpu.write_reg(rd, first_operand.wrapping_add(second_operand) as i64 as u64);
+}
+
+// Zba: SH1ADD, SH2ADD and SH3ADD instructions
+// rd <- rs2 + (rs1 << shift)
+#[inline(always)]
+fn shadd<const DEBUG: bool>(curcpu: &mut Cpu, rs1: RegIndex, rs2: RegIndex, rd: RegIndex, shift: u32) {
+    let first_operand: u64 = curcpu.read_reg(rs1) << shift;
+    curcpu.write_reg(rd, curcpu.read_reg(rs2).wrapping_add(first_operand));
+}
+
+// Zba: ADD.UW, SH1ADD.UW, SH2ADD.UW and SH3ADD.UW instructions
+// rd <- rs2 + (unsigned'rs1[31:0] << shift)
+#[inline(always)]
+fn shadd_uw<const DEBUG: bool>(curcpu: &mut Cpu, rs1: RegIndex, rs2: RegIndex, rd: RegIndex, shift: u32) {
+    let first_operand: u64 = (curcpu.read_reg(rs1) & 0xffffffff) << shift;
+    curcpu.write_reg(rd, curcpu.read_reg(rs2).wrapping_add(first_operand));
+}
+
+// Zba: SLLI.UW instruction
+// rd <- unsigned'rs1[31:0] << imm
+#[inline(always)]
+fn slli_uw<const DEBUG: bool>(curcpu: &mut Cpu, rs1: RegIndex, rd: RegIndex, imm12: u32) {
+    let first_operand: u64 = curcpu.read_reg(rs1) & 0xffffffff;
+    curcpu.write_reg(rd, first_operand << (imm12 & 0x3f));
+}
+
+// Zbb: ANDN instruction
+// rd <- rs1 & !rs2
+#[inline(always)]
+fn andn<const DEBUG: bool>(curcpu: &mut Cpu, rs1: RegIndex, rs2: RegIndex, rd: RegIndex) {
+    curcpu.write_reg(rd, curcpu.read_reg(rs1) & !curcpu.read_reg(rs2));
+}
+
+// Zbb: ORN instruction
+// rd <- rs1 | !rs2
+#[inline(always)]
+fn orn<const DEBUG: bool>(curcpu: &mut Cpu, rs1: RegIndex, rs2: RegIndex, rd: RegIndex) {
+    curcpu.write_reg(rd, curcpu.read_reg(rs1) | !curcpu.read_reg(rs2));
+}
+
+// Zbb: XNOR instruction
+// rd <- !(rs1 ^ rs2)
+#[inline(always)]
+fn xnor<const DEBUG: bool>(curcpu: &mut Cpu, rs1: RegIndex, rs2: RegIndex, rd: RegIndex) {
+    curcpu.write_reg(rd, !(curcpu.read_reg(rs1) ^ curcpu.read_reg(rs2)));
+}
+
+// Zbb: CLZ instruction
+// rd <- number of leading zeros of rs1
+#[inline(always)]
+fn clz<const DEBUG: bool>(curcpu: &mut Cpu, rs1: RegIndex, rd: RegIndex) {
+    let count: u32 = match curcpu.get_xlen() {
+        Xlen::Rv32 => (curcpu.read_reg(rs1) as u32).leading_zeros(),
+        Xlen::Rv64 => curcpu.read_reg(rs1).leading_zeros()
+    };
+    curcpu.write_reg(rd, count as u64);
+}
+
+// Zbb: CLZW instruction
+// rd <- number of leading zeros of rs1[31:0]
+#[inline(always)]
+fn clzw<const DEBUG: bool>(curcpu: &mut Cpu, rs1: RegIndex, rd: RegIndex) {
+    curcpu.write_reg(rd, (curcpu.read_reg(rs1) as u32).leading_zeros() as u64);
+}
+
+// Zbb: CTZ instruction
+// rd <- number of trailing zeros of rs1
+#[inline(always)]
+fn ctz<const DEBUG: bool>(curcpu: &mut Cpu, rs1: RegIndex, rd: RegIndex) {
+    let count: u32 = match curcpu.get_xlen() {
+        Xlen::Rv32 => (curcpu.read_reg(rs1) as u32).trailing_zeros(),
+        Xlen::Rv64 => curcpu.read_reg(rs1).trailing_zeros()
+    };
+    curcpu.write_reg(rd, count as u64);
+}
+
+// Zbb: CTZW instruction
+// rd <- number of trailing zeros of rs1[31:0]
+#[inline(always)]
+fn ctzw<const DEBUG: bool>(curcpu: &mut Cpu, rs1: RegIndex, rd: RegIndex) {
+    curcpu.write_reg(rd, (curcpu.read_reg(rs1) as u32).trailing_zeros() as u64);
+}
+
+// Zbb: CPOP instruction
+// rd <- number of bits set in rs1
+#[inline(always)]
+fn cpop<const DEBUG: bool>(curcpu: &mut Cpu, rs1: RegIndex, rd: RegIndex) {
+    let xlen: Xlen = curcpu.get_xlen();
+    curcpu.write_reg(rd, (curcpu.read_reg(rs1) & xlen.mask()).count_ones() as u64);
+}
+
+// Zbb: CPOPW instruction
+// rd <- number of bits set in rs1[31:0]
+#[inline(always)]
+fn cpopw<const DEBUG: bool>(curcpu: &mut Cpu, rs1: RegIndex, rd: RegIndex) {
+    curcpu.write_reg(rd, (curcpu.read_reg(rs1) as u32).count_ones() as u64);
+}
+
+// Zbb: MIN instruction
+// rd <- signed'rs1 < signed'rs2 ? rs1 : rs2
+#[inline(always)]
+fn min<const DEBUG: bool>(curcpu: &mut Cpu, rs1: RegIndex, rs2: RegIndex, rd: RegIndex) {
+    let first_operand: i64 = curcpu.read_reg(rs1) as i64;
+    let second_operand: i64 = curcpu.read_reg(rs2) as i64;
+    curcpu.write_reg(rd, first_operand.min(second_operand) as u64);
+}
+
+// Zbb: MINU instruction
+// rd <- unsigned'rs1 < unsigned'rs2 ? rs1 : rs2
+#[inline(always)]
+fn minu<const DEBUG: bool>(curcpu: &mut Cpu, rs1: RegIndex, rs2: RegIndex, rd: RegIndex) {
+    // In RV32 mode the sign extension keeps the unsigned order
+    curcpu.write_reg(rd, curcpu.read_reg(rs1).min(curcpu.read_reg(rs2)));
+}
+
+// Zbb: MAX instruction
+// rd <- signed'rs1 > signed'rs2 ? rs1 : rs2
+#[inline(always)]
+fn max<const DEBUG: bool>(curcpu: &mut Cpu, rs1: RegIndex, rs2: RegIndex, rd: RegIndex) {
+    let first_operand: i64 = curcpu.read_reg(rs1) as i64;
+    let second_operand: i64 = curcpu.read_reg(rs2) as i64;
+    curcpu.write_reg(rd, first_operand.max(second_operand) as u64);
+}
+
+// Zbb: MAXU instruction
+// rd <- unsigned'rs1 > unsigned'rs2 ? rs1 : rs2
+#[inline(always)]
+fn maxu<const DEBUG: bool>(curcpu: &mut Cpu, rs1: RegIndex, rs2: RegIndex, rd: RegIndex) {
+    curcpu.write_reg(rd, curcpu.read_reg(rs1).max(curcpu.read_reg(rs2)));
+}
+
+// Zbb: SEXT.B instruction
+// rd <- signed'rs1[7:0]
+#[inline(always)]
+fn sext_b<const DEBUG: bool>(curcpu: &mut Cpu, rs1: RegIndex, rd: RegIndex) {
+    curcpu.write_reg(rd, curcpu.read_reg(rs1) as i8 as i64 as u64);
+}
+
+// Zbb: SEXT.H instruction
+// rd <- signed'rs1[15:0]
+#[inline(always)]
+fn sext_h<const DEBUG: bool>(curcpu: &mut Cpu, rs1: RegIndex, rd: RegIndex) {
+    curcpu.write_reg(rd, curcpu.read_reg(rs1) as i16 as i64 as u64);
+}
+
+// Zbb: ZEXT.H instruction
+// rd <- unsigned'rs1[15:0]
+#[inline(always)]
+fn zext_h<const DEBUG: bool>(curcpu: &mut Cpu, rs1: RegIndex, rd: RegIndex) {
+    curcpu.write_reg(rd, curcpu.read_reg(rs1) & 0xffff);
+}
+
+// Rotate the low XLEN bits of a register to the left
+#[inline(always)]
+fn rotate_left(value: u64, amount: u64, xlen: Xlen) -> u64 {
+    match xlen {
+        Xlen::Rv32 => (value as u32).rotate_left(amount as u32 & 0x1f) as u64,
+        Xlen::Rv64 => value.rotate_left(amount as u32 & 0x3f)
     }
 }
 
+// Zbb: ROL instruction
+// rd <- rs1 rotated left by rs2[5:0]
+#[inline(always)]
+fn rol<const DEBUG: bool>(curcpu: &mut Cpu, rs1: RegIndex, rs2: RegIndex, rd: RegIndex) {
+    let xlen: Xlen = curcpu.get_xlen();
+    curcpu.write_reg(rd, rotate_left(curcpu.read_reg(rs1), curcpu.read_reg(rs2), xlen));
+}
+
+// Zbb: ROLW instruction
+// rd <- signed'(rs1[31:0] rotated left by rs2[4:0])
+#[inline(always)]
+fn rolw<const DEBUG: bool>(curcpu: &mut Cpu, rs1: RegIndex, rs2: RegIndex, rd: RegIndex) {
+    let result: u32 = (curcpu.read_reg(rs1) as u32).rotate_left(curcpu.read_reg(rs2) as u32 & 0x1f);
+    curcpu.write_reg(rd, result as i32 as i64 as u64);
+}
+
+// Zbb: ROR instruction
+// rd <- rs1 rotated right by rs2[5:0]
+#[inline(always)]
+fn ror<const DEBUG: bool>(curcpu: &mut Cpu, rs1: RegIndex, rs2: RegIndex, rd: RegIndex) {
+    let xlen: Xlen = curcpu.get_xlen();
+    // Rotating right by n is rotating left by XLEN - n
+    let amount: u64 = (xlen.bits() as u64).wrapping_sub(curcpu.read_reg(rs2) & xlen.shamt_mask());
+    curcpu.write_reg(rd, rotate_left(curcpu.read_reg(rs1), amount, xlen));
+}
+
+// Zbb: RORI instruction
+// rd <- rs1 rotated right by imm
+#[inline(always)]
+fn rori<const DEBUG: bool>(curcpu: &mut Cpu, rs1: RegIndex, rd: RegIndex, imm12: u32) {
+    let xlen: Xlen = curcpu.get_xlen();
+    let amount: u64 = (xlen.bits() as u64).wrapping_sub(imm12 as u64 & xlen.shamt_mask());
+    curcpu.write_reg(rd, rotate_left(curcpu.read_reg(rs1), amount, xlen));
+}
+
+// Zbb: RORW instruction
+// rd <- signed'(rs1[31:0] rotated right by rs2[4:0])
+#[inline(always)]
+fn rorw<const DEBUG: bool>(curcpu: &mut Cpu, rs1: RegIndex, rs2: RegIndex, rd: RegIndex) {
+    let result: u32 = (curcpu.read_reg(rs1) as u32).rotate_right(curcpu.read_reg(rs2) as u32 & 0x1f);
+    curcpu.write_reg(rd, result as i32 as i64 as u64);
+}
+
+// Zbb: RORIW instruction
+// rd <- signed'(rs1[31:0] rotated right by imm)
+#[inline(always)]
+fn roriw<const DEBUG: bool>(curcpu: &mut Cpu, rs1: RegIndex, rd: RegIndex, imm12: u32) {
+    let result: u32 = (curcpu.read_reg(rs1) as u32).rotate_right(imm12 & 0x1f);
+    curcpu.write_reg(rd, result as i32 as i64 as u64);
+}
+
+// Zbb: ORC.B instruction
+// rd[8i+7:8i] <- rs1[8i+7:8i] != 0 ? 0xff : 0x00
+#[inline(always)]
+fn orc_b<const DEBUG: bool>(curcpu: &mut Cpu, rs1: RegIndex, rd: RegIndex) {
+    let bytes: [u8; 8] = curcpu.read_reg(rs1).to_le_bytes().map(|byte| if byte != 0 { 0xff } else { 0x00 });
+    curcpu.write_reg(rd, u64::from_le_bytes(bytes));
+}
+
+// Zbb: REV8 instruction
+// rd <- rs1 with the order of its bytes reversed
+#[inline(always)]
+fn rev8<const DEBUG: bool>(curcpu: &mut Cpu, rs1: RegIndex, rd: RegIndex) {
+    let result: u64 = match curcpu.get_xlen() {
+        Xlen::Rv32 => (curcpu.read_reg(rs1) as u32).swap_bytes() as u64,
+        Xlen::Rv64 => curcpu.read_reg(rs1).swap_bytes()
+    };
+    curcpu.write_reg(rd, result);
+}
+
+// Zbs: BCLR instruction
+// rd <- rs1 & !(1 << rs2[5:0])
+#[inline(always)]
+fn bclr<const DEBUG: bool>(curcpu: &mut Cpu, rs1: RegIndex, rs2: RegIndex, rd: RegIndex) {
+    let index: u64 = curcpu.read_reg(rs2) & curcpu.get_xlen().shamt_mask();
+    curcpu.write_reg(rd, curcpu.read_reg(rs1) & !(1 << index));
+}
+
+// Zbs: BCLRI instruction
+// rd <- rs1 & !(1 << imm)
+#[inline(always)]
+fn bclri<const DEBUG: bool>(curcpu: &mut Cpu, rs1: RegIndex, rd: RegIndex, imm12: u32) {
+    let index: u64 = imm12 as u64 & curcpu.get_xlen().shamt_mask();
+    curcpu.write_reg(rd, curcpu.read_reg(rs1) & !(1 << index));
+}
+
+// Zbs: BEXT instruction
+// rd <- (rs1 >> rs2[5:0]) & 1
+#[inline(always)]
+fn bext<const DEBUG: bool>(curcpu: &mut Cpu, rs1: RegIndex, rs2: RegIndex, rd: RegIndex) {
+    let index: u64 = curcpu.read_reg(rs2) & curcpu.get_xlen().shamt_mask();
+    curcpu.write_reg(rd, (curcpu.read_reg(rs1) >> index) & 0x1);
+}
+
+// Zbs: BEXTI instruction
+// rd <- (rs1 >> imm) & 1
+#[inline(always)]
+fn bexti<const DEBUG: bool>(curcpu: &mut Cpu, rs1: RegIndex, rd: RegIndex, imm12: u32) {
+    let index: u64 = imm12 as u64 & curcpu.get_xlen().shamt_mask();
+    curcpu.write_reg(rd, (curcpu.read_reg(rs1) >> index) & 0x1);
+}
+
+// Zbs: BINV instruction
+// rd <- rs1 ^ (1 << rs2[5:0])
+#[inline(always)]
+fn binv<const DEBUG: bool>(curcpu: &mut Cpu, rs1: RegIndex, rs2: RegIndex, rd: RegIndex) {
+    let index: u64 = curcpu.read_reg(rs2) & curcpu.get_xlen().shamt_mask();
+    curcpu.write_reg(rd, curcpu.read_reg(rs1) ^ (1 << index));
+}
+
+// Zbs: BINVI instruction
+// rd <- rs1 ^ (1 << imm)
+#[inline(always)]
+fn binvi<const DEBUG: bool>(curcpu: &mut Cpu, rs1: RegIndex, rd: RegIndex, imm12: u32) {
+    let index: u64 = imm12 as u64 & curcpu.get_xlen().shamt_mask();
+    curcpu.write_reg(rd, curcpu.read_reg(rs1) ^ (1 << index));
+}
+
+// Zbs: BSET instruction
+// rd <- rs1 | (1 << rs2[5:0])
+#[inline(always)]
+fn bset<const DEBUG: bool>(curcpu: &mut Cpu, rs1: RegIndex, rs2: RegIndex, rd: RegIndex) {
+    let index: u64 = curcpu.read_reg(rs2) & curcpu.get_xlen().shamt_mask();
+    curcpu.write_reg(rd, curcpu.read_reg(rs1) | (1 << index));
+}
+
+// Zbs: BSETI instruction
+// rd <- rs1 | (1 << imm)
+#[inline(always)]
+fn bseti<const DEBUG: bool>(curcpu: &mut Cpu, rs1: RegIndex, rd: RegIndex, imm12: u32) {
+    let index: u64 = imm12 as u64 & curcpu.get_xlen().shamt_mask();
+    curcpu.write_reg(rd, curcpu.read_reg(rs1) | (1 << index));
+}
+
+// Zicond: CZERO.EQZ instruction
+// rd <- rs2 == 0 ? 0 : rs1
+#[inline(always)]
+fn czero_eqz<const DEBUG: bool>(curcpu: &mut Cpu, rs1: RegIndex, rs2: RegIndex, rd: RegIndex) {
+    let result: u64 = if curcpu.read_reg(rs2) == 0 { 0 } else { curcpu.read_reg(rs1) };
+    curcpu.write_reg(rd, result);
+}
+
+// Zicond: CZERO.NEZ instruction
+// rd <- rs2 != 0 ? 0 : rs1
+#[inline(always)]
+fn czero_nez<const DEBUG: bool>(curcpu: &mut Cpu, rs1: RegIndex, rs2: RegIndex, rd: RegIndex) {
+    let result: u64 = if curcpu.read_reg(rs2) != 0 { 0 } else { curcpu.read_reg(rs1) };
+    curcpu.write_reg(rd, result);
+}
+
+// Carry-less product of the low XLEN bits of two registers, 2 * XLEN bits long
+#[inline(always)]
+fn carryless_product(first_operand: u64, second_operand: u64, xlen: Xlen) -> u128 {
+    let first_operand: u128 = (first_operand & xlen.mask()) as u128;
+    let second_operand: u64 = second_operand & xlen.mask();
+    (0..xlen.bits())
+        .filter(|bit| (second_operand >> bit) & 0x1 != 0)
+        .fold(0, |product, bit| product ^ (first_operand << bit))
+}
+
+// Zbc: CLMUL instruction
+// rd <- low XLEN bits of clmul(rs1, rs2)
+#[inline(always)]
+fn clmul<const DEBUG: bool>(curcpu: &mut Cpu, rs1: RegIndex, rs2: RegIndex, rd: RegIndex) {
+    let xlen: Xlen = curcpu.get_xlen();
+    let product: u128 = carryless_product(curcpu.read_reg(rs1), curcpu.read_reg(rs2), xlen);
+    curcpu.write_reg(rd, product as u64);
+}
+
+// Zbc: CLMULH instruction
+// rd <- high XLEN bits of clmul(rs1, rs2)
+#[inline(always)]
+fn clmulh<const DEBUG: bool>(curcpu: &mut Cpu, rs1: RegIndex, rs2: RegIndex, rd: RegIndex) {
+    let xlen: Xlen = curcpu.get_xlen();
+    let product: u128 = carryless_product(curcpu.read_reg(rs1), curcpu.read_reg(rs2), xlen);
+    curcpu.write_reg(rd, (product >> xlen.bits()) as u64);
+}
+
+// Zbc: CLMULR instruction
+// rd <- bits 2 * XLEN - 2 to XLEN - 1 of clmul(rs1, rs2)
+#[inline(always)]
+fn clmulr<const DEBUG: bool>(curcpu: &mut Cpu, rs1: RegIndex, rs2: RegIndex, rd: RegIndex) {
+    let xlen: Xlen = curcpu.get_xlen();
+    let product: u128 = carryless_product(curcpu.read_reg(rs1), curcpu.read_reg(rs2), xlen);
+    curcpu.write_reg(rd, (product >> (xlen.bits() - 1)) as u64);
+}
+
 #[cfg(test)]
 mod tests {
 
-    use crate::cpu::Cpu;
+    use crate::cpu::{Cpu, ExitReason, FetchFaultAction, Xlen};
+    use crate::csr::CsrFile;
     use crate::rv::*;
     #[test]
     fn add_test() {
@@ -1039,7 +1380,7 @@ mod tests {
         let result = first_op.wrapping_add(second_op);
         cpu.write_reg(1, first_op);
         cpu.write_reg(2, second_op);
-        add(&mut cpu, 0x1, 0x2, 0x3);
+        add::<false>(&mut cpu, 0x1, 0x2, 0x3);
         assert_eq!(cpu.read_reg(3), result);
     }
 
@@ -1051,59 +1392,337 @@ mod tests {
         let result = first_op.wrapping_sub(second_op);
         cpu.write_reg(1, first_op);
         cpu.write_reg(2, second_op);
-        sub(&mut cpu, 0x1, 0x2, 0x3);
+        sub::<false>(&mut cpu, 0x1, 0x2, 0x3);
         assert_eq!(cpu.read_reg(3), result);
     }
 
+    #[test]
+    fn srai_test() {
+        let mut cpu: Cpu = Cpu::new(None);
+        cpu.write_reg(1, 0x8000_0000_0000_0000);
+        // SRAI x3, x1, 63 and SRLI x4, x1, 63
+        srli_srai::<false>(&mut cpu, 0x1, 0x3, 0x400 | 63);
+        srli_srai::<false>(&mut cpu, 0x1, 0x4, 63);
+        assert_eq!((cpu.read_reg(3), cpu.read_reg(4)), (u64::MAX, 1));
+        // SRAIW x3, x1, 31 and SRLIW x4, x1, 0 on 0x80000000
+        cpu.write_reg(1, 0x8000_0000);
+        srliw_sraiw::<false>(&mut cpu, 0x1, 0x3, 0x400 | 31);
+        srliw_sraiw::<false>(&mut cpu, 0x1, 0x4, 0);
+        assert_eq!((cpu.read_reg(3), cpu.read_reg(4)), (u64::MAX, 0xffff_ffff_8000_0000));
+    }
+
+    #[test]
+    fn immediate_test() {
+        // J-type and B-type offsets are multiples of 2: the encoding leaves
+        // out bit 0, and imm[20]/imm[12] is the sign
+        let jtype = |instr: u32| decode_immediate_jtype((instr as i32 >> 12) as u32);
+        assert_eq!(jtype(0xffdff06f), -4);
+        assert_eq!(jtype(0x0010006f), 2048);
+        assert_eq!(jtype(0x7ffff06f), 1048574);
+        assert_eq!(jtype(0x8000006f), -1048576);
+        let btype = |instr: u32| decode_immediate_btype((instr >> 7) & 0x1f, (instr as i32 >> 20) as u32);
+        assert_eq!(btype(0xfe000ce3), -8);
+        assert_eq!(btype(0x000000e3), 2048);
+        assert_eq!(btype(0x7e000fe3), 4094);
+        assert_eq!(btype(0x80000063), -4096);
+    }
+
     #[test]
     fn jal_test() {
         let mut cpu: Cpu = Cpu::new(None);
-        let result = cpu.get_pc().wrapping_sub(5);
-        let imm_minus_five: u32 = 0b111111111111_1_1111111011_1_11111111;
-        jal(&mut cpu, 0x1, imm_minus_five);
+        let result = cpu.get_pc().wrapping_sub(10);
+        let imm_minus_ten: u32 = 0b1111_1111_1111_1111_1111_0111_1111_1111;
+        jal::<false>(&mut cpu, 0x1, imm_minus_ten);
         assert_eq!(cpu.get_next_pc(), result);
     }
 
     #[test]
     fn beq_test() {
         let mut cpu: Cpu = Cpu::new(None);
-        cpu.set_pc(6);
-        let result: u64 = cpu.get_pc().wrapping_sub(6);
-        let imm12: u32 = 0b11111111111111111111111111100000 as u32;
+        cpu.set_pc(12);
+        let result: u64 = cpu.get_pc().wrapping_sub(12);
+        let imm12: u32 = 0b11111111111111111111111111100000;
         let imm5: u32 = 0b10101;
         cpu.write_reg(1, 3);
         cpu.write_reg(2, 3);
-        beq(&mut cpu, 0x1, 0x2, imm5, imm12);
+        beq::<false>(&mut cpu, 0x1, 0x2, imm5, imm12);
         assert_eq!(cpu.get_next_pc(), result);
     }
 
     #[test]
     fn bne_test() {
         let mut cpu: Cpu = Cpu::new(None);
-        cpu.set_pc(6);
-        let result: u64 = cpu.get_pc().wrapping_sub(6);
-        let imm12: u32 = 0b11111111111111111111111111100000 as u32;
+        cpu.set_pc(12);
+        let result: u64 = cpu.get_pc().wrapping_sub(12);
+        let imm12: u32 = 0b11111111111111111111111111100000;
         let imm5: u32 = 0b10101;
         cpu.write_reg(1, 4);
         cpu.write_reg(2, 3);
-        bne(&mut cpu, 0x1, 0x2, imm5, imm12);
+        bne::<false>(&mut cpu, 0x1, 0x2, imm5, imm12);
         assert_eq!(cpu.get_next_pc(), result);
     }
 
     #[test]
     fn load_test() {
-        let mut cpu: Cpu = Cpu::new(None);
-        cpu.store(0xdeadbeef, 0x2, AccessSize::WORD);
-        lh(&mut cpu, 0x1, 0x2, 0x4);
+        let mut cpu: Cpu = Cpu::new(Some(crate::memory::Memory::DRAM_DEFAULT_SIZE));
+        cpu.set_read_write_segment(0x0);
+        cpu.store(0xdeadbeef, 0x4, AccessSize::WORD).unwrap();
+        lh::<false>(&mut cpu, 0x1, 0x2, 0x6).unwrap();
         assert_eq!(cpu.read_reg(0x2), 0xffffffffffffdead);
     }
 
+    #[test]
+    fn zero_register_test() {
+        let mut cpu: Cpu = Cpu::new(Some(crate::memory::Memory::DRAM_DEFAULT_SIZE));
+        cpu.set_read_write_segment(0x0);
+        cpu.store(0xdeadbeef, 0x8, AccessSize::WORD).unwrap();
+        // ADDI x0, x0, 5 and LW x0, 8(x0) leave x0 at 0
+        addi::<false>(&mut cpu, 0x0, 0x0, 5);
+        assert_eq!(cpu.read_reg(0x0), 0);
+        lw::<false>(&mut cpu, 0x0, 0x0, 0x8).unwrap();
+        assert_eq!(cpu.read_reg(0x0), 0);
+        // ADDI x10, x0, 0 reads 0
+        addi::<false>(&mut cpu, 0x0, 0xa, 0);
+        assert_eq!(cpu.read_reg(0xa), 0);
+    }
+
     #[test]
     fn store_test() {
-        let mut cpu: Cpu = Cpu::new(None);
+        let mut cpu: Cpu = Cpu::new(Some(crate::memory::Memory::DRAM_DEFAULT_SIZE));
+        cpu.set_read_write_segment(0x0);
         cpu.write_reg(0x1, 0xef);
-        sb(&mut cpu, 0x0, 0x1, 0x4);
-        lbu(&mut cpu, 0x0, 0x2, 0x4);
+        sb::<false>(&mut cpu, 0x0, 0x1, 0x4).unwrap();
+        lbu::<false>(&mut cpu, 0x0, 0x2, 0x4).unwrap();
         assert_eq!(cpu.read_reg(0x1), cpu.read_reg(0x2));
     }
+
+    #[test]
+    fn tohost_test() {
+        let mut cpu: Cpu = Cpu::new(Some(crate::memory::Memory::DRAM_DEFAULT_SIZE));
+        cpu.set_read_write_segment(0x0);
+        cpu.enable_htif(crate::htif::Htif::new(0x40, None));
+        // Test 3 failed: (3 << 1) | 1, stored as a word sign-extended in the register
+        cpu.write_reg(0x1, 0xffff_ffff_0000_0007);
+        sw::<false>(&mut cpu, 0x0, 0x41, 0x0).unwrap();
+        assert_eq!(cpu.get_exit_code(), Some(3));
+        assert_eq!(cpu.get_next_pc(), Cpu::SENTINEL_RETURN_ADDRESS);
+    }
+
+    #[test]
+    fn test_finisher_test() {
+        let mut cpu: Cpu = Cpu::new(Some(crate::memory::Memory::DRAM_DEFAULT_SIZE));
+        cpu.set_read_write_segment(0x0);
+        assert!(cpu.enable_test_finisher(crate::finisher::TestFinisher::new(0x0)).is_err());
+        cpu.enable_test_finisher(crate::finisher::TestFinisher::new(0x100000)).unwrap();
+        // FAIL with code 3, then a value that is not a command
+        cpu.write_reg(0x1, 0x100000);
+        cpu.write_reg(0x2, 0x1234);
+        sw::<false>(&mut cpu, 0x1, 0x2, 0x0).unwrap();
+        assert_eq!(cpu.get_exit_code(), None);
+        cpu.write_reg(0x2, 0x33333);
+        sw::<false>(&mut cpu, 0x1, 0x2, 0x0).unwrap();
+        assert_eq!(cpu.get_exit_code(), Some(3));
+        assert_eq!(cpu.get_next_pc(), Cpu::SENTINEL_RETURN_ADDRESS);
+    }
+
+    #[test]
+    fn exit_reason_test() {
+        let mut cpu: Cpu = Cpu::new(Some(crate::memory::Memory::DRAM_DEFAULT_SIZE));
+        cpu.set_read_write_segment(0x0);
+        // li a0, 5; ret (to the sentinel address); ebreak
+        cpu.store(0x00500513, 0x0, AccessSize::WORD).unwrap();
+        cpu.store(0x00008067, 0x4, AccessSize::WORD).unwrap();
+        cpu.store(0x00100073, 0x8, AccessSize::WORD).unwrap();
+        let mut breakpoint: Cpu = cpu.clone();
+        cpu.write_reg(0x1, Cpu::SENTINEL_RETURN_ADDRESS);
+        assert_eq!(cpu.get_exit_reason(), None);
+        assert_eq!(cpu.cpu_loop(), (2, ExitReason::Returned(5)));
+        assert_eq!(ExitReason::Returned(5).exit_status(), Some(5));
+        // Without a trap handler the EBREAK stops the program
+        breakpoint.set_pc(0x8);
+        assert_eq!(breakpoint.cpu_loop(), (0, ExitReason::Breakpoint(0x8)));
+        assert_eq!(ExitReason::Breakpoint(0x8).exit_status(), Some(1));
+        assert_eq!(ExitReason::Timeout.exit_status(), Some(ExitReason::LIMIT_EXIT_STATUS));
+        assert_eq!(ExitReason::InstructionLimit.exit_status(), None);
+    }
+
+    #[test]
+    fn illegal_instruction_test() {
+        let mut cpu: Cpu = Cpu::new(None);
+        assert_eq!(decode::<false>(0xffffffff, &mut cpu), Err(Trap::IllegalInstruction(0xffffffff)));
+    }
+
+    #[test]
+    fn misaligned_load_test() {
+        let mut cpu: Cpu = Cpu::new(Some(crate::memory::Memory::DRAM_DEFAULT_SIZE));
+        cpu.set_read_write_segment(0x0);
+        assert_eq!(lw::<false>(&mut cpu, 0x0, 0x1, 0x2), Err(Trap::LoadAddressMisaligned(0x2)));
+        assert_eq!(ld::<false>(&mut cpu, 0x0, 0x1, 0x4000), Err(Trap::LoadAccessFault(0x4000)));
+    }
+
+    #[test]
+    fn ecall_ebreak_test() {
+        let mut cpu: Cpu = Cpu::new(None);
+        cpu.set_pc(0x100);
+        assert_eq!(ecall_ebreak::<false>(&mut cpu, 0x0), Err(Trap::EnvironmentCallFromMMode));
+        assert_eq!(ecall_ebreak::<false>(&mut cpu, 0x1), Err(Trap::Breakpoint(0x100)));
+        cpu.get_csrs_mut().set_privilege(Privilege::User);
+        assert_eq!(ecall_ebreak::<false>(&mut cpu, 0x0), Err(Trap::EnvironmentCallFromUMode));
+        assert_eq!(Trap::EnvironmentCallFromUMode.cause(), 8);
+    }
+
+    #[test]
+    fn wfi_test() {
+        let mut cpu: Cpu = Cpu::new(None);
+        cpu.set_idle_sleep(false);
+        cpu.set_pc(0x100);
+        cpu.set_next_pc_abs(0x104);
+        cpu.set_timer(50);
+        cpu.get_csrs_mut().write(CsrFile::MIE, CsrFile::MTIP).unwrap();
+        // Woken up by the timer even though MIE is clear
+        assert_eq!(decode::<false>(0x10500073, &mut cpu), Ok(()));
+        assert_eq!(cpu.get_mtime(), 50);
+        assert_eq!(cpu.get_next_pc(), 0x104);
+        // Nothing can wake it up: WFI is executed again after a slice
+        cpu.get_csrs_mut().write(CsrFile::MIE, 0).unwrap();
+        assert_eq!(decode::<false>(0x10500073, &mut cpu), Ok(()));
+        assert_eq!(cpu.get_next_pc(), 0x100);
+        cpu.get_csrs_mut().set_privilege(Privilege::User);
+        assert_eq!(decode::<false>(0x10500073, &mut cpu), Err(Trap::IllegalInstruction(0)));
+    }
+
+    #[test]
+    fn fetch_fault_test() {
+        let mut cpu: Cpu = Cpu::new(Some(crate::memory::Memory::DRAM_DEFAULT_SIZE));
+        cpu.set_read_write_segment(0x0);
+        cpu.write_csreg(crate::csr::CsrFile::MTVEC, 0x100).unwrap();
+        // The fault is delivered to the trap handler of the program...
+        cpu.set_pc(0x4000_0000);
+        cpu.step_with_state();
+        assert_eq!(cpu.get_pc(), 0x100);
+        assert!(!cpu.is_finished());
+        // ...unless the program is stopped
+        cpu.set_fetch_fault_action(FetchFaultAction::Stop);
+        cpu.set_pc(0x4000_0000);
+        cpu.step_with_state();
+        assert_eq!(cpu.get_unhandled_trap(), Some(Trap::InstructionAccessFault(0x4000_0000)));
+        assert!(cpu.left_mapped_memory());
+        assert_eq!(FetchFaultAction::parse("debug"), Ok(FetchFaultAction::Debug));
+        assert!(FetchFaultAction::parse("panic").is_err());
+    }
+
+    #[test]
+    fn trap_storm_test() {
+        let mut cpu: Cpu = Cpu::new(Some(crate::memory::Memory::DRAM_DEFAULT_SIZE));
+        cpu.set_read_write_segment(0x0);
+        // The handler is an illegal word (0), so it traps to itself forever:
+        // every trap counts against the bound of the loop
+        cpu.write_csreg(crate::csr::CsrFile::MTVEC, 0x100).unwrap();
+        cpu.set_pc(0x100);
+        assert_eq!(cpu.cpu_loop_bounded(100), 100);
+        assert_eq!(cpu.get_pc(), 0x100);
+        assert!(!cpu.is_finished());
+        // The same when the handler is not mapped
+        cpu.write_csreg(crate::csr::CsrFile::MTVEC, 0x4000_0000).unwrap();
+        assert_eq!(cpu.cpu_loop_bounded(100), 100);
+        assert_eq!(cpu.get_pc(), 0x4000_0000);
+    }
+
+    #[test]
+    fn rv32_test() {
+        let mut cpu: Cpu = Cpu::new(None);
+        cpu.set_xlen(Xlen::Rv32);
+        // Registers keep the 32 bit result sign-extended
+        cpu.write_reg(1, 0x7fff_ffff);
+        addi::<false>(&mut cpu, 1, 2, 1);
+        assert_eq!(cpu.read_reg(2), 0xffff_ffff_8000_0000);
+        // Logical right shifts do not shift in the sign extension
+        srli_srai::<false>(&mut cpu, 2, 3, 4);
+        assert_eq!(cpu.read_reg(3), 0x0800_0000);
+        cpu.write_reg(4, 35);
+        srl::<false>(&mut cpu, 2, 4, 5);
+        assert_eq!(cpu.read_reg(5), 0x1000_0000);
+        // LD, ADDIW and SLLI with shamt[5] set are RV64 only
+        assert!(predecode::<false>(0x0000b083, Xlen::Rv32).is_none());
+        assert!(predecode::<false>(0x0010809b, Xlen::Rv32).is_none());
+        assert!(predecode::<false>(0x02009093, Xlen::Rv32).is_none());
+        assert!(predecode::<false>(0x0000b083, Xlen::Rv64).is_some());
+        // The PC wraps around at 4 GiB
+        cpu.set_pc(0xffff_fffc);
+        cpu.set_next_pc_rel(8);
+        assert_eq!(cpu.get_next_pc(), 0x4);
+        assert_eq!(cpu.read_csreg(crate::csr::CsrFile::MISA).unwrap() >> 30, 0b01);
+    }
+
+    #[test]
+    fn bitmanip_test() {
+        let mut cpu: Cpu = Cpu::new(None);
+        cpu.write_reg(1, 0xffff_ffff_0000_00f0);
+        cpu.write_reg(2, 0x10);
+        // sh3add.uw a3, ra, sp and sh1add a3, ra, sp
+        decode::<false>(0x2020e6bb, &mut cpu).unwrap();
+        assert_eq!(cpu.read_reg(13), 0x790);
+        decode::<false>(0x2020a6b3, &mut cpu).unwrap();
+        assert_eq!(cpu.read_reg(13), 0xffff_fffe_0000_01f0);
+        clz::<false>(&mut cpu, 2, 3);
+        ctz::<false>(&mut cpu, 1, 4);
+        cpop::<false>(&mut cpu, 1, 5);
+        assert_eq!((cpu.read_reg(3), cpu.read_reg(4), cpu.read_reg(5)), (59, 4, 36));
+        min::<false>(&mut cpu, 1, 2, 3);
+        maxu::<false>(&mut cpu, 1, 2, 4);
+        assert_eq!(cpu.read_reg(3), 0xffff_ffff_0000_00f0);
+        assert_eq!(cpu.read_reg(4), 0xffff_ffff_0000_00f0);
+        orc_b::<false>(&mut cpu, 1, 3);
+        rev8::<false>(&mut cpu, 1, 4);
+        assert_eq!(cpu.read_reg(3), 0xffff_ffff_0000_00ff);
+        assert_eq!(cpu.read_reg(4), 0xf000_0000_ffff_ffff);
+        ror::<false>(&mut cpu, 1, 2, 3);
+        assert_eq!(cpu.read_reg(3), 0x00f0_ffff_ffff_0000);
+        bseti::<false>(&mut cpu, 0, 3, 63);
+        bext::<false>(&mut cpu, 1, 2, 4);
+        bexti::<false>(&mut cpu, 1, 5, 4);
+        assert_eq!((cpu.read_reg(3), cpu.read_reg(4), cpu.read_reg(5)), (1 << 63, 0, 1));
+        // In RV32 mode the results are computed on 32 bits
+        cpu.set_xlen(Xlen::Rv32);
+        cpu.write_reg(1, 0x8000_00f0);
+        clz::<false>(&mut cpu, 2, 3);
+        cpop::<false>(&mut cpu, 1, 4);
+        assert_eq!((cpu.read_reg(3), cpu.read_reg(4)), (27, 5));
+        rori::<false>(&mut cpu, 1, 3, 4);
+        rev8::<false>(&mut cpu, 1, 4);
+        assert_eq!(cpu.read_reg(3), 0x0800_000f);
+        assert_eq!(cpu.read_reg(4), 0xffff_ffff_f000_0080);
+        bclri::<false>(&mut cpu, 1, 3, 31);
+        assert_eq!(cpu.read_reg(3), 0xf0);
+        // rev8 and zext.h have a different encoding in RV32 and RV64
+        assert!(predecode::<false>(0x69855513, Xlen::Rv32).is_some());
+        assert!(predecode::<false>(0x6b855513, Xlen::Rv32).is_none());
+        assert!(predecode::<false>(0x08054533, Xlen::Rv64).is_none());
+        assert!(predecode::<false>(0x0805453b, Xlen::Rv32).is_none());
+    }
+
+    #[test]
+    fn zicond_zbc_test() {
+        let mut cpu: Cpu = Cpu::new(None);
+        cpu.write_reg(1, 0x1234);
+        czero_eqz::<false>(&mut cpu, 1, 0, 3);
+        czero_nez::<false>(&mut cpu, 1, 0, 4);
+        assert_eq!((cpu.read_reg(3), cpu.read_reg(4)), (0, 0x1234));
+        cpu.write_reg(2, 0x8000_0000_0000_0003);
+        // czero.nez a3, ra, sp
+        decode::<false>(0x0e20f6b3, &mut cpu).unwrap();
+        assert_eq!(cpu.read_reg(13), 0);
+        // 0x1234 * 0b11 without carries is 0x1234 ^ 0x2468
+        clmul::<false>(&mut cpu, 1, 2, 3);
+        clmulh::<false>(&mut cpu, 1, 2, 4);
+        clmulr::<false>(&mut cpu, 1, 2, 5);
+        assert_eq!(cpu.read_reg(3), 0x1234 ^ 0x2468 ^ (0x1234 << 63));
+        assert_eq!(cpu.read_reg(4), 0x1234 >> 1);
+        assert_eq!(cpu.read_reg(5), 0x1234);
+        // On 32 bits the high half starts at bit 32
+        cpu.set_xlen(Xlen::Rv32);
+        cpu.write_reg(2, 0x8000_0000);
+        clmulh::<false>(&mut cpu, 1, 2, 4);
+        assert_eq!(cpu.read_reg(4), 0x1234 >> 1);
+    }
 }
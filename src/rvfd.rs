@@ -0,0 +1,569 @@
+use crate::cpu::Cpu;
+use crate::cpu::Instruction;
+use crate::cpu::Xlen;
+use crate::fpu::Fpu;
+use crate::memory::AccessSize;
+use crate::rv::{decode_immediate_stype, Handler, OpCodes, Operands};
+use crate::softfloat::{Format, RoundingMode};
+use crate::trap::Trap;
+
+// Major operation of an OP-FP instruction, in instr[31:27]
+struct Funct5;
+impl Funct5 {
+    const FADD:    u8 = 0b00000;
+    const FSUB:    u8 = 0b00001;
+    const FMUL:    u8 = 0b00010;
+    const FDIV:    u8 = 0b00011;
+    const FSGNJ:   u8 = 0b00100;
+    const FMINMAX: u8 = 0b00101;
+    const FCVT_FF: u8 = 0b01000;
+    const FSQRT:   u8 = 0b01011;
+    const FCMP:    u8 = 0b10100;
+    const FCVT_XF: u8 = 0b11000;
+    const FCVT_FX: u8 = 0b11010;
+    const FMV_XF:  u8 = 0b11100;
+    const FMV_FX:  u8 = 0b11110;
+}
+
+/// Find the function that executes an instruction of the F and D
+/// extensions, None if the instruction is illegal. Instructions with a
+/// reserved static rounding mode are illegal, while a reserved dynamic
+/// rounding mode (frm) is only detected when the instruction is executed
+pub(crate) fn predecode<const DEBUG: bool>(instr: Instruction, xlen: Xlen) -> Option<Handler> {
+    let opcode = (instr & 0x7f) as u8;
+    // f3 = instr[14:12], the rounding mode of the arithmetic instructions
+    let f3 = ((instr >> 12) & 0x7) as u8;
+    // fmt = instr[26:25]: single or double precision (half and quad are not supported)
+    let fmt = ((instr >> 25) & 0x3) as u8;
+    let f5 = (instr >> 27) as u8;
+    // rs2 = instr[24:20] selects the variant of the conversions
+    let rs2 = ((instr >> 20) & 0x1f) as u8;
+    let rv64: bool = xlen == Xlen::Rv64;
+    let valid_rm: bool = f3 != 0b101 && f3 != 0b110;
+
+    match opcode {
+        OpCodes::LOAD_FP => match f3 {
+            0b010 => Some(fload::<false>),
+            0b011 => Some(fload::<true>),
+            _ => None
+        },
+        OpCodes::STORE_FP => match f3 {
+            0b010 => Some(fstore::<DEBUG, false>),
+            0b011 => Some(fstore::<DEBUG, true>),
+            _ => None
+        },
+        OpCodes::MADD if valid_rm => precision(fmt, fmadd::<false>, fmadd::<true>),
+        OpCodes::MSUB if valid_rm => precision(fmt, fmsub::<false>, fmsub::<true>),
+        OpCodes::NMSUB if valid_rm => precision(fmt, fnmsub::<false>, fnmsub::<true>),
+        OpCodes::NMADD if valid_rm => precision(fmt, fnmadd::<false>, fnmadd::<true>),
+        OpCodes::OP_FP => match (f5, f3, rs2) {
+            (Funct5::FADD, _, _) if valid_rm => precision(fmt, fadd::<false>, fadd::<true>),
+            (Funct5::FSUB, _, _) if valid_rm => precision(fmt, fsub::<false>, fsub::<true>),
+            (Funct5::FMUL, _, _) if valid_rm => precision(fmt, fmul::<false>, fmul::<true>),
+            (Funct5::FDIV, _, _) if valid_rm => precision(fmt, fdiv::<false>, fdiv::<true>),
+            (Funct5::FSQRT, _, 0) if valid_rm => precision(fmt, fsqrt::<false>, fsqrt::<true>),
+            (Funct5::FSGNJ, 0b000, _) => precision(fmt, fsgnj::<false>, fsgnj::<true>),
+            (Funct5::FSGNJ, 0b001, _) => precision(fmt, fsgnjn::<false>, fsgnjn::<true>),
+            (Funct5::FSGNJ, 0b010, _) => precision(fmt, fsgnjx::<false>, fsgnjx::<true>),
+            (Funct5::FMINMAX, 0b000, _) => precision(fmt, fmin::<false>, fmin::<true>),
+            (Funct5::FMINMAX, 0b001, _) => precision(fmt, fmax::<false>, fmax::<true>),
+            // FCVT.S.D and FCVT.D.S: rs2 is the source format
+            (Funct5::FCVT_FF, _, 1) if valid_rm && fmt == 0 => Some(fcvt_s_d),
+            (Funct5::FCVT_FF, _, 0) if valid_rm && fmt == 1 => Some(fcvt_d_s),
+            (Funct5::FCMP, 0b000, _) => precision(fmt, fle::<false>, fle::<true>),
+            (Funct5::FCMP, 0b001, _) => precision(fmt, flt::<false>, flt::<true>),
+            (Funct5::FCMP, 0b010, _) => precision(fmt, feq::<false>, feq::<true>),
+            // Conversions to and from integers: rs2 selects W, WU, L or LU
+            (Funct5::FCVT_XF, _, 0) if valid_rm => precision(fmt, fcvt_w::<false>, fcvt_w::<true>),
+            (Funct5::FCVT_XF, _, 1) if valid_rm => precision(fmt, fcvt_wu::<false>, fcvt_wu::<true>),
+            (Funct5::FCVT_XF, _, 2) if valid_rm && rv64 => precision(fmt, fcvt_l::<false>, fcvt_l::<true>),
+            (Funct5::FCVT_XF, _, 3) if valid_rm && rv64 => precision(fmt, fcvt_lu::<false>, fcvt_lu::<true>),
+            (Funct5::FCVT_FX, _, 0) if valid_rm => precision(fmt, fcvt_from_w::<false>, fcvt_from_w::<true>),
+            (Funct5::FCVT_FX, _, 1) if valid_rm => precision(fmt, fcvt_from_wu::<false>, fcvt_from_wu::<true>),
+            (Funct5::FCVT_FX, _, 2) if valid_rm && rv64 => precision(fmt, fcvt_from_l::<false>, fcvt_from_l::<true>),
+            (Funct5::FCVT_FX, _, 3) if valid_rm && rv64 => precision(fmt, fcvt_from_lu::<false>, fcvt_from_lu::<true>),
+            // FMV.X.D and FMV.D.X move 64 bits, so they only exist on RV64
+            (Funct5::FMV_XF, 0b000, 0) if fmt == 0 || rv64 => precision(fmt, fmv_x_f::<false>, fmv_x_f::<true>),
+            (Funct5::FMV_XF, 0b001, 0) => precision(fmt, fclass::<false>, fclass::<true>),
+            (Funct5::FMV_FX, 0b000, 0) if fmt == 0 || rv64 => precision(fmt, fmv_f_x::<false>, fmv_f_x::<true>),
+            _ => None
+        },
+        _ => None
+    }
+}
+
+/// Check if an OP-FP instruction writes an integer register (rd) rather
+/// than a floating-point one: comparisons, conversions to integers,
+/// FMV.X.W, FMV.X.D and FCLASS
+pub(crate) fn integer_destination(instr: Instruction) -> bool {
+    matches!((instr >> 27) as u8, Funct5::FCMP | Funct5::FCVT_XF | Funct5::FMV_XF)
+}
+
+// Handler of the single or double precision variant of an instruction
+fn precision(fmt: u8, single: Handler, double: Handler) -> Option<Handler> {
+    match fmt {
+        0 => Some(single),
+        1 => Some(double),
+        _ => None
+    }
+}
+
+#[inline(always)]
+fn format<const DOUBLE: bool>() -> Format {
+    if DOUBLE { Format::F64 } else { Format::F32 }
+}
+
+// The F and D instructions are illegal when mstatus.FS is Off
+#[inline(always)]
+fn check_enabled(curcpu: &Cpu) -> Result<(), Trap> {
+    if curcpu.get_csrs().float_enabled() {
+        Ok(())
+    } else {
+        Err(Trap::IllegalInstruction(0))
+    }
+}
+
+// Rounding mode of an instruction: the rm field (instr[14:12]), or frm
+// when it is dynamic (7). A reserved mode in frm is an illegal instruction
+#[inline(always)]
+fn rounding_mode(curcpu: &Cpu, op: &Operands) -> Result<RoundingMode, Trap> {
+    check_enabled(curcpu)?;
+    let rm: u64 = match op.imm20 & 0x7 {
+        0b111 => curcpu.get_csrs().get_frm(),
+        rm => rm as u64
+    };
+    RoundingMode::from_bits(rm).ok_or(Trap::IllegalInstruction(0))
+}
+
+// Accrue the exception flags raised by an instruction into fflags
+#[inline(always)]
+fn accrue(curcpu: &mut Cpu, flags: u8) {
+    curcpu.get_csrs_mut().accrue_fflags(flags);
+}
+
+// FLW and FLD instructions
+// rd <- memory[signed'rs1 + signed'imm]
+#[inline(always)]
+fn fload<const DOUBLE: bool>(curcpu: &mut Cpu, op: &Operands) -> Result<(), Trap> {
+    check_enabled(curcpu)?;
+    let addr: u64 = (curcpu.read_reg(op.rs1) as i64 + op.imm12 as i32 as i64) as u64;
+    let size: AccessSize = if DOUBLE { AccessSize::DOUBLEWORD } else { AccessSize::WORD };
+    let data: u64 = curcpu.load(addr, size)?;
+    curcpu.write_freg(op.rd, format::<DOUBLE>(), data);
+    Ok(())
+}
+
+// FSW and FSD instructions
+// memory[signed'rs1 + signed'imm] = rs2
+#[inline(always)]
+fn fstore<const DEBUG: bool, const DOUBLE: bool>(curcpu: &mut Cpu, op: &Operands) -> Result<(), Trap> {
+    check_enabled(curcpu)?;
+    // FSW stores the low 32 bits whether the register is NaN-boxed or not
+    let (size, data) = match DOUBLE {
+        true => (AccessSize::DOUBLEWORD, curcpu.get_fpu().read_raw(op.rs2)),
+        false => (AccessSize::WORD, curcpu.get_fpu().read_raw(op.rs2) & 0xffff_ffff)
+    };
+    let addr: u64 = (curcpu.read_reg(op.rs1) as i64 + decode_immediate_stype(op.imm5, op.imm12)) as u64;
+    if DEBUG {
+        curcpu.log_memory_write(addr, size, data);
+    }
+    curcpu.store(data, addr, size)
+}
+
+// Arithmetic on rs1 and rs2, with the result written to rd
+#[inline(always)]
+fn arithmetic<const DOUBLE: bool>(curcpu: &mut Cpu, op: &Operands,
+                                  compute: fn(&Fpu, Format, u64, u64, RoundingMode, &mut u8) -> u64) -> Result<(), Trap> {
+    let rm: RoundingMode = rounding_mode(curcpu, op)?;
+    let fmt: Format = format::<DOUBLE>();
+    let mut flags: u8 = 0;
+    let result: u64 = compute(curcpu.get_fpu(), fmt, curcpu.read_freg(op.rs1, fmt), curcpu.read_freg(op.rs2, fmt), rm, &mut flags);
+    curcpu.write_freg(op.rd, fmt, result);
+    accrue(curcpu, flags);
+    Ok(())
+}
+
+// FADD.S and FADD.D instructions
+// rd <- rs1 + rs2
+fn fadd<const DOUBLE: bool>(curcpu: &mut Cpu, op: &Operands) -> Result<(), Trap> {
+    arithmetic::<DOUBLE>(curcpu, op, Fpu::add)
+}
+
+// FSUB.S and FSUB.D instructions
+// rd <- rs1 - rs2
+fn fsub<const DOUBLE: bool>(curcpu: &mut Cpu, op: &Operands) -> Result<(), Trap> {
+    arithmetic::<DOUBLE>(curcpu, op, Fpu::sub)
+}
+
+// FMUL.S and FMUL.D instructions
+// rd <- rs1 * rs2
+fn fmul<const DOUBLE: bool>(curcpu: &mut Cpu, op: &Operands) -> Result<(), Trap> {
+    arithmetic::<DOUBLE>(curcpu, op, Fpu::mul)
+}
+
+// FDIV.S and FDIV.D instructions
+// rd <- rs1 / rs2
+fn fdiv<const DOUBLE: bool>(curcpu: &mut Cpu, op: &Operands) -> Result<(), Trap> {
+    arithmetic::<DOUBLE>(curcpu, op, Fpu::div)
+}
+
+// FSQRT.S and FSQRT.D instructions
+// rd <- sqrt(rs1)
+fn fsqrt<const DOUBLE: bool>(curcpu: &mut Cpu, op: &Operands) -> Result<(), Trap> {
+    let rm: RoundingMode = rounding_mode(curcpu, op)?;
+    let fmt: Format = format::<DOUBLE>();
+    let mut flags: u8 = 0;
+    let result: u64 = curcpu.get_fpu().sqrt(fmt, curcpu.read_freg(op.rs1, fmt), rm, &mut flags);
+    curcpu.write_freg(op.rd, fmt, result);
+    accrue(curcpu, flags);
+    Ok(())
+}
+
+// Fused multiply-add of rs1, rs2 and rs3 (instr[31:27]), with the
+// product and the addend optionally negated
+#[inline(always)]
+fn fused<const DOUBLE: bool>(curcpu: &mut Cpu, op: &Operands, negate_product: bool, negate_addend: bool) -> Result<(), Trap> {
+    let rm: RoundingMode = rounding_mode(curcpu, op)?;
+    let fmt: Format = format::<DOUBLE>();
+    let rs3 = ((op.imm12 >> 7) & 0x1f) as u8;
+    let negate = |value: u64, negate: bool| if negate { value ^ fmt.sign_bit() } else { value };
+    let a: u64 = negate(curcpu.read_freg(op.rs1, fmt), negate_product);
+    let c: u64 = negate(curcpu.read_freg(rs3, fmt), negate_addend);
+    let mut flags: u8 = 0;
+    let result: u64 = curcpu.get_fpu().fma(fmt, a, curcpu.read_freg(op.rs2, fmt), c, rm, &mut flags);
+    curcpu.write_freg(op.rd, fmt, result);
+    accrue(curcpu, flags);
+    Ok(())
+}
+
+// FMADD.S and FMADD.D instructions
+// rd <- rs1 * rs2 + rs3
+fn fmadd<const DOUBLE: bool>(curcpu: &mut Cpu, op: &Operands) -> Result<(), Trap> {
+    fused::<DOUBLE>(curcpu, op, false, false)
+}
+
+// FMSUB.S and FMSUB.D instructions
+// rd <- rs1 * rs2 - rs3
+fn fmsub<const DOUBLE: bool>(curcpu: &mut Cpu, op: &Operands) -> Result<(), Trap> {
+    fused::<DOUBLE>(curcpu, op, false, true)
+}
+
+// FNMSUB.S and FNMSUB.D instructions
+// rd <- -(rs1 * rs2) + rs3
+fn fnmsub<const DOUBLE: bool>(curcpu: &mut Cpu, op: &Operands) -> Result<(), Trap> {
+    fused::<DOUBLE>(curcpu, op, true, false)
+}
+
+// FNMADD.S and FNMADD.D instructions
+// rd <- -(rs1 * rs2) - rs3
+fn fnmadd<const DOUBLE: bool>(curcpu: &mut Cpu, op: &Operands) -> Result<(), Trap> {
+    fused::<DOUBLE>(curcpu, op, true, true)
+}
+
+// Sign injection: rs1 with the sign computed from the signs of rs1 and rs2
+#[inline(always)]
+fn sign_injection<const DOUBLE: bool>(curcpu: &mut Cpu, op: &Operands, sign: fn(bool, bool) -> bool) -> Result<(), Trap> {
+    check_enabled(curcpu)?;
+    let fmt: Format = format::<DOUBLE>();
+    let a: u64 = curcpu.read_freg(op.rs1, fmt);
+    let b: u64 = curcpu.read_freg(op.rs2, fmt);
+    let magnitude: u64 = a & !fmt.sign_bit();
+    let result: u64 = if sign(fmt.sign(a), fmt.sign(b)) { magnitude | fmt.sign_bit() } else { magnitude };
+    curcpu.write_freg(op.rd, fmt, result);
+    Ok(())
+}
+
+// FSGNJ.S and FSGNJ.D instructions (FMV.S and FMV.D when rs1 = rs2)
+// rd <- {rs2[sign], rs1[magnitude]}
+fn fsgnj<const DOUBLE: bool>(curcpu: &mut Cpu, op: &Operands) -> Result<(), Trap> {
+    sign_injection::<DOUBLE>(curcpu, op, |_, b| b)
+}
+
+// FSGNJN.S and FSGNJN.D instructions (FNEG.S and FNEG.D when rs1 = rs2)
+// rd <- {!rs2[sign], rs1[magnitude]}
+fn fsgnjn<const DOUBLE: bool>(curcpu: &mut Cpu, op: &Operands) -> Result<(), Trap> {
+    sign_injection::<DOUBLE>(curcpu, op, |_, b| !b)
+}
+
+// FSGNJX.S and FSGNJX.D instructions (FABS.S and FABS.D when rs1 = rs2)
+// rd <- {rs1[sign] ^ rs2[sign], rs1[magnitude]}
+fn fsgnjx<const DOUBLE: bool>(curcpu: &mut Cpu, op: &Operands) -> Result<(), Trap> {
+    sign_injection::<DOUBLE>(curcpu, op, |a, b| a ^ b)
+}
+
+// FMIN.S and FMIN.D instructions
+// rd <- min(rs1, rs2)
+fn fmin<const DOUBLE: bool>(curcpu: &mut Cpu, op: &Operands) -> Result<(), Trap> {
+    check_enabled(curcpu)?;
+    let fmt: Format = format::<DOUBLE>();
+    let mut flags: u8 = 0;
+    let result: u64 = fmt.min(curcpu.read_freg(op.rs1, fmt), curcpu.read_freg(op.rs2, fmt), &mut flags);
+    curcpu.write_freg(op.rd, fmt, result);
+    accrue(curcpu, flags);
+    Ok(())
+}
+
+// FMAX.S and FMAX.D instructions
+// rd <- max(rs1, rs2)
+fn fmax<const DOUBLE: bool>(curcpu: &mut Cpu, op: &Operands) -> Result<(), Trap> {
+    check_enabled(curcpu)?;
+    let fmt: Format = format::<DOUBLE>();
+    let mut flags: u8 = 0;
+    let result: u64 = fmt.max(curcpu.read_freg(op.rs1, fmt), curcpu.read_freg(op.rs2, fmt), &mut flags);
+    curcpu.write_freg(op.rd, fmt, result);
+    accrue(curcpu, flags);
+    Ok(())
+}
+
+// FCVT.S.D instruction
+// rd <- single'rs1
+fn fcvt_s_d(curcpu: &mut Cpu, op: &Operands) -> Result<(), Trap> {
+    let rm: RoundingMode = rounding_mode(curcpu, op)?;
+    let mut flags: u8 = 0;
+    let result: u64 = Format::F32.convert(Format::F64, curcpu.read_freg(op.rs1, Format::F64), rm, &mut flags);
+    curcpu.write_freg(op.rd, Format::F32, result);
+    accrue(curcpu, flags);
+    Ok(())
+}
+
+// FCVT.D.S instruction, always exact
+// rd <- double'rs1
+fn fcvt_d_s(curcpu: &mut Cpu, op: &Operands) -> Result<(), Trap> {
+    let rm: RoundingMode = rounding_mode(curcpu, op)?;
+    let mut flags: u8 = 0;
+    let result: u64 = Format::F64.convert(Format::F32, curcpu.read_freg(op.rs1, Format::F32), rm, &mut flags);
+    curcpu.write_freg(op.rd, Format::F64, result);
+    accrue(curcpu, flags);
+    Ok(())
+}
+
+// Comparison of rs1 and rs2, with the result (0 or 1) written to the integer rd
+#[inline(always)]
+fn compare<const DOUBLE: bool>(curcpu: &mut Cpu, op: &Operands, compare: fn(Format, u64, u64, &mut u8) -> bool) -> Result<(), Trap> {
+    check_enabled(curcpu)?;
+    let fmt: Format = format::<DOUBLE>();
+    let mut flags: u8 = 0;
+    let result: bool = compare(fmt, curcpu.read_freg(op.rs1, fmt), curcpu.read_freg(op.rs2, fmt), &mut flags);
+    curcpu.write_reg(op.rd, result as u64);
+    accrue(curcpu, flags);
+    Ok(())
+}
+
+// FEQ.S and FEQ.D instructions, quiet: only signaling NaNs are invalid
+// rd <- rs1 == rs2
+fn feq<const DOUBLE: bool>(curcpu: &mut Cpu, op: &Operands) -> Result<(), Trap> {
+    compare::<DOUBLE>(curcpu, op, Format::eq)
+}
+
+// FLT.S and FLT.D instructions, signaling: any NaN is invalid
+// rd <- rs1 < rs2
+fn flt<const DOUBLE: bool>(curcpu: &mut Cpu, op: &Operands) -> Result<(), Trap> {
+    compare::<DOUBLE>(curcpu, op, Format::lt)
+}
+
+// FLE.S and FLE.D instructions, signaling: any NaN is invalid
+// rd <- rs1 <= rs2
+fn fle<const DOUBLE: bool>(curcpu: &mut Cpu, op: &Operands) -> Result<(), Trap> {
+    compare::<DOUBLE>(curcpu, op, Format::le)
+}
+
+// Conversion of rs1 to an integer of width bits written to rd, saturated
+// when it is out of range
+#[inline(always)]
+fn to_integer<const DOUBLE: bool>(curcpu: &mut Cpu, op: &Operands, signed: bool, width: u32) -> Result<(), Trap> {
+    let rm: RoundingMode = rounding_mode(curcpu, op)?;
+    let fmt: Format = format::<DOUBLE>();
+    let mut flags: u8 = 0;
+    let result: u64 = fmt.to_int(curcpu.read_freg(op.rs1, fmt), signed, width, rm, &mut flags);
+    curcpu.write_reg(op.rd, result);
+    accrue(curcpu, flags);
+    Ok(())
+}
+
+// FCVT.W.S and FCVT.W.D instructions
+// rd <- signed'int32(rs1)
+fn fcvt_w<const DOUBLE: bool>(curcpu: &mut Cpu, op: &Operands) -> Result<(), Trap> {
+    to_integer::<DOUBLE>(curcpu, op, true, 32)
+}
+
+// FCVT.WU.S and FCVT.WU.D instructions, the result is sign-extended
+// rd <- signed'uint32(rs1)
+fn fcvt_wu<const DOUBLE: bool>(curcpu: &mut Cpu, op: &Operands) -> Result<(), Trap> {
+    to_integer::<DOUBLE>(curcpu, op, false, 32)
+}
+
+// FCVT.L.S and FCVT.L.D instructions
+// rd <- int64(rs1)
+fn fcvt_l<const DOUBLE: bool>(curcpu: &mut Cpu, op: &Operands) -> Result<(), Trap> {
+    to_integer::<DOUBLE>(curcpu, op, true, 64)
+}
+
+// FCVT.LU.S and FCVT.LU.D instructions
+// rd <- uint64(rs1)
+fn fcvt_lu<const DOUBLE: bool>(curcpu: &mut Cpu, op: &Operands) -> Result<(), Trap> {
+    to_integer::<DOUBLE>(curcpu, op, false, 64)
+}
+
+// Conversion of the low width bits of the integer rs1 to rd
+#[inline(always)]
+fn from_integer<const DOUBLE: bool>(curcpu: &mut Cpu, op: &Operands, signed: bool, width: u32) -> Result<(), Trap> {
+    let rm: RoundingMode = rounding_mode(curcpu, op)?;
+    let fmt: Format = format::<DOUBLE>();
+    let mut flags: u8 = 0;
+    let result: u64 = fmt.from_int(curcpu.read_reg(op.rs1), signed, width, rm, &mut flags);
+    curcpu.write_freg(op.rd, fmt, result);
+    accrue(curcpu, flags);
+    Ok(())
+}
+
+// FCVT.S.W and FCVT.D.W instructions
+// rd <- float'(signed'rs1[31:0])
+fn fcvt_from_w<const DOUBLE: bool>(curcpu: &mut Cpu, op: &Operands) -> Result<(), Trap> {
+    from_integer::<DOUBLE>(curcpu, op, true, 32)
+}
+
+// FCVT.S.WU and FCVT.D.WU instructions
+// rd <- float'(unsigned'rs1[31:0])
+fn fcvt_from_wu<const DOUBLE: bool>(curcpu: &mut Cpu, op: &Operands) -> Result<(), Trap> {
+    from_integer::<DOUBLE>(curcpu, op, false, 32)
+}
+
+// FCVT.S.L and FCVT.D.L instructions
+// rd <- float'(signed'rs1)
+fn fcvt_from_l<const DOUBLE: bool>(curcpu: &mut Cpu, op: &Operands) -> Result<(), Trap> {
+    from_integer::<DOUBLE>(curcpu, op, true, 64)
+}
+
+// FCVT.S.LU and FCVT.D.LU instructions
+// rd <- float'(unsigned'rs1)
+fn fcvt_from_lu<const DOUBLE: bool>(curcpu: &mut Cpu, op: &Operands) -> Result<(), Trap> {
+    from_integer::<DOUBLE>(curcpu, op, false, 64)
+}
+
+// FMV.X.W and FMV.X.D instructions: the bits are moved unchanged, a
+// single-precision value is sign-extended and not checked for NaN-boxing
+// rd <- rs1
+fn fmv_x_f<const DOUBLE: bool>(curcpu: &mut Cpu, op: &Operands) -> Result<(), Trap> {
+    check_enabled(curcpu)?;
+    let bits: u64 = curcpu.get_fpu().read_raw(op.rs1);
+    curcpu.write_reg(op.rd, if DOUBLE { bits } else { bits as i32 as i64 as u64 });
+    Ok(())
+}
+
+// FMV.W.X and FMV.D.X instructions
+// rd <- rs1
+fn fmv_f_x<const DOUBLE: bool>(curcpu: &mut Cpu, op: &Operands) -> Result<(), Trap> {
+    check_enabled(curcpu)?;
+    let bits: u64 = curcpu.read_reg(op.rs1);
+    let fmt: Format = format::<DOUBLE>();
+    curcpu.write_freg(op.rd, fmt, if DOUBLE { bits } else { bits & 0xffff_ffff });
+    Ok(())
+}
+
+// FCLASS.S and FCLASS.D instructions
+// rd <- class(rs1)
+fn fclass<const DOUBLE: bool>(curcpu: &mut Cpu, op: &Operands) -> Result<(), Trap> {
+    check_enabled(curcpu)?;
+    let fmt: Format = format::<DOUBLE>();
+    let class: u64 = fmt.classify(curcpu.read_freg(op.rs1, fmt));
+    curcpu.write_reg(op.rd, class);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cpu::{Cpu, Xlen};
+    use crate::csr::CsrFile;
+    use crate::fpu::FloatMode;
+    use crate::rv::{decode, predecode};
+    use crate::softfloat::{Format, FLAG_INEXACT, FLAG_INVALID};
+    use crate::trap::Trap;
+
+    // R-type encoding of an OP-FP instruction
+    fn op_fp(funct7: u32, rs2: u32, rs1: u32, rm: u32, rd: u32) -> u32 {
+        (funct7 << 25) | (rs2 << 20) | (rs1 << 15) | (rm << 12) | (rd << 7) | 0b1010011
+    }
+
+    #[test]
+    fn arithmetic_test() {
+        let mut cpu: Cpu = Cpu::new(None);
+        cpu.set_float_mode(FloatMode::Soft);
+        // fmv.w.x f1, x1 and fmv.w.x f2, x2
+        cpu.write_reg(1, 0.1f32.to_bits() as u64);
+        cpu.write_reg(2, 0.2f32.to_bits() as u64);
+        decode::<false>(op_fp(0b1111000, 0, 1, 0, 1), &mut cpu).unwrap();
+        decode::<false>(op_fp(0b1111000, 0, 2, 0, 2), &mut cpu).unwrap();
+        assert_eq!(cpu.get_fpu().read_raw(1), 0xffff_ffff_0000_0000 | 0.1f32.to_bits() as u64);
+        // fadd.s f3, f1, f2 is inexact
+        decode::<false>(op_fp(0b0000000, 2, 1, 0, 3), &mut cpu).unwrap();
+        assert_eq!(cpu.read_freg(3, Format::F32), (0.1f32 + 0.2f32).to_bits() as u64);
+        assert_eq!(cpu.get_csrs().read(CsrFile::FFLAGS), Some(FLAG_INEXACT as u64));
+        assert_eq!(cpu.get_csrs().read(CsrFile::MSTATUS).unwrap() >> 63, 1);
+        // fmadd.s f4, f1, f2, f3 (rs3 in instr[31:27])
+        decode::<false>((3 << 27) | op_fp(0, 2, 1, 0, 4) & !0x7f | 0b1000011, &mut cpu).unwrap();
+        assert_eq!(cpu.read_freg(4, Format::F32), 0.1f32.mul_add(0.2, 0.1 + 0.2).to_bits() as u64);
+        // fcvt.d.s f5, f3 and fle.d x3, f3, f5: the single is not NaN-boxed as a double
+        decode::<false>(op_fp(0b0100001, 0, 3, 0, 5), &mut cpu).unwrap();
+        assert_eq!(cpu.read_freg(5, Format::F64), ((0.1f32 + 0.2f32) as f64).to_bits());
+        decode::<false>(op_fp(0b1010001, 5, 3, 0, 3), &mut cpu).unwrap();
+        assert_eq!(cpu.read_reg(3), 0);
+        assert_eq!(cpu.get_csrs().read(CsrFile::FFLAGS), Some((FLAG_INVALID | FLAG_INEXACT) as u64));
+        // fclass.d x4, f5: positive normal
+        decode::<false>(op_fp(0b1110001, 0, 5, 1, 4), &mut cpu).unwrap();
+        assert_eq!(cpu.read_reg(4), 1 << 6);
+        // fsgnjn.d f6, f5, f5 and fmv.x.d x5, f6
+        decode::<false>(op_fp(0b0010001, 5, 5, 1, 6), &mut cpu).unwrap();
+        decode::<false>(op_fp(0b1110001, 0, 6, 0, 5), &mut cpu).unwrap();
+        assert_eq!(cpu.read_reg(5), (-((0.1f32 + 0.2f32) as f64)).to_bits());
+    }
+
+    #[test]
+    fn conversion_test() {
+        let mut cpu: Cpu = Cpu::new(None);
+        cpu.write_reg(1, -7i64 as u64);
+        // fcvt.d.w f1, x1
+        decode::<false>(op_fp(0b1101001, 0, 1, 0, 1), &mut cpu).unwrap();
+        assert_eq!(cpu.read_freg(1, Format::F64), (-7.0f64).to_bits());
+        // fdiv.d f3, f1, f2 with the dynamic rounding mode toward zero
+        cpu.write_reg(2, 2.0f64.to_bits());
+        decode::<false>(op_fp(0b1111001, 0, 2, 0, 2), &mut cpu).unwrap();
+        cpu.get_csrs_mut().write(CsrFile::FRM, 1).unwrap();
+        decode::<false>(op_fp(0b0001101, 2, 1, 7, 3), &mut cpu).unwrap();
+        assert_eq!(cpu.read_freg(3, Format::F64), (-3.5f64).to_bits());
+        // fcvt.w.d x3, f3, dynamic: -3, fcvt.w.d x4, f3, rne: -4
+        decode::<false>(op_fp(0b1100001, 0, 3, 7, 3), &mut cpu).unwrap();
+        decode::<false>(op_fp(0b1100001, 0, 3, 0, 4), &mut cpu).unwrap();
+        assert_eq!(cpu.read_reg(3), -3i64 as u64);
+        assert_eq!(cpu.read_reg(4), -4i64 as u64);
+        // fcvt.wu.d x5, f3 saturates to 0
+        decode::<false>(op_fp(0b1100001, 1, 3, 1, 5), &mut cpu).unwrap();
+        assert_eq!(cpu.read_reg(5), 0);
+        assert_eq!(cpu.get_csrs().read(CsrFile::FFLAGS).unwrap() & FLAG_INVALID as u64, FLAG_INVALID as u64);
+        // A reserved rounding mode in frm is illegal at execution, in rm at decode
+        cpu.get_csrs_mut().write(CsrFile::FRM, 5).unwrap();
+        assert_eq!(decode::<false>(op_fp(0b0001101, 2, 1, 7, 3), &mut cpu), Err(Trap::IllegalInstruction(0)));
+        assert!(predecode::<false>(op_fp(0b0001101, 2, 1, 5, 3), Xlen::Rv64).is_none());
+        // FCVT.L.D and FMV.X.D only exist on RV64
+        assert!(predecode::<false>(op_fp(0b1100001, 2, 3, 0, 3), Xlen::Rv64).is_some());
+        assert!(predecode::<false>(op_fp(0b1100001, 2, 3, 0, 3), Xlen::Rv32).is_none());
+        assert!(predecode::<false>(op_fp(0b1110001, 0, 3, 0, 3), Xlen::Rv32).is_none());
+    }
+
+    #[test]
+    fn load_store_test() {
+        let mut cpu: Cpu = Cpu::new(Some(crate::memory::Memory::DRAM_DEFAULT_SIZE));
+        cpu.set_read_write_segment(0x0);
+        cpu.write_reg(1, 1.5f32.to_bits() as u64);
+        // fmv.w.x f1, x1, fsw f1, 8(x0), fld f2, 8(x0)
+        decode::<false>(op_fp(0b1111000, 0, 1, 0, 1), &mut cpu).unwrap();
+        decode::<false>(0x00102427, &mut cpu).unwrap();
+        decode::<false>(0x00803107, &mut cpu).unwrap();
+        assert_eq!(cpu.get_fpu().read_raw(2), 1.5f32.to_bits() as u64);
+        // The double is not NaN-boxed, so as a single it is the canonical NaN
+        assert_eq!(cpu.read_freg(2, Format::F32), Format::F32.canonical_nan());
+        // With mstatus.FS Off the instructions are illegal
+        cpu.get_csrs_mut().write(CsrFile::MSTATUS, 0).unwrap();
+        assert_eq!(decode::<false>(0x00803107, &mut cpu), Err(Trap::IllegalInstruction(0)));
+        assert!(!cpu.get_csrs().is_accessible(CsrFile::FCSR));
+    }
+}
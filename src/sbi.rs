@@ -0,0 +1,317 @@
+use crate::cpu::{Cpu, RegIndex, Xlen};
+use crate::csr::CsrFile;
+use crate::memory::AccessSize;
+
+/// Outcome of an emulated SBI call
+pub enum SbiResult {
+    // Error code and value returned to the kernel in a0 and a1
+    Return { error: i64, value: u64 },
+    // Value returned in a0 by a legacy extension (a1 is left untouched)
+    Legacy(u64),
+    // The kernel asked to shut the machine down with this exit code
+    Shutdown(u64)
+}
+
+/// Emulation of the Supervisor Binary Interface provided by the M-mode
+/// firmware (e.g. OpenSBI), so that an S-mode kernel can be booted
+/// without one. When it is enabled, an ECALL from S-mode does not raise
+/// a trap: the extension is taken from a7, the function from a6 and the
+/// arguments from a0-a5, and the error and the value are returned in a0
+/// and a1. The extensions of version 2.0 of the specification that make
+/// sense on a single hart are served: base, the legacy ones, TIME, IPI,
+/// RFENCE, SRST and DBCN (debug console). HSM only reports hart 0 as
+/// started, no hart can be started or stopped. The console is the one of
+/// the UART, and the machine timer interrupt is forwarded to S-mode (STIP)
+#[derive(Clone, Default)]
+pub struct Sbi;
+
+impl Sbi {
+    // Legacy extensions (version 0.1), the extension ID is the function
+    const LEGACY_SET_TIMER:        u64 = 0x00;
+    const LEGACY_CONSOLE_PUTCHAR:  u64 = 0x01;
+    const LEGACY_CONSOLE_GETCHAR:  u64 = 0x02;
+    const LEGACY_CLEAR_IPI:        u64 = 0x03;
+    const LEGACY_SEND_IPI:         u64 = 0x04;
+    const LEGACY_REMOTE_FENCE_I:   u64 = 0x05;
+    const LEGACY_SHUTDOWN:         u64 = 0x08;
+
+    // Extension IDs
+    const EXT_BASE:   u64 = 0x10;
+    const EXT_TIME:   u64 = 0x54494d45;
+    const EXT_IPI:    u64 = 0x735049;
+    const EXT_RFENCE: u64 = 0x52464e43;
+    const EXT_HSM:    u64 = 0x48534d;
+    const EXT_SRST:   u64 = 0x53525354;
+    const EXT_DBCN:   u64 = 0x4442434e;
+
+    // Error codes
+    const ERR_NOT_SUPPORTED:     i64 = -2;
+    const ERR_INVALID_PARAM:     i64 = -3;
+    const ERR_DENIED:            i64 = -4;
+    const ERR_INVALID_ADDRESS:   i64 = -5;
+    const ERR_ALREADY_AVAILABLE: i64 = -6;
+
+    /// Version of the specification: 2.0 (major in bits 30:24)
+    pub const SPEC_VERSION: u64 = 2 << 24;
+    /// Implementation ID reported by the base extension (riviera has no
+    /// registered ID, this one is outside of the assigned range)
+    pub const IMPLEMENTATION_ID: u64 = 0x72697669;
+
+    // Hart state reported by HSM
+    const HART_STARTED: u64 = 0;
+
+    // Argument registers: a0-a5, then the function (a6) and the extension (a7)
+    const A0: RegIndex = 10;
+    const A6: RegIndex = 16;
+    const A7: RegIndex = 17;
+
+    /// Interrupt lines that the devices drive in mip when the firmware is
+    /// emulated: the machine timer interrupt becomes STIP
+    pub const DEVICE_INTERRUPTS: u64 = (CsrFile::DEVICE_INTERRUPTS & !CsrFile::MTIP) | CsrFile::STIP;
+
+    /// Exceptions delegated to the kernel: all the ones S-mode can handle
+    /// except for its own ECALLs, which are the SBI calls
+    pub const DELEGATED_EXCEPTIONS: u64 = 0xb1ff;
+    /// Interrupts delegated to the kernel
+    pub const DELEGATED_INTERRUPTS: u64 = CsrFile::SSIP | CsrFile::STIP | CsrFile::SEIP;
+
+    pub fn new() -> Sbi {
+        Sbi
+    }
+
+    /// Route the interrupt lines of the devices (see Bus::interrupt_lines)
+    /// as the firmware would: the machine timer interrupt is passed on to
+    /// S-mode, and cleared when the kernel sets the next timer event
+    #[inline(always)]
+    pub fn route_interrupts(lines: u64) -> u64 {
+        if lines & CsrFile::MTIP != 0 {
+            (lines & !CsrFile::MTIP) | CsrFile::STIP
+        } else {
+            lines
+        }
+    }
+
+    /// Serve the SBI call made by the kernel
+    pub fn handle(&mut self, cpu: &mut Cpu) -> SbiResult {
+        let extension: u64 = cpu.read_reg(Sbi::A7);
+        let function: u64 = cpu.read_reg(Sbi::A6);
+        let args: [u64; 6] = core::array::from_fn(|i| cpu.read_reg(Sbi::A0 + i as RegIndex));
+        let result: Result<u64, i64> = match extension {
+            Sbi::LEGACY_SET_TIMER..=Sbi::LEGACY_SHUTDOWN => return Sbi::legacy(cpu, extension, &args),
+            Sbi::EXT_BASE => Sbi::base(function, args[0]),
+            Sbi::EXT_TIME if function == 0 => {
+                cpu.set_timer(Sbi::wide_argument(cpu, args[0], args[1]));
+                Ok(0)
+            },
+            Sbi::EXT_IPI if function == 0 => Sbi::send_ipi(cpu, args[0], args[1]),
+            Sbi::EXT_RFENCE => Sbi::remote_fence(cpu, function, args[0], args[1]),
+            Sbi::EXT_HSM => Sbi::hart_management(function, args[0]),
+            Sbi::EXT_SRST if function == 0 => return Sbi::system_reset(args[1]),
+            Sbi::EXT_DBCN => Sbi::debug_console(cpu, function, &args),
+            _ => Err(Sbi::ERR_NOT_SUPPORTED)
+        };
+        match result {
+            Ok(value) => SbiResult::Return { error: 0, value },
+            Err(error) => SbiResult::Return { error, value: 0 }
+        }
+    }
+
+    fn legacy(cpu: &mut Cpu, extension: u64, args: &[u64; 6]) -> SbiResult {
+        let value: u64 = match extension {
+            Sbi::LEGACY_SET_TIMER => {
+                cpu.set_timer(Sbi::wide_argument(cpu, args[0], args[1]));
+                0
+            },
+            Sbi::LEGACY_CONSOLE_PUTCHAR => {
+                cpu.write_console(args[0] as u8);
+                0
+            },
+            Sbi::LEGACY_CONSOLE_GETCHAR => cpu.read_console().map_or(u64::MAX, |byte| byte as u64),
+            Sbi::LEGACY_CLEAR_IPI => {
+                cpu.get_csrs_mut().set_interrupt_pending(CsrFile::SSIP, 0);
+                0
+            },
+            // The mask of the harts is in memory: 0 (no mask) means all of them
+            Sbi::LEGACY_SEND_IPI => {
+                let mask: u64 = match args[0] {
+                    0 => 1,
+                    address => cpu.peek(address, AccessSize::DOUBLEWORD).unwrap_or(0)
+                };
+                Sbi::send_ipi(cpu, mask, 0).map_or_else(|error| error as u64, |_| 0)
+            },
+            Sbi::LEGACY_REMOTE_FENCE_I => {
+                cpu.flush_decode_cache();
+                0
+            },
+            Sbi::LEGACY_SHUTDOWN => return SbiResult::Shutdown(0),
+            // Remote SFENCE.VMA: there is no address translation
+            _ => 0
+        };
+        SbiResult::Legacy(value)
+    }
+
+    fn base(function: u64, argument: u64) -> Result<u64, i64> {
+        match function {
+            0 => Ok(Sbi::SPEC_VERSION),
+            1 => Ok(Sbi::IMPLEMENTATION_ID),
+            2 => Ok(Sbi::implementation_version()),
+            3 => Ok(Sbi::is_supported(argument) as u64),
+            // mvendorid, marchid and mimpid
+            4..=6 => Ok(0),
+            _ => Err(Sbi::ERR_NOT_SUPPORTED)
+        }
+    }
+
+    fn is_supported(extension: u64) -> bool {
+        matches!(extension, Sbi::LEGACY_SET_TIMER..=Sbi::LEGACY_SHUTDOWN | Sbi::EXT_BASE | Sbi::EXT_TIME |
+                 Sbi::EXT_IPI | Sbi::EXT_RFENCE | Sbi::EXT_HSM | Sbi::EXT_SRST | Sbi::EXT_DBCN)
+    }
+
+    // Version of riviera, as major << 16 | minor
+    fn implementation_version() -> u64 {
+        let major: u64 = env!("CARGO_PKG_VERSION_MAJOR").parse().unwrap_or(0);
+        let minor: u64 = env!("CARGO_PKG_VERSION_MINOR").parse().unwrap_or(0);
+        (major << 16) | minor
+    }
+
+    // Whether hart 0, the only one, is in a set of harts: a mask of harts
+    // starting from hart base, or all of them if base is -1
+    fn selects_hart(mask: u64, base: u64) -> Result<bool, i64> {
+        match base {
+            u64::MAX => Ok(true),
+            0 => Ok(mask & 0x1 != 0),
+            _ if mask == 0 => Ok(false),
+            _ => Err(Sbi::ERR_INVALID_PARAM)
+        }
+    }
+
+    fn send_ipi(cpu: &mut Cpu, mask: u64, base: u64) -> Result<u64, i64> {
+        if Sbi::selects_hart(mask, base)? {
+            cpu.get_csrs_mut().set_interrupt_pending(CsrFile::SSIP, CsrFile::SSIP);
+        }
+        Ok(0)
+    }
+
+    // Without address translation, only FENCE.I has something to do
+    fn remote_fence(cpu: &mut Cpu, function: u64, mask: u64, base: u64) -> Result<u64, i64> {
+        if function > 6 {
+            return Err(Sbi::ERR_NOT_SUPPORTED);
+        }
+        if Sbi::selects_hart(mask, base)? && function == 0 {
+            cpu.flush_decode_cache();
+        }
+        Ok(0)
+    }
+
+    // Hart 0 is always running and it is the only one
+    fn hart_management(function: u64, hart: u64) -> Result<u64, i64> {
+        match function {
+            0 if hart == 0 => Err(Sbi::ERR_ALREADY_AVAILABLE),
+            2 if hart == 0 => Ok(Sbi::HART_STARTED),
+            0 | 2 => Err(Sbi::ERR_INVALID_PARAM),
+            1 => Err(Sbi::ERR_DENIED),
+            // Suspending the hart is left to the kernel, which waits with WFI
+            3 => Ok(0),
+            _ => Err(Sbi::ERR_NOT_SUPPORTED)
+        }
+    }
+
+    // Shutdowns and reboots stop the emulator: there is nothing to reboot.
+    // The reason is 0 for a normal shutdown and 1 for a system failure
+    fn system_reset(reason: u64) -> SbiResult {
+        SbiResult::Shutdown((reason != 0) as u64)
+    }
+
+    fn debug_console(cpu: &mut Cpu, function: u64, args: &[u64; 6]) -> Result<u64, i64> {
+        match function {
+            0 => {
+                let address: u64 = Sbi::wide_argument(cpu, args[1], args[2]);
+                let bytes: Vec<u8> = (0..args[0]).map(|i| {
+                    cpu.peek(address.wrapping_add(i), AccessSize::BYTE).map(|byte| byte as u8)
+                }).collect::<Result<Vec<u8>, _>>().map_err(|_| Sbi::ERR_INVALID_ADDRESS)?;
+                for byte in bytes.iter() {
+                    cpu.write_console(*byte);
+                }
+                Ok(bytes.len() as u64)
+            },
+            1 => {
+                let address: u64 = Sbi::wide_argument(cpu, args[1], args[2]);
+                let mut count: u64 = 0;
+                while count < args[0] {
+                    let byte: u8 = match cpu.read_console() {
+                        Some(byte) => byte,
+                        None => break
+                    };
+                    cpu.store(byte as u64, address.wrapping_add(count), AccessSize::BYTE)
+                       .map_err(|_| Sbi::ERR_INVALID_ADDRESS)?;
+                    count += 1;
+                }
+                Ok(count)
+            },
+            2 => {
+                cpu.write_console(args[0] as u8);
+                Ok(0)
+            },
+            _ => Err(Sbi::ERR_NOT_SUPPORTED)
+        }
+    }
+
+    // 64-bit argument passed in one register, or in two on RV32 (low, high)
+    fn wide_argument(cpu: &Cpu, low: u64, high: u64) -> u64 {
+        match cpu.get_xlen() {
+            Xlen::Rv32 => (high << 32) | (low & 0xffffffff),
+            Xlen::Rv64 => low
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cpu::Cpu;
+    use crate::csr::CsrFile;
+    use crate::memory::Memory;
+    use crate::sbi::{Sbi, SbiResult};
+
+    fn call(sbi: &mut Sbi, cpu: &mut Cpu, extension: u64, function: u64, args: &[u64]) -> SbiResult {
+        cpu.write_reg(17, extension);
+        cpu.write_reg(16, function);
+        for (i, arg) in args.iter().enumerate() {
+            cpu.write_reg(10 + i as u8, *arg);
+        }
+        sbi.handle(cpu)
+    }
+
+    #[test]
+    fn base_test() {
+        let mut cpu: Cpu = Cpu::new(Some(Memory::DRAM_DEFAULT_SIZE));
+        let mut sbi: Sbi = Sbi::new();
+        assert!(matches!(call(&mut sbi, &mut cpu, 0x10, 0, &[]), SbiResult::Return { error: 0, value: Sbi::SPEC_VERSION }));
+        // Probe the TIME extension and an unknown one
+        assert!(matches!(call(&mut sbi, &mut cpu, 0x10, 3, &[0x54494d45]), SbiResult::Return { error: 0, value: 1 }));
+        assert!(matches!(call(&mut sbi, &mut cpu, 0x10, 3, &[0x12345]), SbiResult::Return { error: 0, value: 0 }));
+        assert!(matches!(call(&mut sbi, &mut cpu, 0x12345, 0, &[]), SbiResult::Return { error: -2, .. }));
+        // HSM: hart 0 is started and the only one
+        assert!(matches!(call(&mut sbi, &mut cpu, 0x48534d, 2, &[0]), SbiResult::Return { error: 0, value: 0 }));
+        assert!(matches!(call(&mut sbi, &mut cpu, 0x48534d, 2, &[1]), SbiResult::Return { error: -3, .. }));
+        // SRST with a system failure as reason
+        assert!(matches!(call(&mut sbi, &mut cpu, 0x53525354, 0, &[0, 1]), SbiResult::Shutdown(1)));
+    }
+
+    #[test]
+    fn timer_and_ipi_test() {
+        let mut cpu: Cpu = Cpu::new(Some(Memory::DRAM_DEFAULT_SIZE));
+        let mut sbi: Sbi = Sbi::new();
+        assert!(matches!(call(&mut sbi, &mut cpu, 0x54494d45, 0, &[100]), SbiResult::Return { error: 0, .. }));
+        let lines: u64 = Sbi::route_interrupts(CsrFile::MTIP | CsrFile::SEIP);
+        assert_eq!(lines, CsrFile::STIP | CsrFile::SEIP);
+        assert_eq!(lines & !Sbi::DEVICE_INTERRUPTS, 0);
+
+        // All the harts, then a mask without hart 0
+        assert!(matches!(call(&mut sbi, &mut cpu, 0x735049, 0, &[0, u64::MAX]), SbiResult::Return { error: 0, .. }));
+        assert_eq!(cpu.get_csrs().read(CsrFile::MIP).unwrap() & CsrFile::SSIP, CsrFile::SSIP);
+        assert!(matches!(call(&mut sbi, &mut cpu, 0x03, 0, &[]), SbiResult::Legacy(0)));
+        assert_eq!(cpu.get_csrs().read(CsrFile::MIP).unwrap() & CsrFile::SSIP, 0);
+        assert!(matches!(call(&mut sbi, &mut cpu, 0x735049, 0, &[0x2, 0]), SbiResult::Return { error: 0, .. }));
+        assert_eq!(cpu.get_csrs().read(CsrFile::MIP).unwrap() & CsrFile::SSIP, 0);
+    }
+}
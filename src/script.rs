@@ -0,0 +1,278 @@
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::rc::Rc;
+use rhai::{Dynamic, Engine, EvalAltResult, FnPtr, AST, INT};
+use crate::bus::InterruptLine;
+use crate::cpu::Cpu;
+use crate::expr::parse_register;
+use crate::memory::AccessSize;
+use crate::step::StepReport;
+use crate::symbols::SymbolMap;
+
+type ScriptResult<T> = Result<T, Box<EvalAltResult>>;
+
+// Callbacks registered by a script
+#[derive(Default)]
+struct Hooks {
+    // Called before the instruction at an address is executed
+    exec: HashMap<u64, Vec<FnPtr>>,
+    // Called after an instruction writes inside [start, end)
+    write: Vec<(u64, u64, FnPtr)>,
+    // Called after an instruction raises a trap
+    trap: Vec<FnPtr>,
+    // Called with what the program sent to the console
+    uart: Vec<FnPtr>
+}
+
+/// Test scenario written in Rhai (https://rhai.rs), run along with the
+/// program. The top level of the script runs before the first instruction:
+/// it can change the initial state and register callbacks on events:
+///
+/// - `on_exec(address, || ...)`: before the instruction at an address
+///   (a number or a symbol, e.g. "main") is executed. Changing the pc
+///   skips it, e.g. to stub a function
+/// - `on_write(start, len, |address, value| ...)`: after an instruction
+///   writes memory inside the range
+/// - `on_trap(|cause, description| ...)`: after an instruction raises a trap
+/// - `on_uart(|text| ...)`: after the program sends text to the console
+///
+/// Everywhere in the script the state of the CPU can be read and changed
+/// with `reg(name)`, `set_reg(name, value)`, `pc()`, `set_pc(address)`,
+/// `read_mem(address, bytes)`, `write_mem(address, value, bytes)`,
+/// `raise_irq(line)` and `instructions()`, symbols are looked up with
+/// `symbol(name)` and `stop(code)` ends the run with an exit code
+pub struct Script {
+    name: String,
+    engine: Engine,
+    ast: AST,
+    hooks: Rc<RefCell<Hooks>>,
+    // CPU seen by the functions of the script: the CPU being run is
+    // swapped in while the script executes
+    cpu: Rc<RefCell<Cpu>>,
+    // Instructions executed so far in the run
+    instructions: Rc<Cell<u64>>
+}
+
+impl Script {
+    /// Compile a script. The symbols are the ones that the script can
+    /// refer to by name
+    pub fn load(filename: &str, symbols: SymbolMap) -> Result<Script, String> {
+        let source: String = std::fs::read_to_string(filename)
+            .map_err(|why| format!("Could not read script {}: {}", filename, why))?;
+        Script::compile(filename, &source, symbols)
+    }
+
+    /// Compile a script from its source, name is used in the errors
+    pub fn compile(name: &str, source: &str, symbols: SymbolMap) -> Result<Script, String> {
+        let mut script: Script = Script {
+            name: name.to_string(),
+            engine: Engine::new(),
+            ast: AST::empty(),
+            hooks: Rc::new(RefCell::new(Hooks::default())),
+            // Stand-in for the CPU being run, it is never executed
+            cpu: Rc::new(RefCell::new(Cpu::new(Some(0)))),
+            instructions: Rc::new(Cell::new(0))
+        };
+        script.register_hooks(Rc::new(symbols));
+        script.register_state();
+        script.ast = script.engine.compile(source).map_err(|err| format!("{}: {}", name, err))?;
+        Ok(script)
+    }
+
+    fn register_hooks(&mut self, symbols: Rc<SymbolMap>) {
+        let hooks: Rc<RefCell<Hooks>> = self.hooks.clone();
+        self.engine.register_fn("on_exec", move |address: INT, callback: FnPtr| {
+            hooks.borrow_mut().exec.entry(address as u64).or_default().push(callback);
+        });
+        let (hooks, resolver) = (self.hooks.clone(), symbols.clone());
+        self.engine.register_fn("on_exec", move |location: &str, callback: FnPtr| -> ScriptResult<()> {
+            let address: u64 = resolver.resolve(location)?;
+            hooks.borrow_mut().exec.entry(address).or_default().push(callback);
+            Ok(())
+        });
+        let hooks: Rc<RefCell<Hooks>> = self.hooks.clone();
+        self.engine.register_fn("on_write", move |start: INT, len: INT, callback: FnPtr| {
+            hooks.borrow_mut().write.push((start as u64, (start as u64).saturating_add(len as u64), callback));
+        });
+        let (hooks, resolver) = (self.hooks.clone(), symbols.clone());
+        self.engine.register_fn("on_write", move |location: &str, len: INT, callback: FnPtr| -> ScriptResult<()> {
+            let start: u64 = resolver.resolve(location)?;
+            hooks.borrow_mut().write.push((start, start.saturating_add(len as u64), callback));
+            Ok(())
+        });
+        let hooks: Rc<RefCell<Hooks>> = self.hooks.clone();
+        self.engine.register_fn("on_trap", move |callback: FnPtr| hooks.borrow_mut().trap.push(callback));
+        let hooks: Rc<RefCell<Hooks>> = self.hooks.clone();
+        self.engine.register_fn("on_uart", move |callback: FnPtr| hooks.borrow_mut().uart.push(callback));
+        self.engine.register_fn("symbol", move |name: &str| -> ScriptResult<INT> {
+            Ok(symbols.resolve(name)? as INT)
+        });
+    }
+
+    fn register_state(&mut self) {
+        let cpu: Rc<RefCell<Cpu>> = self.cpu.clone();
+        self.engine.register_fn("reg", move |name: &str| -> ScriptResult<INT> {
+            let cpu = cpu.borrow();
+            match (name, parse_register(name)) {
+                ("pc", _) => Ok(cpu.get_pc() as INT),
+                (_, Some(regi)) => Ok(cpu.read_reg(regi) as INT),
+                _ => Err(format!("Invalid register {}", name).into())
+            }
+        });
+        let cpu: Rc<RefCell<Cpu>> = self.cpu.clone();
+        self.engine.register_fn("set_reg", move |name: &str, value: INT| -> ScriptResult<()> {
+            let mut cpu = cpu.borrow_mut();
+            match (name, parse_register(name)) {
+                ("pc", _) => cpu.set_pc(value as u64),
+                (_, Some(0)) => (),
+                (_, Some(regi)) => cpu.write_reg(regi, value as u64),
+                _ => return Err(format!("Invalid register {}", name).into())
+            }
+            Ok(())
+        });
+        let cpu: Rc<RefCell<Cpu>> = self.cpu.clone();
+        self.engine.register_fn("pc", move || cpu.borrow().get_pc() as INT);
+        let cpu: Rc<RefCell<Cpu>> = self.cpu.clone();
+        self.engine.register_fn("set_pc", move |address: INT| cpu.borrow_mut().set_pc(address as u64));
+        let cpu: Rc<RefCell<Cpu>> = self.cpu.clone();
+        self.engine.register_fn("read_mem", move |address: INT, bytes: INT| -> ScriptResult<INT> {
+            let size: AccessSize = Script::access_size(bytes)?;
+            let cpu = cpu.borrow();
+            cpu.peek(address as u64, size).map(|value| value as INT)
+                .map_err(|_| format!("Could not read {} bytes at 0x{:x}", bytes, address).into())
+        });
+        let cpu: Rc<RefCell<Cpu>> = self.cpu.clone();
+        self.engine.register_fn("write_mem", move |address: INT, value: INT, bytes: INT| -> ScriptResult<()> {
+            let size: AccessSize = Script::access_size(bytes)?;
+            cpu.borrow_mut().poke(value as u64, address as u64, size)
+                .map_err(|trap| format!("Could not write {} bytes at 0x{:x}: {}", bytes, address, trap).into())
+        });
+        let cpu: Rc<RefCell<Cpu>> = self.cpu.clone();
+        self.engine.register_fn("raise_irq", move |line: &str| -> ScriptResult<()> {
+            cpu.borrow_mut().raise_irq(InterruptLine::parse(line)?);
+            Ok(())
+        });
+        let cpu: Rc<RefCell<Cpu>> = self.cpu.clone();
+        self.engine.register_fn("raise_irq", move |source: INT| -> ScriptResult<()> {
+            cpu.borrow_mut().raise_irq(InterruptLine::parse(&source.to_string())?);
+            Ok(())
+        });
+        let cpu: Rc<RefCell<Cpu>> = self.cpu.clone();
+        self.engine.register_fn("stop", move |code: INT| cpu.borrow_mut().exit(code as u64));
+        let instructions: Rc<Cell<u64>> = self.instructions.clone();
+        self.engine.register_fn("instructions", move || instructions.get() as INT);
+    }
+
+    fn access_size(bytes: INT) -> ScriptResult<AccessSize> {
+        match bytes {
+            1 => Ok(AccessSize::BYTE),
+            2 => Ok(AccessSize::HALFWORD),
+            4 => Ok(AccessSize::WORD),
+            8 => Ok(AccessSize::DOUBLEWORD),
+            _ => Err(format!("Invalid access size {}: expected 1, 2, 4 or 8 bytes", bytes).into())
+        }
+    }
+
+    /// Run the top level of the script and then the program, for at most
+    /// limit instructions if given, calling the callbacks of the script
+    /// on the way. Every instruction is followed as in replay::record, so
+    /// a scripted run is slower than a normal one. It returns the number
+    /// of executed instructions, or the first error raised by the script
+    pub fn run(&mut self, cpu: &mut Cpu, limit: Option<u64>) -> Result<u64, String> {
+        self.instructions.set(0);
+        self.with_cpu(cpu, |script| script.engine.run_ast(&script.ast))?;
+        cpu.capture_console();
+        let mut count: u64 = 0;
+        while !cpu.is_finished() && limit.is_none_or(|limit| count < limit) {
+            let pc: u64 = cpu.get_pc();
+            let callbacks: Vec<FnPtr> = self.hooks.borrow().exec.get(&pc).cloned().unwrap_or_default();
+            if !callbacks.is_empty() {
+                self.call_all(cpu, &callbacks, || ())?;
+                // The callbacks may have moved the pc or stopped the program
+                if cpu.get_pc() != pc || cpu.is_finished() {
+                    continue;
+                }
+            }
+
+            let report: StepReport = cpu.step_with_state();
+            count += 1;
+            self.instructions.set(count);
+            self.after_step(cpu, &report)?;
+        }
+        Ok(count)
+    }
+
+    // Call the callbacks of the events of an instruction
+    fn after_step(&mut self, cpu: &mut Cpu, report: &StepReport) -> Result<(), String> {
+        for write in &report.memory_writes {
+            let end: u64 = write.address.saturating_add(write.size.bytes() as u64);
+            let callbacks: Vec<FnPtr> = self.hooks.borrow().write.iter()
+                .filter(|(start, stop, _)| write.address < *stop && *start < end)
+                .map(|(_, _, callback)| callback.clone())
+                .collect();
+            self.call_all(cpu, &callbacks, || (write.address as INT, write.new as INT))?;
+        }
+        if let Some(trap) = report.trap {
+            let callbacks: Vec<FnPtr> = self.hooks.borrow().trap.clone();
+            self.call_all(cpu, &callbacks, || (trap.cause() as INT, trap.to_string()))?;
+        }
+        let output: Vec<u8> = cpu.take_console_output();
+        if !output.is_empty() {
+            let callbacks: Vec<FnPtr> = self.hooks.borrow().uart.clone();
+            let text: String = String::from_utf8_lossy(&output).into_owned();
+            self.call_all(cpu, &callbacks, || (text.clone(),))?;
+        }
+        Ok(())
+    }
+
+    fn call_all<A: rhai::FuncArgs>(&mut self, cpu: &mut Cpu, callbacks: &[FnPtr], args: impl Fn() -> A) -> Result<(), String> {
+        if callbacks.is_empty() {
+            return Ok(());
+        }
+        self.with_cpu(cpu, |script| {
+            callbacks.iter().try_for_each(|callback| callback.call::<Dynamic>(&script.engine, &script.ast, args()).map(|_| ()))
+        })
+    }
+
+    // Let the script see cpu while f runs
+    fn with_cpu<F>(&mut self, cpu: &mut Cpu, f: F) -> Result<(), String>
+    where F: FnOnce(&Script) -> ScriptResult<()> {
+        std::mem::swap(cpu, &mut *self.cpu.borrow_mut());
+        let result: ScriptResult<()> = f(self);
+        std::mem::swap(cpu, &mut *self.cpu.borrow_mut());
+        result.map_err(|err| format!("{}: {}", self.name, err))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cpu::{Cpu, ExitReason};
+    use crate::script::Script;
+    use crate::symbols::SymbolMap;
+
+    #[test]
+    fn script_test() {
+        let mut cpu: Cpu = Cpu::new(Some(0x1000));
+        cpu.set_read_write_segment(0x0);
+        // li a0, 5; sw a0, 0(zero); ebreak after the word that is written
+        for (offset, instr) in [0x00500513u32, 0x00a02023, 0x00100073].iter().enumerate() {
+            cpu.poke(*instr as u64, 0x100 + 4 * offset as u64, crate::memory::AccessSize::WORD).unwrap();
+        }
+        cpu.set_pc(0x100);
+        let source: &str = r#"
+            let writes = [];
+            set_reg("a1", 7);
+            on_exec(0x104, || { set_reg("a0", reg("a0") + reg("a1")); });
+            on_write(0x0, 4, |address, value| { writes.push(value); });
+            on_trap(|cause, description| { if cause == 3 { stop(writes[0]); } });
+        "#;
+        let mut script: Script = Script::compile("test", source, SymbolMap::new()).unwrap();
+        assert_eq!(script.run(&mut cpu, Some(10)), Ok(3));
+        assert_eq!(cpu.get_exit_reason(), Some(ExitReason::Exited(12)));
+
+        // Errors of the script stop the run
+        let mut script: Script = Script::compile("test", "reg(\"q9\");", SymbolMap::new()).unwrap();
+        assert!(script.run(&mut cpu, Some(10)).unwrap_err().starts_with("test: "));
+        assert!(Script::compile("test", "on_exec(", SymbolMap::new()).is_err());
+    }
+}
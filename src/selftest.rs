@@ -0,0 +1,275 @@
+use crate::cpu::{Cpu, Instruction, RegIndex, Xlen};
+use crate::rv::{self, OpCodes};
+
+/// Group of self-checks that riviera --selftest runs
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum SelfTest {
+    // Shifts, additions and subtractions (register, immediate and word
+    // forms) on the corner cases of their operands, in RV64 and RV32 mode
+    Alu
+}
+
+impl SelfTest {
+    /// Parse the name of a group: "alu"
+    pub fn parse(name: &str) -> Result<SelfTest, String> {
+        match name.trim() {
+            "alu" => Ok(SelfTest::Alu),
+            name => Err(format!("Invalid self-test {}: expected alu", name))
+        }
+    }
+
+    /// Run every check of the group
+    pub fn run(self) -> SelfTestReport {
+        match self {
+            SelfTest::Alu => alu()
+        }
+    }
+}
+
+/// Result of a self-test: the number of checks and a description of the
+/// ones that failed
+#[derive(Clone, PartialEq, Eq, Debug, Default)]
+pub struct SelfTestReport {
+    pub checks: usize,
+    pub failures: Vec<String>
+}
+
+impl SelfTestReport {
+    pub fn passed(&self) -> bool {
+        self.failures.is_empty()
+    }
+}
+
+// Operands that sit on the edges: zero, one, all ones, the sign bits and
+// the largest values of both widths, the values around the word boundary
+// and two alternating patterns
+const OPERANDS: [u64; 14] = [
+    0x0000_0000_0000_0000, 0x0000_0000_0000_0001, 0xffff_ffff_ffff_ffff, 0x8000_0000_0000_0000,
+    0x7fff_ffff_ffff_ffff, 0x0000_0000_8000_0000, 0xffff_ffff_8000_0000, 0x0000_0000_7fff_ffff,
+    0x0000_0000_ffff_ffff, 0xffff_ffff_0000_0000, 0x0000_0001_0000_0000, 0x5555_5555_5555_5555,
+    0xaaaa_aaaa_aaaa_aaaa, 0x1234_5678_9abc_def0
+];
+
+// Edges of the 12 bits signed immediates
+const IMMEDIATES: [i32; 6] = [0, 1, -1, 2047, -2048, 0x555];
+
+// The operands are in x1 and x2, the result goes to x3
+const RS1: RegIndex = 1;
+const RS2: RegIndex = 2;
+const RD: RegIndex = 3;
+
+#[derive(Clone, Copy)]
+enum Kind {
+    Add,
+    Sub,
+    Sll,
+    Srl,
+    Sra
+}
+
+// An instruction under test: its encoding (f3 and f7, or the upper bits of
+// the immediate) and what it computes
+#[derive(Clone, Copy)]
+struct Operation {
+    name: &'static str,
+    kind: Kind,
+    f3: u32,
+    f7: u32,
+    // Operates on the low 32 bits and sign-extends the result
+    word: bool
+}
+
+const REGISTER_OPERATIONS: [Operation; 10] = [
+    Operation { name: "ADD",  kind: Kind::Add, f3: 0b000, f7: 0b0000000, word: false },
+    Operation { name: "SUB",  kind: Kind::Sub, f3: 0b000, f7: 0b0100000, word: false },
+    Operation { name: "SLL",  kind: Kind::Sll, f3: 0b001, f7: 0b0000000, word: false },
+    Operation { name: "SRL",  kind: Kind::Srl, f3: 0b101, f7: 0b0000000, word: false },
+    Operation { name: "SRA",  kind: Kind::Sra, f3: 0b101, f7: 0b0100000, word: false },
+    Operation { name: "ADDW", kind: Kind::Add, f3: 0b000, f7: 0b0000000, word: true },
+    Operation { name: "SUBW", kind: Kind::Sub, f3: 0b000, f7: 0b0100000, word: true },
+    Operation { name: "SLLW", kind: Kind::Sll, f3: 0b001, f7: 0b0000000, word: true },
+    Operation { name: "SRLW", kind: Kind::Srl, f3: 0b101, f7: 0b0000000, word: true },
+    Operation { name: "SRAW", kind: Kind::Sra, f3: 0b101, f7: 0b0100000, word: true }
+];
+
+const SHIFT_IMMEDIATE_OPERATIONS: [Operation; 6] = [
+    Operation { name: "SLLI",  kind: Kind::Sll, f3: 0b001, f7: 0b0000000, word: false },
+    Operation { name: "SRLI",  kind: Kind::Srl, f3: 0b101, f7: 0b0000000, word: false },
+    Operation { name: "SRAI",  kind: Kind::Sra, f3: 0b101, f7: 0b0100000, word: false },
+    Operation { name: "SLLIW", kind: Kind::Sll, f3: 0b001, f7: 0b0000000, word: true },
+    Operation { name: "SRLIW", kind: Kind::Srl, f3: 0b101, f7: 0b0000000, word: true },
+    Operation { name: "SRAIW", kind: Kind::Sra, f3: 0b101, f7: 0b0100000, word: true }
+];
+
+const ADD_IMMEDIATE_OPERATIONS: [Operation; 2] = [
+    Operation { name: "ADDI",  kind: Kind::Add, f3: 0b000, f7: 0, word: false },
+    Operation { name: "ADDIW", kind: Kind::Add, f3: 0b000, f7: 0, word: true }
+];
+
+// Known results, written down from the specification rather than computed
+// (the operation, rs1, rs2 or the immediate, the result in RV64 mode)
+const VECTORS: [(&str, u64, u64, u64); 12] = [
+    ("SRAI",  0x8000_0000_0000_0000, 63, 0xffff_ffff_ffff_ffff),
+    ("SRAI",  0x8000_0000_0000_0000, 1,  0xc000_0000_0000_0000),
+    ("SRAI",  0x7fff_ffff_ffff_ffff, 62, 0x0000_0000_0000_0001),
+    ("SRAIW", 0x0000_0000_8000_0000, 31, 0xffff_ffff_ffff_ffff),
+    ("SRAIW", 0xffff_ffff_7fff_ffff, 30, 0x0000_0000_0000_0001),
+    ("SRLIW", 0x0000_0000_8000_0000, 0,  0xffff_ffff_8000_0000),
+    ("SLLIW", 0x0000_0000_0000_0001, 31, 0xffff_ffff_8000_0000),
+    ("ADD",   0x7fff_ffff_ffff_ffff, 1,  0x8000_0000_0000_0000),
+    ("SUB",   0x8000_0000_0000_0000, 1,  0x7fff_ffff_ffff_ffff),
+    ("ADDW",  0x0000_0000_7fff_ffff, 1,  0xffff_ffff_8000_0000),
+    ("SUBW",  0x0000_0000_8000_0000, 1,  0x0000_0000_7fff_ffff),
+    ("ADDIW", 0x0000_0000_7fff_ffff, 1,  0xffff_ffff_8000_0000)
+];
+
+// Sign-extend the low bits of a value
+fn sign_extend(value: u64, bits: u32) -> u64 {
+    if bits == 64 {
+        value
+    } else if (value >> (bits - 1)) & 0x1 == 0x1 {
+        value | (u64::MAX << bits)
+    } else {
+        value & !(u64::MAX << bits)
+    }
+}
+
+// Reference model, computed one bit at a time (shifts) or on 128 bits
+// (additions) so that it shares no code with the emulator: bit i of the
+// result of a right shift is bit i + shamt of the operand, or the sign bit
+// (arithmetic) or zero (logical) past the top. The result is sign-extended
+// from the width of the operation
+fn reference(kind: Kind, first: u64, second: u64, bits: u32) -> u64 {
+    let bit = |index: u32| (first >> index) & 0x1;
+    let shamt: u32 = (second & (bits as u64 - 1)) as u32;
+    let result: u64 = match kind {
+        Kind::Add => (first as u128 + second as u128) as u64,
+        Kind::Sub => (first as u128 + (!second) as u128 + 1) as u64,
+        Kind::Sll => (0..bits).filter(|index| *index >= shamt).fold(0, |acc, index| acc | bit(index - shamt) << index),
+        Kind::Srl => (0..bits).filter(|index| index + shamt < bits).fold(0, |acc, index| acc | bit(index + shamt) << index),
+        Kind::Sra => (0..bits).fold(0, |acc, index| acc | bit((index + shamt).min(bits - 1)) << index)
+    };
+    sign_extend(result, bits)
+}
+
+fn encode_rtype(operation: &Operation) -> Instruction {
+    let opcode: u8 = if operation.word { OpCodes::RTYPE64 } else { OpCodes::RTYPE };
+    operation.f7 << 25 | (RS2 as u32) << 20 | (RS1 as u32) << 15 | operation.f3 << 12 | (RD as u32) << 7 | opcode as u32
+}
+
+fn encode_itype(operation: &Operation, imm12: u32) -> Instruction {
+    let opcode: u8 = if operation.word { OpCodes::ITYPE64 } else { OpCodes::ITYPE };
+    (imm12 & 0xfff) << 20 | (RS1 as u32) << 15 | operation.f3 << 12 | (RD as u32) << 7 | opcode as u32
+}
+
+// Execute one instruction and return rd
+fn execute(cpu: &mut Cpu, instr: Instruction, first: u64, second: u64) -> Result<u64, String> {
+    cpu.write_reg(RS1, first);
+    cpu.write_reg(RS2, second);
+    cpu.write_reg(RD, 0);
+    match rv::decode::<false>(instr, cpu) {
+        Ok(()) => Ok(cpu.read_reg(RD)),
+        Err(trap) => Err(format!("{:?}", trap))
+    }
+}
+
+fn check(report: &mut SelfTestReport, cpu: &mut Cpu, name: &str, instr: Instruction, first: u64, second: u64, expected: u64) {
+    report.checks += 1;
+    let mode: &str = match cpu.get_xlen() {
+        Xlen::Rv32 => "RV32",
+        Xlen::Rv64 => "RV64"
+    };
+    match execute(cpu, instr, first, second) {
+        Ok(result) if result == expected => (),
+        Ok(result) => report.failures.push(format!("{} ({}) 0x{:016x}, 0x{:x}: got 0x{:016x}, expected 0x{:016x}",
+                                                   name, mode, first, second, result, expected)),
+        Err(trap) => report.failures.push(format!("{} ({}) 0x{:016x}, 0x{:x}: trapped with {}", name, mode, first, second, trap))
+    }
+}
+
+fn alu() -> SelfTestReport {
+    let mut report: SelfTestReport = SelfTestReport::default();
+    let mut cpu: Cpu = Cpu::new(None);
+    for xlen in [Xlen::Rv64, Xlen::Rv32] {
+        cpu.set_xlen(xlen);
+        // The word forms only exist in RV64. In RV32 mode the registers
+        // hold the operands sign-extended, and so do the results
+        let width = |operation: &Operation| if operation.word { 32 } else { xlen.bits() };
+        let available = |operation: &&Operation| xlen == Xlen::Rv64 || !operation.word;
+        let operands: Vec<u64> = OPERANDS.iter().map(|operand| sign_extend(*operand, xlen.bits())).collect();
+
+        for operation in REGISTER_OPERATIONS.iter().filter(available) {
+            let bits: u32 = width(operation);
+            let shift: bool = !matches!(operation.kind, Kind::Add | Kind::Sub);
+            // Every shift amount, with bits above the shift amount field
+            // set too: they must be ignored
+            let seconds: Vec<u64> = if shift {
+                (0..bits as u64).flat_map(|shamt| [shamt, shamt | !(bits as u64 - 1)]).collect()
+            } else {
+                operands.clone()
+            };
+            for first in &operands {
+                for second in &seconds {
+                    let expected: u64 = sign_extend(reference(operation.kind, *first, *second, bits), xlen.bits());
+                    check(&mut report, &mut cpu, operation.name, encode_rtype(operation), *first, *second, expected);
+                }
+            }
+        }
+        for operation in SHIFT_IMMEDIATE_OPERATIONS.iter().filter(available) {
+            let bits: u32 = width(operation);
+            for shamt in 0..bits {
+                let instr: Instruction = encode_itype(operation, (operation.f7 << 5) | shamt);
+                for first in &operands {
+                    let expected: u64 = sign_extend(reference(operation.kind, *first, shamt as u64, bits), xlen.bits());
+                    check(&mut report, &mut cpu, operation.name, instr, *first, shamt as u64, expected);
+                }
+            }
+        }
+        for operation in ADD_IMMEDIATE_OPERATIONS.iter().filter(available) {
+            let bits: u32 = width(operation);
+            for immediate in IMMEDIATES {
+                let instr: Instruction = encode_itype(operation, immediate as u32);
+                let second: u64 = immediate as i64 as u64;
+                for first in &operands {
+                    let expected: u64 = sign_extend(reference(operation.kind, *first, second, bits), xlen.bits());
+                    check(&mut report, &mut cpu, operation.name, instr, *first, second, expected);
+                }
+            }
+        }
+    }
+
+    cpu.set_xlen(Xlen::Rv64);
+    for (name, first, second, expected) in VECTORS {
+        let operation: &Operation = REGISTER_OPERATIONS.iter().chain(SHIFT_IMMEDIATE_OPERATIONS.iter())
+            .chain(ADD_IMMEDIATE_OPERATIONS.iter())
+            .find(|operation| operation.name == name)
+            .expect("self-test vector of an unknown operation");
+        let instr: Instruction = if SHIFT_IMMEDIATE_OPERATIONS.iter().any(|operation| operation.name == name) {
+            encode_itype(operation, (operation.f7 << 5) | second as u32)
+        } else if ADD_IMMEDIATE_OPERATIONS.iter().any(|operation| operation.name == name) {
+            encode_itype(operation, second as u32)
+        } else {
+            encode_rtype(operation)
+        };
+        check(&mut report, &mut cpu, name, instr, first, second, expected);
+    }
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::selftest::{reference, Kind, SelfTest, SelfTestReport};
+
+    #[test]
+    fn alu_test() {
+        let report: SelfTestReport = SelfTest::parse("alu").unwrap().run();
+        assert!(report.checks > 10000);
+        assert_eq!(report.failures, Vec::<String>::new());
+        assert!(SelfTest::parse("fpu").is_err());
+        // The reference model itself
+        assert_eq!(reference(Kind::Sra, 0x8000_0000, 4, 32), 0xffff_ffff_f800_0000);
+        assert_eq!(reference(Kind::Srl, 0x8000_0000, 4, 32), 0x0800_0000);
+        assert_eq!(reference(Kind::Sll, 0x1, 63, 64), 0x8000_0000_0000_0000);
+        assert_eq!(reference(Kind::Sub, 0, 1, 64), u64::MAX);
+    }
+}
@@ -0,0 +1,433 @@
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::sync::Arc;
+use crate::clint::Clint;
+use crate::cpu::{Cpu, RegIndex, Xlen};
+use crate::memory::AccessSize;
+use crate::syscall::{SyscallEmulator, SyscallResult};
+
+// What a semihosting handle refers to. Host files are shared (and not
+// duplicated) when the emulator is cloned, as for the system calls
+#[derive(Clone)]
+enum HostFile {
+    Stdin,
+    Stdout,
+    Stderr,
+    Host(Arc<File>),
+    // Contents of a file made up by the emulator, and the read position
+    Buffer(Vec<u8>, usize)
+}
+
+/// RISC-V semihosting: an EBREAK preceded by `slli zero, zero, 0x1f` and
+/// followed by `srai zero, zero, 7` asks the host for the operation in a0
+/// (the ARM semihosting numbers), whose argument is in a1: a value, or the
+/// address of a block of XLEN-wide fields. The result is written back to
+/// a0 and the execution continues after the EBREAK. Any other EBREAK is a
+/// breakpoint as usual
+#[derive(Clone)]
+pub struct Semihosting {
+    files: HashMap<u64, HostFile>,
+    next_handle: u64,
+    // errno of the last operation that failed (SYS_ERRNO)
+    errno: i64,
+    cmdline: String,
+    // Heap and stack given by SYS_HEAPINFO
+    heap_start: u64,
+    stack_top: u64
+}
+
+impl Semihosting {
+    /// Instructions around the EBREAK of a semihosting call
+    pub const ENTRY_NOP: u32 = 0x01f01013;
+    pub const EXIT_NOP: u32 = 0x40705013;
+
+    // Operation numbers
+    const SYS_OPEN:          u64 = 0x01;
+    const SYS_CLOSE:         u64 = 0x02;
+    const SYS_WRITEC:        u64 = 0x03;
+    const SYS_WRITE0:        u64 = 0x04;
+    const SYS_WRITE:         u64 = 0x05;
+    const SYS_READ:          u64 = 0x06;
+    const SYS_READC:         u64 = 0x07;
+    const SYS_ISERROR:       u64 = 0x08;
+    const SYS_ISTTY:         u64 = 0x09;
+    const SYS_SEEK:          u64 = 0x0a;
+    const SYS_FLEN:          u64 = 0x0c;
+    const SYS_REMOVE:        u64 = 0x0e;
+    const SYS_RENAME:        u64 = 0x0f;
+    const SYS_CLOCK:         u64 = 0x10;
+    const SYS_TIME:          u64 = 0x11;
+    const SYS_ERRNO:         u64 = 0x13;
+    const SYS_GET_CMDLINE:   u64 = 0x15;
+    const SYS_HEAPINFO:      u64 = 0x16;
+    const SYS_EXIT:          u64 = 0x18;
+    const SYS_EXIT_EXTENDED: u64 = 0x20;
+    const SYS_ELAPSED:       u64 = 0x30;
+    const SYS_TICKFREQ:      u64 = 0x31;
+
+    // Reason of SYS_EXIT for a normal termination
+    const ADP_STOPPED_APPLICATION_EXIT: u64 = 0x20026;
+    // Features told by the :semihosting-features file: SYS_EXIT_EXTENDED
+    // and :tt opened for appending is stderr
+    const FEATURES: [u8; 5] = [b'S', b'H', b'F', b'B', 0x3];
+
+    const EBADF:  i64 = 9;
+    const EINVAL: i64 = 22;
+    const ENOSYS: i64 = 38;
+
+    const A0: RegIndex = 10;
+    const A1: RegIndex = 11;
+
+    /// Serve the semihosting calls of a program with the given command
+    /// line. The heap is between heap_start and the stack, which starts
+    /// at stack_top
+    pub fn new(cmdline: &str, heap_start: u64, stack_top: u64) -> Semihosting {
+        Semihosting {
+            files: HashMap::new(),
+            next_handle: 1,
+            errno: 0,
+            cmdline: cmdline.to_string(),
+            heap_start,
+            stack_top
+        }
+    }
+
+    /// Check if the EBREAK at pc is a semihosting call
+    pub fn is_call(cpu: &Cpu, pc: u64) -> bool {
+        let word = |addr: u64| cpu.peek(addr, AccessSize::WORD).ok();
+        word(pc.wrapping_sub(4)) == Some(Semihosting::ENTRY_NOP as u64)
+            && word(pc.wrapping_add(4)) == Some(Semihosting::EXIT_NOP as u64)
+    }
+
+    /// Perform the operation requested by the program. Failures return
+    /// -1 (or what the operation defines) and set the errno of SYS_ERRNO
+    pub fn handle(&mut self, cpu: &mut Cpu) -> SyscallResult {
+        let operation: u64 = cpu.read_reg(Semihosting::A0);
+        let argument: u64 = cpu.read_reg(Semihosting::A1);
+        let result: Result<u64, i64> = match operation {
+            Semihosting::SYS_EXIT => return SyscallResult::Exit(Semihosting::exit_code(cpu, argument)),
+            Semihosting::SYS_EXIT_EXTENDED => {
+                let code: u64 = match Semihosting::fields::<2>(cpu, argument) {
+                    Ok([Semihosting::ADP_STOPPED_APPLICATION_EXIT, code]) => code,
+                    _ => 1
+                };
+                return SyscallResult::Exit(code);
+            },
+            Semihosting::SYS_OPEN => self.open(cpu, argument),
+            Semihosting::SYS_CLOSE => self.close(cpu, argument),
+            Semihosting::SYS_WRITEC => SyscallEmulator::load_buffer(cpu, argument, 1)
+                .map(|data| Semihosting::console(cpu, &data)),
+            Semihosting::SYS_WRITE0 => SyscallEmulator::load_string(cpu, argument)
+                .map(|text| Semihosting::console(cpu, text.as_bytes())),
+            Semihosting::SYS_WRITE => self.write(cpu, argument),
+            Semihosting::SYS_READ => self.read(cpu, argument),
+            Semihosting::SYS_READC => {
+                let mut byte: [u8; 1] = [0];
+                match cpu.read_stdin(&mut byte) {
+                    Ok(1) => Ok(byte[0] as u64),
+                    Ok(_) => Err(Semihosting::EINVAL),
+                    Err(err) => Err(SyscallEmulator::errno(err))
+                }
+            },
+            Semihosting::SYS_ISERROR => Semihosting::fields::<1>(cpu, argument)
+                .map(|[status]| Semihosting::signed(cpu, status).is_negative() as u64),
+            Semihosting::SYS_ISTTY => self.istty(cpu, argument),
+            Semihosting::SYS_SEEK => self.seek(cpu, argument),
+            Semihosting::SYS_FLEN => self.flen(cpu, argument),
+            Semihosting::SYS_REMOVE => Semihosting::path(cpu, argument)
+                .and_then(|path| std::fs::remove_file(path).map(|_| 0).map_err(SyscallEmulator::errno)),
+            Semihosting::SYS_RENAME => Semihosting::rename(cpu, argument),
+            // Centiseconds and seconds, of the guest time
+            Semihosting::SYS_CLOCK => Ok(cpu.get_mtime() / (Clint::TIMEBASE_FREQUENCY / 100)),
+            Semihosting::SYS_TIME => Ok(cpu.read_rtc_time().as_secs()),
+            Semihosting::SYS_ERRNO => Ok(self.errno as u64),
+            Semihosting::SYS_GET_CMDLINE => self.get_cmdline(cpu, argument),
+            Semihosting::SYS_HEAPINFO => self.heapinfo(cpu, argument),
+            Semihosting::SYS_ELAPSED => SyscallEmulator::store_buffer(cpu, argument, &cpu.get_mtime().to_le_bytes()).map(|_| 0),
+            Semihosting::SYS_TICKFREQ => Ok(Clint::TIMEBASE_FREQUENCY),
+            _ => Err(Semihosting::ENOSYS)
+        };
+        match result {
+            Ok(value) => SyscallResult::Return(value),
+            Err(errno) => {
+                self.errno = errno;
+                SyscallResult::Return(cpu.get_xlen().mask())
+            }
+        }
+    }
+
+    // The reason of SYS_EXIT is in a1 on RV32 and in a block with the exit
+    // code on RV64. Any reason but a normal termination is a failure
+    fn exit_code(cpu: &Cpu, argument: u64) -> u64 {
+        match cpu.get_xlen() {
+            Xlen::Rv32 if argument == Semihosting::ADP_STOPPED_APPLICATION_EXIT => 0,
+            Xlen::Rv32 => 1,
+            Xlen::Rv64 => match Semihosting::fields::<2>(cpu, argument) {
+                Ok([Semihosting::ADP_STOPPED_APPLICATION_EXIT, code]) => code,
+                _ => 1
+            }
+        }
+    }
+
+    fn open(&mut self, cpu: &mut Cpu, block: u64) -> Result<u64, i64> {
+        let [name, mode, length] = Semihosting::fields::<3>(cpu, block)?;
+        let name: String = String::from_utf8_lossy(&SyscallEmulator::load_buffer(cpu, name, length)?).into_owned();
+        // Modes are the ones of fopen, r, r+, w, w+, a and a+, each also
+        // in binary (odd numbers)
+        let file: HostFile = match (name.as_str(), mode / 2) {
+            (":tt", 0 | 1) => HostFile::Stdin,
+            (":tt", 2 | 3) => HostFile::Stdout,
+            (":tt", 4 | 5) => HostFile::Stderr,
+            (":semihosting-features", 0) => HostFile::Buffer(Semihosting::FEATURES.to_vec(), 0),
+            (_, 0..=5) => {
+                let mut options: OpenOptions = OpenOptions::new();
+                match mode / 2 {
+                    0 => options.read(true),
+                    1 => options.read(true).write(true),
+                    2 => options.write(true).create(true).truncate(true),
+                    3 => options.read(true).write(true).create(true).truncate(true),
+                    4 => options.append(true).create(true),
+                    _ => options.read(true).append(true).create(true)
+                };
+                HostFile::Host(Arc::new(options.open(&name).map_err(SyscallEmulator::errno)?))
+            },
+            _ => return Err(Semihosting::EINVAL)
+        };
+        let handle: u64 = self.next_handle;
+        self.next_handle += 1;
+        self.files.insert(handle, file);
+        Ok(handle)
+    }
+
+    fn close(&mut self, cpu: &Cpu, block: u64) -> Result<u64, i64> {
+        let [handle] = Semihosting::fields::<1>(cpu, block)?;
+        self.files.remove(&handle).map(|_| 0).ok_or(Semihosting::EBADF)
+    }
+
+    // Write to a handle, returning the number of bytes that were not written
+    fn write(&mut self, cpu: &mut Cpu, block: u64) -> Result<u64, i64> {
+        let [handle, buffer, length] = Semihosting::fields::<3>(cpu, block)?;
+        let data: Vec<u8> = SyscallEmulator::load_buffer(cpu, buffer, length)?;
+        let written: usize = match self.files.get(&handle) {
+            Some(HostFile::Stdout) => Semihosting::console(cpu, &data) as usize,
+            Some(HostFile::Stderr) => cpu.write_stderr(&data).map(|_| data.len()).map_err(SyscallEmulator::errno)?,
+            Some(HostFile::Host(file)) => (&**file).write(&data).map_err(SyscallEmulator::errno)?,
+            _ => return Err(Semihosting::EBADF)
+        };
+        Ok(length - written as u64)
+    }
+
+    // Read from a handle, returning the number of bytes that were not read
+    // (all of them at the end of the file). The guest chooses the length:
+    // the data goes through a buffer of at most READ_CHUNK bytes, and the
+    // read stops at the end of the memory the buffer is in
+    fn read(&mut self, cpu: &mut Cpu, block: u64) -> Result<u64, i64> {
+        let [handle, buffer, length] = Semihosting::fields::<3>(cpu, block)?;
+        let wanted: u64 = length.min(cpu.mapped_bytes(buffer));
+        let mut data: Vec<u8> = vec![0; wanted.min(SyscallEmulator::READ_CHUNK) as usize];
+        let mut total: u64 = 0;
+        loop {
+            let len: usize = (wanted - total).min(SyscallEmulator::READ_CHUNK) as usize;
+            let (read, more) = match self.read_chunk(cpu, handle, &mut data[..len]) {
+                Ok(result) => result,
+                Err(_) if total > 0 => break,
+                Err(errno) => return Err(errno)
+            };
+            SyscallEmulator::store_buffer(cpu, buffer.wrapping_add(total), &data[..read])?;
+            total += read as u64;
+            if total == wanted || read < len || !more {
+                break;
+            }
+        }
+        Ok(length - total)
+    }
+
+    // Read once from a handle into data. It returns the number of bytes
+    // read and whether reading again might not block (it might on stdin)
+    fn read_chunk(&mut self, cpu: &mut Cpu, handle: u64, data: &mut [u8]) -> Result<(usize, bool), i64> {
+        match self.files.get_mut(&handle) {
+            Some(HostFile::Stdin) => Ok((cpu.read_stdin(data).map_err(SyscallEmulator::errno)?, false)),
+            Some(HostFile::Host(file)) => Ok(((&**file).read(data).map_err(SyscallEmulator::errno)?, true)),
+            Some(HostFile::Buffer(contents, position)) => {
+                let read: usize = data.len().min(contents.len() - *position);
+                data[..read].copy_from_slice(&contents[*position..*position + read]);
+                *position += read;
+                Ok((read, true))
+            },
+            _ => Err(Semihosting::EBADF)
+        }
+    }
+
+    fn istty(&self, cpu: &Cpu, block: u64) -> Result<u64, i64> {
+        let [handle] = Semihosting::fields::<1>(cpu, block)?;
+        match self.files.get(&handle) {
+            Some(HostFile::Stdin | HostFile::Stdout | HostFile::Stderr) => Ok(1),
+            Some(_) => Ok(0),
+            None => Err(Semihosting::EBADF)
+        }
+    }
+
+    // Move to an absolute position
+    fn seek(&mut self, cpu: &Cpu, block: u64) -> Result<u64, i64> {
+        let [handle, position] = Semihosting::fields::<2>(cpu, block)?;
+        match self.files.get_mut(&handle) {
+            Some(HostFile::Host(file)) => (&**file).seek(SeekFrom::Start(position)).map(|_| 0).map_err(SyscallEmulator::errno),
+            Some(HostFile::Buffer(contents, current)) => {
+                *current = (position as usize).min(contents.len());
+                Ok(0)
+            },
+            Some(_) => Err(Semihosting::EINVAL),
+            None => Err(Semihosting::EBADF)
+        }
+    }
+
+    fn flen(&self, cpu: &Cpu, block: u64) -> Result<u64, i64> {
+        let [handle] = Semihosting::fields::<1>(cpu, block)?;
+        match self.files.get(&handle) {
+            Some(HostFile::Host(file)) => file.metadata().map(|metadata| metadata.len()).map_err(SyscallEmulator::errno),
+            Some(HostFile::Buffer(contents, _)) => Ok(contents.len() as u64),
+            Some(_) => Err(Semihosting::EINVAL),
+            None => Err(Semihosting::EBADF)
+        }
+    }
+
+    fn rename(cpu: &Cpu, block: u64) -> Result<u64, i64> {
+        let [from, from_length, to, to_length] = Semihosting::fields::<4>(cpu, block)?;
+        let name = |address: u64, length: u64| SyscallEmulator::load_buffer(cpu, address, length)
+            .map(|bytes| String::from_utf8_lossy(&bytes).into_owned());
+        std::fs::rename(name(from, from_length)?, name(to, to_length)?).map(|_| 0).map_err(SyscallEmulator::errno)
+    }
+
+    // Copy the command line, NUL-terminated, into the buffer of the block
+    // and its length into the second field, if it fits
+    fn get_cmdline(&self, cpu: &mut Cpu, block: u64) -> Result<u64, i64> {
+        let [buffer, size] = Semihosting::fields::<2>(cpu, block)?;
+        let mut cmdline: Vec<u8> = self.cmdline.as_bytes().to_vec();
+        if cmdline.len() as u64 >= size {
+            return Err(Semihosting::EINVAL);
+        }
+        let length: u64 = cmdline.len() as u64;
+        cmdline.push(0);
+        SyscallEmulator::store_buffer(cpu, buffer, &cmdline)?;
+        Semihosting::store_fields(cpu, block + (cpu.get_xlen().bits() / 8) as u64, &[length])?;
+        Ok(0)
+    }
+
+    // Fill the block pointed to by the argument with the heap base and
+    // limit and the stack base (top) and limit
+    fn heapinfo(&self, cpu: &mut Cpu, argument: u64) -> Result<u64, i64> {
+        let [block] = Semihosting::fields::<1>(cpu, argument)?;
+        Semihosting::store_fields(cpu, block, &[self.heap_start, self.stack_top, self.stack_top, self.heap_start])?;
+        Ok(0)
+    }
+
+    // Print on the standard output of the program, as the system calls do
+    fn console(cpu: &Cpu, data: &[u8]) -> u64 {
+        let _ = cpu.write_stdout(data);
+        data.len() as u64
+    }
+
+    // Read a name given by the address and the length in a block
+    fn path(cpu: &Cpu, block: u64) -> Result<String, i64> {
+        let [name, length] = Semihosting::fields::<2>(cpu, block)?;
+        Ok(String::from_utf8_lossy(&SyscallEmulator::load_buffer(cpu, name, length)?).into_owned())
+    }
+
+    // Value of a field as a signed number of XLEN bits
+    fn signed(cpu: &Cpu, value: u64) -> i64 {
+        match cpu.get_xlen() {
+            Xlen::Rv32 => value as i32 as i64,
+            Xlen::Rv64 => value as i64
+        }
+    }
+
+    // Read the first N fields of a block, each XLEN bits wide
+    fn fields<const N: usize>(cpu: &Cpu, block: u64) -> Result<[u64; N], i64> {
+        let xlen: Xlen = cpu.get_xlen();
+        let size: AccessSize = if xlen == Xlen::Rv32 { AccessSize::WORD } else { AccessSize::DOUBLEWORD };
+        let mut fields: [u64; N] = [0; N];
+        for (index, field) in fields.iter_mut().enumerate() {
+            *field = cpu.peek(block.wrapping_add(index as u64 * (xlen.bits() / 8) as u64), size)
+                        .map_err(|_| SyscallEmulator::EFAULT)?;
+        }
+        Ok(fields)
+    }
+
+    fn store_fields(cpu: &mut Cpu, block: u64, fields: &[u64]) -> Result<(), i64> {
+        let bytes: usize = (cpu.get_xlen().bits() / 8) as usize;
+        let data: Vec<u8> = fields.iter().flat_map(|field| field.to_le_bytes()[..bytes].to_vec()).collect();
+        SyscallEmulator::store_buffer(cpu, block, &data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cpu::Cpu;
+    use crate::memory::{AccessSize, Memory};
+    use crate::semihosting::Semihosting;
+    use crate::syscall::SyscallResult;
+
+    #[test]
+    fn features_file_test() {
+        let mut cpu: Cpu = Cpu::new(Some(Memory::DRAM_DEFAULT_SIZE));
+        cpu.set_read_write_segment(0x0);
+        let mut semihosting: Semihosting = Semihosting::new("prog", 0x1000, 0x8000);
+        let name: &[u8] = b":semihosting-features";
+        for (i, byte) in name.iter().enumerate() {
+            cpu.store(*byte as u64, 0x200 + i as u64, AccessSize::BYTE).unwrap();
+        }
+        // SYS_OPEN [name, mode rb, length]
+        cpu.store(0x200, 0x100, AccessSize::DOUBLEWORD).unwrap();
+        cpu.store(1, 0x108, AccessSize::DOUBLEWORD).unwrap();
+        cpu.store(name.len() as u64, 0x110, AccessSize::DOUBLEWORD).unwrap();
+        cpu.write_reg(10, 0x01);
+        cpu.write_reg(11, 0x100);
+        let SyscallResult::Return(handle) = semihosting.handle(&mut cpu) else { panic!() };
+        // SYS_FLEN [handle], then SYS_READ [handle, buffer, 8] leaves 3 bytes unread
+        cpu.store(handle, 0x100, AccessSize::DOUBLEWORD).unwrap();
+        cpu.write_reg(10, 0x0c);
+        assert!(matches!(semihosting.handle(&mut cpu), SyscallResult::Return(5)));
+        cpu.store(0x300, 0x108, AccessSize::DOUBLEWORD).unwrap();
+        cpu.store(8, 0x110, AccessSize::DOUBLEWORD).unwrap();
+        cpu.write_reg(10, 0x06);
+        assert!(matches!(semihosting.handle(&mut cpu), SyscallResult::Return(3)));
+        assert_eq!(cpu.load(0x300, AccessSize::WORD).unwrap(), 0x42464853);
+        // A huge length is bounded: at the end of the file nothing is read
+        cpu.store(u64::MAX, 0x110, AccessSize::DOUBLEWORD).unwrap();
+        assert!(matches!(semihosting.handle(&mut cpu), SyscallResult::Return(u64::MAX)));
+        // SYS_CLOSE twice: the second one fails with EBADF
+        cpu.write_reg(10, 0x02);
+        assert!(matches!(semihosting.handle(&mut cpu), SyscallResult::Return(0)));
+        assert!(matches!(semihosting.handle(&mut cpu), SyscallResult::Return(u64::MAX)));
+        cpu.write_reg(10, 0x13);
+        assert!(matches!(semihosting.handle(&mut cpu), SyscallResult::Return(9)));
+    }
+
+    #[test]
+    fn exit_test() {
+        let mut cpu: Cpu = Cpu::new(Some(Memory::DRAM_DEFAULT_SIZE));
+        cpu.set_read_write_segment(0x0);
+        // slli zero, zero, 0x1f; ebreak; srai zero, zero, 7 with a0 = SYS_EXIT
+        // and a1 pointing to [ADP_Stopped_ApplicationExit, 7]
+        cpu.store(Semihosting::ENTRY_NOP as u64, 0x0, AccessSize::WORD).unwrap();
+        cpu.store(0x00100073, 0x4, AccessSize::WORD).unwrap();
+        cpu.store(Semihosting::EXIT_NOP as u64, 0x8, AccessSize::WORD).unwrap();
+        cpu.store(0x20026, 0x100, AccessSize::DOUBLEWORD).unwrap();
+        cpu.store(7, 0x108, AccessSize::DOUBLEWORD).unwrap();
+        cpu.write_reg(10, 0x18);
+        cpu.write_reg(11, 0x100);
+        cpu.set_pc(0x0);
+        // Without semihosting the EBREAK is a breakpoint
+        let mut breakpoint: Cpu = cpu.clone();
+        breakpoint.step_with_state();
+        assert!(breakpoint.step_with_state().trap.is_some());
+        assert_eq!(breakpoint.get_exit_code(), None);
+
+        cpu.set_semihosting(Semihosting::new("", 0x0, 0x0));
+        cpu.step_with_state();
+        assert!(cpu.step_with_state().trap.is_none());
+        assert_eq!(cpu.get_exit_code(), Some(7));
+        assert!(cpu.is_finished());
+    }
+}
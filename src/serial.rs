@@ -0,0 +1,234 @@
+use std::io::{Read, Write};
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use crate::cli::HostConsole;
+
+/// Where the console of a UART is connected
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum SerialSpec {
+    /// Terminal of the emulator
+    Stdio,
+    /// TCP socket listening at address (host:port) for a client to attach,
+    /// speaking the telnet protocol or not. With wait the emulator does
+    /// not start until the first client has attached
+    Tcp { address: String, telnet: bool, wait: bool }
+}
+
+impl SerialSpec {
+    /// Parse a serial backend: stdio, or tcp:[<host>:]<port>[,wait] and
+    /// telnet:[<host>:]<port>[,wait]. The host is 127.0.0.1 if not given
+    pub fn parse(spec: &str) -> Result<SerialSpec, String> {
+        let invalid = || format!("Invalid serial backend {}: expected stdio, tcp:[<host>:]<port>[,wait] \
+                                  or telnet:[<host>:]<port>[,wait]", spec);
+        let (protocol, address) = match spec.split_once(':') {
+            None if spec == "stdio" => return Ok(SerialSpec::Stdio),
+            Some((protocol, address)) if protocol == "tcp" || protocol == "telnet" => (protocol, address),
+            _ => return Err(invalid())
+        };
+        let (address, wait) = match address.strip_suffix(",wait") {
+            Some(address) => (address, true),
+            None => (address, false)
+        };
+        let (host, port) = address.rsplit_once(':').unwrap_or(("127.0.0.1", address));
+        if host.is_empty() || port.parse::<u16>().is_err() {
+            return Err(invalid());
+        }
+        Ok(SerialSpec::Tcp { address: format!("{}:{}", host, port), telnet: protocol == "telnet", wait })
+    }
+}
+
+/// Console of a UART served on a TCP socket, as the tcp and telnet serial
+/// backends of QEMU: a client (nc, telnet) attaches to see what the guest
+/// prints and to type for it, and can detach and attach again later. A
+/// new client replaces the previous one, and the output of the guest is
+/// dropped while no client is attached. In telnet mode the client is
+/// asked to send each key as it is pressed and to leave the echo to the
+/// guest, and the telnet commands are removed from what it sends
+pub struct TcpConsole {
+    client: Arc<Mutex<Option<TcpStream>>>,
+    receiver: Receiver<u8>,
+    local_address: String
+}
+
+impl TcpConsole {
+    // IAC WILL ECHO, IAC WILL SUPPRESS-GO-AHEAD: character mode
+    const TELNET_NEGOTIATION: [u8; 6] = [0xff, 0xfb, 0x01, 0xff, 0xfb, 0x03];
+
+    /// Listen on address, waiting for the first client if wait is set
+    pub fn listen(address: &str, telnet: bool, wait: bool) -> Result<TcpConsole, String> {
+        let listener: TcpListener = TcpListener::bind(address)
+            .map_err(|why| format!("Could not listen on {}: {}", address, why))?;
+        let local_address: String = listener.local_addr().map(|addr| addr.to_string()).unwrap_or(address.to_string());
+        let client: Arc<Mutex<Option<TcpStream>>> = Arc::new(Mutex::new(None));
+        let (sender, receiver) = mpsc::channel::<u8>();
+        if wait {
+            let (stream, _) = listener.accept().map_err(|why| format!("Could not accept a client on {}: {}", address, why))?;
+            attach(stream, telnet, &client, &sender);
+        }
+        let accepted = client.clone();
+        std::thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                attach(stream, telnet, &accepted, &sender);
+            }
+        });
+        Ok(TcpConsole { client, receiver, local_address })
+    }
+
+    /// Address the console listens on (with the actual port if 0 was given)
+    pub fn get_address(&self) -> &str {
+        &self.local_address
+    }
+
+    /// Check if a client is attached
+    pub fn is_attached(&self) -> bool {
+        self.client.lock().map(|client| client.is_some()).unwrap_or(false)
+    }
+}
+
+// Make stream the client of the console and pass what it sends to sender
+// from a thread of its own, until it detaches
+fn attach(mut stream: TcpStream, telnet: bool, client: &Arc<Mutex<Option<TcpStream>>>, sender: &Sender<u8>) {
+    if telnet && stream.write_all(&TcpConsole::TELNET_NEGOTIATION).is_err() {
+        return;
+    }
+    let Ok(reader) = stream.try_clone() else { return };
+    if let Ok(mut client) = client.lock() {
+        if let Some(previous) = client.replace(stream) {
+            let _ = previous.shutdown(Shutdown::Both);
+        }
+    }
+    let sender: Sender<u8> = sender.clone();
+    std::thread::spawn(move || read_client(reader, telnet, sender));
+}
+
+fn read_client(mut stream: TcpStream, telnet: bool, sender: Sender<u8>) {
+    let mut filter: TelnetFilter = TelnetFilter::default();
+    let mut buffer: [u8; 256] = [0; 256];
+    while let Ok(read) = stream.read(&mut buffer) {
+        if read == 0 {
+            break;
+        }
+        for byte in buffer[..read].iter() {
+            let byte: Option<u8> = if telnet { filter.filter(*byte) } else { Some(*byte) };
+            if let Some(byte) = byte {
+                if sender.send(byte).is_err() {
+                    return;
+                }
+            }
+        }
+    }
+}
+
+impl HostConsole for TcpConsole {
+    fn write(&mut self, bytes: &[u8]) {
+        if let Ok(mut client) = self.client.lock() {
+            if client.as_mut().is_some_and(|stream| stream.write_all(bytes).is_err()) {
+                *client = None;
+            }
+        }
+    }
+
+    fn read_byte(&mut self) -> Option<u8> {
+        self.receiver.try_recv().ok()
+    }
+}
+
+#[derive(Default, Clone, Copy, PartialEq, Eq)]
+enum TelnetState {
+    #[default]
+    Data,
+    // After a carriage return, which may be followed by LF or NUL
+    Return,
+    // After IAC
+    Command,
+    // After WILL, WONT, DO or DONT
+    Option,
+    // Inside a subnegotiation, and after an IAC inside it
+    Subnegotiation,
+    SubnegotiationCommand
+}
+
+// Remove the telnet commands from the bytes sent by a client. The end of a
+// line (CR LF or CR NUL) becomes a carriage return, as from a serial terminal
+#[derive(Default)]
+struct TelnetFilter {
+    state: TelnetState
+}
+
+impl TelnetFilter {
+    const IAC: u8 = 0xff;
+    const SB: u8 = 0xfa;
+    const SE: u8 = 0xf0;
+
+    fn filter(&mut self, byte: u8) -> Option<u8> {
+        let (state, data) = match (self.state, byte) {
+            (TelnetState::Data | TelnetState::Return, TelnetFilter::IAC) => (TelnetState::Command, None),
+            (TelnetState::Return, b'\n' | 0) => (TelnetState::Data, None),
+            (TelnetState::Data | TelnetState::Return, b'\r') => (TelnetState::Return, Some(byte)),
+            (TelnetState::Data | TelnetState::Return, _) => (TelnetState::Data, Some(byte)),
+            // IAC IAC is an escaped 0xff
+            (TelnetState::Command, TelnetFilter::IAC) => (TelnetState::Data, Some(byte)),
+            (TelnetState::Command, TelnetFilter::SB) => (TelnetState::Subnegotiation, None),
+            (TelnetState::Command, 0xfb..=0xfe) => (TelnetState::Option, None),
+            (TelnetState::Command | TelnetState::Option, _) => (TelnetState::Data, None),
+            (TelnetState::Subnegotiation, TelnetFilter::IAC) => (TelnetState::SubnegotiationCommand, None),
+            (TelnetState::Subnegotiation, _) => (TelnetState::Subnegotiation, None),
+            (TelnetState::SubnegotiationCommand, TelnetFilter::SE) => (TelnetState::Data, None),
+            (TelnetState::SubnegotiationCommand, _) => (TelnetState::Subnegotiation, None)
+        };
+        self.state = state;
+        data
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+    use std::net::TcpStream;
+    use crate::cli::HostConsole;
+    use crate::serial::{SerialSpec, TcpConsole, TelnetFilter};
+
+    #[test]
+    fn parse_test() {
+        assert_eq!(SerialSpec::parse("stdio"), Ok(SerialSpec::Stdio));
+        assert_eq!(SerialSpec::parse("tcp:4444"),
+                   Ok(SerialSpec::Tcp { address: "127.0.0.1:4444".to_string(), telnet: false, wait: false }));
+        assert_eq!(SerialSpec::parse("telnet:0.0.0.0:23,wait"),
+                   Ok(SerialSpec::Tcp { address: "0.0.0.0:23".to_string(), telnet: true, wait: true }));
+        assert!(SerialSpec::parse("tcp:").is_err());
+        assert!(SerialSpec::parse("tcp:host:70000").is_err());
+        assert!(SerialSpec::parse("pty").is_err());
+    }
+
+    #[test]
+    fn telnet_filter_test() {
+        let mut filter: TelnetFilter = TelnetFilter::default();
+        // IAC DO ECHO, IAC SB TTYPE ... IAC SE, ls CR NUL, IAC IAC, CR LF
+        let sent: Vec<u8> = vec![0xff, 0xfd, 0x01, 0xff, 0xfa, 0x18, 0x00, 0xff, 0xf0,
+                                 b'l', b's', b'\r', 0x00, 0xff, 0xff, b'\r', b'\n'];
+        let received: Vec<u8> = sent.into_iter().filter_map(|byte| filter.filter(byte)).collect();
+        assert_eq!(received, vec![b'l', b's', b'\r', 0xff, b'\r']);
+    }
+
+    #[test]
+    fn tcp_console_test() {
+        let mut console: TcpConsole = TcpConsole::listen("127.0.0.1:0", false, false).unwrap();
+        let mut client: TcpStream = TcpStream::connect(console.get_address()).unwrap();
+        while !console.is_attached() {
+            std::thread::yield_now();
+        }
+        console.write(b"login: ");
+        let mut output: [u8; 7] = [0; 7];
+        client.read_exact(&mut output).unwrap();
+        assert_eq!(&output, b"login: ");
+        client.write_all(b"root").unwrap();
+        let mut input: Vec<u8> = Vec::new();
+        while input.len() < 4 {
+            if let Some(byte) = console.read_byte() {
+                input.push(byte);
+            }
+        }
+        assert_eq!(input, b"root");
+    }
+}
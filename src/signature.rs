@@ -0,0 +1,68 @@
+use crate::symbols::SymbolMap;
+
+/// Symbols of a compliance test (riscv-tests, RISCOF architecture tests):
+/// the test stores to tohost when it is over, and the results it has to
+/// produce are stored between begin_signature and end_signature
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Signature {
+    pub tohost: u64,
+    pub begin: u64,
+    pub end: u64
+}
+
+impl Signature {
+    /// Find the symbols of the test in the symbol table of the executable
+    pub fn from_symbols(symbols: &SymbolMap) -> Result<Signature, String> {
+        let find = |name: &str| symbols.address_of(name).ok_or(format!("Symbol {} not found, is this a compliance test?", name));
+        let signature: Signature = Signature {
+            tohost: find("tohost")?,
+            begin: find("begin_signature")?,
+            end: find("end_signature")?
+        };
+        if signature.end < signature.begin {
+            return Err(format!("end_signature (0x{:x}) is before begin_signature (0x{:x})", signature.end, signature.begin));
+        }
+        Ok(signature)
+    }
+
+    /// Size of the signature in bytes
+    pub fn len(&self) -> u64 {
+        self.end - self.begin
+    }
+
+    /// Check if the signature is empty
+    pub fn is_empty(&self) -> bool {
+        self.end == self.begin
+    }
+
+    /// Lay out the signature as RISCOF expects it: one 32-bit word per
+    /// line, as 8 lowercase hexadecimal digits, starting from the lowest
+    /// address. The last word is padded with zeros
+    pub fn encode(data: &[u8]) -> String {
+        let mut text: String = String::with_capacity(data.len() / 4 * 9);
+        for word in data.chunks(4) {
+            let mut bytes: [u8; 4] = [0; 4];
+            bytes[..word.len()].copy_from_slice(word);
+            text.push_str(&format!("{:08x}\n", u32::from_le_bytes(bytes)));
+        }
+        text
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::signature::Signature;
+    use crate::symbols::SymbolMap;
+
+    #[test]
+    fn signature_test() {
+        let symbols: SymbolMap = SymbolMap::parse("0000000080001000 D tohost\n\
+                                                   0000000080002000 D begin_signature\n\
+                                                   0000000080002010 D end_signature\n");
+        let signature: Signature = Signature::from_symbols(&symbols).unwrap();
+        assert_eq!((signature.tohost, signature.len()), (0x80001000, 0x10));
+        assert!(Signature::from_symbols(&SymbolMap::parse("0000000080001000 D tohost\n")).is_err());
+
+        assert_eq!(Signature::encode(&[0xef, 0xbe, 0xad, 0xde, 0x01]), "deadbeef\n00000001\n");
+    }
+}
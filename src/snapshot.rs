@@ -0,0 +1,165 @@
+use std::fs::File;
+use std::io::{Read, Write};
+
+/// Part of the machine whose state can be saved into a snapshot and
+/// restored from it. Each part reads back exactly what it wrote, in the
+/// same order
+pub trait Snapshot {
+    fn save(&self, writer: &mut SnapshotWriter);
+
+    fn restore(&mut self, reader: &mut SnapshotReader) -> Result<(), String>;
+}
+
+/// Build the binary image of a snapshot. Values are stored in little endian
+#[derive(Default)]
+pub struct SnapshotWriter {
+    data: Vec<u8>
+}
+
+impl SnapshotWriter {
+    // File signature and format version, checked when a snapshot is loaded
+    const MAGIC: &'static [u8; 8] = b"RIVSNAP\0";
+    const VERSION: u32 = 8;
+
+    pub fn new() -> SnapshotWriter {
+        let mut writer: SnapshotWriter = SnapshotWriter { data: Vec::new() };
+        writer.data.extend_from_slice(SnapshotWriter::MAGIC);
+        writer.put_u32(SnapshotWriter::VERSION);
+        writer
+    }
+
+    pub fn put_u8(&mut self, value: u8) {
+        self.data.push(value);
+    }
+
+    pub fn put_u32(&mut self, value: u32) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn put_u64(&mut self, value: u64) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    /// Store a buffer preceded by its length
+    pub fn put_bytes(&mut self, bytes: &[u8]) {
+        self.put_u64(bytes.len() as u64);
+        self.data.extend_from_slice(bytes);
+    }
+
+    pub fn write_to_file(&self, filename: &str) -> Result<(), String> {
+        let mut file: File = match File::create(filename) {
+            Ok(file) => file,
+            Err(why) => return Err(format!("Could not create snapshot {}: {}", filename, why))
+        };
+        file.write_all(&self.data).map_err(|why| format!("Could not write snapshot {}: {}", filename, why))
+    }
+}
+
+/// Read the values of a snapshot back, in the order they were written
+pub struct SnapshotReader {
+    data: Vec<u8>,
+    position: usize
+}
+
+impl SnapshotReader {
+    /// Check the signature and the version of a snapshot
+    pub fn new(data: Vec<u8>) -> Result<SnapshotReader, String> {
+        let mut reader: SnapshotReader = SnapshotReader { data, position: 0 };
+        if reader.take(SnapshotWriter::MAGIC.len())? != SnapshotWriter::MAGIC {
+            return Err("Not a riviera snapshot".to_string());
+        }
+        match reader.get_u32()? {
+            SnapshotWriter::VERSION => Ok(reader),
+            version => Err(format!("Unsupported snapshot version {}", version))
+        }
+    }
+
+    pub fn read_from_file(filename: &str) -> Result<SnapshotReader, String> {
+        let mut data: Vec<u8> = Vec::new();
+        match File::open(filename).and_then(|mut file| file.read_to_end(&mut data)) {
+            Ok(_) => SnapshotReader::new(data),
+            Err(why) => Err(format!("Could not read snapshot {}: {}", filename, why))
+        }
+    }
+
+    pub fn get_u8(&mut self) -> Result<u8, String> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn get_u32(&mut self) -> Result<u32, String> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    pub fn get_u64(&mut self) -> Result<u64, String> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    /// Read a buffer stored with its length
+    pub fn get_bytes(&mut self) -> Result<Vec<u8>, String> {
+        let length: u64 = self.get_u64()?;
+        let length: usize = usize::try_from(length).map_err(|_| "Snapshot is corrupted".to_string())?;
+        Ok(self.take(length)?.to_vec())
+    }
+
+    /// Check that the whole snapshot has been consumed
+    pub fn finish(&self) -> Result<(), String> {
+        if self.position == self.data.len() {
+            Ok(())
+        } else {
+            Err("Snapshot has trailing data".to_string())
+        }
+    }
+
+    fn take(&mut self, length: usize) -> Result<&[u8], String> {
+        let end: usize = self.position.checked_add(length)
+            .filter(|end| *end <= self.data.len())
+            .ok_or("Snapshot is truncated")?;
+        let bytes: &[u8] = &self.data[self.position..end];
+        self.position = end;
+        Ok(bytes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cpu::Cpu;
+    use crate::memory::AccessSize;
+    use crate::snapshot::{Snapshot, SnapshotReader, SnapshotWriter};
+
+    #[test]
+    fn format_test() {
+        let mut writer: SnapshotWriter = SnapshotWriter::new();
+        writer.put_u8(0x12);
+        writer.put_u64(0xdeadbeef_cafebabe);
+        writer.put_bytes(b"dram");
+        let mut reader: SnapshotReader = SnapshotReader::new(writer.data.clone()).unwrap();
+        assert_eq!(reader.get_u8(), Ok(0x12));
+        assert_eq!(reader.get_u64(), Ok(0xdeadbeef_cafebabe));
+        assert_eq!(reader.get_bytes(), Ok(b"dram".to_vec()));
+        assert_eq!(reader.finish(), Ok(()));
+        assert!(reader.get_u8().is_err());
+
+        // Truncated and foreign files are rejected
+        assert!(SnapshotReader::new(writer.data[..10].to_vec()).is_err());
+        assert!(SnapshotReader::new(b"\x7fELF\x02\x01\x01\0\0\0\0\0".to_vec()).is_err());
+    }
+
+    #[test]
+    fn cpu_round_trip_test() {
+        let mut cpu: Cpu = Cpu::new(Some(0x100));
+        cpu.set_read_write_segment(0x20000);
+        cpu.write_reg(10, 0x1234);
+        cpu.set_pc(0x10008);
+        cpu.store(0xabcd, 0x20010, AccessSize::WORD).unwrap();
+        let mut writer: SnapshotWriter = SnapshotWriter::new();
+        cpu.save(&mut writer);
+
+        let mut restored: Cpu = Cpu::new(None);
+        let mut reader: SnapshotReader = SnapshotReader::new(writer.data).unwrap();
+        restored.restore(&mut reader).unwrap();
+        reader.finish().unwrap();
+        assert_eq!(restored.read_reg(10), 0x1234);
+        assert_eq!(restored.get_pc(), 0x10008);
+        assert_eq!(restored.peek(0x20010, AccessSize::WORD), Ok(0xabcd));
+    }
+}
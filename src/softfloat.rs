@@ -0,0 +1,705 @@
+use std::cmp::Ordering;
+
+/// Rounding modes of the F and D extensions, with their encoding in frm
+/// and in the rm field of the instructions
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum RoundingMode {
+    NearestEven = 0,
+    TowardZero = 1,
+    Down = 2,
+    Up = 3,
+    NearestMaxMagnitude = 4
+}
+
+impl RoundingMode {
+    /// Rounding mode encoded in frm, None for the reserved encodings
+    pub fn from_bits(bits: u64) -> Option<RoundingMode> {
+        match bits {
+            0 => Some(RoundingMode::NearestEven),
+            1 => Some(RoundingMode::TowardZero),
+            2 => Some(RoundingMode::Down),
+            3 => Some(RoundingMode::Up),
+            4 => Some(RoundingMode::NearestMaxMagnitude),
+            _ => None
+        }
+    }
+}
+
+// Exception flags, in the same positions as in fflags
+pub const FLAG_INVALID: u8 = 0x10;
+pub const FLAG_DIVIDE_BY_ZERO: u8 = 0x08;
+pub const FLAG_OVERFLOW: u8 = 0x04;
+pub const FLAG_UNDERFLOW: u8 = 0x02;
+pub const FLAG_INEXACT: u8 = 0x01;
+
+/// IEEE 754 binary format (single or double precision) with its
+/// arithmetic implemented in software, bit-exact with the RISC-V
+/// specification: every operation rounds as selected by the rounding
+/// mode, raises the exception flags (underflow is detected after
+/// rounding) and returns the canonical NaN instead of propagating NaN
+/// payloads. Values are passed as their bit patterns.
+/// The operations work on integer significands: a finite value is
+/// sig * 2^exp, and the results are computed exactly or with a sticky
+/// bit below the rounding position, then rounded once
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Format {
+    exp_bits: u32,
+    frac_bits: u32
+}
+
+impl Format {
+    pub const F32: Format = Format { exp_bits: 8, frac_bits: 23 };
+    pub const F64: Format = Format { exp_bits: 11, frac_bits: 52 };
+
+    fn bias(self) -> i32 {
+        (1 << (self.exp_bits - 1)) - 1
+    }
+
+    // Exponent of the smallest normal number
+    fn emin(self) -> i32 {
+        1 - self.bias()
+    }
+
+    pub fn sign_bit(self) -> u64 {
+        1 << (self.exp_bits + self.frac_bits)
+    }
+
+    // Exponent field, in place (also the bits of an infinity)
+    fn exp_mask(self) -> u64 {
+        ((1 << self.exp_bits) - 1) << self.frac_bits
+    }
+
+    fn frac_mask(self) -> u64 {
+        (1 << self.frac_bits) - 1
+    }
+
+    fn quiet_bit(self) -> u64 {
+        1 << (self.frac_bits - 1)
+    }
+
+    /// NaN returned by the operations whose result is not a number
+    pub fn canonical_nan(self) -> u64 {
+        self.exp_mask() | self.quiet_bit()
+    }
+
+    fn signed(self, sign: bool, magnitude: u64) -> u64 {
+        if sign { self.sign_bit() | magnitude } else { magnitude }
+    }
+
+    fn zero(self, sign: bool) -> u64 {
+        self.signed(sign, 0)
+    }
+
+    fn infinity(self, sign: bool) -> u64 {
+        self.signed(sign, self.exp_mask())
+    }
+
+    fn max_finite(self, sign: bool) -> u64 {
+        self.signed(sign, self.exp_mask() - 1)
+    }
+
+    pub fn sign(self, a: u64) -> bool {
+        a & self.sign_bit() != 0
+    }
+
+    pub fn is_nan(self, a: u64) -> bool {
+        a & self.exp_mask() == self.exp_mask() && a & self.frac_mask() != 0
+    }
+
+    pub fn is_signaling(self, a: u64) -> bool {
+        self.is_nan(a) && a & self.quiet_bit() == 0
+    }
+
+    pub fn is_infinity(self, a: u64) -> bool {
+        a & !self.sign_bit() == self.exp_mask()
+    }
+
+    pub fn is_zero(self, a: u64) -> bool {
+        a & !self.sign_bit() == 0
+    }
+
+    // Magnitude of a finite value as (exp, sig), such that it is sig * 2^exp
+    fn unpack(self, a: u64) -> (i32, u128) {
+        let biased: i32 = ((a & self.exp_mask()) >> self.frac_bits) as i32;
+        let frac: u128 = (a & self.frac_mask()) as u128;
+        if biased == 0 {
+            (self.emin() - self.frac_bits as i32, frac)
+        } else {
+            (biased - self.bias() - self.frac_bits as i32, frac | (1 << self.frac_bits))
+        }
+    }
+
+    // Round sig * 2^exp to the format and pack it. sig may have a sticky
+    // bit in its least significant bit, as long as there are at least
+    // two more bits above it than the precision of the format
+    fn round_pack(self, sign: bool, exp: i32, sig: u128, rm: RoundingMode, flags: &mut u8) -> u64 {
+        if sig == 0 {
+            return self.zero(sign);
+        }
+        let frac_bits: i32 = self.frac_bits as i32;
+        // Exponent of the most significant bit, and of the last bit kept
+        let top: i32 = exp + 127 - sig.leading_zeros() as i32;
+        let ulp: i32 = top.max(self.emin()) - frac_bits;
+        let (kept, inexact) = round_shift(sig, ulp - exp, sign, rm);
+        if inexact {
+            *flags |= FLAG_INEXACT;
+            // Tininess is detected after rounding: a result just below the
+            // smallest normal number is not tiny if rounding it with an
+            // unbounded exponent gives the smallest normal number
+            let rounds_to_normal: bool = top == self.emin() - 1 &&
+                round_shift(sig, top - frac_bits - exp, sign, rm).0 >> (frac_bits + 1) != 0;
+            if top < self.emin() && !rounds_to_normal {
+                *flags |= FLAG_UNDERFLOW;
+            }
+        }
+        // The hidden bit of a normal significand is added to the exponent
+        // field, and so is the carry of a significand rounded up to the
+        // next power of two
+        let bits: u128 = (((ulp - self.emin() + frac_bits) as u128) << frac_bits) + kept;
+        if bits >= self.exp_mask() as u128 {
+            *flags |= FLAG_OVERFLOW | FLAG_INEXACT;
+            let to_infinity: bool = match rm {
+                RoundingMode::NearestEven | RoundingMode::NearestMaxMagnitude => true,
+                RoundingMode::TowardZero => false,
+                RoundingMode::Down => sign,
+                RoundingMode::Up => !sign
+            };
+            return if to_infinity { self.infinity(sign) } else { self.max_finite(sign) };
+        }
+        self.signed(sign, bits as u64)
+    }
+
+    // Result of an operation with a NaN operand: the canonical NaN, and
+    // the invalid flag if any operand is a signaling NaN
+    fn propagate_nan(self, operands: &[u64], flags: &mut u8) -> u64 {
+        if operands.iter().any(|a| self.is_signaling(*a)) {
+            *flags |= FLAG_INVALID;
+        }
+        self.canonical_nan()
+    }
+
+    fn invalid(self, flags: &mut u8) -> u64 {
+        *flags |= FLAG_INVALID;
+        self.canonical_nan()
+    }
+
+    /// a + b
+    pub fn add(self, a: u64, b: u64, rm: RoundingMode, flags: &mut u8) -> u64 {
+        if self.is_nan(a) || self.is_nan(b) {
+            return self.propagate_nan(&[a, b], flags);
+        }
+        let (sign_a, sign_b) = (self.sign(a), self.sign(b));
+        if self.is_infinity(a) || self.is_infinity(b) {
+            if self.is_infinity(a) && self.is_infinity(b) && sign_a != sign_b {
+                return self.invalid(flags);
+            }
+            return if self.is_infinity(a) { a } else { b };
+        }
+        let (exp_a, sig_a) = self.unpack(a);
+        let (exp_b, sig_b) = self.unpack(b);
+        self.add_magnitudes((sign_a, exp_a, sig_a), (sign_b, exp_b, sig_b), rm, flags)
+    }
+
+    /// a - b
+    pub fn sub(self, a: u64, b: u64, rm: RoundingMode, flags: &mut u8) -> u64 {
+        self.add(a, b ^ self.sign_bit(), rm, flags)
+    }
+
+    // Sum of two finite values given as (sign, exp, sig), whose
+    // significands are at most 106 bits long (a product of significands)
+    fn add_magnitudes(self, a: (bool, i32, u128), b: (bool, i32, u128), rm: RoundingMode, flags: &mut u8) -> u64 {
+        let ((sign_a, exp_a, sig_a), (sign_b, exp_b, sig_b)) = (a, b);
+        if sig_a == 0 && sig_b == 0 {
+            // Zeros of opposite sign add up to +0, or -0 when rounding down
+            let sign: bool = if sign_a == sign_b { sign_a } else { rm == RoundingMode::Down };
+            return self.zero(sign);
+        }
+        if sig_a == 0 {
+            return self.round_pack(sign_b, exp_b, sig_b, rm, flags);
+        }
+        if sig_b == 0 {
+            return self.round_pack(sign_a, exp_a, sig_a, rm, flags);
+        }
+        // Align both significands to bit 125, which leaves room for the
+        // carry and keeps at least 19 bits below the longest significand,
+        // then shift the smaller operand right to the larger exponent
+        let (exp_a, sig_a) = normalize(exp_a, sig_a, 125);
+        let (exp_b, sig_b) = normalize(exp_b, sig_b, 125);
+        let ((sign_big, exp_big, sig_big), (sign_small, exp_small, sig_small)) = if exp_a >= exp_b {
+            ((sign_a, exp_a, sig_a), (sign_b, exp_b, sig_b))
+        } else {
+            ((sign_b, exp_b, sig_b), (sign_a, exp_a, sig_a))
+        };
+        let sig_small: u128 = shift_right_jam(sig_small, (exp_big - exp_small) as u32);
+        if sign_big == sign_small {
+            return self.round_pack(sign_big, exp_big, sig_big + sig_small, rm, flags);
+        }
+        match sig_big.cmp(&sig_small) {
+            Ordering::Greater => self.round_pack(sign_big, exp_big, sig_big - sig_small, rm, flags),
+            Ordering::Less => self.round_pack(sign_small, exp_big, sig_small - sig_big, rm, flags),
+            Ordering::Equal => self.zero(rm == RoundingMode::Down)
+        }
+    }
+
+    /// a * b
+    pub fn mul(self, a: u64, b: u64, rm: RoundingMode, flags: &mut u8) -> u64 {
+        if self.is_nan(a) || self.is_nan(b) {
+            return self.propagate_nan(&[a, b], flags);
+        }
+        let sign: bool = self.sign(a) != self.sign(b);
+        if self.is_infinity(a) || self.is_infinity(b) {
+            if self.is_zero(a) || self.is_zero(b) {
+                return self.invalid(flags);
+            }
+            return self.infinity(sign);
+        }
+        let (exp_a, sig_a) = self.unpack(a);
+        let (exp_b, sig_b) = self.unpack(b);
+        self.round_pack(sign, exp_a + exp_b, sig_a * sig_b, rm, flags)
+    }
+
+    /// a * b + c rounded once
+    pub fn fma(self, a: u64, b: u64, c: u64, rm: RoundingMode, flags: &mut u8) -> u64 {
+        // Infinity times zero is invalid even if the addend is a quiet NaN
+        let infinity_times_zero: bool = (self.is_infinity(a) && self.is_zero(b)) ||
+            (self.is_zero(a) && self.is_infinity(b));
+        if self.is_nan(a) || self.is_nan(b) || self.is_nan(c) {
+            if infinity_times_zero {
+                *flags |= FLAG_INVALID;
+            }
+            return self.propagate_nan(&[a, b, c], flags);
+        }
+        if infinity_times_zero {
+            return self.invalid(flags);
+        }
+        let sign: bool = self.sign(a) != self.sign(b);
+        if self.is_infinity(a) || self.is_infinity(b) {
+            if self.is_infinity(c) && self.sign(c) != sign {
+                return self.invalid(flags);
+            }
+            return self.infinity(sign);
+        }
+        if self.is_infinity(c) {
+            return c;
+        }
+        let (exp_a, sig_a) = self.unpack(a);
+        let (exp_b, sig_b) = self.unpack(b);
+        let (exp_c, sig_c) = self.unpack(c);
+        self.add_magnitudes((sign, exp_a + exp_b, sig_a * sig_b), (self.sign(c), exp_c, sig_c), rm, flags)
+    }
+
+    /// a / b
+    pub fn div(self, a: u64, b: u64, rm: RoundingMode, flags: &mut u8) -> u64 {
+        if self.is_nan(a) || self.is_nan(b) {
+            return self.propagate_nan(&[a, b], flags);
+        }
+        let sign: bool = self.sign(a) != self.sign(b);
+        if self.is_infinity(a) {
+            if self.is_infinity(b) {
+                return self.invalid(flags);
+            }
+            return self.infinity(sign);
+        }
+        if self.is_infinity(b) {
+            return self.zero(sign);
+        }
+        if self.is_zero(b) {
+            if self.is_zero(a) {
+                return self.invalid(flags);
+            }
+            *flags |= FLAG_DIVIDE_BY_ZERO;
+            return self.infinity(sign);
+        }
+        if self.is_zero(a) {
+            return self.zero(sign);
+        }
+        // With both significands at bit 63, the quotient of the dividend
+        // shifted by 64 has 64 or 65 bits, the remainder is the sticky bit
+        let (exp_a, sig_a) = self.unpack(a);
+        let (exp_b, sig_b) = self.unpack(b);
+        let (exp_a, sig_a) = normalize(exp_a, sig_a, 63);
+        let (exp_b, sig_b) = normalize(exp_b, sig_b, 63);
+        let quotient: u128 = (sig_a << 64) / sig_b;
+        let sticky: bool = (sig_a << 64) % sig_b != 0;
+        self.round_pack(sign, exp_a - exp_b - 64, quotient | sticky as u128, rm, flags)
+    }
+
+    /// Square root of a
+    pub fn sqrt(self, a: u64, rm: RoundingMode, flags: &mut u8) -> u64 {
+        if self.is_nan(a) {
+            return self.propagate_nan(&[a], flags);
+        }
+        if self.is_zero(a) {
+            return a;
+        }
+        if self.sign(a) {
+            return self.invalid(flags);
+        }
+        if self.is_infinity(a) {
+            return a;
+        }
+        // The exponent has to be even to be halved: the radicand is
+        // aligned to bit 124 or 125, so its root has 63 bits
+        let (exp, sig) = self.unpack(a);
+        let (mut exp, mut sig) = normalize(exp, sig, 124);
+        if exp % 2 != 0 {
+            sig <<= 1;
+            exp -= 1;
+        }
+        let root: u128 = sig.isqrt();
+        let sticky: bool = root * root != sig;
+        self.round_pack(false, exp / 2, root | sticky as u128, rm, flags)
+    }
+
+    /// Minimum of a and b: a NaN operand is ignored unless both are
+    /// NaNs, and -0 is smaller than +0
+    pub fn min(self, a: u64, b: u64, flags: &mut u8) -> u64 {
+        self.min_max(a, b, true, flags)
+    }
+
+    /// Maximum of a and b (see min)
+    pub fn max(self, a: u64, b: u64, flags: &mut u8) -> u64 {
+        self.min_max(a, b, false, flags)
+    }
+
+    fn min_max(self, a: u64, b: u64, min: bool, flags: &mut u8) -> u64 {
+        if self.is_signaling(a) || self.is_signaling(b) {
+            *flags |= FLAG_INVALID;
+        }
+        match (self.is_nan(a), self.is_nan(b)) {
+            (true, true) => self.canonical_nan(),
+            (true, false) => b,
+            (false, true) => a,
+            (false, false) => {
+                // Unlike the comparisons, -0 and +0 are ordered
+                let a_first: bool = if self.sign(a) != self.sign(b) {
+                    self.sign(a) == min
+                } else {
+                    self.less(a, b) == min
+                };
+                if a_first { a } else { b }
+            }
+        }
+    }
+
+    // a < b for values that are not NaNs
+    fn less(self, a: u64, b: u64) -> bool {
+        if self.is_zero(a) && self.is_zero(b) {
+            return false;
+        }
+        let (magnitude_a, magnitude_b) = (a & !self.sign_bit(), b & !self.sign_bit());
+        match (self.sign(a), self.sign(b)) {
+            (false, false) => magnitude_a < magnitude_b,
+            (true, true) => magnitude_a > magnitude_b,
+            (sign_a, _) => sign_a
+        }
+    }
+
+    /// a == b, a quiet comparison: only signaling NaNs are invalid
+    pub fn eq(self, a: u64, b: u64, flags: &mut u8) -> bool {
+        if self.is_nan(a) || self.is_nan(b) {
+            if self.is_signaling(a) || self.is_signaling(b) {
+                *flags |= FLAG_INVALID;
+            }
+            return false;
+        }
+        a == b || (self.is_zero(a) && self.is_zero(b))
+    }
+
+    /// a < b, a signaling comparison: any NaN is invalid
+    pub fn lt(self, a: u64, b: u64, flags: &mut u8) -> bool {
+        if self.is_nan(a) || self.is_nan(b) {
+            *flags |= FLAG_INVALID;
+            return false;
+        }
+        self.less(a, b)
+    }
+
+    /// a <= b, a signaling comparison: any NaN is invalid
+    pub fn le(self, a: u64, b: u64, flags: &mut u8) -> bool {
+        if self.is_nan(a) || self.is_nan(b) {
+            *flags |= FLAG_INVALID;
+            return false;
+        }
+        !self.less(b, a)
+    }
+
+    /// Class of a value as returned by FCLASS: one bit set out of
+    /// -inf, -normal, -subnormal, -0, +0, +subnormal, +normal, +inf,
+    /// signaling NaN and quiet NaN
+    pub fn classify(self, a: u64) -> u64 {
+        let sign: bool = self.sign(a);
+        let bit: u32 = if self.is_nan(a) {
+            if self.is_signaling(a) { 8 } else { 9 }
+        } else if self.is_infinity(a) {
+            if sign { 0 } else { 7 }
+        } else if self.is_zero(a) {
+            if sign { 3 } else { 4 }
+        } else if a & self.exp_mask() == 0 {
+            if sign { 2 } else { 5 }
+        } else if sign {
+            1
+        } else {
+            6
+        };
+        1 << bit
+    }
+
+    /// Convert a to an integer of width bits (32 or 64), signed or
+    /// unsigned. Out of range values and NaNs are invalid and saturate
+    /// (NaNs to the largest integer). The 32-bit results are returned
+    /// sign-extended, as they are written in the registers
+    pub fn to_int(self, a: u64, signed: bool, width: u32, rm: RoundingMode, flags: &mut u8) -> u64 {
+        let max: u128 = if signed { (1 << (width - 1)) - 1 } else { (1 << width) - 1 };
+        let min: i128 = if signed { -(1 << (width - 1)) } else { 0 };
+        let sign: bool = self.sign(a) && !self.is_nan(a);
+        let value: i128 = if self.is_nan(a) || self.is_infinity(a) {
+            *flags |= FLAG_INVALID;
+            if sign { min } else { max as i128 }
+        } else {
+            let (exp, sig) = self.unpack(a);
+            let (magnitude, inexact) = if exp >= 0 {
+                // Too large for any integer: saturated below
+                if exp > 64 { (u128::MAX, false) } else { (sig << exp, false) }
+            } else {
+                round_shift(sig, -exp, sign, rm)
+            };
+            let (too_small, too_large) = if sign {
+                (magnitude > min.unsigned_abs(), false)
+            } else {
+                (false, magnitude > max)
+            };
+            if too_small || too_large {
+                *flags |= FLAG_INVALID;
+                if too_small { min } else { max as i128 }
+            } else {
+                if inexact {
+                    *flags |= FLAG_INEXACT;
+                }
+                if sign { -(magnitude as i128) } else { magnitude as i128 }
+            }
+        };
+        if width == 32 { value as i32 as i64 as u64 } else { value as u64 }
+    }
+
+    /// Convert the low width bits (32 or 64) of an integer, signed or
+    /// unsigned, to this format
+    pub fn from_int(self, value: u64, signed: bool, width: u32, rm: RoundingMode, flags: &mut u8) -> u64 {
+        let value: i128 = match (width, signed) {
+            (32, true) => value as i32 as i128,
+            (32, false) => value as u32 as i128,
+            (_, true) => value as i64 as i128,
+            (_, false) => value as i128
+        };
+        self.round_pack(value < 0, 0, value.unsigned_abs(), rm, flags)
+    }
+
+    /// Convert a value of another format to this format
+    pub fn convert(self, from: Format, a: u64, rm: RoundingMode, flags: &mut u8) -> u64 {
+        if from.is_nan(a) {
+            from.propagate_nan(&[a], flags);
+            return self.canonical_nan();
+        }
+        let sign: bool = from.sign(a);
+        if from.is_infinity(a) {
+            return self.infinity(sign);
+        }
+        let (exp, sig) = from.unpack(a);
+        self.round_pack(sign, exp, sig, rm, flags)
+    }
+}
+
+// Shift sig left so that its most significant bit is at position msb
+fn normalize(exp: i32, sig: u128, msb: u32) -> (i32, u128) {
+    let shift: i32 = sig.leading_zeros() as i32 - (127 - msb as i32);
+    if shift >= 0 {
+        (exp - shift, sig << shift)
+    } else {
+        (exp - shift, sig >> -shift)
+    }
+}
+
+// Shift right, keeping a sticky bit in the least significant bit if any
+// of the bits shifted out was set
+fn shift_right_jam(sig: u128, shift: u32) -> u128 {
+    match shift {
+        0 => sig,
+        1..=127 => (sig >> shift) | (sig & ((1 << shift) - 1) != 0) as u128,
+        _ => (sig != 0) as u128
+    }
+}
+
+// Drop the low shift bits of a significand, rounding the rest according
+// to the rounding mode. It returns the rounded significand and whether
+// any bit that was dropped was set
+fn round_shift(sig: u128, shift: i32, sign: bool, rm: RoundingMode) -> (u128, bool) {
+    if shift <= 0 {
+        return (sig << -shift, false);
+    }
+    // How the dropped bits compare with half of the last bit kept
+    let (kept, rest_vs_half, inexact) = match shift {
+        1..=127 => {
+            let rest: u128 = sig & ((1 << shift) - 1);
+            (sig >> shift, rest.cmp(&(1 << (shift - 1))), rest != 0)
+        },
+        128 => (0, sig.cmp(&(1 << 127)), sig != 0),
+        _ => (0, Ordering::Less, sig != 0)
+    };
+    let round_up: bool = inexact && match rm {
+        RoundingMode::NearestEven => rest_vs_half == Ordering::Greater ||
+            (rest_vs_half == Ordering::Equal && kept & 0x1 == 0x1),
+        RoundingMode::NearestMaxMagnitude => rest_vs_half != Ordering::Less,
+        RoundingMode::TowardZero => false,
+        RoundingMode::Down => sign,
+        RoundingMode::Up => !sign
+    };
+    (kept + round_up as u128, inexact)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::softfloat::*;
+
+    const RNE: RoundingMode = RoundingMode::NearestEven;
+
+    // Pseudo-random doubles covering all the exponents (their low halves
+    // are used as floats)
+    fn samples() -> Vec<u64> {
+        let mut state: u64 = 0x2545f4914f6cdd1d;
+        (0..4000).map(|_| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state
+        }).collect()
+    }
+
+    #[test]
+    fn host_rounding_test() {
+        // Round to nearest even matches the host FPU, NaNs aside
+        let values: Vec<u64> = samples();
+        for operands in values.chunks_exact(3) {
+            let (a, b, c) = (operands[0], operands[1], operands[2]);
+            let (x, y, z) = (f64::from_bits(a), f64::from_bits(b), f64::from_bits(c));
+            let mut flags: u8 = 0;
+            for (soft, host) in [(Format::F64.add(a, b, RNE, &mut flags), x + y),
+                                 (Format::F64.mul(a, b, RNE, &mut flags), x * y),
+                                 (Format::F64.div(a, b, RNE, &mut flags), x / y),
+                                 (Format::F64.sqrt(a & !(1 << 63), RNE, &mut flags), x.abs().sqrt()),
+                                 (Format::F64.fma(a, b, c, RNE, &mut flags), x.mul_add(y, z))] {
+                if !host.is_nan() {
+                    assert_eq!(soft, host.to_bits(), "{:?} {:?} {:?}", x, y, z);
+                }
+            }
+            let (a, b) = (a as u32 as u64, b as u32 as u64);
+            let (x, y) = (f32::from_bits(a as u32), f32::from_bits(b as u32));
+            for (soft, host) in [(Format::F32.add(a, b, RNE, &mut flags), x + y),
+                                 (Format::F32.mul(a, b, RNE, &mut flags), x * y),
+                                 (Format::F32.div(a, b, RNE, &mut flags), x / y),
+                                 (Format::F32.convert(Format::F64, c, RNE, &mut flags), z as f32)] {
+                if !host.is_nan() {
+                    assert_eq!(soft, host.to_bits() as u64, "{:?} {:?} {:?}", x, y, z);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn rounding_modes_test() {
+        let (one, three) = (1.0f64.to_bits(), 3.0f64.to_bits());
+        let third: u64 = (1.0f64 / 3.0).to_bits();
+        let mut flags: u8 = 0;
+        assert_eq!(Format::F64.div(one, three, RNE, &mut flags), third);
+        assert_eq!(flags, FLAG_INEXACT);
+        // 1/3 is rounded down to nearest, so rounding up gives the next double
+        assert_eq!(Format::F64.div(one, three, RoundingMode::Up, &mut flags), third + 1);
+        assert_eq!(Format::F64.div(one, three, RoundingMode::TowardZero, &mut flags), third);
+        // -1/3 toward -infinity
+        assert_eq!(Format::F64.div(one | (1 << 63), three, RoundingMode::Down, &mut flags), (third + 1) | (1 << 63));
+        // Ties: 2^24 + 1 is halfway between two floats
+        let tie: u64 = (1 << 24) + 1;
+        assert_eq!(Format::F32.from_int(tie, false, 64, RNE, &mut flags), 16777216.0f32.to_bits() as u64);
+        assert_eq!(Format::F32.from_int(tie, false, 64, RoundingMode::NearestMaxMagnitude, &mut flags),
+                   16777218.0f32.to_bits() as u64);
+        // x - x is -0 only when rounding down
+        assert_eq!(Format::F64.sub(three, three, RNE, &mut flags), 0);
+        assert_eq!(Format::F64.sub(three, three, RoundingMode::Down, &mut flags), 1 << 63);
+        // Conversions to integers
+        let minus_two_and_half: u64 = (-2.5f64).to_bits();
+        flags = 0;
+        assert_eq!(Format::F64.to_int(minus_two_and_half, true, 32, RNE, &mut flags) as i64, -2);
+        assert_eq!(Format::F64.to_int(minus_two_and_half, true, 32, RoundingMode::NearestMaxMagnitude, &mut flags) as i64, -3);
+        assert_eq!(Format::F64.to_int(minus_two_and_half, true, 64, RoundingMode::Up, &mut flags) as i64, -2);
+        assert_eq!(Format::F64.to_int(minus_two_and_half, true, 64, RoundingMode::Down, &mut flags) as i64, -3);
+        assert_eq!(flags, FLAG_INEXACT);
+    }
+
+    #[test]
+    fn exception_flags_test() {
+        let mut flags: u8 = 0;
+        let max: u64 = f64::MAX.to_bits();
+        // Overflow goes to infinity or to the largest number depending on the rounding mode
+        assert_eq!(Format::F64.add(max, max, RNE, &mut flags), f64::INFINITY.to_bits());
+        assert_eq!(flags, FLAG_OVERFLOW | FLAG_INEXACT);
+        assert_eq!(Format::F64.add(max, max, RoundingMode::TowardZero, &mut flags), max);
+        // Underflow needs a tiny and inexact result
+        flags = 0;
+        let min_normal: u64 = f64::MIN_POSITIVE.to_bits();
+        assert_eq!(Format::F64.div(min_normal, 2.0f64.to_bits(), RNE, &mut flags), 1 << 51);
+        assert_eq!(flags, 0);
+        assert_eq!(Format::F64.div(min_normal, 3.0f64.to_bits(), RNE, &mut flags), 0x0005_5555_5555_5555);
+        assert_eq!(flags, FLAG_UNDERFLOW | FLAG_INEXACT);
+        // Tininess after rounding: a result just below the smallest normal
+        // number is not tiny if it rounds up to it with 53 bits...
+        flags = 0;
+        let largest_subnormal: u64 = (1 << 52) - 1;
+        assert_eq!(Format::F64.mul(largest_subnormal, (1.0 + f64::EPSILON).to_bits(), RNE, &mut flags), min_normal);
+        assert_eq!(flags, FLAG_INEXACT);
+        // ...but it is if only the subnormal precision rounds it up
+        assert_eq!(Format::F64.mul(min_normal, (1.0 - f64::EPSILON / 2.0).to_bits(), RNE, &mut flags), min_normal);
+        assert_eq!(flags, FLAG_UNDERFLOW | FLAG_INEXACT);
+        // Invalid operations and division by zero
+        flags = 0;
+        assert_eq!(Format::F64.sqrt((-1.0f64).to_bits(), RNE, &mut flags), Format::F64.canonical_nan());
+        assert_eq!(flags, FLAG_INVALID);
+        flags = 0;
+        assert_eq!(Format::F32.div(1.0f32.to_bits() as u64, 0, RNE, &mut flags), f32::INFINITY.to_bits() as u64);
+        assert_eq!(flags, FLAG_DIVIDE_BY_ZERO);
+        // Signaling NaNs are invalid, quiet NaNs are not (except for fma(inf, 0, qNaN))
+        flags = 0;
+        let signaling: u64 = 0x7f80_0001;
+        assert_eq!(Format::F32.add(signaling, 0, RNE, &mut flags), 0x7fc0_0000);
+        assert_eq!(flags, FLAG_INVALID);
+        flags = 0;
+        assert!(!Format::F32.eq(0x7fc0_0000, 0, &mut flags));
+        assert_eq!(flags, 0);
+        assert!(!Format::F32.le(0x7fc0_0000, 0, &mut flags));
+        assert_eq!(flags, FLAG_INVALID);
+        flags = 0;
+        Format::F32.fma(f32::INFINITY.to_bits() as u64, 0, 0x7fc0_0000, RNE, &mut flags);
+        assert_eq!(flags, FLAG_INVALID);
+        // Out of range conversions saturate and are only invalid
+        flags = 0;
+        assert_eq!(Format::F64.to_int((-1.5f64).to_bits(), false, 32, RNE, &mut flags), 0);
+        assert_eq!(Format::F64.to_int(f64::NAN.to_bits(), true, 32, RNE, &mut flags), 0x7fff_ffff);
+        assert_eq!(Format::F64.to_int(1e20f64.to_bits(), false, 32, RNE, &mut flags), u64::MAX);
+        assert_eq!(flags, FLAG_INVALID);
+    }
+
+    #[test]
+    fn min_max_classify_test() {
+        let mut flags: u8 = 0;
+        let (zero, minus_zero, nan) = (0, 1 << 31, 0x7fc0_0000);
+        assert_eq!(Format::F32.min(zero, minus_zero, &mut flags), minus_zero);
+        assert_eq!(Format::F32.max(minus_zero, zero, &mut flags), zero);
+        assert_eq!(Format::F32.max(nan, 2.0f32.to_bits() as u64, &mut flags), 2.0f32.to_bits() as u64);
+        assert_eq!(Format::F32.min(nan, nan, &mut flags), nan);
+        assert_eq!(flags, 0);
+        assert!(Format::F32.eq(zero, minus_zero, &mut flags));
+        assert!(Format::F32.lt((-1.0f32).to_bits() as u64, minus_zero, &mut flags));
+        assert_eq!(Format::F32.classify(minus_zero), 1 << 3);
+        assert_eq!(Format::F32.classify(0x7f80_0001), 1 << 8);
+        assert_eq!(Format::F64.classify(1), 1 << 5);
+        assert_eq!(Format::F64.classify(f64::NEG_INFINITY.to_bits()), 1 << 0);
+    }
+}
@@ -0,0 +1,220 @@
+use std::ops::Range;
+use crate::expr::parse_size;
+use crate::memory::AccessSize;
+
+/// A function that has been called and has not returned yet
+#[derive(Clone)]
+struct GuardedFrame {
+    // PC of the call instruction and address of the called function
+    call_pc: u64,
+    function: u64,
+    return_address: u64,
+    // Stack pointer at the call: the frame of the callee lies below it
+    sp: u64,
+    // Stack slot where the callee saved its return address, if it did
+    saved_ra: Option<u64>
+}
+
+/// What went wrong on the stack
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ViolationKind {
+    // A store overwrote the return address saved by a function that is still running
+    Overwrite { address: u64, size: AccessSize },
+    // A function returned somewhere else than right after its call
+    ReturnMismatch { expected: u64, actual: u64 }
+}
+
+/// Stack corruption caught by the guard, with the instruction that caused it
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct StackViolation {
+    pub pc: u64,
+    pub kind: ViolationKind,
+    // Function whose frame was corrupted and the call that created it
+    pub function: u64,
+    pub call_pc: u64
+}
+
+/// Guard against stack buffer overruns. Calls and returns are tracked
+/// through ra and sp: when a function saves its return address on the
+/// stack (at the boundary with the frame of its caller) that slot is
+/// write-protected until the function returns, so an overrun is reported
+/// by the store that causes it rather than when the program crashes
+#[derive(Clone, Default)]
+pub struct StackGuard {
+    frames: Vec<GuardedFrame>,
+    violations: Vec<StackViolation>,
+    // Total number of violations, only the first ones are kept
+    count: u64
+}
+
+impl StackGuard {
+    // Maximum number of violations that are kept
+    const MAX_VIOLATIONS: usize = 64;
+
+    pub fn new() -> StackGuard {
+        StackGuard { frames: Vec::new(), violations: Vec::new(), count: 0 }
+    }
+
+    /// A call instruction at pc jumps to function, with the given return address and sp
+    pub fn call(&mut self, pc: u64, function: u64, return_address: u64, sp: u64) {
+        self.frames.push(GuardedFrame { call_pc: pc, function, return_address, sp, saved_ra: None });
+    }
+
+    /// A return instruction at pc jumps to target. Frames are popped up to
+    /// the one that returns to target, so that functions left through
+    /// longjmp-like jumps do not stay on the stack. A return that does not
+    /// match any active call is reported
+    pub fn ret(&mut self, pc: u64, target: u64) {
+        match self.frames.iter().rposition(|frame| frame.return_address == target) {
+            Some(position) => self.frames.truncate(position),
+            None => if let Some(frame) = self.frames.pop() {
+                self.report(StackViolation {
+                    pc,
+                    kind: ViolationKind::ReturnMismatch { expected: frame.return_address, actual: target },
+                    function: frame.function,
+                    call_pc: frame.call_pc
+                });
+            }
+        }
+    }
+
+    /// Check a store executed at pc. The first doubleword store of the return
+    /// address inside the frame of the innermost function marks the slot
+    /// to protect, any other store that touches a protected slot is reported
+    #[inline(always)]
+    pub fn check_store(&mut self, pc: u64, address: u64, size: AccessSize, data: u64) {
+        let end: u64 = address.wrapping_add(size.bytes() as u64);
+        if let Some(frame) = self.frames.last_mut() {
+            if frame.saved_ra.is_none() && size == AccessSize::DOUBLEWORD &&
+               data == frame.return_address && address < frame.sp {
+                frame.saved_ra = Some(address);
+                return;
+            }
+        }
+        // Protected slots get higher going out of the call stack, so the
+        // search stops at the first one above the store
+        let mut violation: Option<StackViolation> = None;
+        for frame in self.frames.iter().rev() {
+            let slot: u64 = match frame.saved_ra {
+                Some(slot) => slot,
+                None => continue
+            };
+            if slot >= end {
+                break;
+            }
+            if address < slot + 8 {
+                violation = Some(StackViolation {
+                    pc,
+                    kind: ViolationKind::Overwrite { address, size },
+                    function: frame.function,
+                    call_pc: frame.call_pc
+                });
+                break;
+            }
+        }
+        if let Some(violation) = violation {
+            self.report(violation);
+        }
+    }
+
+    /// Violations caught so far (only the first ones are kept)
+    pub fn get_violations(&self) -> &[StackViolation] {
+        &self.violations
+    }
+
+    /// Total number of violations caught so far
+    pub fn get_count(&self) -> u64 {
+        self.count
+    }
+
+    fn report(&mut self, violation: StackViolation) {
+        self.count += 1;
+        if self.violations.len() < StackGuard::MAX_VIOLATIONS {
+            self.violations.push(violation);
+        }
+    }
+}
+
+/// Size of the stack and of the guard region right below it, in bytes:
+/// the program must never access the guard region, it gets there only
+/// when its stack overflows
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct StackLimit {
+    pub size: u64,
+    pub guard: u64
+}
+
+impl StackLimit {
+    // Guard region when only the size of the stack is given, one page
+    const DEFAULT_GUARD: u64 = 0x1000;
+
+    /// Parse a stack limit written as <size>[:<guard>], where both can
+    /// end with k or m (e.g. "64k" or "1m:64k")
+    pub fn parse(spec: &str) -> Result<StackLimit, String> {
+        let (size, guard) = match spec.trim().split_once(':') {
+            Some((size, guard)) => (parse_size(size)?, parse_size(guard)?),
+            None => (parse_size(spec)?, StackLimit::DEFAULT_GUARD)
+        };
+        if size == 0 || guard == 0 {
+            return Err(format!("Invalid stack limit {}: the stack and its guard region cannot be empty", spec));
+        }
+        Ok(StackLimit { size, guard })
+    }
+
+    /// Guard region of a stack that starts at sp (the initial stack
+    /// pointer), None if it would go below address 0
+    pub fn guard_region(&self, sp: u64) -> Option<Range<u64>> {
+        let end: u64 = sp.checked_sub(self.size)?;
+        Some(end.checked_sub(self.guard)?..end)
+    }
+}
+
+/// The stack grew into its guard region: the instruction at pc accessed
+/// address, with the stack pointer at sp
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct StackOverflow {
+    pub pc: u64,
+    pub sp: u64,
+    pub address: u64
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::memory::AccessSize;
+    use crate::stackguard::{StackGuard, StackLimit, ViolationKind};
+
+    #[test]
+    fn overrun_test() {
+        let mut guard: StackGuard = StackGuard::new();
+        guard.call(0x100, 0x400, 0x104, 0x8000);
+        // Prologue: save ra at the top of the frame, then the locals
+        guard.check_store(0x400, 0x7ff8, AccessSize::DOUBLEWORD, 0x104);
+        guard.check_store(0x404, 0x7fe0, AccessSize::DOUBLEWORD, 0x0);
+        guard.check_store(0x408, 0x20000, AccessSize::WORD, 0x0);
+        assert_eq!(guard.get_count(), 0);
+        // A buffer overrun reaches the saved return address
+        guard.check_store(0x410, 0x7ff6, AccessSize::WORD, 0x41414141);
+        assert_eq!(guard.get_count(), 1);
+        let violation = guard.get_violations()[0];
+        assert_eq!(violation.pc, 0x410);
+        assert_eq!(violation.kind, ViolationKind::Overwrite { address: 0x7ff6, size: AccessSize::WORD });
+        assert_eq!((violation.function, violation.call_pc), (0x400, 0x100));
+        // Returning somewhere else is reported as well
+        guard.ret(0x420, 0x41414141);
+        assert_eq!(guard.get_count(), 2);
+        assert_eq!(guard.get_violations()[1].kind, ViolationKind::ReturnMismatch { expected: 0x104, actual: 0x41414141 });
+        // Once the function is gone its slot can be reused
+        guard.check_store(0x108, 0x7ff8, AccessSize::DOUBLEWORD, 0x0);
+        assert_eq!(guard.get_count(), 2);
+    }
+    #[test]
+    fn stack_limit_test() {
+        assert_eq!(StackLimit::parse("64k"), Ok(StackLimit { size: 0x10000, guard: 0x1000 }));
+        assert_eq!(StackLimit::parse("1m:0x100"), Ok(StackLimit { size: 0x100000, guard: 0x100 }));
+        assert!(StackLimit::parse("64k:0").is_err());
+        assert!(StackLimit::parse("big").is_err());
+        let limit: StackLimit = StackLimit::parse("0x2000:0x1000").unwrap();
+        assert_eq!(limit.guard_region(0x80010000), Some(0x8000d000..0x8000e000));
+        assert_eq!(limit.guard_region(0x2800), None);
+    }
+}
@@ -0,0 +1,328 @@
+use std::collections::BTreeMap;
+use crate::cpu::Cpu;
+use crate::csr::Privilege;
+use crate::profiling::BranchRecord;
+
+/// Statistics of a run, saved with --stats and compared with
+/// `riviera compare`. They are grouped in sections (e.g. "instructions",
+/// "traps", with --cache-sim "caches", with --branch-predictor "branch
+/// prediction" and with --profile "mnemonics", "branches", "memory" and
+/// "hot pcs"), each one a set of named counters. On disk they are a JSON
+/// object with one object of numbers per section:
+///
+/// ```json
+/// {
+///   "instructions": {
+///     "M-mode": 1200,
+///     "total": 1200
+///   }
+/// }
+/// ```
+#[derive(Clone, PartialEq, Eq, Debug, Default)]
+pub struct RunStats {
+    sections: BTreeMap<String, BTreeMap<String, u64>>
+}
+
+/// Values of a counter in two runs, None if the counter is missing in a run
+#[derive(Clone, PartialEq, Debug)]
+pub struct Change {
+    pub section: String,
+    pub name: String,
+    pub before: Option<u64>,
+    pub after: Option<u64>
+}
+
+impl Change {
+    /// Relative change in percent, None if the counter is missing in a
+    /// run or it was zero before
+    pub fn percent(&self) -> Option<f64> {
+        match (self.before, self.after) {
+            (Some(before), Some(after)) if before > 0 => Some((after as f64 - before as f64) * 100.0 / before as f64),
+            _ => None
+        }
+    }
+
+    /// Check if the change is worth highlighting: the counter changed by
+    /// more than threshold percent, or it appeared or disappeared
+    pub fn is_significant(&self, threshold: f64) -> bool {
+        match (self.before, self.after) {
+            (Some(before), Some(after)) if before == after => false,
+            (Some(0), Some(_)) => true,
+            (Some(_), Some(_)) => self.percent().is_some_and(|percent| percent.abs() >= threshold),
+            _ => true
+        }
+    }
+}
+
+impl RunStats {
+    /// Number of hottest PCs saved with the profile of the instructions
+    pub const HOT_PCS: usize = 10;
+
+    pub fn new() -> RunStats {
+        RunStats { sections: BTreeMap::new() }
+    }
+
+    /// Collect the statistics of the run of a Cpu
+    pub fn collect(cpu: &Cpu, instr_count: u64) -> RunStats {
+        let mut stats: RunStats = RunStats::new();
+        stats.set("instructions", "total", instr_count);
+        let accounting = cpu.get_time_accounting();
+        for privilege in [Privilege::Machine, Privilege::Supervisor] {
+            stats.set("instructions", &format!("{}-mode", privilege), accounting.get_normal(privilege));
+            stats.set("instructions", &format!("{}-mode trap handlers", privilege), accounting.get_handler(privilege));
+        }
+        stats.set("instructions", "idle", accounting.get_idle());
+        for (_, cause) in cpu.get_trap_statistics().causes() {
+            stats.set("traps", cause.description, cause.count);
+        }
+        if let Some(cache_sim) = cpu.get_cache_sim() {
+            for (name, cache) in [("I$", &cache_sim.icache), ("D$", &cache_sim.dcache)] {
+                stats.set("caches", &format!("{} accesses", name), cache.get_accesses());
+                stats.set("caches", &format!("{} misses", name), cache.get_misses());
+            }
+        }
+        if let Some(branches) = cpu.get_branch_profile() {
+            let total: BranchRecord = branches.total();
+            stats.set("branch prediction", "branches", total.executed);
+            stats.set("branch prediction", "mispredicted", total.mispredicted);
+        }
+        if let Some(profile) = cpu.get_profile() {
+            for (mnemonic, count) in profile.mnemonics() {
+                stats.set("mnemonics", &mnemonic, count);
+            }
+            let (taken, not_taken): (u64, u64) = profile.branches();
+            stats.set("branches", "taken", taken);
+            stats.set("branches", "not taken", not_taken);
+            stats.set("memory", "loads", profile.loads());
+            stats.set("memory", "stores", profile.stores());
+            for (pc, count) in profile.top_pcs(RunStats::HOT_PCS) {
+                stats.set("hot pcs", &format!("0x{:x}", pc), count);
+            }
+        }
+        stats
+    }
+
+    /// Set the value of a counter
+    pub fn set(&mut self, section: &str, name: &str, value: u64) {
+        self.sections.entry(section.to_string()).or_default().insert(name.to_string(), value);
+    }
+
+    /// Value of a counter, None if it is not in the statistics
+    pub fn get(&self, section: &str, name: &str) -> Option<u64> {
+        self.sections.get(section)?.get(name).copied()
+    }
+
+    /// Compare these statistics (before) with the ones of another run
+    /// (after). Every counter of either run is listed, grouped by section
+    pub fn compare(&self, after: &RunStats) -> Vec<Change> {
+        let mut keys: Vec<(&String, &String)> = Vec::new();
+        for stats in [self, after] {
+            for (section, counters) in &stats.sections {
+                keys.extend(counters.keys().map(|name| (section, name)));
+            }
+        }
+        keys.sort();
+        keys.dedup();
+        keys.into_iter().map(|(section, name)| Change {
+            section: section.clone(),
+            name: name.clone(),
+            before: self.get(section, name),
+            after: after.get(section, name)
+        }).collect()
+    }
+
+    /// Write the statistics as JSON
+    pub fn to_json(&self) -> String {
+        let mut json: String = String::from("{");
+        for (i, (section, counters)) in self.sections.iter().enumerate() {
+            json += if i == 0 { "\n" } else { ",\n" };
+            json += &format!("  {}: {{", quote(section));
+            for (j, (name, value)) in counters.iter().enumerate() {
+                json += if j == 0 { "\n" } else { ",\n" };
+                json += &format!("    {}: {}", quote(name), value);
+            }
+            json += "\n  }";
+        }
+        json += "\n}\n";
+        json
+    }
+
+    /// Read statistics written by to_json
+    pub fn parse(text: &str) -> Result<RunStats, String> {
+        let mut parser: JsonParser = JsonParser { text: text.as_bytes(), pos: 0 };
+        let mut stats: RunStats = RunStats::new();
+        parser.object(|parser, section| {
+            parser.object(|parser, name| {
+                let value: u64 = parser.number()?;
+                stats.set(&section, &name, value);
+                Ok(())
+            })
+        })?;
+        parser.skip_whitespace();
+        if parser.pos < parser.text.len() {
+            return Err(parser.error("unexpected text after the statistics"));
+        }
+        Ok(stats)
+    }
+
+    pub fn read_from_file(filename: &str) -> Result<RunStats, String> {
+        let text: String = std::fs::read_to_string(filename)
+            .map_err(|why| format!("Could not read {}: {}", filename, why))?;
+        RunStats::parse(&text).map_err(|why| format!("Invalid statistics in {}: {}", filename, why))
+    }
+
+    pub fn write_to_file(&self, filename: &str) -> Result<(), String> {
+        std::fs::write(filename, self.to_json())
+            .map_err(|why| format!("Could not write {}: {}", filename, why))
+    }
+}
+
+// Quote a string for JSON
+pub(crate) fn quote(string: &str) -> String {
+    let mut quoted: String = String::from("\"");
+    for c in string.chars() {
+        match c {
+            '"' => quoted += "\\\"",
+            '\\' => quoted += "\\\\",
+            c if (c as u32) < 0x20 => quoted += &format!("\\u{:04x}", c as u32),
+            c => quoted.push(c)
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+// Parser of the subset of JSON used by the statistics: objects, strings
+// and unsigned integers
+struct JsonParser<'a> {
+    text: &'a [u8],
+    pos: usize
+}
+
+impl JsonParser<'_> {
+    fn error(&self, message: &str) -> String {
+        format!("{} at byte {}", message, self.pos)
+    }
+
+    fn skip_whitespace(&mut self) {
+        while self.text.get(self.pos).is_some_and(|c| c.is_ascii_whitespace()) {
+            self.pos += 1;
+        }
+    }
+
+    fn expect(&mut self, expected: u8) -> Result<(), String> {
+        self.skip_whitespace();
+        if self.text.get(self.pos) != Some(&expected) {
+            return Err(self.error(&format!("expected '{}'", expected as char)));
+        }
+        self.pos += 1;
+        Ok(())
+    }
+
+    // Parse an object, calling member for the value of each key
+    fn object<F>(&mut self, mut member: F) -> Result<(), String>
+    where F: FnMut(&mut Self, String) -> Result<(), String> {
+        self.expect(b'{')?;
+        self.skip_whitespace();
+        if self.text.get(self.pos) == Some(&b'}') {
+            self.pos += 1;
+            return Ok(());
+        }
+        loop {
+            let key: String = self.string()?;
+            self.expect(b':')?;
+            member(self, key)?;
+            self.skip_whitespace();
+            match self.text.get(self.pos) {
+                Some(b',') => self.pos += 1,
+                Some(b'}') => { self.pos += 1; return Ok(()) },
+                _ => return Err(self.error("expected ',' or '}'"))
+            }
+        }
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        self.expect(b'"')?;
+        let mut string: Vec<u8> = Vec::new();
+        loop {
+            match self.text.get(self.pos) {
+                Some(b'"') => break,
+                Some(b'\\') => {
+                    let escaped: u8 = *self.text.get(self.pos + 1).ok_or(self.error("unterminated string"))?;
+                    self.pos += 2;
+                    match escaped {
+                        b'"' | b'\\' | b'/' => string.push(escaped),
+                        b'n' => string.push(b'\n'),
+                        b't' => string.push(b'\t'),
+                        b'u' => {
+                            let code: u32 = self.text.get(self.pos..self.pos + 4)
+                                .and_then(|hex| u32::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok())
+                                .ok_or(self.error("invalid escape"))?;
+                            let c: char = char::from_u32(code).ok_or(self.error("invalid escape"))?;
+                            string.extend_from_slice(c.to_string().as_bytes());
+                            self.pos += 4;
+                            continue;
+                        },
+                        _ => return Err(self.error("invalid escape"))
+                    }
+                },
+                Some(c) => { string.push(*c); self.pos += 1 },
+                None => return Err(self.error("unterminated string"))
+            }
+        }
+        self.pos += 1;
+        String::from_utf8(string).map_err(|_| self.error("invalid UTF-8 in string"))
+    }
+
+    fn number(&mut self) -> Result<u64, String> {
+        self.skip_whitespace();
+        let start: usize = self.pos;
+        while self.text.get(self.pos).is_some_and(|c| c.is_ascii_digit()) {
+            self.pos += 1;
+        }
+        std::str::from_utf8(&self.text[start..self.pos]).ok()
+            .and_then(|digits| digits.parse::<u64>().ok())
+            .ok_or(self.error("expected an unsigned integer"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::stats::{Change, RunStats};
+
+    #[test]
+    fn json_test() {
+        let mut stats: RunStats = RunStats::new();
+        stats.set("instructions", "total", 1200);
+        stats.set("traps", "Environment call from M-mode", 3);
+        stats.set("traps", "odd \"name\"\\", 1);
+        let parsed: RunStats = RunStats::parse(&stats.to_json()).unwrap();
+        assert_eq!(parsed, stats);
+        assert_eq!(parsed.get("instructions", "total"), Some(1200));
+        assert_eq!(RunStats::parse("{}"), Ok(RunStats::new()));
+        assert!(RunStats::parse("{\"a\": {\"b\": -1}}").is_err());
+        assert!(RunStats::parse("{\"a\": {\"b\": 1}").is_err());
+        assert!(RunStats::parse("{\"a\": {\"b\": 1}} x").is_err());
+    }
+
+    #[test]
+    fn compare_test() {
+        let mut before: RunStats = RunStats::new();
+        before.set("instructions", "total", 1000);
+        before.set("instructions", "idle", 100);
+        before.set("traps", "Breakpoint", 2);
+        let mut after: RunStats = RunStats::new();
+        after.set("instructions", "total", 800);
+        after.set("instructions", "idle", 102);
+        after.set("traps", "Illegal instruction", 1);
+
+        let changes: Vec<Change> = before.compare(&after);
+        let names: Vec<&str> = changes.iter().map(|change| change.name.as_str()).collect();
+        assert_eq!(names, ["idle", "total", "Breakpoint", "Illegal instruction"]);
+        assert_eq!(changes[1].percent(), Some(-20.0));
+        assert!(changes[1].is_significant(5.0));
+        assert!(!changes[0].is_significant(5.0));
+        assert!(changes[2].is_significant(5.0) && changes[2].after.is_none());
+        assert!(changes[3].is_significant(5.0) && changes[3].before.is_none());
+    }
+}
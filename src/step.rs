@@ -0,0 +1,109 @@
+use crate::cpu::{CSRegIndex, Instruction, RegIndex};
+use crate::memory::AccessSize;
+use crate::trap::Trap;
+
+/// A register written by an instruction
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct RegisterWrite {
+    pub register: RegIndex,
+    pub old: u64,
+    pub new: u64
+}
+
+/// A memory location written by an instruction
+#[derive(Clone, Copy, Debug)]
+pub struct MemoryWrite {
+    pub address: u64,
+    pub size: AccessSize,
+    // None if the location could not be read before the write
+    pub old: Option<u64>,
+    pub new: u64
+}
+
+/// A CSR whose value changed, either because of the instruction or
+/// because of the trap it raised
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct CsrWrite {
+    pub csr: CSRegIndex,
+    pub old: u64,
+    pub new: u64
+}
+
+/// Everything that happened while executing a single instruction, so that
+/// external tools can follow the execution through the library API alone
+#[derive(Clone, Debug)]
+pub struct StepReport {
+    // Address of the instruction
+    pub pc: u64,
+    // Raw instruction, None if it could not be fetched or nothing was executed
+    pub instruction: Option<Instruction>,
+    // Decoded instruction, empty if the instruction could not be decoded
+    pub disassembly: String,
+    pub register_writes: Vec<RegisterWrite>,
+    pub memory_writes: Vec<MemoryWrite>,
+    pub csr_writes: Vec<CsrWrite>,
+    // Trap raised by the instruction, the writes of an instruction that
+    // raised a trap are not reported since it did not retire
+    pub trap: Option<Trap>,
+    // Address of the next instruction to be executed
+    pub next_pc: u64
+}
+
+impl StepReport {
+    /// Create an empty report for the instruction at the given address
+    pub fn new(pc: u64) -> StepReport {
+        StepReport {
+            pc,
+            instruction: None,
+            disassembly: String::new(),
+            register_writes: Vec::new(),
+            memory_writes: Vec::new(),
+            csr_writes: Vec::new(),
+            trap: None,
+            next_pc: pc
+        }
+    }
+
+    /// Check if the instruction was executed without raising a trap
+    pub fn retired(&self) -> bool {
+        self.instruction.is_some() && self.trap.is_none()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cpu::Cpu;
+    use crate::memory::{AccessSize, Memory};
+    use crate::step::{RegisterWrite, StepReport};
+    use crate::trap::Trap;
+
+    #[test]
+    fn step_with_state_test() {
+        let mut cpu: Cpu = Cpu::new(Some(Memory::DRAM_DEFAULT_SIZE));
+        // addi a0, zero, 5; sd a0, 8(sp); illegal instruction
+        let program: [u32; 3] = [0x00500513, 0x00a13423, 0xffffffff];
+        let text: Vec<u8> = program.iter().flat_map(|instr| instr.to_le_bytes()).collect();
+        cpu.set_read_only_segment(0x0);
+        cpu.set_read_write_segment(0x1000);
+        cpu.store_from_buffer(&text, 0x0).unwrap();
+        cpu.set_stack_pointer(0x1000);
+
+        let report: StepReport = cpu.step_with_state();
+        assert!(report.retired());
+        assert_eq!(report.register_writes, vec![RegisterWrite { register: 10, old: 0, new: 5 }]);
+        assert_eq!(report.next_pc, 0x4);
+
+        let report: StepReport = cpu.step_with_state();
+        assert_eq!(report.memory_writes.len(), 1);
+        assert_eq!(report.memory_writes[0].address, 0x1008);
+        assert_eq!(report.memory_writes[0].size, AccessSize::DOUBLEWORD);
+        assert_eq!(report.memory_writes[0].new, 5);
+        assert!(report.register_writes.is_empty());
+
+        let report: StepReport = cpu.step_with_state();
+        assert_eq!(report.trap, Some(Trap::IllegalInstruction(0xffffffff)));
+        assert!(!report.retired());
+        // Nothing is executed once the program is over
+        assert_eq!(cpu.step_with_state().instruction, None);
+    }
+}
@@ -0,0 +1,295 @@
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+use crate::trap::Trap;
+use crate::expr::parse_number;
+
+/// A named address in the guest address space
+#[derive(Clone)]
+pub struct Symbol {
+    pub address: u64,
+    pub name: String
+}
+
+/// A named range of the guest address space, e.g. an ELF section or the stack
+#[derive(Clone)]
+pub struct Region {
+    pub start: u64,
+    pub end: u64,
+    pub name: String
+}
+
+/// Table of symbols used to translate raw addresses into something
+/// readable (e.g. main+0x1c) in debug and trace output.
+/// Symbols are always kept sorted by address so that lookups can be done
+/// with a binary search. The map also knows the regions of memory (the
+/// sections of the executable and the stack), so that diagnostics can
+/// say where an address is (e.g. .bss: counter+0x4)
+#[derive(Clone)]
+pub struct SymbolMap {
+    symbols: Vec<Symbol>,
+    regions: Vec<Region>
+}
+
+impl Default for SymbolMap {
+    fn default() -> SymbolMap {
+        SymbolMap::new()
+    }
+}
+
+impl SymbolMap {
+
+    /// Create an empty symbol map
+    pub fn new() -> SymbolMap {
+        SymbolMap { symbols: Vec::new(), regions: Vec::new() }
+    }
+
+    /// Read a symbol file from disk. Both the output of nm
+    /// ("0000000000010078 T main") and GNU ld map files
+    /// ("0x0000000000010078    main") are accepted
+    pub fn from_file(filename: &str) -> Result<SymbolMap, String> {
+        let filepath: &Path = Path::new(filename);
+        let display = filepath.display();
+        let mut contents: String = String::new();
+
+        let mut file = match File::open(filepath) {
+            Err(why) => return Err(format!("Could not open {}: {}", display, why)),
+            Ok(file) => file,
+        };
+
+        if let Err(why) = file.read_to_string(&mut contents) {
+            return Err(format!("Could not read {}: {}", display, why));
+        }
+
+        let symbol_map: SymbolMap = SymbolMap::parse(&contents);
+        if symbol_map.is_empty() {
+            return Err(format!("No symbols found in {}", display));
+        }
+        Ok(symbol_map)
+    }
+
+    /// Parse the textual content of a symbol file. Lines that cannot be
+    /// interpreted as a symbol definition are silently skipped, since map
+    /// files are full of section and archive information we do not need
+    pub fn parse(contents: &str) -> SymbolMap {
+        let mut symbol_map: SymbolMap = SymbolMap::new();
+        for line in contents.lines() {
+            let tokens: Vec<&str> = line.split_whitespace().collect();
+            match tokens.as_slice() {
+                // nm format: <address> <type> <name>
+                [address, kind, name] if kind.len() == 1 => {
+                    // Skip debugging and undefined symbols
+                    if *kind == "N" || *kind == "U" || !SymbolMap::is_valid_name(name) {
+                        continue;
+                    }
+                    if let Some(address) = SymbolMap::parse_address(address) {
+                        symbol_map.insert(address, name);
+                    }
+                },
+                // GNU ld map format: 0x<address> <name>
+                [address, name] if address.starts_with("0x") => {
+                    if !SymbolMap::is_valid_name(name) {
+                        continue;
+                    }
+                    if let Some(address) = SymbolMap::parse_address(address) {
+                        symbol_map.insert(address, name);
+                    }
+                },
+                _ => ()
+            }
+        }
+        symbol_map
+    }
+
+    /// Add a symbol to the map, keeping the vector ordered by address
+    pub fn insert(&mut self, address: u64, name: &str) {
+        let position: usize = self.symbols.partition_point(|sym| sym.address <= address);
+        self.symbols.insert(position, Symbol { address, name: name.to_string() });
+    }
+
+    /// Find the symbol that contains a certain address (i.e. the closest one
+    /// that starts at or before the address) and return its name together
+    /// with the offset of the address from the beginning of the symbol
+    pub fn lookup(&self, address: u64) -> Option<(&str, u64)> {
+        let position: usize = self.symbols.partition_point(|sym| sym.address <= address);
+        if position == 0 {
+            return None;
+        }
+        let symbol: &Symbol = &self.symbols[position - 1];
+        Some((symbol.name.as_str(), address - symbol.address))
+    }
+
+    /// Return a string in the form "name+0xoffset" for the given address,
+    /// if the address can be attributed to any symbol
+    pub fn symbolize(&self, address: u64) -> Option<String> {
+        match self.lookup(address) {
+            Some((name, 0)) => Some(name.to_string()),
+            Some((name, offset)) => Some(format!("{}+0x{:x}", name, offset)),
+            None => None
+        }
+    }
+
+    /// Address of a symbol given its name, the first one if more
+    /// symbols have the same name
+    pub fn address_of(&self, name: &str) -> Option<u64> {
+        self.symbols.iter().find(|sym| sym.name == name).map(|sym| sym.address)
+    }
+
+    /// Resolve a location given as an address (e.g. 0x20048), a symbol
+    /// (counter) or a symbol with an offset (counter+0x4)
+    pub fn resolve(&self, location: &str) -> Result<u64, String> {
+        let location: &str = location.trim();
+        if location.starts_with(|c: char| c.is_ascii_digit()) {
+            return parse_number(location);
+        }
+        let (name, offset): (&str, u64) = match location.split_once('+') {
+            Some((name, offset)) => (name, parse_number(offset)?),
+            None => (location, 0)
+        };
+        match self.address_of(name) {
+            Some(address) => Ok(address.wrapping_add(offset)),
+            None => Err(format!("Unknown symbol {}", name))
+        }
+    }
+
+    /// Replace the symbols with the ones of another map, keeping the regions
+    pub fn replace_symbols(&mut self, other: SymbolMap) {
+        self.symbols = other.symbols;
+    }
+
+    /// Name a range of addresses. Regions are not supposed to overlap,
+    /// if they do the first one added wins
+    pub fn add_region(&mut self, start: u64, size: u64, name: &str) {
+        self.regions.push(Region { start, end: start.saturating_add(size), name: name.to_string() });
+    }
+
+    /// Forget the regions with a name (e.g. to name them again with
+    /// other bounds)
+    pub fn remove_region(&mut self, name: &str) {
+        self.regions.retain(|region| region.name != name);
+    }
+
+    /// Region that contains an address, the smallest one if they nest
+    /// (e.g. the stack guard inside the stack)
+    pub fn region(&self, address: u64) -> Option<&Region> {
+        self.regions.iter()
+            .filter(|region| region.start <= address && address < region.end)
+            .min_by_key(|region| region.end - region.start)
+    }
+
+    /// Describe where an address is, for diagnostics: the region and the
+    /// symbol containing it ("0x2004c" is ".bss: counter+0x4"), just the
+    /// region ("stack") or just the symbol if no region is known at all.
+    /// Symbols outside the region of the address are not used, and
+    /// addresses outside every known region are not described
+    pub fn describe(&self, address: u64) -> Option<String> {
+        let symbol: Option<String> = self.symbolize(address);
+        match self.region(address) {
+            Some(region) => match self.lookup(address) {
+                Some((_, offset)) if address - offset >= region.start =>
+                    Some(format!("{}: {}", region.name, symbol.unwrap_or_default())),
+                _ => Some(region.name.clone())
+            },
+            None if self.regions.is_empty() => symbol,
+            None => None
+        }
+    }
+
+    /// Where an address is, as " <location>" to be appended to a message,
+    /// or an empty string if nothing is known about the address
+    pub fn annotate(&self, address: u64) -> String {
+        match self.describe(address) {
+            Some(location) => format!(" <{}>", location),
+            None => String::new()
+        }
+    }
+
+    /// Describe a trap, with the location of the address it is about
+    pub fn describe_trap(&self, trap: &Trap) -> String {
+        match trap.address() {
+            Some(address) => format!("{}{}", trap, self.annotate(address)),
+            None => trap.to_string()
+        }
+    }
+
+    /// Names of the symbols, in increasing order of address
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.symbols.iter().map(|symbol| symbol.name.as_str())
+    }
+
+    /// Number of symbols in the map
+    pub fn len(&self) -> usize {
+        self.symbols.len()
+    }
+
+    /// Check if the map contains no symbols
+    pub fn is_empty(&self) -> bool {
+        self.symbols.is_empty()
+    }
+
+    fn parse_address(token: &str) -> Option<u64> {
+        let digits: &str = token.trim_start_matches("0x");
+        u64::from_str_radix(digits, 16).ok()
+    }
+
+    fn is_valid_name(name: &str) -> bool {
+        let first_char_ok: bool = name.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_' || c == '.' || c == '$');
+        first_char_ok && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.' || c == '$')
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::symbols::SymbolMap;
+
+    #[test]
+    fn parse_nm_test() {
+        let symbol_map = SymbolMap::parse("0000000000010078 T main\n\
+                                           00000000000100f0 t swap\n\
+                                                            U memcpy\n");
+        assert_eq!(symbol_map.len(), 2);
+        assert_eq!(symbol_map.symbolize(0x10078), Some("main".to_string()));
+        assert_eq!(symbol_map.symbolize(0x10080), Some("main+0x8".to_string()));
+        assert_eq!(symbol_map.symbolize(0x10100), Some("swap+0x10".to_string()));
+        assert_eq!(symbol_map.symbolize(0x10000), None);
+    }
+
+    #[test]
+    fn resolve_test() {
+        let symbol_map = SymbolMap::parse("0000000000010078 T main\n\
+                                           0000000000020048 B counter\n");
+        assert_eq!(symbol_map.resolve("counter"), Ok(0x20048));
+        assert_eq!(symbol_map.resolve("main+0x8"), Ok(0x10080));
+        assert_eq!(symbol_map.resolve("0x20000"), Ok(0x20000));
+        assert_eq!(symbol_map.resolve("16"), Ok(16));
+        assert!(symbol_map.resolve("missing").is_err());
+    }
+
+    #[test]
+    fn parse_map_test() {
+        let symbol_map = SymbolMap::parse(" .text          0x0000000000010078       0x54 /tmp/main.o\n\
+                                           \x20               0x0000000000010078                main\n\
+                                           \x20               0x0000000000011000                . = ALIGN (0x1000)\n");
+        assert_eq!(symbol_map.len(), 1);
+        assert_eq!(symbol_map.lookup(0x1007c), Some(("main", 0x4)));
+    }
+
+    #[test]
+    fn describe_test() {
+        let mut symbol_map = SymbolMap::parse("0000000000010078 T main\n\
+                                               0000000000020048 B counter\n");
+        symbol_map.add_region(0x10000, 0x100, ".text");
+        symbol_map.add_region(0x20040, 0x10, ".bss");
+        symbol_map.add_region(0x30000, 0x1000, "stack");
+        assert_eq!(symbol_map.describe(0x2004c), Some(".bss: counter+0x4".to_string()));
+        assert_eq!(symbol_map.describe(0x20040), Some(".bss".to_string()));
+        assert_eq!(symbol_map.describe(0x10078), Some(".text: main".to_string()));
+        // counter is not on the stack
+        assert_eq!(symbol_map.describe(0x30ffc), Some("stack".to_string()));
+        assert_eq!(symbol_map.describe(0x20100), None);
+        // Without regions the symbols are used for every address
+        let symbols_only = SymbolMap::parse("0000000000020048 B counter\n");
+        assert_eq!(symbols_only.describe(0x20100), Some("counter+0xb8".to_string()));
+        assert_eq!(symbols_only.describe(0x100), None);
+    }
+}
@@ -0,0 +1,478 @@
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
+use crate::cpu::{Cpu, RegIndex};
+use crate::memory::AccessSize;
+use crate::snapshot::{Snapshot, SnapshotReader, SnapshotWriter};
+
+/// Outcome of an emulated system call
+pub enum SyscallResult {
+    // Value to be returned to the guest in a0
+    Return(u64),
+    // The guest asked to terminate with this exit code
+    Exit(u64)
+}
+
+// Files the guest can refer to through a file descriptor. Host files are
+// shared (and not duplicated) when the emulator is cloned, since &File
+// can be read, written and seeked
+#[derive(Clone)]
+enum GuestFile {
+    Stdin,
+    Stdout,
+    Stderr,
+    Host(Arc<File>)
+}
+
+/// User-mode emulation of the most common Linux/newlib system calls.
+/// When it is enabled, ECALL does not raise a trap: the system call number
+/// is taken from a7 and its arguments from a0-a5, the operation is performed
+/// on the host and the result (or a negated errno) is written back to a0
+#[derive(Clone)]
+pub struct SyscallEmulator {
+    files: HashMap<u64, GuestFile>,
+    next_fd: u64,
+    // Lowest and current value of the program break (end of the heap)
+    heap_start: u64,
+    program_break: u64,
+    // Highest address the heap can grow to
+    heap_limit: u64,
+    // Host directory that is the root of the file system of the guest, if
+    // the guest is confined to it (otherwise it sees the host file system)
+    root: Option<PathBuf>
+}
+
+impl SyscallEmulator {
+    // System call numbers, as defined by the RISC-V Linux ABI
+    const SYS_OPENAT:       u64 = 56;
+    const SYS_CLOSE:        u64 = 57;
+    const SYS_LSEEK:        u64 = 62;
+    const SYS_READ:         u64 = 63;
+    const SYS_WRITE:        u64 = 64;
+    const SYS_NEWFSTATAT:   u64 = 79;
+    const SYS_FSTAT:        u64 = 80;
+    const SYS_EXIT:         u64 = 93;
+    const SYS_EXIT_GROUP:   u64 = 94;
+    const SYS_GETTIMEOFDAY: u64 = 169;
+    const SYS_BRK:          u64 = 214;
+    // Legacy open used by newlib (libgloss)
+    const SYS_OPEN:         u64 = 1024;
+
+    // Error numbers returned (negated) to the guest
+    const ENOENT: i64 = 2;
+    const EIO:    i64 = 5;
+    const EBADF:  i64 = 9;
+    const EACCES: i64 = 13;
+    pub(crate) const EFAULT: i64 = 14;
+    const EINVAL: i64 = 22;
+    const ESPIPE: i64 = 29;
+    const ENOSYS: i64 = 38;
+
+    // Argument and return value registers
+    const A0: RegIndex = 10;
+    const A7: RegIndex = 17;
+
+    // Largest buffer the data of a read goes through at once
+    pub(crate) const READ_CHUNK: u64 = 0x10000;
+
+    /// Create the emulation layer. The heap starts right after the
+    /// program data and cannot grow past heap_limit
+    pub fn new(heap_start: u64, heap_limit: u64) -> SyscallEmulator {
+        let mut files: HashMap<u64, GuestFile> = HashMap::new();
+        files.insert(0, GuestFile::Stdin);
+        files.insert(1, GuestFile::Stdout);
+        files.insert(2, GuestFile::Stderr);
+        // Keep the heap 8-byte aligned
+        let heap_start: u64 = (heap_start + 7) & !7;
+        SyscallEmulator {
+            files,
+            next_fd: 3,
+            heap_start,
+            program_break: heap_start,
+            heap_limit,
+            root: None
+        }
+    }
+
+    /// Confine the file system calls of the guest to a host directory,
+    /// which becomes its root: absolute paths start there, relative ones
+    /// too (the working directory of the guest is /), and the guest cannot
+    /// get out of it through .. or symbolic links
+    pub fn set_root(&mut self, root: &str) -> Result<(), String> {
+        let root: PathBuf = Path::new(root).canonicalize()
+            .map_err(|why| format!("Invalid file system root {}: {}", root, why))?;
+        if !root.is_dir() {
+            return Err(format!("Invalid file system root {}: not a directory", root.display()));
+        }
+        self.root = Some(root);
+        Ok(())
+    }
+
+    // Host path of a path of the guest. Without a root it is the path
+    // itself, relative to the host working directory. With a root, .. is
+    // resolved inside the root and the path is refused (EACCES) if it
+    // leads outside of it through a symbolic link, or through a link that
+    // does not lead anywhere (a file created there could end up outside)
+    fn host_path(&self, path: &str) -> Result<PathBuf, i64> {
+        let root: &PathBuf = match self.root.as_ref() {
+            Some(root) => root,
+            None => return Ok(PathBuf::from(path))
+        };
+        let mut components: Vec<&std::ffi::OsStr> = Vec::new();
+        for component in Path::new(path).components() {
+            match component {
+                Component::Normal(name) => components.push(name),
+                Component::ParentDir => { components.pop(); },
+                _ => ()
+            }
+        }
+        let host_path: PathBuf = components.iter().fold(root.clone(), |path, name| path.join(name));
+        let resolved: PathBuf = match host_path.canonicalize() {
+            Ok(resolved) => resolved,
+            Err(_) if host_path.symlink_metadata().is_ok() => return Err(SyscallEmulator::EACCES),
+            // A file that does not exist yet: its directory must be inside
+            Err(_) => match host_path.parent().map(Path::canonicalize) {
+                Some(Ok(parent)) => parent,
+                _ => return Ok(host_path)
+            }
+        };
+        if resolved.starts_with(root) {
+            Ok(host_path)
+        } else {
+            Err(SyscallEmulator::EACCES)
+        }
+    }
+
+    /// Perform the system call requested by the guest
+    pub fn handle(&mut self, cpu: &mut Cpu) -> SyscallResult {
+        let number: u64 = cpu.read_reg(SyscallEmulator::A7);
+        let args: [u64; 6] = core::array::from_fn(|i| cpu.read_reg(SyscallEmulator::A0 + i as RegIndex));
+        let result: Result<u64, i64> = match number {
+            SyscallEmulator::SYS_EXIT | SyscallEmulator::SYS_EXIT_GROUP => return SyscallResult::Exit(args[0]),
+            SyscallEmulator::SYS_READ => self.read(cpu, args[0], args[1], args[2]),
+            SyscallEmulator::SYS_WRITE => self.write(cpu, args[0], args[1], args[2]),
+            SyscallEmulator::SYS_OPENAT => self.open(cpu, args[1], args[2], false),
+            SyscallEmulator::SYS_OPEN => self.open(cpu, args[0], args[1], true),
+            SyscallEmulator::SYS_CLOSE => self.close(args[0]),
+            SyscallEmulator::SYS_LSEEK => self.lseek(args[0], args[1] as i64, args[2]),
+            SyscallEmulator::SYS_FSTAT => self.fstat(cpu, args[0], args[1]),
+            SyscallEmulator::SYS_NEWFSTATAT => self.stat(cpu, args[1], args[2]),
+            SyscallEmulator::SYS_GETTIMEOFDAY => SyscallEmulator::gettimeofday(cpu, args[0]),
+            SyscallEmulator::SYS_BRK => Ok(self.brk(args[0])),
+            _ => Err(SyscallEmulator::ENOSYS)
+        };
+        match result {
+            Ok(value) => SyscallResult::Return(value),
+            Err(errno) => SyscallResult::Return((-errno) as u64)
+        }
+    }
+
+    // The guest chooses count: the data goes through a buffer of at most
+    // READ_CHUNK bytes, and the read stops at the end of the memory the
+    // buffer is in (a short read)
+    fn read(&mut self, cpu: &mut Cpu, fd: u64, buf: u64, count: u64) -> Result<u64, i64> {
+        let count: u64 = match (count, cpu.mapped_bytes(buf)) {
+            (0, _) => return Ok(0),
+            (_, 0) => return Err(SyscallEmulator::EFAULT),
+            (count, mapped) => count.min(mapped)
+        };
+        let mut data: Vec<u8> = vec![0; count.min(SyscallEmulator::READ_CHUNK) as usize];
+        let mut total: u64 = 0;
+        while total < count {
+            let len: usize = (count - total).min(SyscallEmulator::READ_CHUNK) as usize;
+            let (read, host_file) = match self.files.get(&fd) {
+                Some(GuestFile::Stdin) => (cpu.read_stdin(&mut data[..len]), false),
+                Some(GuestFile::Host(file)) => ((&**file).read(&mut data[..len]), true),
+                _ => return Err(SyscallEmulator::EBADF)
+            };
+            let read: usize = match read {
+                Ok(read) => read,
+                Err(_) if total > 0 => break,
+                Err(err) => return Err(SyscallEmulator::errno(err))
+            };
+            SyscallEmulator::store_buffer(cpu, buf.wrapping_add(total), &data[..read])?;
+            total += read as u64;
+            // The standard input is read once, as it might block for more
+            if read < len || !host_file {
+                break;
+            }
+        }
+        Ok(total)
+    }
+
+    fn write(&mut self, cpu: &mut Cpu, fd: u64, buf: u64, count: u64) -> Result<u64, i64> {
+        let data: Vec<u8> = SyscallEmulator::load_buffer(cpu, buf, count)?;
+        let written: usize = match self.files.get(&fd) {
+            Some(GuestFile::Stdout) => cpu.write_stdout(&data).map(|_| data.len()),
+            Some(GuestFile::Stderr) => cpu.write_stderr(&data).map(|_| data.len()),
+            Some(GuestFile::Host(file)) => (&**file).write(&data),
+            _ => return Err(SyscallEmulator::EBADF)
+        }.map_err(SyscallEmulator::errno)?;
+        Ok(written as u64)
+    }
+
+    // Open a host file. openat uses the Linux flag values while the legacy
+    // open of newlib uses the newlib ones. Directory file descriptors of
+    // openat are ignored: relative paths are relative to the host working
+    // directory, or to the root of the guest if it has one
+    fn open(&mut self, cpu: &mut Cpu, path: u64, flags: u64, newlib_flags: bool) -> Result<u64, i64> {
+        let path: PathBuf = self.host_path(&SyscallEmulator::load_string(cpu, path)?)?;
+        let (create, truncate, append, exclusive): (u64, u64, u64, u64) = if newlib_flags {
+            (0x200, 0x400, 0x8, 0x800)
+        } else {
+            (0x40, 0x200, 0x400, 0x80)
+        };
+        let mut options: OpenOptions = OpenOptions::new();
+        match flags & 0x3 {
+            0 => options.read(true),
+            1 => options.write(true),
+            2 => options.read(true).write(true),
+            _ => return Err(SyscallEmulator::EINVAL)
+        };
+        options.append(flags & append != 0).truncate(flags & truncate != 0);
+        if flags & create != 0 {
+            if flags & exclusive != 0 {
+                options.create_new(true);
+            } else {
+                options.create(true);
+            }
+        }
+        let file: File = options.open(&path).map_err(SyscallEmulator::errno)?;
+        let fd: u64 = self.next_fd;
+        self.next_fd += 1;
+        self.files.insert(fd, GuestFile::Host(Arc::new(file)));
+        Ok(fd)
+    }
+
+    fn close(&mut self, fd: u64) -> Result<u64, i64> {
+        match self.files.remove(&fd) {
+            Some(_) => Ok(0),
+            None => Err(SyscallEmulator::EBADF)
+        }
+    }
+
+    fn lseek(&mut self, fd: u64, offset: i64, whence: u64) -> Result<u64, i64> {
+        let position: SeekFrom = match whence {
+            0 => SeekFrom::Start(offset as u64),
+            1 => SeekFrom::Current(offset),
+            2 => SeekFrom::End(offset),
+            _ => return Err(SyscallEmulator::EINVAL)
+        };
+        match self.files.get(&fd) {
+            Some(GuestFile::Host(file)) => (&**file).seek(position).map_err(SyscallEmulator::errno),
+            // The standard streams are not seekable
+            Some(_) => Err(SyscallEmulator::ESPIPE),
+            None => Err(SyscallEmulator::EBADF)
+        }
+    }
+
+    fn fstat(&mut self, cpu: &mut Cpu, fd: u64, statbuf: u64) -> Result<u64, i64> {
+        const S_IFCHR: u64 = 0o020000;
+        let (mode, size): (u64, u64) = match self.files.get(&fd) {
+            Some(GuestFile::Host(file)) => SyscallEmulator::file_mode(&file.metadata().map_err(SyscallEmulator::errno)?),
+            Some(_) => (S_IFCHR | 0o620, 0),
+            None => return Err(SyscallEmulator::EBADF)
+        };
+        SyscallEmulator::store_stat(cpu, statbuf, mode, size)
+    }
+
+    // newfstatat of a path (the directory file descriptor is ignored, as in open)
+    fn stat(&mut self, cpu: &mut Cpu, path: u64, statbuf: u64) -> Result<u64, i64> {
+        let path: PathBuf = self.host_path(&SyscallEmulator::load_string(cpu, path)?)?;
+        let (mode, size): (u64, u64) = SyscallEmulator::file_mode(&std::fs::metadata(path).map_err(SyscallEmulator::errno)?);
+        SyscallEmulator::store_stat(cpu, statbuf, mode, size)
+    }
+
+    // Type and permissions, and size of a host file
+    fn file_mode(metadata: &std::fs::Metadata) -> (u64, u64) {
+        const S_IFDIR: u64 = 0o040000;
+        const S_IFREG: u64 = 0o100000;
+        let kind: u64 = if metadata.is_dir() { S_IFDIR } else { S_IFREG };
+        (kind | 0o644, metadata.len())
+    }
+
+    // Fill a struct stat with the layout used by RISC-V Linux. Only the file
+    // type, size and block size are meaningful: newlib uses them to decide
+    // whether a stream is a terminal (line buffered) and how big its buffer is
+    fn store_stat(cpu: &mut Cpu, statbuf: u64, mode: u64, size: u64) -> Result<u64, i64> {
+        let mut stat: [u8; 128] = [0; 128];
+        stat[16..20].copy_from_slice(&(mode as u32).to_le_bytes());
+        // st_nlink
+        stat[20..24].copy_from_slice(&1u32.to_le_bytes());
+        stat[48..56].copy_from_slice(&size.to_le_bytes());
+        // st_blksize
+        stat[56..60].copy_from_slice(&4096u32.to_le_bytes());
+        stat[64..72].copy_from_slice(&size.div_ceil(512).to_le_bytes());
+        SyscallEmulator::store_buffer(cpu, statbuf, &stat)?;
+        Ok(0)
+    }
+
+    fn gettimeofday(cpu: &mut Cpu, timeval: u64) -> Result<u64, i64> {
+        let now = cpu.read_rtc_time();
+        let mut buffer: [u8; 16] = [0; 16];
+        buffer[0..8].copy_from_slice(&now.as_secs().to_le_bytes());
+        buffer[8..16].copy_from_slice(&(now.subsec_micros() as u64).to_le_bytes());
+        SyscallEmulator::store_buffer(cpu, timeval, &buffer)?;
+        Ok(0)
+    }
+
+    // Move the program break. As in Linux, the current break is returned
+    // when the requested one is not valid, so brk(0) can be used to query it
+    fn brk(&mut self, address: u64) -> u64 {
+        if address >= self.heap_start && address <= self.heap_limit {
+            self.program_break = address;
+        }
+        self.program_break
+    }
+
+    pub(crate) fn load_buffer(cpu: &Cpu, address: u64, len: u64) -> Result<Vec<u8>, i64> {
+        (0..len).map(|i| {
+            cpu.peek(address.wrapping_add(i), AccessSize::BYTE)
+               .map(|byte| byte as u8)
+               .map_err(|_| SyscallEmulator::EFAULT)
+        }).collect()
+    }
+
+    pub(crate) fn store_buffer(cpu: &mut Cpu, address: u64, data: &[u8]) -> Result<(), i64> {
+        for (i, byte) in data.iter().enumerate() {
+            cpu.store(*byte as u64, address.wrapping_add(i as u64), AccessSize::BYTE)
+               .map_err(|_| SyscallEmulator::EFAULT)?;
+        }
+        Ok(())
+    }
+
+    // Read a NUL-terminated string from guest memory
+    pub(crate) fn load_string(cpu: &Cpu, address: u64) -> Result<String, i64> {
+        let mut bytes: Vec<u8> = Vec::new();
+        loop {
+            let byte: u8 = cpu.peek(address.wrapping_add(bytes.len() as u64), AccessSize::BYTE)
+                              .map_err(|_| SyscallEmulator::EFAULT)? as u8;
+            if byte == 0 {
+                break;
+            }
+            bytes.push(byte);
+        }
+        String::from_utf8(bytes).map_err(|_| SyscallEmulator::ENOENT)
+    }
+
+    // Convert a host error into the errno returned to the guest
+    pub(crate) fn errno(err: std::io::Error) -> i64 {
+        err.raw_os_error().map(|errno| errno as i64).unwrap_or(SyscallEmulator::EIO)
+    }
+}
+
+// Only the program break is saved: the files opened by the program
+// belong to the host process and cannot be reopened from a snapshot
+impl Snapshot for SyscallEmulator {
+    fn save(&self, writer: &mut SnapshotWriter) {
+        writer.put_u64(self.program_break);
+    }
+
+    fn restore(&mut self, reader: &mut SnapshotReader) -> Result<(), String> {
+        self.program_break = reader.get_u64()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+    use crate::cpu::Cpu;
+    use crate::memory::{AccessSize, Memory};
+    use crate::syscall::{SyscallEmulator, SyscallResult};
+
+    #[test]
+    fn brk_and_exit_test() {
+        let mut cpu: Cpu = Cpu::new(Some(Memory::DRAM_DEFAULT_SIZE));
+        let mut syscalls: SyscallEmulator = SyscallEmulator::new(0x101, 0x1000);
+        // brk(0) returns the current (aligned) break
+        cpu.write_reg(17, 214);
+        cpu.write_reg(10, 0);
+        assert!(matches!(syscalls.handle(&mut cpu), SyscallResult::Return(0x108)));
+        cpu.write_reg(10, 0x800);
+        assert!(matches!(syscalls.handle(&mut cpu), SyscallResult::Return(0x800)));
+        // Growing past the limit fails and the break is unchanged
+        cpu.write_reg(10, 0x2000);
+        assert!(matches!(syscalls.handle(&mut cpu), SyscallResult::Return(0x800)));
+
+        cpu.write_reg(17, 93);
+        cpu.write_reg(10, 3);
+        assert!(matches!(syscalls.handle(&mut cpu), SyscallResult::Exit(3)));
+    }
+
+    #[test]
+    fn errors_test() {
+        let mut cpu: Cpu = Cpu::new(Some(Memory::DRAM_DEFAULT_SIZE));
+        cpu.set_read_write_segment(0x0);
+        let mut syscalls: SyscallEmulator = SyscallEmulator::new(0x0, 0x0);
+        // close() of a file that was never opened
+        cpu.write_reg(17, 57);
+        cpu.write_reg(10, 42);
+        assert!(matches!(syscalls.handle(&mut cpu), SyscallResult::Return(value) if value as i64 == -9));
+        // Unknown system call
+        cpu.write_reg(17, 4242);
+        assert!(matches!(syscalls.handle(&mut cpu), SyscallResult::Return(value) if value as i64 == -38));
+        // fstat() of stdout reports a character device
+        cpu.write_reg(17, 80);
+        cpu.write_reg(10, 1);
+        cpu.write_reg(11, 0x100);
+        assert!(matches!(syscalls.handle(&mut cpu), SyscallResult::Return(0)));
+        assert_eq!(cpu.load(0x110, AccessSize::WORD).unwrap() & 0o170000, 0o020000);
+    }
+
+    #[test]
+    fn fs_root_test() {
+        let base: PathBuf = std::env::temp_dir().join(format!("riviera-fsroot-{}", std::process::id()));
+        let root: PathBuf = base.join("root");
+        std::fs::create_dir_all(root.join("etc")).unwrap();
+        std::fs::write(root.join("etc/motd"), "hello").unwrap();
+        std::fs::write(base.join("secret"), "secret").unwrap();
+        let mut cpu: Cpu = Cpu::new(Some(Memory::DRAM_DEFAULT_SIZE));
+        cpu.set_read_write_segment(0x0);
+        let mut syscalls: SyscallEmulator = SyscallEmulator::new(0x0, 0x0);
+        assert!(syscalls.set_root(root.join("etc/motd").to_str().unwrap()).is_err());
+        syscalls.set_root(root.to_str().unwrap()).unwrap();
+        let mut stat = |cpu: &mut Cpu, path: &str| -> i64 {
+            cpu.store_from_buffer(format!("{}\0", path).as_bytes(), 0x100).unwrap();
+            cpu.write_reg(17, 79);
+            cpu.write_reg(10, -100i64 as u64);
+            cpu.write_reg(11, 0x100);
+            cpu.write_reg(12, 0x200);
+            match syscalls.handle(cpu) {
+                SyscallResult::Return(value) => value as i64,
+                _ => panic!("newfstatat did not return")
+            }
+        };
+        // Absolute and relative paths start at the root, .. stops there
+        assert_eq!(stat(&mut cpu, "/etc/motd"), 0);
+        assert_eq!(cpu.load(0x230, AccessSize::DOUBLEWORD).unwrap(), 5);
+        assert_eq!(stat(&mut cpu, "etc/../../../etc/motd"), 0);
+        assert_eq!(stat(&mut cpu, "../secret"), -2);
+        // A symbolic link cannot lead outside
+        #[cfg(unix)]
+        {
+            std::os::unix::fs::symlink(base.join("secret"), root.join("escape")).unwrap();
+            assert_eq!(stat(&mut cpu, "/escape"), -13);
+        }
+        // A read as long as the guest wants stops at the end of the file,
+        // or fails if the buffer is not in memory
+        let mut call = |cpu: &mut Cpu, number: u64, args: [u64; 3]| -> i64 {
+            cpu.write_reg(17, number);
+            for (i, arg) in args.iter().enumerate() {
+                cpu.write_reg(10 + i as u8, *arg);
+            }
+            match syscalls.handle(cpu) {
+                SyscallResult::Return(value) => value as i64,
+                _ => panic!("the system call did not return")
+            }
+        };
+        cpu.store_from_buffer(b"/etc/motd\0", 0x100).unwrap();
+        let fd: u64 = call(&mut cpu, 1024, [0x100, 0, 0]) as u64;
+        assert_eq!(call(&mut cpu, 63, [fd, 0xdead_0000_0000, u64::MAX]), -14);
+        assert_eq!(call(&mut cpu, 63, [fd, 0x300, u64::MAX]), 5);
+        assert_eq!(cpu.load(0x300, AccessSize::BYTE).unwrap(), b'h' as u64);
+        std::fs::remove_dir_all(&base).unwrap();
+    }
+}
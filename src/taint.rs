@@ -0,0 +1,281 @@
+use std::collections::HashSet;
+use std::ops::Range;
+use crate::cpu::{Instruction, RegIndex, REG_FILE_SIZE};
+use crate::expr::parse_range;
+use crate::memory::AccessSize;
+
+/// Where tainted data comes from: the characters received by the UART
+/// or a range of memory (e.g. a buffer filled by a device)
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum TaintSource {
+    Uart,
+    Memory(Range<u64>)
+}
+
+impl TaintSource {
+    /// Parse a taint source: "uart" or a range "<start>:<len>"
+    pub fn parse(source: &str) -> Result<TaintSource, String> {
+        match source.trim() {
+            "uart" => Ok(TaintSource::Uart),
+            range => parse_range(range).map(TaintSource::Memory)
+        }
+    }
+}
+
+/// Tainted data that reached a place where it matters
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum TaintEvent {
+    // A jump through a tainted register: the data controls the PC
+    Jump { register: RegIndex, target: u64 },
+    // A store of tainted data into a sink
+    Sink { address: u64, size: AccessSize }
+}
+
+/// Tainted data that reached the PC or a sink, with the instruction that did it
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct TaintReport {
+    pub pc: u64,
+    pub event: TaintEvent
+}
+
+/// Taint engine: the data loaded from the sources is tainted, and the
+/// taint follows the data through the registers (the result of an
+/// instruction is tainted if one of its operands is) and the memory (each
+/// byte stored from a tainted register is). Jumps to a tainted address
+/// and stores of tainted data into the sinks are reported. The floating
+/// point registers and the CSRs are not tracked, and neither are the
+/// writes of the devices and of the emulated system calls
+#[derive(Clone, Default)]
+pub struct TaintTracker {
+    // Loads from these ranges return tainted data
+    sources: Vec<Range<u64>>,
+    sinks: Vec<Range<u64>>,
+    // Tainted registers, one bit each
+    registers: u32,
+    // Tainted bytes of memory
+    memory: HashSet<u64>,
+    reports: Vec<TaintReport>,
+    // Total number of reports, only the first ones are kept
+    count: u64
+}
+
+impl TaintTracker {
+    // Maximum number of reports that are kept
+    const MAX_REPORTS: usize = 64;
+
+    pub fn new() -> TaintTracker {
+        TaintTracker::default()
+    }
+
+    /// Taint the data loaded from a range of addresses
+    pub fn add_source(&mut self, source: Range<u64>) {
+        if !source.is_empty() {
+            self.sources.push(source);
+        }
+    }
+
+    /// Report the stores of tainted data into a range of addresses
+    pub fn add_sink(&mut self, sink: Range<u64>) {
+        if !sink.is_empty() {
+            self.sinks.push(sink);
+        }
+    }
+
+    /// Propagate the taint through the instruction at pc that has just
+    /// been executed, given the registers before its execution
+    pub fn step(&mut self, pc: u64, instr: Instruction, regs: &[u64; REG_FILE_SIZE]) {
+        let opcode: Instruction = instr & 0x7f;
+        let rd: RegIndex = ((instr >> 7) & 0x1f) as RegIndex;
+        let funct3: Instruction = (instr >> 12) & 0x7;
+        let rs1: RegIndex = ((instr >> 15) & 0x1f) as RegIndex;
+        let rs2: RegIndex = ((instr >> 20) & 0x1f) as RegIndex;
+        let i_imm: u64 = ((instr as i32) >> 20) as u64;
+        let s_imm: u64 = ((((instr & 0xfe000000) as i32) >> 20) as u64) | ((instr >> 7) & 0x1f) as u64;
+        match opcode {
+            // LOAD
+            0x03 => {
+                let size: AccessSize = TaintTracker::access_size(funct3);
+                let tainted: bool = self.is_memory_tainted(regs[rs1 as usize].wrapping_add(i_imm), size);
+                self.set_register(rd, tainted);
+            },
+            // STORE
+            0x23 => {
+                let size: AccessSize = TaintTracker::access_size(funct3);
+                let tainted: bool = self.is_register_tainted(rs2);
+                self.store(pc, regs[rs1 as usize].wrapping_add(s_imm), size, tainted);
+            },
+            // STORE-FP: the floating point registers are never tainted
+            0x27 => {
+                let size: AccessSize = TaintTracker::access_size(funct3);
+                self.store(pc, regs[rs1 as usize].wrapping_add(s_imm), size, false);
+            },
+            // OP-IMM and OP-IMM-32
+            0x13 | 0x1b => {
+                let tainted: bool = self.is_register_tainted(rs1);
+                self.set_register(rd, tainted);
+            },
+            // OP and OP-32 (also the multiplications and divisions)
+            0x33 | 0x3b => {
+                let tainted: bool = self.is_register_tainted(rs1) || self.is_register_tainted(rs2);
+                self.set_register(rd, tainted);
+            },
+            // LUI, AUIPC, JAL and the CSR instructions write untainted values
+            0x37 | 0x17 | 0x6f => self.set_register(rd, false),
+            0x73 if funct3 != 0 => self.set_register(rd, false),
+            // JALR
+            0x67 => {
+                if self.is_register_tainted(rs1) {
+                    let target: u64 = regs[rs1 as usize].wrapping_add(i_imm) & !1;
+                    self.report(TaintReport { pc, event: TaintEvent::Jump { register: rs1, target } });
+                }
+                self.set_register(rd, false);
+            },
+            // AMO: the old value goes to rd, the new one depends on it and on rs2
+            0x2f => {
+                let size: AccessSize = TaintTracker::access_size(funct3);
+                let address: u64 = regs[rs1 as usize];
+                let old: bool = self.is_memory_tainted(address, size);
+                let value: bool = self.is_register_tainted(rs2);
+                match instr >> 27 {
+                    // LR
+                    0x02 => self.set_register(rd, old),
+                    // SC: rd tells if it succeeded
+                    0x03 => {
+                        self.store(pc, address, size, value);
+                        self.set_register(rd, false);
+                    },
+                    // AMOSWAP
+                    0x01 => {
+                        self.store(pc, address, size, value);
+                        self.set_register(rd, old);
+                    },
+                    _ => {
+                        self.store(pc, address, size, old || value);
+                        self.set_register(rd, old);
+                    }
+                }
+            },
+            _ => ()
+        }
+    }
+
+    fn access_size(funct3: Instruction) -> AccessSize {
+        match funct3 & 0x3 {
+            0 => AccessSize::BYTE,
+            1 => AccessSize::HALFWORD,
+            2 => AccessSize::WORD,
+            _ => AccessSize::DOUBLEWORD
+        }
+    }
+
+    fn set_register(&mut self, reg: RegIndex, tainted: bool) {
+        if tainted && reg != 0 {
+            self.registers |= 1 << reg;
+        } else {
+            self.registers &= !(1 << reg);
+        }
+    }
+
+    fn store(&mut self, pc: u64, address: u64, size: AccessSize, tainted: bool) {
+        let end: u64 = address.wrapping_add(size.bytes() as u64);
+        for byte in 0..size.bytes() as u64 {
+            if tainted {
+                self.memory.insert(address.wrapping_add(byte));
+            } else {
+                self.memory.remove(&address.wrapping_add(byte));
+            }
+        }
+        if tainted && self.sinks.iter().any(|sink| address < sink.end && end > sink.start) {
+            self.report(TaintReport { pc, event: TaintEvent::Sink { address, size } });
+        }
+    }
+
+    /// Check if a register holds tainted data
+    pub fn is_register_tainted(&self, reg: RegIndex) -> bool {
+        self.registers & (1 << reg) != 0
+    }
+
+    /// Check if any of the bytes of an access is tainted, or comes from a source
+    pub fn is_memory_tainted(&self, address: u64, size: AccessSize) -> bool {
+        let end: u64 = address.wrapping_add(size.bytes() as u64);
+        self.sources.iter().any(|source| address < source.end && end > source.start) ||
+            (0..size.bytes() as u64).any(|byte| self.memory.contains(&address.wrapping_add(byte)))
+    }
+
+    /// Number of tainted bytes in memory (the sources are not counted)
+    pub fn tainted_bytes(&self) -> usize {
+        self.memory.len()
+    }
+
+    /// Tainted data that reached the PC or a sink so far (only the first
+    /// reports are kept)
+    pub fn get_reports(&self) -> &[TaintReport] {
+        &self.reports
+    }
+
+    /// Total number of reports so far
+    pub fn get_count(&self) -> u64 {
+        self.count
+    }
+
+    fn report(&mut self, report: TaintReport) {
+        self.count += 1;
+        if self.reports.len() < TaintTracker::MAX_REPORTS {
+            self.reports.push(report);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::memory::AccessSize;
+    use crate::taint::{TaintEvent, TaintReport, TaintSource, TaintTracker};
+
+    #[test]
+    fn taint_test() {
+        const LBU_A0_0_T0: u32 = 0x0002c503;
+        const ADDI_A1_A0_1: u32 = 0x00150593;
+        const ADD_A2_A1_S0: u32 = 0x00858633;
+        const SD_A2_8_SP: u32 = 0x00c13423;
+        const LD_A3_8_SP: u32 = 0x00813683;
+        const LUI_A1_1: u32 = 0x000015b7;
+        const JALR_RA_0_A3: u32 = 0x000680e7;
+        const SB_A1_0_S0: u32 = 0x00b40023;
+        const SB_A0_0_S0: u32 = 0x00a40023;
+        let mut regs: [u64; 32] = [0; 32];
+        regs[2] = 0x1000;
+        regs[5] = 0x10000000;
+        regs[8] = 0x2000;
+        let mut taint: TaintTracker = TaintTracker::new();
+        taint.add_source(0x10000000..0x10000001);
+        taint.add_sink(0x2000..0x2010);
+
+        taint.step(0x100, LBU_A0_0_T0, &regs);
+        assert!(taint.is_register_tainted(10));
+        taint.step(0x104, ADDI_A1_A0_1, &regs);
+        taint.step(0x108, ADD_A2_A1_S0, &regs);
+        assert!(taint.is_register_tainted(11) && taint.is_register_tainted(12) && !taint.is_register_tainted(8));
+        // Through the stack
+        taint.step(0x10c, SD_A2_8_SP, &regs);
+        assert_eq!(taint.tainted_bytes(), 8);
+        assert!(taint.is_memory_tainted(0x100f, AccessSize::BYTE));
+        taint.step(0x110, LD_A3_8_SP, &regs);
+        assert!(taint.is_register_tainted(13));
+        taint.step(0x114, LUI_A1_1, &regs);
+        assert!(!taint.is_register_tainted(11));
+        regs[13] = 0x4141;
+        taint.step(0x118, JALR_RA_0_A3, &regs);
+        // An untainted store is not reported, a tainted one is
+        taint.step(0x11c, SB_A1_0_S0, &regs);
+        taint.step(0x120, SB_A0_0_S0, &regs);
+        assert_eq!(taint.get_reports(), &[
+            TaintReport { pc: 0x118, event: TaintEvent::Jump { register: 13, target: 0x4140 } },
+            TaintReport { pc: 0x120, event: TaintEvent::Sink { address: 0x2000, size: AccessSize::BYTE } }
+        ]);
+        assert_eq!(taint.get_count(), 2);
+
+        assert_eq!(TaintSource::parse("uart"), Ok(TaintSource::Uart));
+        assert_eq!(TaintSource::parse("0x80001000:64"), Ok(TaintSource::Memory(0x80001000..0x80001040)));
+        assert!(TaintSource::parse("0x80001000").is_err());
+    }
+}
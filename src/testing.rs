@@ -0,0 +1,185 @@
+use crate::device::Device;
+use crate::memory::{AccessSize, MemError};
+
+/// Test harness for a single device: it maps the device on a mock bus
+/// of its own, so that its registers can be read and written and its
+/// state advanced without a CPU or a program. Accesses use offsets inside
+/// the register window and are checked the way the real bus checks them
+/// (misaligned accesses and accesses that the device refuses fail).
+/// The harness also watches the interrupt line of the device after every
+/// access and every cycle, and remembers when its level changed.
+/// It works with the devices of riviera (e.g. the UART and the CLINT),
+/// with devices of crates embedding riviera and with plugins loaded
+/// through `PluginDevice::load`
+pub struct DeviceHarness<D: Device> {
+    device: D,
+    // Number of times the device has been cycled
+    cycles: u64,
+    interrupt: bool,
+    // Cycle at which the interrupt line changed and its new level
+    interrupt_changes: Vec<(u64, bool)>
+}
+
+impl<D: Device> DeviceHarness<D> {
+    pub fn new(device: D) -> DeviceHarness<D> {
+        let interrupt: bool = device.interrupt();
+        DeviceHarness { device, cycles: 0, interrupt, interrupt_changes: Vec::new() }
+    }
+
+    /// Get the device, e.g. to look at state that is not visible through its registers
+    pub fn device(&self) -> &D {
+        &self.device
+    }
+
+    pub fn device_mut(&mut self) -> &mut D {
+        &mut self.device
+    }
+
+    /// Number of cycles executed so far
+    pub fn cycles(&self) -> u64 {
+        self.cycles
+    }
+
+    // Accesses must be aligned and inside the register window
+    fn check(&self, offset: u64, size: AccessSize) -> Result<(), MemError> {
+        if !offset.is_multiple_of(size.bytes() as u64) {
+            Err(MemError::Misaligned(offset))
+        } else if offset >= self.device.size() {
+            Err(MemError::OutOfBounds(offset))
+        } else {
+            Ok(())
+        }
+    }
+
+    // Record a change of the level of the interrupt line
+    fn sample_interrupt(&mut self) {
+        let interrupt: bool = self.device.interrupt();
+        if interrupt != self.interrupt {
+            self.interrupt = interrupt;
+            self.interrupt_changes.push((self.cycles, interrupt));
+        }
+    }
+
+    /// Read a register as the program does
+    pub fn read(&mut self, offset: u64, size: AccessSize) -> Result<u64, MemError> {
+        self.check(offset, size)?;
+        let result: Option<u64> = self.device.read(offset, size);
+        self.sample_interrupt();
+        result.ok_or(MemError::OutOfBounds(offset))
+    }
+
+    /// Read a register without side effects, as the debugger does
+    pub fn peek(&self, offset: u64, size: AccessSize) -> Result<u64, MemError> {
+        self.check(offset, size)?;
+        self.device.peek(offset, size).ok_or(MemError::OutOfBounds(offset))
+    }
+
+    /// Write a register as the program does
+    pub fn write(&mut self, offset: u64, data: u64, size: AccessSize) -> Result<(), MemError> {
+        self.check(offset, size)?;
+        let result: Option<()> = self.device.write(offset, data, size);
+        self.sample_interrupt();
+        result.ok_or(MemError::OutOfBounds(offset))
+    }
+
+    /// Cycle the device once, as the bus does after every instruction
+    pub fn tick(&mut self) {
+        self.device.cycle();
+        self.cycles += 1;
+        self.sample_interrupt();
+    }
+
+    /// Cycle the device count times
+    pub fn tick_n(&mut self, count: u64) {
+        for _ in 0..count {
+            self.tick();
+        }
+    }
+
+    /// Cycle the device until its interrupt line is high, for at most
+    /// max_cycles cycles. It returns the number of cycles it took
+    pub fn tick_until_interrupt(&mut self, max_cycles: u64) -> Option<u64> {
+        for cycle in 0..=max_cycles {
+            if self.interrupt {
+                return Some(cycle);
+            }
+            if cycle < max_cycles {
+                self.tick();
+            }
+        }
+        None
+    }
+
+    /// Current level of the interrupt line
+    pub fn interrupt(&self) -> bool {
+        self.interrupt
+    }
+
+    /// Changes of the interrupt line so far, as (cycle, new level) pairs
+    pub fn interrupt_changes(&self) -> &[(u64, bool)] {
+        &self.interrupt_changes
+    }
+
+    /// Check that reading a register returns the expected value
+    #[track_caller]
+    pub fn assert_read(&mut self, offset: u64, size: AccessSize, expected: u64) {
+        match self.read(offset, size) {
+            Ok(value) => assert!(value == expected, "{}: read of offset 0x{:x} returned 0x{:x} instead of 0x{:x}",
+                                 self.device.name(), offset, value, expected),
+            Err(err) => panic!("{}: read of offset 0x{:x} failed: {:?}", self.device.name(), offset, err)
+        }
+    }
+
+    /// Check that writing a register succeeds
+    #[track_caller]
+    pub fn assert_write(&mut self, offset: u64, data: u64, size: AccessSize) {
+        if let Err(err) = self.write(offset, data, size) {
+            panic!("{}: write of 0x{:x} to offset 0x{:x} failed: {:?}", self.device.name(), data, offset, err);
+        }
+    }
+
+    /// Check the level of the interrupt line
+    #[track_caller]
+    pub fn assert_interrupt(&self, expected: bool) {
+        assert!(self.interrupt == expected, "{}: interrupt line is {} after {} cycles",
+                self.device.name(), if self.interrupt { "high" } else { "low" }, self.cycles);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::clint::Clint;
+    use crate::memory::{AccessSize, MemError};
+    use crate::testing::DeviceHarness;
+    use crate::uart::UART;
+
+    #[test]
+    fn clint_harness_test() {
+        let mut clint: DeviceHarness<Clint> = DeviceHarness::new(Clint::new());
+        clint.assert_write(0x4000, 10, AccessSize::DOUBLEWORD);
+        clint.assert_interrupt(false);
+        assert_eq!(clint.tick_until_interrupt(100), Some(10));
+        clint.assert_read(0xbff8, AccessSize::DOUBLEWORD, 10);
+        // Moving mtimecmp forward clears the interrupt
+        clint.assert_write(0x4000, 20, AccessSize::DOUBLEWORD);
+        clint.assert_interrupt(false);
+        assert_eq!(clint.interrupt_changes(), &[(10, true), (10, false)]);
+        assert_eq!(clint.device().get_mtime(), 10);
+        assert_eq!(clint.read(0x4002, AccessSize::WORD), Err(MemError::Misaligned(0x4002)));
+        assert_eq!(clint.read(0x100, AccessSize::WORD), Err(MemError::OutOfBounds(0x100)));
+        assert_eq!(clint.read(0x10000, AccessSize::WORD), Err(MemError::OutOfBounds(0x10000)));
+    }
+
+    #[test]
+    fn uart_harness_test() {
+        let mut uart: DeviceHarness<UART> = DeviceHarness::new(UART::new());
+        // THR empty interrupt
+        uart.assert_write(0x1, 0x2, AccessSize::BYTE);
+        uart.assert_interrupt(true);
+        uart.assert_read(0x2, AccessSize::BYTE, 0x2);
+        uart.assert_write(0x3, 0x80, AccessSize::BYTE);
+        uart.assert_write(0x0, 0x03, AccessSize::BYTE);
+        assert_eq!(uart.peek(0x0, AccessSize::BYTE), Ok(0x03));
+        assert_eq!(uart.tick_until_interrupt(0), Some(0));
+    }
+}
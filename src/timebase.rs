@@ -0,0 +1,122 @@
+use std::time::Duration;
+use crate::clint::Clint;
+use crate::expr::parse_number;
+
+/// Where the time CSR takes its value from
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum TimeSource {
+    // The guest time (mtime): it follows the executed instructions and
+    // the time scale, and stops when the time is frozen
+    Mtime,
+    // The wall clock of the host, from the first time it is read
+    Host
+}
+
+/// Timebase read through the time CSR (rdtime), ticking at a given
+/// frequency. Derived from mtime, it is mtime converted from the nominal
+/// frequency of the CLINT; derived from the host clock, it counts the real
+/// time elapsed since the program first read it. The CLINT timer always
+/// compares mtime, so with the host clock the timer interrupts do not
+/// follow the time CSR
+#[derive(Clone)]
+pub struct Timebase {
+    source: TimeSource,
+    frequency: u64,
+    // Host time (since the epoch) at which the host timebase was first read
+    host_start: Option<Duration>
+}
+
+impl Default for Timebase {
+    fn default() -> Timebase {
+        Timebase::new(TimeSource::Mtime, Clint::TIMEBASE_FREQUENCY)
+    }
+}
+
+impl Timebase {
+    pub fn new(source: TimeSource, frequency: u64) -> Timebase {
+        Timebase { source, frequency, host_start: None }
+    }
+
+    /// Parse a timebase given as mtime or host, optionally followed by
+    /// :<frequency> in Hz (the frequency of the CLINT by default)
+    pub fn parse(spec: &str) -> Result<Timebase, String> {
+        let (source, frequency) = match spec.trim().split_once(':') {
+            Some((source, frequency)) => (source, Some(frequency)),
+            None => (spec.trim(), None)
+        };
+        let source: TimeSource = match source {
+            "mtime" => TimeSource::Mtime,
+            "host" => TimeSource::Host,
+            source => return Err(format!("Invalid time source {}: expected mtime or host", source))
+        };
+        let frequency: u64 = match frequency {
+            Some(frequency) => parse_number(frequency)?,
+            None => Clint::TIMEBASE_FREQUENCY
+        };
+        if frequency == 0 {
+            return Err("The frequency of the timebase cannot be 0".to_string());
+        }
+        Ok(Timebase::new(source, frequency))
+    }
+
+    pub fn get_source(&self) -> TimeSource {
+        self.source
+    }
+
+    /// Ticks of the time CSR per second (timebase-frequency in the device tree)
+    pub fn get_frequency(&self) -> u64 {
+        self.frequency
+    }
+
+    /// Value of the time CSR when it follows mtime
+    #[inline(always)]
+    pub fn from_mtime(&self, mtime: u64) -> u64 {
+        if self.frequency == Clint::TIMEBASE_FREQUENCY {
+            mtime
+        } else {
+            (mtime as u128 * self.frequency as u128 / Clint::TIMEBASE_FREQUENCY as u128) as u64
+        }
+    }
+
+    /// Value of mtime that corresponds to a value of the time CSR (e.g.
+    /// the next timer event requested by a kernel)
+    pub fn to_mtime(&self, time: u64) -> u64 {
+        let mtime: u128 = time as u128 * Clint::TIMEBASE_FREQUENCY as u128 / self.frequency as u128;
+        mtime.min(u64::MAX as u128) as u64
+    }
+
+    /// Value of the time CSR when it follows the host clock, now being the
+    /// current host time
+    pub fn from_host(&mut self, now: Duration) -> u64 {
+        let start: Duration = *self.host_start.get_or_insert(now);
+        let elapsed: Duration = now.saturating_sub(start);
+        (elapsed.as_nanos() * self.frequency as u128 / 1_000_000_000) as u64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use crate::clint::Clint;
+    use crate::timebase::{TimeSource, Timebase};
+
+    #[test]
+    fn timebase_test() {
+        let timebase: Timebase = Timebase::parse("mtime").unwrap();
+        assert_eq!(timebase.get_frequency(), Clint::TIMEBASE_FREQUENCY);
+        assert_eq!(timebase.from_mtime(1234), 1234);
+
+        // 1 MHz: a tenth of the ticks of mtime
+        let timebase: Timebase = Timebase::parse("mtime:1000000").unwrap();
+        assert_eq!(timebase.from_mtime(Clint::TIMEBASE_FREQUENCY), 1_000_000);
+        assert_eq!(timebase.to_mtime(1_000_000), Clint::TIMEBASE_FREQUENCY);
+
+        let mut timebase: Timebase = Timebase::parse("host:0x1000").unwrap();
+        assert_eq!(timebase.get_source(), TimeSource::Host);
+        assert_eq!(timebase.from_host(Duration::from_secs(100)), 0);
+        assert_eq!(timebase.from_host(Duration::from_millis(102_500)), 0x2800);
+
+        assert!(Timebase::parse("cycle").is_err());
+        assert!(Timebase::parse("host:0").is_err());
+    }
+}
@@ -0,0 +1,268 @@
+use std::collections::VecDeque;
+use std::fmt;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::net::TcpStream;
+use std::sync::{Arc, Mutex};
+use colored::Colorize;
+use crate::symbols::SymbolMap;
+
+/// What a trace record is about
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum TraceKind {
+    // An executed instruction
+    Instruction,
+    // A CSR access
+    Csr,
+    // A trap or an interrupt that was taken
+    Trap,
+    // A load or a store
+    Memory
+}
+
+impl TraceKind {
+    fn tag(&self) -> &'static str {
+        match self {
+            TraceKind::Instruction => "[exec]",
+            TraceKind::Csr => "[csr]",
+            TraceKind::Trap => "[trap]",
+            TraceKind::Memory => "[mem]"
+        }
+    }
+}
+
+/// A single line of trace: the kind of event, the PC of the instruction
+/// that caused it and a description without colors
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct TraceRecord {
+    pub kind: TraceKind,
+    pub pc: u64,
+    pub message: String
+}
+
+impl fmt::Display for TraceRecord {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} pc=0x{:0>16x} {}", self.kind.tag(), self.pc, self.message)
+    }
+}
+
+/// Consumer of trace records. Sinks are shared between the CPU that
+/// produces the records and the code that set them up (e.g. to read
+/// the content of a ring buffer after the run)
+pub trait TraceSink: Send {
+    fn record(&mut self, record: &TraceRecord);
+
+    fn flush(&mut self) {}
+}
+
+pub type SharedSink = Arc<Mutex<dyn TraceSink>>;
+
+/// Print records on the terminal
+pub struct StdoutSink;
+
+impl TraceSink for StdoutSink {
+    fn record(&mut self, record: &TraceRecord) {
+        let tag = match record.kind {
+            TraceKind::Instruction => record.kind.tag().blue(),
+            TraceKind::Csr => record.kind.tag().purple(),
+            TraceKind::Trap => record.kind.tag().red(),
+            TraceKind::Memory => record.kind.tag().cyan()
+        };
+        println!("{} pc=0x{:0>16x} {}", tag, record.pc, record.message);
+    }
+}
+
+/// Write records to a file, one per line
+pub struct FileSink {
+    writer: BufWriter<File>
+}
+
+impl FileSink {
+    pub fn create(filename: &str) -> Result<FileSink, String> {
+        match File::create(filename) {
+            Ok(file) => Ok(FileSink { writer: BufWriter::new(file) }),
+            Err(why) => Err(format!("Could not create trace file {}: {}", filename, why))
+        }
+    }
+}
+
+impl TraceSink for FileSink {
+    fn record(&mut self, record: &TraceRecord) {
+        let _ = writeln!(self.writer, "{}", record);
+    }
+
+    fn flush(&mut self) {
+        let _ = self.writer.flush();
+    }
+}
+
+/// Send records to a TCP listener (e.g. `nc -l 4000`) for live monitoring.
+/// If the connection drops, the following records are discarded
+pub struct TcpSink {
+    writer: Option<BufWriter<TcpStream>>
+}
+
+impl TcpSink {
+    pub fn connect(address: &str) -> Result<TcpSink, String> {
+        match TcpStream::connect(address) {
+            Ok(stream) => Ok(TcpSink { writer: Some(BufWriter::new(stream)) }),
+            Err(why) => Err(format!("Could not connect to trace listener {}: {}", address, why))
+        }
+    }
+}
+
+impl TraceSink for TcpSink {
+    fn record(&mut self, record: &TraceRecord) {
+        if let Some(writer) = self.writer.as_mut() {
+            if writeln!(writer, "{}", record).is_err() {
+                self.writer = None;
+            }
+        }
+    }
+
+    fn flush(&mut self) {
+        if let Some(writer) = self.writer.as_mut() {
+            let _ = writer.flush();
+        }
+    }
+}
+
+/// Keep the last records in memory, to see what happened right before
+/// a crash without paying for writing the whole trace
+pub struct RingSink {
+    capacity: usize,
+    records: VecDeque<TraceRecord>
+}
+
+impl RingSink {
+    pub fn new(capacity: usize) -> RingSink {
+        RingSink { capacity, records: VecDeque::with_capacity(capacity) }
+    }
+
+    /// Records in the ring, from the oldest to the newest
+    pub fn get_records(&self) -> impl Iterator<Item = &TraceRecord> {
+        self.records.iter()
+    }
+}
+
+impl TraceSink for RingSink {
+    fn record(&mut self, record: &TraceRecord) {
+        if self.capacity == 0 {
+            return;
+        }
+        if self.records.len() == self.capacity {
+            self.records.pop_front();
+        }
+        self.records.push_back(record.clone());
+    }
+}
+
+/// Sink selected from the command line: stdout, file:<path>,
+/// tcp:<host>:<port> or ring:<number of records>
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum SinkSpec {
+    Stdout,
+    File(String),
+    Tcp(String),
+    Ring(usize)
+}
+
+impl SinkSpec {
+    pub fn parse(spec: &str) -> Result<SinkSpec, String> {
+        match spec.split_once(':') {
+            None if spec == "stdout" => Ok(SinkSpec::Stdout),
+            Some(("file", path)) if !path.is_empty() => Ok(SinkSpec::File(path.to_string())),
+            Some(("tcp", address)) if !address.is_empty() => Ok(SinkSpec::Tcp(address.to_string())),
+            Some(("ring", capacity)) => match capacity.parse::<usize>() {
+                Ok(capacity) => Ok(SinkSpec::Ring(capacity)),
+                Err(_) => Err(format!("Invalid ring size {}", capacity))
+            },
+            _ => Err(format!("Invalid trace sink {}: expected stdout, file:<path>, tcp:<host>:<port> or ring:<n>", spec))
+        }
+    }
+}
+
+/// Dispatch trace records to any number of sinks
+#[derive(Clone, Default)]
+pub struct Tracer {
+    sinks: Vec<SharedSink>,
+    // Used to say where the PC of each record is
+    symbols: SymbolMap
+}
+
+impl Tracer {
+    pub fn new() -> Tracer {
+        Tracer { sinks: Vec::new(), symbols: SymbolMap::new() }
+    }
+
+    pub fn add_sink(&mut self, sink: SharedSink) {
+        self.sinks.push(sink);
+    }
+
+    /// Set the symbols and regions used to describe the PC of the records
+    pub fn set_symbols(&mut self, symbols: SymbolMap) {
+        self.symbols = symbols;
+    }
+
+    pub fn get_symbols(&self) -> &SymbolMap {
+        &self.symbols
+    }
+
+    /// Check if there is at least a sink, so that records are not even
+    /// built when nobody is going to consume them
+    #[inline(always)]
+    pub fn is_enabled(&self) -> bool {
+        !self.sinks.is_empty()
+    }
+
+    pub fn emit(&self, kind: TraceKind, pc: u64, message: String) {
+        let message: String = match self.symbols.describe(pc) {
+            Some(location) => format!("<{}> {}", location, message),
+            None => message
+        };
+        let record: TraceRecord = TraceRecord { kind, pc, message };
+        for sink in self.sinks.iter() {
+            if let Ok(mut sink) = sink.lock() {
+                sink.record(&record);
+            }
+        }
+    }
+
+    pub fn flush(&self) {
+        for sink in self.sinks.iter() {
+            if let Ok(mut sink) = sink.lock() {
+                sink.flush();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+    use crate::trace::{RingSink, SinkSpec, TraceKind, Tracer};
+
+    #[test]
+    fn sinks_test() {
+        assert_eq!(SinkSpec::parse("ring:2"), Ok(SinkSpec::Ring(2)));
+        assert_eq!(SinkSpec::parse("tcp:localhost:4000"), Ok(SinkSpec::Tcp("localhost:4000".to_string())));
+        assert!(SinkSpec::parse("file:").is_err());
+        assert!(SinkSpec::parse("socket").is_err());
+
+        // The same records reach every sink, rings only keep the last ones
+        let small: Arc<Mutex<RingSink>> = Arc::new(Mutex::new(RingSink::new(2)));
+        let large: Arc<Mutex<RingSink>> = Arc::new(Mutex::new(RingSink::new(8)));
+        let mut tracer: Tracer = Tracer::new();
+        assert!(!tracer.is_enabled());
+        tracer.add_sink(small.clone());
+        tracer.add_sink(large.clone());
+        for pc in 0..3 {
+            tracer.emit(TraceKind::Instruction, pc * 4, format!("instruction {}", pc));
+        }
+        let pcs: Vec<u64> = small.lock().unwrap().get_records().map(|record| record.pc).collect();
+        assert_eq!(pcs, vec![4, 8]);
+        assert_eq!(large.lock().unwrap().get_records().count(), 3);
+        let first: String = large.lock().unwrap().get_records().next().unwrap().to_string();
+        assert_eq!(first, "[exec] pc=0x0000000000000000 instruction 0");
+    }
+}
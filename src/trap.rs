@@ -0,0 +1,280 @@
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::fmt;
+use crate::cpu::Instruction;
+
+/// Synchronous exceptions that can be raised while executing an instruction.
+/// The payload of each variant is the value that ends up in mtval
+/// (faulting address, faulting instruction or pc)
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Trap {
+    InstructionAddressMisaligned(u64),
+    InstructionAccessFault(u64),
+    IllegalInstruction(Instruction),
+    Breakpoint(u64),
+    LoadAddressMisaligned(u64),
+    LoadAccessFault(u64),
+    StoreAddressMisaligned(u64),
+    StoreAccessFault(u64),
+    EnvironmentCallFromUMode,
+    EnvironmentCallFromSMode,
+    EnvironmentCallFromMMode
+}
+
+impl Trap {
+    /// Exception code that is written in mcause when the trap is taken
+    pub fn cause(&self) -> u64 {
+        match self {
+            Trap::InstructionAddressMisaligned(_) => 0,
+            Trap::InstructionAccessFault(_)       => 1,
+            Trap::IllegalInstruction(_)           => 2,
+            Trap::Breakpoint(_)                   => 3,
+            Trap::LoadAddressMisaligned(_)        => 4,
+            Trap::LoadAccessFault(_)              => 5,
+            Trap::StoreAddressMisaligned(_)       => 6,
+            Trap::StoreAccessFault(_)             => 7,
+            Trap::EnvironmentCallFromUMode        => 8,
+            Trap::EnvironmentCallFromSMode        => 9,
+            Trap::EnvironmentCallFromMMode        => 11
+        }
+    }
+
+    /// Name of the trap, without the trap value
+    pub fn description(&self) -> &'static str {
+        match self {
+            Trap::InstructionAddressMisaligned(_) => "instruction address misaligned",
+            Trap::InstructionAccessFault(_)       => "instruction access fault",
+            Trap::IllegalInstruction(_)           => "illegal instruction",
+            Trap::Breakpoint(_)                   => "breakpoint",
+            Trap::LoadAddressMisaligned(_)        => "load address misaligned",
+            Trap::LoadAccessFault(_)              => "load access fault",
+            Trap::StoreAddressMisaligned(_)       => "store address misaligned",
+            Trap::StoreAccessFault(_)             => "store access fault",
+            Trap::EnvironmentCallFromUMode        => "environment call from U-mode",
+            Trap::EnvironmentCallFromSMode        => "environment call from S-mode",
+            Trap::EnvironmentCallFromMMode        => "environment call from M-mode"
+        }
+    }
+
+    /// Trap value that is written in mtval when the trap is taken
+    /// Address the trap is about (the faulting address or the address of
+    /// the breakpoint), None if its value is not an address
+    pub fn address(&self) -> Option<u64> {
+        match *self {
+            Trap::IllegalInstruction(_) | Trap::EnvironmentCallFromUMode | Trap::EnvironmentCallFromSMode |
+            Trap::EnvironmentCallFromMMode => None,
+            _ => Some(self.tval())
+        }
+    }
+
+    pub fn tval(&self) -> u64 {
+        match *self {
+            Trap::InstructionAddressMisaligned(addr) => addr,
+            Trap::InstructionAccessFault(addr)       => addr,
+            Trap::IllegalInstruction(instr)          => instr as u64,
+            Trap::Breakpoint(pc)                     => pc,
+            Trap::LoadAddressMisaligned(addr)        => addr,
+            Trap::LoadAccessFault(addr)              => addr,
+            Trap::StoreAddressMisaligned(addr)       => addr,
+            Trap::StoreAccessFault(addr)             => addr,
+            Trap::EnvironmentCallFromUMode           => 0,
+            Trap::EnvironmentCallFromSMode           => 0,
+            Trap::EnvironmentCallFromMMode           => 0
+        }
+    }
+}
+
+impl fmt::Display for Trap {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            Trap::IllegalInstruction(instr) => write!(f, "{} (0x{:08x})", self.description(), instr),
+            Trap::EnvironmentCallFromUMode |
+            Trap::EnvironmentCallFromSMode |
+            Trap::EnvironmentCallFromMMode  => write!(f, "{}", self.description()),
+            _ => write!(f, "{} (0x{:x})", self.description(), self.tval())
+        }
+    }
+}
+
+/// Asynchronous interrupts, listed from the highest to the lowest priority
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Interrupt {
+    MachineExternal,
+    MachineSoftware,
+    MachineTimer,
+    SupervisorExternal,
+    SupervisorSoftware,
+    SupervisorTimer
+}
+
+impl Interrupt {
+    /// Interrupts in decreasing order of priority
+    pub const PRIORITY: [Interrupt; 6] = [
+        Interrupt::MachineExternal, Interrupt::MachineSoftware, Interrupt::MachineTimer,
+        Interrupt::SupervisorExternal, Interrupt::SupervisorSoftware, Interrupt::SupervisorTimer
+    ];
+
+    /// Exception code that is written in mcause (without the interrupt
+    /// bit). It is also the position of the interrupt bit in mip and mie
+    pub fn cause(&self) -> u64 {
+        match self {
+            Interrupt::SupervisorSoftware => 1,
+            Interrupt::MachineSoftware    => 3,
+            Interrupt::SupervisorTimer    => 5,
+            Interrupt::MachineTimer       => 7,
+            Interrupt::SupervisorExternal => 9,
+            Interrupt::MachineExternal    => 11
+        }
+    }
+
+    /// Name of the interrupt
+    pub fn description(&self) -> &'static str {
+        match self {
+            Interrupt::SupervisorSoftware => "supervisor software interrupt",
+            Interrupt::MachineSoftware    => "machine software interrupt",
+            Interrupt::SupervisorTimer    => "supervisor timer interrupt",
+            Interrupt::MachineTimer       => "machine timer interrupt",
+            Interrupt::SupervisorExternal => "supervisor external interrupt",
+            Interrupt::MachineExternal    => "machine external interrupt"
+        }
+    }
+}
+
+impl fmt::Display for Interrupt {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.description())
+    }
+}
+
+/// How many times a certain kind of trap was raised and by which instructions
+#[derive(Clone)]
+pub struct CauseStatistics {
+    pub description: &'static str,
+    pub count: u64,
+    // Number of traps raised by the instruction at each PC
+    pcs: HashMap<u64, u64>
+}
+
+impl CauseStatistics {
+    /// The n PCs that raised this trap the most, with the number of traps
+    /// each of them raised, in decreasing order
+    pub fn top_pcs(&self, n: usize) -> Vec<(u64, u64)> {
+        let mut pcs: Vec<(u64, u64)> = self.pcs.iter().map(|(pc, count)| (*pc, *count)).collect();
+        pcs.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        pcs.truncate(n);
+        pcs
+    }
+}
+
+/// A trap of the history kept by the statistics
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct TrapEvent {
+    // Position of the trap among all the ones raised, from 1
+    pub number: u64,
+    // mcause value, with the interrupt bit set for interrupts
+    pub cause: u64,
+    pub description: &'static str,
+    pub pc: u64,
+    pub tval: u64
+}
+
+/// Record of all the traps raised during the execution, grouped by cause.
+/// Interrupts are keyed by their mcause value, with the interrupt bit set.
+/// The last traps are also kept in the order they were raised
+#[derive(Clone, Default)]
+pub struct TrapStatistics {
+    causes: BTreeMap<u64, CauseStatistics>,
+    history: VecDeque<TrapEvent>,
+    total: u64
+}
+
+impl TrapStatistics {
+    pub fn new() -> TrapStatistics {
+        TrapStatistics { causes: BTreeMap::new(), history: VecDeque::new(), total: 0 }
+    }
+
+    /// Bit that distinguishes interrupts from exceptions in the cause
+    pub const INTERRUPT_BIT: u64 = 1 << 63;
+    /// Number of traps kept in the history
+    pub const HISTORY_LENGTH: usize = 64;
+
+    /// Count a trap raised by the instruction at the given PC
+    pub fn record(&mut self, trap: &Trap, pc: u64) {
+        self.record_cause(trap.cause(), trap.description(), pc, trap.tval());
+    }
+
+    /// Count an interrupt taken before executing the instruction at the given PC
+    pub fn record_interrupt(&mut self, interrupt: &Interrupt, pc: u64) {
+        self.record_cause(TrapStatistics::INTERRUPT_BIT | interrupt.cause(), interrupt.description(), pc, 0);
+    }
+
+    fn record_cause(&mut self, cause: u64, description: &'static str, pc: u64, tval: u64) {
+        let statistics: &mut CauseStatistics = self.causes.entry(cause).or_insert(CauseStatistics {
+            description,
+            count: 0,
+            pcs: HashMap::new()
+        });
+        statistics.count += 1;
+        *statistics.pcs.entry(pc).or_insert(0) += 1;
+        self.total += 1;
+        if self.history.len() == TrapStatistics::HISTORY_LENGTH {
+            self.history.pop_front();
+        }
+        self.history.push_back(TrapEvent { number: self.total, cause, description, pc, tval });
+    }
+
+    /// The last traps raised (at most HISTORY_LENGTH), oldest first
+    pub fn history(&self) -> impl Iterator<Item = &TrapEvent> {
+        self.history.iter()
+    }
+
+    /// Total number of raised traps
+    pub fn total(&self) -> u64 {
+        self.total
+    }
+
+    /// Check if no trap was raised
+    pub fn is_empty(&self) -> bool {
+        self.causes.is_empty()
+    }
+
+    /// Statistics of each cause, ordered by cause code
+    pub fn causes(&self) -> impl Iterator<Item = (u64, &CauseStatistics)> {
+        self.causes.iter().map(|(cause, stats)| (*cause, stats))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::trap::{Interrupt, Trap, TrapStatistics};
+
+    #[test]
+    fn statistics_test() {
+        let mut stats: TrapStatistics = TrapStatistics::new();
+        stats.record(&Trap::LoadAddressMisaligned(0x21), 0x100);
+        stats.record(&Trap::LoadAddressMisaligned(0x23), 0x104);
+        stats.record(&Trap::LoadAddressMisaligned(0x25), 0x104);
+        stats.record(&Trap::EnvironmentCallFromMMode, 0x200);
+        assert_eq!(stats.total(), 4);
+
+        let causes: Vec<u64> = stats.causes().map(|(cause, _)| cause).collect();
+        assert_eq!(causes, vec![4, 11]);
+        let (_, misaligned) = stats.causes().next().unwrap();
+        assert_eq!(misaligned.description, "load address misaligned");
+        assert_eq!(misaligned.top_pcs(1), vec![(0x104, 2)]);
+
+        // Interrupts do not share the causes of exceptions with the same code
+        stats.record_interrupt(&Interrupt::MachineTimer, 0x300);
+        stats.record(&Trap::StoreAccessFault(0x0), 0x300);
+        let causes: Vec<u64> = stats.causes().map(|(cause, _)| cause).collect();
+        assert_eq!(causes, vec![4, 7, 11, TrapStatistics::INTERRUPT_BIT | 7]);
+
+        // The history keeps the last traps in order
+        let last: Vec<(u64, u64, u64)> = stats.history().skip(4).map(|event| (event.number, event.cause, event.pc)).collect();
+        assert_eq!(last, vec![(5, TrapStatistics::INTERRUPT_BIT | 7, 0x300), (6, 7, 0x300)]);
+        for i in 0..TrapStatistics::HISTORY_LENGTH as u64 {
+            stats.record(&Trap::Breakpoint(i), i);
+        }
+        assert_eq!(stats.history().count(), TrapStatistics::HISTORY_LENGTH);
+        assert_eq!(stats.history().next().map(|event| (event.number, event.tval)), Some((7, 0)));
+    }
+}
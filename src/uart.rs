@@ -1,21 +1,61 @@
-use crate::cli::CLI;
+use crate::bus::Bus;
+use crate::cli::{CLI, SharedHostConsole};
+use crate::device::Device;
+use crate::expr;
+use crate::memory::AccessSize;
+use crate::snapshot::{Snapshot, SnapshotReader, SnapshotWriter};
 
-#[allow(dead_code)]
+/// UART mapped in addition to the one of the machine, given as
+/// `<base>` optionally followed by `,irq=<source>` to connect its interrupt
+/// to a PLIC source
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct UartSpec {
+    pub base: u64,
+    pub irq: Option<usize>
+}
+
+impl UartSpec {
+    pub fn parse(spec: &str) -> Result<UartSpec, String> {
+        let (base, irq) = match spec.split_once(",irq=") {
+            Some((base, irq)) => match irq.parse::<usize>() {
+                Ok(irq) => (base, Some(irq)),
+                Err(_) => return Err(format!("Invalid interrupt source {}", irq))
+            },
+            None => (spec, None)
+        };
+        Ok(UartSpec { base: expr::parse_number(base)?, irq })
+    }
+}
+
+/// 16550-compatible UART. Only the registers are emulated (no FIFOs,
+/// no baud rate): characters written to THR are printed on the host
+/// terminal as soon as the UART is cycled. The interrupt line is high
+/// while received data is available or THR is empty, if the corresponding
+/// interrupt is enabled in IER
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Clone)]
 pub struct UART {
     rhr: u8,
     thr: u8,
     ier: u8,
     fcr: u8,
-    isr: u8,
     lcr: u8,
     mcr: u8,
     lsr: u8,
     msr: u8,
     spr: u8,
+    // Divisor latch (least and most significant byte)
+    dll: u8,
+    dlm: u8,
     terminal: CLI
 }
 
-#[allow(dead_code)]
+impl Default for UART {
+    fn default() -> UART {
+        UART::new()
+    }
+}
+
 impl UART {
     const RHR_THR_ADDR: u8 = 0x0;
     const IER_ADDR:     u8 = 0x1;
@@ -26,79 +66,249 @@ impl UART {
     const MSR_ADDR:     u8 = 0x6;
     const SPR_ADDR:     u8 = 0x7;
 
+    // LCR divisor latch access bit: offsets 0 and 1 access DLL and DLM
+    const LCR_DLAB: u8 = 1 << 7;
+    // LSR bits: data ready, THR empty and transmitter empty
+    const LSR_DR:   u8 = 1 << 0;
+    const LSR_THRE: u8 = 1 << 5;
+    const LSR_TEMT: u8 = 1 << 6;
+    // IER bits: received data available and THR empty interrupts
+    const IER_RX: u8 = 1 << 0;
+    const IER_THRE: u8 = 1 << 1;
+    // ISR values: no interrupt pending, THR empty and received data available
+    const ISR_NO_INTERRUPT: u8 = 0x1;
+    const ISR_THRE: u8 = 0x2;
+    const ISR_RX: u8 = 0x4;
+
+    /// Frequency of the reference clock told to the guest (the baud rate
+    /// is not emulated, any value works)
+    pub const CLOCK_FREQUENCY: u32 = 3_686_400;
+
     pub fn new() -> UART {
         UART {
             rhr: 0, thr: 0, ier: 0,
-            fcr: 0, isr: 0, lcr: 0,
-            mcr: 0, lsr: 0x1, msr: 0,
-            spr: 0, terminal: CLI::new()
+            fcr: 0, lcr: 0,
+            mcr: 0, lsr: UART::LSR_THRE | UART::LSR_TEMT, msr: 0,
+            spr: 0, dll: 0, dlm: 0, terminal: CLI::new()
         }
     }
 
     fn thr_full(&self) -> bool {
-        (self.lsr >> 6) & 0x1 == 0x0
+        self.lsr & UART::LSR_THRE == 0x0
     }
 
     fn set_thr_full(&mut self) {
-        self.lsr = self.lsr & 0b10111111;
+        self.lsr &= !(UART::LSR_THRE | UART::LSR_TEMT);
     }
 
     fn set_thr_empty(&mut self) {
-        self.lsr = self.lsr | 0b01000000;
+        self.lsr |= UART::LSR_THRE | UART::LSR_TEMT;
     }
 
     fn rhr_ready(&self) -> bool {
-        self.lsr & 0x1 == 0x1
+        self.lsr & UART::LSR_DR == UART::LSR_DR
     }
 
     fn rhr_set_not_ready(&mut self) {
-        self.lsr = self.lsr & 0b11111110;
+        self.lsr &= !UART::LSR_DR;
     }
 
     fn rhr_set_ready(&mut self) {
-        self.lsr = self.lsr | 0b00000001;
+        self.lsr |= UART::LSR_DR;
+    }
+
+    fn dlab(&self) -> bool {
+        self.lcr & UART::LCR_DLAB == UART::LCR_DLAB
     }
 
-    pub fn cycle(&mut self) {
-        if self.thr_full() && self.thr != 0 {
-            self.terminal.write_byte(self.thr);
-            self.set_thr_empty()
+    // Interrupt status: the pending interrupt with the highest priority
+    fn isr(&self) -> u8 {
+        if self.ier & UART::IER_RX != 0 && self.rhr_ready() {
+            UART::ISR_RX
+        } else if self.ier & UART::IER_THRE != 0 && !self.thr_full() {
+            UART::ISR_THRE
+        } else {
+            UART::ISR_NO_INTERRUPT
         }
+    }
 
-        if self.rhr_ready() {
-            self.rhr = self.terminal.read_byte();
-            self.rhr_set_not_ready()
+    /// Level of the interrupt line of the UART
+    #[inline(always)]
+    pub fn interrupt(&self) -> bool {
+        self.ier != 0 && self.isr() != UART::ISR_NO_INTERRUPT
+    }
+
+    /// Advance the state of the UART: transmit the character in THR, if any,
+    /// and move the next received character (if any) into RHR.
+    /// It returns the character that has been transmitted
+    #[inline(always)]
+    pub fn cycle(&mut self) -> Option<u8> {
+        let transmitted: Option<u8> = self.transmit();
+        self.poll_input();
+        transmitted
+    }
+
+    /// Transmit the character in THR, if any, and return it
+    #[inline(always)]
+    pub fn transmit(&mut self) -> Option<u8> {
+        if !self.thr_full() {
+            return None;
         }
+        self.terminal.write_byte(self.thr);
+        self.terminal.show_output();
+        self.set_thr_empty();
+        Some(self.thr)
+    }
+
+    /// Show a character on the terminal of the UART without transmitting
+    /// it (e.g. one sent to the host console through HTIF)
+    pub fn print(&mut self, byte: u8) {
+        self.terminal.write_byte(byte);
+        self.terminal.show_output();
+    }
+
+    /// Connect the UART to console instead of the terminal of the emulator
+    pub fn set_console(&mut self, console: SharedHostConsole) {
+        self.terminal.set_console(console);
+    }
+
+    /// Move the next character typed on the terminal (if any) into RHR,
+    /// unless RHR still holds the previous one. It returns the character
+    /// that has been received
+    #[inline(always)]
+    pub fn poll_input(&mut self) -> Option<u8> {
+        if self.rhr_ready() || !self.terminal.has_input() {
+            return None;
+        }
+        let byte: u8 = self.terminal.read_byte();
+        self.receive(byte);
+        Some(byte)
+    }
+
+    /// Read the received character in RHR, if there is one
+    pub fn take_received(&mut self) -> Option<u8> {
+        if !self.rhr_ready() {
+            return None;
+        }
+        Some(self.read(UART::RHR_THR_ADDR))
+    }
+
+    /// Put a character into RHR as if it had been received (e.g. when the
+    /// inputs of a run are replayed)
+    pub fn receive(&mut self, byte: u8) {
+        self.rhr = byte;
+        self.rhr_set_ready();
     }
 
     pub fn write(&mut self, addr: u8, data: u8) {
         match addr {
+            UART::RHR_THR_ADDR if self.dlab() => self.dll = data,
+            UART::IER_ADDR if self.dlab() => self.dlm = data,
             UART::RHR_THR_ADDR => { self.thr = data;  self.set_thr_full()}
-            UART::IER_ADDR     => self.ier = data,
+            UART::IER_ADDR     => self.ier = data & (UART::IER_RX | UART::IER_THRE),
             UART::FCR_ISR_ADDR => self.fcr = data,
             UART::LCR_ADDR     => self.lcr = data,
             UART::MCR_ADDR     => self.mcr = data,
-            UART::SPR_ADDR     => self.mcr = data,
+            UART::SPR_ADDR     => self.spr = data,
             _ => (),
         }
     }
 
+    /// Read a register as the guest does: reading RHR consumes the
+    /// received character
     pub fn read(&mut self, addr: u8) -> u8 {
+        let data: u8 = self.peek(addr);
+        if addr == UART::RHR_THR_ADDR && !self.dlab() {
+            self.rhr_set_not_ready();
+            self.rhr = 0;
+        }
+        data
+    }
+
+    /// Read a register without any side effect (used by the debugger)
+    pub fn peek(&self, addr: u8) -> u8 {
         match addr {
-            UART::RHR_THR_ADDR => {
-                let rhr: u8 = self.rhr;
-                self.rhr_set_ready();
-                self.rhr = 0;
-                rhr
-            },
+            UART::RHR_THR_ADDR if self.dlab() => self.dll,
+            UART::IER_ADDR if self.dlab() => self.dlm,
+            UART::RHR_THR_ADDR => self.rhr,
+            UART::IER_ADDR     => self.ier,
+            UART::FCR_ISR_ADDR => self.isr(),
+            UART::LCR_ADDR     => self.lcr,
+            UART::MCR_ADDR     => self.mcr,
+            UART::LSR_ADDR     => self.lsr,
+            UART::MSR_ADDR     => self.msr,
+            UART::SPR_ADDR     => self.spr,
             _ => 0x0
         }
     }
 }
 
+// The UART can also be used as a standalone device, e.g. in the device
+// test harness. Registers are one byte wide: as on the bus, only the low
+// byte is written and wider reads return a single byte
+impl Device for UART {
+    fn name(&self) -> &str {
+        "uart"
+    }
+
+    fn size(&self) -> u64 {
+        Bus::UART_SIZE
+    }
+
+    fn read(&mut self, offset: u64, _size: AccessSize) -> Option<u64> {
+        Some(UART::read(self, offset as u8) as u64)
+    }
+
+    fn peek(&self, offset: u64, _size: AccessSize) -> Option<u64> {
+        Some(UART::peek(self, offset as u8) as u64)
+    }
+
+    fn write(&mut self, offset: u64, data: u64, _size: AccessSize) -> Option<()> {
+        UART::write(self, offset as u8, data as u8);
+        Some(())
+    }
+
+    fn cycle(&mut self) {
+        UART::cycle(self);
+    }
+
+    fn interrupt(&self) -> bool {
+        UART::interrupt(self)
+    }
+
+    fn compatible(&self) -> &[&str] {
+        &["ns16550a"]
+    }
+
+    fn clone_device(&self) -> Box<dyn Device> {
+        Box::new(self.clone())
+    }
+}
+
+// The terminal is not part of the snapshot: characters that have not been
+// read yet by the program stay with the terminal of the running emulator
+impl Snapshot for UART {
+    fn save(&self, writer: &mut SnapshotWriter) {
+        for register in [self.rhr, self.thr, self.ier, self.fcr, self.lcr, self.mcr,
+                         self.lsr, self.msr, self.spr, self.dll, self.dlm] {
+            writer.put_u8(register);
+        }
+    }
+
+    fn restore(&mut self, reader: &mut SnapshotReader) -> Result<(), String> {
+        for register in [&mut self.rhr, &mut self.thr, &mut self.ier, &mut self.fcr, &mut self.lcr, &mut self.mcr,
+                         &mut self.lsr, &mut self.msr, &mut self.spr, &mut self.dll, &mut self.dlm] {
+            *register = reader.get_u8()?;
+        }
+        Ok(())
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    use crate::uart::UART;
+    use std::sync::{Arc, Mutex};
+    use crate::cli::BufferConsole;
+    use crate::uart::{UartSpec, UART};
 
     #[test]
     fn write_test() {
@@ -110,6 +320,72 @@ mod tests {
         uart.terminal.show_output()
     }
 
+    #[test]
+    fn registers_test() {
+        let mut uart = UART::new();
+        // THR is empty after reset
+        assert_eq!(uart.read(0x5) & 0x60, 0x60);
+        uart.write(0x0, b'a');
+        assert_eq!(uart.read(0x5) & 0x60, 0x0);
+        uart.cycle();
+        assert_eq!(uart.read(0x5) & 0x60, 0x60);
+        // Programming the divisor does not transmit anything
+        uart.write(0x3, 0x80);
+        uart.write(0x0, 0x03);
+        assert_eq!(uart.read(0x5) & 0x60, 0x60);
+        assert_eq!(uart.read(0x0), 0x03);
+        uart.write(0x3, 0x03);
+        uart.write(0x7, 0x5a);
+        assert_eq!(uart.read(0x7), 0x5a);
+        assert_eq!(uart.read(0x4), 0x0);
+        // THR empty interrupt
+        assert!(!uart.interrupt());
+        assert_eq!(uart.read(0x2), 0x1);
+        uart.write(0x1, 0x2);
+        assert!(uart.interrupt());
+        assert_eq!(uart.read(0x2), 0x2);
+        uart.write(0x0, b'b');
+        assert!(!uart.interrupt());
+        uart.cycle();
+        assert!(uart.interrupt());
+    }
+
+    #[test]
+    fn spec_test() {
+        assert_eq!(UartSpec::parse("0x10002000,irq=11"), Ok(UartSpec { base: 0x10002000, irq: Some(11) }));
+        assert_eq!(UartSpec::parse("0x10002000"), Ok(UartSpec { base: 0x10002000, irq: None }));
+        assert!(UartSpec::parse("0x10002000,irq=x").is_err());
+        assert!(UartSpec::parse("uart").is_err());
+    }
+
+    #[test]
+    fn keyboard_test() {
+        let console: Arc<Mutex<BufferConsole>> = Arc::new(Mutex::new(BufferConsole::new()));
+        let mut uart = UART::new();
+        uart.set_console(console.clone());
+        uart.write(0x1, 0x1);
+        console.lock().unwrap().send_input(b"ok");
+        // The console is polled from time to time, not at every cycle
+        let wait_data_ready = |uart: &mut UART| {
+            for _ in 0..256 {
+                uart.cycle();
+                if uart.read(0x5) & 0x1 == 0x1 {
+                    return true;
+                }
+            }
+            false
+        };
+        assert!(wait_data_ready(&mut uart));
+        assert!(uart.interrupt());
+        assert_eq!(uart.read(0x2), 0x4);
+        assert_eq!(uart.read(0x0), b'o');
+        assert_eq!(uart.read(0x5) & 0x1, 0x0);
+        assert!(!uart.interrupt());
+        assert!(wait_data_ready(&mut uart));
+        assert_eq!(uart.read(0x0), b'k');
+        assert!(!wait_data_ready(&mut uart));
+    }
+
     #[test]
     fn read_test() {
         let mut uart = UART::new();
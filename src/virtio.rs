@@ -0,0 +1,558 @@
+use crate::memory::AccessSize;
+
+/// Memory of the machine as seen by a device that accesses it directly
+/// (DMA): the bus gives the device its RAM regions, without going through
+/// the other devices or the simulated caches
+pub trait GuestMemory {
+    /// Read data.len() bytes at addr, None if they are not all in memory
+    fn read_bytes(&self, addr: u64, data: &mut [u8]) -> Option<()>;
+
+    /// Write data at addr, None if it does not all fit in memory
+    fn write_bytes(&mut self, addr: u64, data: &[u8]) -> Option<()>;
+}
+
+fn read_u16(memory: &dyn GuestMemory, addr: u64) -> Option<u16> {
+    let mut bytes: [u8; 2] = [0; 2];
+    memory.read_bytes(addr, &mut bytes)?;
+    Some(u16::from_le_bytes(bytes))
+}
+
+fn read_u32(memory: &dyn GuestMemory, addr: u64) -> Option<u32> {
+    let mut bytes: [u8; 4] = [0; 4];
+    memory.read_bytes(addr, &mut bytes)?;
+    Some(u32::from_le_bytes(bytes))
+}
+
+fn read_u64(memory: &dyn GuestMemory, addr: u64) -> Option<u64> {
+    let mut bytes: [u8; 8] = [0; 8];
+    memory.read_bytes(addr, &mut bytes)?;
+    Some(u64::from_le_bytes(bytes))
+}
+
+// A split virtqueue: the descriptor table, the ring of the buffers made
+// available by the driver and the ring of the buffers used by the device
+#[derive(Clone, Default)]
+struct Virtqueue {
+    num: u32,
+    ready: bool,
+    desc: u64,
+    driver: u64,
+    device: u64,
+    // Next entry of the available ring to be processed
+    last_avail: u16
+}
+
+// A descriptor of a buffer in guest memory
+#[derive(Clone, Copy)]
+struct Descriptor {
+    addr: u64,
+    len: u32,
+    // The device writes the buffer (otherwise it reads it)
+    writable: bool
+}
+
+impl Virtqueue {
+    const DESC_F_NEXT: u16 = 1;
+    const DESC_F_WRITE: u16 = 2;
+    const AVAIL_F_NO_INTERRUPT: u16 = 1;
+
+    // Follow the chain of descriptors starting at head. The addresses come
+    // from the driver: one that overflows breaks the queue (None), as one
+    // that is not in memory does
+    fn chain(&self, memory: &dyn GuestMemory, head: u16) -> Option<Vec<Descriptor>> {
+        let mut chain: Vec<Descriptor> = Vec::new();
+        let mut index: u16 = head;
+        loop {
+            // A chain cannot be longer than the table (it would be a loop)
+            if index as u32 >= self.num || chain.len() as u32 >= self.num {
+                return None;
+            }
+            let entry: u64 = self.desc.checked_add(16 * index as u64)?;
+            let flags: u16 = read_u16(memory, entry.checked_add(12)?)?;
+            chain.push(Descriptor {
+                addr: read_u64(memory, entry)?,
+                len: read_u32(memory, entry.checked_add(8)?)?,
+                writable: flags & Virtqueue::DESC_F_WRITE != 0
+            });
+            if flags & Virtqueue::DESC_F_NEXT == 0 {
+                return Some(chain);
+            }
+            index = read_u16(memory, entry.checked_add(14)?)?;
+        }
+    }
+}
+
+/// Block device with the virtio-mmio interface (version 2, the one
+/// without legacy registers) and a single request queue. The disk is a
+/// host image file kept in memory while the machine runs, so that the
+/// machine can be cloned and go back in time; it is written back to the
+/// file by write_back. Requests are served as soon as the driver notifies
+/// the queue, and their completion is signaled with an interrupt
+#[derive(Clone)]
+pub struct VirtioBlock {
+    filename: String,
+    disk: Vec<u8>,
+    // The disk has been written since it was loaded or written back
+    dirty: bool,
+    status: u32,
+    device_features_sel: u32,
+    driver_features: u64,
+    driver_features_sel: u32,
+    queue_sel: u32,
+    queue: Virtqueue,
+    interrupt_status: u32,
+    // The queue has been notified and it has not been processed yet
+    notified: bool
+}
+
+impl VirtioBlock {
+    pub const SECTOR_SIZE: u64 = 512;
+    pub const QUEUE_NUM_MAX: u32 = 256;
+
+    const MAGIC: u32 = 0x74726976;
+    const VERSION: u32 = 2;
+    const DEVICE_ID_BLOCK: u32 = 2;
+    const VENDOR_ID: u32 = 0x56495200;
+
+    const MAGIC_ADDR:               u64 = 0x000;
+    const VERSION_ADDR:             u64 = 0x004;
+    const DEVICE_ID_ADDR:           u64 = 0x008;
+    const VENDOR_ID_ADDR:           u64 = 0x00c;
+    const DEVICE_FEATURES_ADDR:     u64 = 0x010;
+    const DEVICE_FEATURES_SEL_ADDR: u64 = 0x014;
+    const DRIVER_FEATURES_ADDR:     u64 = 0x020;
+    const DRIVER_FEATURES_SEL_ADDR: u64 = 0x024;
+    const QUEUE_SEL_ADDR:           u64 = 0x030;
+    const QUEUE_NUM_MAX_ADDR:       u64 = 0x034;
+    const QUEUE_NUM_ADDR:           u64 = 0x038;
+    const QUEUE_READY_ADDR:         u64 = 0x044;
+    const QUEUE_NOTIFY_ADDR:        u64 = 0x050;
+    const INTERRUPT_STATUS_ADDR:    u64 = 0x060;
+    const INTERRUPT_ACK_ADDR:       u64 = 0x064;
+    const STATUS_ADDR:              u64 = 0x070;
+    const QUEUE_DESC_LOW_ADDR:      u64 = 0x080;
+    const QUEUE_DESC_HIGH_ADDR:     u64 = 0x084;
+    const QUEUE_DRIVER_LOW_ADDR:    u64 = 0x090;
+    const QUEUE_DRIVER_HIGH_ADDR:   u64 = 0x094;
+    const QUEUE_DEVICE_LOW_ADDR:    u64 = 0x0a0;
+    const QUEUE_DEVICE_HIGH_ADDR:   u64 = 0x0a4;
+    const CONFIG_GENERATION_ADDR:   u64 = 0x0fc;
+    // Configuration space: the capacity of the disk in sectors
+    const CONFIG_ADDR:              u64 = 0x100;
+
+    // Features: flush requests and the non-legacy interface
+    const F_BLK_FLUSH: u64 = 1 << 9;
+    const F_VERSION_1: u64 = 1 << 32;
+    const FEATURES: u64 = VirtioBlock::F_BLK_FLUSH | VirtioBlock::F_VERSION_1;
+
+    // Device status bits
+    const STATUS_FEATURES_OK: u32 = 8;
+    const STATUS_NEEDS_RESET: u32 = 64;
+
+    // Interrupt status bits: a used buffer and a configuration change
+    const INT_USED_BUFFER: u32 = 1;
+    const INT_CONFIG_CHANGE: u32 = 2;
+
+    // Request types and status
+    const T_IN: u32 = 0;
+    const T_OUT: u32 = 1;
+    const T_FLUSH: u32 = 4;
+    const T_GET_ID: u32 = 8;
+    const S_OK: u8 = 0;
+    const S_IOERR: u8 = 1;
+    const S_UNSUPP: u8 = 2;
+    // Length of the identifier returned by GET_ID
+    const ID_LEN: usize = 20;
+
+    /// Create a device whose disk holds disk, which was read from filename.
+    /// The disk is made of whole sectors
+    pub fn new(filename: &str, disk: Vec<u8>) -> Result<VirtioBlock, String> {
+        if disk.is_empty() || !(disk.len() as u64).is_multiple_of(VirtioBlock::SECTOR_SIZE) {
+            return Err(format!("Disk image {} is not made of {}-byte sectors ({} bytes)",
+                               filename, VirtioBlock::SECTOR_SIZE, disk.len()));
+        }
+        Ok(VirtioBlock {
+            filename: filename.to_string(),
+            disk,
+            dirty: false,
+            status: 0,
+            device_features_sel: 0,
+            driver_features: 0,
+            driver_features_sel: 0,
+            queue_sel: 0,
+            queue: Virtqueue::default(),
+            interrupt_status: 0,
+            notified: false
+        })
+    }
+
+    /// Open a disk image file
+    pub fn open(filename: &str) -> Result<VirtioBlock, String> {
+        let disk: Vec<u8> = std::fs::read(filename).map_err(|why| format!("Could not read disk image {}: {}", filename, why))?;
+        VirtioBlock::new(filename, disk)
+    }
+
+    pub fn get_filename(&self) -> &str {
+        &self.filename
+    }
+
+    /// Size of the disk in sectors
+    pub fn capacity(&self) -> u64 {
+        self.disk.len() as u64 / VirtioBlock::SECTOR_SIZE
+    }
+
+    /// Content of the disk
+    pub fn get_disk(&self) -> &[u8] {
+        &self.disk
+    }
+
+    /// Whether the disk has been written since it was loaded or written back
+    pub fn is_dirty(&self) -> bool {
+        self.dirty
+    }
+
+    /// Write the disk back to its image file, if it has been written
+    pub fn write_back(&mut self) -> Result<(), String> {
+        if self.dirty {
+            std::fs::write(&self.filename, &self.disk)
+                .map_err(|why| format!("Could not write disk image {}: {}", self.filename, why))?;
+            self.dirty = false;
+        }
+        Ok(())
+    }
+
+    /// Level of the interrupt line of the device
+    #[inline(always)]
+    pub fn interrupt(&self) -> bool {
+        self.interrupt_status != 0
+    }
+
+    /// Whether the queue has been notified, processing has to follow
+    pub fn is_notified(&self) -> bool {
+        self.notified
+    }
+
+    fn reset(&mut self) {
+        let (filename, disk, dirty) = (std::mem::take(&mut self.filename), std::mem::take(&mut self.disk), self.dirty);
+        *self = VirtioBlock { dirty, ..VirtioBlock::new(&filename, disk).expect("the disk was valid") };
+    }
+
+    /// Read a register at an offset from the beginning of the device.
+    /// Registers are 32 bits wide, the configuration space can also be
+    /// read a byte at a time
+    pub fn read(&self, offset: u64, size: AccessSize) -> Option<u64> {
+        if offset >= VirtioBlock::CONFIG_ADDR {
+            let start: usize = (offset - VirtioBlock::CONFIG_ADDR) as usize;
+            let config: [u8; 8] = self.capacity().to_le_bytes();
+            let bytes: &[u8] = config.get(start..)?.get(..size.bytes())?;
+            return Some(bytes.iter().rev().fold(0, |value, byte| (value << 8) | *byte as u64));
+        }
+        if size != AccessSize::WORD {
+            return None;
+        }
+        let value: u32 = match offset {
+            VirtioBlock::MAGIC_ADDR => VirtioBlock::MAGIC,
+            VirtioBlock::VERSION_ADDR => VirtioBlock::VERSION,
+            VirtioBlock::DEVICE_ID_ADDR => VirtioBlock::DEVICE_ID_BLOCK,
+            VirtioBlock::VENDOR_ID_ADDR => VirtioBlock::VENDOR_ID,
+            VirtioBlock::DEVICE_FEATURES_ADDR => match self.device_features_sel {
+                0 => VirtioBlock::FEATURES as u32,
+                1 => (VirtioBlock::FEATURES >> 32) as u32,
+                _ => 0
+            },
+            VirtioBlock::QUEUE_NUM_MAX_ADDR if self.queue_sel == 0 => VirtioBlock::QUEUE_NUM_MAX,
+            VirtioBlock::QUEUE_READY_ADDR if self.queue_sel == 0 => self.queue.ready as u32,
+            VirtioBlock::QUEUE_NUM_MAX_ADDR | VirtioBlock::QUEUE_READY_ADDR => 0,
+            VirtioBlock::INTERRUPT_STATUS_ADDR => self.interrupt_status,
+            VirtioBlock::STATUS_ADDR => self.status,
+            VirtioBlock::CONFIG_GENERATION_ADDR => 0,
+            _ => return None
+        };
+        Some(value as u64)
+    }
+
+    /// Write a register at an offset from the beginning of the device
+    pub fn write(&mut self, offset: u64, data: u64, size: AccessSize) -> Option<()> {
+        if size != AccessSize::WORD {
+            return None;
+        }
+        let data: u32 = data as u32;
+        let queue: Option<&mut Virtqueue> = (self.queue_sel == 0).then_some(&mut self.queue);
+        let set_low = |field: &mut u64| *field = (*field & !0xffffffff) | data as u64;
+        let set_high = |field: &mut u64| *field = (*field & 0xffffffff) | ((data as u64) << 32);
+        match (offset, queue) {
+            (VirtioBlock::DEVICE_FEATURES_SEL_ADDR, _) => self.device_features_sel = data,
+            (VirtioBlock::DRIVER_FEATURES_ADDR, _) => match self.driver_features_sel {
+                0 => set_low(&mut self.driver_features),
+                1 => set_high(&mut self.driver_features),
+                _ => ()
+            },
+            (VirtioBlock::DRIVER_FEATURES_SEL_ADDR, _) => self.driver_features_sel = data,
+            (VirtioBlock::QUEUE_SEL_ADDR, _) => self.queue_sel = data,
+            (VirtioBlock::QUEUE_NUM_ADDR, Some(queue)) => queue.num = data.min(VirtioBlock::QUEUE_NUM_MAX),
+            (VirtioBlock::QUEUE_READY_ADDR, Some(queue)) => queue.ready = data & 0x1 != 0,
+            (VirtioBlock::QUEUE_DESC_LOW_ADDR, Some(queue)) => set_low(&mut queue.desc),
+            (VirtioBlock::QUEUE_DESC_HIGH_ADDR, Some(queue)) => set_high(&mut queue.desc),
+            (VirtioBlock::QUEUE_DRIVER_LOW_ADDR, Some(queue)) => set_low(&mut queue.driver),
+            (VirtioBlock::QUEUE_DRIVER_HIGH_ADDR, Some(queue)) => set_high(&mut queue.driver),
+            (VirtioBlock::QUEUE_DEVICE_LOW_ADDR, Some(queue)) => set_low(&mut queue.device),
+            (VirtioBlock::QUEUE_DEVICE_HIGH_ADDR, Some(queue)) => set_high(&mut queue.device),
+            // Registers of the queues that do not exist are ignored
+            (VirtioBlock::QUEUE_NUM_ADDR | VirtioBlock::QUEUE_READY_ADDR | VirtioBlock::QUEUE_DESC_LOW_ADDR |
+             VirtioBlock::QUEUE_DESC_HIGH_ADDR | VirtioBlock::QUEUE_DRIVER_LOW_ADDR | VirtioBlock::QUEUE_DRIVER_HIGH_ADDR |
+             VirtioBlock::QUEUE_DEVICE_LOW_ADDR | VirtioBlock::QUEUE_DEVICE_HIGH_ADDR, None) => (),
+            (VirtioBlock::QUEUE_NOTIFY_ADDR, _) => self.notified |= data == 0 && self.queue.ready,
+            (VirtioBlock::INTERRUPT_ACK_ADDR, _) => self.interrupt_status &= !data,
+            (VirtioBlock::STATUS_ADDR, _) if data == 0 => self.reset(),
+            (VirtioBlock::STATUS_ADDR, _) => {
+                // The features are only accepted if they were all offered,
+                // and the legacy interface is not supported
+                let accepted: bool = self.driver_features & !VirtioBlock::FEATURES == 0 &&
+                    self.driver_features & VirtioBlock::F_VERSION_1 != 0;
+                self.status = if accepted { data } else { data & !VirtioBlock::STATUS_FEATURES_OK };
+            },
+            _ => return None
+        }
+        Some(())
+    }
+
+    /// Serve the requests made available by the driver, reading and
+    /// writing them in memory, and raise the interrupt if any was served
+    pub fn process(&mut self, memory: &mut dyn GuestMemory) {
+        self.notified = false;
+        if self.status & VirtioBlock::STATUS_NEEDS_RESET != 0 {
+            return;
+        }
+        match self.process_queue(memory) {
+            Some(served) if served => {
+                let flags: u16 = read_u16(memory, self.queue.driver).unwrap_or(0);
+                if flags & Virtqueue::AVAIL_F_NO_INTERRUPT == 0 {
+                    self.interrupt_status |= VirtioBlock::INT_USED_BUFFER;
+                }
+            },
+            Some(_) => (),
+            // The queue is broken: the driver has to reset the device
+            None => {
+                self.status |= VirtioBlock::STATUS_NEEDS_RESET;
+                self.interrupt_status |= VirtioBlock::INT_CONFIG_CHANGE;
+            }
+        }
+    }
+
+    // Serve the available requests, return whether there were any, None
+    // if the queue is not in memory (or its addresses overflow)
+    fn process_queue(&mut self, memory: &mut dyn GuestMemory) -> Option<bool> {
+        let queue: Virtqueue = self.queue.clone();
+        if queue.num == 0 {
+            return None;
+        }
+        let avail_idx: u16 = read_u16(memory, queue.driver.checked_add(2)?)?;
+        let mut used_idx: u16 = read_u16(memory, queue.device.checked_add(2)?)?;
+        let mut served: bool = false;
+        while self.queue.last_avail != avail_idx {
+            let slot: u64 = (self.queue.last_avail as u32 % queue.num) as u64;
+            let head: u16 = read_u16(memory, queue.driver.checked_add(4 + 2 * slot)?)?;
+            let chain: Vec<Descriptor> = queue.chain(memory, head)?;
+            let written: u32 = self.serve(memory, &chain)?;
+            let used: u64 = queue.device.checked_add(4 + 8 * (used_idx as u32 % queue.num) as u64)?;
+            memory.write_bytes(used, &(head as u32).to_le_bytes())?;
+            memory.write_bytes(used.checked_add(4)?, &written.to_le_bytes())?;
+            used_idx = used_idx.wrapping_add(1);
+            memory.write_bytes(queue.device.checked_add(2)?, &used_idx.to_le_bytes())?;
+            self.queue.last_avail = self.queue.last_avail.wrapping_add(1);
+            served = true;
+        }
+        Some(served)
+    }
+
+    // Serve the request described by a chain of descriptors: a header
+    // (type, reserved, sector), the data buffers and the status byte.
+    // Return the number of bytes written to memory, None if the buffers
+    // are not in memory (or their addresses overflow)
+    fn serve(&mut self, memory: &mut dyn GuestMemory, chain: &[Descriptor]) -> Option<u32> {
+        let (header, status) = match chain {
+            [header, .., status] if chain.len() >= 2 && header.len >= 16 && !header.writable && status.writable && status.len >= 1 =>
+                (header, status),
+            _ => return None
+        };
+        let buffers: &[Descriptor] = &chain[1..chain.len() - 1];
+        let kind: u32 = read_u32(memory, header.addr)?;
+        let sector: u64 = read_u64(memory, header.addr.checked_add(8)?)?;
+        let mut written: u32 = 0;
+        let result: u8 = match kind {
+            VirtioBlock::T_IN | VirtioBlock::T_OUT => {
+                let length: u64 = buffers.iter().map(|buffer| buffer.len as u64).sum();
+                let start: Option<u64> = sector.checked_mul(VirtioBlock::SECTOR_SIZE)
+                    .filter(|start| start.checked_add(length).is_some_and(|end| end <= self.disk.len() as u64));
+                // Reads fill writable buffers, writes take readable ones
+                let directions_ok: bool = buffers.iter().all(|buffer| buffer.writable == (kind == VirtioBlock::T_IN));
+                match start {
+                    Some(mut position) if directions_ok => {
+                        for buffer in buffers {
+                            let range = position as usize..(position + buffer.len as u64) as usize;
+                            if kind == VirtioBlock::T_IN {
+                                memory.write_bytes(buffer.addr, &self.disk[range])?;
+                                written += buffer.len;
+                            } else {
+                                memory.read_bytes(buffer.addr, &mut self.disk[range])?;
+                                self.dirty = true;
+                            }
+                            position += buffer.len as u64;
+                        }
+                        VirtioBlock::S_OK
+                    },
+                    _ => VirtioBlock::S_IOERR
+                }
+            },
+            VirtioBlock::T_FLUSH => VirtioBlock::S_OK,
+            VirtioBlock::T_GET_ID => match buffers.first() {
+                Some(buffer) if buffer.writable => {
+                    let mut id: [u8; VirtioBlock::ID_LEN] = [0; VirtioBlock::ID_LEN];
+                    id[..7].copy_from_slice(b"riviera");
+                    let length: usize = (buffer.len as usize).min(VirtioBlock::ID_LEN);
+                    memory.write_bytes(buffer.addr, &id[..length])?;
+                    written += length as u32;
+                    VirtioBlock::S_OK
+                },
+                _ => VirtioBlock::S_IOERR
+            },
+            _ => VirtioBlock::S_UNSUPP
+        };
+        memory.write_bytes(status.addr, &[result])?;
+        Some(written + 1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::memory::AccessSize;
+    use crate::virtio::{GuestMemory, VirtioBlock};
+
+    struct Ram(Vec<u8>);
+
+    impl GuestMemory for Ram {
+        fn read_bytes(&self, addr: u64, data: &mut [u8]) -> Option<()> {
+            data.copy_from_slice(self.0.get(addr as usize..)?.get(..data.len())?);
+            Some(())
+        }
+
+        fn write_bytes(&mut self, addr: u64, data: &[u8]) -> Option<()> {
+            self.0.get_mut(addr as usize..)?.get_mut(..data.len())?.copy_from_slice(data);
+            Some(())
+        }
+    }
+
+    const DESC: u64 = 0x000;
+    const AVAIL: u64 = 0x100;
+    const USED: u64 = 0x200;
+    const HEADER: u64 = 0x300;
+    const DATA: u64 = 0x400;
+    const STATUS: u64 = 0x800;
+
+    // Driver side: negotiate the features and set up a queue of 4 entries
+    fn setup() -> (VirtioBlock, Ram) {
+        let mut disk: Vec<u8> = vec![0; 4 * VirtioBlock::SECTOR_SIZE as usize];
+        disk[512..516].copy_from_slice(b"data");
+        let mut block: VirtioBlock = VirtioBlock::new("disk.img", disk).unwrap();
+        assert_eq!(block.read(0x0, AccessSize::WORD), Some(0x74726976));
+        assert_eq!(block.read(0x8, AccessSize::WORD), Some(2));
+        assert_eq!(block.read(0x100, AccessSize::DOUBLEWORD), Some(4));
+        assert_eq!(block.read(0x100, AccessSize::BYTE), Some(4));
+        block.write(0x070, 1 | 2, AccessSize::WORD).unwrap();
+        // Without VERSION_1 the features are refused
+        block.write(0x070, 1 | 2 | 8, AccessSize::WORD).unwrap();
+        assert_eq!(block.read(0x070, AccessSize::WORD), Some(3));
+        block.write(0x024, 1, AccessSize::WORD).unwrap();
+        block.write(0x020, 1, AccessSize::WORD).unwrap();
+        block.write(0x070, 1 | 2 | 8, AccessSize::WORD).unwrap();
+        assert_eq!(block.read(0x070, AccessSize::WORD), Some(11));
+        block.write(0x038, 4, AccessSize::WORD).unwrap();
+        block.write(0x080, DESC, AccessSize::WORD).unwrap();
+        block.write(0x090, AVAIL, AccessSize::WORD).unwrap();
+        block.write(0x0a0, USED, AccessSize::WORD).unwrap();
+        block.write(0x044, 1, AccessSize::WORD).unwrap();
+        block.write(0x070, 1 | 2 | 8 | 4, AccessSize::WORD).unwrap();
+        (block, Ram(vec![0; 0x1000]))
+    }
+
+    // Make a request available: header, one data buffer and status
+    fn submit(block: &mut VirtioBlock, ram: &mut Ram, kind: u32, sector: u64, len: u32) {
+        let desc = |ram: &mut Ram, index: u64, addr: u64, len: u32, flags: u16, next: u16| {
+            let entry: u64 = DESC + 16 * index;
+            ram.write_bytes(entry, &addr.to_le_bytes()).unwrap();
+            ram.write_bytes(entry + 8, &len.to_le_bytes()).unwrap();
+            ram.write_bytes(entry + 12, &flags.to_le_bytes()).unwrap();
+            ram.write_bytes(entry + 14, &next.to_le_bytes()).unwrap();
+        };
+        ram.write_bytes(HEADER, &kind.to_le_bytes()).unwrap();
+        ram.write_bytes(HEADER + 8, &sector.to_le_bytes()).unwrap();
+        desc(ram, 0, HEADER, 16, 1, 1);
+        desc(ram, 1, DATA, len, if kind == 0 { 1 | 2 } else { 1 }, 2);
+        desc(ram, 2, STATUS, 1, 2, 0);
+        let mut idx: [u8; 2] = [0; 2];
+        ram.read_bytes(AVAIL + 2, &mut idx).unwrap();
+        let idx: u16 = u16::from_le_bytes(idx);
+        ram.write_bytes(AVAIL + 4 + 2 * (idx % 4) as u64, &0u16.to_le_bytes()).unwrap();
+        ram.write_bytes(AVAIL + 2, &(idx + 1).to_le_bytes()).unwrap();
+        block.write(0x050, 0, AccessSize::WORD).unwrap();
+        assert!(block.is_notified());
+        block.process(ram);
+    }
+
+    #[test]
+    fn request_test() {
+        let (mut block, mut ram) = setup();
+        // Read sector 1
+        submit(&mut block, &mut ram, 0, 1, 512);
+        assert_eq!(&ram.0[DATA as usize..DATA as usize + 4], b"data");
+        assert_eq!(ram.0[STATUS as usize], 0);
+        assert_eq!(&ram.0[USED as usize + 2..USED as usize + 12], &[1, 0, 0, 0, 0, 0, 1, 2, 0, 0]);
+        assert!(block.interrupt());
+        block.write(0x064, 1, AccessSize::WORD).unwrap();
+        assert!(!block.interrupt());
+
+        // Write it to sector 3
+        submit(&mut block, &mut ram, 1, 3, 512);
+        assert_eq!(ram.0[STATUS as usize], 0);
+        assert_eq!(&block.get_disk()[1536..1540], b"data");
+        assert!(block.is_dirty());
+
+        // Past the end of the disk, and an unknown request
+        submit(&mut block, &mut ram, 0, 4, 512);
+        assert_eq!(ram.0[STATUS as usize], 1);
+        submit(&mut block, &mut ram, 99, 0, 512);
+        assert_eq!(ram.0[STATUS as usize], 2);
+        assert_eq!(u16::from_le_bytes([ram.0[USED as usize + 2], ram.0[USED as usize + 3]]), 4);
+
+        // Resetting the device keeps the disk
+        block.write(0x070, 0, AccessSize::WORD).unwrap();
+        assert_eq!(block.read(0x070, AccessSize::WORD), Some(0));
+        assert!(block.is_dirty());
+        assert_eq!(&block.get_disk()[1536..1540], b"data");
+    }
+
+    #[test]
+    fn overflow_test() {
+        // A used ring at the top of the address space breaks the queue
+        let (mut block, mut ram) = setup();
+        block.write(0x0a0, 0xffff_ffff, AccessSize::WORD).unwrap();
+        block.write(0x0a4, 0xffff_ffff, AccessSize::WORD).unwrap();
+        submit(&mut block, &mut ram, 0, 1, 512);
+        assert_eq!(block.read(0x070, AccessSize::WORD), Some(1 | 2 | 8 | 4 | 64));
+        // So does a descriptor table there
+        let (mut block, mut ram) = setup();
+        block.write(0x080, 0xffff_fff8, AccessSize::WORD).unwrap();
+        block.write(0x084, 0xffff_ffff, AccessSize::WORD).unwrap();
+        ram.write_bytes(AVAIL + 2, &1u16.to_le_bytes()).unwrap();
+        block.write(0x050, 0, AccessSize::WORD).unwrap();
+        block.process(&mut ram);
+        assert_eq!(block.read(0x070, AccessSize::WORD), Some(1 | 2 | 8 | 4 | 64));
+    }
+
+    #[test]
+    fn image_test() {
+        assert!(VirtioBlock::new("disk.img", vec![0; 1000]).is_err());
+        assert!(VirtioBlock::new("disk.img", Vec::new()).is_err());
+        assert_eq!(VirtioBlock::new("disk.img", vec![0; 2048]).unwrap().capacity(), 4);
+    }
+}
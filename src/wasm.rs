@@ -0,0 +1,104 @@
+//! JavaScript API of riviera when it is built for WebAssembly
+//! (wasm32-unknown-unknown with wasm-bindgen): a web page loads a program
+//! from bytes, steps through it, reads the registers, types on the console
+//! and gets the console output of the program through a callback.
+//! There is no file system, standard input or clock on this target: the
+//! real-time clock of the guest starts at the epoch and follows mtime
+
+use std::sync::{Arc, Mutex};
+use wasm_bindgen::prelude::*;
+use crate::cli::BufferConsole;
+use crate::emulator::Emulator;
+use crate::image::ImageFormat;
+
+#[wasm_bindgen]
+pub struct Riviera {
+    emulator: Emulator,
+    // Console output of the program not passed to the callback yet
+    console: Arc<Mutex<BufferConsole>>,
+    callback: Option<js_sys::Function>
+}
+
+#[wasm_bindgen]
+impl Riviera {
+    /// Create an emulator with memory_size bytes of DRAM (the default
+    /// size if not given)
+    #[wasm_bindgen(constructor)]
+    pub fn new(memory_size: Option<usize>) -> Riviera {
+        let mut emulator: Emulator = Emulator::new(memory_size);
+        let console: Arc<Mutex<BufferConsole>> = Arc::new(Mutex::new(BufferConsole::new()));
+        emulator.set_host_console(console.clone());
+        emulator.set_rtc(0);
+        Riviera { emulator, console, callback: None }
+    }
+
+    /// Load an ELF executable
+    #[wasm_bindgen(js_name = loadElf)]
+    pub fn load_elf(&mut self, bytes: &[u8]) -> Result<(), JsError> {
+        self.emulator.load_program_bytes(bytes).map_err(|err| JsError::new(&err))
+    }
+
+    /// Load a raw binary at address and start the execution from there
+    #[wasm_bindgen(js_name = loadBinary)]
+    pub fn load_binary(&mut self, bytes: &[u8], address: u64) -> Result<(), JsError> {
+        self.emulator.load_image_bytes(bytes.to_vec(), ImageFormat::Bin, address).map_err(|err| JsError::new(&err))
+    }
+
+    /// Call callback with the text the program sends to the console
+    #[wasm_bindgen(js_name = onConsole)]
+    pub fn on_console(&mut self, callback: js_sys::Function) {
+        self.callback = Some(callback);
+    }
+
+    /// Type text on the console of the program: it is received by the
+    /// UART one character at a time as the program runs
+    #[wasm_bindgen(js_name = sendInput)]
+    pub fn send_input(&mut self, text: &str) {
+        if let Ok(mut console) = self.console.lock() {
+            console.send_input(text.as_bytes());
+        }
+    }
+
+    /// Execute at most count instructions, return how many were executed
+    pub fn step(&mut self, count: u64) -> u64 {
+        let executed: u64 = self.emulator.step(count);
+        self.flush_console();
+        executed
+    }
+
+    pub fn pc(&self) -> u64 {
+        self.emulator.get_cpu().get_pc()
+    }
+
+    /// Value of the integer register x<index>
+    pub fn register(&self, index: u8) -> Result<u64, JsError> {
+        if index >= 32 {
+            return Err(JsError::new(&format!("Invalid register x{}", index)));
+        }
+        Ok(self.emulator.get_cpu().read_reg(index))
+    }
+
+    /// Values of the 32 integer registers
+    pub fn registers(&self) -> Vec<u64> {
+        (0..32).map(|index| self.emulator.get_cpu().read_reg(index)).collect()
+    }
+
+    /// Whether the program is over
+    #[wasm_bindgen(js_name = isFinished)]
+    pub fn is_finished(&self) -> bool {
+        self.emulator.is_finished()
+    }
+
+    // The callback cannot be called while the emulator runs (it would
+    // have to be Send), so the output is passed on after each step
+    fn flush_console(&mut self) {
+        let output: Vec<u8> = match self.console.lock() {
+            Ok(mut console) => console.take_output(),
+            Err(_) => return
+        };
+        if let (Some(callback), false) = (self.callback.as_ref(), output.is_empty()) {
+            let text: String = String::from_utf8_lossy(&output).into_owned();
+            let _ = callback.call1(&JsValue::NULL, &JsValue::from_str(&text));
+        }
+    }
+}